@@ -0,0 +1,665 @@
+//! Typed async client for the Scherzo HTTP API.
+//!
+//! [`Client`] has a method for each endpoint of a running `scherzo start`,
+//! returning the [`types`] the server sends. Responses whose shape follows
+//! the printer's configuration, like [`Client::printer`], are returned as
+//! JSON.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), scherzo_client::Error> {
+//! use scherzo_client::{Client, JobFormat};
+//!
+//! let client = Client::new("http://127.0.0.1:3000").with_api_key("secret");
+//! let upload = client
+//!     .upload(b"G28\nG1 X10 F600\n".to_vec(), JobFormat::GCode)
+//!     .await?;
+//! let job = client.enqueue(upload.job_id).await?;
+//! println!("{} is {:?}", job.name, job.status);
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod types;
+
+pub use types::*;
+
+use futures::{Stream, stream};
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value, json};
+use std::time::Duration;
+use thiserror::Error;
+use uuid::Uuid;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{method} {path} failed")]
+    Request {
+        method: Method,
+        path: String,
+        #[source]
+        source: reqwest::Error,
+    },
+    #[error("{method} {path}: {status}{}", message_suffix(.message))]
+    Status {
+        method: Method,
+        path: String,
+        status: StatusCode,
+        /// Explanation of the error, from the body of the response.
+        message: String,
+        /// The body, if the server sent a structured error.
+        body: Option<Box<ErrorBody>>,
+    },
+    #[error("invalid response to {method} {path}")]
+    Decode {
+        method: Method,
+        path: String,
+        #[source]
+        source: serde_json::Error,
+    },
+}
+
+impl Error {
+    /// Status of the response, if the server sent an error.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Error::Status { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    /// Code of the error the server sent, e.g. `job_not_found`.
+    pub fn code(&self) -> Option<&str> {
+        self.body().map(|body| body.code.as_str())
+    }
+
+    /// Structured error the server sent, if any.
+    pub fn body(&self) -> Option<&ErrorBody> {
+        match self {
+            Error::Status { body, .. } => body.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+fn message_suffix(message: &str) -> String {
+    if message.is_empty() {
+        String::new()
+    } else {
+        format!(": {message}")
+    }
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Client for one Scherzo server.
+///
+/// Clones share a connection pool.
+#[derive(Clone, Debug)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+}
+
+impl Client {
+    /// A client for the server at `base_url`, e.g. `http://127.0.0.1:3000`.
+    pub fn new(base_url: &str) -> Self {
+        Self::with_http(base_url, reqwest::Client::new())
+    }
+
+    /// A client sending its requests through `http`, e.g. to set timeouts.
+    pub fn with_http(base_url: &str, http: reqwest::Client) -> Self {
+        Self {
+            http,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key: None,
+        }
+    }
+
+    /// Authenticate with `api_key` (see `server.auth.api_keys`).
+    pub fn with_api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Succeeds once the server is up.
+    pub async fn health(&self) -> Result<()> {
+        self.send(Method::GET, "/health", |request| request)
+            .await
+            .map(drop)
+    }
+
+    /// Toolhead, rails and motion system of the printer.
+    pub async fn printer(&self) -> Result<Value> {
+        self.get("/printer").await
+    }
+
+    pub async fn pause_status(&self) -> Result<PauseStatus> {
+        self.get("/printer/pause").await
+    }
+
+    /// Statistics for the current or last print.
+    pub async fn print_stats(&self) -> Result<PrintStats> {
+        self.get("/printer/print_stats").await
+    }
+
+    /// Active bed mesh and calibration progress.
+    pub async fn bed_mesh(&self) -> Result<Value> {
+        self.get("/printer/bed_mesh").await
+    }
+
+    /// Stop following the bed mesh until the next calibration or restart.
+    pub async fn clear_bed_mesh(&self) -> Result<Value> {
+        self.json(Method::DELETE, "/printer/bed_mesh", None).await
+    }
+
+    /// Start bed mesh calibration, moving to the first point to probe.
+    pub async fn calibrate_bed_mesh(&self) -> Result<Value> {
+        self.json(Method::POST, "/printer/bed_mesh/calibrate", None)
+            .await
+    }
+
+    /// Record the bed height at the current calibration point.
+    pub async fn report_probe_height(&self, z: f64) -> Result<Value> {
+        let body = json!({ "z": z });
+        self.json(
+            Method::POST,
+            "/printer/bed_mesh/calibrate/height",
+            Some(body),
+        )
+        .await
+    }
+
+    /// Cancel bed mesh calibration, restoring the mesh active before.
+    pub async fn cancel_bed_mesh_calibration(&self) -> Result<Value> {
+        self.json(Method::DELETE, "/printer/bed_mesh/calibrate", None)
+            .await
+    }
+
+    /// Input shaper settings and the progress of the last calibration.
+    pub async fn input_shaper(&self) -> Result<Value> {
+        self.get("/printer/input_shaper").await
+    }
+
+    /// Start calibrating the input shaper of `axis` in the background.
+    pub async fn calibrate_input_shaper(&self, axis: Axis) -> Result<Value> {
+        let body = json!({ "axis": axis });
+        self.json(Method::POST, "/printer/input_shaper/calibrate", Some(body))
+            .await
+    }
+
+    /// Record accelerometer samples for the running resonance test,
+    /// returning how many are kept.
+    pub async fn report_accelerometer_samples(
+        &self,
+        accelerometer: &str,
+        samples: &[AccelSample],
+    ) -> Result<usize> {
+        let path = format!("/printer/accelerometers/{accelerometer}/samples");
+        let response: Value = self
+            .json(Method::POST, &path, Some(json!({ "samples": samples })))
+            .await?;
+        decode(&Method::POST, &path, response["recorded"].clone())
+    }
+
+    /// Record a filament sensor reading, pausing the running job on runout.
+    pub async fn report_filament_sensor(
+        &self,
+        sensor: &str,
+        filament_present: bool,
+    ) -> Result<PauseStatus> {
+        let path = format!("/printer/filament_sensors/{sensor}");
+        let body = json!({ "filament_present": filament_present });
+        self.json(Method::POST, &path, Some(body)).await
+    }
+
+    /// Run G-code through the console, returning the lines it responded with.
+    pub async fn run_gcode(&self, script: &str) -> Result<Vec<String>> {
+        let path = "/printer/gcode/script";
+        let response: Value = self
+            .json(Method::POST, path, Some(json!({ "script": script })))
+            .await?;
+        decode(&Method::POST, path, response["output"].clone())
+    }
+
+    /// Macros the console can run.
+    pub async fn macros(&self) -> Result<Vec<MacroInfo>> {
+        self.get("/printer/gcode/macros").await
+    }
+
+    /// The latest crash report, if one was written.
+    pub async fn crash_report(&self) -> Result<Option<Value>> {
+        let path = "/debug/crash_report";
+        let (status, body) = self.send(Method::GET, path, |request| request).await?;
+        if status == StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+        parse(&Method::GET, path, &body).map(Some)
+    }
+
+    /// G-code jobs compiled by another compiler version, or with other
+    /// options, than the server would compile them with now.
+    pub async fn outdated_jobs(&self) -> Result<OutdatedJobs> {
+        self.get("/jobs/outdated").await
+    }
+
+    /// Hits, misses and size of the compile cache.
+    pub async fn compile_cache_stats(&self) -> Result<CompileCacheStats> {
+        self.get("/debug/compile_cache").await
+    }
+
+    /// Queued jobs and whether the queue is held.
+    pub async fn queue(&self) -> Result<QueueStatus> {
+        self.get("/queue").await
+    }
+
+    /// Change the order queued jobs print in. `jobs` must hold each queued
+    /// job once.
+    pub async fn reorder_queue(&self, jobs: &[Uuid]) -> Result<QueueStatus> {
+        self.json(Method::PUT, "/queue", Some(json!({ "jobs": jobs })))
+            .await
+    }
+
+    /// Confirm the printer is ready for the next job, releasing a held queue.
+    pub async fn confirm_queue(&self) -> Result<QueueStatus> {
+        self.json(Method::POST, "/queue/confirm", None).await
+    }
+
+    /// Stored jobs, oldest first.
+    pub async fn jobs(&self) -> Result<Vec<JobMetadata>> {
+        self.get("/jobs").await
+    }
+
+    /// Upload a new job.
+    pub async fn upload(&self, body: Vec<u8>, format: JobFormat) -> Result<UploadResponse> {
+        let path = "/jobs";
+        let (_, response) = self
+            .send(Method::POST, path, |request| {
+                request
+                    .header(reqwest::header::CONTENT_TYPE, format.content_type())
+                    .body(body)
+            })
+            .await?;
+        parse(&Method::POST, path, &response)
+    }
+
+    pub async fn job(&self, id: Uuid) -> Result<JobMetadata> {
+        self.get(&format!("/jobs/{id}")).await
+    }
+
+    /// Delete a job, returning its metadata.
+    pub async fn delete_job(&self, id: Uuid) -> Result<JobMetadata> {
+        self.json(Method::DELETE, &format!("/jobs/{id}"), None)
+            .await
+    }
+
+    pub async fn rename_job(&self, id: Uuid, name: &str) -> Result<JobMetadata> {
+        let path = format!("/jobs/{id}/rename");
+        self.json(Method::PUT, &path, Some(json!({ "name": name })))
+            .await
+    }
+
+    /// Enable or disable firmware retraction for a job that has not started.
+    pub async fn set_firmware_retraction(&self, id: Uuid, enabled: bool) -> Result<JobMetadata> {
+        let path = format!("/jobs/{id}/firmware_retraction");
+        self.json(Method::PUT, &path, Some(json!({ "enabled": enabled })))
+            .await
+    }
+
+    /// Exclude one of a job's objects, skipping its remaining moves if the
+    /// job is already printing.
+    pub async fn exclude_object(&self, id: Uuid, object: &str) -> Result<JobMetadata> {
+        let path = format!("/jobs/{id}/exclude_object");
+        self.json(Method::POST, &path, Some(json!({ "name": object })))
+            .await
+    }
+
+    pub async fn estimate(&self, id: Uuid) -> Result<Estimate> {
+        self.get(&format!("/jobs/{id}/estimate")).await
+    }
+
+    /// Sample where the toolhead would be over a job every `interval`
+    /// seconds, or the server's default, without moving the printer.
+    pub async fn dry_run(&self, id: Uuid, interval: Option<f64>) -> Result<DryRun> {
+        let path = format!("/jobs/{id}/dry_run");
+        let body = interval.map(|interval| json!({ "interval": interval }));
+        self.json(Method::POST, &path, body).await
+    }
+
+    /// Compare job `b` with job `a`: the commands, heater targets, size,
+    /// time, filament, and objects that differ.
+    pub async fn diff_jobs(&self, a: Uuid, b: Uuid) -> Result<JobDiff> {
+        self.get(&format!("/jobs/{a}/diff/{b}")).await
+    }
+
+    pub async fn preview(&self, id: Uuid) -> Result<Preview> {
+        self.get(&format!("/jobs/{id}/preview")).await
+    }
+
+    /// Layers of a G-code job's toolpath, in print order.
+    pub async fn layers(&self, id: Uuid) -> Result<Vec<LayerSummary>> {
+        self.get(&format!("/jobs/{id}/layers")).await
+    }
+
+    /// Layer `number` (1-based) of a job's toolpath as an SVG document.
+    pub async fn layer_svg(&self, id: Uuid, number: usize) -> Result<String> {
+        let path = format!("/jobs/{id}/layers/{number}.svg");
+        let (_, body) = self.send(Method::GET, &path, |request| request).await?;
+        Ok(String::from_utf8_lossy(&body).into_owned())
+    }
+
+    /// Layer `number` (1-based) of a job's toolpath as a PNG image whose
+    /// longer side is `size` pixels, or the server's default size.
+    pub async fn layer_png(&self, id: Uuid, number: usize, size: Option<u32>) -> Result<Vec<u8>> {
+        let path = format!("/jobs/{id}/layers/{number}.png");
+        let (_, body) = self
+            .send(Method::GET, &path, |request| match size {
+                Some(size) => request.query(&[("size", size)]),
+                None => request,
+            })
+            .await?;
+        Ok(body)
+    }
+
+    /// A job's planned moves as binary glTF (`.glb`), for 3D viewers.
+    pub async fn toolpath_glb(&self, id: Uuid) -> Result<Vec<u8>> {
+        let path = format!("/jobs/{id}/toolpath.glb");
+        let (_, body) = self.send(Method::GET, &path, |request| request).await?;
+        Ok(body)
+    }
+
+    /// Change a job's speed and extrusion factors or heater temperature
+    /// offsets, taking effect at once if it is printing.
+    pub async fn set_overrides(
+        &self,
+        id: Uuid,
+        update: &JobOverridesUpdate,
+    ) -> Result<JobMetadata> {
+        let path = format!("/jobs/{id}/overrides");
+        self.json(Method::POST, &path, Some(json!(update))).await
+    }
+
+    /// Queue a job for execution.
+    pub async fn enqueue(&self, id: Uuid) -> Result<JobMetadata> {
+        self.job_action(id, "enqueue").await
+    }
+
+    /// Queue a job, first resolving its template with `variables`. Variables
+    /// left out take their defaults.
+    pub async fn enqueue_with_variables(
+        &self,
+        id: Uuid,
+        variables: &Map<String, Value>,
+    ) -> Result<JobMetadata> {
+        let path = format!("/jobs/{id}/enqueue");
+        let body = json!({ "variables": variables });
+        self.json(Method::POST, &path, Some(body)).await
+    }
+
+    /// Pause an enqueued or running job.
+    pub async fn pause(&self, id: Uuid) -> Result<JobMetadata> {
+        self.job_action(id, "pause").await
+    }
+
+    /// Resume a paused job.
+    pub async fn resume(&self, id: Uuid) -> Result<JobMetadata> {
+        self.job_action(id, "resume").await
+    }
+
+    /// Cancel a job that has not finished yet.
+    pub async fn cancel(&self, id: Uuid) -> Result<JobMetadata> {
+        self.job_action(id, "cancel").await
+    }
+
+    /// Continue an interrupted job from its last power loss recovery
+    /// checkpoint, returning the enqueued job that does.
+    pub async fn resume_from_checkpoint(&self, id: Uuid) -> Result<JobMetadata> {
+        self.job_action(id, "resume_from_checkpoint").await
+    }
+
+    /// The job's metadata each time it changes, polled every `interval`,
+    /// ending once the job finishes or an error is returned.
+    ///
+    /// The server has no push endpoint, so changes between two polls that
+    /// undo each other are missed.
+    pub fn watch_job(
+        &self,
+        id: Uuid,
+        interval: Duration,
+    ) -> impl Stream<Item = Result<JobMetadata>> + '_ {
+        let state = (None::<JobMetadata>, false);
+        stream::unfold(state, move |(last, done)| async move {
+            if done {
+                return None;
+            }
+            loop {
+                if last.is_some() {
+                    tokio::time::sleep(interval).await;
+                }
+                match self.job(id).await {
+                    Ok(job) if last.as_ref() == Some(&job) => continue,
+                    Ok(job) => {
+                        let done = job.status.is_finished();
+                        return Some((Ok(job.clone()), (Some(job), done)));
+                    }
+                    Err(e) => return Some((Err(e), (last, true))),
+                }
+            }
+        })
+    }
+
+    async fn job_action(&self, id: Uuid, action: &str) -> Result<JobMetadata> {
+        self.json(Method::POST, &format!("/jobs/{id}/{action}"), None)
+            .await
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        self.json(Method::GET, path, None).await
+    }
+
+    /// Send `body` as JSON, if any, and decode the JSON response.
+    async fn json<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> Result<T> {
+        let (_, response) = self
+            .send(method.clone(), path, |request| match &body {
+                Some(body) => request.json(body),
+                None => request,
+            })
+            .await?;
+        parse(&method, path, &response)
+    }
+
+    /// Send a request, returning the status and body of a successful
+    /// response.
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        build: impl FnOnce(RequestBuilder) -> RequestBuilder,
+    ) -> Result<(StatusCode, Vec<u8>)> {
+        let mut request = self
+            .http
+            .request(method.clone(), format!("{}{path}", self.base_url));
+        if let Some(key) = &self.api_key {
+            request = request.bearer_auth(key);
+        }
+        let request_error = |source| Error::Request {
+            method: method.clone(),
+            path: path.to_string(),
+            source,
+        };
+        let response = build(request).send().await.map_err(request_error)?;
+        let status = response.status();
+        let body = response.bytes().await.map_err(request_error)?;
+        if !status.is_success() {
+            let error = serde_json::from_slice::<ErrorBody>(&body)
+                .ok()
+                .map(Box::new);
+            let message = match &error {
+                Some(error) => error.message.clone(),
+                None => String::from_utf8_lossy(&body).trim().to_string(),
+            };
+            return Err(Error::Status {
+                method,
+                path: path.to_string(),
+                status,
+                message,
+                body: error,
+            });
+        }
+        Ok((status, body.into()))
+    }
+}
+
+fn parse<T: DeserializeOwned>(method: &Method, path: &str, body: &[u8]) -> Result<T> {
+    serde_json::from_slice(body).map_err(|source| Error::Decode {
+        method: method.clone(),
+        path: path.to_string(),
+        source,
+    })
+}
+
+fn decode<T: DeserializeOwned>(method: &Method, path: &str, value: Value) -> Result<T> {
+    serde_json::from_value(value).map_err(|source| Error::Decode {
+        method: method.clone(),
+        path: path.to_string(),
+        source,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        Router,
+        extract::Path,
+        http::{HeaderMap, StatusCode as AxumStatus},
+        routing::{get, post},
+    };
+    use futures::StreamExt;
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    fn job(id: Uuid, status: JobStatus) -> JobMetadata {
+        JobMetadata {
+            id,
+            name: "benchy".to_string(),
+            original_filename: None,
+            size_bytes: 42,
+            created_at: "2025-01-01T00:00:00Z".to_string(),
+            status,
+            original_format: Some("gcode".to_string()),
+            firmware_retraction: true,
+            objects: Vec::new(),
+            excluded_objects: Vec::new(),
+            print_stats: None,
+            resumed_from: None,
+            template_variables: Vec::new(),
+            template_values: Map::new(),
+            overrides: JobOverrides::default(),
+            assets: Vec::new(),
+            build: None,
+        }
+    }
+
+    async fn serve(app: Router) -> Client {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Client::new(&format!("http://{addr}/")).with_api_key("key-1")
+    }
+
+    #[tokio::test]
+    async fn sends_requests_and_reports_errors() {
+        let app = Router::new()
+            .route(
+                "/jobs/{id}/enqueue",
+                post(|Path(id): Path<Uuid>, headers: HeaderMap| async move {
+                    if headers["authorization"] != "Bearer key-1" {
+                        return Err(AxumStatus::UNAUTHORIZED);
+                    }
+                    Ok(axum::Json(job(id, JobStatus::Enqueued)))
+                }),
+            )
+            .route(
+                "/jobs/{id}",
+                get(|| async {
+                    let body = json!({ "code": "job_not_found", "message": "Job not found" });
+                    (AxumStatus::NOT_FOUND, axum::Json(body))
+                }),
+            )
+            .route(
+                "/queue",
+                get(|| async { (AxumStatus::BAD_GATEWAY, "upstream down") }),
+            )
+            .route(
+                "/debug/crash_report",
+                get(|| async { AxumStatus::NO_CONTENT }),
+            );
+        let client = serve(app).await;
+
+        let id = Uuid::new_v4();
+        let enqueued = client.enqueue(id).await.unwrap();
+        assert_eq!((enqueued.id, enqueued.status), (id, JobStatus::Enqueued));
+
+        let err = client.job(id).await.unwrap_err();
+        assert_eq!(err.status(), Some(StatusCode::NOT_FOUND));
+        assert_eq!(
+            err.to_string(),
+            format!("GET /jobs/{id}: 404 Not Found: Job not found")
+        );
+        assert_eq!(err.code(), Some("job_not_found"));
+
+        // Bodies that are not structured errors are kept as the message
+        let err = client.queue().await.unwrap_err();
+        assert_eq!(err.code(), None);
+        assert!(err.to_string().ends_with(": upstream down"), "{err}");
+        assert!(client.crash_report().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn watches_a_job_until_it_finishes() {
+        let polls = Arc::new(AtomicUsize::new(0));
+        let app = Router::new().route(
+            "/jobs/{id}",
+            get({
+                let polls = polls.clone();
+                move |Path(id): Path<Uuid>| async move {
+                    let status = match polls.fetch_add(1, Ordering::SeqCst) {
+                        0..2 => JobStatus::Enqueued,
+                        2..4 => JobStatus::Running,
+                        _ => JobStatus::Completed,
+                    };
+                    axum::Json(job(id, status))
+                }
+            }),
+        );
+        let client = serve(app).await;
+
+        let statuses: Vec<_> = client
+            .watch_job(Uuid::new_v4(), Duration::from_millis(1))
+            .map(|job| job.unwrap().status)
+            .collect()
+            .await;
+        assert_eq!(
+            statuses,
+            [
+                JobStatus::Enqueued,
+                JobStatus::Running,
+                JobStatus::Completed
+            ]
+        );
+        assert_eq!(polls.load(Ordering::SeqCst), 5);
+    }
+}
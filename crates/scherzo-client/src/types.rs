@@ -0,0 +1,465 @@
+//! Requests and responses of the API, as they appear on the wire.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+/// Metadata of a stored job.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobMetadata {
+    pub id: Uuid,
+    pub name: String,
+    pub original_filename: Option<String>,
+    pub size_bytes: u64,
+    /// When the job was uploaded (RFC 3339).
+    pub created_at: String,
+    pub status: JobStatus,
+    /// Format the job was uploaded in, e.g. `gcode` or `wasm`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_format: Option<String>,
+    /// Whether `G10`/`G11` use the printer's firmware retraction.
+    #[serde(default = "default_true")]
+    pub firmware_retraction: bool,
+    /// Objects labelled by the slicer.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub objects: Vec<JobObject>,
+    /// Names of the objects excluded from the print.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub excluded_objects: Vec<String>,
+    /// Final statistics, once the job has printed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub print_stats: Option<PrintStats>,
+    /// Job this one continues from a power loss recovery checkpoint.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resumed_from: Option<Uuid>,
+    /// Variables declared by a G-code template, resolved at enqueue.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub template_variables: Vec<TemplateVariable>,
+    /// Values the template was last resolved with; defaults fill the rest.
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub template_values: Map<String, Value>,
+    /// Speed, extrusion, and temperature overrides, applied live while the
+    /// job prints.
+    #[serde(default)]
+    pub overrides: JobOverrides,
+    /// Files uploaded in the job's archive besides its G-code.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub assets: Vec<JobAsset>,
+    /// How the component was compiled, if the server compiled it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build: Option<JobBuild>,
+}
+
+/// What a job's component was compiled from and with.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobBuild {
+    /// Version of the compiler.
+    pub compiler_version: String,
+    /// SHA-256 of the compile options and the commands jobs could import.
+    pub options_hash: String,
+    /// SHA-256 of the G-code compiled.
+    pub input_hash: String,
+}
+
+/// Jobs that may compile differently with the server's current compiler,
+/// options, or plugin commands.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutdatedJobs {
+    /// Version of the server's compiler.
+    pub compiler_version: String,
+    pub jobs: Vec<OutdatedJob>,
+}
+
+/// A job to compile again.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutdatedJob {
+    pub id: Uuid,
+    pub name: String,
+    /// Compiler version it was compiled with, if recorded.
+    pub compiler_version: Option<String>,
+    /// Whether the compile options or commands have changed since.
+    pub options_changed: bool,
+}
+
+/// A file kept with a job, served from `/jobs/{id}/assets/{path}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobAsset {
+    /// Path in the uploaded archive.
+    pub path: String,
+    pub size_bytes: u64,
+}
+
+/// Adjustments to a job's commanded speeds, extrusion, and temperatures.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobOverrides {
+    /// Multiplies the feedrate of moves.
+    #[serde(default = "default_factor")]
+    pub speed_factor: f64,
+    /// Multiplies the extruder distance of moves.
+    #[serde(default = "default_factor")]
+    pub extrude_factor: f64,
+    /// Added to the target of each heater (°C) by name.
+    #[serde(default)]
+    pub temperature_offsets: BTreeMap<String, f64>,
+}
+
+impl Default for JobOverrides {
+    fn default() -> Self {
+        Self {
+            speed_factor: 1.0,
+            extrude_factor: 1.0,
+            temperature_offsets: BTreeMap::new(),
+        }
+    }
+}
+
+/// Changes to a job's overrides; fields left out keep their values.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct JobOverridesUpdate {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speed_factor: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extrude_factor: Option<f64>,
+    /// Offsets by heater; an offset of 0 removes the heater's.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub temperature_offsets: BTreeMap<String, f64>,
+}
+
+/// A variable of a job template.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TemplateVariable {
+    pub name: String,
+    /// Value used when the job is enqueued without one.
+    pub default: Value,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_factor() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Uploaded,
+    Enqueued,
+    Running,
+    Paused,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    /// Whether the job has stopped for good and can no longer change state.
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            JobStatus::Completed | JobStatus::Failed | JobStatus::Cancelled
+        )
+    }
+}
+
+/// An object a job prints, which can be excluded mid-print.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobObject {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub center: Option<[f64; 2]>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub polygon: Vec<[f64; 2]>,
+}
+
+/// Format of an uploaded job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobFormat {
+    /// G-code, compiled into a component by the server.
+    GCode,
+    /// An already compiled WebAssembly component.
+    Wasm,
+    /// A zip archive of G-code and assets, like thumbnails, kept with the
+    /// job.
+    Zip,
+    /// A tar archive, optionally gzipped, like [`JobFormat::Zip`].
+    Tar,
+}
+
+impl JobFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            JobFormat::GCode => "text/x-gcode",
+            JobFormat::Wasm => "application/wasm",
+            JobFormat::Zip => "application/zip",
+            JobFormat::Tar => "application/x-tar",
+        }
+    }
+}
+
+/// Response to an upload.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UploadResponse {
+    pub job_id: Uuid,
+    pub url: String,
+    /// Format the job was compiled from, if it was not a component.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compiled_from: Option<String>,
+}
+
+/// Body of an error response.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorBody {
+    /// Stable identifier of the kind of error, e.g. `job_not_found`, to
+    /// match on or translate rather than parsing `message`.
+    pub code: String,
+    /// Description of the error in English.
+    pub message: String,
+    /// Values for the message, keyed by name, for the errors that have any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<SourceLocation>,
+}
+
+/// Where in a job's G-code an error is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceLocation {
+    pub line: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub column: Option<usize>,
+}
+
+/// Estimated print time of a job.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Estimate {
+    pub estimated_seconds: f64,
+    pub estimated_duration: String,
+}
+
+/// A job's planned motion, sampled over time.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DryRun {
+    /// Seconds the job's motion takes.
+    pub duration: f64,
+    pub estimated_duration: String,
+    /// Seconds between samples; wider than requested for long jobs.
+    pub interval: f64,
+    pub samples: Vec<DryRunSample>,
+}
+
+/// Where the toolhead is at a point of a [`DryRun`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DryRunSample {
+    pub time: f64,
+    /// X/Y/Z/E position (mm).
+    pub position: [f64; 4],
+    /// Speed (mm/s) along the current move.
+    pub velocity: f64,
+}
+
+/// How two jobs differ, `a` being the one compared against.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobDiff {
+    /// Whether both run the same commands, ignoring comments and formatting.
+    pub identical: bool,
+    /// Commands only one job has, in order, or `None` if they differ by too
+    /// many to list.
+    pub changes: Option<Vec<CommandChange>>,
+    pub commands: Compared<usize>,
+    /// First targets (°C) of the heaters whose targets differ.
+    pub temperatures: Vec<TemperatureChange>,
+    pub size_bytes: Compared<u64>,
+    pub estimated_seconds: Compared<f64>,
+    pub filament_mm: Compared<f64>,
+    pub layers: Compared<usize>,
+    /// Labelled objects only `b` prints.
+    pub objects_added: Vec<String>,
+    /// Labelled objects only `a` prints.
+    pub objects_removed: Vec<String>,
+}
+
+/// A value of the two jobs of a [`JobDiff`].
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Compared<T> {
+    pub a: T,
+    pub b: T,
+}
+
+/// A command only one job of a [`JobDiff`] has.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandChange {
+    pub change: ChangeKind,
+    /// Line of the command in the job that has it.
+    pub line: usize,
+    pub command: String,
+}
+
+/// Which job of a [`JobDiff`] has a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    /// Only `a`.
+    Removed,
+    /// Only `b`.
+    Added,
+}
+
+/// A heater the jobs of a [`JobDiff`] first heat to different targets.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TemperatureChange {
+    pub heater: String,
+    pub a: Option<f64>,
+    pub b: Option<f64>,
+}
+
+/// Summary of a job's commands.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Preview {
+    pub commands_count: usize,
+    pub summary: String,
+}
+
+/// A layer of a job's toolpath.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LayerSummary {
+    /// 1-based layer number.
+    pub number: usize,
+    /// Z height the layer is printed at.
+    pub z: f64,
+    /// Number of extruding moves in the layer.
+    pub segments: usize,
+}
+
+/// What the printer is doing with a job.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PrintState {
+    #[default]
+    Standby,
+    Printing,
+    Paused,
+    Complete,
+    Cancelled,
+    Error,
+}
+
+/// Statistics for the current or last print.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PrintStats {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<Uuid>,
+    pub state: PrintState,
+    /// Seconds since the print started, including pauses.
+    pub total_duration: f64,
+    /// Seconds spent printing, excluding pauses.
+    pub print_duration: f64,
+    /// Net filament (mm) extruded.
+    pub filament_used: f64,
+    /// Layer being printed (1-based), once the first layer has started.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current_layer: Option<usize>,
+    pub total_layers: usize,
+    /// Source line of the last statement run, if the job is G-code.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current_line: Option<usize>,
+    /// Fraction (0 to 1) of the job's statements that have run.
+    pub progress: f64,
+}
+
+/// Jobs waiting to print and what the queue is doing.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueueStatus {
+    /// Enqueued jobs in the order they will print.
+    pub jobs: Vec<Uuid>,
+    pub printing: Option<Uuid>,
+    pub hold: Option<QueueHold>,
+}
+
+/// Why the queue is not starting the next job.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "reason")]
+pub enum QueueHold {
+    /// `confirm_between_jobs` is set and the last job completed.
+    Confirmation { job_id: Uuid },
+    /// The last job failed or was cancelled.
+    JobStopped { job_id: Uuid },
+    /// The between-jobs script after the last job failed.
+    ScriptFailed { job_id: Uuid, error: String },
+}
+
+/// Whether the print is paused, and the filament sensor readings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PauseStatus {
+    pub paused: bool,
+    pub reason: Option<PauseReason>,
+    /// When the print paused (RFC 3339).
+    pub paused_at: Option<String>,
+    pub sensors: BTreeMap<String, SensorState>,
+}
+
+/// Why a print paused.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PauseReason {
+    /// A filament sensor reported the filament ran out.
+    Runout { sensor: String },
+    /// Paused through the API.
+    Requested,
+}
+
+/// Last reading of a filament sensor.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SensorState {
+    pub filament_present: bool,
+    pub pause_on_runout: bool,
+    /// Times the filament ran out.
+    pub runouts: u64,
+}
+
+/// Hits, misses and size of the server's compile cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompileCacheStats {
+    /// Uploads whose compiled component was reused.
+    pub hits: u64,
+    /// Uploads compiled from scratch.
+    pub misses: u64,
+    /// Components dropped to stay within the size limit.
+    pub evictions: u64,
+    pub entries: usize,
+    pub size_bytes: u64,
+    /// Size limit, or 0 if the cache is disabled.
+    pub max_size_bytes: u64,
+}
+
+/// A macro the console can run.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MacroInfo {
+    pub name: String,
+    pub description: Option<String>,
+    /// Where the macro is defined: `config` or `plugin`.
+    pub source: String,
+}
+
+/// Axis to calibrate the input shaper of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Axis {
+    X,
+    Y,
+}
+
+/// Accelerometer reading.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AccelSample {
+    /// When the sample was taken (s), on the accelerometer's own clock.
+    pub time: f64,
+    /// Acceleration along X (mm/s^2).
+    pub x: f64,
+    /// Acceleration along Y (mm/s^2).
+    pub y: f64,
+    /// Acceleration along Z (mm/s^2).
+    pub z: f64,
+}
@@ -0,0 +1,447 @@
+//! Arc fitting for sliced G-code.
+//!
+//! Slicers export curved perimeters of a mesh as long chains of tiny `G1`
+//! segments, each costing a statement (and a host call). Like the ArcWelder
+//! post-processor, [`fit_arcs`] replaces a run of segments lying on a circle
+//! with one `G2`/`G3` arc, and a run lying on a line with one `G1`, as long
+//! as no point of the original path is further than the tolerance from the
+//! replacement.
+//!
+//! Only plain moves are fitted: `G1` with X and/or Y and optional E and F, in
+//! absolute millimeter positioning, extruding at a steady rate. Anything
+//! else, including comments, ends a run and is kept as is.
+
+use crate::estimate::{MotionInterpreter, param, verb};
+use scherzo_gcode::{Statement, Value, parse};
+use std::f64::consts::TAU;
+
+/// Longest run of segments considered for one replacement.
+const MAX_RUN: usize = 500;
+
+/// Limits on the moves [`fit_arcs`] may replace.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ArcFitConfig {
+    /// Largest distance (mm) of the original path from its replacement.
+    pub tolerance: f64,
+    /// Fewest segments replaced by one arc.
+    pub min_arc_segments: usize,
+    /// Largest arc radius (mm). Flatter curves are only merged into lines.
+    pub max_radius: f64,
+    /// Largest relative difference in extrusion per mm between the segments
+    /// of a run.
+    pub flow_tolerance: f64,
+}
+
+impl Default for ArcFitConfig {
+    fn default() -> Self {
+        Self {
+            tolerance: 0.05,
+            min_arc_segments: 3,
+            max_radius: 1000.0,
+            flow_tolerance: 0.05,
+        }
+    }
+}
+
+/// What [`fit_arcs`] changed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ArcFitStats {
+    pub statements_before: usize,
+    pub statements_after: usize,
+    /// `G2`/`G3` arcs written.
+    pub arcs: usize,
+    /// `G1` moves written in place of colinear runs.
+    pub lines: usize,
+}
+
+impl ArcFitStats {
+    /// Render the statistics as JSON.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "statements_before": self.statements_before,
+            "statements_after": self.statements_after,
+            "arcs": self.arcs,
+            "lines": self.lines,
+        })
+    }
+}
+
+/// Replace runs of short `G1` segments in `statements` with arcs and lines.
+/// Replacements keep the source line of the first segment they replace.
+pub fn fit_arcs(statements: &[Statement], config: &ArcFitConfig) -> (Vec<Statement>, ArcFitStats) {
+    let mut interp = MotionInterpreter::new();
+    let mut out = Vec::with_capacity(statements.len());
+    let mut stats = ArcFitStats {
+        statements_before: statements.len(),
+        ..ArcFitStats::default()
+    };
+    let mut index = 0;
+    while index < statements.len() {
+        if let Some(fit) = fit_run(&interp, &statements[index..], config) {
+            for stmt in &statements[index..index + fit.segments] {
+                interp.interpret(stmt);
+            }
+            match fit.shape {
+                Shape::Line => stats.lines += 1,
+                Shape::Arc(_) => stats.arcs += 1,
+            }
+            out.push(fit.statement);
+            index += fit.segments;
+        } else {
+            interp.interpret(&statements[index]);
+            out.push(statements[index].clone());
+            index += 1;
+        }
+    }
+    stats.statements_after = out.len();
+    (out, stats)
+}
+
+/// Render `statements` back to G-code, one per line.
+pub fn to_gcode(statements: &[Statement]) -> String {
+    let mut out = String::new();
+    for stmt in statements {
+        out.push_str(stmt.raw.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FittedArc {
+    center: [f64; 2],
+    clockwise: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Shape {
+    Line,
+    Arc(FittedArc),
+}
+
+/// Replacement for the first `segments` statements of a run.
+struct Fit {
+    segments: usize,
+    shape: Shape,
+    statement: Statement,
+}
+
+/// Longest replacement for the run of plain moves at the start of
+/// `statements`, if it replaces more than one statement.
+fn fit_run(
+    interp: &MotionInterpreter,
+    statements: &[Statement],
+    config: &ArcFitConfig,
+) -> Option<Fit> {
+    if !interp.is_absolute() || interp.is_inches() {
+        return None;
+    }
+    let feed = param(statements.first()?, 'F');
+    let mut sim = interp.clone();
+    let mut points = vec![sim.position()];
+    let mut line = None;
+    let mut arc = None;
+    for (index, stmt) in statements.iter().enumerate().take(MAX_RUN) {
+        if !is_plain_move(stmt) || (index > 0 && param(stmt, 'F').is_some_and(|f| Some(f) != feed))
+        {
+            break;
+        }
+        sim.interpret(stmt);
+        let point = sim.position();
+        if point[..2] == points[points.len() - 1][..2] {
+            break;
+        }
+        points.push(point);
+        if !steady_flow(&points, config.flow_tolerance) {
+            break;
+        }
+
+        let segments = index + 1;
+        let fits_line = is_line(&points, config.tolerance);
+        let fitted_arc = fit_arc(&points, config);
+        if fits_line {
+            line = Some(segments);
+        }
+        if let Some(fitted) = fitted_arc
+            && segments >= config.min_arc_segments
+        {
+            arc = Some((segments, fitted));
+        }
+        if !fits_line && fitted_arc.is_none() {
+            break;
+        }
+    }
+
+    let line = line.filter(|&segments| segments > 1);
+    let (segments, shape) = match (line, arc) {
+        (Some(line), Some((segments, arc))) if segments > line => (segments, Shape::Arc(arc)),
+        (Some(line), _) => (line, Shape::Line),
+        (None, Some((segments, arc))) => (segments, Shape::Arc(arc)),
+        (None, None) => return None,
+    };
+    let run = &statements[..segments];
+    let text = render(interp, run, &points[..=segments], shape, feed);
+    let mut statement = parse(&format!("{text}\n")).ok()?.into_iter().next()?;
+    statement.line = run[0].line;
+    Some(Fit {
+        segments,
+        shape,
+        statement,
+    })
+}
+
+/// Whether `stmt` is a `G1` with only numeric X, Y, E, and F words, moving
+/// X or Y.
+fn is_plain_move(stmt: &Statement) -> bool {
+    if verb(stmt).as_deref() != Some("G1") || stmt.comment.is_some() || stmt.checksum.is_some() {
+        return false;
+    }
+    let mut moves_xy = false;
+    for word in &stmt.words[1..] {
+        let Some(letter) = word.letter.map(|l| l.to_ascii_uppercase()) else {
+            return false;
+        };
+        if word.name.is_some() || !matches!(word.value, Some(Value::Number(_))) {
+            return false;
+        }
+        match letter {
+            'X' | 'Y' => moves_xy = true,
+            'E' | 'F' => {}
+            _ => return false,
+        }
+    }
+    moves_xy
+}
+
+/// Whether every segment extrudes at the rate of the whole run, or none
+/// extrudes at all.
+fn steady_flow(points: &[[f64; 4]], tolerance: f64) -> bool {
+    let xy = |a: &[f64; 4], b: &[f64; 4]| (b[0] - a[0]).hypot(b[1] - a[1]);
+    let length: f64 = points.windows(2).map(|w| xy(&w[0], &w[1])).sum();
+    let first = points[0];
+    let last = points[points.len() - 1];
+    let rate = (last[3] - first[3]) / length;
+    points.windows(2).all(|w| {
+        let segment = (w[1][3] - w[0][3]) / xy(&w[0], &w[1]);
+        (segment - rate).abs() <= tolerance * rate.abs()
+    })
+}
+
+/// Whether every point lies within `tolerance` of the straight path from the
+/// first to the last, in order.
+fn is_line(points: &[[f64; 4]], tolerance: f64) -> bool {
+    let first = points[0];
+    let last = points[points.len() - 1];
+    let dir = [last[0] - first[0], last[1] - first[1]];
+    let length = dir[0].hypot(dir[1]);
+    let mut along = 0.0;
+    points.iter().all(|p| {
+        let d = [p[0] - first[0], p[1] - first[1]];
+        let t = (d[0] * dir[0] + d[1] * dir[1]) / length;
+        let off = (d[0] * dir[1] - d[1] * dir[0]).abs() / length;
+        let ok = off <= tolerance && t >= along - tolerance && t <= length + tolerance;
+        along = along.max(t);
+        ok
+    })
+}
+
+/// Circle through the first, middle, and last points, if every point lies
+/// within the tolerance of it, every segment's chord stays within the
+/// tolerance of the arc, and the points turn one way for less than a turn.
+fn fit_arc(points: &[[f64; 4]], config: &ArcFitConfig) -> Option<FittedArc> {
+    if points.len() < 3 {
+        return None;
+    }
+    let a = points[0];
+    let b = points[points.len() / 2];
+    let c = points[points.len() - 1];
+    let d = 2.0 * (a[0] * (b[1] - c[1]) + b[0] * (c[1] - a[1]) + c[0] * (a[1] - b[1]));
+    if d.abs() < f64::EPSILON {
+        return None;
+    }
+    let sq = |p: [f64; 4]| p[0] * p[0] + p[1] * p[1];
+    let center = [
+        (sq(a) * (b[1] - c[1]) + sq(b) * (c[1] - a[1]) + sq(c) * (a[1] - b[1])) / d,
+        (sq(a) * (c[0] - b[0]) + sq(b) * (a[0] - c[0]) + sq(c) * (b[0] - a[0])) / d,
+    ];
+    let radius = (a[0] - center[0]).hypot(a[1] - center[1]);
+    if radius > config.max_radius {
+        return None;
+    }
+
+    let clockwise = d < 0.0;
+    let mut swept = 0.0;
+    for pair in points.windows(2) {
+        let r0 = [pair[0][0] - center[0], pair[0][1] - center[1]];
+        let r1 = [pair[1][0] - center[0], pair[1][1] - center[1]];
+        let step = (r0[0] * r1[1] - r0[1] * r1[0]).atan2(r0[0] * r1[0] + r0[1] * r1[1]);
+        let sagitta = radius * (1.0 - (step / 2.0).cos());
+        if (step < 0.0) != clockwise
+            || step == 0.0
+            || sagitta > config.tolerance
+            || (r1[0].hypot(r1[1]) - radius).abs() > config.tolerance
+        {
+            return None;
+        }
+        swept += step.abs();
+    }
+    (swept < TAU - 1e-6).then_some(FittedArc { center, clockwise })
+}
+
+/// G-code replacing `run`, which moves through `points` (machine
+/// coordinates, starting before the run).
+fn render(
+    interp: &MotionInterpreter,
+    run: &[Statement],
+    points: &[[f64; 4]],
+    shape: Shape,
+    feed: Option<f64>,
+) -> String {
+    let base = interp.offsets();
+    let start = points[0];
+    let end = points[points.len() - 1];
+    let mut text = match shape {
+        Shape::Line => "G1".to_string(),
+        Shape::Arc(arc) if arc.clockwise => "G2".to_string(),
+        Shape::Arc(_) => "G3".to_string(),
+    };
+    text.push_str(&format!(
+        " X{} Y{}",
+        number(end[0] - base[0], 4),
+        number(end[1] - base[1], 4)
+    ));
+    if let Shape::Arc(arc) = shape {
+        text.push_str(&format!(
+            " I{} J{}",
+            number(arc.center[0] - start[0], 4),
+            number(arc.center[1] - start[1], 4)
+        ));
+    }
+    if run.iter().any(|stmt| param(stmt, 'E').is_some()) {
+        let e = if interp.is_absolute_e() {
+            end[3] - base[3]
+        } else {
+            end[3] - start[3]
+        };
+        text.push_str(&format!(" E{}", number(e, 5)));
+    }
+    if let Some(feed) = feed {
+        text.push_str(&format!(" F{}", number(feed, 3)));
+    }
+    text
+}
+
+/// Format `value` with at most `decimals` decimals and no trailing zeros.
+fn number(value: f64, decimals: usize) -> String {
+    let text = format!("{value:.decimals$}");
+    let text = text.trim_end_matches('0').trim_end_matches('.');
+    match text {
+        "-0" => "0".to_string(),
+        text => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::estimate::{MotionCommand, MotionInterpreter};
+
+    /// G-code tracing `segments` chords of a circle of `radius` around
+    /// (100, 100), extruding 0.05 mm of filament per mm.
+    fn circle(radius: f64, segments: usize, turn: f64) -> String {
+        let mut out = format!("G28\nG92 E0\nG1 X{} Y100 F3000\n", 100.0 + radius);
+        let step = turn / segments as f64;
+        let chord = 2.0 * radius * (step / 2.0).sin();
+        for i in 1..=segments {
+            let angle = step * i as f64;
+            out.push_str(&format!(
+                "G1 X{:.4} Y{:.4} E{:.5}\n",
+                100.0 + radius * angle.cos(),
+                100.0 + radius * angle.sin(),
+                0.05 * chord * i as f64
+            ));
+        }
+        out
+    }
+
+    /// Toolhead positions visited by `statements`.
+    fn path(statements: &[Statement]) -> Vec<[f64; 4]> {
+        let mut interp = MotionInterpreter::new();
+        let mut points = Vec::new();
+        for stmt in statements {
+            for cmd in interp.interpret(stmt) {
+                if let MotionCommand::Move { target, .. } = cmd {
+                    points.push(target);
+                }
+            }
+        }
+        points
+    }
+
+    #[test]
+    fn dense_circle_becomes_a_few_arcs() {
+        let source = circle(20.0, 360, 1.5 * std::f64::consts::PI);
+        let statements = parse(&source).unwrap();
+        let (fitted, stats) = fit_arcs(&statements, &ArcFitConfig::default());
+        assert!(
+            stats.statements_after * 10 <= stats.statements_before,
+            "{stats:?}"
+        );
+        assert_eq!(stats.arcs, 1);
+        assert_eq!(to_gcode(&fitted).lines().nth(3).unwrap(), fitted[3].raw);
+        assert!(fitted[3].raw.starts_with("G3 "), "{}", fitted[3].raw);
+        assert_eq!(fitted[3].line, statements[3].line);
+
+        // Ends at the same place, and never strays from the original path
+        let before = path(&statements);
+        let after = path(&fitted);
+        let end = |points: &[[f64; 4]]| points[points.len() - 1];
+        for axis in 0..4 {
+            assert!((end(&before)[axis] - end(&after)[axis]).abs() < 1e-4);
+        }
+        for point in &after {
+            let radius = (point[0] - 100.0).hypot(point[1] - 100.0);
+            assert!((radius - 20.0).abs() < 0.05, "{point:?}");
+        }
+    }
+
+    #[test]
+    fn colinear_segments_merge_into_one_move() {
+        let source =
+            "G1 X0 Y0 F1200\nM83\nG1 X1 Y1 E0.1\nG1 X2 Y2 E0.1\nG1 X3 Y3 E0.1\nG1 X3 Y10 E0.7\n";
+        let statements = parse(source).unwrap();
+        let (fitted, stats) = fit_arcs(&statements, &ArcFitConfig::default());
+        assert_eq!(stats.lines, 1);
+        assert_eq!(
+            to_gcode(&fitted),
+            "G1 X0 Y0 F1200\nM83\nG1 X3 Y3 E0.3\nG1 X3 Y10 E0.7\n"
+        );
+    }
+
+    #[test]
+    fn keeps_moves_that_cannot_be_fitted() {
+        let config = ArcFitConfig::default();
+        // A flow change, a Z move, a comment, and relative positioning each
+        // end the run.
+        for source in [
+            "G1 X1 E1\nG1 X2 E3\nG1 X3 E3.5\n",
+            "G1 X1\nG1 X2 Z1\nG1 X3\n",
+            "G1 X1\nG1 X2 ; seam\nG1 X3\n",
+            "G91\nG1 X1\nG1 X1\nG1 X1\n",
+        ] {
+            let statements = parse(source).unwrap();
+            let (fitted, stats) = fit_arcs(&statements, &config);
+            assert_eq!(fitted, statements, "{source}");
+            assert_eq!(stats.statements_after, stats.statements_before);
+        }
+
+        // Points further from the circle than the tolerance
+        let source = circle(20.0, 360, 1.5 * std::f64::consts::PI);
+        let strict = ArcFitConfig {
+            tolerance: 1e-6,
+            ..config
+        };
+        let (_, stats) = fit_arcs(&parse(&source).unwrap(), &strict);
+        assert_eq!(stats.arcs, 0);
+    }
+}
@@ -0,0 +1,537 @@
+//! Decompilation of job components back into G-code.
+//!
+//! The compiler lowers every statement into a fixed call sequence inside the
+//! exported `run` function: a builder constructor, one setter per parameter
+//! (with constant arguments, strings and lists living in the data segments)
+//! and a final `submit`. Walking that sequence recovers the original program
+//! up to formatting, comments and words the compiler ignores.
+//!
+//! Jobs compiled with [`InterfaceShape::Batched`](crate::InterfaceShape)
+//! instead pass runs of statements to `submit` as lists of records. Reading
+//! those needs the record layouts, which come from the WIT embedded in the
+//! component, so they only decompile from the component.
+
+use anyhow::{Context, Result, anyhow, bail};
+use ryu::Buffer;
+use std::collections::HashMap;
+use wasmparser::{DataKind, Operator, Parser, Payload, TypeRef};
+use wit_component::WitPrinter;
+use wit_parser::{Int, Resolve, SizeAlign, TypeDefKind, WorldId};
+
+/// Recover G-code from a compiled job (component or core module).
+pub fn decompile(bytes: &[u8]) -> Result<String> {
+    let module = job_module(bytes)?;
+    let mut job = JobModule::parse(module)?;
+    if Parser::is_component(bytes) {
+        let (resolve, _) = decode_wit(bytes)?;
+        job.records = record_layouts(&resolve)?;
+    }
+    let mut out = String::new();
+    for stmt in job.statements()? {
+        out.push_str(&stmt);
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+/// Render the WIT embedded in a compiled job (component or core module).
+pub fn extract_wit(bytes: &[u8]) -> Result<String> {
+    let (resolve, world) = decode_wit(bytes)?;
+
+    // Print the package the job's interfaces live in rather than the
+    // synthesized root package of the component.
+    let pkg = resolve
+        .packages
+        .iter()
+        .find(|(_, pkg)| pkg.name.namespace == "job" && pkg.name.name == "print")
+        .map(|(id, _)| id)
+        .or(resolve.worlds[world].package)
+        .ok_or_else(|| anyhow!("no WIT package found"))?;
+
+    let mut printer = WitPrinter::default();
+    printer.print(&resolve, pkg, &[])?;
+    Ok(printer.output.to_string())
+}
+
+fn decode_wit(bytes: &[u8]) -> Result<(Resolve, WorldId)> {
+    Ok(if Parser::is_component(bytes) {
+        match wit_parser::decoding::decode(bytes).context("failed to decode component")? {
+            wit_parser::decoding::DecodedWasm::Component(resolve, world) => (resolve, world),
+            wit_parser::decoding::DecodedWasm::WitPackage(..) => {
+                bail!("input is a WIT package, not a job component")
+            }
+        }
+    } else {
+        let (_, bindgen) =
+            wit_component::metadata::decode(bytes).context("failed to decode module metadata")?;
+        (bindgen.resolve, bindgen.world)
+    })
+}
+
+/// Layout of the `statement` records a batched `submit` takes.
+struct RecordLayout {
+    size: usize,
+    /// Parameter name, kind, offset of the `option` and of its payload.
+    fields: Vec<(String, Kind, usize, usize)>,
+}
+
+/// Record layouts of the batched interfaces in `resolve`, by interface.
+fn record_layouts(resolve: &Resolve) -> Result<HashMap<String, RecordLayout>> {
+    let mut sizes = SizeAlign::default();
+    sizes.fill(resolve);
+    let mut layouts = HashMap::new();
+    for (id, iface) in &resolve.interfaces {
+        let Some(submit) = iface.functions.get("submit") else {
+            continue;
+        };
+        let Some((_, wit_parser::Type::Id(list))) = submit.params.first() else {
+            continue;
+        };
+        let TypeDefKind::List(record_ty @ wit_parser::Type::Id(record)) = resolve.types[*list].kind
+        else {
+            continue;
+        };
+        let TypeDefKind::Record(record) = &resolve.types[record].kind else {
+            bail!(
+                "batched `submit` takes a list of {:?}",
+                resolve.types[record].kind
+            );
+        };
+
+        let offsets = sizes.field_offsets(record.fields.iter().map(|field| &field.ty));
+        let mut fields = Vec::new();
+        for (field, (offset, ty)) in record.fields.iter().zip(offsets) {
+            let payload = match ty {
+                wit_parser::Type::Id(option) => match &resolve.types[*option].kind {
+                    TypeDefKind::Option(inner) => sizes.payload_offset(Int::U8, [Some(inner)]),
+                    other => bail!("record field `{}` is a {other:?}", field.name),
+                },
+                other => bail!("record field `{}` is a {other:?}", field.name),
+            };
+            let (param, kind) = split_kind(&field.name)?;
+            fields.push((
+                param.to_string(),
+                kind,
+                offset.size_wasm32(),
+                payload.size_wasm32(),
+            ));
+        }
+        let name = resolve
+            .id_of(id)
+            .ok_or_else(|| anyhow!("unnamed interface"))?;
+        let size = sizes.size(&record_ty).size_wasm32();
+        layouts.insert(name, RecordLayout { size, fields });
+    }
+    Ok(layouts)
+}
+
+/// Locate the core module holding the job's `run` function.
+fn job_module(bytes: &[u8]) -> Result<&[u8]> {
+    if !Parser::is_component(bytes) {
+        return Ok(bytes);
+    }
+
+    for payload in Parser::new(0).parse_all(bytes) {
+        if let Payload::ModuleSection {
+            unchecked_range, ..
+        } = payload.context("failed to parse component")?
+        {
+            let module = &bytes[unchecked_range];
+            if exports_run(module)? {
+                return Ok(module);
+            }
+        }
+    }
+
+    bail!("component does not contain a job module exporting `run`")
+}
+
+fn exports_run(module: &[u8]) -> Result<bool> {
+    for payload in Parser::new(0).parse_all(module) {
+        match payload? {
+            Payload::ExportSection(reader) => {
+                for export in reader {
+                    if export?.name == "run" {
+                        return Ok(true);
+                    }
+                }
+            }
+            Payload::CodeSectionStart { .. } => break,
+            _ => {}
+        }
+    }
+    Ok(false)
+}
+
+/// The pieces of a compiled job module needed to replay `run`.
+struct JobModule<'a> {
+    /// Imported functions in index order as `(interface, name)`.
+    imports: Vec<(&'a str, &'a str)>,
+    run_index: u32,
+    bodies: Vec<wasmparser::FunctionBody<'a>>,
+    memory: Vec<u8>,
+    /// Records of batched interfaces, when the job's WIT is known.
+    records: HashMap<String, RecordLayout>,
+}
+
+impl<'a> JobModule<'a> {
+    fn parse(module: &'a [u8]) -> Result<Self> {
+        let mut imports = Vec::new();
+        let mut run_index = None;
+        let mut bodies = Vec::new();
+        let mut memory = Vec::new();
+
+        for payload in Parser::new(0).parse_all(module) {
+            match payload.context("failed to parse job module")? {
+                Payload::ImportSection(reader) => {
+                    for import in reader {
+                        let import = import?;
+                        if matches!(import.ty, TypeRef::Func(_)) {
+                            imports.push((import.module, import.name));
+                        }
+                    }
+                }
+                Payload::ExportSection(reader) => {
+                    for export in reader {
+                        let export = export?;
+                        if export.name == "run" {
+                            run_index = Some(export.index);
+                        }
+                    }
+                }
+                Payload::CodeSectionEntry(body) => bodies.push(body),
+                Payload::DataSection(reader) => {
+                    for data in reader {
+                        let data = data?;
+                        let DataKind::Active { offset_expr, .. } = data.kind else {
+                            continue;
+                        };
+                        let offset = match offset_expr.get_operators_reader().read()? {
+                            Operator::I32Const { value } => value as usize,
+                            other => bail!("unsupported data segment offset {other:?}"),
+                        };
+                        let end = offset + data.data.len();
+                        if memory.len() < end {
+                            memory.resize(end, 0);
+                        }
+                        memory[offset..end].copy_from_slice(data.data);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            imports,
+            run_index: run_index.ok_or_else(|| anyhow!("module does not export `run`"))?,
+            bodies,
+            memory,
+            records: HashMap::new(),
+        })
+    }
+
+    /// Replay the call sequence in `run`, rendering one G-code line per builder.
+    fn statements(&self) -> Result<Vec<String>> {
+        let defined = self
+            .run_index
+            .checked_sub(self.imports.len() as u32)
+            .ok_or_else(|| anyhow!("`run` export refers to an import"))?;
+        let body = self
+            .bodies
+            .get(defined as usize)
+            .ok_or_else(|| anyhow!("missing body for `run`"))?;
+
+        let mut out = Vec::new();
+        let mut current: Option<String> = None;
+        let mut consts: Vec<Const> = Vec::new();
+        let mut interfaces: HashMap<&str, String> = HashMap::new();
+
+        let mut reader = body.get_operators_reader()?;
+        while !reader.eof() {
+            match reader.read()? {
+                Operator::I32Const { value } => consts.push(Const::I32(value)),
+                Operator::I64Const { value } => consts.push(Const::I64(value)),
+                Operator::F64Const { value } => consts.push(Const::F64(f64::from(value))),
+                Operator::Call { function_index } => {
+                    let (interface, name) = *self
+                        .imports
+                        .get(function_index as usize)
+                        .ok_or_else(|| anyhow!("call to non-imported function {function_index}"))?;
+                    let args = std::mem::take(&mut consts);
+
+                    if name.starts_with("[constructor]") {
+                        let verb = interfaces
+                            .entry(interface)
+                            .or_insert_with(|| verb_from_interface(interface));
+                        current = Some(verb.clone());
+                    } else if name == "[method]builder.submit" {
+                        out.push(
+                            current
+                                .take()
+                                .ok_or_else(|| anyhow!("submit without a builder"))?,
+                        );
+                    } else if name == "submit" {
+                        let verb = interfaces
+                            .entry(interface)
+                            .or_insert_with(|| verb_from_interface(interface));
+                        self.render_batch(interface, verb, &args, &mut out)?;
+                    } else if let Some(setter) = name.strip_prefix("[method]builder.set-") {
+                        let line = current
+                            .as_mut()
+                            .ok_or_else(|| anyhow!("setter `{name}` without a builder"))?;
+                        let word = self.render_word(setter, &args)?;
+                        line.push(' ');
+                        line.push_str(&word);
+                    } else {
+                        bail!("unexpected call to `{interface}#{name}`");
+                    }
+                }
+                Operator::LocalGet { .. } | Operator::LocalSet { .. } | Operator::End => {}
+                other => bail!("unexpected instruction in `run`: {other:?}"),
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Render the statements of one batched `submit` call to `interface`.
+    fn render_batch(
+        &self,
+        interface: &str,
+        verb: &str,
+        args: &[Const],
+        out: &mut Vec<String>,
+    ) -> Result<()> {
+        let (ptr, count) = match *args {
+            [Const::I32(count)] => {
+                out.extend(std::iter::repeat_n(verb.to_string(), count as usize));
+                return Ok(());
+            }
+            [Const::I32(ptr), Const::I32(count)] => (ptr as usize, count as usize),
+            _ => bail!("unexpected arguments for `{interface}#submit`"),
+        };
+        let layout = self.records.get(interface).ok_or_else(|| {
+            anyhow!("batched calls to `{interface}` need the job's WIT; decompile the component")
+        })?;
+
+        for index in 0..count {
+            let base = ptr + index * layout.size;
+            let mut line = verb.to_string();
+            for (param, kind, offset, payload) in &layout.fields {
+                let at = |extra: usize, len: usize| {
+                    self.read((base + offset + extra) as u32, len as u32)
+                };
+                if at(0, 1)?[0] == 0 {
+                    continue;
+                }
+                let word = |len| -> Result<[u8; 8]> { Ok(at(*payload, len)?.try_into()?) };
+                let args = match kind {
+                    Kind::Int => vec![Const::I64(i64::from_le_bytes(word(8)?))],
+                    Kind::Float => vec![Const::F64(f64::from_le_bytes(word(8)?))],
+                    _ => {
+                        let b = word(8)?;
+                        let ptr = i32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+                        let len = i32::from_le_bytes([b[4], b[5], b[6], b[7]]);
+                        vec![Const::I32(ptr), Const::I32(len)]
+                    }
+                };
+                let setter = format!("{param}{}", kind_suffix(*kind));
+                line.push(' ');
+                line.push_str(&self.render_word(&setter, &args)?);
+            }
+            out.push(line);
+        }
+        Ok(())
+    }
+
+    fn render_word(&self, setter: &str, args: &[Const]) -> Result<String> {
+        let (param, kind) = split_kind(setter)?;
+
+        let value = match (kind, args) {
+            (Kind::Int, [Const::I64(i)]) => i.to_string(),
+            (Kind::Float, [Const::F64(f)]) => format_float(*f),
+            (Kind::String, [Const::I32(ptr), Const::I32(len)]) => {
+                quote(&self.read_str(*ptr as u32, *len as u32)?)
+            }
+            (Kind::ListInt, [Const::I32(ptr), Const::I32(len)]) => self
+                .read_words(*ptr as u32, *len as u32)?
+                .map(|b| i64::from_le_bytes(b).to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+            (Kind::ListFloat, [Const::I32(ptr), Const::I32(len)]) => self
+                .read_words(*ptr as u32, *len as u32)?
+                .map(|b| format_float(f64::from_le_bytes(b)))
+                .collect::<Vec<_>>()
+                .join(","),
+            (Kind::ListString, [Const::I32(ptr), Const::I32(len)]) => {
+                let mut items = Vec::new();
+                for b in self.read_words(*ptr as u32, *len as u32)? {
+                    let offset = u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+                    let len = u32::from_le_bytes([b[4], b[5], b[6], b[7]]);
+                    items.push(quote(&self.read_str(offset, len)?));
+                }
+                items.join(",")
+            }
+            _ => bail!("unexpected arguments for setter `set-{setter}`"),
+        };
+
+        // Lists only lex back as `NAME=a,b` parameters
+        let is_list = matches!(kind, Kind::ListInt | Kind::ListFloat | Kind::ListString);
+        let mut chars = param.chars();
+        Ok(match (chars.next(), chars.next()) {
+            (Some(letter), None) if !is_list => format!("{}{value}", letter.to_ascii_uppercase()),
+            _ => format!("{}={value}", param.to_uppercase().replace('-', "_")),
+        })
+    }
+
+    fn read(&self, offset: u32, len: u32) -> Result<&[u8]> {
+        let start = offset as usize;
+        let end = start + len as usize;
+        self.memory
+            .get(start..end)
+            .ok_or_else(|| anyhow!("data reference {start}..{end} is out of bounds"))
+    }
+
+    fn read_str(&self, offset: u32, len: u32) -> Result<String> {
+        let bytes = self.read(offset, len)?;
+        String::from_utf8(bytes.to_vec()).context("string literal is not UTF-8")
+    }
+
+    fn read_words(&self, offset: u32, count: u32) -> Result<impl Iterator<Item = [u8; 8]> + '_> {
+        let bytes = self.read(offset, count * 8)?;
+        Ok(bytes
+            .chunks_exact(8)
+            .map(|chunk| chunk.try_into().expect("8 byte chunk")))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Const {
+    I32(i32),
+    I64(i64),
+    F64(f64),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Int,
+    Float,
+    String,
+    ListInt,
+    ListFloat,
+    ListString,
+}
+
+/// Setter suffixes, longest first so `-list-int` wins over `-int`.
+const KINDS: &[(&str, Kind)] = &[
+    ("-list-string", Kind::ListString),
+    ("-list-float", Kind::ListFloat),
+    ("-list-int", Kind::ListInt),
+    ("-string", Kind::String),
+    ("-float", Kind::Float),
+    ("-int", Kind::Int),
+];
+
+/// Split a setter or record field name like `x-float` into its parameter
+/// and kind.
+fn split_kind(name: &str) -> Result<(&str, Kind)> {
+    KINDS
+        .iter()
+        .find_map(|(suffix, kind)| Some((name.strip_suffix(suffix)?, *kind)))
+        .ok_or_else(|| anyhow!("unrecognized setter `set-{name}`"))
+}
+
+fn kind_suffix(kind: Kind) -> &'static str {
+    KINDS
+        .iter()
+        .find(|(_, k)| std::mem::discriminant(k) == std::mem::discriminant(&kind))
+        .map_or("", |(suffix, _)| suffix)
+}
+
+/// Undo the verb normalization, e.g. `g1` -> `G1` and `g1-0` -> `G1.0`.
+fn verb_from_interface(interface: &str) -> String {
+    let name = interface.rsplit('/').next().unwrap_or(interface);
+    let mut chars = name.chars();
+    let is_letter_code = chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.as_str().split('-').count() <= 2
+        && chars
+            .as_str()
+            .split('-')
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()));
+    if is_letter_code {
+        name.to_uppercase().replacen('-', ".", 1)
+    } else {
+        name.to_uppercase().replace('-', "_")
+    }
+}
+
+fn format_float(f: f64) -> String {
+    Buffer::new().format(f).to_string()
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{s}\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CompileOptions, InterfaceShape, compile_gcode, compile_gcode_with};
+
+    #[test]
+    fn round_trips_through_component() {
+        let input =
+            "G28\nG1 X1.5 Y-2 F3000\nM104 S200\nG1.0 X1\nSET_PIN PIN=\"fan\" VALUES=1,2,3\n";
+        let out = compile_gcode(input).unwrap();
+        let gcode = decompile(&out.component).unwrap();
+        assert_eq!(gcode, "G28\nG1 X1.5 Y-2 F3000\nM104 S200\nG1.0 X1\n");
+        assert_eq!(decompile(&out.wasm).unwrap(), gcode);
+    }
+
+    #[test]
+    fn keeps_object_markers() {
+        let input = "EXCLUDE_OBJECT_DEFINE NAME=part_1 CENTER=10,20\n\
+                     EXCLUDE_OBJECT_START NAME=part_1\nG1 X1\nEXCLUDE_OBJECT_END\n\
+                     EXCLUDE_OBJECT NAME=part_1\n";
+        let out = compile_gcode(input).unwrap();
+        let gcode = decompile(&out.component).unwrap();
+        assert_eq!(
+            gcode,
+            "EXCLUDE_OBJECT_START NAME=\"part_1\"\nG1 X1\nEXCLUDE_OBJECT_END\n\
+             EXCLUDE_OBJECT NAME=\"part_1\"\n"
+        );
+    }
+
+    #[test]
+    fn decompiles_strings_and_lists() {
+        let input = "M118 P\"hello world\" L=1.5,2.5 N=4,5\n";
+        let out = compile_gcode(input).unwrap();
+        let gcode = decompile(&out.component).unwrap();
+        assert_eq!(gcode, "M118 P\"hello world\" L=1.5,2.5 N=4,5\n");
+    }
+
+    #[test]
+    fn decodes_batched_records() {
+        let options = CompileOptions {
+            interface_shape: InterfaceShape::Batched,
+            ..CompileOptions::default()
+        };
+        let input = "G28\nG28\nG1 Y-2 X1.5\nG1 X3\nM104 S200\nM118 P\"hi\" L=1.5,2.5\n";
+        let out = compile_gcode_with(input, &options).unwrap();
+        let gcode = decompile(&out.component).unwrap();
+        assert_eq!(
+            gcode,
+            "G28\nG28\nG1 X1.5 Y-2\nG1 X3\nM104 S200\nM118 L=1.5,2.5 P\"hi\"\n"
+        );
+        assert!(decompile(&out.wasm).is_err());
+    }
+
+    #[test]
+    fn extracts_embedded_wit() {
+        let out = compile_gcode("G1 X1\n").unwrap();
+        let wit = extract_wit(&out.component).unwrap();
+        assert!(wit.contains("package job:print"));
+        assert!(wit.contains("interface g1"));
+        assert!(wit.contains("set-x-int"));
+    }
+}
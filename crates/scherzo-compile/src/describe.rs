@@ -0,0 +1,145 @@
+//! Reflecting over what a compiled job needs from its host.
+//!
+//! Every job carries a `scherzo:describe` custom section listing the
+//! interfaces it imports, the verb each one submits, and the WIT types each
+//! parameter is set with. A runtime or plugin can read it to check a job's
+//! requirements without decoding and walking the job's WIT. Parameter names
+//! are interned: each is stored once in [`Description::names`] and the
+//! interfaces refer to it by index.
+
+use crate::{InterfaceShape, VerbShape, import_module_name, wasm_util, wit_type_name};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// Name of the custom section holding a job's [`Description`].
+pub const SECTION: &str = "scherzo:describe";
+
+/// Interfaces a job imports and the parameters each takes.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Description {
+    /// How the interfaces take statements.
+    pub shape: InterfaceShape,
+    /// Parameter names, sorted, referred to by [`ParamDescription::name`].
+    pub names: Vec<String>,
+    /// Imported interfaces, sorted by verb.
+    pub interfaces: Vec<InterfaceDescription>,
+}
+
+/// One interface a job imports.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InterfaceDescription {
+    /// Qualified interface name, e.g. `job:print/g1`.
+    pub interface: String,
+    /// Verb the interface submits, e.g. `G1`.
+    pub verb: String,
+    pub params: Vec<ParamDescription>,
+}
+
+/// A parameter of an interface's statements.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ParamDescription {
+    /// Index into [`Description::names`].
+    pub name: u32,
+    /// WIT types the parameter is set with, e.g. `f64` or `list<s64>`.
+    pub kinds: Vec<String>,
+}
+
+impl Description {
+    pub(crate) fn new(verbs: &[VerbShape], shape: InterfaceShape) -> Self {
+        let names: Vec<String> = verbs
+            .iter()
+            .flat_map(|verb| verb.params.keys().cloned())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        let interfaces = verbs
+            .iter()
+            .map(|verb| InterfaceDescription {
+                interface: import_module_name(&verb.raw),
+                verb: verb.raw.clone(),
+                params: verb
+                    .params
+                    .iter()
+                    .map(|(name, shape)| ParamDescription {
+                        name: names.binary_search(name).unwrap_or_default() as u32,
+                        kinds: shape.kinds.iter().map(wit_type_name).collect(),
+                    })
+                    .collect(),
+            })
+            .collect();
+        Self {
+            shape,
+            names,
+            interfaces,
+        }
+    }
+
+    /// Read the description embedded in a compiled job, either the component
+    /// or its core module.
+    pub fn read(bytes: &[u8]) -> Result<Self> {
+        let data = wasm_util::custom_section(bytes, SECTION)?
+            .with_context(|| format!("job has no `{SECTION}` section"))?;
+        serde_json::from_slice(data).with_context(|| format!("invalid `{SECTION}` section"))
+    }
+
+    /// Store the description in `bytes`, replacing any already there.
+    pub(crate) fn embed(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        let data = serde_json::to_vec(self)?;
+        wasm_util::embed_custom_section(bytes, SECTION, &data)
+    }
+
+    /// Name of `param`.
+    pub fn name(&self, param: &ParamDescription) -> &str {
+        &self.names[param.name as usize]
+    }
+
+    /// The interface submitting `verb`, e.g. `G1`.
+    pub fn interface(&self, verb: &str) -> Option<&InterfaceDescription> {
+        self.interfaces.iter().find(|iface| iface.verb == verb)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CompileOptions, compile_gcode, compile_gcode_with};
+
+    #[test]
+    fn describes_interfaces_with_interned_names() {
+        let out = compile_gcode("G1 X1 Y2.5\nG0 X3 F600\nM104 S200\nG28\n").unwrap();
+        let description = Description::read(&out.component).unwrap();
+        assert_eq!(Description::read(&out.wasm).unwrap(), description);
+
+        assert_eq!(description.shape, InterfaceShape::Builder);
+        assert_eq!(description.names, ["F", "S", "X", "Y"]);
+        let verbs: Vec<_> = description.interfaces.iter().map(|i| &i.verb).collect();
+        assert_eq!(verbs, ["G0", "G1", "G28", "M104"]);
+
+        let g1 = description.interface("G1").unwrap();
+        assert_eq!(g1.interface, "job:print/g1");
+        let params: Vec<_> = g1
+            .params
+            .iter()
+            .map(|p| (description.name(p), p.kinds.clone()))
+            .collect();
+        assert_eq!(
+            params,
+            [
+                ("X", vec!["s64".to_string()]),
+                ("Y", vec!["f64".to_string()])
+            ]
+        );
+        assert!(description.interface("G28").unwrap().params.is_empty());
+
+        let batched = CompileOptions {
+            interface_shape: InterfaceShape::Batched,
+            ..CompileOptions::default()
+        };
+        let out = compile_gcode_with("G1 X1\n", &batched).unwrap();
+        assert_eq!(
+            Description::read(&out.component).unwrap().shape,
+            InterfaceShape::Batched
+        );
+    }
+}
@@ -0,0 +1,320 @@
+//! Semantic diff of two programs.
+//!
+//! [`diff_programs`] compares programs command by command rather than by
+//! text: words are compared by value, so `G1 X10.0` and `g1 x10` are the
+//! same command, and comments and blank lines are ignored. The commands
+//! only one side has are found with Myers' algorithm after the common start
+//! and end are set aside, up to [`MAX_EDIT_DISTANCE`] of them; programs
+//! further apart, like most re-slices with other settings, only report that
+//! they differ. The heater targets each program sets first are compared
+//! too.
+
+use crate::estimate::{param, verb};
+use scherzo_gcode::{Number, Statement, Value};
+
+/// Most commands the two programs may differ by and still be listed.
+pub const MAX_EDIT_DISTANCE: usize = 1000;
+
+/// A command only one of two programs has.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandChange {
+    /// In the first program, at `line`, but not the second.
+    Removed { line: usize, command: String },
+    /// In the second program, at `line`, but not the first.
+    Added { line: usize, command: String },
+}
+
+/// The first target (°C) each program sets a heater to, where they differ.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemperatureChange {
+    /// `extruder` or `heater_bed`.
+    pub heater: &'static str,
+    pub a: Option<f64>,
+    pub b: Option<f64>,
+}
+
+/// Output of [`diff_programs`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProgramDiff {
+    /// Commands in each program.
+    pub commands: [usize; 2],
+    /// Commands only one program has, in program order, or `None` if they
+    /// differ by more than [`MAX_EDIT_DISTANCE`].
+    pub changes: Option<Vec<CommandChange>>,
+    pub temperatures: Vec<TemperatureChange>,
+}
+
+impl ProgramDiff {
+    /// Whether both programs run the same commands.
+    pub fn is_identical(&self) -> bool {
+        self.changes.as_ref().is_some_and(Vec::is_empty)
+    }
+}
+
+/// Heaters and the verbs that set their targets.
+const HEATERS: [(&str, [&str; 2]); 2] = [
+    ("extruder", ["M104", "M109"]),
+    ("heater_bed", ["M140", "M190"]),
+];
+
+/// Compare program `a` with program `b`.
+pub fn diff_programs(a: &[Statement], b: &[Statement]) -> ProgramDiff {
+    let a: Vec<_> = a.iter().filter_map(Command::new).collect();
+    let b: Vec<_> = b.iter().filter_map(Command::new).collect();
+
+    let prefix = a.iter().zip(&b).take_while(|(a, b)| a == b).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (middle_a, middle_b) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+    let changes = edit_script(middle_a, middle_b, MAX_EDIT_DISTANCE).map(|edits| {
+        edits
+            .into_iter()
+            .map(|edit| match edit {
+                Edit::Delete(i) => {
+                    let command = &middle_a[i];
+                    CommandChange::Removed {
+                        line: command.line,
+                        command: command.text.clone(),
+                    }
+                }
+                Edit::Insert(i) => {
+                    let command = &middle_b[i];
+                    CommandChange::Added {
+                        line: command.line,
+                        command: command.text.clone(),
+                    }
+                }
+            })
+            .collect()
+    });
+
+    let temperatures = HEATERS
+        .iter()
+        .filter_map(|&(heater, verbs)| {
+            let first = |commands: &[Command]| {
+                commands
+                    .iter()
+                    .find(|command| command.verb.as_deref().is_some_and(|v| verbs.contains(&v)))
+                    .and_then(|command| command.target)
+            };
+            let (a, b) = (first(&a), first(&b));
+            (a != b).then_some(TemperatureChange { heater, a, b })
+        })
+        .collect();
+
+    ProgramDiff {
+        commands: [a.len(), b.len()],
+        changes,
+        temperatures,
+    }
+}
+
+/// A command in a canonical form, compared by its text.
+struct Command {
+    line: usize,
+    text: String,
+    verb: Option<String>,
+    /// `S` of a heater command.
+    target: Option<f64>,
+}
+
+impl PartialEq for Command {
+    fn eq(&self, other: &Self) -> bool {
+        self.text == other.text
+    }
+}
+
+impl Command {
+    fn new(stmt: &Statement) -> Option<Self> {
+        if stmt.words.is_empty() {
+            return None;
+        }
+        let words: Vec<_> = stmt
+            .words
+            .iter()
+            .map(|word| {
+                let mut text = match (word.letter, &word.name) {
+                    (_, Some(name)) => format!("{}=", name.to_ascii_uppercase()),
+                    (Some(letter), None) => letter.to_ascii_uppercase().to_string(),
+                    (None, None) => String::new(),
+                };
+                if let Some(value) = &word.value {
+                    format_value(&mut text, value);
+                }
+                text
+            })
+            .collect();
+        Some(Self {
+            line: stmt.line,
+            text: words.join(" "),
+            verb: verb(stmt),
+            target: param(stmt, 'S'),
+        })
+    }
+}
+
+fn format_value(text: &mut String, value: &Value) {
+    match value {
+        Value::Number(Number::Int(i)) => text.push_str(&i.to_string()),
+        Value::Number(Number::Float(f)) => text.push_str(&f.to_string()),
+        Value::Text(s) => text.push_str(s),
+        Value::List(values) => {
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    text.push(',');
+                }
+                format_value(text, value);
+            }
+        }
+    }
+}
+
+/// A step of an edit script, by index into the side it applies to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Edit {
+    Delete(usize),
+    Insert(usize),
+}
+
+/// The shortest edit script turning `a` into `b`, found with Myers'
+/// algorithm, or `None` if it takes more than `max_d` edits. Keeps the
+/// frontier of every round for the walk back, so memory grows with the
+/// square of the edits rather than the length of the programs.
+fn edit_script<T: PartialEq>(a: &[T], b: &[T], max_d: usize) -> Option<Vec<Edit>> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max_d = max_d.min(a.len() + b.len()) as isize;
+    let offset = max_d + 1;
+    // Furthest x reached on each diagonal k = x - y
+    let mut v = vec![0isize; 2 * offset as usize + 1];
+    // The part of `v` round d starts from, diagonals -d - 1 to d + 1
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+    for d in 0..=max_d {
+        trace.push(v[(offset - d - 1) as usize..=(offset + d + 1) as usize].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let at = |k: isize| v[(offset + k) as usize];
+            let mut x = if k == -d || (k != d && at(k - 1) < at(k + 1)) {
+                at(k + 1)
+            } else {
+                at(k - 1) + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[(offset + k) as usize] = x;
+            if x >= n && y >= m {
+                return Some(walk_back(&trace, n, m));
+            }
+        }
+    }
+    None
+}
+
+/// Follow the frontiers of [`edit_script`] back from the end of both sides.
+fn walk_back(trace: &[Vec<isize>], n: isize, m: isize) -> Vec<Edit> {
+    let mut edits = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let at = |k: isize| v[(k + d + 1) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = at(prev_k);
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            edits.push(if x == prev_x {
+                Edit::Insert(prev_y as usize)
+            } else {
+                Edit::Delete(prev_x as usize)
+            });
+        }
+        (x, y) = (prev_x, prev_y);
+    }
+    edits.reverse();
+    edits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scherzo_gcode::parse;
+
+    #[test]
+    fn edit_script_is_shortest() {
+        let a: Vec<_> = "ABCABBA".chars().collect();
+        let b: Vec<_> = "CBABAC".chars().collect();
+        let edits = edit_script(&a, &b, 100).unwrap();
+        assert_eq!(edits.len(), 5);
+
+        // What neither side drops is common to both
+        let kept = |side: &[char], dropped: Vec<usize>| -> String {
+            (0..side.len())
+                .filter(|i| !dropped.contains(i))
+                .map(|i| side[i])
+                .collect()
+        };
+        let deleted = edits.iter().filter_map(|edit| match *edit {
+            Edit::Delete(i) => Some(i),
+            Edit::Insert(_) => None,
+        });
+        let inserted = edits.iter().filter_map(|edit| match *edit {
+            Edit::Insert(i) => Some(i),
+            Edit::Delete(_) => None,
+        });
+        assert_eq!(kept(&a, deleted.collect()), kept(&b, inserted.collect()));
+        assert!(edit_script(&a, &b, 4).is_none());
+    }
+
+    #[test]
+    fn compares_commands_by_value() {
+        let a = parse("M140 S60\nM104 S210\nG1 X10.0 F600 ; first\nG1 Y5\nG1 Z1\n").unwrap();
+        let b = parse("M140 S60\nM104 S215\n\ng1 x10 f600\nG1 Y6\nG1 Z1\n").unwrap();
+        let diff = diff_programs(&a, &b);
+
+        assert_eq!(diff.commands, [5, 5]);
+        assert!(!diff.is_identical());
+        assert_eq!(
+            diff.changes.unwrap(),
+            [
+                CommandChange::Removed {
+                    line: 2,
+                    command: "M104 S210".to_string()
+                },
+                CommandChange::Added {
+                    line: 2,
+                    command: "M104 S215".to_string()
+                },
+                CommandChange::Removed {
+                    line: 4,
+                    command: "G1 Y5".to_string()
+                },
+                CommandChange::Added {
+                    line: 5,
+                    command: "G1 Y6".to_string()
+                },
+            ]
+        );
+        assert_eq!(
+            diff.temperatures,
+            [TemperatureChange {
+                heater: "extruder",
+                a: Some(210.0),
+                b: Some(215.0)
+            }]
+        );
+        assert!(diff_programs(&a, &a).is_identical());
+    }
+}
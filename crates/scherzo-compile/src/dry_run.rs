@@ -0,0 +1,214 @@
+//! Time-sampled dry run of a program.
+//!
+//! [`dry_run`] plans a program the way estimates do, then samples the
+//! toolhead at a fixed interval along the planned moves: where it is and how
+//! fast it is going, following each move's accelerate, cruise and decelerate
+//! phases. Nothing is sent to any hardware.
+
+use crate::estimate::{MotionInterpreter, TrajectoryEvent, plan_trajectory};
+use scherzo_core::planner::{MachineLimits, PlannedMove};
+use scherzo_gcode::Statement;
+
+/// Most samples a dry run returns; longer programs are sampled more
+/// sparsely.
+pub const MAX_SAMPLES: usize = 100_000;
+
+/// The toolhead at one instant.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DryRunSample {
+    /// Seconds since the program started.
+    pub time: f64,
+    /// X/Y/Z/E position (mm).
+    pub position: [f64; 4],
+    /// Speed (mm/s) along the current move; zero while dwelling.
+    pub velocity: f64,
+}
+
+/// Output of [`dry_run`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DryRun {
+    /// Seconds the program takes.
+    pub duration: f64,
+    /// Seconds between samples, widened from the one asked for if it would
+    /// take more than [`MAX_SAMPLES`].
+    pub interval: f64,
+    /// Samples from the start to the end of the program, the last one at
+    /// `duration`.
+    pub samples: Vec<DryRunSample>,
+}
+
+/// A stretch of the trajectory, starting at `start` seconds.
+struct Span {
+    start: f64,
+    kind: SpanKind,
+}
+
+enum SpanKind {
+    Move(Box<PlannedMove>),
+    Dwell { seconds: f64, position: [f64; 4] },
+}
+
+impl Span {
+    fn duration(&self) -> f64 {
+        match &self.kind {
+            SpanKind::Move(mv) => mv.move_t(),
+            SpanKind::Dwell { seconds, .. } => *seconds,
+        }
+    }
+
+    /// Position and speed `t` seconds into the span.
+    fn sample(&self, t: f64) -> ([f64; 4], f64) {
+        let mv = match &self.kind {
+            SpanKind::Move(mv) => mv,
+            SpanKind::Dwell { position, .. } => return (*position, 0.0),
+        };
+        if t >= mv.move_t() {
+            return (mv.end_pos, mv.end_v);
+        }
+        let (distance, velocity) = travel(mv, t.max(0.0));
+        let mut position = mv.start_pos;
+        for (axis, ratio) in position.iter_mut().zip(mv.axes_r) {
+            *axis += ratio * distance;
+        }
+        (position, velocity)
+    }
+}
+
+/// Distance (mm) covered and speed (mm/s) `t` seconds into `mv`.
+fn travel(mv: &PlannedMove, t: f64) -> (f64, f64) {
+    let accel_d = (mv.start_v + mv.cruise_v) * 0.5 * mv.accel_t;
+    if t < mv.accel_t {
+        return (
+            mv.start_v * t + 0.5 * mv.accel * t * t,
+            mv.start_v + mv.accel * t,
+        );
+    }
+    let t = t - mv.accel_t;
+    if t < mv.cruise_t {
+        return (accel_d + mv.cruise_v * t, mv.cruise_v);
+    }
+    let cruise_d = mv.cruise_v * mv.cruise_t;
+    let t = t - mv.cruise_t;
+    (
+        accel_d + cruise_d + mv.cruise_v * t - 0.5 * mv.accel * t * t,
+        (mv.cruise_v - mv.accel * t).max(mv.end_v),
+    )
+}
+
+/// Plan `statements` from the state of `interp` and sample the toolhead
+/// every `interval` seconds (positive), without sending anything anywhere.
+pub fn dry_run(
+    statements: &[Statement],
+    limits: &MachineLimits,
+    interp: MotionInterpreter,
+    interval: f64,
+) -> DryRun {
+    let mut spans = Vec::new();
+    let mut time = 0.0;
+    let mut position = interp.position();
+    plan_trajectory(statements, limits, interp, |event| {
+        let kind = match event {
+            TrajectoryEvent::Move(mv) => {
+                position = mv.end_pos;
+                SpanKind::Move(Box::new(*mv))
+            }
+            TrajectoryEvent::Dwell(seconds) => SpanKind::Dwell { seconds, position },
+        };
+        let span = Span { start: time, kind };
+        time += span.duration();
+        spans.push(span);
+    });
+
+    let duration = time;
+    let interval = interval.max(duration / (MAX_SAMPLES - 1) as f64);
+    let mut samples = Vec::new();
+    let mut spans = spans.iter().peekable();
+    let mut current = None;
+    let mut step = 0;
+    loop {
+        let time = (step as f64 * interval).min(duration);
+        while let Some(span) = spans.next_if(|span| span.start <= time) {
+            current = Some(span);
+        }
+        // The toolhead has stopped where the last move ends
+        let (position, velocity) = match current {
+            Some(span) if time < duration => span.sample(time - span.start),
+            _ => (position, 0.0),
+        };
+        samples.push(DryRunSample {
+            time,
+            position,
+            velocity,
+        });
+        if time >= duration {
+            break;
+        }
+        step += 1;
+    }
+
+    DryRun {
+        duration,
+        interval,
+        samples,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scherzo_gcode::parse;
+
+    #[test]
+    fn samples_follow_the_planned_moves() {
+        let limits = MachineLimits::default();
+        let statements = parse("G1 X100 F6000\nG4 P500\nG1 X0 Y10 E2\n").unwrap();
+        let run = dry_run(&statements, &limits, MotionInterpreter::new(), 0.01);
+
+        let estimate = crate::estimate::estimate_statements(&statements, &limits);
+        assert!((run.duration - estimate.total_seconds).abs() < 1e-9);
+        let first = run.samples[0];
+        assert_eq!(
+            (first.time, first.position, first.velocity),
+            (0.0, [0.0; 4], 0.0)
+        );
+        let last = run.samples.last().unwrap();
+        assert_eq!(last.time, run.duration);
+        assert!(
+            last.position
+                .iter()
+                .zip([0.0, 10.0, 0.0, 2.0])
+                .all(|(a, b)| (a - b).abs() < 1e-6)
+        );
+
+        // Never faster than commanded, and still while dwelling at X100
+        assert!(run.samples.iter().all(|s| s.velocity <= 100.0 + 1e-9));
+        let dwelling: Vec<_> = run
+            .samples
+            .iter()
+            .filter(|s| (s.position[0] - 100.0).abs() < 1e-9)
+            .collect();
+        assert!(dwelling.len() >= 45);
+        assert!(dwelling.iter().all(|s| s.velocity == 0.0));
+        // X only moves forward on the way out
+        let out: Vec<_> = run
+            .samples
+            .iter()
+            .take_while(|s| s.position[1] == 0.0)
+            .collect();
+        assert!(out.windows(2).all(|w| w[0].position[0] <= w[1].position[0]));
+    }
+
+    #[test]
+    fn long_programs_are_sampled_sparsely() {
+        let statements = parse("G1 X200 F60\n").unwrap();
+        let run = dry_run(
+            &statements,
+            &MachineLimits::default(),
+            MotionInterpreter::new(),
+            0.0001,
+        );
+        assert!(run.samples.len() <= MAX_SAMPLES);
+        assert!(run.interval > 0.0001);
+        assert_eq!(run.samples.last().unwrap().time, run.duration);
+    }
+}
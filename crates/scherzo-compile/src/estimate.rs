@@ -0,0 +1,834 @@
+//! Print time and material estimation.
+//!
+//! Interprets the motion-relevant subset of G-code (G0-G4, G10/G11, G20/G21,
+//! G28, G90/G91, G92, M82/M83 and M207/M208, plus the `EXCLUDE_OBJECT`
+//! markers) and runs the resulting moves through the `scherzo-core` lookahead
+//! planner so estimates account for acceleration
+//! and cornering rather than just distance over feedrate.
+
+use crate::objects::ObjectCommand;
+use anyhow::{Context, Result};
+use scherzo_core::{
+    exclude_object::ExcludeObject,
+    planner::{LookAheadQueue, MachineLimits, PlannedMove},
+    retraction::{FirmwareRetraction, RetractionConfig},
+};
+use scherzo_gcode::{Number, Statement, Value, parse};
+
+/// Default feedrate (mm/s) used until a program sets one with `F`.
+const DEFAULT_SPEED: f64 = 25.0;
+/// Length of the chords used to approximate G2/G3 arcs (mm).
+const MM_PER_ARC_SEGMENT: f64 = 1.0;
+
+/// Summary of a G-code program's estimated execution.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Estimate {
+    /// Total estimated duration, including dwells (seconds).
+    pub total_seconds: f64,
+    /// Net filament consumed (mm of E axis travel).
+    pub filament_mm: f64,
+    /// Number of planned moves.
+    pub moves: usize,
+    /// Per-layer breakdown, in print order.
+    pub layers: Vec<LayerEstimate>,
+    /// Extents of all extruding moves.
+    pub bounding_box: Option<BoundingBox>,
+}
+
+/// Time and material attributed to a single layer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerEstimate {
+    /// Z height at which the layer was printed.
+    pub z: f64,
+    pub seconds: f64,
+    pub filament_mm: f64,
+}
+
+/// Axis-aligned bounding box in machine coordinates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingBox {
+    pub min: [f64; 3],
+    pub max: [f64; 3],
+}
+
+impl BoundingBox {
+    fn point(p: [f64; 3]) -> Self {
+        Self { min: p, max: p }
+    }
+
+    fn include(&mut self, p: [f64; 3]) {
+        for (i, v) in p.into_iter().enumerate() {
+            self.min[i] = self.min[i].min(v);
+            self.max[i] = self.max[i].max(v);
+        }
+    }
+}
+
+/// Estimate a G-code program from source.
+pub fn estimate_gcode(source: &str, limits: &MachineLimits) -> Result<Estimate> {
+    let statements = parse(source).context("failed to parse gcode")?;
+    Ok(estimate_statements(&statements, limits))
+}
+
+/// Estimate an already-parsed G-code program.
+pub fn estimate_statements(statements: &[Statement], limits: &MachineLimits) -> Estimate {
+    estimate_with(statements, limits, MotionInterpreter::new())
+}
+
+/// Estimate an already-parsed G-code program, starting from the state of
+/// `interp` (e.g. with firmware retraction enabled).
+pub fn estimate_with(
+    statements: &[Statement],
+    limits: &MachineLimits,
+    interp: MotionInterpreter,
+) -> Estimate {
+    let mut estimate = Estimate::default();
+    plan_trajectory(statements, limits, interp, |event| match event {
+        TrajectoryEvent::Move(mv) => estimate.record_move(mv),
+        TrajectoryEvent::Dwell(seconds) => estimate.record_dwell(seconds),
+    });
+    estimate
+}
+
+/// Step of a program's planned trajectory.
+#[derive(Debug, Clone, Copy)]
+pub enum TrajectoryEvent<'a> {
+    /// A move, with the speeds the lookahead resolved for it.
+    Move(&'a PlannedMove),
+    /// A pause (seconds) with the toolhead standing still.
+    Dwell(f64),
+}
+
+/// Plan an already-parsed G-code program the way estimates do, starting
+/// from the state of `interp`, and pass each move and dwell to `visit` in
+/// order.
+pub fn plan_trajectory(
+    statements: &[Statement],
+    limits: &MachineLimits,
+    mut interp: MotionInterpreter,
+    mut visit: impl FnMut(TrajectoryEvent<'_>),
+) {
+    let mut lookahead = LookAheadQueue::new();
+
+    for stmt in statements {
+        for cmd in interp.interpret(stmt) {
+            match cmd {
+                MotionCommand::Move {
+                    start,
+                    target,
+                    speed,
+                } => {
+                    let mv = PlannedMove::new(limits, start, target, speed);
+                    if mv.move_d == 0.0 {
+                        continue;
+                    }
+                    if lookahead.add_move(mv) {
+                        for mv in lookahead.flush(true) {
+                            visit(TrajectoryEvent::Move(&mv));
+                        }
+                    }
+                }
+                MotionCommand::Dwell { seconds } => {
+                    for mv in lookahead.flush(false) {
+                        visit(TrajectoryEvent::Move(&mv));
+                    }
+                    visit(TrajectoryEvent::Dwell(seconds));
+                }
+            }
+        }
+    }
+
+    for mv in lookahead.flush(false) {
+        visit(TrajectoryEvent::Move(&mv));
+    }
+}
+
+impl Estimate {
+    fn record_move(&mut self, mv: &PlannedMove) {
+        let seconds = mv.move_t();
+        let extrude = mv.axes_d[3];
+        self.total_seconds += seconds;
+        self.filament_mm += extrude;
+        self.moves += 1;
+
+        if extrude > 0.0 && mv.is_kinematic_move {
+            let start = [mv.start_pos[0], mv.start_pos[1], mv.start_pos[2]];
+            let end = [mv.end_pos[0], mv.end_pos[1], mv.end_pos[2]];
+            let bbox = self
+                .bounding_box
+                .get_or_insert_with(|| BoundingBox::point(start));
+            bbox.include(start);
+            bbox.include(end);
+
+            let z = mv.end_pos[2];
+            let new_layer = self
+                .layers
+                .last()
+                .is_none_or(|layer| z > layer.z + 0.000_001);
+            if new_layer {
+                self.layers.push(LayerEstimate {
+                    z,
+                    seconds: 0.0,
+                    filament_mm: 0.0,
+                });
+            }
+        }
+
+        if let Some(layer) = self.layers.last_mut() {
+            layer.seconds += seconds;
+            layer.filament_mm += extrude;
+        }
+    }
+
+    fn record_dwell(&mut self, seconds: f64) {
+        self.total_seconds += seconds;
+        if let Some(layer) = self.layers.last_mut() {
+            layer.seconds += seconds;
+        }
+    }
+}
+
+/// Verbs the interpreter acts on; every other verb leaves the motion state
+/// unchanged.
+pub const MOTION_VERBS: [&str; 19] = [
+    "G0", "G1", "G2", "G3", "G4", "G10", "G11", "G20", "G21", "G28", "G90", "G91", "G92", "M82",
+    "M83", "M207", "M208", "M220", "M221",
+];
+
+/// Motion produced by interpreting a single statement.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MotionCommand {
+    /// Move the toolhead (X/Y/Z/E machine coordinates) at `speed` mm/s.
+    Move {
+        start: [f64; 4],
+        target: [f64; 4],
+        speed: f64,
+    },
+    /// Pause motion for the given duration.
+    Dwell { seconds: f64 },
+}
+
+/// Modal G-code state machine translating statements into machine moves.
+#[derive(Debug, Clone)]
+pub struct MotionInterpreter {
+    position: [f64; 4],
+    base: [f64; 4],
+    absolute: bool,
+    absolute_e: bool,
+    speed: f64,
+    scale: f64,
+    /// Multiplies the feedrate of `G0`-`G3` moves, as set by `M220`.
+    speed_factor: f64,
+    /// Multiplies the E distance of `G0`-`G3` moves, as set by `M221`.
+    extrude_factor: f64,
+    /// `G10`/`G11` handling; without it both are ignored.
+    retraction: Option<FirmwareRetraction>,
+    exclude_object: ExcludeObject,
+}
+
+impl Default for MotionInterpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MotionInterpreter {
+    pub fn new() -> Self {
+        Self {
+            position: [0.0; 4],
+            base: [0.0; 4],
+            absolute: true,
+            absolute_e: true,
+            speed: DEFAULT_SPEED,
+            scale: 1.0,
+            speed_factor: 1.0,
+            extrude_factor: 1.0,
+            retraction: None,
+            exclude_object: ExcludeObject::new(),
+        }
+    }
+
+    /// Turn `G10`/`G11` into extruder moves using `config`.
+    pub fn with_retraction(mut self, config: RetractionConfig) -> Self {
+        self.retraction = Some(FirmwareRetraction::new(config));
+        self
+    }
+
+    /// Turn `G10`/`G11` into extruder moves using `config`, or ignore them
+    /// if `None`. Any retraction in effect is forgotten.
+    pub fn set_retraction(&mut self, config: Option<RetractionConfig>) {
+        self.retraction = config.map(FirmwareRetraction::new);
+    }
+
+    /// Firmware retraction state, if enabled.
+    pub fn retraction(&self) -> Option<&FirmwareRetraction> {
+        self.retraction.as_ref()
+    }
+
+    /// Skip the moves of object `name`. Returns false if it was already
+    /// excluded.
+    pub fn exclude(&mut self, name: &str) -> bool {
+        self.exclude_object.exclude(name)
+    }
+
+    /// Excluded objects and the object being printed.
+    pub fn exclude_object(&self) -> &ExcludeObject {
+        &self.exclude_object
+    }
+
+    /// Commanded toolhead position in machine coordinates. Moves of
+    /// excluded objects still update it.
+    pub fn position(&self) -> [f64; 4] {
+        self.position
+    }
+
+    /// Take `position` (machine coordinates) as the commanded position, e.g.
+    /// after something else moved the toolhead. `G92` offsets are kept.
+    pub fn sync_position(&mut self, position: [f64; 4]) {
+        self.position = position;
+    }
+
+    /// Offsets (mm) set by `G92`, subtracted from machine coordinates to get
+    /// G-code coordinates.
+    pub fn offsets(&self) -> [f64; 4] {
+        self.base
+    }
+
+    /// Whether X/Y/Z are absolute (`G90`) rather than relative (`G91`).
+    pub fn is_absolute(&self) -> bool {
+        self.absolute
+    }
+
+    /// Whether E is absolute (`M82`) rather than relative (`M83`).
+    pub fn is_absolute_e(&self) -> bool {
+        self.absolute_e
+    }
+
+    /// Whether units are inches (`G20`) rather than millimeters (`G21`).
+    pub fn is_inches(&self) -> bool {
+        self.scale != 1.0
+    }
+
+    /// Feedrate (mm/s) of the next move.
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    /// Factor the feedrate of `G0`-`G3` moves is multiplied by.
+    pub fn speed_factor(&self) -> f64 {
+        self.speed_factor
+    }
+
+    /// Multiply the feedrate of later `G0`-`G3` moves by `factor`.
+    pub fn set_speed_factor(&mut self, factor: f64) {
+        self.speed_factor = factor;
+    }
+
+    /// Factor the E distance of `G0`-`G3` moves is multiplied by.
+    pub fn extrude_factor(&self) -> f64 {
+        self.extrude_factor
+    }
+
+    /// Multiply the E distance of later `G0`-`G3` moves by `factor`. The E
+    /// offset is rebased so the next absolute E only scales the distance
+    /// from where the extruder is, as Klipper does.
+    pub fn set_extrude_factor(&mut self, factor: f64) {
+        let e = (self.position[3] - self.base[3]) / self.extrude_factor;
+        self.base[3] = self.position[3] - e * factor;
+        self.extrude_factor = factor;
+    }
+
+    /// Interpret one statement, returning the motion it produces.
+    pub fn interpret(&mut self, stmt: &Statement) -> Vec<MotionCommand> {
+        let commands = self.commanded(stmt);
+        let filter = &mut self.exclude_object;
+        let mut out = Vec::with_capacity(commands.len());
+        for cmd in commands {
+            let MotionCommand::Move {
+                start,
+                target,
+                speed,
+            } = cmd
+            else {
+                out.push(cmd);
+                continue;
+            };
+            let mut from = filter.toolhead_position(start);
+            for point in filter.filter_move(start, target) {
+                out.push(MotionCommand::Move {
+                    start: from,
+                    target: point,
+                    speed,
+                });
+                from = point;
+            }
+        }
+        out
+    }
+
+    fn commanded(&mut self, stmt: &Statement) -> Vec<MotionCommand> {
+        if let Some(cmd) = ObjectCommand::parse(stmt) {
+            match cmd {
+                ObjectCommand::Define(_) => {}
+                ObjectCommand::Start(name) => self.exclude_object.start(&name),
+                ObjectCommand::End => self.exclude_object.end(),
+                ObjectCommand::Exclude(name) => {
+                    self.exclude_object.exclude(&name);
+                }
+            }
+            return Vec::new();
+        }
+        let Some(verb) = verb(stmt) else {
+            return Vec::new();
+        };
+
+        match verb.as_str() {
+            "G0" | "G1" => self.linear_move(stmt).into_iter().collect(),
+            "G2" => self.arc_move(stmt, true),
+            "G3" => self.arc_move(stmt, false),
+            "G4" => {
+                let seconds = param(stmt, 'P')
+                    .map(|ms| ms / 1000.0)
+                    .or_else(|| param(stmt, 'S'))
+                    .unwrap_or(0.0);
+                vec![MotionCommand::Dwell { seconds }]
+            }
+            // G10 with L or P sets tool offsets in other firmwares
+            "G10" if !has_word(stmt, 'L') && !has_word(stmt, 'P') => {
+                let mv = self
+                    .retraction
+                    .as_mut()
+                    .and_then(FirmwareRetraction::retract);
+                mv.map(|mv| self.extruder_move(mv.distance, mv.speed))
+                    .into_iter()
+                    .collect()
+            }
+            "G11" => {
+                let mv = self
+                    .retraction
+                    .as_mut()
+                    .and_then(FirmwareRetraction::unretract);
+                mv.map(|mv| self.extruder_move(mv.distance, mv.speed))
+                    .into_iter()
+                    .collect()
+            }
+            "G20" => {
+                self.scale = 25.4;
+                Vec::new()
+            }
+            "G21" => {
+                self.scale = 1.0;
+                Vec::new()
+            }
+            "G28" => {
+                self.home(stmt);
+                Vec::new()
+            }
+            "G90" => {
+                self.absolute = true;
+                Vec::new()
+            }
+            "G91" => {
+                self.absolute = false;
+                Vec::new()
+            }
+            "G92" => {
+                self.set_position(stmt);
+                Vec::new()
+            }
+            "M82" => {
+                self.absolute_e = true;
+                Vec::new()
+            }
+            "M83" => {
+                self.absolute_e = false;
+                Vec::new()
+            }
+            "M207" | "M208" => {
+                self.tune_retraction(stmt, verb == "M207");
+                Vec::new()
+            }
+            // `S<percent>`, back to 100% without one, as in Klipper
+            "M220" | "M221" => {
+                let percent = param(stmt, 'S').unwrap_or(100.0);
+                match verb.as_str() {
+                    _ if percent <= 0.0 => {}
+                    "M220" => self.set_speed_factor(percent / 100.0),
+                    _ => self.set_extrude_factor(percent / 100.0),
+                }
+                Vec::new()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn extruder_move(&mut self, distance: f64, speed: f64) -> MotionCommand {
+        let start = self.position;
+        self.position[3] += distance;
+        MotionCommand::Move {
+            start,
+            target: self.position,
+            speed,
+        }
+    }
+
+    /// `M207 S<length> F<speed>` sets the retract and `M208 S<extra>
+    /// F<speed>` the unretract parameters. Lengths are in mm and feedrates
+    /// in mm/min, regardless of G20.
+    fn tune_retraction(&mut self, stmt: &Statement, retract: bool) {
+        let Some(retraction) = &mut self.retraction else {
+            return;
+        };
+        let mut config = *retraction.config();
+        let (length, speed) = if retract {
+            (&mut config.retract_length, &mut config.retract_speed)
+        } else {
+            (
+                &mut config.unretract_extra_length,
+                &mut config.unretract_speed,
+            )
+        };
+        if let Some(s) = param(stmt, 'S')
+            && s >= 0.0
+        {
+            *length = s;
+        }
+        if let Some(f) = param(stmt, 'F')
+            && f > 0.0
+        {
+            *speed = f / 60.0;
+        }
+        retraction.set_config(config);
+    }
+
+    fn update_speed(&mut self, stmt: &Statement) {
+        if let Some(f) = param(stmt, 'F')
+            && f > 0.0
+        {
+            self.speed = f * self.scale / 60.0;
+        }
+    }
+
+    fn target(&self, stmt: &Statement) -> [f64; 4] {
+        let mut target = self.position;
+        for (axis, letter) in ['X', 'Y', 'Z', 'E'].into_iter().enumerate() {
+            let Some(v) = param(stmt, letter) else {
+                continue;
+            };
+            let v = if axis == 3 {
+                v * self.scale * self.extrude_factor
+            } else {
+                v * self.scale
+            };
+            let relative = !self.absolute || (axis == 3 && !self.absolute_e);
+            target[axis] = if relative {
+                self.position[axis] + v
+            } else {
+                v + self.base[axis]
+            };
+        }
+        target
+    }
+
+    fn linear_move(&mut self, stmt: &Statement) -> Option<MotionCommand> {
+        self.update_speed(stmt);
+        let target = self.target(stmt);
+        if target == self.position {
+            return None;
+        }
+        let start = std::mem::replace(&mut self.position, target);
+        Some(MotionCommand::Move {
+            start,
+            target,
+            speed: self.speed * self.speed_factor,
+        })
+    }
+
+    fn arc_move(&mut self, stmt: &Statement, clockwise: bool) -> Vec<MotionCommand> {
+        self.update_speed(stmt);
+        let target = self.target(stmt);
+        let start = self.position;
+        let (Some(i), Some(j)) = (param(stmt, 'I'), param(stmt, 'J')) else {
+            // Radius-format arcs are not supported; fall back to a chord.
+            return self.linear_move(stmt).into_iter().collect();
+        };
+
+        let center = [start[0] + i * self.scale, start[1] + j * self.scale];
+        let r0 = [start[0] - center[0], start[1] - center[1]];
+        let r1 = [target[0] - center[0], target[1] - center[1]];
+        let radius = r0[0].hypot(r0[1]);
+        let mut angle = (r0[0] * r1[1] - r0[1] * r1[0]).atan2(r0[0] * r1[0] + r0[1] * r1[1]);
+        if clockwise && angle >= 0.0 {
+            angle -= 2.0 * std::f64::consts::PI;
+        } else if !clockwise && angle <= 0.0 {
+            angle += 2.0 * std::f64::consts::PI;
+        }
+
+        let arc_len = (angle * radius).hypot(target[2] - start[2]);
+        let segments = ((arc_len / MM_PER_ARC_SEGMENT).floor() as usize).max(1);
+        let mut out = Vec::with_capacity(segments);
+        for n in 1..=segments {
+            let t = n as f64 / segments as f64;
+            let point = if n == segments {
+                target
+            } else {
+                let (sin, cos) = (angle * t).sin_cos();
+                [
+                    center[0] + r0[0] * cos - r0[1] * sin,
+                    center[1] + r0[0] * sin + r0[1] * cos,
+                    start[2] + (target[2] - start[2]) * t,
+                    start[3] + (target[3] - start[3]) * t,
+                ]
+            };
+            let from = std::mem::replace(&mut self.position, point);
+            out.push(MotionCommand::Move {
+                start: from,
+                target: point,
+                speed: self.speed * self.speed_factor,
+            });
+        }
+        out
+    }
+
+    fn home(&mut self, stmt: &Statement) {
+        let named: Vec<usize> = ['X', 'Y', 'Z']
+            .into_iter()
+            .enumerate()
+            .filter(|(_, letter)| has_word(stmt, *letter))
+            .map(|(axis, _)| axis)
+            .collect();
+        let axes = if named.is_empty() {
+            vec![0, 1, 2]
+        } else {
+            named
+        };
+        for axis in axes {
+            self.position[axis] = 0.0;
+            self.base[axis] = 0.0;
+        }
+    }
+
+    fn set_position(&mut self, stmt: &Statement) {
+        for (axis, letter) in ['X', 'Y', 'Z', 'E'].into_iter().enumerate() {
+            if let Some(v) = param(stmt, letter) {
+                let factor = if axis == 3 { self.extrude_factor } else { 1.0 };
+                self.base[axis] = self.position[axis] - v * self.scale * factor;
+            }
+        }
+    }
+}
+
+/// Uppercase verb of a statement, e.g. `G1` (G1.0 is normalized to G1).
+pub(crate) fn verb(stmt: &Statement) -> Option<String> {
+    let first = stmt.words.first()?;
+    let letter = first.letter?.to_ascii_uppercase();
+    match &first.value {
+        Some(Value::Number(Number::Int(i))) => Some(format!("{letter}{i}")),
+        Some(Value::Number(Number::Float(f))) if f.fract() == 0.0 => {
+            Some(format!("{letter}{}", *f as i64))
+        }
+        _ => None,
+    }
+}
+
+pub(crate) fn param(stmt: &Statement, letter: char) -> Option<f64> {
+    stmt.words.iter().skip(1).find_map(|word| {
+        if !word.letter?.eq_ignore_ascii_case(&letter) {
+            return None;
+        }
+        match word.value.as_ref()? {
+            Value::Number(Number::Int(i)) => Some(*i as f64),
+            Value::Number(Number::Float(f)) => Some(*f),
+            _ => None,
+        }
+    })
+}
+
+fn has_word(stmt: &Statement, letter: char) -> bool {
+    stmt.words
+        .iter()
+        .skip(1)
+        .any(|word| word.letter.is_some_and(|l| l.eq_ignore_ascii_case(&letter)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> MachineLimits {
+        MachineLimits {
+            max_velocity: 100.0,
+            max_accel: 1000.0,
+            minimum_cruise_ratio: 0.0,
+            square_corner_velocity: 5.0,
+            ..MachineLimits::default()
+        }
+    }
+
+    #[test]
+    fn single_move_includes_acceleration() {
+        let est = estimate_gcode("G1 X100 F6000\n", &limits()).unwrap();
+        assert_eq!(est.moves, 1);
+        assert!((est.total_seconds - 1.1).abs() < 1e-9);
+        assert!(est.layers.is_empty());
+        assert!(est.bounding_box.is_none());
+    }
+
+    #[test]
+    fn tracks_layers_filament_and_extents() {
+        let source = "\
+G28
+M83
+G1 Z0.2 F600
+G1 X10 Y0 E1 F3000
+G1 X10 Y10 E1
+G1 E-0.5
+G4 P500
+G1 E0.5
+G1 Z0.4
+G1 X0 Y10 E1
+";
+        let est = estimate_gcode(source, &limits()).unwrap();
+        assert_eq!(est.layers.len(), 2);
+        assert!((est.layers[0].z - 0.2).abs() < 1e-9);
+        assert!((est.layers[1].z - 0.4).abs() < 1e-9);
+        assert!((est.filament_mm - 3.0).abs() < 1e-9);
+        let layer_time: f64 = est.layers.iter().map(|l| l.seconds).sum();
+        assert!(layer_time >= 0.5);
+        assert!(est.total_seconds > layer_time);
+
+        let bbox = est.bounding_box.unwrap();
+        assert_eq!(bbox.min, [0.0, 0.0, 0.2]);
+        assert_eq!(bbox.max, [10.0, 10.0, 0.4]);
+    }
+
+    #[test]
+    fn interprets_relative_and_offset_coordinates() {
+        let mut interp = MotionInterpreter::new();
+        for line in parse("G1 X5 E2\nG92 E0\nG1 E1\nG91\nG1 X1\nG20\nG1 Y1\n").unwrap() {
+            interp.interpret(&line);
+        }
+        assert_eq!(interp.position(), [6.0, 25.4, 0.0, 3.0]);
+    }
+
+    #[test]
+    fn speed_and_extrude_factors_scale_moves() {
+        let mut interp = MotionInterpreter::new();
+        let run = |interp: &mut MotionInterpreter, line: &str| {
+            let stmt = &parse(&format!("{line}\n")).unwrap()[0];
+            interp.interpret(stmt)
+        };
+        run(&mut interp, "G1 X10 E2 F600");
+        interp.set_speed_factor(1.5);
+        interp.set_extrude_factor(0.5);
+        let moves = run(&mut interp, "G1 X20 E4");
+        let [MotionCommand::Move { target, speed, .. }] = moves[..] else {
+            panic!("expected one move, got {moves:?}");
+        };
+        assert_eq!(target, [20.0, 0.0, 0.0, 3.0]);
+        assert!((speed - 15.0).abs() < 1e-9);
+        // The commanded feedrate is unchanged
+        assert_eq!(interp.speed(), 10.0);
+
+        run(&mut interp, "G92 E0");
+        run(&mut interp, "G1 E2");
+        assert_eq!(interp.position()[3], 4.0);
+
+        run(&mut interp, "M220 S50");
+        run(&mut interp, "M221");
+        assert_eq!(interp.speed_factor(), 0.5);
+        assert_eq!(interp.extrude_factor(), 1.0);
+        run(&mut interp, "G1 E3");
+        assert_eq!(interp.position()[3], 5.0);
+    }
+
+    #[test]
+    fn firmware_retraction_moves_extruder() {
+        let stmts = parse("G10\nG10\nG1 X10\nG11\nM207 S2 F1800\nM208 S0.5\nG10\nG11\n").unwrap();
+        let config = RetractionConfig {
+            retract_length: 1.0,
+            retract_speed: 40.0,
+            ..RetractionConfig::default()
+        };
+
+        let mut interp = MotionInterpreter::new().with_retraction(config);
+        let moves: Vec<_> = stmts.iter().flat_map(|s| interp.interpret(s)).collect();
+        let extruder: Vec<_> = moves
+            .iter()
+            .filter_map(|mv| match mv {
+                MotionCommand::Move {
+                    start,
+                    target,
+                    speed,
+                } if start[3] != target[3] => Some((target[3] - start[3], *speed)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            extruder,
+            [(-1.0, 40.0), (1.0, 10.0), (-2.0, 30.0), (2.5, 10.0)]
+        );
+        assert_eq!(interp.position(), [10.0, 0.0, 0.0, 0.5]);
+
+        // Without firmware retraction G10/G11 do nothing, and G10 with tool
+        // offset words is never a retract
+        let mut plain = MotionInterpreter::new();
+        assert!(stmts.iter().all(|s| plain.interpret(s).len() <= 1));
+        assert_eq!(plain.position(), [10.0, 0.0, 0.0, 0.0]);
+        let mut interp = MotionInterpreter::new().with_retraction(config);
+        assert!(
+            interp
+                .interpret(&parse("G10 L2 P1 X5\n").unwrap()[0])
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn excluded_objects_are_not_printed() {
+        let source = "EXCLUDE_OBJECT_START NAME=a\nG1 X10 E1 F600\nEXCLUDE_OBJECT_END\n\
+                      EXCLUDE_OBJECT_START NAME=b\nG1 X20 E2\nEXCLUDE_OBJECT_END\n\
+                      EXCLUDE_OBJECT_START NAME=a\nG1 Y10 E3\nEXCLUDE_OBJECT_END\n";
+        let stmts = parse(source).unwrap();
+
+        let mut interp = MotionInterpreter::new();
+        assert!(interp.exclude("B"));
+        let est = estimate_with(&stmts, &limits(), interp.clone());
+        assert!((est.filament_mm - 2.0).abs() < 1e-9, "{}", est.filament_mm);
+
+        // The toolhead travels to where object b ended before printing on
+        let moves: Vec<_> = stmts.iter().flat_map(|s| interp.interpret(s)).collect();
+        let targets: Vec<_> = moves
+            .iter()
+            .filter_map(|mv| match mv {
+                MotionCommand::Move { target, .. } => Some(*target),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            targets,
+            [
+                [10.0, 0.0, 0.0, 1.0],
+                [20.0, 0.0, 0.0, 1.0],
+                [20.0, 10.0, 0.0, 2.0]
+            ]
+        );
+        assert_eq!(interp.position(), [20.0, 10.0, 0.0, 3.0]);
+
+        // Jobs can exclude objects themselves
+        let stmts = parse(&format!("EXCLUDE_OBJECT NAME=b\n{source}")).unwrap();
+        let est = estimate_statements(&stmts, &limits());
+        assert!((est.filament_mm - 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn arcs_end_at_target() {
+        let mut interp = MotionInterpreter::new();
+        let stmts = parse("G1 X10 Y0\nG3 X0 Y10 I-10 J0\n").unwrap();
+        interp.interpret(&stmts[0]);
+        let moves = interp.interpret(&stmts[1]);
+        // Quarter circle of radius 10 is ~15.7mm long
+        assert_eq!(moves.len(), 15);
+        let pos = interp.position();
+        assert!((pos[0] - 0.0).abs() < 1e-9 && (pos[1] - 10.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,395 @@
+//! Binary glTF export of a program's planned toolpath.
+//!
+//! [`toolpath_glb`] plans a program the way estimates do and writes its moves
+//! as glTF lines a web viewer can load as is: extrusions colored from blue
+//! (slowest cruise speed) to red (fastest), and travels in translucent gray.
+//! Runs of nearly collinear moves at the same speed are merged, so curves
+//! sliced into many short moves stay light.
+
+use crate::estimate::{MotionInterpreter, TrajectoryEvent, plan_trajectory};
+use scherzo_core::planner::{MachineLimits, MoveType, PlannedMove};
+use scherzo_gcode::Statement;
+use serde_json::{Value, json};
+
+/// Furthest (mm) a merged away point may be from the line replacing it.
+const TOLERANCE: f64 = 0.02;
+/// Cruise speeds (mm/s) are rounded to this before comparing them, so moves
+/// the planner slowed by a hair still merge.
+const SPEED_STEP: f64 = 1.0;
+/// Most points merged into one line, bounding the work of checking them.
+const MAX_MERGED: usize = 64;
+/// Colors of the slowest, middle and fastest extrusion speeds.
+const SPEED_COLORS: [[f32; 3]; 3] = [[0.1, 0.3, 1.0], [0.1, 0.8, 0.2], [1.0, 0.2, 0.1]];
+/// Color and opacity of travel moves.
+const TRAVEL_COLOR: [f64; 4] = [0.5, 0.5, 0.5, 0.3];
+
+const FLOAT: u32 = 5126;
+const ARRAY_BUFFER: u32 = 34962;
+const LINES: u32 = 1;
+
+/// The planned moves of `statements` as a binary glTF (`.glb`) file.
+///
+/// Positions are in meters with Y up, so machine X, Y and Z become glTF X,
+/// -Z and Y. The scene's `extras` hold the extrusion speed range (mm/s) the
+/// colors span.
+pub fn toolpath_glb(statements: &[Statement], limits: &MachineLimits) -> Vec<u8> {
+    let mut extrusions = Lines::default();
+    let mut travels = Lines::default();
+    plan_trajectory(statements, limits, MotionInterpreter::new(), |event| {
+        let TrajectoryEvent::Move(mv) = event else {
+            return;
+        };
+        match move_kind(mv) {
+            Some(MoveKind::Extrusion) => extrusions.push(mv, (mv.cruise_v / SPEED_STEP).round()),
+            Some(MoveKind::Travel) => travels.push(mv, 0.0),
+            None => {}
+        }
+    });
+    extrusions.finish();
+    travels.finish();
+    encode_glb(&extrusions, &travels)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MoveKind {
+    Extrusion,
+    Travel,
+}
+
+fn move_kind(mv: &PlannedMove) -> Option<MoveKind> {
+    match mv.move_type {
+        MoveType::Print if mv.axes_d[3] > 0.0 => Some(MoveKind::Extrusion),
+        MoveType::Print | MoveType::Travel => Some(MoveKind::Travel),
+        MoveType::ExtrudeOnly => None,
+    }
+}
+
+/// Line segments of one kind of move, merged as they are added.
+#[derive(Debug, Default)]
+struct Lines {
+    /// Start and end (mm) of each segment.
+    segments: Vec<[[f64; 3]; 2]>,
+    /// Rounded speed of each segment.
+    speeds: Vec<f64>,
+    /// The segment still being extended, with the points merged into it.
+    open: Option<OpenLine>,
+}
+
+#[derive(Debug)]
+struct OpenLine {
+    start: [f64; 3],
+    merged: Vec<[f64; 3]>,
+    end: [f64; 3],
+    speed: f64,
+}
+
+impl Lines {
+    fn push(&mut self, mv: &PlannedMove, speed: f64) {
+        let start = [mv.start_pos[0], mv.start_pos[1], mv.start_pos[2]];
+        let end = [mv.end_pos[0], mv.end_pos[1], mv.end_pos[2]];
+        if let Some(open) = &mut self.open
+            && open.end == start
+            && open.speed == speed
+            && open.merged.len() < MAX_MERGED
+            && open
+                .merged
+                .iter()
+                .chain([&open.end])
+                .all(|&point| distance_to_segment(point, open.start, end) <= TOLERANCE)
+        {
+            open.merged.push(open.end);
+            open.end = end;
+            return;
+        }
+        self.finish();
+        self.open = Some(OpenLine {
+            start,
+            merged: Vec::new(),
+            end,
+            speed,
+        });
+    }
+
+    fn finish(&mut self) {
+        if let Some(open) = self.open.take() {
+            self.segments.push([open.start, open.end]);
+            self.speeds.push(open.speed);
+        }
+    }
+
+    /// Vertex positions (meters, Y up), two per segment.
+    fn positions(&self) -> Vec<[f32; 3]> {
+        self.segments
+            .iter()
+            .flatten()
+            .map(|&[x, y, z]| {
+                [
+                    (x / 1000.0) as f32,
+                    (z / 1000.0) as f32,
+                    (-y / 1000.0) as f32,
+                ]
+            })
+            .collect()
+    }
+
+    /// Lowest and highest speed (mm/s), if there are segments.
+    fn speed_range(&self) -> Option<(f64, f64)> {
+        let min = self.speeds.iter().copied().reduce(f64::min)?;
+        let max = self.speeds.iter().copied().reduce(f64::max)?;
+        Some((min * SPEED_STEP, max * SPEED_STEP))
+    }
+
+    /// Vertex colors, two per segment, spanning `range`.
+    fn colors(&self, (min, max): (f64, f64)) -> Vec<[f32; 3]> {
+        self.speeds
+            .iter()
+            .flat_map(|&speed| {
+                let fraction = if max > min {
+                    (speed * SPEED_STEP - min) / (max - min)
+                } else {
+                    0.0
+                };
+                let color = speed_color(fraction as f32);
+                [color, color]
+            })
+            .collect()
+    }
+}
+
+/// Color of an extrusion `fraction` (0 to 1) of the way from the slowest
+/// to the fastest speed.
+fn speed_color(fraction: f32) -> [f32; 3] {
+    let [slow, middle, fast] = SPEED_COLORS;
+    let (from, to, t) = if fraction < 0.5 {
+        (slow, middle, fraction * 2.0)
+    } else {
+        (middle, fast, fraction * 2.0 - 1.0)
+    };
+    [0, 1, 2].map(|channel| from[channel] + (to[channel] - from[channel]) * t)
+}
+
+/// Distance (mm) from `point` to the segment `from`–`to`.
+fn distance_to_segment(point: [f64; 3], from: [f64; 3], to: [f64; 3]) -> f64 {
+    let delta = [0, 1, 2].map(|axis| to[axis] - from[axis]);
+    let offset = [0, 1, 2].map(|axis| point[axis] - from[axis]);
+    let length_sq: f64 = delta.iter().map(|d| d * d).sum();
+    let t = if length_sq > 0.0 {
+        let dot: f64 = (0..3).map(|axis| offset[axis] * delta[axis]).sum();
+        (dot / length_sq).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    (0..3)
+        .map(|axis| (offset[axis] - t * delta[axis]).powi(2))
+        .sum::<f64>()
+        .sqrt()
+}
+
+/// Builds the glTF document and its binary buffer together.
+#[derive(Default)]
+struct Builder {
+    buffer: Vec<u8>,
+    views: Vec<Value>,
+    accessors: Vec<Value>,
+}
+
+impl Builder {
+    /// Append `values` as a VEC3 float accessor, returning its index.
+    fn vec3(&mut self, values: &[[f32; 3]], bounds: bool) -> usize {
+        let offset = self.buffer.len();
+        for value in values.iter().flatten() {
+            self.buffer.extend(value.to_le_bytes());
+        }
+        self.views.push(json!({
+            "buffer": 0,
+            "byteOffset": offset,
+            "byteLength": self.buffer.len() - offset,
+            "target": ARRAY_BUFFER,
+        }));
+        let mut accessor = json!({
+            "bufferView": self.views.len() - 1,
+            "componentType": FLOAT,
+            "count": values.len(),
+            "type": "VEC3",
+        });
+        // Positions must state their extents
+        if bounds {
+            let fold = |pick: fn(f32, f32) -> f32| {
+                values
+                    .iter()
+                    .copied()
+                    .reduce(|a, b| [0, 1, 2].map(|axis| pick(a[axis], b[axis])))
+            };
+            accessor["min"] = json!(fold(f32::min));
+            accessor["max"] = json!(fold(f32::max));
+        }
+        self.accessors.push(accessor);
+        self.accessors.len() - 1
+    }
+}
+
+fn encode_glb(extrusions: &Lines, travels: &Lines) -> Vec<u8> {
+    let mut builder = Builder::default();
+    let mut primitives = Vec::new();
+    let speed_range = extrusions.speed_range();
+    if let Some(range) = speed_range {
+        let position = builder.vec3(&extrusions.positions(), true);
+        let color = builder.vec3(&extrusions.colors(range), false);
+        primitives.push(json!({
+            "attributes": { "POSITION": position, "COLOR_0": color },
+            "material": 0,
+            "mode": LINES,
+        }));
+    }
+    if !travels.segments.is_empty() {
+        let position = builder.vec3(&travels.positions(), true);
+        primitives.push(json!({
+            "attributes": { "POSITION": position },
+            "material": 1,
+            "mode": LINES,
+        }));
+    }
+
+    let mut node = json!({ "name": "toolpath" });
+    let mut document = json!({
+        "asset": { "version": "2.0", "generator": "scherzo" },
+        "scene": 0,
+        "scenes": [{
+            "nodes": [0],
+            "extras": {
+                "min_speed": speed_range.map(|(min, _)| min),
+                "max_speed": speed_range.map(|(_, max)| max),
+            },
+        }],
+        "materials": [
+            {
+                "name": "extrusion",
+                "pbrMetallicRoughness": { "metallicFactor": 0.0, "roughnessFactor": 1.0 },
+            },
+            {
+                "name": "travel",
+                "pbrMetallicRoughness": {
+                    "baseColorFactor": TRAVEL_COLOR,
+                    "metallicFactor": 0.0,
+                    "roughnessFactor": 1.0,
+                },
+                "alphaMode": "BLEND",
+            },
+        ],
+    });
+    // A mesh needs at least one primitive, so an empty program has none
+    if !primitives.is_empty() {
+        node["mesh"] = json!(0);
+        document["meshes"] = json!([{ "primitives": primitives }]);
+        document["buffers"] = json!([{ "byteLength": builder.buffer.len() }]);
+        document["bufferViews"] = json!(builder.views);
+        document["accessors"] = json!(builder.accessors);
+    }
+    document["nodes"] = json!([node]);
+
+    let mut json = document.to_string().into_bytes();
+    pad(&mut json, b' ');
+    let mut bin = builder.buffer;
+    pad(&mut bin, 0);
+
+    let mut glb = Vec::new();
+    glb.extend(b"glTF");
+    glb.extend(2u32.to_le_bytes());
+    // Total length, filled in once the chunks are written
+    glb.extend([0; 4]);
+    glb_chunk(&mut glb, b"JSON", &json);
+    if !bin.is_empty() {
+        glb_chunk(&mut glb, b"BIN\0", &bin);
+    }
+    let length = glb.len() as u32;
+    glb[8..12].copy_from_slice(&length.to_le_bytes());
+    glb
+}
+
+fn glb_chunk(glb: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    glb.extend((data.len() as u32).to_le_bytes());
+    glb.extend(kind);
+    glb.extend(data);
+}
+
+/// Pad `data` to a multiple of 4 bytes, as GLB chunks require.
+fn pad(data: &mut Vec<u8>, byte: u8) {
+    while !data.len().is_multiple_of(4) {
+        data.push(byte);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The JSON chunk and the length of the binary chunk of `glb`.
+    fn read_glb(glb: &[u8]) -> (Value, usize) {
+        let word = |at: usize| u32::from_le_bytes(glb[at..at + 4].try_into().unwrap()) as usize;
+        assert_eq!(&glb[0..4], b"glTF");
+        assert_eq!(word(4), 2);
+        assert_eq!(word(8), glb.len());
+        let json_len = word(12);
+        assert_eq!(&glb[16..20], b"JSON");
+        let document = serde_json::from_slice(&glb[20..20 + json_len]).unwrap();
+        let bin = 20 + json_len;
+        if bin == glb.len() {
+            return (document, 0);
+        }
+        assert_eq!(&glb[bin + 4..bin + 8], b"BIN\0");
+        (document, word(bin))
+    }
+
+    fn assert_close(actual: &Value, expected: [f64; 3]) {
+        let actual: Vec<f64> = serde_json::from_value(actual.clone()).unwrap();
+        for (actual, expected) in actual.iter().zip(expected) {
+            assert!((actual - expected).abs() < 1e-6, "{actual} != {expected}");
+        }
+    }
+
+    #[test]
+    fn merges_collinear_moves_and_colors_by_speed() {
+        // Ten short extrusions along a line, a fast extrusion back, then a
+        // travel and a retraction
+        let mut source = String::from("G1 Z0.2 F600\nG1 X10 Y10 F6000\n");
+        for i in 1..=10 {
+            source.push_str(&format!("G1 X{} E{i} F1200\n", 10 + 2 * i));
+        }
+        source.push_str("G1 Y30 E11 F3000\nG1 X0 Y0 F9000\nG1 E10\n");
+        let statements = scherzo_gcode::parse(&source).unwrap();
+        let (document, bin_len) = read_glb(&toolpath_glb(&statements, &MachineLimits::default()));
+
+        let primitives = &document["meshes"][0]["primitives"];
+        let extrusion = &document["accessors"]
+            [primitives[0]["attributes"]["POSITION"].as_u64().unwrap() as usize];
+        // One merged line along X, and one along Y
+        assert_eq!(extrusion["count"], 4);
+        assert_close(&extrusion["min"], [0.01, 0.0002, -0.03]);
+        assert_close(&extrusion["max"], [0.03, 0.0002, -0.01]);
+        let travel = &document["accessors"]
+            [primitives[1]["attributes"]["POSITION"].as_u64().unwrap() as usize];
+        assert_eq!(travel["count"], 6);
+        assert_eq!(primitives[1]["material"], 1);
+        assert_eq!(bin_len, (4 + 4 + 6) * 12);
+
+        let extras = &document["scenes"][0]["extras"];
+        assert!(extras["min_speed"].as_f64().unwrap() < extras["max_speed"].as_f64().unwrap());
+    }
+
+    #[test]
+    fn keeps_corners_of_curved_paths() {
+        assert!(distance_to_segment([1.0, 0.1, 0.0], [0.0, 0.0, 0.0], [2.0, 0.0, 0.0]) > TOLERANCE);
+        let statements = scherzo_gcode::parse("G1 X10 E1 F1200\nG1 X20 Y1 E2\n").unwrap();
+        let (document, _) = read_glb(&toolpath_glb(&statements, &MachineLimits::default()));
+        assert_eq!(document["accessors"][0]["count"], 4);
+    }
+
+    #[test]
+    fn empty_programs_have_no_mesh() {
+        let statements = scherzo_gcode::parse("M104 S200\n").unwrap();
+        let (document, bin_len) = read_glb(&toolpath_glb(&statements, &MachineLimits::default()));
+        assert_eq!(bin_len, 0);
+        assert!(document.get("meshes").is_none());
+        assert_eq!(document["nodes"], json!([{ "name": "toolpath" }]));
+    }
+}
@@ -0,0 +1,216 @@
+//! Parameterized jobs.
+//!
+//! A G-code job becomes a template by declaring its variables in comments:
+//!
+//! ```gcode
+//! ; TEMPLATE_VARIABLE NAME=bed_temp DEFAULT=60
+//! ; TEMPLATE_VARIABLE NAME=purge_length DEFAULT=30
+//! M190 S{ bed_temp }
+//! G1 X{ purge_length } E{ purge_length / 10 }
+//! ```
+//!
+//! The rest of the file is a [`Template`], resolved with values for the
+//! variables before it is compiled. Each variable needs a default, so the
+//! job can be compiled, validated and previewed as uploaded. Files without
+//! declarations are plain G-code, even if they contain braces.
+
+use crate::template::Template;
+use anyhow::{Context, Result, bail};
+use serde_json::{Map, Value};
+
+/// Comment keyword declaring a variable.
+const DECLARATION: &str = "TEMPLATE_VARIABLE";
+
+/// A variable declared by a job template.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemplateVariable {
+    pub name: String,
+    /// Value used when the job is resolved without one.
+    pub default: Value,
+}
+
+/// A G-code job with declared variables.
+#[derive(Debug, Clone)]
+pub struct JobTemplate {
+    template: Template,
+    variables: Vec<TemplateVariable>,
+}
+
+impl JobTemplate {
+    /// Parse `source`, or return `None` if it declares no variables.
+    pub fn parse(source: &str) -> Result<Option<Self>> {
+        let mut variables: Vec<TemplateVariable> = Vec::new();
+        for (number, line) in source.lines().enumerate() {
+            let Some(variable) = parse_declaration(line)
+                .with_context(|| format!("line {}: invalid {DECLARATION}", number + 1))?
+            else {
+                continue;
+            };
+            if variables.iter().any(|v| v.name == variable.name) {
+                bail!(
+                    "line {}: template variable `{}` is declared twice",
+                    number + 1,
+                    variable.name
+                );
+            }
+            variables.push(variable);
+        }
+        if variables.is_empty() {
+            return Ok(None);
+        }
+        let template = Template::parse(source).context("invalid job template")?;
+        Ok(Some(Self {
+            template,
+            variables,
+        }))
+    }
+
+    /// The declared variables, in the order of their declarations.
+    pub fn variables(&self) -> &[TemplateVariable] {
+        &self.variables
+    }
+
+    /// The G-code for `values`, with declared defaults for the variables it
+    /// leaves out. Values for undeclared variables are an error.
+    pub fn resolve(&self, values: &Map<String, Value>) -> Result<String> {
+        if let Some(name) = values
+            .keys()
+            .find(|name| !self.variables.iter().any(|v| &v.name == *name))
+        {
+            bail!("job has no template variable `{name}`");
+        }
+        let mut context = Map::new();
+        for variable in &self.variables {
+            let value = values.get(&variable.name).unwrap_or(&variable.default);
+            context.insert(variable.name.clone(), value.clone());
+        }
+        self.template.render(&context)
+    }
+}
+
+/// Parse `; TEMPLATE_VARIABLE NAME=<name> DEFAULT=<value>`, or return `None`
+/// if `line` is something else.
+fn parse_declaration(line: &str) -> Result<Option<TemplateVariable>> {
+    let Some(comment) = line.trim_start().strip_prefix(';') else {
+        return Ok(None);
+    };
+    let mut words = comment.split_whitespace();
+    if !words
+        .next()
+        .is_some_and(|word| word.eq_ignore_ascii_case(DECLARATION))
+    {
+        return Ok(None);
+    }
+
+    let (mut name, mut default) = (None, None);
+    for word in words {
+        let Some((key, value)) = word.split_once('=') else {
+            bail!("expected KEY=VALUE, found `{word}`");
+        };
+        match key.to_ascii_uppercase().as_str() {
+            "NAME" => name = Some(value),
+            // Numbers and booleans keep their type; anything else is text
+            "DEFAULT" => {
+                default = Some(
+                    serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.into())),
+                )
+            }
+            _ => bail!("unknown parameter `{key}`"),
+        }
+    }
+    let name = name.context("missing NAME")?;
+    let identifier = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !identifier {
+        bail!("`{name}` is not a valid variable name");
+    }
+    let default = default.with_context(|| format!("template variable `{name}` has no DEFAULT"))?;
+    Ok(Some(TemplateVariable {
+        name: name.to_string(),
+        default,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const SOURCE: &str = "; TEMPLATE_VARIABLE NAME=bed_temp DEFAULT=60\n\
+                          ;template_variable name=material default=PLA\n\
+                          M190 S{ bed_temp }\n\
+                          M117 { material }\n";
+
+    #[test]
+    fn resolves_values_over_defaults() {
+        let template = JobTemplate::parse(SOURCE).unwrap().unwrap();
+        assert_eq!(
+            template.variables(),
+            [
+                TemplateVariable {
+                    name: "bed_temp".to_string(),
+                    default: json!(60),
+                },
+                TemplateVariable {
+                    name: "material".to_string(),
+                    default: json!("PLA"),
+                },
+            ]
+        );
+
+        let defaults = template.resolve(&Map::new()).unwrap();
+        assert!(defaults.ends_with("M190 S60\nM117 PLA\n"), "{defaults}");
+        let values = json!({ "bed_temp": 85 });
+        let resolved = template.resolve(values.as_object().unwrap()).unwrap();
+        assert!(resolved.ends_with("M190 S85\nM117 PLA\n"), "{resolved}");
+
+        let unknown = json!({ "nozzle_temp": 200 });
+        let err = template.resolve(unknown.as_object().unwrap()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "job has no template variable `nozzle_temp`"
+        );
+    }
+
+    #[test]
+    fn plain_gcode_is_not_a_template() {
+        let source = "; printed with {braces} in a comment\nG1 X10\n";
+        assert!(JobTemplate::parse(source).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_bad_declarations() {
+        let errors = [
+            (
+                "; TEMPLATE_VARIABLE NAME=temp\n",
+                "template variable `temp` has no DEFAULT",
+            ),
+            (
+                "; TEMPLATE_VARIABLE NAME=1x DEFAULT=1\n",
+                "`1x` is not a valid variable name",
+            ),
+            ("; TEMPLATE_VARIABLE DEFAULT=1\n", "missing NAME"),
+            (
+                "; TEMPLATE_VARIABLE NAME=a DEFAULT=1 MIN=0\n",
+                "unknown parameter `MIN`",
+            ),
+        ];
+        for (source, message) in errors {
+            let err = JobTemplate::parse(source).unwrap_err();
+            assert_eq!(
+                format!("{:#}", err),
+                format!("line 1: invalid TEMPLATE_VARIABLE: {message}")
+            );
+        }
+
+        let twice = "; TEMPLATE_VARIABLE NAME=a DEFAULT=1\n; TEMPLATE_VARIABLE NAME=a DEFAULT=2\n";
+        let err = JobTemplate::parse(twice).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 2: template variable `a` is declared twice"
+        );
+    }
+}
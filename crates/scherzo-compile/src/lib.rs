@@ -1,7 +1,31 @@
+pub mod arc_fit;
+pub mod decompile;
+pub mod describe;
+pub mod diff;
+pub mod dry_run;
+pub mod estimate;
+#[cfg(feature = "render")]
+pub mod gltf;
+pub mod job_template;
+pub mod modal;
+pub mod objects;
+#[cfg(feature = "render")]
+pub mod render;
+pub mod resume;
+pub mod simulate;
+pub mod source_map;
+pub mod template;
+pub mod wasm_util;
+
 use anyhow::{Context, Result, anyhow, bail};
+use arc_fit::{ArcFitConfig, ArcFitStats, fit_arcs};
+use describe::Description;
 use heck::ToKebabCase;
+use objects::{ObjectCommand, ObjectDefinition, collect_objects};
 use ryu::Buffer;
 use scherzo_gcode::{Number, Statement, Value, Word, parse};
+use serde::{Deserialize, Serialize};
+use source_map::SourceMap;
 use std::collections::{BTreeMap, BTreeSet, HashMap};
 use wasm_encoder::{
     CodeSection, ConstExpr, DataSection, EntityType, ExportKind, ExportSection, Function,
@@ -10,7 +34,7 @@ use wasm_encoder::{
 };
 use wit_component::{ComponentEncoder, StringEncoding, embed_component_metadata};
 use wit_encoder::{
-    Interface, Package, PackageName, ResourceFunc, StandaloneFunc, Type, TypeDef, World,
+    Field, Interface, Package, PackageName, ResourceFunc, StandaloneFunc, Type, TypeDef, World,
 };
 use wit_parser::Resolve;
 
@@ -23,26 +47,251 @@ pub struct Compilation {
     pub wasm: Vec<u8>,
     /// Component-encoded wasm with embedded WIT.
     pub component: Vec<u8>,
+    /// Verbs and parameter shapes used by the job.
+    pub metadata: Metadata,
+    /// Where the bytes of the job go.
+    pub sizes: SizeBreakdown,
+}
+
+/// Bytes of a compiled job, by what they hold.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SizeBreakdown {
+    /// The component as a whole.
+    pub component: usize,
+    /// The core module inside it.
+    pub wasm: usize,
+    /// Function bodies, mostly the `run` function making every host call.
+    pub code: usize,
+    /// Strings and lists passed to the host.
+    pub data: usize,
+    /// Function signatures and the imports naming each host call.
+    pub types: usize,
+    /// Body of the `run` function.
+    pub function: usize,
+}
+
+/// A limit of [`CompileOptions`] a job is over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizeLimit {
+    /// [`CompileOptions::max_component_size`].
+    Component,
+    /// [`CompileOptions::max_function_size`].
+    Function,
+}
+
+/// Error of compiling a job larger than [`CompileOptions`] allow, so callers
+/// can tell it from a program that does not compile at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeLimitExceeded {
+    pub limit: SizeLimit,
+    /// Bytes the job took.
+    pub size: usize,
+    /// Most bytes allowed.
+    pub max: usize,
+}
+
+impl std::fmt::Display for SizeLimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let what = match self.limit {
+            SizeLimit::Component => "compiled job",
+            SizeLimit::Function => "job's run function",
+        };
+        write!(
+            f,
+            "{what} takes {} bytes, over the limit of {}",
+            self.size, self.max
+        )
+    }
+}
+
+impl std::error::Error for SizeLimitExceeded {}
+
+/// Version of the compiler, which jobs compiled by another version may
+/// compile differently with.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Summary of the host calls a compiled job makes.
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    /// Number of statements submitted to the host.
+    pub statements: usize,
+    /// Verb token (e.g. "G1") mapped to each parameter's WIT types.
+    pub verbs: BTreeMap<String, BTreeMap<String, Vec<String>>>,
+    /// Objects labelled by the slicer, which can be excluded mid-print.
+    pub objects: Vec<ObjectDefinition>,
+    /// Source line, layer, and filament for each submitted statement.
+    pub source_map: SourceMap,
+    /// What arc fitting changed, if it ran.
+    pub arc_fit: Option<ArcFitStats>,
+}
+
+/// Passes run over a program before it is compiled, and limits on what it
+/// compiles to.
+#[derive(Debug, Clone, Default)]
+pub struct CompileOptions {
+    /// Input verb, like `M109`, mapped to the interface it compiles to.
+    /// Variants of one command share an interface, keeping the job's world
+    /// small.
+    pub verb_aliases: BTreeMap<String, VerbAlias>,
+    /// Replace runs of short `G1` segments with arcs and lines.
+    pub arc_fit: Option<ArcFitConfig>,
+    /// Have lines that start with a coordinate, like `X10 Y5`, repeat the
+    /// last `G0`-`G3` rather than be taken as an `X` verb.
+    pub modal_motion: bool,
+    /// Most bytes the component may take; larger jobs fail with
+    /// [`SizeLimitExceeded`].
+    pub max_component_size: Option<usize>,
+    /// Most bytes the body of the `run` function may take. Checked before
+    /// the component is encoded, so oversized jobs fail early.
+    pub max_function_size: Option<usize>,
+    /// How the job's interfaces take each verb's statements.
+    pub interface_shape: InterfaceShape,
+}
+
+/// Shape of the interface a job imports for each verb.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InterfaceShape {
+    /// A `builder` resource per statement, with a setter call per parameter
+    /// and a final `submit`.
+    #[default]
+    Builder,
+    /// A `submit` function taking a list of `statement` records, one field
+    /// per parameter, called once for each run of consecutive statements of
+    /// the verb. Dense move streams cross into the host far less often.
+    /// Verbs without parameters take the number of statements instead.
+    Batched,
+}
+
+/// Interface a verb of [`CompileOptions::verb_aliases`] compiles to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerbAlias {
+    /// Name of the interface, e.g. `set-hotend-temp`.
+    pub verb: String,
+    /// Parameters set on every statement of the verb, e.g. a `wait` flag
+    /// telling `M109` from `M104`. A statement's own parameter of the same
+    /// name takes precedence.
+    pub params: Vec<(String, Value)>,
+}
+
+impl VerbAlias {
+    /// Compile the verb as `verb`, with no added parameters.
+    pub fn new(verb: impl Into<String>) -> Self {
+        Self {
+            verb: verb.into(),
+            params: Vec::new(),
+        }
+    }
+
+    /// Also set `name` to `value` on every statement.
+    pub fn with_param(mut self, name: impl Into<String>, value: Value) -> Self {
+        self.params.push((name.into(), value));
+        self
+    }
+}
+
+impl Compilation {
+    /// Render the job metadata, including artifact sizes, as JSON.
+    pub fn metadata_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "statements": self.metadata.statements,
+            "verbs": self.metadata.verbs,
+            "objects": self
+                .metadata
+                .objects
+                .iter()
+                .map(|object| serde_json::json!({
+                    "name": object.name,
+                    "center": object.center,
+                    "polygon": object.polygon,
+                }))
+                .collect::<Vec<_>>(),
+            "layers": self.metadata.source_map.layers,
+            "arc_fit": self.metadata.arc_fit.as_ref().map(ArcFitStats::to_json),
+            "sizes": {
+                "wit": self.wit.len(),
+                "wasm": self.sizes.wasm,
+                "component": self.sizes.component,
+                "code": self.sizes.code,
+                "data": self.sizes.data,
+                "types": self.sizes.types,
+                "function": self.sizes.function,
+            },
+        })
+    }
 }
 
 /// Compile a G-code program into a per-job WIT description and a wasm module
 /// that calls host-provided builder functions in the same order as the input.
 pub fn compile_gcode(source: &str) -> Result<Compilation> {
-    let statements = parse(source).context("failed to parse gcode")?;
-    let (verb_shapes, compiled_stmts) = infer_shapes(&statements)?;
+    compile_gcode_with(source, &CompileOptions::default())
+}
 
-    let wit = build_wit(&verb_shapes)?;
-    let module = build_wasm(&verb_shapes, &compiled_stmts)?;
-    let component = build_component(&wit, &module)?;
-    let wasm = module.finish();
+/// Compile a G-code program like [`compile_gcode`], running the passes in
+/// `options` first.
+pub fn compile_gcode_with(source: &str, options: &CompileOptions) -> Result<Compilation> {
+    let mut statements = parse(source).context("failed to parse gcode")?;
+    if options.modal_motion {
+        modal::attach_motion_verbs(&mut statements);
+    }
+    let arc_fit = options.arc_fit.map(|config| {
+        let (fitted, stats) = fit_arcs(&statements, &config);
+        statements = fitted;
+        stats
+    });
+    let (verb_shapes, compiled_stmts) = infer_shapes(&statements, &options.verb_aliases)?;
+
+    let wit = build_wit(&verb_shapes, options.interface_shape)?;
+    let (module, function) = build_wasm(&verb_shapes, &compiled_stmts, options.interface_shape)?;
+    check_size(SizeLimit::Function, function, options.max_function_size)?;
+    let description = Description::new(&verb_shapes, options.interface_shape);
+    let component = description.embed(&build_component(&wit, &module)?)?;
+    check_size(
+        SizeLimit::Component,
+        component.len(),
+        options.max_component_size,
+    )?;
+    let wasm = description.embed(&module.finish())?;
+    let sizes = size_breakdown(&wasm, component.len(), function)?;
+    let mut metadata = build_metadata(&verb_shapes, &compiled_stmts);
+    metadata.objects = collect_objects(&statements);
+    metadata.source_map = SourceMap::build(&statements);
+    metadata.arc_fit = arc_fit;
 
     Ok(Compilation {
         wit,
         wasm,
         component,
+        metadata,
+        sizes,
     })
 }
 
+fn check_size(limit: SizeLimit, size: usize, max: Option<usize>) -> Result<()> {
+    match max {
+        Some(max) if size > max => Err(SizeLimitExceeded { limit, size, max }.into()),
+        _ => Ok(()),
+    }
+}
+
+fn size_breakdown(wasm: &[u8], component: usize, function: usize) -> Result<SizeBreakdown> {
+    let mut sizes = SizeBreakdown {
+        component,
+        wasm: wasm.len(),
+        function,
+        ..SizeBreakdown::default()
+    };
+    for section in wasm_util::section_sizes(wasm)? {
+        match section.name.as_str() {
+            "code" => sizes.code += section.size,
+            "data" | "data count" => sizes.data += section.size,
+            "type" | "import" | "function" => sizes.types += section.size,
+            _ => {}
+        }
+    }
+    Ok(sizes)
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 enum ParamKind {
     Int,
@@ -81,14 +330,28 @@ struct CompiledStatement {
     params: Vec<(String, ParamLiteral)>,
 }
 
-fn infer_shapes(statements: &[Statement]) -> Result<(Vec<VerbShape>, Vec<CompiledStatement>)> {
+fn infer_shapes(
+    statements: &[Statement],
+    aliases: &BTreeMap<String, VerbAlias>,
+) -> Result<(Vec<VerbShape>, Vec<CompiledStatement>)> {
     let mut per_verb: HashMap<String, VerbShape> = HashMap::new();
     let mut compiled = Vec::new();
 
     for stmt in statements {
-        let Some((verb, tail)) = split_verb(stmt) else {
+        let Some((mut verb, tail)) = split_verb(stmt) else {
             continue;
         };
+        let mut params: Vec<(String, &Value)> = tail.iter().filter_map(normalize_param).collect();
+        if let Some(alias) = aliases.get(&verb.raw) {
+            verb.raw = alias.verb.clone();
+            let added = alias
+                .params
+                .iter()
+                .filter(|(name, _)| !params.iter().any(|(own, _)| own == name))
+                .map(|(name, value)| (name.clone(), value))
+                .collect::<Vec<_>>();
+            params.splice(0..0, added);
+        }
 
         let verb_shape = per_verb
             .entry(verb.raw.clone())
@@ -99,11 +362,7 @@ fn infer_shapes(statements: &[Statement]) -> Result<(Vec<VerbShape>, Vec<Compile
 
         let mut compiled_params = Vec::new();
 
-        for word in tail {
-            let Some((name, value)) = normalize_param(word) else {
-                continue;
-            };
-
+        for (name, value) in params {
             let (kind, literal) = classify_value(value)?;
             let shape = verb_shape
                 .params
@@ -126,9 +385,56 @@ fn infer_shapes(statements: &[Statement]) -> Result<(Vec<VerbShape>, Vec<Compile
     Ok((verbs, compiled))
 }
 
+fn build_metadata(verbs: &[VerbShape], statements: &[CompiledStatement]) -> Metadata {
+    let verbs = verbs
+        .iter()
+        .map(|verb| {
+            let params = verb
+                .params
+                .iter()
+                .map(|(name, shape)| {
+                    let kinds = shape.kinds.iter().map(wit_type_name).collect();
+                    (name.clone(), kinds)
+                })
+                .collect();
+            (verb.raw.clone(), params)
+        })
+        .collect();
+
+    Metadata {
+        statements: statements.len(),
+        verbs,
+        objects: Vec::new(),
+        source_map: SourceMap::default(),
+        arc_fit: None,
+    }
+}
+
+fn wit_type_name(kind: &ParamKind) -> String {
+    match kind {
+        ParamKind::Int => "s64",
+        ParamKind::Float => "f64",
+        ParamKind::String => "string",
+        ParamKind::ListInt => "list<s64>",
+        ParamKind::ListFloat => "list<f64>",
+        ParamKind::ListString => "list<string>",
+    }
+    .to_string()
+}
+
 fn split_verb(stmt: &Statement) -> Option<(NormalizedVerb, &[Word])> {
     let first = stmt.words.first()?;
-    let verb = normalize_verb(first)?;
+    // Of the extended commands, only the object markers are kept, so the
+    // printer can skip the moves of excluded objects. Definitions are in the
+    // metadata instead.
+    let marker =
+        ObjectCommand::parse(stmt).filter(|command| !matches!(command, ObjectCommand::Define(_)));
+    let verb = match (marker, &first.value) {
+        (Some(_), Some(Value::Text(command))) => NormalizedVerb {
+            raw: command.to_ascii_uppercase(),
+        },
+        _ => normalize_verb(first)?,
+    };
     Some((verb, &stmt.words[1..]))
 }
 
@@ -231,13 +537,19 @@ fn classify_list(items: &[Value]) -> Result<(ParamKind, ParamLiteral)> {
     bail!("unsupported list contents")
 }
 
-fn build_wit(verbs: &[VerbShape]) -> Result<String> {
+fn build_wit(verbs: &[VerbShape], shape: InterfaceShape) -> Result<String> {
     let mut pkg = Package::new(PackageName::new("job", "print", None));
 
     let mut world = World::new("job");
 
     for verb in verbs {
         let mut iface = Interface::new(verb.raw.to_kebab_case());
+        if shape == InterfaceShape::Batched {
+            batched_interface(&mut iface, verb);
+            world.named_interface_import(iface.name().clone());
+            pkg.interface(iface);
+            continue;
+        }
         let mut funcs = Vec::new();
 
         funcs.push(ResourceFunc::constructor());
@@ -264,6 +576,99 @@ fn build_wit(verbs: &[VerbShape]) -> Result<String> {
     Ok(format!("{pkg}"))
 }
 
+/// Declare the `statement` record and `submit` function of
+/// [`InterfaceShape::Batched`].
+fn batched_interface(iface: &mut Interface, verb: &VerbShape) {
+    let mut submit = StandaloneFunc::new("submit", false);
+    if verb.params.is_empty() {
+        submit.params_mut().item("count", Type::U32);
+    } else {
+        let fields = record_fields(verb)
+            .map(|(name, kind)| Field::new(name, Type::option(type_for_kind(kind))));
+        iface.type_def(TypeDef::record("statement", fields));
+        submit
+            .params_mut()
+            .item("statements", Type::list(Type::named("statement")));
+    }
+    iface.function(submit);
+}
+
+/// Fields of a verb's `statement` record in declaration order, named like
+/// the setters of the builder shape, e.g. `x-float`.
+fn record_fields(verb: &VerbShape) -> impl Iterator<Item = (String, &ParamKind)> {
+    verb.params.iter().flat_map(|(param, shape)| {
+        shape.kinds.iter().map(move |kind| {
+            (
+                format!("{}{}", param.to_kebab_case(), kind_suffix(kind)),
+                kind,
+            )
+        })
+    })
+}
+
+/// Canonical ABI layout of a verb's `statement` record.
+struct RecordLayout {
+    /// Offset of the `option` field for each parameter and kind.
+    fields: BTreeMap<(String, ParamKind), u32>,
+    size: u32,
+    align: u32,
+}
+
+impl RecordLayout {
+    fn new(verb: &VerbShape) -> Self {
+        let mut fields = BTreeMap::new();
+        let mut size = 0u32;
+        let mut align = 1;
+        for (param, shape) in &verb.params {
+            for kind in &shape.kinds {
+                let (field_size, field_align) = option_size_align(kind);
+                let offset = size.next_multiple_of(field_align);
+                fields.insert((param.clone(), kind.clone()), offset);
+                size = offset + field_size;
+                align = align.max(field_align);
+            }
+        }
+        Self {
+            fields,
+            size: size.next_multiple_of(align),
+            align,
+        }
+    }
+
+    /// The records of `stmts` as the list `submit` reads.
+    fn encode(&self, stmts: &[CompiledStatement], data: &mut DataAllocator) -> Vec<u8> {
+        let mut bytes = vec![0; self.size as usize * stmts.len()];
+        for (record, stmt) in bytes.chunks_exact_mut(self.size as usize).zip(stmts) {
+            for (param, literal) in &stmt.params {
+                let kind = literal_kind(literal);
+                let offset = self.fields[&(param.clone(), kind.clone())] as usize;
+                let payload = match literal {
+                    ParamLiteral::I64(i) => i.to_le_bytes().to_vec(),
+                    ParamLiteral::F64(f) => f.to_le_bytes().to_vec(),
+                    _ => {
+                        let (ptr, len) = alloc_literal(literal, data).expect("pointer literal");
+                        [ptr.to_le_bytes(), len.to_le_bytes()].concat()
+                    }
+                };
+                // `some`, then the payload after the one byte discriminant
+                let start = offset + option_size_align(&kind).1 as usize;
+                record[offset] = 1;
+                record[start..start + payload.len()].copy_from_slice(&payload);
+            }
+        }
+        bytes
+    }
+}
+
+/// Size and alignment of an `option` of a parameter of `kind`.
+fn option_size_align(kind: &ParamKind) -> (u32, u32) {
+    match kind {
+        ParamKind::Int | ParamKind::Float => (16, 8),
+        // A pointer and a length
+        _ => (12, 4),
+    }
+}
+
 fn type_for_kind(kind: &ParamKind) -> Type {
     match kind {
         ParamKind::Int => Type::S64,
@@ -318,7 +723,12 @@ impl DataAllocator {
     }
 }
 
-fn build_wasm(verbs: &[VerbShape], stmts: &[CompiledStatement]) -> Result<Module> {
+/// The job's core module and the size of its `run` function's body.
+fn build_wasm(
+    verbs: &[VerbShape],
+    stmts: &[CompiledStatement],
+    shape: InterfaceShape,
+) -> Result<(Module, usize)> {
     let mut types = TypeSection::new();
     let mut type_cache: HashMap<(Vec<ValType>, Vec<ValType>), u32> = HashMap::new();
     let mut imports = ImportSection::new();
@@ -349,6 +759,19 @@ fn build_wasm(verbs: &[VerbShape], stmts: &[CompiledStatement]) -> Result<Module
 
     for verb in verbs {
         let module = import_module_name(&verb.raw);
+        if shape == InterfaceShape::Batched {
+            // A count, or the pointer and length of a list of records
+            let params = if verb.params.is_empty() {
+                vec![ValType::I32]
+            } else {
+                vec![ValType::I32, ValType::I32]
+            };
+            let ty = add_func_type(params, vec![], &mut types, &mut type_cache);
+            imports.import(&module, "submit", EntityType::Function(ty));
+            import_indices.insert(format!("{module}::submit"), next_func_index);
+            next_func_index += 1;
+            continue;
+        }
         let builder_ident = "builder".to_string();
         let builder_symbol = builder_ident.clone();
         let ctor_name = format!("[constructor]{builder_symbol}");
@@ -404,44 +827,69 @@ fn build_wasm(verbs: &[VerbShape], stmts: &[CompiledStatement]) -> Result<Module
 
     let mut func = Function::new(vec![(1, ValType::I32)]);
 
-    for stmt in stmts {
-        let module = import_module_name(&stmt.verb);
-        // builder handle
-        let builder_ident = "builder".to_string();
-        let builder_symbol = builder_ident.clone();
-        let ctor_name = format!("[constructor]{builder_symbol}");
-        let lookup = format!("{module}::{ctor_name}");
-        let ctor = *import_indices.get(&lookup).ok_or_else(|| {
-            let keys: Vec<_> = import_indices.keys().cloned().collect();
-            anyhow!("missing ctor key {lookup}; available: {keys:?}")
-        })?;
-        func.instruction(&Instruction::Call(ctor));
-        func.instruction(&Instruction::LocalSet(0));
-
-        for (param, literal) in &stmt.params {
-            let kind = literal_kind(literal);
-            let setter_name = format!(
-                "[method]{builder_symbol}.set-{}{}",
-                param.to_kebab_case(),
-                kind_suffix(&kind)
-            );
-            let setter = *import_indices
-                .get(&format!("{module}::{setter_name}"))
-                .ok_or_else(|| anyhow!("missing setter for {module}:{param}"))?;
+    if shape == InterfaceShape::Batched {
+        let verbs: HashMap<&str, &VerbShape> = verbs.iter().map(|v| (v.raw.as_str(), v)).collect();
+        let mut layouts: HashMap<&str, RecordLayout> = HashMap::new();
+        for batch in stmts.chunk_by(|a, b| a.verb == b.verb) {
+            let verb = verbs[batch[0].verb.as_str()];
+            let module = import_module_name(&verb.raw);
+            let submit = *import_indices
+                .get(&format!("{module}::submit"))
+                .ok_or_else(|| anyhow!("missing submit for {module}"))?;
+            if verb.params.is_empty() {
+                func.instruction(&Instruction::I32Const(batch.len() as i32));
+            } else {
+                let layout = layouts
+                    .entry(&verb.raw)
+                    .or_insert_with(|| RecordLayout::new(verb));
+                let records = layout.encode(batch, &mut data_alloc);
+                let (offset, _) = data_alloc.alloc(records, layout.align);
+                func.instruction(&Instruction::I32Const(offset as i32));
+                func.instruction(&Instruction::I32Const(batch.len() as i32));
+            }
+            func.instruction(&Instruction::Call(submit));
+        }
+    } else {
+        for stmt in stmts {
+            let module = import_module_name(&stmt.verb);
+            // builder handle
+            let builder_ident = "builder".to_string();
+            let builder_symbol = builder_ident.clone();
+            let ctor_name = format!("[constructor]{builder_symbol}");
+            let lookup = format!("{module}::{ctor_name}");
+            let ctor = *import_indices.get(&lookup).ok_or_else(|| {
+                let keys: Vec<_> = import_indices.keys().cloned().collect();
+                anyhow!("missing ctor key {lookup}; available: {keys:?}")
+            })?;
+            func.instruction(&Instruction::Call(ctor));
+            func.instruction(&Instruction::LocalSet(0));
+
+            for (param, literal) in &stmt.params {
+                let kind = literal_kind(literal);
+                let setter_name = format!(
+                    "[method]{builder_symbol}.set-{}{}",
+                    param.to_kebab_case(),
+                    kind_suffix(&kind)
+                );
+                let setter = *import_indices
+                    .get(&format!("{module}::{setter_name}"))
+                    .ok_or_else(|| anyhow!("missing setter for {module}:{param}"))?;
 
+                func.instruction(&Instruction::LocalGet(0));
+                emit_literal(&mut func, literal, &mut data_alloc);
+                func.instruction(&Instruction::Call(setter));
+            }
+            let submit_name = format!("[method]{builder_symbol}.submit");
+            let submit = *import_indices
+                .get(&format!("{module}::{submit_name}"))
+                .ok_or_else(|| anyhow!("missing submit for {module}"))?;
             func.instruction(&Instruction::LocalGet(0));
-            emit_literal(&mut func, literal, &mut data_alloc);
-            func.instruction(&Instruction::Call(setter));
+            func.instruction(&Instruction::Call(submit));
         }
-        let submit_name = format!("[method]{builder_symbol}.submit");
-        let submit = *import_indices
-            .get(&format!("{module}::{submit_name}"))
-            .ok_or_else(|| anyhow!("missing submit for {module}"))?;
-        func.instruction(&Instruction::LocalGet(0));
-        func.instruction(&Instruction::Call(submit));
     }
 
     func.instruction(&Instruction::End);
+    let function_size = func.byte_len();
     code.function(&func);
 
     exports.export("run", ExportKind::Func, run_index);
@@ -476,7 +924,7 @@ fn build_wasm(verbs: &[VerbShape], stmts: &[CompiledStatement]) -> Result<Module
         module.section(&data);
     }
 
-    Ok(module)
+    Ok((module, function_size))
 }
 
 fn build_component(wit: &str, core: &Module) -> Result<Vec<u8>> {
@@ -505,19 +953,28 @@ fn emit_literal(func: &mut Function, lit: &ParamLiteral, data: &mut DataAllocato
         ParamLiteral::F64(f) => {
             func.instruction(&Instruction::F64Const(Ieee64::from(*f)));
         }
-        ParamLiteral::Str(s) => {
-            let (offset, len) = data.alloc(s.as_bytes().to_vec(), 1);
+        _ => {
+            let (offset, len) = alloc_literal(lit, data).expect("pointer literal");
             func.instruction(&Instruction::I32Const(offset as i32));
             func.instruction(&Instruction::I32Const(len as i32));
         }
+    }
+}
+
+/// Store a string or list literal in the data segments, returning the
+/// pointer and length the canonical ABI passes for it, or `None` for
+/// numbers.
+fn alloc_literal(lit: &ParamLiteral, data: &mut DataAllocator) -> Option<(u32, u32)> {
+    Some(match lit {
+        ParamLiteral::I64(_) | ParamLiteral::F64(_) => return None,
+        ParamLiteral::Str(s) => data.alloc(s.as_bytes().to_vec(), 1),
         ParamLiteral::ListI64(items) => {
             let mut bytes = Vec::with_capacity(items.len() * 8);
             for i in items {
                 bytes.extend_from_slice(&i.to_le_bytes());
             }
             let (offset, len) = data.alloc(bytes, 8);
-            func.instruction(&Instruction::I32Const(offset as i32));
-            func.instruction(&Instruction::I32Const((len / 8) as i32));
+            (offset, len / 8)
         }
         ParamLiteral::ListF64(items) => {
             let mut bytes = Vec::with_capacity(items.len() * 8);
@@ -525,8 +982,7 @@ fn emit_literal(func: &mut Function, lit: &ParamLiteral, data: &mut DataAllocato
                 bytes.extend_from_slice(&f.to_le_bytes());
             }
             let (offset, len) = data.alloc(bytes, 8);
-            func.instruction(&Instruction::I32Const(offset as i32));
-            func.instruction(&Instruction::I32Const((len / 8) as i32));
+            (offset, len / 8)
         }
         ParamLiteral::ListStr(items) => {
             let mut string_spans: Vec<(u32, u32)> = Vec::with_capacity(items.len());
@@ -541,13 +997,14 @@ fn emit_literal(func: &mut Function, lit: &ParamLiteral, data: &mut DataAllocato
                 bytes.extend_from_slice(&len.to_le_bytes());
             }
             let (offset, len) = data.alloc(bytes, 4);
-            func.instruction(&Instruction::I32Const(offset as i32));
-            func.instruction(&Instruction::I32Const((len / 8) as i32));
+            (offset, len / 8)
         }
-    }
+    })
 }
 
-fn import_module_name(raw: &str) -> String {
+/// Interface a job imports to run the verb `raw`, e.g. `job:print/m104` for
+/// `M104`.
+pub fn import_module_name(raw: &str) -> String {
     format!("job:print/{}", raw.to_kebab_case())
 }
 
@@ -576,6 +1033,174 @@ mod tests {
         assert!(Parser::is_component(&out.component));
     }
 
+    #[test]
+    fn reports_metadata() {
+        let input = "G1 X1.5 Y2\nG1 X3\nM117 \"hi\"\n";
+        let out = compile_gcode(input).expect("compile");
+
+        assert_eq!(out.metadata.statements, 3);
+        let g1 = &out.metadata.verbs["G1"];
+        assert_eq!(g1["X"], ["s64", "f64"]);
+        assert_eq!(g1["Y"], ["s64"]);
+
+        let json = out.metadata_json();
+        assert_eq!(json["sizes"]["component"], out.component.len());
+    }
+
+    #[test]
+    fn fits_arcs_before_compiling() {
+        let mut input = String::from("G1 X10 Y0 F1200\n");
+        for i in 1..=90 {
+            let angle = (i as f64).to_radians();
+            input.push_str(&format!(
+                "G1 X{:.4} Y{:.4}\n",
+                10.0 * angle.cos(),
+                10.0 * angle.sin()
+            ));
+        }
+        let options = CompileOptions {
+            arc_fit: Some(ArcFitConfig::default()),
+            ..CompileOptions::default()
+        };
+        let out = compile_gcode_with(&input, &options).expect("compile");
+
+        assert_eq!(out.metadata.statements, 2);
+        assert!(out.metadata.verbs.contains_key("G3"));
+        let json = out.metadata_json();
+        assert_eq!(json["arc_fit"]["statements_before"], 91);
+        assert!(compile_gcode(&input).unwrap().metadata_json()["arc_fit"].is_null());
+    }
+
+    #[test]
+    fn modal_motion_keeps_bare_lines_out_of_the_interface() {
+        let input = "G1 X0 Y0 F1200\nX10 Y5\n";
+        assert!(
+            compile_gcode(input)
+                .unwrap()
+                .metadata
+                .verbs
+                .contains_key("X10")
+        );
+
+        let options = CompileOptions {
+            modal_motion: true,
+            ..CompileOptions::default()
+        };
+        let out = compile_gcode_with(input, &options).expect("compile");
+        assert_eq!(out.metadata.verbs.keys().collect::<Vec<_>>(), ["G1"]);
+        assert_eq!(out.metadata.statements, 2);
+    }
+
+    #[test]
+    fn enforces_size_limits() {
+        let input = "M118 P\"hello\"\nG1 X1 Y2\nG1 X3\n".repeat(20);
+        let out = compile_gcode(&input).expect("compile");
+        let sizes = out.sizes;
+        assert_eq!(sizes.component, out.component.len());
+        assert_eq!(sizes.wasm, out.wasm.len());
+        assert!(sizes.function > 0 && sizes.function < sizes.code);
+        assert!(sizes.data > 20 * "hello".len());
+        assert!(sizes.code + sizes.data + sizes.types < sizes.wasm);
+
+        let fits = CompileOptions {
+            max_component_size: Some(sizes.component),
+            max_function_size: Some(sizes.function),
+            ..CompileOptions::default()
+        };
+        assert_eq!(compile_gcode_with(&input, &fits).unwrap().sizes, sizes);
+
+        for (options, limit) in [
+            (
+                CompileOptions {
+                    max_function_size: Some(sizes.function - 1),
+                    ..fits.clone()
+                },
+                SizeLimit::Function,
+            ),
+            (
+                CompileOptions {
+                    max_component_size: Some(1000),
+                    ..fits
+                },
+                SizeLimit::Component,
+            ),
+        ] {
+            let err = compile_gcode_with(&input, &options).unwrap_err();
+            let exceeded = err.downcast_ref::<SizeLimitExceeded>().unwrap();
+            assert_eq!(exceeded.limit, limit);
+        }
+    }
+
+    #[test]
+    fn maps_aliased_verbs_to_one_interface() {
+        let wait = |flag| Value::Number(Number::Int(flag));
+        let options = CompileOptions {
+            verb_aliases: BTreeMap::from([
+                (
+                    "M104".to_string(),
+                    VerbAlias::new("set-hotend-temp").with_param("wait", wait(0)),
+                ),
+                (
+                    "M109".to_string(),
+                    VerbAlias::new("set-hotend-temp").with_param("wait", wait(1)),
+                ),
+            ]),
+            ..CompileOptions::default()
+        };
+        let input = "M104 S200\nM109 S210\nM109 S215 wait=0\nG1 X1\n";
+        let out = compile_gcode_with(input, &options).expect("compile");
+
+        assert!(out.wit.contains("interface set-hotend-temp"));
+        assert!(!out.wit.contains("interface m104"));
+        assert!(!out.wit.contains("interface m109"));
+        assert_eq!(
+            out.metadata.verbs.keys().collect::<Vec<_>>(),
+            ["G1", "set-hotend-temp"]
+        );
+        let temp = &out.metadata.verbs["set-hotend-temp"];
+        assert_eq!(temp["S"], ["s64"]);
+        assert_eq!(temp["wait"], ["s64"]);
+        assert_eq!(out.metadata.statements, 4);
+    }
+
+    fn host_calls(wasm: &[u8]) -> usize {
+        let mut calls = 0;
+        for payload in Parser::new(0).parse_all(wasm) {
+            if let wasmparser::Payload::CodeSectionEntry(body) = payload.unwrap() {
+                let mut ops = body.get_operators_reader().unwrap();
+                while !ops.eof() {
+                    if let wasmparser::Operator::Call { .. } = ops.read().unwrap() {
+                        calls += 1;
+                    }
+                }
+            }
+        }
+        calls
+    }
+
+    #[test]
+    fn batches_runs_of_a_verb_into_one_call() {
+        let input: String = (0..100).map(|i| format!("G1 X{i} Y{i}.5\n")).collect();
+        let batched = CompileOptions {
+            interface_shape: InterfaceShape::Batched,
+            ..CompileOptions::default()
+        };
+        let builder = compile_gcode(&input).expect("compile");
+        let out = compile_gcode_with(&input, &batched).expect("compile");
+
+        assert!(out.wit.contains("record statement"));
+        assert!(
+            out.wit
+                .contains("submit: func(statements: list<statement>)")
+        );
+        assert!(!out.wit.contains("resource builder"));
+        // One `submit` for the whole run instead of a builder, two setters
+        // and a submit per statement
+        assert_eq!(host_calls(&out.wasm), 1);
+        assert_eq!(host_calls(&builder.wasm), 400);
+        assert!(Parser::is_component(&out.component));
+    }
+
     #[test]
     fn preserves_float_verb_with_hyphen() {
         let input = "G1.0 X1\n";
@@ -0,0 +1,74 @@
+//! Modal motion for bare coordinate lines.
+//!
+//! Some dialects, e.g. LinuxCNC-style and hand-written G-code, leave out the
+//! verb of a move that repeats the previous one: `G1 X0 Y0` followed by
+//! `X10 Y5`. Compiled as is, `X10` would be taken as the verb. With
+//! [`attach_motion_verbs`], such a line repeats the last `G0`-`G3` instead.
+
+use crate::estimate::verb;
+use scherzo_gcode::{Number, Statement, Value, Word};
+
+/// Words a bare line may start with and still be a move.
+const MOTION_WORDS: [char; 7] = ['X', 'Y', 'Z', 'E', 'I', 'J', 'F'];
+
+/// Verbs that stay in effect for the bare lines after them.
+const MODAL_VERBS: [&str; 4] = ["G0", "G1", "G2", "G3"];
+
+/// Give each line that starts with a coordinate the last motion verb before
+/// it, returning how many lines were changed. Lines before the first motion
+/// verb are left as they are.
+pub fn attach_motion_verbs(statements: &mut [Statement]) -> usize {
+    let mut modal: Option<i64> = None;
+    let mut attached = 0;
+    for stmt in statements {
+        if let Some(verb) = verb(stmt)
+            && MODAL_VERBS.contains(&verb.as_str())
+        {
+            modal = verb[1..].parse().ok();
+            continue;
+        }
+        if let Some(number) = modal
+            && is_bare_motion(stmt)
+        {
+            let word = Word {
+                letter: Some('G'),
+                name: None,
+                value: Some(Value::Number(Number::Int(number))),
+            };
+            stmt.words.insert(0, word);
+            attached += 1;
+        }
+    }
+    attached
+}
+
+/// Whether `stmt` starts with a coordinate or feedrate rather than a verb.
+fn is_bare_motion(stmt: &Statement) -> bool {
+    let Some(first) = stmt.words.first() else {
+        return false;
+    };
+    first.name.is_none()
+        && matches!(first.value, Some(Value::Number(_)))
+        && first
+            .letter
+            .is_some_and(|letter| MOTION_WORDS.contains(&letter.to_ascii_uppercase()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::estimate::param;
+    use scherzo_gcode::parse;
+
+    #[test]
+    fn bare_lines_repeat_the_last_motion_verb() {
+        let source = "X1\nG0 X0 Y0\nX10 Y5\nM104 S200\ny6 F600\nG2 X0 Y0 I1 J0\nI2 J0\nG92 E0\n";
+        let mut statements = parse(source).unwrap();
+        assert_eq!(attach_motion_verbs(&mut statements), 3);
+
+        let verbs: Vec<_> = statements.iter().filter_map(verb).collect();
+        assert_eq!(verbs, ["X1", "G0", "G0", "M104", "G0", "G2", "G2", "G92"]);
+        assert_eq!(param(&statements[2], 'Y'), Some(5.0));
+        assert_eq!(param(&statements[4], 'F'), Some(600.0));
+    }
+}
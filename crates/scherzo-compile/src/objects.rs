@@ -0,0 +1,149 @@
+//! Object markers for excluding objects mid-print.
+//!
+//! Slicers that label objects emit Klipper's extended commands:
+//! `EXCLUDE_OBJECT_DEFINE NAME=<name> CENTER=<x>,<y> POLYGON=<json>` once per
+//! object, then `EXCLUDE_OBJECT_START NAME=<name>` and `EXCLUDE_OBJECT_END`
+//! around each object's moves. A job may also exclude an object itself with
+//! `EXCLUDE_OBJECT NAME=<name>`.
+
+use scherzo_gcode::{Number, Statement, Value};
+
+/// An object the job prints, as defined by the slicer.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ObjectDefinition {
+    /// Object name, upper-cased.
+    pub name: String,
+    /// Center of the object on the bed, if known.
+    pub center: Option<[f64; 2]>,
+    /// Outline of the object on the bed, if known.
+    pub polygon: Vec<[f64; 2]>,
+}
+
+/// An object marker command.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ObjectCommand {
+    Define(ObjectDefinition),
+    Start(String),
+    End,
+    Exclude(String),
+}
+
+impl ObjectCommand {
+    /// Parse `stmt` if it is an object marker.
+    pub fn parse(stmt: &Statement) -> Option<Self> {
+        let first = stmt.words.first()?;
+        if first.letter.is_some() || first.name.is_some() {
+            return None;
+        }
+        let Some(Value::Text(command)) = &first.value else {
+            return None;
+        };
+        let name = || text_param(stmt, "NAME").map(|name| name.to_ascii_uppercase());
+        Some(match command.to_ascii_uppercase().as_str() {
+            "EXCLUDE_OBJECT_DEFINE" => Self::Define(ObjectDefinition {
+                name: name()?,
+                center: param(stmt, "CENTER").and_then(center),
+                polygon: param(stmt, "POLYGON").map(polygon).unwrap_or_default(),
+            }),
+            "EXCLUDE_OBJECT_START" => Self::Start(name()?),
+            "EXCLUDE_OBJECT_END" => Self::End,
+            "EXCLUDE_OBJECT" => Self::Exclude(name()?),
+            _ => return None,
+        })
+    }
+}
+
+/// Objects a program prints, in order of first definition. Objects that are
+/// started without being defined are included without a center or outline.
+pub fn collect_objects(statements: &[Statement]) -> Vec<ObjectDefinition> {
+    let mut objects: Vec<ObjectDefinition> = Vec::new();
+    for stmt in statements {
+        let object = match ObjectCommand::parse(stmt) {
+            Some(ObjectCommand::Define(object)) => object,
+            Some(ObjectCommand::Start(name)) => ObjectDefinition {
+                name,
+                ..Default::default()
+            },
+            _ => continue,
+        };
+        match objects.iter_mut().find(|o| o.name == object.name) {
+            Some(existing) if existing.center.is_none() && existing.polygon.is_empty() => {
+                *existing = object;
+            }
+            Some(_) => {}
+            None => objects.push(object),
+        }
+    }
+    objects
+}
+
+fn param<'a>(stmt: &'a Statement, name: &str) -> Option<&'a Value> {
+    stmt.words.iter().skip(1).find_map(|word| {
+        word.name
+            .as_ref()
+            .filter(|n| n.eq_ignore_ascii_case(name))
+            .and(word.value.as_ref())
+    })
+}
+
+fn text_param(stmt: &Statement, name: &str) -> Option<String> {
+    let text = render(param(stmt, name)?);
+    let text = text.trim_matches(|c| c == '"' || c == '\'');
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+/// Re-join a value the lexer split on commas.
+fn render(value: &Value) -> String {
+    match value {
+        Value::Number(Number::Int(i)) => i.to_string(),
+        Value::Number(Number::Float(f)) => f.to_string(),
+        Value::Text(text) => text.clone(),
+        Value::List(items) => items.iter().map(render).collect::<Vec<_>>().join(","),
+    }
+}
+
+fn center(value: &Value) -> Option<[f64; 2]> {
+    let text = render(value);
+    let (x, y) = text.split_once(',')?;
+    Some([x.trim().parse().ok()?, y.trim().parse().ok()?])
+}
+
+fn polygon(value: &Value) -> Vec<[f64; 2]> {
+    serde_json::from_str(&render(value)).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scherzo_gcode::parse;
+
+    #[test]
+    fn collects_defined_and_started_objects() {
+        let stmts = parse(
+            "EXCLUDE_OBJECT_DEFINE NAME=part_1 CENTER=10.5,20 POLYGON=[[1,2],[3,4.5],[5,2]]\n\
+             EXCLUDE_OBJECT_START NAME=part_2\n\
+             G1 X1\n\
+             EXCLUDE_OBJECT_END NAME=part_2\n\
+             EXCLUDE_OBJECT_START NAME=part_1\n\
+             EXCLUDE_OBJECT_END\n",
+        )
+        .unwrap();
+        let objects = collect_objects(&stmts);
+        assert_eq!(
+            objects,
+            [
+                ObjectDefinition {
+                    name: "PART_1".to_string(),
+                    center: Some([10.5, 20.0]),
+                    polygon: vec![[1.0, 2.0], [3.0, 4.5], [5.0, 2.0]],
+                },
+                ObjectDefinition {
+                    name: "PART_2".to_string(),
+                    ..Default::default()
+                },
+            ]
+        );
+        assert_eq!(ObjectCommand::parse(&stmts[2]), None);
+        assert_eq!(ObjectCommand::parse(&stmts[5]), Some(ObjectCommand::End));
+    }
+}
@@ -0,0 +1,337 @@
+//! Layer-by-layer pictures of a program's toolpath.
+//!
+//! [`Toolpath::build`] collects the extruding moves of a program, split into
+//! layers the same way as [`SourceMap`](crate::source_map::SourceMap), so a UI
+//! without a 3D viewer can show a job one layer at a time. Every layer is drawn
+//! over the extents of the whole print, so pictures of different layers line
+//! up.
+
+use crate::estimate::{MotionCommand, MotionInterpreter};
+use anyhow::Result;
+use flate2::{Compression, write::ZlibEncoder};
+use scherzo_gcode::Statement;
+use std::{fmt::Write as _, io::Write as _};
+
+/// Width (mm) extrusions are drawn with.
+const LINE_WIDTH: f64 = 0.45;
+/// Space (mm) around the print's extents.
+const MARGIN: f64 = 2.0;
+/// Color extrusions are drawn in.
+const INK: &str = "#1f6feb";
+/// Gray level (0 to 255) extrusions are rasterized in.
+const INK_GRAY: f64 = 40.0;
+
+/// A straight extruding move in the XY plane.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Segment {
+    pub from: [f64; 2],
+    pub to: [f64; 2],
+}
+
+/// Extrusions printed at one height.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layer {
+    /// Z height the layer was printed at.
+    pub z: f64,
+    pub segments: Vec<Segment>,
+}
+
+/// Extents (mm) of every extrusion in the XY plane.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+    pub min: [f64; 2],
+    pub max: [f64; 2],
+}
+
+impl Bounds {
+    fn include(&mut self, point: [f64; 2]) {
+        for (axis, value) in point.into_iter().enumerate() {
+            self.min[axis] = self.min[axis].min(value);
+            self.max[axis] = self.max[axis].max(value);
+        }
+    }
+
+    /// The extents with [`MARGIN`] added on every side.
+    fn padded(&self) -> Self {
+        Self {
+            min: self.min.map(|v| v - MARGIN),
+            max: self.max.map(|v| v + MARGIN),
+        }
+    }
+
+    fn width(&self) -> f64 {
+        self.max[0] - self.min[0]
+    }
+
+    fn height(&self) -> f64 {
+        self.max[1] - self.min[1]
+    }
+}
+
+/// The extrusions of a program, layer by layer.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Toolpath {
+    /// Layers in print order; layer `n` (1-based) is `layers[n - 1]`.
+    pub layers: Vec<Layer>,
+    /// Extents of all layers, or `None` if nothing is extruded.
+    pub bounds: Option<Bounds>,
+}
+
+impl Toolpath {
+    /// Collect the extrusions of a parsed program.
+    pub fn build(statements: &[Statement]) -> Self {
+        let mut interp = MotionInterpreter::new();
+        let mut toolpath = Self::default();
+
+        for stmt in statements {
+            for cmd in interp.interpret(stmt) {
+                let MotionCommand::Move { start, target, .. } = cmd else {
+                    continue;
+                };
+                let extrude = target[3] - start[3];
+                let planar = start[0] != target[0] || start[1] != target[1];
+                if extrude <= 0.0 || !planar {
+                    continue;
+                }
+                // Layers start at each new height extruded at, as in source maps
+                let new_layer = toolpath
+                    .layers
+                    .last()
+                    .is_none_or(|layer| target[2] > layer.z + 0.000_001);
+                if new_layer {
+                    toolpath.layers.push(Layer {
+                        z: target[2],
+                        segments: Vec::new(),
+                    });
+                }
+                let segment = Segment {
+                    from: [start[0], start[1]],
+                    to: [target[0], target[1]],
+                };
+                let bounds = toolpath.bounds.get_or_insert(Bounds {
+                    min: segment.from,
+                    max: segment.from,
+                });
+                bounds.include(segment.from);
+                bounds.include(segment.to);
+                if let Some(layer) = toolpath.layers.last_mut() {
+                    layer.segments.push(segment);
+                }
+            }
+        }
+        toolpath
+    }
+
+    /// Parse and collect the extrusions of G-code `source`.
+    pub fn from_gcode(source: &str) -> Result<Self> {
+        Ok(Self::build(&scherzo_gcode::parse(source)?))
+    }
+
+    /// Layer `number` (1-based).
+    pub fn layer(&self, number: usize) -> Option<&Layer> {
+        self.layers.get(number.checked_sub(1)?)
+    }
+
+    /// Layer `number` (1-based) as an SVG document in millimeters, with Y
+    /// pointing up as on the bed.
+    pub fn layer_svg(&self, number: usize) -> Option<String> {
+        let layer = self.layer(number)?;
+        let bounds = self.bounds?.padded();
+        let (width, height) = (bounds.width(), bounds.height());
+        let point = |[x, y]: [f64; 2]| (x - bounds.min[0], bounds.max[1] - y);
+
+        let mut svg = String::new();
+        let _ = write!(
+            svg,
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width:.3}mm\" height=\"{height:.3}mm\" viewBox=\"0 0 {width:.3} {height:.3}\">\n\
+             <title>Layer {number} at Z{z:.3}</title>\n\
+             <path fill=\"none\" stroke=\"{INK}\" stroke-width=\"{LINE_WIDTH}\" stroke-linecap=\"round\" stroke-linejoin=\"round\" d=\"",
+            z = layer.z,
+        );
+        let mut pen = None;
+        for segment in &layer.segments {
+            if pen != Some(segment.from) {
+                let (x, y) = point(segment.from);
+                let _ = write!(svg, "M{x:.3} {y:.3}");
+            }
+            let (x, y) = point(segment.to);
+            let _ = write!(svg, "L{x:.3} {y:.3}");
+            pen = Some(segment.to);
+        }
+        svg.push_str("\"/>\n</svg>\n");
+        Some(svg)
+    }
+
+    /// Layer `number` (1-based) as a grayscale PNG whose longer side is
+    /// `size` pixels.
+    pub fn layer_png(&self, number: usize, size: u32) -> Option<Vec<u8>> {
+        let layer = self.layer(number)?;
+        let bounds = self.bounds?.padded();
+        let scale = f64::from(size.max(1)) / bounds.width().max(bounds.height());
+        let width = ((bounds.width() * scale).round() as u32).max(1);
+        let height = ((bounds.height() * scale).round() as u32).max(1);
+
+        let mut image = Raster::new(width, height);
+        let pixel = |[x, y]: [f64; 2]| [(x - bounds.min[0]) * scale, (bounds.max[1] - y) * scale];
+        let radius = (LINE_WIDTH * scale / 2.0).max(0.5);
+        for segment in &layer.segments {
+            image.line(pixel(segment.from), pixel(segment.to), radius);
+        }
+        Some(image.encode_png())
+    }
+}
+
+/// An 8-bit grayscale image, white until drawn on.
+struct Raster {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl Raster {
+    fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![u8::MAX; width as usize * height as usize],
+        }
+    }
+
+    /// Draw a line `radius` pixels either side of `from`–`to` with round
+    /// caps, antialiased by how far each pixel center is from the line.
+    fn line(&mut self, from: [f64; 2], to: [f64; 2], radius: f64) {
+        let reach = radius + 1.0;
+        let x_range = pixel_range(
+            from[0].min(to[0]) - reach,
+            from[0].max(to[0]) + reach,
+            self.width,
+        );
+        let y_range = pixel_range(
+            from[1].min(to[1]) - reach,
+            from[1].max(to[1]) + reach,
+            self.height,
+        );
+        let delta = [to[0] - from[0], to[1] - from[1]];
+        let length_sq = delta[0] * delta[0] + delta[1] * delta[1];
+
+        for y in y_range {
+            for x in x_range.clone() {
+                let center = [f64::from(x) + 0.5, f64::from(y) + 0.5];
+                let offset = [center[0] - from[0], center[1] - from[1]];
+                let t = if length_sq > 0.0 {
+                    ((offset[0] * delta[0] + offset[1] * delta[1]) / length_sq).clamp(0.0, 1.0)
+                } else {
+                    0.0
+                };
+                let distance = (offset[0] - t * delta[0]).hypot(offset[1] - t * delta[1]);
+                let coverage = (radius + 0.5 - distance).clamp(0.0, 1.0);
+                if coverage > 0.0 {
+                    let shade = (255.0 - coverage * (255.0 - INK_GRAY)).round() as u8;
+                    let index = y as usize * self.width as usize + x as usize;
+                    self.pixels[index] = self.pixels[index].min(shade);
+                }
+            }
+        }
+    }
+
+    fn encode_png(&self) -> Vec<u8> {
+        let mut rows = ZlibEncoder::new(Vec::new(), Compression::default());
+        for row in self.pixels.chunks(self.width as usize) {
+            // Each row starts with its filter type, here none
+            let _ = rows.write_all(&[0]);
+            let _ = rows.write_all(row);
+        }
+        let data = rows.finish().unwrap_or_default();
+
+        let mut header = Vec::with_capacity(13);
+        header.extend(self.width.to_be_bytes());
+        header.extend(self.height.to_be_bytes());
+        // 8-bit grayscale, deflate, adaptive filtering, no interlacing
+        header.extend([8, 0, 0, 0, 0]);
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png_chunk(&mut png, b"IHDR", &header);
+        png_chunk(&mut png, b"IDAT", &data);
+        png_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+}
+
+/// Pixels from `min` to `max` (inclusive), clamped to `0..len`.
+fn pixel_range(min: f64, max: f64, len: u32) -> std::ops::Range<u32> {
+    let start = min.floor().max(0.0) as u32;
+    let end = (max.ceil().max(0.0) as u32).min(len);
+    start.min(end)..end
+}
+
+fn png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend((data.len() as u32).to_be_bytes());
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+    png.extend(kind);
+    png.extend(data);
+    png.extend(crc.finalize().to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TWO_LAYERS: &str = "G28\nG1 Z0.2 F600\nG1 X10 Y10\nG1 X30 E1\nG1 Y30 E2\nG1 X10 Y10\nG1 Z0.4\nG1 X30 E3\nG1 X10 E2.5\n";
+
+    #[test]
+    fn splits_extrusions_into_layers() {
+        let toolpath = Toolpath::from_gcode(TWO_LAYERS).unwrap();
+        assert_eq!(toolpath.layers.len(), 2);
+        assert_eq!(toolpath.layers[0].z, 0.2);
+        assert_eq!(
+            toolpath.layers[0].segments,
+            [
+                Segment {
+                    from: [10.0, 10.0],
+                    to: [30.0, 10.0],
+                },
+                Segment {
+                    from: [30.0, 10.0],
+                    to: [30.0, 30.0],
+                },
+            ]
+        );
+        // Travel and retraction are not drawn
+        assert_eq!(toolpath.layers[1].segments.len(), 1);
+        let bounds = toolpath.bounds.unwrap();
+        assert_eq!((bounds.min, bounds.max), ([10.0, 10.0], [30.0, 30.0]));
+        assert!(toolpath.layer(0).is_none());
+        assert!(toolpath.layer(3).is_none());
+    }
+
+    #[test]
+    fn draws_layers_over_the_whole_print() {
+        let toolpath = Toolpath::from_gcode(TWO_LAYERS).unwrap();
+        let svg = toolpath.layer_svg(1).unwrap();
+        assert!(svg.contains("viewBox=\"0 0 24.000 24.000\""), "{svg}");
+        // Y is flipped so the bed's front is at the bottom
+        assert!(
+            svg.contains("d=\"M2.000 22.000L22.000 22.000L22.000 2.000\""),
+            "{svg}"
+        );
+        assert!(svg.contains("<title>Layer 1 at Z0.200</title>"), "{svg}");
+        assert!(toolpath.layer_svg(3).is_none());
+
+        let png = toolpath.layer_png(2, 240).unwrap();
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(png[16..20], 240u32.to_be_bytes());
+        assert_eq!(png[20..24], 240u32.to_be_bytes());
+        assert!(png.ends_with(&[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]));
+    }
+
+    #[test]
+    fn nothing_to_draw_without_extrusions() {
+        let toolpath = Toolpath::from_gcode("G28\nG1 X10 Y10 F600\n").unwrap();
+        assert!(toolpath.layers.is_empty());
+        assert!(toolpath.bounds.is_none());
+        assert!(toolpath.layer_png(1, 100).is_none());
+    }
+}
@@ -0,0 +1,212 @@
+//! Resuming a program part way through.
+//!
+//! After a power loss the printer no longer knows where the toolhead is, and
+//! homing Z would drive the nozzle into the part. A resume program reheats,
+//! declares the saved Z, lifts, homes only X and Y, and returns to the saved
+//! position. It then restores the offsets, modes, and fan speed the program
+//! had reached, and runs the rest of the program from the statement it
+//! stopped at.
+
+use crate::{
+    estimate::{MotionInterpreter, param, verb},
+    split_verb,
+};
+use anyhow::{Result, bail};
+use scherzo_gcode::Statement;
+use std::fmt::Write;
+
+/// Distance (mm) to lift the nozzle before homing X and Y.
+const Z_LIFT: f64 = 2.0;
+/// Speed (mm/s) of the lift and of the descent back to the part.
+const Z_SPEED: f64 = 10.0;
+/// Speed (mm/s) of the travel back to the saved position.
+const TRAVEL_SPEED: f64 = 100.0;
+
+/// Temperatures and part cooling fan speed a program has set.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ThermalState {
+    /// Extruder target temperature (°C).
+    pub extruder_temp: f64,
+    /// Bed target temperature (°C).
+    pub bed_temp: f64,
+    /// Part cooling fan speed, from 0 to 1.
+    pub fan_speed: f64,
+}
+
+impl ThermalState {
+    /// Track the heater and fan commands of `stmt`.
+    pub fn apply(&mut self, stmt: &Statement) {
+        let Some(verb) = verb(stmt) else {
+            return;
+        };
+        match verb.as_str() {
+            "M104" | "M109" => {
+                if let Some(temp) = param(stmt, 'S') {
+                    self.extruder_temp = temp;
+                }
+            }
+            "M140" | "M190" => {
+                if let Some(temp) = param(stmt, 'S') {
+                    self.bed_temp = temp;
+                }
+            }
+            "M106" => {
+                let speed = param(stmt, 'S').unwrap_or(255.0);
+                self.fan_speed = (speed / 255.0).clamp(0.0, 1.0);
+            }
+            "M107" => self.fan_speed = 0.0,
+            _ => {}
+        }
+    }
+}
+
+/// Where and how to resume a program.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResumePoint {
+    /// Number of submitted statements that already ran.
+    pub statement: usize,
+    /// Toolhead position (machine coordinates) when the program stopped.
+    pub position: [f64; 4],
+    /// Temperatures and fan speed to restore.
+    pub thermal: ThermalState,
+}
+
+/// G-code that resumes `statements` at `point`.
+pub fn resume_program(statements: &[Statement], point: &ResumePoint) -> Result<String> {
+    let mut interp = MotionInterpreter::new();
+    let mut submitted = 0;
+    let mut start = statements.len();
+    for (index, stmt) in statements.iter().enumerate() {
+        if split_verb(stmt).is_some() {
+            if submitted == point.statement {
+                start = index;
+                break;
+            }
+            submitted += 1;
+        }
+        interp.interpret(stmt);
+    }
+    if submitted < point.statement {
+        bail!(
+            "program has {submitted} statements, cannot resume after {}",
+            point.statement
+        );
+    }
+
+    let [x, y, z, _] = point.position;
+    let offsets = interp.offsets();
+    let gcode: Vec<_> = (0..4)
+        .map(|axis| number(point.position[axis] - offsets[axis]))
+        .collect();
+    let thermal = &point.thermal;
+    let mut out = String::new();
+    let mut line = |text: String| {
+        out.push_str(&text);
+        out.push('\n');
+    };
+
+    line(format!("; Resuming after statement {}", point.statement));
+    line("G21".into());
+    line("G90".into());
+    line("M82".into());
+    if thermal.bed_temp > 0.0 {
+        line(format!("M140 S{}", number(thermal.bed_temp)));
+    }
+    if thermal.extruder_temp > 0.0 {
+        line(format!("M104 S{}", number(thermal.extruder_temp)));
+    }
+    if thermal.bed_temp > 0.0 {
+        line(format!("M190 S{}", number(thermal.bed_temp)));
+    }
+    if thermal.extruder_temp > 0.0 {
+        line(format!("M109 S{}", number(thermal.extruder_temp)));
+    }
+    line(format!("G92 Z{}", number(z)));
+    line(format!(
+        "G1 Z{} F{}",
+        number(z + Z_LIFT),
+        number(Z_SPEED * 60.0)
+    ));
+    line("G28 X Y".into());
+    line(format!(
+        "G1 X{} Y{} F{}",
+        number(x),
+        number(y),
+        number(TRAVEL_SPEED * 60.0)
+    ));
+    line(format!("G1 Z{} F{}", number(z), number(Z_SPEED * 60.0)));
+    line(format!(
+        "G92 X{} Y{} Z{} E{}",
+        gcode[0], gcode[1], gcode[2], gcode[3]
+    ));
+    if thermal.fan_speed > 0.0 {
+        line(format!("M106 S{}", number(thermal.fan_speed * 255.0)));
+    } else {
+        line("M107".into());
+    }
+    if !interp.is_absolute() {
+        line("G91".into());
+    }
+    if !interp.is_absolute_e() {
+        line("M83".into());
+    }
+    let scale = if interp.is_inches() {
+        line("G20".into());
+        25.4
+    } else {
+        1.0
+    };
+    line(format!("G1 F{}", number(interp.speed() * 60.0 / scale)));
+
+    for stmt in &statements[start..] {
+        writeln!(out, "{}", stmt.raw.trim_end())?;
+    }
+    Ok(out)
+}
+
+/// Format `value` with at most 4 decimals and no trailing zeros.
+fn number(value: f64) -> String {
+    let text = format!("{value:.4}");
+    let text = text.trim_end_matches('0').trim_end_matches('.');
+    match text {
+        "-0" => "0".to_string(),
+        text => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scherzo_gcode::parse;
+
+    #[test]
+    fn resume_program_restores_state_and_continues() {
+        let source = "M140 S60\nM104 S210\nG28\nG92 E0\nM106 S127.5\nG1 Z0.2 F600\n\
+                      G1 X10 Y5 E1 F1800\nM83\nG1 X20 E0.5\nG1 X30 E0.5\n";
+        let stmts = parse(source).unwrap();
+
+        let mut thermal = ThermalState::default();
+        for stmt in &stmts {
+            thermal.apply(stmt);
+        }
+        assert_eq!(thermal.fan_speed, 0.5);
+
+        let point = ResumePoint {
+            statement: 9,
+            position: [20.0, 5.0, 0.2, 1.5],
+            thermal,
+        };
+        let program = resume_program(&stmts, &point).unwrap();
+        let expected = "; Resuming after statement 9\nG21\nG90\nM82\nM140 S60\nM104 S210\n\
+                        M190 S60\nM109 S210\nG92 Z0.2\nG1 Z2.2 F600\nG28 X Y\n\
+                        G1 X20 Y5 F6000\nG1 Z0.2 F600\nG92 X20 Y5 Z0.2 E1.5\nM106 S127.5\n\
+                        M83\nG1 F1800\nG1 X30 E0.5\n";
+        assert_eq!(program, expected);
+
+        let past_end = ResumePoint {
+            statement: 11,
+            ..point
+        };
+        assert!(resume_program(&stmts, &past_end).is_err());
+    }
+}
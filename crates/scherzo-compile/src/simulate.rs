@@ -0,0 +1,349 @@
+//! Virtual printer simulation.
+//!
+//! Drives the `scherzo-core` toolhead, iterative solvers and step
+//! compressors with the motion from a G-code program, recording every
+//! command that would be sent to the stepper MCUs. Useful for offline
+//! analysis of step timing without hardware attached.
+
+use crate::estimate::{MotionCommand, MotionInterpreter};
+use anyhow::{Context, Result};
+pub use scherzo_core::kinematics::Kinematics;
+use scherzo_core::{
+    itersolve::IterativeSolver,
+    kinematics::{StepperKinematics, cartesian},
+    planner::MachineLimits,
+    retraction::RetractionConfig,
+    step_compressor::{Command, RecordingSink, StepCompressor},
+    toolhead::MotionController,
+    trap_queue::TrapQueue,
+};
+use scherzo_gcode::{Statement, parse};
+use std::io::Write;
+
+/// Virtual printer configuration.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulationConfig {
+    pub kinematics: Kinematics,
+    /// Steps per mm for the X, Y, Z and E steppers (A/B for core kinematics).
+    pub steps_per_mm: [f64; 4],
+    /// MCU clock frequency (Hz).
+    pub mcu_freq: f64,
+    /// Maximum step time error (seconds) allowed by the step compressor.
+    pub max_step_error: f64,
+    /// Firmware retraction for `G10`/`G11`, if enabled.
+    pub retraction: Option<RetractionConfig>,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            kinematics: Kinematics::Cartesian,
+            steps_per_mm: [80.0, 80.0, 400.0, 100.0],
+            mcu_freq: 16_000_000.0,
+            max_step_error: 0.000_025,
+            retraction: None,
+        }
+    }
+}
+
+/// A single command emitted for a stepper.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceRecord {
+    pub stepper: &'static str,
+    pub command: Command,
+}
+
+/// Output of a simulation run.
+#[derive(Debug, Clone, Default)]
+pub struct Simulation {
+    /// Print time at which the last move completes (seconds).
+    pub print_time: f64,
+    /// Stepper commands in the order each stepper emitted them.
+    pub trace: Vec<TraceRecord>,
+    /// Total steps taken by each stepper, in declaration order.
+    pub steps: Vec<(&'static str, u64)>,
+    mcu_freq: f64,
+}
+
+/// Simulate a G-code program from source.
+pub fn simulate_gcode(
+    source: &str,
+    limits: &MachineLimits,
+    config: &SimulationConfig,
+) -> Result<Simulation> {
+    let statements = parse(source).context("failed to parse gcode")?;
+    simulate_statements(&statements, limits, config)
+}
+
+/// Simulate an already-parsed G-code program.
+pub fn simulate_statements(
+    statements: &[Statement],
+    limits: &MachineLimits,
+    config: &SimulationConfig,
+) -> Result<Simulation> {
+    let mut interp = MotionInterpreter::new();
+    if let Some(retraction) = config.retraction {
+        interp = interp.with_retraction(retraction);
+    }
+    let mut toolhead = MotionController::new(*limits);
+    let mut steppers = Stepper::for_config(config);
+
+    for stmt in statements {
+        for cmd in interp.interpret(stmt) {
+            match cmd {
+                MotionCommand::Move {
+                    start,
+                    target,
+                    speed,
+                } => {
+                    if start != toolhead.position() {
+                        // Homing teleports the toolhead
+                        flush_steps(&mut toolhead, &mut steppers, config)?;
+                        toolhead.set_position(start)?;
+                        for stepper in &mut steppers {
+                            stepper.set_position(start);
+                        }
+                    }
+                    if toolhead.move_to(target, speed)? {
+                        flush_steps(&mut toolhead, &mut steppers, config)?;
+                    }
+                }
+                MotionCommand::Dwell { seconds } => {
+                    toolhead.dwell(seconds)?;
+                    flush_steps(&mut toolhead, &mut steppers, config)?;
+                }
+            }
+        }
+    }
+
+    toolhead.flush_lookahead()?;
+    flush_steps(&mut toolhead, &mut steppers, config)?;
+
+    let mut simulation = Simulation {
+        print_time: toolhead.print_time(),
+        mcu_freq: config.mcu_freq,
+        ..Simulation::default()
+    };
+    for stepper in steppers {
+        let name = stepper.name;
+        let commands = stepper.compressor.into_sink().commands;
+        let steps = commands
+            .iter()
+            .map(|command| match command {
+                Command::QueueStep(qs) => qs.count as u64,
+                Command::SetNextStepDir(_) => 0,
+            })
+            .sum();
+        simulation.steps.push((name, steps));
+        simulation
+            .trace
+            .extend(commands.into_iter().map(|command| TraceRecord {
+                stepper: name,
+                command,
+            }));
+    }
+
+    Ok(simulation)
+}
+
+/// Generate steps for everything scheduled so far and retire those moves.
+fn flush_steps(
+    toolhead: &mut MotionController,
+    steppers: &mut [Stepper],
+    config: &SimulationConfig,
+) -> Result<()> {
+    let flush_time = toolhead.print_time();
+    let clock = (flush_time * config.mcu_freq) as u64;
+    for stepper in steppers.iter_mut() {
+        let trapq = if stepper.extruder {
+            toolhead.extruder_trapq()
+        } else {
+            toolhead.trapq()
+        };
+        stepper.generate(trapq, flush_time, clock)?;
+    }
+    toolhead.finalize_moves(flush_time, flush_time);
+    Ok(())
+}
+
+impl Simulation {
+    /// Write the trace as CSV, one row per stepper command.
+    pub fn write_csv(&self, mut out: impl Write) -> std::io::Result<()> {
+        writeln!(out, "stepper,command,clock,time,interval,count,add,dir")?;
+        for record in &self.trace {
+            match &record.command {
+                Command::QueueStep(qs) => writeln!(
+                    out,
+                    "{},queue_step,{},{:.9},{},{},{},",
+                    record.stepper,
+                    qs.first_clock,
+                    qs.first_clock as f64 / self.mcu_freq,
+                    qs.interval,
+                    qs.count,
+                    qs.add
+                )?,
+                Command::SetNextStepDir(sd) => writeln!(
+                    out,
+                    "{},set_next_step_dir,{},{:.9},,,,{}",
+                    record.stepper,
+                    sd.req_clock,
+                    sd.req_clock as f64 / self.mcu_freq,
+                    sd.dir as u8
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Stepper {
+    name: &'static str,
+    extruder: bool,
+    solver: IterativeSolver<StepperKinematics>,
+    compressor: StepCompressor<RecordingSink>,
+}
+
+impl Stepper {
+    fn new(
+        oid: u32,
+        name: &'static str,
+        kin: StepperKinematics,
+        steps_per_mm: f64,
+        config: &SimulationConfig,
+    ) -> Self {
+        let flags = kin.active_flags();
+        let max_error = (config.max_step_error * config.mcu_freq) as u32;
+        let mut compressor = StepCompressor::new(oid, max_error, RecordingSink::default());
+        compressor.set_time(0.0, config.mcu_freq);
+        Self {
+            name,
+            extruder: false,
+            solver: IterativeSolver::new(1.0 / steps_per_mm, flags, 0.0, 0.0, kin, ()),
+            compressor,
+        }
+    }
+
+    fn for_config(config: &SimulationConfig) -> Vec<Self> {
+        let names = match config.kinematics {
+            Kinematics::Cartesian => ["stepper_x", "stepper_y", "stepper_z"],
+            Kinematics::CoreXY => ["stepper_a", "stepper_b", "stepper_z"],
+            Kinematics::CoreXZ => ["stepper_a", "stepper_y", "stepper_b"],
+        };
+        let mut steppers: Vec<_> = names
+            .into_iter()
+            .enumerate()
+            .map(|(axis, name)| {
+                let kin = config.kinematics.stepper(axis);
+                Self::new(axis as u32, name, kin, config.steps_per_mm[axis], config)
+            })
+            .collect();
+        // The extruder trapq tracks filament position on its X axis
+        let kin = StepperKinematics::Cartesian(cartesian::CartesianKin::new(cartesian::Axis::X));
+        let mut extruder = Self::new(3, "extruder", kin, config.steps_per_mm[3], config);
+        extruder.extruder = true;
+        steppers.push(extruder);
+        steppers
+    }
+
+    fn set_position(&mut self, pos: [f64; 4]) {
+        if self.extruder {
+            self.solver.set_position(pos[3], 0.0, 0.0);
+        } else {
+            self.solver.set_position(pos[0], pos[1], pos[2]);
+        }
+    }
+
+    fn generate(&mut self, trapq: &TrapQueue, flush_time: f64, clock: u64) -> Result<()> {
+        self.solver
+            .generate_steps(&mut self.compressor, trapq, flush_time)
+            .with_context(|| format!("{}: failed to generate steps", self.name))?;
+        self.compressor
+            .flush(clock)
+            .with_context(|| format!("{}: failed to compress steps", self.name))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn net_steps(simulation: &Simulation, stepper: &str) -> i64 {
+        let mut dir = true;
+        let mut net = 0i64;
+        for record in simulation.trace.iter().filter(|r| r.stepper == stepper) {
+            match &record.command {
+                Command::SetNextStepDir(sd) => dir = sd.dir,
+                Command::QueueStep(qs) => {
+                    let count = qs.count as i64;
+                    net += if dir { count } else { -count };
+                }
+            }
+        }
+        net
+    }
+
+    #[test]
+    fn cartesian_steps_match_distance() {
+        let config = SimulationConfig::default();
+        let simulation = simulate_gcode(
+            "G1 X10 Y5 F6000\nG1 X0 Y0\nG1 X10\n",
+            &MachineLimits::default(),
+            &config,
+        )
+        .unwrap();
+
+        assert_eq!(net_steps(&simulation, "stepper_x"), 800);
+        assert_eq!(net_steps(&simulation, "stepper_y"), 0);
+        assert_eq!(
+            simulation
+                .steps
+                .iter()
+                .find(|(n, _)| *n == "stepper_y")
+                .unwrap()
+                .1,
+            800
+        );
+        assert!(simulation.print_time > 0.0);
+    }
+
+    #[test]
+    fn corexy_moves_both_motors() {
+        let config = SimulationConfig {
+            kinematics: Kinematics::CoreXY,
+            ..SimulationConfig::default()
+        };
+        let simulation =
+            simulate_gcode("G1 X10 F6000\n", &MachineLimits::default(), &config).unwrap();
+        assert_eq!(net_steps(&simulation, "stepper_a"), 800);
+        assert_eq!(net_steps(&simulation, "stepper_b"), 800);
+    }
+
+    #[test]
+    fn dwell_extends_print_time() {
+        let limits = MachineLimits::default();
+        let config = SimulationConfig::default();
+        let moves = simulate_gcode("G1 X10 F6000\nG1 X20\n", &limits, &config).unwrap();
+        let dwell = simulate_gcode("G1 X10 F6000\nG4 P500\nG1 X20\n", &limits, &config).unwrap();
+        // The pause lands between the moves, so the first no longer
+        // blends into the second
+        assert!(dwell.print_time - moves.print_time > 0.5);
+        assert_eq!(net_steps(&dwell, "stepper_x"), 1600);
+    }
+
+    #[test]
+    fn writes_csv_trace() {
+        let simulation = simulate_gcode(
+            "M83\nG1 X1 E1 F600\n",
+            &MachineLimits::default(),
+            &SimulationConfig::default(),
+        )
+        .unwrap();
+        let mut out = Vec::new();
+        simulation.write_csv(&mut out).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        assert!(csv.starts_with("stepper,command,"));
+        assert!(csv.contains("stepper_x,queue_step,"));
+        assert!(csv.contains("extruder,queue_step,"));
+    }
+}
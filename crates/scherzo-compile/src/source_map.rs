@@ -0,0 +1,107 @@
+//! Mapping submitted statements back to the source.
+//!
+//! A compiled job makes one `submit` call per statement with a verb, in
+//! program order, so a host that counts those calls knows how far the job
+//! has run. Entry `n` of a [`SourceMap`] describes the `n`-th submitted
+//! statement: the line it came from, and the layer and filament the print
+//! has reached once it has run, with the temperatures and fan speed it has
+//! set.
+
+use crate::{
+    estimate::{MotionCommand, MotionInterpreter},
+    resume::ThermalState,
+    split_verb,
+};
+use scherzo_gcode::Statement;
+
+/// Where a submitted statement came from and what it completes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SourceEntry {
+    /// 1-based source line.
+    pub line: usize,
+    /// 1-based layer being printed, or 0 before the first extrusion.
+    pub layer: usize,
+    /// Net filament (mm) extruded up to and including this statement.
+    pub filament_mm: f64,
+    /// Temperatures and fan speed set up to and including this statement.
+    pub thermal: ThermalState,
+}
+
+/// Source location and print progress for every submitted statement.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceMap {
+    pub entries: Vec<SourceEntry>,
+    /// Number of layers in the print.
+    pub layers: usize,
+}
+
+impl SourceMap {
+    /// Build the map for a parsed program.
+    pub fn build(statements: &[Statement]) -> Self {
+        let mut interp = MotionInterpreter::new();
+        let mut map = Self::default();
+        let mut layer_z: Option<f64> = None;
+        let mut filament_mm = 0.0;
+        let mut thermal = ThermalState::default();
+
+        for stmt in statements {
+            thermal.apply(stmt);
+            for cmd in interp.interpret(stmt) {
+                let MotionCommand::Move { start, target, .. } = cmd else {
+                    continue;
+                };
+                let extrude = target[3] - start[3];
+                filament_mm += extrude;
+                let kinematic = (0..3).any(|axis| target[axis] != start[axis]);
+                // Layers start at each new height extruded at, as in estimates
+                if extrude > 0.0 && kinematic && layer_z.is_none_or(|z| target[2] > z + 0.000_001) {
+                    layer_z = Some(target[2]);
+                    map.layers += 1;
+                }
+            }
+            if split_verb(stmt).is_some() {
+                map.entries.push(SourceEntry {
+                    line: stmt.line,
+                    layer: map.layers,
+                    filament_mm,
+                    thermal,
+                });
+            }
+        }
+        map
+    }
+
+    /// Fraction (0 to 1) of the job done after `submitted` statements.
+    pub fn progress(&self, submitted: usize) -> f64 {
+        if self.entries.is_empty() {
+            return 1.0;
+        }
+        submitted.min(self.entries.len()) as f64 / self.entries.len() as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scherzo_gcode::parse;
+
+    #[test]
+    fn tracks_layers_and_filament_per_statement() {
+        let stmts =
+            parse("; start\nG28\nG1 Z0.2 F600\nG1 X10 E1\nG1 E0.5\nG1 Z0.4\nG1 X0 E1.5\nM84\n")
+                .unwrap();
+        let map = SourceMap::build(&stmts);
+
+        let lines: Vec<_> = map.entries.iter().map(|entry| entry.line).collect();
+        assert_eq!(lines, [2, 3, 4, 5, 6, 7, 8]);
+        let layers: Vec<_> = map.entries.iter().map(|entry| entry.layer).collect();
+        assert_eq!(layers, [0, 0, 1, 1, 1, 2, 2]);
+        assert_eq!(map.layers, 2);
+        assert_eq!(map.entries[3].filament_mm, 0.5);
+        assert_eq!(map.entries[6].filament_mm, 1.5);
+
+        assert_eq!(map.progress(0), 0.0);
+        assert_eq!(map.progress(7), 1.0);
+        assert_eq!(map.progress(100), 1.0);
+    }
+}
@@ -0,0 +1,1068 @@
+//! Jinja-like templates for G-code macros.
+//!
+//! Supports the subset of Jinja2 that Klipper macros use in practice, with
+//! Klipper's single-brace `{ expression }` output: `{% if %}`/`{% elif %}`/
+//! `{% else %}`, `{% for %}` (with `loop.index`, `loop.first` and
+//! `loop.last`), `{% set %}`, `{# comments #}`, and `-` whitespace control on
+//! `{% %}` and `{# #}` tags.
+//! Expressions have literals, lists, attribute and index access,
+//! arithmetic, comparisons, `and`/`or`/`not`, `in`, `~` concatenation,
+//! printf-style `'%.2f' % value` formatting, `range()`, and the `int`,
+//! `float`, `round`, `abs`, `default`, `lower`, `upper`, `string`, `length`,
+//! `join`, `min` and `max` filters. Values are JSON values, so printer state
+//! can be passed in as serialized structs.
+
+use anyhow::{Context, Result, bail};
+use serde_json::{Map, Number, Value};
+
+/// Most loop iterations a single render may run, to stop runaway loops.
+const MAX_ITERATIONS: usize = 100_000;
+
+/// A parsed template.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Text(String),
+    Output(Expr),
+    If {
+        branches: Vec<(Expr, Vec<Node>)>,
+        otherwise: Vec<Node>,
+    },
+    For {
+        var: String,
+        iter: Expr,
+        body: Vec<Node>,
+    },
+    Set {
+        var: String,
+        value: Expr,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Literal(Value),
+    List(Vec<Expr>),
+    Var(String),
+    Attr(Box<Expr>, String),
+    Index(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Filter(Box<Expr>, String, Vec<Expr>),
+    Call(String, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    In,
+    NotIn,
+    Concat,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    FloorDiv,
+    Rem,
+}
+
+impl Template {
+    /// Parse `source`.
+    pub fn parse(source: &str) -> Result<Self> {
+        let segments = split(source)?;
+        let mut parser = NodeParser {
+            segments: segments.into_iter().peekable(),
+        };
+        let (nodes, end) = parser.nodes()?;
+        if let Some(tag) = end {
+            bail!("unexpected `{{% {tag} %}}`");
+        }
+        Ok(Self { nodes })
+    }
+
+    /// Render with the variables in `context`.
+    pub fn render(&self, context: &Map<String, Value>) -> Result<String> {
+        let mut renderer = Renderer {
+            scopes: vec![context.clone()],
+            iterations: 0,
+            out: String::new(),
+        };
+        renderer.nodes(&self.nodes)?;
+        Ok(renderer.out)
+    }
+}
+
+/// A piece of template source.
+#[derive(Debug)]
+enum Segment {
+    Text(String),
+    Output(String),
+    Tag(String),
+}
+
+/// Split `source` into text, `{ }` and `{% %}` segments, dropping
+/// comments and applying whitespace control.
+fn split(source: &str) -> Result<Vec<Segment>> {
+    let mut segments = Vec::new();
+    let mut rest = source;
+    let mut trim_next = false;
+    loop {
+        let open = rest.find('{');
+        let mut text = &rest[..open.unwrap_or(rest.len())];
+        if trim_next {
+            text = text.trim_start();
+            trim_next = false;
+        }
+        let Some(open) = open else {
+            if !text.is_empty() {
+                segments.push(Segment::Text(text.to_string()));
+            }
+            return Ok(segments);
+        };
+
+        let after = &rest[open + 1..];
+        let (kind, mut inner_start) = match after.chars().next() {
+            Some('%') => ('%', open + 2),
+            Some('#') => ('#', open + 2),
+            _ => ('{', open + 1),
+        };
+        if kind != '{' && rest[inner_start..].starts_with('-') {
+            text = text.trim_end();
+            inner_start += 1;
+        }
+        if !text.is_empty() {
+            segments.push(Segment::Text(text.to_string()));
+        }
+        let len = match kind {
+            '{' => expression_end(&rest[inner_start..]),
+            _ => rest[inner_start..].find(&format!("{kind}}}")),
+        }
+        .with_context(|| format!("unclosed `{}`", &rest[open..inner_start]))?;
+        let mut inner = &rest[inner_start..inner_start + len];
+        let close_len = if kind == '{' { 1 } else { 2 };
+        if kind != '{' && inner.ends_with('-') {
+            trim_next = true;
+            inner = &inner[..inner.len() - 1];
+        }
+        match kind {
+            '{' => segments.push(Segment::Output(inner.trim().to_string())),
+            '%' => segments.push(Segment::Tag(inner.trim().to_string())),
+            _ => {}
+        }
+        rest = &rest[inner_start + len + close_len..];
+    }
+}
+
+/// Offset of the `}` closing an expression, skipping quoted strings.
+fn expression_end(source: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in source.char_indices() {
+        match (quote, c) {
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '}') => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+struct NodeParser {
+    segments: std::iter::Peekable<std::vec::IntoIter<Segment>>,
+}
+
+impl NodeParser {
+    /// Parse nodes up to the end of the input or an end tag (`endif`,
+    /// `elif ...`, `else`, `endfor`), which is returned.
+    fn nodes(&mut self) -> Result<(Vec<Node>, Option<String>)> {
+        let mut nodes = Vec::new();
+        while let Some(segment) = self.segments.next() {
+            match segment {
+                Segment::Text(text) => nodes.push(Node::Text(text)),
+                Segment::Output(expr) => nodes.push(Node::Output(
+                    parse_expr(&expr).with_context(|| format!("in `{{{expr}}}`"))?,
+                )),
+                Segment::Tag(tag) => {
+                    let (keyword, rest) = tag.split_once(char::is_whitespace).unwrap_or((&tag, ""));
+                    let rest = rest.trim();
+                    let context = || format!("in `{{% {tag} %}}`");
+                    match keyword {
+                        "if" => nodes.push(self.if_node(rest).with_context(context)?),
+                        "for" => nodes.push(self.for_node(rest).with_context(context)?),
+                        "set" => {
+                            let (var, value) = rest
+                                .split_once('=')
+                                .context("expected `set name = value`")
+                                .with_context(context)?;
+                            nodes.push(Node::Set {
+                                var: identifier(var.trim()).with_context(context)?,
+                                value: parse_expr(value).with_context(context)?,
+                            });
+                        }
+                        "endif" | "elif" | "else" | "endfor" => return Ok((nodes, Some(tag))),
+                        _ => bail!("unknown tag `{{% {tag} %}}`"),
+                    }
+                }
+            }
+        }
+        Ok((nodes, None))
+    }
+
+    fn if_node(&mut self, condition: &str) -> Result<Node> {
+        let mut branches = Vec::new();
+        let mut condition = parse_expr(condition)?;
+        loop {
+            let (body, end) = self.nodes()?;
+            let end = end.context("missing `{% endif %}`")?;
+            match end.split_once(char::is_whitespace) {
+                Some(("elif", next)) => {
+                    branches.push((condition, body));
+                    condition = parse_expr(next)?;
+                }
+                _ if end == "else" => {
+                    branches.push((condition, body));
+                    let (otherwise, end) = self.nodes()?;
+                    if end.as_deref() != Some("endif") {
+                        bail!("missing `{{% endif %}}`");
+                    }
+                    return Ok(Node::If {
+                        branches,
+                        otherwise,
+                    });
+                }
+                _ if end == "endif" => {
+                    branches.push((condition, body));
+                    return Ok(Node::If {
+                        branches,
+                        otherwise: Vec::new(),
+                    });
+                }
+                _ => bail!("unexpected `{{% {end} %}}`"),
+            }
+        }
+    }
+
+    fn for_node(&mut self, spec: &str) -> Result<Node> {
+        let (var, iter) = spec
+            .split_once(" in ")
+            .context("expected `for name in items`")?;
+        let (body, end) = self.nodes()?;
+        if end.as_deref() != Some("endfor") {
+            bail!("missing `{{% endfor %}}`");
+        }
+        Ok(Node::For {
+            var: identifier(var.trim())?,
+            iter: parse_expr(iter)?,
+            body,
+        })
+    }
+}
+
+fn identifier(name: &str) -> Result<String> {
+    let valid = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        bail!("`{name}` is not a valid name");
+    }
+    Ok(name.to_string())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(Number),
+    Str(String),
+    Ident(String),
+    Op(&'static str),
+}
+
+const OPERATORS: [&str; 22] = [
+    "==", "!=", "<=", ">=", "//", "<", ">", "+", "-", "*", "/", "%", "~", "|", ".", ",", "(", ")",
+    "[", "]", "=", ":",
+];
+
+fn tokenize(source: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = source.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                .unwrap_or(rest.len());
+            let text = &rest[..len];
+            let number = if text.contains('.') {
+                Number::from_f64(
+                    text.parse()
+                        .with_context(|| format!("bad number `{text}`"))?,
+                )
+                .context("bad number")?
+            } else {
+                Number::from(text.parse::<i64>()?)
+            };
+            tokens.push(Token::Number(number));
+            rest = &rest[len..];
+        } else if c == '\'' || c == '"' {
+            let end = rest[1..]
+                .find(c)
+                .with_context(|| format!("unterminated string in `{source}`"))?;
+            tokens.push(Token::Str(rest[1..end + 1].to_string()));
+            rest = &rest[end + 2..];
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..len].to_string()));
+            rest = &rest[len..];
+        } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        } else {
+            bail!("unexpected `{c}`");
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+fn parse_expr(source: &str) -> Result<Expr> {
+    let mut parser = ExprParser {
+        tokens: tokenize(source)?,
+        pos: 0,
+    };
+    let expr = parser.or()?;
+    if let Some(token) = parser.tokens.get(parser.pos) {
+        bail!("unexpected {token:?}");
+    }
+    Ok(expr)
+}
+
+struct ExprParser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl ExprParser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat_op(&mut self, op: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Op(next)) if *next == op) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Ident(ident)) if ident == keyword) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_op(&mut self, op: &str) -> Result<()> {
+        if !self.eat_op(op) {
+            bail!("expected `{op}`");
+        }
+        Ok(())
+    }
+
+    fn binary(op: BinOp, left: Expr, right: Expr) -> Expr {
+        Expr::Binary(op, Box::new(left), Box::new(right))
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut left = self.and()?;
+        while self.eat_keyword("or") {
+            left = Self::binary(BinOp::Or, left, self.and()?);
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut left = self.not()?;
+        while self.eat_keyword("and") {
+            left = Self::binary(BinOp::And, left, self.not()?);
+        }
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Result<Expr> {
+        if self.eat_keyword("not") {
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Expr> {
+        let mut left = self.concat()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op("==")) => BinOp::Eq,
+                Some(Token::Op("!=")) => BinOp::Ne,
+                Some(Token::Op("<")) => BinOp::Lt,
+                Some(Token::Op("<=")) => BinOp::Le,
+                Some(Token::Op(">")) => BinOp::Gt,
+                Some(Token::Op(">=")) => BinOp::Ge,
+                Some(Token::Ident(ident)) if ident == "in" => BinOp::In,
+                Some(Token::Ident(ident))
+                    if ident == "not"
+                        && matches!(self.tokens.get(self.pos + 1), Some(Token::Ident(next)) if next == "in") =>
+                {
+                    self.pos += 1;
+                    BinOp::NotIn
+                }
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            left = Self::binary(op, left, self.concat()?);
+        }
+    }
+
+    fn concat(&mut self) -> Result<Expr> {
+        let mut left = self.additive()?;
+        while self.eat_op("~") {
+            left = Self::binary(BinOp::Concat, left, self.additive()?);
+        }
+        Ok(left)
+    }
+
+    fn additive(&mut self) -> Result<Expr> {
+        let mut left = self.multiplicative()?;
+        loop {
+            let op = if self.eat_op("+") {
+                BinOp::Add
+            } else if self.eat_op("-") {
+                BinOp::Sub
+            } else {
+                return Ok(left);
+            };
+            left = Self::binary(op, left, self.multiplicative()?);
+        }
+    }
+
+    fn multiplicative(&mut self) -> Result<Expr> {
+        let mut left = self.unary()?;
+        loop {
+            let op = if self.eat_op("*") {
+                BinOp::Mul
+            } else if self.eat_op("//") {
+                BinOp::FloorDiv
+            } else if self.eat_op("/") {
+                BinOp::Div
+            } else if self.eat_op("%") {
+                BinOp::Rem
+            } else {
+                return Ok(left);
+            };
+            left = Self::binary(op, left, self.unary()?);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.eat_op("-") {
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        if self.eat_op("+") {
+            return self.unary();
+        }
+        self.postfix()
+    }
+
+    fn postfix(&mut self) -> Result<Expr> {
+        let mut expr = self.primary()?;
+        loop {
+            if self.eat_op(".") {
+                let Some(Token::Ident(name)) = self.next() else {
+                    bail!("expected a name after `.`");
+                };
+                expr = Expr::Attr(Box::new(expr), name);
+            } else if self.eat_op("[") {
+                let index = self.or()?;
+                self.expect_op("]")?;
+                expr = Expr::Index(Box::new(expr), Box::new(index));
+            } else if self.eat_op("|") {
+                let Some(Token::Ident(name)) = self.next() else {
+                    bail!("expected a filter name after `|`");
+                };
+                let args = if self.eat_op("(") {
+                    self.args()?
+                } else {
+                    Vec::new()
+                };
+                expr = Expr::Filter(Box::new(expr), name, args);
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    /// Arguments up to the closing parenthesis.
+    fn args(&mut self) -> Result<Vec<Expr>> {
+        let mut args = Vec::new();
+        if self.eat_op(")") {
+            return Ok(args);
+        }
+        loop {
+            args.push(self.or()?);
+            if self.eat_op(")") {
+                return Ok(args);
+            }
+            self.expect_op(",")?;
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::Number(number)) => Ok(Expr::Literal(Value::Number(number))),
+            Some(Token::Str(text)) => Ok(Expr::Literal(Value::String(text))),
+            Some(Token::Ident(ident)) => Ok(match ident.as_str() {
+                "true" | "True" => Expr::Literal(Value::Bool(true)),
+                "false" | "False" => Expr::Literal(Value::Bool(false)),
+                "none" | "None" => Expr::Literal(Value::Null),
+                _ if self.eat_op("(") => Expr::Call(ident, self.args()?),
+                _ => Expr::Var(ident),
+            }),
+            Some(Token::Op("(")) => {
+                let expr = self.or()?;
+                self.expect_op(")")?;
+                Ok(expr)
+            }
+            Some(Token::Op("[")) => {
+                let mut items = Vec::new();
+                if !self.eat_op("]") {
+                    loop {
+                        items.push(self.or()?);
+                        if self.eat_op("]") {
+                            break;
+                        }
+                        self.expect_op(",")?;
+                    }
+                }
+                Ok(Expr::List(items))
+            }
+            Some(token) => bail!("unexpected {token:?}"),
+            None => bail!("unexpected end of expression"),
+        }
+    }
+}
+
+struct Renderer {
+    scopes: Vec<Map<String, Value>>,
+    iterations: usize,
+    out: String,
+}
+
+impl Renderer {
+    fn nodes(&mut self, nodes: &[Node]) -> Result<()> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => self.out.push_str(text),
+                Node::Output(expr) => {
+                    let value = self.eval(expr)?;
+                    self.out.push_str(&to_string(&value));
+                }
+                Node::If {
+                    branches,
+                    otherwise,
+                } => {
+                    let mut taken = None;
+                    for (condition, body) in branches {
+                        if truthy(&self.eval(condition)?) {
+                            taken = Some(body);
+                            break;
+                        }
+                    }
+                    self.nodes(taken.unwrap_or(otherwise))?;
+                }
+                Node::For { var, iter, body } => {
+                    let items = match self.eval(iter)? {
+                        Value::Array(items) => items,
+                        Value::Object(map) => map.keys().cloned().map(Value::String).collect(),
+                        Value::String(text) => {
+                            text.chars().map(|c| Value::String(c.into())).collect()
+                        }
+                        value => bail!("cannot loop over {}", to_string(&value)),
+                    };
+                    let count = items.len();
+                    for (i, item) in items.into_iter().enumerate() {
+                        self.iterations += 1;
+                        if self.iterations > MAX_ITERATIONS {
+                            bail!("more than {MAX_ITERATIONS} loop iterations");
+                        }
+                        let mut scope = Map::new();
+                        scope.insert(var.clone(), item);
+                        let mut loop_info = Map::new();
+                        loop_info.insert("index".into(), (i + 1).into());
+                        loop_info.insert("index0".into(), i.into());
+                        loop_info.insert("first".into(), (i == 0).into());
+                        loop_info.insert("last".into(), (i + 1 == count).into());
+                        loop_info.insert("length".into(), count.into());
+                        scope.insert("loop".into(), Value::Object(loop_info));
+                        self.scopes.push(scope);
+                        let result = self.nodes(body);
+                        self.scopes.pop();
+                        result?;
+                    }
+                }
+                Node::Set { var, value } => {
+                    let value = self.eval(value)?;
+                    let scope = self.scopes.last_mut().expect("root scope");
+                    scope.insert(var.clone(), value);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn eval(&self, expr: &Expr) -> Result<Value> {
+        Ok(match expr {
+            Expr::Literal(value) => value.clone(),
+            Expr::List(items) => Value::Array(
+                items
+                    .iter()
+                    .map(|item| self.eval(item))
+                    .collect::<Result<_>>()?,
+            ),
+            Expr::Var(name) => self
+                .scopes
+                .iter()
+                .rev()
+                .find_map(|scope| scope.get(name))
+                .cloned()
+                .unwrap_or(Value::Null),
+            Expr::Attr(target, name) => match self.eval(target)? {
+                Value::Object(map) => map.get(name).cloned().unwrap_or(Value::Null),
+                _ => Value::Null,
+            },
+            Expr::Index(target, index) => index_value(&self.eval(target)?, &self.eval(index)?),
+            Expr::Not(inner) => Value::Bool(!truthy(&self.eval(inner)?)),
+            Expr::Neg(inner) => arithmetic(BinOp::Sub, &Value::from(0), &self.eval(inner)?)?,
+            Expr::Binary(BinOp::And, left, right) => {
+                let left = self.eval(left)?;
+                if truthy(&left) {
+                    self.eval(right)?
+                } else {
+                    left
+                }
+            }
+            Expr::Binary(BinOp::Or, left, right) => {
+                let left = self.eval(left)?;
+                if truthy(&left) {
+                    left
+                } else {
+                    self.eval(right)?
+                }
+            }
+            Expr::Binary(op, left, right) => binary(*op, &self.eval(left)?, &self.eval(right)?)?,
+            Expr::Filter(target, name, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.eval(arg))
+                    .collect::<Result<Vec<_>>>()?;
+                filter(name, self.eval(target)?, &args)?
+            }
+            Expr::Call(name, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.eval(arg))
+                    .collect::<Result<Vec<_>>>()?;
+                call(name, &args)?
+            }
+        })
+    }
+}
+
+fn index_value(target: &Value, index: &Value) -> Value {
+    match (target, index) {
+        (Value::Object(map), Value::String(key)) => map.get(key).cloned(),
+        (Value::Array(items), Value::Number(n)) => n.as_i64().and_then(|i| {
+            let i = if i < 0 { items.len() as i64 + i } else { i };
+            items.get(usize::try_from(i).ok()?).cloned()
+        }),
+        _ => None,
+    }
+    .unwrap_or(Value::Null)
+}
+
+/// Python-style truthiness.
+fn truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64().is_some_and(|n| n != 0.0),
+        Value::String(s) => !s.is_empty(),
+        Value::Array(items) => !items.is_empty(),
+        Value::Object(map) => !map.is_empty(),
+    }
+}
+
+/// Render `value` as template output. Null renders as nothing.
+fn to_string(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        Value::Bool(true) => "True".into(),
+        Value::Bool(false) => "False".into(),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => i.to_string(),
+            None => float_string(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        Value::Array(items) => {
+            let items: Vec<_> = items.iter().map(repr).collect();
+            format!("[{}]", items.join(", "))
+        }
+        Value::Object(_) => value.to_string(),
+    }
+}
+
+/// Render `value` as it appears inside a list.
+fn repr(value: &Value) -> String {
+    match value {
+        Value::String(s) => format!("'{s}'"),
+        Value::Null => "None".into(),
+        value => to_string(value),
+    }
+}
+
+/// Format a float the way Python does, keeping `.0` on whole numbers.
+fn float_string(value: f64) -> String {
+    if value.is_finite() && value.fract() == 0.0 && value.abs() < 1e16 {
+        format!("{value:.1}")
+    } else {
+        value.to_string()
+    }
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::Bool(b) => Some(f64::from(u8::from(*b))),
+        _ => None,
+    }
+}
+
+fn float(value: f64) -> Result<Value> {
+    Number::from_f64(value)
+        .map(Value::Number)
+        .with_context(|| format!("{value} is not a finite number"))
+}
+
+fn values_equal(left: &Value, right: &Value) -> bool {
+    match (number(left), number(right)) {
+        (Some(l), Some(r)) if !left.is_boolean() || !right.is_boolean() => l == r,
+        _ => left == right,
+    }
+}
+
+fn binary(op: BinOp, left: &Value, right: &Value) -> Result<Value> {
+    Ok(match op {
+        BinOp::Eq => Value::Bool(values_equal(left, right)),
+        BinOp::Ne => Value::Bool(!values_equal(left, right)),
+        BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => {
+            let ordering = match (left, right) {
+                (Value::String(l), Value::String(r)) => l.cmp(r),
+                _ => {
+                    let (Some(l), Some(r)) = (number(left), number(right)) else {
+                        bail!("cannot compare {} and {}", repr(left), repr(right));
+                    };
+                    l.total_cmp(&r)
+                }
+            };
+            Value::Bool(match op {
+                BinOp::Lt => ordering.is_lt(),
+                BinOp::Le => ordering.is_le(),
+                BinOp::Gt => ordering.is_gt(),
+                _ => ordering.is_ge(),
+            })
+        }
+        BinOp::In | BinOp::NotIn => {
+            let found = match right {
+                Value::String(haystack) => haystack.contains(&to_string(left)),
+                Value::Array(items) => items.iter().any(|item| values_equal(item, left)),
+                Value::Object(map) => map.contains_key(&to_string(left)),
+                _ => bail!("cannot search in {}", repr(right)),
+            };
+            Value::Bool(found == (op == BinOp::In))
+        }
+        BinOp::Concat => Value::String(to_string(left) + &to_string(right)),
+        BinOp::Add => match (left, right) {
+            (Value::String(l), Value::String(r)) => Value::String(format!("{l}{r}")),
+            (Value::Array(l), Value::Array(r)) => Value::Array([l.clone(), r.clone()].concat()),
+            _ => arithmetic(op, left, right)?,
+        },
+        BinOp::Rem if left.is_string() => Value::String(printf(&to_string(left), right)?),
+        _ => arithmetic(op, left, right)?,
+    })
+}
+
+fn arithmetic(op: BinOp, left: &Value, right: &Value) -> Result<Value> {
+    let as_int = |value: &Value| match value {
+        Value::Number(n) => n.as_i64(),
+        Value::Bool(b) => Some(i64::from(*b)),
+        _ => None,
+    };
+    if let (Some(l), Some(r)) = (as_int(left), as_int(right)) {
+        let result = match op {
+            BinOp::Add => l.checked_add(r),
+            BinOp::Sub => l.checked_sub(r),
+            BinOp::Mul => l.checked_mul(r),
+            BinOp::FloorDiv if r != 0 => Some(l.div_euclid(r)),
+            BinOp::Rem if r != 0 => Some(l.rem_euclid(r)),
+            BinOp::FloorDiv | BinOp::Rem => bail!("division by zero"),
+            _ => None,
+        };
+        if let Some(result) = result {
+            return Ok(Value::from(result));
+        }
+    }
+    let (Some(l), Some(r)) = (number(left), number(right)) else {
+        bail!("cannot do arithmetic on {} and {}", repr(left), repr(right));
+    };
+    if r == 0.0 && matches!(op, BinOp::Div | BinOp::FloorDiv | BinOp::Rem) {
+        bail!("division by zero");
+    }
+    float(match op {
+        BinOp::Add => l + r,
+        BinOp::Sub => l - r,
+        BinOp::Mul => l * r,
+        BinOp::Div => l / r,
+        BinOp::FloorDiv => (l / r).floor(),
+        BinOp::Rem => l - r * (l / r).floor(),
+        _ => unreachable!("not an arithmetic operator"),
+    })
+}
+
+/// Python's `format % args` for `%s`, `%d`, `%i`, `%f`, `%g` and `%%`, with
+/// optional precision.
+fn printf(format: &str, args: &Value) -> Result<String> {
+    let args = match args {
+        Value::Array(items) => items.clone(),
+        value => vec![value.clone()],
+    };
+    let mut args = args.iter();
+    let mut out = String::new();
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        let mut precision = None;
+        if chars.peek() == Some(&'.') {
+            chars.next();
+            let mut digits = String::new();
+            while let Some(d) = chars.next_if(char::is_ascii_digit) {
+                digits.push(d);
+            }
+            precision = Some(digits.parse::<usize>().unwrap_or(0));
+        }
+        let conversion = chars.next().context("incomplete format")?;
+        if conversion == '%' {
+            out.push('%');
+            continue;
+        }
+        let arg = args.next().context("not enough arguments for format")?;
+        let value = || number(arg).with_context(|| format!("{} is not a number", repr(arg)));
+        match conversion {
+            's' => out.push_str(&to_string(arg)),
+            'd' | 'i' => out.push_str(&(value()?.trunc() as i64).to_string()),
+            'f' => out.push_str(&format!("{:.*}", precision.unwrap_or(6), value()?)),
+            'g' => out.push_str(&match precision {
+                Some(precision) => format!("{:.*}", precision, value()?),
+                None => value()?.to_string(),
+            }),
+            _ => bail!("unsupported format `%{conversion}`"),
+        }
+    }
+    Ok(out)
+}
+
+fn filter(name: &str, value: Value, args: &[Value]) -> Result<Value> {
+    let arg = |i: usize| args.get(i);
+    Ok(match name {
+        "default" | "d" => match value {
+            Value::Null => arg(0).cloned().unwrap_or(Value::String(String::new())),
+            value => value,
+        },
+        "float" => match &value {
+            Value::String(text) => match text.trim().parse::<f64>() {
+                Ok(parsed) => float(parsed)?,
+                Err(_) => float(arg(0).and_then(number).unwrap_or(0.0))?,
+            },
+            _ => float(number(&value).unwrap_or(0.0))?,
+        },
+        "int" => {
+            let parsed = match &value {
+                Value::String(text) => text.trim().parse::<f64>().ok(),
+                _ => number(&value),
+            };
+            match parsed {
+                Some(parsed) => Value::from(parsed.trunc() as i64),
+                None => arg(0).cloned().unwrap_or(Value::from(0)),
+            }
+        }
+        "round" => {
+            let n = number(&value).with_context(|| format!("cannot round {}", repr(&value)))?;
+            let digits = arg(0).and_then(number).unwrap_or(0.0) as i32;
+            let scale = 10f64.powi(digits);
+            float((n * scale).round() / scale)?
+        }
+        "abs" => match value.as_i64() {
+            Some(i) => Value::from(i.abs()),
+            None => float(number(&value).context("abs of a non-number")?.abs())?,
+        },
+        "lower" => Value::String(to_string(&value).to_lowercase()),
+        "upper" => Value::String(to_string(&value).to_uppercase()),
+        "string" => Value::String(to_string(&value)),
+        "length" | "count" => Value::from(match &value {
+            Value::String(text) => text.chars().count(),
+            Value::Array(items) => items.len(),
+            Value::Object(map) => map.len(),
+            _ => bail!("{} has no length", repr(&value)),
+        }),
+        "join" => {
+            let separator = arg(0).map(to_string).unwrap_or_default();
+            let Value::Array(items) = &value else {
+                bail!("can only join lists");
+            };
+            let items: Vec<_> = items.iter().map(to_string).collect();
+            Value::String(items.join(&separator))
+        }
+        "min" | "max" => {
+            let Value::Array(items) = value else {
+                bail!("`{name}` needs a list");
+            };
+            let mut best: Option<Value> = None;
+            for item in items {
+                let better = match &best {
+                    None => true,
+                    Some(best) => {
+                        let op = if name == "min" { BinOp::Lt } else { BinOp::Gt };
+                        truthy(&binary(op, &item, best)?)
+                    }
+                };
+                if better {
+                    best = Some(item);
+                }
+            }
+            best.unwrap_or(Value::Null)
+        }
+        _ => bail!("unknown filter `{name}`"),
+    })
+}
+
+fn call(name: &str, args: &[Value]) -> Result<Value> {
+    match name {
+        "range" => {
+            let ints = args
+                .iter()
+                .map(|arg| arg.as_i64().context("range() needs integers"))
+                .collect::<Result<Vec<_>>>()?;
+            let (start, stop, step) = match ints[..] {
+                [stop] => (0, stop, 1),
+                [start, stop] => (start, stop, 1),
+                [start, stop, step] if step != 0 => (start, stop, step),
+                _ => bail!("range() takes 1 to 3 arguments and a non-zero step"),
+            };
+            let len = if step > 0 {
+                (stop - start + step - 1).max(0) / step
+            } else {
+                (start - stop - step - 1).max(0) / -step
+            };
+            if len as usize > MAX_ITERATIONS {
+                bail!("range() of more than {MAX_ITERATIONS} items");
+            }
+            Ok(Value::Array(
+                (0..len).map(|i| Value::from(start + i * step)).collect(),
+            ))
+        }
+        _ => bail!("unknown function `{name}`"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn render(source: &str, context: Value) -> String {
+        let Value::Object(context) = context else {
+            panic!("context must be an object");
+        };
+        Template::parse(source).unwrap().render(&context).unwrap()
+    }
+
+    #[test]
+    fn renders_klipper_style_macros() {
+        let context = json!({
+            "params": {"TEMP": "210", "LINES": "2"},
+            "printer": {"toolhead": {"position": {"x": 10.0, "z": 0.2}, "homed_axes": "xyz"}},
+        });
+        let source = "\
+{% set temp = params.TEMP|default(200)|float %}
+{%- set speed = params.SPEED|default(50)|int -%}
+M104 S{temp}
+{% if 'z' in printer.toolhead.homed_axes and temp > 180 -%}
+G1 Z{printer.toolhead.position.z + 0.3} F{speed * 60}
+{% elif temp > 0 %}cold
+{%- else %}none{% endif -%}
+{% for i in range(params.LINES|int) -%}
+G1 X{'%.2f' % (i * 1.5)} ; line {loop.index} of {loop.length}{# comment #}
+{% endfor -%}
+{[1, 2, 3]|join('}') ~ ' ' ~ (7 // 2) ~ ' ' ~ (7 / 2) ~ ' ' ~ (-3)|abs}";
+        assert_eq!(
+            render(source, context),
+            "M104 S210.0\nG1 Z0.5 F3000\n\
+             G1 X0.00 ; line 1 of 2\nG1 X1.50 ; line 2 of 2\n1}2}3 3 3.5 3"
+        );
+    }
+
+    #[test]
+    fn reports_template_errors() {
+        for source in [
+            "{% if x %}unclosed",
+            "{1 + }",
+            "{% for x of y %}{% endfor %}",
+            "{% endfor %}",
+            "{x",
+            "{% unknown %}",
+        ] {
+            assert!(Template::parse(source).is_err(), "{source}");
+        }
+        let render_err = |source: &str| {
+            Template::parse(source)
+                .unwrap()
+                .render(&Map::new())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(render_err("{1 / 0}"), "division by zero");
+        assert_eq!(render_err("{x|bogus}"), "unknown filter `bogus`");
+        assert!(render_err("{% for i in range(1000000) %}{% endfor %}").contains("range()"));
+    }
+}
@@ -0,0 +1,430 @@
+//! Inspecting compiled jobs and plugins, and rewriting their custom sections.
+//!
+//! Custom sections carry data alongside the code, such as embedded schemas,
+//! source maps and signatures. These helpers walk only the top-level sections
+//! of the binary, so on a component they touch the component's own custom
+//! sections and copy the core modules and components nested inside it
+//! untouched. Core modules are handled the same way.
+
+use anyhow::{Context, Result, bail};
+use std::borrow::Cow;
+use wasm_encoder::{CustomSection, Section};
+use wasmparser::{ExternalKind, Parser, Payload, TypeRef};
+use wit_component::WitPrinter;
+use wit_parser::{Resolve, WorldId, WorldItem, WorldKey, decoding::DecodedWasm};
+
+/// Magic number and version (or layer) at the start of every binary.
+const HEADER_LEN: usize = 8;
+
+/// Id shared by custom sections in core modules and components.
+const CUSTOM_SECTION_ID: u8 = 0;
+
+/// A top-level section of a binary.
+struct RawSection<'a> {
+    id: u8,
+    /// The whole section, including its id and size.
+    bytes: &'a [u8],
+    /// The section's contents after its size.
+    contents: &'a [u8],
+}
+
+impl<'a> RawSection<'a> {
+    /// Name and data of a custom section.
+    fn custom(&self) -> Result<Option<(&'a str, &'a [u8])>> {
+        if self.id != CUSTOM_SECTION_ID {
+            return Ok(None);
+        }
+        let mut pos = 0;
+        let len = read_u32(self.contents, &mut pos)? as usize;
+        let name = self
+            .contents
+            .get(pos..pos + len)
+            .context("custom section name runs past the section")?;
+        let name = std::str::from_utf8(name).context("custom section name is not UTF-8")?;
+        Ok(Some((name, &self.contents[pos + len..])))
+    }
+}
+
+/// What a binary imports and exports, and where its bytes go.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ComponentReport {
+    /// Whether the binary is a component rather than a core module.
+    pub component: bool,
+    /// Size of the whole binary in bytes.
+    pub size: usize,
+    pub imports: Vec<Item>,
+    pub exports: Vec<Item>,
+    /// The embedded WIT, if the binary carries any.
+    pub wit: Option<String>,
+    /// Bytes taken by each kind of top-level section, in order of first
+    /// appearance. Custom sections are listed by name.
+    pub sections: Vec<SectionSize>,
+}
+
+/// An imported or exported interface or function.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Item {
+    /// Interface name such as `job:print/gcode`, or the function name for a
+    /// bare function.
+    pub name: String,
+    /// Functions of the interface; empty for a bare function.
+    pub functions: Vec<String>,
+}
+
+/// Total size of the top-level sections of one kind.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SectionSize {
+    pub name: String,
+    pub count: usize,
+    /// Bytes including each section's id and size.
+    pub size: usize,
+}
+
+/// Report the imports, exports, embedded WIT and section sizes of a
+/// component or core module.
+pub fn inspect(bytes: &[u8]) -> Result<ComponentReport> {
+    let component = Parser::is_component(bytes);
+    let sections = section_sizes(bytes)?;
+
+    let (imports, exports, wit) = if component {
+        let DecodedWasm::Component(resolve, world) =
+            wit_parser::decoding::decode(bytes).context("failed to decode component")?
+        else {
+            bail!("input is a WIT package, not a component");
+        };
+        let imports = world_items(&resolve, resolve.worlds[world].imports.iter());
+        let exports = world_items(&resolve, resolve.worlds[world].exports.iter());
+        (imports, exports, Some(print_world(&resolve, world)?))
+    } else {
+        let (imports, exports) = module_items(bytes)?;
+        // Modules built for a component carry their WIT in a custom section
+        let wit = match wit_component::metadata::decode(bytes) {
+            Ok((_, bindgen)) if bindgen.resolve.worlds.iter().next().is_some() => {
+                Some(print_world(&bindgen.resolve, bindgen.world)?)
+            }
+            _ => None,
+        };
+        (imports, exports, wit)
+    };
+
+    Ok(ComponentReport {
+        component,
+        size: bytes.len(),
+        imports,
+        exports,
+        wit,
+        sections,
+    })
+}
+
+fn world_items<'a>(
+    resolve: &Resolve,
+    items: impl Iterator<Item = (&'a WorldKey, &'a WorldItem)>,
+) -> Vec<Item> {
+    items
+        .filter_map(|(key, item)| match item {
+            WorldItem::Interface { id, .. } => Some(Item {
+                name: resolve.name_world_key(key),
+                functions: resolve.interfaces[*id].functions.keys().cloned().collect(),
+            }),
+            WorldItem::Function(function) => Some(Item {
+                name: function.name.clone(),
+                functions: Vec::new(),
+            }),
+            WorldItem::Type(_) => None,
+        })
+        .collect()
+}
+
+/// Function imports grouped by module, and function exports, of a core
+/// module.
+fn module_items(bytes: &[u8]) -> Result<(Vec<Item>, Vec<Item>)> {
+    let mut imports: Vec<Item> = Vec::new();
+    let mut exports = Vec::new();
+    for payload in Parser::new(0).parse_all(bytes) {
+        match payload.context("failed to parse module")? {
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    let import = import?;
+                    if !matches!(import.ty, TypeRef::Func(_)) {
+                        continue;
+                    }
+                    let function = import.name.to_string();
+                    match imports.iter_mut().find(|item| item.name == import.module) {
+                        Some(item) => item.functions.push(function),
+                        None => imports.push(Item {
+                            name: import.module.to_string(),
+                            functions: vec![function],
+                        }),
+                    }
+                }
+            }
+            Payload::ExportSection(reader) => {
+                for export in reader {
+                    let export = export?;
+                    if export.kind == ExternalKind::Func {
+                        exports.push(Item {
+                            name: export.name.to_string(),
+                            functions: Vec::new(),
+                        });
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok((imports, exports))
+}
+
+/// Render the package a world belongs to as WIT.
+fn print_world(resolve: &Resolve, world: WorldId) -> Result<String> {
+    let pkg = resolve.worlds[world]
+        .package
+        .context("world has no package")?;
+    let mut printer = WitPrinter::default();
+    printer.print(resolve, pkg, &[])?;
+    Ok(printer.output.to_string())
+}
+
+/// Bytes taken by each kind of top-level section of a binary, as in
+/// [`ComponentReport::sections`], without decoding anything else.
+pub fn section_sizes(bytes: &[u8]) -> Result<Vec<SectionSize>> {
+    let component = Parser::is_component(bytes);
+    let (_, raw_sections) = sections(bytes)?;
+    let mut sizes: Vec<SectionSize> = Vec::new();
+    for section in &raw_sections {
+        let name = match section.custom()? {
+            Some((name, _)) => format!("custom:{name}"),
+            None => section_name(component, section.id).to_string(),
+        };
+        match sizes.iter_mut().find(|entry| entry.name == name) {
+            Some(entry) => {
+                entry.count += 1;
+                entry.size += section.bytes.len();
+            }
+            None => sizes.push(SectionSize {
+                name,
+                count: 1,
+                size: section.bytes.len(),
+            }),
+        }
+    }
+    Ok(sizes)
+}
+
+fn section_name(component: bool, id: u8) -> &'static str {
+    let names: &[&str] = if component {
+        &[
+            "custom",
+            "core module",
+            "core instance",
+            "core type",
+            "component",
+            "instance",
+            "alias",
+            "type",
+            "canon",
+            "start",
+            "import",
+            "export",
+            "value",
+        ]
+    } else {
+        &[
+            "custom",
+            "type",
+            "import",
+            "function",
+            "table",
+            "memory",
+            "global",
+            "export",
+            "start",
+            "element",
+            "code",
+            "data",
+            "data count",
+            "tag",
+        ]
+    };
+    names.get(usize::from(id)).copied().unwrap_or("unknown")
+}
+
+/// Data of the top-level custom section `name`, if there is one.
+pub fn custom_section<'a>(bytes: &'a [u8], name: &str) -> Result<Option<&'a [u8]>> {
+    let (_, sections) = sections(bytes)?;
+    for section in sections {
+        if let Some((section_name, data)) = section.custom()?
+            && section_name == name
+        {
+            return Ok(Some(data));
+        }
+    }
+    Ok(None)
+}
+
+/// Store `data` in the top-level custom section `name`, replacing any
+/// section of that name. The section is added after the others.
+pub fn embed_custom_section(bytes: &[u8], name: &str, data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = strip_section(bytes, name)?;
+    CustomSection {
+        name: Cow::Borrowed(name),
+        data: Cow::Borrowed(data),
+    }
+    .append_to(&mut out);
+    Ok(out)
+}
+
+/// Remove every top-level custom section named `name`.
+pub fn strip_section(bytes: &[u8], name: &str) -> Result<Vec<u8>> {
+    let (header, sections) = sections(bytes)?;
+    let mut out = Vec::with_capacity(bytes.len());
+    out.extend_from_slice(header);
+    for section in sections {
+        if let Some((section_name, _)) = section.custom()?
+            && section_name == name
+        {
+            continue;
+        }
+        out.extend_from_slice(section.bytes);
+    }
+    Ok(out)
+}
+
+/// Split a binary into its header and top-level sections.
+fn sections(bytes: &[u8]) -> Result<(&[u8], Vec<RawSection<'_>>)> {
+    if bytes.len() < HEADER_LEN || &bytes[..4] != b"\0asm" {
+        bail!("not a WebAssembly binary");
+    }
+    let mut sections = Vec::new();
+    let mut pos = HEADER_LEN;
+    while pos < bytes.len() {
+        let start = pos;
+        let id = bytes[pos];
+        pos += 1;
+        let size = read_u32(bytes, &mut pos)? as usize;
+        let contents = bytes
+            .get(pos..pos + size)
+            .with_context(|| format!("section at offset {start} runs past the end"))?;
+        pos += size;
+        sections.push(RawSection {
+            id,
+            bytes: &bytes[start..pos],
+            contents,
+        });
+    }
+    Ok((&bytes[..HEADER_LEN], sections))
+}
+
+/// Read an unsigned LEB128 `u32` at `pos`, advancing past it.
+fn read_u32(bytes: &[u8], pos: &mut usize) -> Result<u32> {
+    let mut value = 0u32;
+    for shift in (0..35).step_by(7) {
+        let byte = *bytes.get(*pos).context("unexpected end of binary")?;
+        *pos += 1;
+        value |= u32::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("invalid LEB128 integer at offset {}", *pos - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compile_gcode, decompile::decompile};
+    use wasmparser::Validator;
+
+    #[test]
+    fn embeds_replaces_and_strips_component_sections() {
+        let out = compile_gcode("G1 X10 F600\nM104 S200\n").unwrap();
+        let component = &out.component;
+        assert_eq!(custom_section(component, "scherzo-test").unwrap(), None);
+
+        let embedded = embed_custom_section(component, "scherzo-test", b"first").unwrap();
+        let embedded = embed_custom_section(&embedded, "scherzo-test", b"second").unwrap();
+        Validator::new().validate_all(&embedded).unwrap();
+        assert_eq!(
+            custom_section(&embedded, "scherzo-test").unwrap(),
+            Some(&b"second"[..])
+        );
+        assert_eq!(decompile(&embedded).unwrap(), decompile(component).unwrap());
+
+        let stripped = strip_section(&embedded, "scherzo-test").unwrap();
+        assert_eq!(stripped, *component);
+    }
+
+    #[test]
+    fn leaves_nested_module_sections_alone() {
+        let section = |data: &'static [u8]| CustomSection {
+            name: Cow::Borrowed("scherzo-test"),
+            data: Cow::Borrowed(data),
+        };
+        let mut module = wasm_encoder::Module::new();
+        module.section(&section(b"inner"));
+        let mut component = wasm_encoder::Component::new();
+        component.section(&wasm_encoder::ModuleSection(&module));
+        let component = component.finish();
+        assert_eq!(custom_section(&component, "scherzo-test").unwrap(), None);
+
+        let embedded = embed_custom_section(&component, "scherzo-test", b"outer").unwrap();
+        Validator::new().validate_all(&embedded).unwrap();
+        assert_eq!(
+            custom_section(&embedded, "scherzo-test").unwrap(),
+            Some(&b"outer"[..])
+        );
+        assert_eq!(strip_section(&embedded, "scherzo-test").unwrap(), component);
+    }
+
+    #[test]
+    fn handles_core_modules() {
+        let out = compile_gcode("G1 X10 F600\n").unwrap();
+        let embedded = embed_custom_section(&out.wasm, "scherzo-test", &[0; 200]).unwrap();
+        Validator::new().validate_all(&embedded).unwrap();
+        assert_eq!(
+            custom_section(&embedded, "scherzo-test").unwrap(),
+            Some(&[0; 200][..])
+        );
+        assert_eq!(strip_section(&embedded, "scherzo-test").unwrap(), out.wasm);
+    }
+
+    #[test]
+    fn inspects_job_components_and_modules() {
+        let out = compile_gcode("G1 X10 F600\nM104 S200\n").unwrap();
+        let report = inspect(&out.component).unwrap();
+        assert!(report.component);
+        assert_eq!(report.size, out.component.len());
+        let exports: Vec<_> = report.exports.iter().map(|item| &item.name[..]).collect();
+        assert_eq!(exports, ["run"]);
+        let gcode = report
+            .imports
+            .iter()
+            .find(|item| item.name.starts_with("job:print/"))
+            .unwrap();
+        assert!(!gcode.functions.is_empty());
+        assert!(report.wit.unwrap().contains("import job:print/"));
+        let total: usize = report.sections.iter().map(|section| section.size).sum();
+        assert_eq!(total + 8, out.component.len());
+        assert!(report.sections.iter().any(|s| s.name == "core module"));
+
+        let embedded = embed_custom_section(&out.component, "scherzo-test", b"data").unwrap();
+        let report = inspect(&embedded).unwrap();
+        let custom = report.sections.last().unwrap();
+        assert_eq!(custom.name, "custom:scherzo-test");
+        assert_eq!(custom.size, 1 + 1 + 1 + 12 + 4);
+
+        let report = inspect(&out.wasm).unwrap();
+        assert!(!report.component);
+        assert!(report.exports.iter().any(|item| item.name == "run"));
+        assert!(report.imports.iter().all(|item| !item.functions.is_empty()));
+    }
+
+    #[test]
+    fn rejects_truncated_binaries() {
+        let out = compile_gcode("G1 X10 F600\n").unwrap();
+        let truncated = &out.component[..out.component.len() - 1];
+        assert!(strip_section(truncated, "scherzo-test").is_err());
+        assert!(custom_section(b"not wasm", "scherzo-test").is_err());
+    }
+}
@@ -0,0 +1,86 @@
+//! Step generation for 1000 cartesian moves, solved in closed form and by
+//! the secant search.
+
+use criterion::{BatchSize, Criterion, black_box, criterion_group, criterion_main};
+use scherzo_core::{
+    itersolve::{CalcPositionCallback, IterativeSolver},
+    kinematics::cartesian::{Axis, CartesianKin},
+    step_compressor::{CommandSink, StepCompressor},
+    trap_queue::{Move, TrapQueue},
+};
+
+const MOVES: usize = 1000;
+const MOVE_T: f64 = 0.1;
+
+/// Cartesian X without its linear coefficients, forcing the secant search
+struct Secant(CartesianKin);
+
+impl CalcPositionCallback for Secant {
+    fn calc_position(&mut self, m: &Move, move_time: f64) -> f64 {
+        self.0.calc_position(m, move_time)
+    }
+}
+
+struct NullSink;
+
+impl CommandSink for NullSink {
+    fn push(&mut self, command: scherzo_core::step_compressor::Command) {
+        black_box(command);
+    }
+}
+
+fn filled_queue() -> TrapQueue {
+    let mut tq = TrapQueue::new();
+    for i in 0..MOVES {
+        let x = i as f64 * 5.0;
+        tq.append(
+            i as f64 * MOVE_T,
+            MOVE_T / 4.0,
+            MOVE_T / 2.0,
+            MOVE_T / 4.0,
+            x,
+            0.0,
+            0.0,
+            1.0,
+            0.0,
+            0.0,
+            0.0,
+            66.7,
+            2667.0,
+        )
+        .unwrap();
+    }
+    tq
+}
+
+fn generate<C: CalcPositionCallback>(tq: &TrapQueue, callback: C) {
+    let flags = CartesianKin::new(Axis::X).active_flags();
+    let mut solver = IterativeSolver::new(0.0125, flags, 0.0, 0.0, callback, ());
+    let mut sc = StepCompressor::new(0, 25, NullSink);
+    sc.set_time(0.0, 16_000_000.0);
+    solver
+        .generate_steps(&mut sc, tq, MOVES as f64 * MOVE_T)
+        .unwrap();
+    sc.flush(u64::MAX).unwrap();
+}
+
+fn itersolve(c: &mut Criterion) {
+    let tq = filled_queue();
+    c.bench_function("generate_steps_quadratic", |b| {
+        b.iter_batched(
+            || CartesianKin::new(Axis::X),
+            |kin| generate(&tq, kin),
+            BatchSize::SmallInput,
+        )
+    });
+    c.bench_function("generate_steps_secant", |b| {
+        b.iter_batched(
+            || Secant(CartesianKin::new(Axis::X)),
+            |kin| generate(&tq, kin),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, itersolve);
+criterion_main!(benches);
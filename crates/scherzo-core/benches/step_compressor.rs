@@ -0,0 +1,112 @@
+//! Compressing a trace of 1M steps: trapezoidal moves at varying speeds,
+//! flushed every 100ms as the host does, and all queued up front then
+//! flushed at once to time compression alone.
+
+use criterion::{BatchSize, Criterion, black_box, criterion_group, criterion_main};
+use scherzo_core::step_compressor::{Command, CommandSink, StepCompressor, StepCompressorConfig};
+
+const STEPS: usize = 1_000_000;
+const MCU_FREQ: f64 = 72_000_000.0;
+/// 25us, Klipper's default `max_error`
+const MAX_ERROR: u32 = 1800;
+const STEPS_PER_MM: f64 = 80.0;
+const ACCEL: f64 = 3000.0;
+const FLUSH_TIME: f64 = 0.1;
+
+struct NullSink;
+
+impl CommandSink for NullSink {
+    fn push(&mut self, command: Command) {
+        black_box(command);
+    }
+}
+
+/// Step times of moves of 2 to 50mm, each accelerating from and
+/// decelerating to a stop, at cruise speeds of 20 to 300mm/s
+fn trace() -> Vec<f64> {
+    let mut times = Vec::with_capacity(STEPS);
+    let mut start = 0.0;
+    let mut i = 0u64;
+    while times.len() < STEPS {
+        i += 1;
+        let distance = 2.0 + (i * 37 % 49) as f64;
+        let cruise_v = 20.0 + (i * 53 % 281) as f64;
+        let accel_d = (cruise_v * cruise_v / (2.0 * ACCEL)).min(distance / 2.0);
+        let peak_v = (2.0 * ACCEL * accel_d).sqrt();
+        let accel_t = peak_v / ACCEL;
+        let cruise_t = (distance - 2.0 * accel_d) / peak_v;
+        let steps = (distance * STEPS_PER_MM) as usize;
+        for step in 1..=steps.min(STEPS - times.len()) {
+            let d = step as f64 / STEPS_PER_MM;
+            let t = if d <= accel_d {
+                (2.0 * d / ACCEL).sqrt()
+            } else if d <= distance - accel_d {
+                accel_t + (d - accel_d) / peak_v
+            } else {
+                let left = (distance - d).max(0.0);
+                2.0 * accel_t + cruise_t - (2.0 * left / ACCEL).sqrt()
+            };
+            times.push(start + t);
+        }
+        start += 2.0 * accel_t + cruise_t;
+    }
+    times
+}
+
+fn compress(times: &[f64]) -> StepCompressor<NullSink> {
+    let mut sc = StepCompressor::new(0, MAX_ERROR, NullSink);
+    sc.set_time(0.0, MCU_FREQ);
+    let mut next_flush = FLUSH_TIME;
+    for &time in times {
+        if time > next_flush {
+            sc.flush((next_flush * MCU_FREQ) as u64).unwrap();
+            next_flush += FLUSH_TIME;
+        }
+        sc.append(1, 0.0, time).unwrap();
+    }
+    sc.commit().unwrap();
+    sc.flush(u64::MAX).unwrap();
+    sc
+}
+
+/// Every step of `times` queued and none sent: far steps don't force a
+/// flush and sequences may run past a single `queue_step`.
+fn queued(times: &[f64]) -> StepCompressor<NullSink> {
+    let mut sc = StepCompressorConfig::new(MAX_ERROR)
+        .with_clock_diff_max(1 << 40)
+        .build(0, NullSink);
+    sc.set_time(0.0, MCU_FREQ);
+    sc.set_split_moves(true);
+    for &time in times {
+        sc.append(1, 0.0, time).unwrap();
+    }
+    sc.commit().unwrap();
+    sc
+}
+
+fn step_compressor(c: &mut Criterion) {
+    let times = trace();
+    let mut group = c.benchmark_group("step_compressor");
+    group.sample_size(10);
+    group.bench_function("append_flush_1m_steps", |b| {
+        b.iter_batched(
+            || times.clone(),
+            |times| compress(black_box(&times)),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("flush_1m_steps", |b| {
+        b.iter_batched(
+            || queued(&times),
+            |mut sc| {
+                sc.flush(u64::MAX).unwrap();
+                sc
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, step_compressor);
+criterion_main!(benches);
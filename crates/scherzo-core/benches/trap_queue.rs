@@ -0,0 +1,67 @@
+//! Trap queue lookups on a queue of 100k moves.
+
+use criterion::{BatchSize, Criterion, black_box, criterion_group, criterion_main};
+use scherzo_core::trap_queue::TrapQueue;
+
+const MOVES: usize = 100_000;
+const MOVE_T: f64 = 0.01;
+
+fn filled_queue() -> TrapQueue {
+    let mut tq = TrapQueue::new();
+    for i in 0..MOVES {
+        let x = i as f64;
+        tq.append(
+            x * MOVE_T,
+            MOVE_T / 4.0,
+            MOVE_T / 2.0,
+            MOVE_T / 4.0,
+            x,
+            0.0,
+            0.0,
+            1.0,
+            0.0,
+            0.0,
+            0.0,
+            100.0,
+            1000.0,
+        )
+        .unwrap();
+    }
+    tq
+}
+
+fn trap_queue(c: &mut Criterion) {
+    let tq = filled_queue();
+    let end = MOVES as f64 * MOVE_T;
+
+    c.bench_function("find_move", |b| {
+        let mut time = 0.0;
+        b.iter(|| {
+            time = (time + 7.3) % end;
+            black_box(tq.find_move(black_box(time)))
+        })
+    });
+    c.bench_function("get_active_range", |b| {
+        b.iter(|| black_box(tq.get_active_range(black_box(end / 2.0), black_box(end / 2.0 + 1.0))))
+    });
+    c.bench_function("extract_old", |b| {
+        b.iter(|| black_box(tq.extract_old(50, black_box(end / 2.0), black_box(end / 2.0 + 1.0))))
+    });
+    c.bench_function("finalize_moves", |b| {
+        b.iter_batched(
+            filled_queue,
+            |mut tq| {
+                let mut time = 0.0;
+                while time < end {
+                    time += 1.0;
+                    tq.finalize_moves(time, time - 1.0);
+                }
+                tq
+            },
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(benches, trap_queue);
+criterion_main!(benches);
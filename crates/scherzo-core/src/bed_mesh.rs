@@ -0,0 +1,245 @@
+//! Bed mesh compensation.
+//!
+//! A bed mesh records the height of the bed at a grid of probed points, like
+//! Klipper's `bed_mesh`. Moves are split into short segments, and the Z of
+//! each segment end is raised or lowered by the bed height under it,
+//! interpolated bilinearly between the probed points. Outside the probed area
+//! the height at the nearest edge is used.
+
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum BedMeshError {
+    #[error("mesh_min ({min:?}) must be below mesh_max ({max:?}) on both axes")]
+    InvalidArea { min: [f64; 2], max: [f64; 2] },
+    #[error("a mesh needs at least 2 points on each axis, got {0:?}")]
+    TooFewPoints([usize; 2]),
+    #[error("every row of the mesh must have {expected} points")]
+    RaggedRows { expected: usize },
+    #[error("expected {expected} probed heights, got {got}")]
+    WrongHeightCount { expected: usize, got: usize },
+    #[error("probed heights must be finite")]
+    NonFiniteHeight,
+}
+
+/// Grid of points to probe.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MeshGrid {
+    min: [f64; 2],
+    max: [f64; 2],
+    count: [usize; 2],
+}
+
+impl MeshGrid {
+    /// Grid of `count` (X, Y) points spanning `min` to `max`.
+    pub fn new(min: [f64; 2], max: [f64; 2], count: [usize; 2]) -> Result<Self, BedMeshError> {
+        check_area(min, max)?;
+        if count.iter().any(|&count| count < 2) {
+            return Err(BedMeshError::TooFewPoints(count));
+        }
+        Ok(Self { min, max, count })
+    }
+
+    pub fn count(&self) -> [usize; 2] {
+        self.count
+    }
+
+    /// Points to probe, row by row along X in a serpentine so each row starts
+    /// above where the last one ended.
+    pub fn probe_points(&self) -> Vec<[f64; 2]> {
+        let [nx, ny] = self.count;
+        let mut points = Vec::with_capacity(nx * ny);
+        for row in 0..ny {
+            let y = lerp(self.min[1], self.max[1], row, ny);
+            for i in 0..nx {
+                let col = if row % 2 == 0 { i } else { nx - 1 - i };
+                points.push([lerp(self.min[0], self.max[0], col, nx), y]);
+            }
+        }
+        points
+    }
+
+    /// Build the mesh from the heights measured at [`Self::probe_points`], in
+    /// the same order.
+    pub fn mesh(&self, heights: &[f64]) -> Result<BedMesh, BedMeshError> {
+        let [nx, ny] = self.count;
+        if heights.len() != nx * ny {
+            return Err(BedMeshError::WrongHeightCount {
+                expected: nx * ny,
+                got: heights.len(),
+            });
+        }
+        let points = heights
+            .chunks(nx)
+            .enumerate()
+            .map(|(row, heights)| {
+                let mut row_heights = heights.to_vec();
+                if row % 2 == 1 {
+                    row_heights.reverse();
+                }
+                row_heights
+            })
+            .collect();
+        BedMesh::new(self.min, self.max, points)
+    }
+}
+
+/// Measured bed heights over a grid.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BedMesh {
+    min: [f64; 2],
+    max: [f64; 2],
+    /// Heights (mm) by row along Y, then column along X.
+    points: Vec<Vec<f64>>,
+}
+
+impl BedMesh {
+    /// Mesh of `points` (rows along Y of heights along X) evenly spread from
+    /// `min` to `max`.
+    pub fn new(min: [f64; 2], max: [f64; 2], points: Vec<Vec<f64>>) -> Result<Self, BedMeshError> {
+        check_area(min, max)?;
+        let count = [points.first().map_or(0, Vec::len), points.len()];
+        if count.iter().any(|&count| count < 2) {
+            return Err(BedMeshError::TooFewPoints(count));
+        }
+        if points.iter().any(|row| row.len() != count[0]) {
+            return Err(BedMeshError::RaggedRows { expected: count[0] });
+        }
+        if points.iter().flatten().any(|height| !height.is_finite()) {
+            return Err(BedMeshError::NonFiniteHeight);
+        }
+        Ok(Self { min, max, points })
+    }
+
+    pub fn min(&self) -> [f64; 2] {
+        self.min
+    }
+
+    pub fn max(&self) -> [f64; 2] {
+        self.max
+    }
+
+    pub fn points(&self) -> &[Vec<f64>] {
+        &self.points
+    }
+
+    /// Difference (mm) between the highest and lowest probed points.
+    pub fn range(&self) -> f64 {
+        let heights = self.points.iter().flatten();
+        let max = heights.clone().copied().fold(f64::NEG_INFINITY, f64::max);
+        let min = heights.copied().fold(f64::INFINITY, f64::min);
+        max - min
+    }
+
+    /// Bed height (mm) at `x`, `y`.
+    pub fn z_offset(&self, x: f64, y: f64) -> f64 {
+        let (col, tx) = cell(x, self.min[0], self.max[0], self.points[0].len());
+        let (row, ty) = cell(y, self.min[1], self.max[1], self.points.len());
+        let at = |row: usize, col: usize| self.points[row][col];
+        let low = at(row, col) + (at(row, col + 1) - at(row, col)) * tx;
+        let high = at(row + 1, col) + (at(row + 1, col + 1) - at(row + 1, col)) * tx;
+        low + (high - low) * ty
+    }
+
+    /// Compensated targets for a move from `start` to `target` (X/Y/Z/E),
+    /// split into segments no longer than `segment_length` (mm) in X/Y.
+    pub fn compensate(
+        &self,
+        start: [f64; 4],
+        target: [f64; 4],
+        segment_length: f64,
+    ) -> Vec<[f64; 4]> {
+        let distance = (target[0] - start[0]).hypot(target[1] - start[1]);
+        let segments = if segment_length > 0.0 {
+            ((distance / segment_length).ceil() as usize).max(1)
+        } else {
+            1
+        };
+        (1..=segments)
+            .map(|i| {
+                let t = i as f64 / segments as f64;
+                let mut point: [f64; 4] =
+                    std::array::from_fn(|axis| start[axis] + (target[axis] - start[axis]) * t);
+                if i == segments {
+                    point = target;
+                }
+                point[2] += self.z_offset(point[0], point[1]);
+                point
+            })
+            .collect()
+    }
+}
+
+fn check_area(min: [f64; 2], max: [f64; 2]) -> Result<(), BedMeshError> {
+    if (0..2).all(|axis| min[axis] < max[axis]) {
+        Ok(())
+    } else {
+        Err(BedMeshError::InvalidArea { min, max })
+    }
+}
+
+/// Position of point `index` of `count` evenly spread from `min` to `max`.
+fn lerp(min: f64, max: f64, index: usize, count: usize) -> f64 {
+    min + (max - min) * index as f64 / (count - 1) as f64
+}
+
+/// Index of the grid cell containing `value` and the fraction through it,
+/// clamped to the grid.
+fn cell(value: f64, min: f64, max: f64, count: usize) -> (usize, f64) {
+    let pos = ((value - min) / (max - min)).clamp(0.0, 1.0) * (count - 1) as f64;
+    let index = (pos.floor() as usize).min(count - 2);
+    (index, pos - index as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probes_in_a_serpentine_and_interpolates() {
+        let grid = MeshGrid::new([0.0, 0.0], [100.0, 50.0], [3, 2]).unwrap();
+        assert_eq!(
+            grid.probe_points(),
+            [
+                [0.0, 0.0],
+                [50.0, 0.0],
+                [100.0, 0.0],
+                [100.0, 50.0],
+                [50.0, 50.0],
+                [0.0, 50.0]
+            ]
+        );
+        let mesh = grid.mesh(&[0.0, 0.1, 0.2, 0.4, 0.3, 0.2]).unwrap();
+        assert_eq!(mesh.points(), [vec![0.0, 0.1, 0.2], vec![0.2, 0.3, 0.4]]);
+        assert!((mesh.range() - 0.4).abs() < 1e-12);
+
+        assert!((mesh.z_offset(25.0, 25.0) - 0.15).abs() < 1e-12);
+        assert!((mesh.z_offset(100.0, 50.0) - 0.4).abs() < 1e-12);
+        // Beyond the probed area the edge height is used
+        assert!((mesh.z_offset(-10.0, 60.0) - 0.2).abs() < 1e-12);
+
+        assert_eq!(
+            grid.mesh(&[0.0; 5]),
+            Err(BedMeshError::WrongHeightCount {
+                expected: 6,
+                got: 5
+            })
+        );
+        assert!(MeshGrid::new([0.0, 0.0], [100.0, 50.0], [1, 3]).is_err());
+        assert!(BedMesh::new([0.0, 0.0], [1.0, 1.0], vec![vec![0.0, 1.0], vec![0.0]]).is_err());
+    }
+
+    #[test]
+    fn compensate_splits_moves_and_follows_the_bed() {
+        let mesh = BedMesh::new([0.0, 0.0], [10.0, 10.0], vec![vec![0.0, 1.0]; 2]).unwrap();
+        let points = mesh.compensate([0.0, 5.0, 0.2, 0.0], [10.0, 5.0, 0.2, 1.0], 4.0);
+        let z: Vec<_> = points.iter().map(|point| point[2]).collect();
+        assert_eq!(points.len(), 3);
+        assert!((z[0] - (0.2 + 10.0 / 3.0 / 10.0)).abs() < 1e-12);
+        assert_eq!(points[2], [10.0, 5.0, 1.2, 1.0]);
+
+        // Z-only moves are not split
+        let points = mesh.compensate([5.0, 5.0, 0.2, 0.0], [5.0, 5.0, 0.4, 0.0], 4.0);
+        assert_eq!(points, [[5.0, 5.0, 0.9, 0.0]]);
+    }
+}
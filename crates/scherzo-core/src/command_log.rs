@@ -0,0 +1,337 @@
+//! Binary log of the step command stream.
+//!
+//! [`CommandLogWriter`] records every [`Command`] a step compressor emits,
+//! with its clocks, so a reported motion bug can be reproduced exactly by
+//! feeding the same commands to a virtual MCU or an analysis tool.
+//! [`CommandLogReader`] reads them back.
+//!
+//! The format is compact enough to leave on for a whole print. After a
+//! 5-byte header (`SZCL` and a version byte) each record is a tag byte and
+//! its fields as LEB128 varints:
+//!
+//! - `0`: the MCU clock frequency, as 8 little-endian bytes of an `f64`,
+//!   written whenever step timing is (re)started.
+//! - `1`: `queue_step`: oid, first clock, interval, count, add, and the
+//!   request and minimum clocks as offsets back from the first clock.
+//! - `2`: `set_next_step_dir`: oid, direction, request clock.
+//!
+//! Each record's clock is stored as the difference from the clock of the
+//! record before it, zigzag-encoded as steppers may be flushed out of
+//! order. The last clock of a `queue_step` follows from its other fields.
+
+use crate::step_compressor::{Command, QueueStep, SetNextStepDir};
+use std::io::{self, Read, Write};
+
+/// First bytes of a command log.
+pub const MAGIC: [u8; 4] = *b"SZCL";
+/// Version of the format written.
+pub const VERSION: u8 = 1;
+
+const TAG_CLOCK_FREQ: u8 = 0;
+const TAG_QUEUE_STEP: u8 = 1;
+const TAG_SET_NEXT_STEP_DIR: u8 = 2;
+
+/// An entry of a command log.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LogRecord {
+    /// Clocks of the commands after this tick at this frequency (Hz).
+    ClockFreq(f64),
+    Command(Command),
+}
+
+/// Writes a command log.
+pub struct CommandLogWriter<W: Write> {
+    writer: W,
+    /// Clock the next record's clock is stored relative to.
+    clock: u64,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> CommandLogWriter<W> {
+    /// Start a log by writing its header to `writer`.
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        Ok(Self {
+            writer,
+            clock: 0,
+            buf: Vec::with_capacity(32),
+        })
+    }
+
+    /// Record that the commands from now on are timed at `freq` Hz.
+    pub fn clock_freq(&mut self, freq: f64) -> io::Result<()> {
+        self.buf.clear();
+        self.buf.push(TAG_CLOCK_FREQ);
+        self.buf.extend_from_slice(&freq.to_le_bytes());
+        self.writer.write_all(&self.buf)
+    }
+
+    pub fn record(&mut self, command: &Command) -> io::Result<()> {
+        self.buf.clear();
+        match command {
+            Command::QueueStep(step) => {
+                self.buf.push(TAG_QUEUE_STEP);
+                put_varint(&mut self.buf, step.oid as u64);
+                self.put_clock(step.first_clock);
+                put_varint(&mut self.buf, step.interval as u64);
+                put_varint(&mut self.buf, step.count as u64);
+                put_varint(&mut self.buf, zigzag(step.add as i64));
+                put_varint(
+                    &mut self.buf,
+                    zigzag(offset(step.first_clock, step.req_clock)),
+                );
+                put_varint(
+                    &mut self.buf,
+                    zigzag(offset(step.first_clock, step.min_clock)),
+                );
+            }
+            Command::SetNextStepDir(dir) => {
+                self.buf.push(TAG_SET_NEXT_STEP_DIR);
+                put_varint(&mut self.buf, dir.oid as u64);
+                self.buf.push(dir.dir as u8);
+                self.put_clock(dir.req_clock);
+            }
+        }
+        self.writer.write_all(&self.buf)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn put_clock(&mut self, clock: u64) {
+        put_varint(&mut self.buf, zigzag(offset(clock, self.clock)));
+        self.clock = clock;
+    }
+}
+
+/// Reads the records of a command log.
+pub struct CommandLogReader<R: Read> {
+    reader: R,
+    clock: u64,
+}
+
+impl<R: Read> CommandLogReader<R> {
+    /// Fails unless `reader` starts with the header of a log this version
+    /// can read.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0; 5];
+        reader.read_exact(&mut header)?;
+        if header[..4] != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a command log",
+            ));
+        }
+        if header[4] != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported command log version {}", header[4]),
+            ));
+        }
+        Ok(Self { reader, clock: 0 })
+    }
+
+    /// The next record, or `None` at the end of the log. A log that ends
+    /// partway through a record, e.g. as the host crashed, fails with
+    /// [`io::ErrorKind::UnexpectedEof`].
+    pub fn read_record(&mut self) -> io::Result<Option<LogRecord>> {
+        let mut tag = [0];
+        if self.reader.read(&mut tag)? == 0 {
+            return Ok(None);
+        }
+        let record = match tag[0] {
+            TAG_CLOCK_FREQ => {
+                let mut freq = [0; 8];
+                self.reader.read_exact(&mut freq)?;
+                LogRecord::ClockFreq(f64::from_le_bytes(freq))
+            }
+            TAG_QUEUE_STEP => {
+                let oid = self.field::<u32>()?;
+                let first_clock = self.clock()?;
+                let interval = self.field::<u32>()?;
+                let count = self.field::<u16>()?;
+                let add = self.signed::<i16>()?;
+                let req_clock = first_clock.wrapping_sub(self.signed::<i64>()? as u64);
+                let min_clock = first_clock.wrapping_sub(self.signed::<i64>()? as u64);
+                let after_first = (count as i64 - 1).max(0);
+                let addfactor = after_first * (after_first + 1) / 2;
+                let ticks = add as i64 * addfactor + interval as i64 * after_first;
+                LogRecord::Command(Command::QueueStep(QueueStep {
+                    oid,
+                    first_clock,
+                    last_clock: first_clock.wrapping_add(ticks as u64),
+                    interval,
+                    count,
+                    add,
+                    req_clock,
+                    min_clock,
+                }))
+            }
+            TAG_SET_NEXT_STEP_DIR => {
+                let oid = self.field::<u32>()?;
+                let mut dir = [0];
+                self.reader.read_exact(&mut dir)?;
+                LogRecord::Command(Command::SetNextStepDir(SetNextStepDir {
+                    oid,
+                    dir: dir[0] != 0,
+                    req_clock: self.clock()?,
+                }))
+            }
+            tag => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown command log record {tag}"),
+                ));
+            }
+        };
+        Ok(Some(record))
+    }
+
+    fn clock(&mut self) -> io::Result<u64> {
+        let diff = self.signed::<i64>()?;
+        self.clock = self.clock.wrapping_add(diff as u64);
+        Ok(self.clock)
+    }
+
+    fn field<T: TryFrom<u64>>(&mut self) -> io::Result<T> {
+        let value = get_varint(&mut self.reader)?;
+        T::try_from(value).map_err(|_| out_of_range(value))
+    }
+
+    fn signed<T: TryFrom<i64>>(&mut self) -> io::Result<T> {
+        let value = unzigzag(get_varint(&mut self.reader)?);
+        T::try_from(value).map_err(|_| out_of_range(value))
+    }
+}
+
+impl<R: Read> Iterator for CommandLogReader<R> {
+    type Item = io::Result<LogRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+fn out_of_range(value: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("command log field {value} out of range"),
+    )
+}
+
+/// `clock - reference` as a signed difference.
+fn offset(clock: u64, reference: u64) -> i64 {
+    clock.wrapping_sub(reference) as i64
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn get_varint(reader: &mut impl Read) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        reader.read_exact(&mut byte)?;
+        value |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "command log varint too long",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::step_compressor::{RecordingSink, StepCompressor};
+
+    fn read_all(log: &[u8]) -> io::Result<Vec<LogRecord>> {
+        CommandLogReader::new(log)?.collect()
+    }
+
+    #[test]
+    fn round_trips_compressor_output() {
+        let mut commands = Vec::new();
+        for (oid, step_time) in [(3, 0.000_91), (7, 0.000_37)] {
+            let mut sc = StepCompressor::new(oid, 25, RecordingSink::default());
+            sc.set_time(0.0, 1_000_000.0);
+            for i in 1..=500 {
+                let dir = if i < 300 { 1 } else { 0 };
+                let t = i as f64 * step_time * (1.0 + i as f64 / 1000.0);
+                sc.append(dir, 0.0, t).unwrap();
+            }
+            sc.append(1, 0.0, 5000.0).unwrap();
+            sc.flush(u64::MAX).unwrap();
+            commands.extend(sc.into_sink().commands);
+        }
+
+        let mut log = CommandLogWriter::new(Vec::new()).unwrap();
+        log.clock_freq(1_000_000.0).unwrap();
+        for command in &commands {
+            log.record(command).unwrap();
+        }
+        let log = log.into_inner();
+
+        let records = read_all(&log).unwrap();
+        assert_eq!(records[0], LogRecord::ClockFreq(1_000_000.0));
+        let replayed: Vec<_> = records[1..]
+            .iter()
+            .map(|record| match record {
+                LogRecord::Command(command) => command.clone(),
+                other => panic!("unexpected {other:?}"),
+            })
+            .collect();
+        assert_eq!(replayed, commands);
+        // Well under the in-memory size of the commands
+        assert!(log.len() < commands.len() * 16, "{} bytes", log.len());
+    }
+
+    #[test]
+    fn rejects_other_files_and_cut_off_records() {
+        assert_eq!(
+            read_all(b"GIF89a").unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            read_all(b"SZCL\x09").unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(read_all(b"SZCL\x01").unwrap(), []);
+
+        let mut log = CommandLogWriter::new(Vec::new()).unwrap();
+        log.record(&Command::SetNextStepDir(SetNextStepDir {
+            oid: 1,
+            dir: true,
+            req_clock: 1 << 40,
+        }))
+        .unwrap();
+        let log = log.into_inner();
+        assert_eq!(read_all(&log).unwrap().len(), 1);
+        assert_eq!(
+            read_all(&log[..log.len() - 1]).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+}
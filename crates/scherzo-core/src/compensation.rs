@@ -0,0 +1,451 @@
+//! Ordered toolhead position compensation.
+//!
+//! A [`CompensationStack`] maps the position a move asks for to the position
+//! the kinematics must reach. Klipper chains its move transforms (`bed_mesh`,
+//! `skew_correction`, ...) in the order the modules load, so the result can
+//! depend on the config layout. Here the stages always run in one order,
+//! whichever are active:
+//!
+//! 1. [`SkewCorrection`] turns the commanded X/Y/Z into square frame
+//!    coordinates, like Klipper's `skew_correction`.
+//! 2. The [`BedMesh`] raises Z by the bed height under the corrected X/Y,
+//!    which is where the mesh was probed.
+//! 3. [`TwistCompensation`] raises Z by the height error of an X gantry that
+//!    twists along its travel.
+//! 4. [`UserTransform`]s, in the order they were added.
+//!
+//! The stack works on positions alone, so it sits in front of any kinematics.
+//! Moves are split into short segments while a stage that bends straight
+//! lines (the mesh or twist) is active.
+
+use crate::bed_mesh::BedMesh;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum CompensationError {
+    #[error("{0} must be finite")]
+    NonFinite(&'static str),
+    #[error("twist start_x ({start_x}) must be below end_x ({end_x})")]
+    InvalidTwistRange { start_x: f64, end_x: f64 },
+    #[error("twist compensation needs at least 2 z_compensations, got {0}")]
+    TooFewTwistPoints(usize),
+}
+
+/// A compensation stage, in the order the stack applies them.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Skew,
+    BedMesh,
+    Twist,
+    User { name: String },
+}
+
+/// Frame skew, as the tangents of each axis pair's angle away from square.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SkewCorrection {
+    #[serde(default)]
+    pub xy: f64,
+    #[serde(default)]
+    pub xz: f64,
+    #[serde(default)]
+    pub yz: f64,
+}
+
+impl SkewCorrection {
+    pub fn apply(&self, [x, y, z]: [f64; 3]) -> [f64; 3] {
+        [
+            x - y * self.xy - z * (self.xz - self.xy * self.yz),
+            y - z * self.yz,
+            z,
+        ]
+    }
+
+    fn validate(&self) -> Result<(), CompensationError> {
+        if [self.xy, self.xz, self.yz].iter().all(|v| v.is_finite()) {
+            Ok(())
+        } else {
+            Err(CompensationError::NonFinite("skew factors"))
+        }
+    }
+}
+
+/// Z offsets (mm) measured along X, evenly spread from `start_x` to `end_x`.
+/// Between them the offset is interpolated linearly; beyond them the nearest
+/// end is used.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TwistCompensation {
+    pub start_x: f64,
+    pub end_x: f64,
+    pub z_compensations: Vec<f64>,
+}
+
+impl TwistCompensation {
+    /// Z offset (mm) at `x`.
+    pub fn z_offset(&self, x: f64) -> f64 {
+        let values = &self.z_compensations;
+        let pos = ((x - self.start_x) / (self.end_x - self.start_x)).clamp(0.0, 1.0)
+            * (values.len() - 1) as f64;
+        let index = (pos.floor() as usize).min(values.len() - 2);
+        let t = pos - index as f64;
+        values[index] + (values[index + 1] - values[index]) * t
+    }
+
+    fn validate(&self) -> Result<(), CompensationError> {
+        if !self.start_x.is_finite() || !self.end_x.is_finite() {
+            return Err(CompensationError::NonFinite("twist start_x and end_x"));
+        }
+        if self.start_x >= self.end_x {
+            return Err(CompensationError::InvalidTwistRange {
+                start_x: self.start_x,
+                end_x: self.end_x,
+            });
+        }
+        if self.z_compensations.len() < 2 {
+            return Err(CompensationError::TooFewTwistPoints(
+                self.z_compensations.len(),
+            ));
+        }
+        if !self.z_compensations.iter().all(|v| v.is_finite()) {
+            return Err(CompensationError::NonFinite("twist z_compensations"));
+        }
+        Ok(())
+    }
+}
+
+/// Named affine transform, `matrix * position + offset`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UserTransform {
+    pub name: String,
+    #[serde(default = "identity")]
+    pub matrix: [[f64; 3]; 3],
+    #[serde(default)]
+    pub offset: [f64; 3],
+}
+
+fn identity() -> [[f64; 3]; 3] {
+    [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]
+}
+
+impl UserTransform {
+    pub fn apply(&self, pos: [f64; 3]) -> [f64; 3] {
+        std::array::from_fn(|row| {
+            (0..3)
+                .map(|col| self.matrix[row][col] * pos[col])
+                .sum::<f64>()
+                + self.offset[row]
+        })
+    }
+
+    fn validate(&self) -> Result<(), CompensationError> {
+        let values = self.matrix.iter().flatten().chain(&self.offset);
+        if values.into_iter().all(|v| v.is_finite()) {
+            Ok(())
+        } else {
+            Err(CompensationError::NonFinite("transform matrix and offset"))
+        }
+    }
+}
+
+/// Stages set up from config. The bed mesh is not part of it, as it comes
+/// from calibration.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct CompensationConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skew: Option<SkewCorrection>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub twist: Option<TwistCompensation>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub transforms: Vec<UserTransform>,
+}
+
+/// Compensation stages applied in a fixed order. See the module docs.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CompensationStack {
+    skew: Option<SkewCorrection>,
+    bed_mesh: Option<BedMesh>,
+    twist: Option<TwistCompensation>,
+    transforms: Vec<UserTransform>,
+}
+
+impl CompensationStack {
+    pub fn new(config: &CompensationConfig) -> Result<Self, CompensationError> {
+        let mut stack = Self::default();
+        stack.set_skew(config.skew)?;
+        stack.set_twist(config.twist.clone())?;
+        for transform in &config.transforms {
+            stack.push_transform(transform.clone())?;
+        }
+        Ok(stack)
+    }
+
+    pub fn skew(&self) -> Option<&SkewCorrection> {
+        self.skew.as_ref()
+    }
+
+    pub fn set_skew(&mut self, skew: Option<SkewCorrection>) -> Result<(), CompensationError> {
+        skew.as_ref().map(SkewCorrection::validate).transpose()?;
+        self.skew = skew;
+        Ok(())
+    }
+
+    pub fn bed_mesh(&self) -> Option<&BedMesh> {
+        self.bed_mesh.as_ref()
+    }
+
+    pub fn set_bed_mesh(&mut self, mesh: Option<BedMesh>) {
+        self.bed_mesh = mesh;
+    }
+
+    pub fn twist(&self) -> Option<&TwistCompensation> {
+        self.twist.as_ref()
+    }
+
+    pub fn set_twist(&mut self, twist: Option<TwistCompensation>) -> Result<(), CompensationError> {
+        twist
+            .as_ref()
+            .map(TwistCompensation::validate)
+            .transpose()?;
+        self.twist = twist;
+        Ok(())
+    }
+
+    pub fn transforms(&self) -> &[UserTransform] {
+        &self.transforms
+    }
+
+    /// Add `transform` after the ones already in the stack.
+    pub fn push_transform(&mut self, transform: UserTransform) -> Result<(), CompensationError> {
+        transform.validate()?;
+        self.transforms.push(transform);
+        Ok(())
+    }
+
+    pub fn clear_transforms(&mut self) {
+        self.transforms.clear();
+    }
+
+    /// Active stages, in the order they are applied.
+    pub fn stages(&self) -> Vec<Stage> {
+        let fixed = [
+            self.skew.is_some().then_some(Stage::Skew),
+            self.bed_mesh.is_some().then_some(Stage::BedMesh),
+            self.twist.is_some().then_some(Stage::Twist),
+        ];
+        let user = self.transforms.iter().map(|transform| Stage::User {
+            name: transform.name.clone(),
+        });
+        fixed.into_iter().flatten().chain(user).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.skew.is_none()
+            && self.bed_mesh.is_none()
+            && self.twist.is_none()
+            && self.transforms.is_empty()
+    }
+
+    /// Whether straight moves stay straight, so they need not be split.
+    pub fn is_linear(&self) -> bool {
+        self.bed_mesh.is_none() && self.twist.is_none()
+    }
+
+    /// Position the kinematics must reach for the commanded `pos`.
+    pub fn apply(&self, pos: [f64; 3]) -> [f64; 3] {
+        let mut pos = match &self.skew {
+            Some(skew) => skew.apply(pos),
+            None => pos,
+        };
+        if let Some(mesh) = &self.bed_mesh {
+            pos[2] += mesh.z_offset(pos[0], pos[1]);
+        }
+        if let Some(twist) = &self.twist {
+            pos[2] += twist.z_offset(pos[0]);
+        }
+        self.transforms
+            .iter()
+            .fold(pos, |pos, transform| transform.apply(pos))
+    }
+
+    /// Compensated targets for a move from `start` to `target` (X/Y/Z/E).
+    /// While the stack is not linear the move is split into segments no
+    /// longer than `segment_length` (mm) in X/Y.
+    pub fn compensate(
+        &self,
+        start: [f64; 4],
+        target: [f64; 4],
+        segment_length: f64,
+    ) -> Vec<[f64; 4]> {
+        if self.is_empty() {
+            return vec![target];
+        }
+        let distance = (target[0] - start[0]).hypot(target[1] - start[1]);
+        let segments = if !self.is_linear() && segment_length > 0.0 {
+            ((distance / segment_length).ceil() as usize).max(1)
+        } else {
+            1
+        };
+        (1..=segments)
+            .map(|i| {
+                let t = i as f64 / segments as f64;
+                let mut point: [f64; 4] =
+                    std::array::from_fn(|axis| start[axis] + (target[axis] - start[axis]) * t);
+                if i == segments {
+                    point = target;
+                }
+                let [x, y, z] = self.apply([point[0], point[1], point[2]]);
+                [x, y, z, point[3]]
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tilted_mesh() -> BedMesh {
+        // 0.1 mm higher at X = 10
+        BedMesh::new(
+            [0.0, 0.0],
+            [10.0, 10.0],
+            vec![vec![0.0, 0.1], vec![0.0, 0.1]],
+        )
+        .unwrap()
+    }
+
+    fn twist() -> TwistCompensation {
+        TwistCompensation {
+            start_x: 0.0,
+            end_x: 10.0,
+            z_compensations: vec![0.0, 0.02, -0.02],
+        }
+    }
+
+    #[test]
+    fn empty_stack_passes_moves_through() {
+        let stack = CompensationStack::default();
+        assert!(stack.is_empty());
+        assert!(stack.stages().is_empty());
+        let target = [3.0, 4.0, 0.2, 1.0];
+        assert_eq!(stack.compensate([0.0; 4], target, 1.0), vec![target]);
+    }
+
+    #[test]
+    fn skew_matches_klipper() {
+        let skew = SkewCorrection {
+            xy: 0.01,
+            xz: 0.02,
+            yz: 0.03,
+        };
+        let [x, y, z] = skew.apply([100.0, 50.0, 10.0]);
+        assert!((x - (100.0 - 0.5 - 10.0 * (0.02 - 0.01 * 0.03))).abs() < 1e-12);
+        assert!((y - (50.0 - 0.3)).abs() < 1e-12);
+        assert_eq!(z, 10.0);
+    }
+
+    #[test]
+    fn twist_interpolates_and_clamps() {
+        let twist = twist();
+        assert!((twist.z_offset(2.5) - 0.01).abs() < 1e-12);
+        assert!((twist.z_offset(7.5)).abs() < 1e-12);
+        assert_eq!(twist.z_offset(-5.0), 0.0);
+        assert_eq!(twist.z_offset(50.0), -0.02);
+    }
+
+    #[test]
+    fn stages_apply_in_a_fixed_order() {
+        let mut stack = CompensationStack::new(&CompensationConfig {
+            skew: Some(SkewCorrection {
+                xy: 0.1,
+                ..SkewCorrection::default()
+            }),
+            twist: Some(twist()),
+            transforms: vec![UserTransform {
+                name: "lift".into(),
+                matrix: identity(),
+                offset: [0.0, 0.0, 1.0],
+            }],
+        })
+        .unwrap();
+        stack.set_bed_mesh(Some(tilted_mesh()));
+        assert_eq!(
+            stack.stages(),
+            vec![
+                Stage::Skew,
+                Stage::BedMesh,
+                Stage::Twist,
+                Stage::User {
+                    name: "lift".into()
+                },
+            ]
+        );
+
+        // Skew moves X from 10 to 5 before the mesh and twist look it up.
+        let [x, y, z] = stack.apply([10.0, 50.0, 0.2]);
+        assert!((x - 5.0).abs() < 1e-12);
+        assert_eq!(y, 50.0);
+        assert!((z - (0.2 + 0.05 + 0.02 + 1.0)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn only_nonlinear_stages_split_moves() {
+        let mut stack = CompensationStack::default();
+        stack
+            .set_skew(Some(SkewCorrection {
+                xy: 0.01,
+                ..SkewCorrection::default()
+            }))
+            .unwrap();
+        assert!(stack.is_linear());
+        assert_eq!(
+            stack.compensate([0.0; 4], [10.0, 0.0, 0.2, 1.0], 4.0).len(),
+            1
+        );
+
+        stack.set_twist(Some(twist())).unwrap();
+        let points = stack.compensate([0.0; 4], [10.0, 0.0, 0.2, 1.0], 4.0);
+        assert_eq!(points.len(), 3);
+        assert!((points[0][2] - (0.2 / 3.0 + twist().z_offset(10.0 / 3.0))).abs() < 1e-12);
+        assert_eq!(points[2][3], 1.0);
+    }
+
+    #[test]
+    fn config_round_trips_and_rejects_bad_stages() {
+        let config = CompensationConfig {
+            skew: Some(SkewCorrection::default()),
+            twist: Some(twist()),
+            transforms: vec![],
+        };
+        let json = serde_json::to_string(&config).unwrap();
+        assert_eq!(
+            serde_json::from_str::<CompensationConfig>(&json).unwrap(),
+            config
+        );
+
+        let backwards = CompensationConfig {
+            twist: Some(TwistCompensation {
+                start_x: 10.0,
+                end_x: 0.0,
+                z_compensations: vec![0.0, 0.0],
+            }),
+            ..CompensationConfig::default()
+        };
+        assert_eq!(
+            CompensationStack::new(&backwards),
+            Err(CompensationError::InvalidTwistRange {
+                start_x: 10.0,
+                end_x: 0.0
+            })
+        );
+        let mut stack = CompensationStack::default();
+        let nan = UserTransform {
+            name: "bad".into(),
+            matrix: identity(),
+            offset: [f64::NAN, 0.0, 0.0],
+        };
+        assert!(stack.push_transform(nan).is_err());
+        assert!(stack.is_empty());
+    }
+}
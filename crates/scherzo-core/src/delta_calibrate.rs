@@ -0,0 +1,457 @@
+//! Delta calibration.
+//!
+//! Follows Klipper's `delta_calibrate.py`. The probe is lowered onto the
+//! bed at a handful of points, and each trigger position is reported with
+//! the geometry the printer was configured with. The carriage travel from
+//! the endstops at each trigger is what actually happened, so the
+//! calibration looks for the geometry under which those travels put every
+//! point on a flat bed at Z = 0. The endstop positions, delta radius and
+//! the angles of towers A and B are fitted by damped least squares; tower C
+//! stays put so the solution cannot spin, and the arm lengths are kept.
+
+use crate::kinematics::delta::DeltaKin;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Tower angles (degrees) of a delta built to spec.
+pub const DEFAULT_ANGLES: [f64; 3] = [210.0, 330.0, 90.0];
+/// Parameters fitted: radius, angles of towers A and B, and the three
+/// endstops.
+const PARAMS: usize = 6;
+/// Least squares iterations allowed.
+const MAX_ITERATIONS: usize = 100;
+/// Parameter change below which the fit has converged.
+const CONVERGED: f64 = 1e-9;
+/// Step of the finite differences of the probed heights.
+const DIFF_STEP: f64 = 1e-6;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum DeltaCalibrationError {
+    #[error("delta calibration needs at least {PARAMS} probed points, got {0}")]
+    TooFewSamples(usize),
+    #[error("probed points must be finite")]
+    NonFiniteSample,
+    #[error("probed point {index} ({point:?}) is out of reach of the arms")]
+    Unreachable { index: usize, point: [f64; 3] },
+    #[error("the probed points do not determine the delta geometry")]
+    Singular,
+}
+
+/// Geometry of a linear delta, as in Klipper's `[printer]` and
+/// `[stepper_a]`..`[stepper_c]` sections.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeltaGeometry {
+    /// Distance (mm) from the center to each tower, less the effector and
+    /// carriage offsets.
+    pub radius: f64,
+    /// Angle (degrees) of towers A, B and C around the center.
+    pub angles: [f64; 3],
+    /// Diagonal rod length (mm) of each tower.
+    pub arms: [f64; 3],
+    /// Toolhead Z (mm) at the center when each carriage is at its endstop.
+    pub endstops: [f64; 3],
+}
+
+impl DeltaGeometry {
+    /// X/Y position (mm) of each tower.
+    pub fn towers(&self) -> [[f64; 2]; 3] {
+        self.angles.map(|angle| {
+            let angle = angle.to_radians();
+            [self.radius * angle.cos(), self.radius * angle.sin()]
+        })
+    }
+
+    /// Position callback of each tower's stepper.
+    pub fn steppers(&self) -> [DeltaKin; 3] {
+        let towers = self.towers();
+        std::array::from_fn(|i| DeltaKin::new(self.arms[i].powi(2), towers[i][0], towers[i][1]))
+    }
+
+    /// Carriage height (mm) of each tower with the toolhead at `pos`, or
+    /// `None` if an arm cannot reach it.
+    pub fn carriage_heights(&self, pos: [f64; 3]) -> Option<[f64; 3]> {
+        let towers = self.towers();
+        let mut heights = [0.0; 3];
+        for (i, height) in heights.iter_mut().enumerate() {
+            let dx = towers[i][0] - pos[0];
+            let dy = towers[i][1] - pos[1];
+            let dz2 = self.arms[i].powi(2) - dx * dx - dy * dy;
+            if dz2 <= 0.0 {
+                return None;
+            }
+            *height = dz2.sqrt() + pos[2];
+        }
+        Some(heights)
+    }
+
+    /// Toolhead position with the carriages at `heights`, or `None` if the
+    /// arms cannot meet.
+    pub fn position(&self, heights: [f64; 3]) -> Option<[f64; 3]> {
+        let towers = self.towers();
+        let centers: [[f64; 3]; 3] =
+            std::array::from_fn(|i| [towers[i][0], towers[i][1], heights[i]]);
+        trilaterate(centers, self.arms.map(|arm| arm * arm))
+    }
+
+    /// Carriage height (mm) of each tower at its endstop.
+    fn abs_endstops(&self) -> [f64; 3] {
+        std::array::from_fn(|i| {
+            self.endstops[i] + (self.arms[i].powi(2) - self.radius.powi(2)).sqrt()
+        })
+    }
+
+    fn params(&self) -> [f64; PARAMS] {
+        let [a, b, _] = self.angles;
+        let [ea, eb, ec] = self.endstops;
+        [self.radius, a, b, ea, eb, ec]
+    }
+
+    fn with_params(&self, [radius, a, b, ea, eb, ec]: [f64; PARAMS]) -> Self {
+        Self {
+            radius,
+            angles: [a, b, self.angles[2]],
+            arms: self.arms,
+            endstops: [ea, eb, ec],
+        }
+    }
+}
+
+/// Points (mm) to probe on a delta whose bed reaches `radius` from the
+/// center: the center and six points around it.
+pub fn probe_points(radius: f64) -> Vec<[f64; 2]> {
+    // Slightly scattered distances, as Klipper uses, help separate the
+    // radius from the endstops
+    const SCATTER: [f64; 6] = [0.95, 0.90, 0.85, 0.70, 0.75, 0.80];
+    let mut points = vec![[0.0, 0.0]];
+    for (i, scatter) in SCATTER.into_iter().enumerate() {
+        let angle = (90.0 + 60.0 * i as f64).to_radians();
+        let dist = radius * scatter;
+        points.push([dist * angle.cos(), dist * angle.sin()]);
+    }
+    points
+}
+
+/// Result of [`calibrate`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeltaCalibration {
+    /// Fitted geometry.
+    pub geometry: DeltaGeometry,
+    /// RMS height (mm) of the probed points with the original geometry.
+    pub rms_before: f64,
+    /// RMS height (mm) of the probed points with the fitted geometry.
+    pub rms_after: f64,
+    pub iterations: usize,
+}
+
+/// Fit `geometry` to `samples`, the toolhead positions where the probe
+/// triggered as reported with `geometry`. The probe's Z offset must already
+/// be applied, so that a calibrated printer reports Z = 0 everywhere.
+pub fn calibrate(
+    geometry: &DeltaGeometry,
+    samples: &[[f64; 3]],
+) -> Result<DeltaCalibration, DeltaCalibrationError> {
+    if samples.len() < PARAMS {
+        return Err(DeltaCalibrationError::TooFewSamples(samples.len()));
+    }
+    if samples.iter().flatten().any(|v| !v.is_finite()) {
+        return Err(DeltaCalibrationError::NonFiniteSample);
+    }
+    // Carriage travel from the endstops at each trigger
+    let abs_endstops = geometry.abs_endstops();
+    let travels = samples
+        .iter()
+        .enumerate()
+        .map(|(index, &point)| {
+            let heights = geometry
+                .carriage_heights(point)
+                .ok_or(DeltaCalibrationError::Unreachable { index, point })?;
+            Ok(std::array::from_fn(|i| abs_endstops[i] - heights[i]))
+        })
+        .collect::<Result<Vec<[f64; 3]>, _>>()?;
+    let heights = |params: &[f64; PARAMS]| -> Option<Vec<f64>> {
+        let geometry = geometry.with_params(*params);
+        let abs_endstops = geometry.abs_endstops();
+        travels
+            .iter()
+            .map(|travel| {
+                let heights = std::array::from_fn(|i| abs_endstops[i] - travel[i]);
+                geometry.position(heights).map(|pos| pos[2])
+            })
+            .collect()
+    };
+
+    let mut params = geometry.params();
+    let mut residuals = heights(&params).ok_or(DeltaCalibrationError::Singular)?;
+    let rms_before = rms(&residuals);
+    let mut cost = sum_squares(&residuals);
+    let mut damping = 1e-3;
+    let mut iterations = 0;
+    while iterations < MAX_ITERATIONS {
+        iterations += 1;
+        // Jacobian of the heights by central differences
+        let mut jacobian = vec![[0.0; PARAMS]; samples.len()];
+        for param in 0..PARAMS {
+            let mut high = params;
+            let mut low = params;
+            high[param] += DIFF_STEP;
+            low[param] -= DIFF_STEP;
+            let (Some(high), Some(low)) = (heights(&high), heights(&low)) else {
+                return Err(DeltaCalibrationError::Singular);
+            };
+            for (row, (high, low)) in jacobian.iter_mut().zip(high.iter().zip(&low)) {
+                row[param] = (high - low) / (2.0 * DIFF_STEP);
+            }
+        }
+        let mut jtj = [[0.0; PARAMS]; PARAMS];
+        let mut jtr = [0.0; PARAMS];
+        for (row, residual) in jacobian.iter().zip(&residuals) {
+            for i in 0..PARAMS {
+                jtr[i] += row[i] * residual;
+                for j in 0..PARAMS {
+                    jtj[i][j] += row[i] * row[j];
+                }
+            }
+        }
+
+        // Levenberg-Marquardt: raise the damping until a step helps
+        let step = loop {
+            let mut damped = jtj;
+            for (i, row) in damped.iter_mut().enumerate() {
+                row[i] += damping * jtj[i][i].max(1e-12);
+            }
+            let delta = solve(damped, jtr.map(|v| -v)).ok_or(DeltaCalibrationError::Singular)?;
+            let candidate: [f64; PARAMS] = std::array::from_fn(|i| params[i] + delta[i]);
+            if let Some(candidate_residuals) = heights(&candidate) {
+                let candidate_cost = sum_squares(&candidate_residuals);
+                if candidate_cost <= cost {
+                    damping = (damping * 0.1).max(1e-12);
+                    break Some((candidate, candidate_residuals, candidate_cost, delta));
+                }
+            }
+            damping *= 10.0;
+            if damping > 1e12 {
+                break None;
+            }
+        };
+        let Some((candidate, candidate_residuals, candidate_cost, delta)) = step else {
+            // No step improves the fit any further
+            break;
+        };
+        params = candidate;
+        residuals = candidate_residuals;
+        cost = candidate_cost;
+        if delta.iter().all(|d| d.abs() < CONVERGED) {
+            break;
+        }
+    }
+
+    Ok(DeltaCalibration {
+        geometry: geometry.with_params(params),
+        rms_before,
+        rms_after: rms(&residuals),
+        iterations,
+    })
+}
+
+/// Point at distance sqrt(`radius2`) from each of the `centers`, below
+/// them, as Klipper's `mathutil.trilateration`.
+fn trilaterate(centers: [[f64; 3]; 3], radius2: [f64; 3]) -> Option<[f64; 3]> {
+    let [c1, c2, c3] = centers;
+    let s21 = sub(c2, c1);
+    let s31 = sub(c3, c1);
+    let d = dot(s21, s21).sqrt();
+    let ex = scale(s21, 1.0 / d);
+    let i = dot(ex, s31);
+    let vect_ey = sub(s31, scale(ex, i));
+    let ey = scale(vect_ey, 1.0 / dot(vect_ey, vect_ey).sqrt());
+    let ez = cross(ex, ey);
+    let j = dot(ey, s31);
+    let x = (radius2[0] - radius2[1] + d * d) / (2.0 * d);
+    let y = (radius2[0] - radius2[2] - x * x + (x - i).powi(2) + j * j) / (2.0 * j);
+    let z2 = radius2[0] - x * x - y * y;
+    if z2 < 0.0 {
+        return None;
+    }
+    let z = -z2.sqrt();
+    let pos = add(c1, add(scale(ex, x), add(scale(ey, y), scale(ez, z))));
+    pos.iter().all(|v| v.is_finite()).then_some(pos)
+}
+
+fn sub(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    std::array::from_fn(|i| a[i] - b[i])
+}
+
+fn add(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    std::array::from_fn(|i| a[i] + b[i])
+}
+
+fn scale(a: [f64; 3], s: f64) -> [f64; 3] {
+    a.map(|v| v * s)
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn sum_squares(values: &[f64]) -> f64 {
+    values.iter().map(|v| v * v).sum()
+}
+
+fn rms(values: &[f64]) -> f64 {
+    (sum_squares(values) / values.len() as f64).sqrt()
+}
+
+// Solve `a x = b` by Gaussian elimination with partial pivoting
+fn solve(mut a: [[f64; PARAMS]; PARAMS], mut b: [f64; PARAMS]) -> Option<[f64; PARAMS]> {
+    for col in 0..PARAMS {
+        let pivot = (col..PARAMS).max_by(|&r, &s| a[r][col].abs().total_cmp(&a[s][col].abs()))?;
+        if !a[pivot][col].is_normal() {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        for row in col + 1..PARAMS {
+            let factor = a[row][col] / a[col][col];
+            for k in col..PARAMS {
+                a[row][k] -= factor * a[col][k];
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+    let mut x = [0.0; PARAMS];
+    for row in (0..PARAMS).rev() {
+        let sum: f64 = (row + 1..PARAMS).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+    Some(x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        itersolve::CalcPositionCallback,
+        trap_queue::{Coord, Move},
+    };
+
+    fn nominal() -> DeltaGeometry {
+        DeltaGeometry {
+            radius: 140.0,
+            angles: DEFAULT_ANGLES,
+            arms: [300.0; 3],
+            endstops: [300.0; 3],
+        }
+    }
+
+    /// Where a printer configured with `assumed` but built as `actual`
+    /// reports the probe triggering on a flat bed at (`x`, `y`)
+    fn probe(assumed: &DeltaGeometry, actual: &DeltaGeometry, [x, y]: [f64; 2]) -> [f64; 3] {
+        let actual_z = |z: f64| {
+            let heights = assumed.carriage_heights([x, y, z]).unwrap();
+            let (assumed_ends, actual_ends) = (assumed.abs_endstops(), actual.abs_endstops());
+            let heights = std::array::from_fn(|i| actual_ends[i] - (assumed_ends[i] - heights[i]));
+            actual.position(heights).unwrap()[2]
+        };
+        // The reported Z moves the nozzle by about as much, so iterate
+        let mut z = 0.0;
+        for _ in 0..20 {
+            z -= actual_z(z);
+        }
+        [x, y, z]
+    }
+
+    #[test]
+    fn position_inverts_carriage_heights() {
+        let geometry = DeltaGeometry {
+            angles: [209.5, 330.8, 90.0],
+            arms: [299.0, 300.5, 300.0],
+            ..nominal()
+        };
+        for pos in [[0.0, 0.0, 0.0], [50.0, -30.0, 10.0], [-80.0, 60.0, 120.0]] {
+            let heights = geometry.carriage_heights(pos).unwrap();
+            let back = geometry.position(heights).unwrap();
+            for axis in 0..3 {
+                assert!((back[axis] - pos[axis]).abs() < 1e-9, "{pos:?} -> {back:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn steppers_follow_carriage_heights() {
+        let geometry = nominal();
+        let pos = [20.0, -10.0, 5.0];
+        let m = Move {
+            print_time: 0.0,
+            move_t: 1.0,
+            start_v: 0.0,
+            half_accel: 0.0,
+            start_pos: Coord::new(pos[0], pos[1], pos[2]),
+            axes_r: Coord::default(),
+            tag: None,
+        };
+        let heights = geometry.carriage_heights(pos).unwrap();
+        for (mut stepper, height) in geometry.steppers().into_iter().zip(heights) {
+            assert!((stepper.calc_position(&m, 0.0) - height).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn flattens_probed_bed() {
+        let assumed = nominal();
+        let actual = DeltaGeometry {
+            radius: 141.2,
+            angles: [210.6, 329.7, 90.0],
+            endstops: [300.4, 299.7, 300.1],
+            ..assumed
+        };
+        let mut points = probe_points(100.0);
+        points.extend(probe_points(50.0).into_iter().skip(1));
+        let samples: Vec<_> = points
+            .into_iter()
+            .map(|point| probe(&assumed, &actual, point))
+            .collect();
+
+        let calibration = calibrate(&assumed, &samples).unwrap();
+        assert!(calibration.rms_before > 0.1, "{calibration:?}");
+        assert!(calibration.rms_after < 1e-6, "{calibration:?}");
+        let fitted = calibration.geometry;
+        assert!((fitted.radius - actual.radius).abs() < 1e-3, "{fitted:?}");
+        for i in 0..3 {
+            assert!(
+                (fitted.angles[i] - actual.angles[i]).abs() < 1e-3,
+                "{fitted:?}"
+            );
+            assert!(
+                (fitted.endstops[i] - actual.endstops[i]).abs() < 1e-3,
+                "{fitted:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_too_few_samples() {
+        let samples = [[0.0, 0.0, 0.0]; 3];
+        assert_eq!(
+            calibrate(&nominal(), &samples),
+            Err(DeltaCalibrationError::TooFewSamples(3))
+        );
+    }
+
+    #[test]
+    fn rejects_unreachable_samples() {
+        let mut samples = vec![[0.0, 0.0, 0.0]; 7];
+        samples[4] = [500.0, 0.0, 0.0];
+        assert!(matches!(
+            calibrate(&nominal(), &samples),
+            Err(DeltaCalibrationError::Unreachable { index: 4, .. })
+        ));
+    }
+}
@@ -0,0 +1,48 @@
+//! Motion errors.
+//!
+//! Every fallible step from planning a move to compressing its steps
+//! reports a [`MotionError`], carrying enough context (target, print time,
+//! stepper oid) for the runtime to tell the user what went wrong.
+
+use crate::step_compressor::StepCompressError;
+use thiserror::Error;
+
+pub type Result<T, E = MotionError> = std::result::Result<T, E>;
+
+#[derive(Debug, Error)]
+pub enum MotionError {
+    /// A move was requested to a non-finite position or at a speed that is
+    /// not positive.
+    #[error("invalid move to {target:?} at {speed} mm/s")]
+    InvalidMove { target: [f64; 4], speed: f64 },
+    /// A dwell was requested for a duration that is not finite.
+    #[error("invalid dwell of {seconds} s")]
+    InvalidDwell { seconds: f64 },
+    /// A move was queued on a trapq before the motion already in it ends.
+    #[error("move at print time {print_time:.6} overlaps queued motion ending at {queue_end:.6}")]
+    MoveOverlap { print_time: f64, queue_end: f64 },
+    /// The toolhead was asked to home.
+    #[error("homing is not supported yet")]
+    HomingUnsupported,
+    /// The steps of a stepper could not be compressed into MCU commands.
+    #[error("stepper {oid}: step generation up to print time {print_time:.6} failed: {source}")]
+    StepCompress {
+        oid: u32,
+        print_time: f64,
+        #[source]
+        source: StepCompressError,
+    },
+}
+
+impl MotionError {
+    /// Whether the request that caused the error was at fault, rather than
+    /// the motion system.
+    pub fn is_invalid_request(&self) -> bool {
+        matches!(
+            self,
+            MotionError::InvalidMove { .. }
+                | MotionError::InvalidDwell { .. }
+                | MotionError::HomingUnsupported
+        )
+    }
+}
@@ -0,0 +1,179 @@
+//! Excluding objects mid-print.
+//!
+//! Slicers label the moves of each printed object with
+//! `EXCLUDE_OBJECT_START`/`EXCLUDE_OBJECT_END`. Once an object is excluded
+//! its moves are dropped, following Klipper's `exclude_object` module: the
+//! toolhead stays where the object was entered and, when the next object
+//! starts, travels to where the skipped moves left off without extruding.
+//! The extruder keeps the retraction state it had when the skipped moves
+//! ended, so a retract inside an excluded object is never undone twice.
+//!
+//! Object names are case-insensitive, as they are in Klipper.
+
+use std::collections::BTreeSet;
+
+/// Moves skipped since entering an excluded object.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Skip {
+    /// Toolhead position when the object was entered.
+    toolhead: [f64; 4],
+    /// Filament retracted (mm) when the object was entered.
+    retracted: f64,
+    /// Commanded position at the end of the last skipped move.
+    last: [f64; 4],
+}
+
+/// Drops the moves of excluded objects.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExcludeObject {
+    excluded: BTreeSet<String>,
+    current: Option<String>,
+    /// Subtracted from the commanded E so skipped extrusion is not made up.
+    extruder_offset: f64,
+    /// Furthest commanded E position, to tell retracts from extrusion.
+    max_e: Option<f64>,
+    skip: Option<Skip>,
+}
+
+impl ExcludeObject {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Exclude `name` from the rest of the print. Returns false if it was
+    /// already excluded.
+    pub fn exclude(&mut self, name: &str) -> bool {
+        self.excluded.insert(key(name))
+    }
+
+    pub fn is_excluded(&self, name: &str) -> bool {
+        self.excluded.contains(&key(name))
+    }
+
+    /// Excluded object names, upper-cased.
+    pub fn excluded(&self) -> impl Iterator<Item = &str> {
+        self.excluded.iter().map(String::as_str)
+    }
+
+    /// Object whose moves are being printed, upper-cased.
+    pub fn current(&self) -> Option<&str> {
+        self.current.as_deref()
+    }
+
+    /// `EXCLUDE_OBJECT_START NAME=<name>`.
+    pub fn start(&mut self, name: &str) {
+        self.current = Some(key(name));
+    }
+
+    /// `EXCLUDE_OBJECT_END`.
+    pub fn end(&mut self) {
+        self.current = None;
+    }
+
+    /// Whether moves are currently being dropped.
+    pub fn in_excluded_object(&self) -> bool {
+        self.current
+            .as_ref()
+            .is_some_and(|name| self.excluded.contains(name))
+    }
+
+    /// Where the toolhead is while the commanded position is `commanded`.
+    pub fn toolhead_position(&self, commanded: [f64; 4]) -> [f64; 4] {
+        match &self.skip {
+            Some(skip) => skip.toolhead,
+            None => self.adjust(commanded),
+        }
+    }
+
+    /// Filter a commanded move from `start` to `target`, returning the
+    /// positions the toolhead should actually move through, in order.
+    pub fn filter_move(&mut self, start: [f64; 4], target: [f64; 4]) -> Vec<[f64; 4]> {
+        let max_e = self.max_e.unwrap_or(start[3]).max(start[3]);
+        if self.in_excluded_object() {
+            let skip = self.skip.unwrap_or(Skip {
+                toolhead: self.adjust(start),
+                retracted: max_e - start[3],
+                last: start,
+            });
+            self.skip = Some(Skip {
+                last: target,
+                ..skip
+            });
+            self.max_e = Some(max_e.max(target[3]));
+            return Vec::new();
+        }
+
+        let mut moves = Vec::with_capacity(2);
+        if let Some(skip) = self.skip.take() {
+            // Keep the retraction depth the skipped moves ended with
+            let retracted = max_e - skip.last[3];
+            let e = skip.toolhead[3] - (retracted - skip.retracted);
+            self.extruder_offset = skip.last[3] - e;
+            moves.push(self.adjust(skip.last));
+        }
+        self.max_e = Some(max_e.max(target[3]));
+        moves.push(self.adjust(target));
+        moves
+    }
+
+    fn adjust(&self, mut position: [f64; 4]) -> [f64; 4] {
+        position[3] -= self.extruder_offset;
+        position
+    }
+}
+
+fn key(name: &str) -> String {
+    name.trim().to_ascii_uppercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moves_pass_through_without_exclusions() {
+        let mut filter = ExcludeObject::new();
+        filter.start("part_1");
+        let target = [10.0, 5.0, 0.2, 1.0];
+        assert_eq!(filter.filter_move([0.0; 4], target), [target]);
+        assert_eq!(filter.current(), Some("PART_1"));
+        assert!(!filter.in_excluded_object());
+    }
+
+    #[test]
+    fn excluded_objects_are_skipped() {
+        let mut filter = ExcludeObject::new();
+        assert!(filter.exclude("Part_2"));
+        assert!(!filter.exclude("PART_2"));
+
+        filter.start("part_1");
+        assert_eq!(filter.filter_move([0.0; 4], [10.0, 0.0, 0.2, 2.0]).len(), 1);
+        filter.end();
+
+        filter.start("part_2");
+        assert!(filter.in_excluded_object());
+        assert!(
+            filter
+                .filter_move([10.0, 0.0, 0.2, 2.0], [20.0, 0.0, 0.2, 5.0])
+                .is_empty()
+        );
+        // Retract at the end of the skipped object
+        assert!(
+            filter
+                .filter_move([20.0, 0.0, 0.2, 5.0], [20.0, 10.0, 0.2, 4.0])
+                .is_empty()
+        );
+        assert_eq!(
+            filter.toolhead_position([20.0, 10.0, 0.2, 4.0]),
+            [10.0, 0.0, 0.2, 2.0]
+        );
+        filter.end();
+
+        // Travel to where the skipped moves ended, keeping the retract, then
+        // unretract and carry on without making up the skipped extrusion
+        let moves = filter.filter_move([20.0, 10.0, 0.2, 4.0], [20.0, 10.0, 0.2, 5.0]);
+        assert_eq!(moves, [[20.0, 10.0, 0.2, 1.0], [20.0, 10.0, 0.2, 2.0]]);
+        let moves = filter.filter_move([20.0, 10.0, 0.2, 5.0], [30.0, 10.0, 0.2, 6.0]);
+        assert_eq!(moves, [[30.0, 10.0, 0.2, 3.0]]);
+    }
+}
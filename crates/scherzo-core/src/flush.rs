@@ -0,0 +1,379 @@
+//! Step generation scheduler.
+//!
+//! Moves are scheduled onto the trapqs well ahead of the MCU, but steps are
+//! only generated a short time before they are due so the MCU's queues stay
+//! small. [`FlushScheduler`] decides when to flush the lookahead queue and
+//! how far to run the step generators, following `_flush_handler` and
+//! `_advance_flush_time` in Klipper's `toolhead.py`.
+//!
+//! The scheduler is sans-IO: the caller supplies the estimated print time of
+//! the MCU on every call and sleeps until the print time it returns.
+
+use crate::{error::Result, toolhead::MotionController};
+
+/// How long (seconds) trapq history is kept after its steps are flushed.
+pub const MOVE_HISTORY_EXPIRE: f64 = 30.0;
+
+/// Buffering windows, all in seconds of print time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BufferConfig {
+    /// Flush the lookahead queue once less than this much motion is queued
+    /// ahead of the MCU.
+    pub low_time: f64,
+    /// Producers should wait while more than this much motion is queued.
+    pub high_time: f64,
+    /// Delay before the first move after the toolhead was idle.
+    pub start_time: f64,
+    /// Generate more steps once less than this much is flushed ahead of the
+    /// MCU.
+    pub flush_low_time: f64,
+    /// How far past `flush_low_time` each background flush runs.
+    pub flush_batch_time: f64,
+    /// Extra time flushed after the last move so its steps are sent.
+    pub flush_extra_time: f64,
+    /// How far step generation runs ahead of the compressor flush.
+    pub step_flush_time: f64,
+    /// Minimum time between restarting motion and its first step.
+    pub min_kin_time: f64,
+}
+
+impl Default for BufferConfig {
+    fn default() -> Self {
+        Self {
+            low_time: 1.0,
+            high_time: 2.0,
+            start_time: 0.250,
+            flush_low_time: 0.200,
+            flush_batch_time: 0.200,
+            flush_extra_time: 0.250,
+            step_flush_time: 0.050,
+            min_kin_time: 0.100,
+        }
+    }
+}
+
+/// Converts trapq motion into MCU commands, e.g. one stepper's solver and
+/// step compressor.
+pub trait StepGenerator {
+    /// Generate steps for motion up to `step_gen_time`, then release the
+    /// compressed steps scheduled before `flush_time`.
+    fn generate(
+        &mut self,
+        toolhead: &MotionController,
+        step_gen_time: f64,
+        flush_time: f64,
+    ) -> Result<()>;
+}
+
+/// How closely the scheduler keeps up with the MCU.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FlushStats {
+    /// Step generation passes.
+    pub flushes: u64,
+    /// Passes that started after the MCU had already reached the last flush
+    /// time, so the new steps may arrive late.
+    pub underruns: u64,
+    /// Motion queued ahead of the MCU at the last timer (seconds).
+    pub buffer_time: f64,
+    /// Smallest lead of flushed steps over the MCU at a flush (seconds).
+    pub min_flush_lead: f64,
+    /// Largest delay between a requested wake-up and the timer actually
+    /// running (seconds).
+    pub max_timer_lateness: f64,
+    /// Print time up to which steps have been released.
+    pub last_flush_time: f64,
+    /// Lead of the steps over the MCU at the last flush (seconds); negative
+    /// if the MCU had already passed the previous flush time.
+    pub last_flush_lead: f64,
+}
+
+/// Decides when to turn queued motion into steps.
+#[derive(Clone, Debug)]
+pub struct FlushScheduler {
+    config: BufferConfig,
+    last_flush_time: f64,
+    /// Steps may not be scheduled before this print time when motion
+    /// restarts.
+    min_restart_time: f64,
+    /// Print time the timer was asked to run at, or `None` while idle.
+    wake_time: Option<f64>,
+    stats: FlushStats,
+}
+
+impl FlushScheduler {
+    pub fn new(config: BufferConfig) -> Self {
+        Self {
+            config,
+            last_flush_time: 0.0,
+            min_restart_time: 0.0,
+            wake_time: None,
+            stats: FlushStats {
+                min_flush_lead: f64::INFINITY,
+                ..FlushStats::default()
+            },
+        }
+    }
+
+    pub fn config(&self) -> &BufferConfig {
+        &self.config
+    }
+
+    pub fn stats(&self) -> FlushStats {
+        self.stats
+    }
+
+    /// Whether all queued motion has been flushed.
+    pub fn is_idle(&self) -> bool {
+        self.wake_time.is_none()
+    }
+
+    /// Prepare the toolhead for new moves and return when the timer should
+    /// next run.
+    ///
+    /// After an idle period the print time is moved far enough ahead of the
+    /// MCU to build up a buffer before the first step.
+    pub fn prepare(&mut self, toolhead: &mut MotionController, est_print_time: f64) -> Result<f64> {
+        if self.is_idle() {
+            // Nothing is pending, so there is nothing left to flush before now
+            self.last_flush_time = self.last_flush_time.max(est_print_time);
+            let kin_time = (est_print_time + self.config.min_kin_time).max(self.min_restart_time);
+            let min_print_time = (est_print_time + self.config.start_time).max(kin_time);
+            if min_print_time > toolhead.print_time() {
+                toolhead.set_print_time(min_print_time)?;
+            }
+            self.wake_time = Some(est_print_time);
+        }
+        Ok(self.wake_time.unwrap())
+    }
+
+    /// How long (seconds) a producer should wait before queueing more moves,
+    /// if the buffer is full.
+    pub fn throttle_time(&self, toolhead: &MotionController, est_print_time: f64) -> Option<f64> {
+        let buffer_time = toolhead.print_time() - est_print_time;
+        (buffer_time > self.config.high_time).then_some(buffer_time - self.config.high_time)
+    }
+
+    /// Run the timer at `est_print_time`.
+    ///
+    /// Returns the print time at which to run it again, or `None` once all
+    /// motion has been flushed and the toolhead is idle.
+    pub fn on_timer<G: StepGenerator>(
+        &mut self,
+        toolhead: &mut MotionController,
+        generators: &mut [G],
+        est_print_time: f64,
+    ) -> Result<Option<f64>> {
+        if let Some(wake_time) = self.wake_time {
+            let lateness = est_print_time - wake_time;
+            self.stats.max_timer_lateness = self.stats.max_timer_lateness.max(lateness);
+        }
+        let buffer_time = toolhead.print_time() - est_print_time;
+        self.stats.buffer_time = buffer_time;
+        if buffer_time > self.config.low_time {
+            // Running normally; the lookahead queue has plenty of time
+            return Ok(self.wake_at(est_print_time + buffer_time - self.config.low_time));
+        }
+
+        // Running low: commit to the moves queued so far
+        toolhead.flush_lookahead()?;
+        loop {
+            let end_flush = toolhead.print_time() + self.config.flush_extra_time;
+            if self.last_flush_time >= end_flush {
+                return Ok(self.wake_at_idle());
+            }
+            let lead = self.last_flush_time - est_print_time;
+            if lead > self.config.flush_low_time {
+                return Ok(self.wake_at(est_print_time + lead - self.config.flush_low_time));
+            }
+            let flush_time =
+                est_print_time + self.config.flush_low_time + self.config.flush_batch_time;
+            self.advance_flush_time(toolhead, generators, flush_time.min(end_flush), lead)?;
+        }
+    }
+
+    /// Flush every queued move now, e.g. before waiting for the toolhead to
+    /// stop or at the end of a job.
+    pub fn flush_all<G: StepGenerator>(
+        &mut self,
+        toolhead: &mut MotionController,
+        generators: &mut [G],
+        est_print_time: f64,
+    ) -> Result<()> {
+        toolhead.flush_lookahead()?;
+        let lead = self.last_flush_time - est_print_time;
+        let flush_time = toolhead.print_time() + self.config.flush_extra_time;
+        self.advance_flush_time(toolhead, generators, flush_time, lead)?;
+        self.wake_at_idle();
+        Ok(())
+    }
+
+    fn advance_flush_time<G: StepGenerator>(
+        &mut self,
+        toolhead: &mut MotionController,
+        generators: &mut [G],
+        flush_time: f64,
+        lead: f64,
+    ) -> Result<()> {
+        let flush_time = flush_time.max(self.last_flush_time);
+        // Generate steps a little past the flush time so the compressor can
+        // look ahead, but never past the end of the queued motion
+        let step_gen_time = (flush_time + self.config.step_flush_time)
+            .min(toolhead.print_time())
+            .max(flush_time);
+        for generator in generators.iter_mut() {
+            generator.generate(toolhead, step_gen_time, flush_time)?;
+        }
+        self.min_restart_time = self.min_restart_time.max(step_gen_time);
+        toolhead.finalize_moves(step_gen_time, flush_time - MOVE_HISTORY_EXPIRE);
+
+        self.last_flush_time = flush_time;
+        self.stats.flushes += 1;
+        if lead < 0.0 {
+            self.stats.underruns += 1;
+        }
+        self.stats.min_flush_lead = self.stats.min_flush_lead.min(lead);
+        self.stats.last_flush_lead = lead;
+        self.stats.last_flush_time = flush_time;
+        Ok(())
+    }
+
+    fn wake_at(&mut self, print_time: f64) -> Option<f64> {
+        self.wake_time = Some(print_time);
+        self.wake_time
+    }
+
+    fn wake_at_idle(&mut self) -> Option<f64> {
+        self.wake_time = None;
+        None
+    }
+}
+
+impl Default for FlushScheduler {
+    fn default() -> Self {
+        Self::new(BufferConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::planner::MachineLimits;
+
+    /// Records the times each flush asked for.
+    #[derive(Default)]
+    struct Recorder(Vec<(f64, f64)>);
+
+    impl StepGenerator for Recorder {
+        fn generate(
+            &mut self,
+            _toolhead: &MotionController,
+            step_gen_time: f64,
+            flush_time: f64,
+        ) -> Result<()> {
+            self.0.push((step_gen_time, flush_time));
+            Ok(())
+        }
+    }
+
+    /// Run the timer in simulated time until the scheduler goes idle.
+    fn run(
+        scheduler: &mut FlushScheduler,
+        toolhead: &mut MotionController,
+        generators: &mut [Recorder],
+        mut now: f64,
+    ) -> f64 {
+        while let Some(wake) = scheduler.on_timer(toolhead, generators, now).unwrap() {
+            assert!(wake > now, "timer must make progress");
+            now = wake;
+        }
+        now
+    }
+
+    #[test]
+    fn buffers_start_and_flushes_in_batches() {
+        let mut toolhead = MotionController::new(MachineLimits::default());
+        let mut scheduler = FlushScheduler::default();
+        let mut generators = [Recorder::default()];
+
+        let wake = scheduler.prepare(&mut toolhead, 10.0).unwrap();
+        assert_eq!(wake, 10.0);
+        assert_eq!(toolhead.print_time(), 10.25);
+        toolhead.move_to([100.0, 0.0, 0.0, 0.0], 100.0).unwrap();
+        let end = run(&mut scheduler, &mut toolhead, &mut generators, wake);
+
+        let flushes = &generators[0].0;
+        let end_of_motion = toolhead.print_time();
+        assert!(end_of_motion > 11.0);
+        assert!(flushes.len() > 2, "{flushes:?}");
+        assert!(flushes.windows(2).all(|w| w[0].1 < w[1].1));
+        assert!(flushes.iter().all(|(step_gen, flush)| step_gen >= flush));
+        assert_eq!(
+            flushes.last().unwrap().1,
+            end_of_motion + BufferConfig::default().flush_extra_time
+        );
+        assert!(end < end_of_motion + BufferConfig::default().flush_extra_time);
+
+        let stats = scheduler.stats();
+        assert_eq!(stats.flushes, flushes.len() as u64);
+        assert_eq!(stats.underruns, 0);
+        assert!(stats.min_flush_lead >= 0.0);
+        assert!(scheduler.is_idle());
+    }
+
+    #[test]
+    fn waits_while_buffer_is_full() {
+        let mut toolhead = MotionController::new(MachineLimits::default());
+        let mut scheduler = FlushScheduler::default();
+        let mut generators = [Recorder::default()];
+
+        scheduler.prepare(&mut toolhead, 0.0).unwrap();
+        for i in 1..=20 {
+            let x = if i % 2 == 0 { 0.0 } else { 200.0 };
+            toolhead.move_to([x, 0.0, 0.0, 0.0], 100.0).unwrap();
+        }
+        assert!(toolhead.print_time() > 20.0);
+        let throttle = scheduler.throttle_time(&toolhead, 0.0).unwrap();
+        assert!(throttle > 18.0);
+
+        // Plenty is buffered, so the first timer does nothing
+        let wake = scheduler
+            .on_timer(&mut toolhead, &mut generators, 0.0)
+            .unwrap()
+            .unwrap();
+        assert!(generators[0].0.is_empty());
+        assert!(wake > 18.0);
+    }
+
+    #[test]
+    fn late_timers_count_as_drift() {
+        let mut toolhead = MotionController::new(MachineLimits::default());
+        let mut scheduler = FlushScheduler::default();
+        let mut generators = [Recorder::default()];
+
+        scheduler.prepare(&mut toolhead, 0.0).unwrap();
+        toolhead.move_to([50.0, 0.0, 0.0, 0.0], 100.0).unwrap();
+        let wake = scheduler
+            .on_timer(&mut toolhead, &mut generators, 0.0)
+            .unwrap()
+            .unwrap();
+
+        // The host stalled for half a second past the requested wake-up
+        scheduler
+            .on_timer(&mut toolhead, &mut generators, wake + 0.5)
+            .unwrap();
+        let stats = scheduler.stats();
+        assert!((stats.max_timer_lateness - 0.5).abs() < 1e-9);
+        assert_eq!(stats.underruns, 1);
+        assert!(stats.min_flush_lead < 0.0);
+        assert_eq!(stats.last_flush_lead, stats.min_flush_lead);
+
+        scheduler
+            .flush_all(&mut toolhead, &mut generators, wake + 0.5)
+            .unwrap();
+        assert!(scheduler.is_idle());
+        assert_eq!(
+            scheduler.stats().last_flush_time,
+            toolhead.print_time() + BufferConfig::default().flush_extra_time
+        );
+    }
+}
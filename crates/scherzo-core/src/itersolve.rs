@@ -1,57 +1,85 @@
 // Iterative solver for kinematic moves
 
 use crate::{
+    error::MotionError,
     step_compressor::{CommandSink, StepCompressor},
-    trap_queue::{Move, TrapQueue},
+    trap_queue::{Coord, Move, TrapQueue, XYZ},
 };
 
 // Constants
 const SEEK_TIME_RESET: f64 = 0.000100;
 
-// Active flags for axis filtering
+// Active flags for axis filtering, by axis index (0 = X, 1 = Y, 2 = Z)
 #[derive(Debug, Clone, Copy, Default)]
-pub struct ActiveFlags(u8);
+pub struct ActiveFlags(u32);
 
 impl ActiveFlags {
-    const X: u8 = 1 << 0;
-    const Y: u8 = 1 << 1;
-    const Z: u8 = 1 << 2;
+    /// Most axes that can be flagged.
+    pub const MAX_AXES: usize = u32::BITS as usize;
 
     pub const fn new() -> Self {
         Self(0)
     }
 
-    pub const fn with_x(mut self) -> Self {
-        self.0 |= Self::X;
+    pub const fn with_axis(mut self, axis: usize) -> Self {
+        assert!(axis < Self::MAX_AXES, "axis index out of range");
+        self.0 |= 1 << axis;
         self
     }
 
-    pub const fn with_y(mut self) -> Self {
-        self.0 |= Self::Y;
-        self
+    pub const fn has_axis(&self, axis: usize) -> bool {
+        axis < Self::MAX_AXES && self.0 & (1 << axis) != 0
     }
 
-    pub const fn with_z(mut self) -> Self {
-        self.0 |= Self::Z;
-        self
+    pub const fn with_x(self) -> Self {
+        self.with_axis(0)
+    }
+
+    pub const fn with_y(self) -> Self {
+        self.with_axis(1)
+    }
+
+    pub const fn with_z(self) -> Self {
+        self.with_axis(2)
     }
 
     pub const fn has_x(&self) -> bool {
-        self.0 & Self::X != 0
+        self.has_axis(0)
     }
 
     pub const fn has_y(&self) -> bool {
-        self.0 & Self::Y != 0
+        self.has_axis(1)
     }
 
     pub const fn has_z(&self) -> bool {
-        self.0 & Self::Z != 0
+        self.has_axis(2)
     }
 }
 
 // Position callback trait - calculates position at a given time in a move
-pub trait CalcPositionCallback {
-    fn calc_position(&mut self, m: &Move, move_time: f64) -> f64;
+// over `N` axes
+pub trait CalcPositionCallback<const N: usize = XYZ> {
+    fn calc_position(&mut self, m: &Move<N>, move_time: f64) -> f64;
+
+    /// Weights of each axis when the stepper position is a fixed linear
+    /// combination of the toolhead coordinates.
+    ///
+    /// The position is then quadratic in move time, so step times are solved
+    /// in closed form instead of by the secant search. Returning `None` (the
+    /// default) always uses the search.
+    fn linear_coefficients(&self) -> Option<Coord<N>> {
+        None
+    }
+}
+
+impl<const N: usize, C: CalcPositionCallback<N> + ?Sized> CalcPositionCallback<N> for &mut C {
+    fn calc_position(&mut self, m: &Move<N>, move_time: f64) -> f64 {
+        (**self).calc_position(m, move_time)
+    }
+
+    fn linear_coefficients(&self) -> Option<Coord<N>> {
+        (**self).linear_coefficients()
+    }
 }
 
 // Post-step callback trait - called after steps are generated
@@ -64,6 +92,36 @@ impl PostCallback for () {
     fn post_step(&mut self) {}
 }
 
+/// Work done by [`IterativeSolver::generate_steps`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SolverCounts {
+    /// Positions evaluated by the secant search.
+    pub iterations: u64,
+    /// Steps the step compressor dropped to avoid a step+dir+step.
+    pub rollbacks: u64,
+    /// Steps passed to the step compressor.
+    pub steps: u64,
+}
+
+impl std::ops::AddAssign for SolverCounts {
+    fn add_assign(&mut self, other: Self) {
+        self.iterations += other.iterations;
+        self.rollbacks += other.rollbacks;
+        self.steps += other.steps;
+    }
+}
+
+/// Per-flush solver statistics, for tuning and spotting regressions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SolverStats {
+    /// Calls to [`IterativeSolver::generate_steps`].
+    pub flushes: u64,
+    /// Work of the last flush.
+    pub last_flush: SolverCounts,
+    /// Work of every flush so far.
+    pub total: SolverCounts,
+}
+
 // Timepos helper struct for secant method
 #[derive(Debug, Clone, Copy)]
 struct TimePos {
@@ -71,8 +129,9 @@ struct TimePos {
     position: f64,
 }
 
-/// Iterative solver for generating step times from kinematic moves
-pub struct IterativeSolver<C, P = ()> {
+/// Iterative solver for generating step times from kinematic moves over `N`
+/// axes
+pub struct IterativeSolver<C, P = (), const N: usize = XYZ> {
     step_dist: f64,
     commanded_pos: f64,
     last_flush_time: f64,
@@ -82,9 +141,22 @@ pub struct IterativeSolver<C, P = ()> {
     gen_steps_post_active: f64,
     calc_position_cb: C,
     post_cb: P,
+    // Work of the flush in progress
+    counts: SolverCounts,
+    stats: Option<SolverStats>,
 }
 
 impl<C: CalcPositionCallback, P: PostCallback> IterativeSolver<C, P> {
+    pub fn set_position(&mut self, x: f64, y: f64, z: f64) {
+        self.set_coord(Coord::new(x, y, z));
+    }
+
+    pub fn calc_position_from_coord(&mut self, x: f64, y: f64, z: f64) -> f64 {
+        self.calc_position_at(Coord::new(x, y, z))
+    }
+}
+
+impl<C: CalcPositionCallback<N>, P: PostCallback, const N: usize> IterativeSolver<C, P, N> {
     pub fn new(
         step_dist: f64,
         active_flags: ActiveFlags,
@@ -103,46 +175,138 @@ impl<C: CalcPositionCallback, P: PostCallback> IterativeSolver<C, P> {
             gen_steps_post_active,
             calc_position_cb,
             post_cb,
+            counts: SolverCounts::default(),
+            stats: None,
         }
     }
 
+    /// Collect [`SolverStats`] from the next flush on.
+    pub fn enable_stats(&mut self) {
+        self.stats.get_or_insert_default();
+    }
+
+    /// Statistics collected since [`enable_stats`](Self::enable_stats).
+    pub fn stats(&self) -> Option<&SolverStats> {
+        self.stats.as_ref()
+    }
+
     pub fn commanded_pos(&self) -> f64 {
         self.commanded_pos
     }
 
-    pub fn set_position(&mut self, x: f64, y: f64, z: f64) {
-        self.commanded_pos = self.calc_position_from_coord(x, y, z);
+    pub fn set_coord(&mut self, pos: Coord<N>) {
+        self.commanded_pos = self.calc_position_at(pos);
     }
 
-    pub fn calc_position_from_coord(&mut self, x: f64, y: f64, z: f64) -> f64 {
+    pub fn calc_position_at(&mut self, pos: Coord<N>) -> f64 {
         // Create a dummy move at the given position with a long duration
         let m = Move {
             print_time: 0.0,
             move_t: 1000.0,
             start_v: 0.0,
             half_accel: 0.0,
-            start_pos: crate::trap_queue::Coord { x, y, z },
-            axes_r: crate::trap_queue::Coord {
-                x: 0.0,
-                y: 0.0,
-                z: 0.0,
-            },
+            start_pos: pos,
+            axes_r: Coord::default(),
+            tag: None,
         };
         self.calc_position_cb.calc_position(&m, 500.0)
     }
 
     // Check if a move is likely to cause movement on this stepper
-    fn check_active(&self, m: &Move) -> bool {
-        (self.active_flags.has_x() && m.axes_r.x != 0.0)
-            || (self.active_flags.has_y() && m.axes_r.y != 0.0)
-            || (self.active_flags.has_z() && m.axes_r.z != 0.0)
+    fn check_active(&self, m: &Move<N>) -> bool {
+        m.axes_r
+            .axes
+            .iter()
+            .enumerate()
+            .any(|(axis, r)| self.active_flags.has_axis(axis) && *r != 0.0)
     }
 
-    // Generate step times for a portion of a move using secant method
+    // Generate step times for a portion of a move, in closed form when the
+    // stepper position is quadratic in time
     fn gen_steps_range<S: CommandSink>(
         &mut self,
         sc: &mut StepCompressor<S>,
-        m: &Move,
+        m: &Move<N>,
+        abs_start: f64,
+        abs_end: f64,
+    ) -> Result<(), crate::step_compressor::StepCompressError> {
+        if let Some(coeffs) = self.calc_position_cb.linear_coefficients() {
+            let rate = coeffs.dot(&m.axes_r);
+            let end_v = m.start_v + 2.0 * m.half_accel * m.move_t;
+            if rate != 0.0 && m.start_v >= 0.0 && end_v >= 0.0 {
+                return self.gen_steps_quadratic(sc, m, coeffs, rate, abs_start, abs_end);
+            }
+        }
+        self.gen_steps_secant(sc, m, abs_start, abs_end)
+    }
+
+    // Solve step times of a move whose stepper position is
+    // `p0 + rate * (start_v + half_accel * t) * t` and so moves in one
+    // direction. Matches the secant search: steps where the position crosses
+    // half way between step positions, direction changes only once the
+    // stepper is fully past the old step position, and commits once the
+    // stepper reaches its last step position.
+    fn gen_steps_quadratic<S: CommandSink>(
+        &mut self,
+        sc: &mut StepCompressor<S>,
+        m: &Move<N>,
+        coeffs: Coord<N>,
+        rate: f64,
+        abs_start: f64,
+        abs_end: f64,
+    ) -> Result<(), crate::step_compressor::StepCompressError> {
+        let half_step = 0.5 * self.step_dist;
+        let start = (abs_start - m.print_time).max(0.0);
+        let end = (abs_end - m.print_time).min(m.move_t);
+        let p0 = coeffs.dot(&m.start_pos);
+        let position = |t: f64| p0 + rate * (m.start_v + m.half_accel * t) * t;
+        // Time the stepper reaches `target`, which is no later than `end`
+        let solve = |target: f64| {
+            let dist = (target - p0) / rate;
+            let disc = (m.start_v * m.start_v + 4.0 * m.half_accel * dist).max(0.0);
+            let denom = m.start_v + disc.sqrt();
+            let t = if denom > 0.0 { 2.0 * dist / denom } else { 0.0 };
+            t.clamp(start, end)
+        };
+
+        let mut sdir = sc.get_last_dir();
+        let sign = |sdir: bool| if sdir { 1.0 } else { -1.0 };
+        let end_pos = position(end);
+        if sign(sdir) * (position(start) - self.commanded_pos) >= 0.0 {
+            // Avoid rollback if stepper fully reaches step position
+            sc.commit()?;
+        }
+        let mut target = self.commanded_pos + sign(sdir) * half_step;
+        if (rate > 0.0) != sdir {
+            let reversed = target - sign(sdir) * 2.0 * half_step;
+            if sign(!sdir) * (end_pos - reversed) > 0.000000010 {
+                sdir = !sdir;
+                target = reversed;
+            }
+        }
+
+        let dir = sign(sdir);
+        if (rate > 0.0) == sdir {
+            while dir * (end_pos - target) >= -0.000000001 {
+                sc.append(sdir as i32, m.print_time, solve(target))?;
+                self.counts.steps += 1;
+                target += dir * 2.0 * half_step;
+            }
+        }
+
+        self.commanded_pos = target - dir * half_step;
+        if dir * (end_pos - self.commanded_pos) >= 0.0 {
+            sc.commit()?;
+        }
+        self.post_cb.post_step();
+        Ok(())
+    }
+
+    // Generate step times for a portion of a move using secant method
+    fn gen_steps_secant<S: CommandSink>(
+        &mut self,
+        sc: &mut StepCompressor<S>,
+        m: &Move<N>,
         abs_start: f64,
         abs_end: f64,
     ) -> Result<(), crate::step_compressor::StepCompressError> {
@@ -204,6 +368,7 @@ impl<C: CalcPositionCallback, P: PostCallback> IterativeSolver<C, P> {
             old_guess = guess;
             guess.time = next_time;
             guess.position = self.calc_position_cb.calc_position(m, next_time);
+            self.counts.iterations += 1;
             let guess_dist = guess.position - target;
 
             if guess_dist.abs() > 0.000000001 {
@@ -250,6 +415,7 @@ impl<C: CalcPositionCallback, P: PostCallback> IterativeSolver<C, P> {
 
             // Found next step - submit it
             sc.append(sdir as i32, m.print_time, guess.time)?;
+            self.counts.steps += 1;
             target = if sdir {
                 target + half_step + half_step
             } else {
@@ -284,7 +450,29 @@ impl<C: CalcPositionCallback, P: PostCallback> IterativeSolver<C, P> {
     pub fn generate_steps<S: CommandSink>(
         &mut self,
         sc: &mut StepCompressor<S>,
-        trapq: &TrapQueue,
+        trapq: &TrapQueue<N>,
+        flush_time: f64,
+    ) -> Result<(), MotionError> {
+        let rollbacks = sc.rollbacks();
+        self.counts = SolverCounts::default();
+        let result = self.generate_flush_steps(sc, trapq, flush_time);
+        if let Some(stats) = &mut self.stats {
+            self.counts.rollbacks = sc.rollbacks() - rollbacks;
+            stats.flushes += 1;
+            stats.last_flush = self.counts;
+            stats.total += self.counts;
+        }
+        result.map_err(|source| MotionError::StepCompress {
+            oid: sc.oid(),
+            print_time: flush_time,
+            source,
+        })
+    }
+
+    fn generate_flush_steps<S: CommandSink>(
+        &mut self,
+        sc: &mut StepCompressor<S>,
+        trapq: &TrapQueue<N>,
         flush_time: f64,
     ) -> Result<(), crate::step_compressor::StepCompressError> {
         let last_flush_time = self.last_flush_time;
@@ -296,15 +484,7 @@ impl<C: CalcPositionCallback, P: PostCallback> IterativeSolver<C, P> {
         }
 
         // Find first move that hasn't been fully processed
-        let mut move_idx = 0;
-        while move_idx < moves.len() {
-            let m = moves[move_idx];
-            if last_flush_time < m.print_time + m.move_t {
-                break;
-            }
-            move_idx += 1;
-        }
-
+        let mut move_idx = trapq.find_move(last_flush_time);
         if move_idx >= moves.len() {
             return Ok(());
         }
@@ -317,7 +497,7 @@ impl<C: CalcPositionCallback, P: PostCallback> IterativeSolver<C, P> {
                 break;
             }
 
-            let m = moves[move_idx];
+            let m = &moves[move_idx];
             let move_start = m.print_time;
             let move_end = move_start + m.move_t;
 
@@ -344,7 +524,7 @@ impl<C: CalcPositionCallback, P: PostCallback> IterativeSolver<C, P> {
                     }
 
                     while pm_idx < move_idx {
-                        self.gen_steps_range(sc, moves[pm_idx], abs_start, flush_time)?;
+                        self.gen_steps_range(sc, &moves[pm_idx], abs_start, flush_time)?;
                         pm_idx += 1;
                     }
                 }
@@ -385,32 +565,16 @@ impl<C: CalcPositionCallback, P: PostCallback> IterativeSolver<C, P> {
     }
 
     // Check if the given stepper is likely to be active in the given time range
-    pub fn check_active_time(&self, trapq: &TrapQueue, flush_time: f64) -> Option<f64> {
-        let moves = trapq.get_active_moves();
-        if moves.is_empty() {
-            return None;
-        }
-
-        // Find first move past last flush time
-        let mut move_idx = 0;
-        while move_idx < moves.len() {
-            let m = moves[move_idx];
-            if self.last_flush_time < m.print_time + m.move_t {
-                break;
-            }
-            move_idx += 1;
-        }
-
-        // Check moves for activity
-        while move_idx < moves.len() {
-            let m = moves[move_idx];
+    pub fn check_active_time(&self, trapq: &TrapQueue<N>, flush_time: f64) -> Option<f64> {
+        // Check moves past last flush time for activity
+        let first = trapq.find_move(self.last_flush_time);
+        for m in trapq.iter_active().skip(first) {
             if self.check_active(m) {
                 return Some(m.print_time);
             }
             if flush_time <= m.print_time + m.move_t {
                 return None;
             }
-            move_idx += 1;
         }
 
         None
@@ -439,7 +603,7 @@ mod tests {
         fn calc_position(&mut self, m: &Move, move_time: f64) -> f64 {
             // Calculate position along the move using trapezoidal profile
             let move_dist = (m.start_v + m.half_accel * move_time) * move_time;
-            m.start_pos.x + m.axes_r.x * move_dist
+            m.start_pos.x() + m.axes_r.x() * move_dist
         }
     }
 
@@ -467,19 +631,21 @@ mod tests {
         );
 
         let mut trapq = TrapQueue::new();
-        trapq.append(
-            0.0, // print_time
-            0.5, // accel time
-            0.5, // cruise time
-            0.5, // decel time
-            0.0, // start_pos x
-            0.0, // start_pos y
-            0.0, // start_pos z
-            10.0, 10.0, 10.0, // axes_r (x, y, z)
-            0.0,  // start_v
-            0.0,  // cruise_v
-            20.0, // accel
-        );
+        trapq
+            .append(
+                0.0, // print_time
+                0.5, // accel time
+                0.5, // cruise time
+                0.5, // decel time
+                0.0, // start_pos x
+                0.0, // start_pos y
+                0.0, // start_pos z
+                10.0, 10.0, 10.0, // axes_r (x, y, z)
+                0.0,  // start_v
+                0.0,  // cruise_v
+                20.0, // accel
+            )
+            .unwrap();
 
         let sink = RecordingSink {
             commands: Vec::new(),
@@ -498,6 +664,199 @@ mod tests {
         assert!(!commands.is_empty(), "Expected some step commands");
     }
 
+    #[test]
+    fn steps_axes_past_z() {
+        // An extruder moved as a fourth axis
+        struct AxisCallback(usize);
+
+        impl CalcPositionCallback<4> for AxisCallback {
+            fn calc_position(&mut self, m: &Move<4>, move_time: f64) -> f64 {
+                crate::kinematics::move_get_coord(m, move_time).axes[self.0]
+            }
+
+            fn linear_coefficients(&self) -> Option<Coord<4>> {
+                let mut coeffs = Coord::default();
+                coeffs.axes[self.0] = 1.0;
+                Some(coeffs)
+            }
+        }
+
+        let mut trapq = TrapQueue::<4>::default();
+        // 1mm along X, then 1mm along E
+        let moves = [
+            ([0.0; 4], [1.0, 0.0, 0.0, 0.0]),
+            ([1.0, 0.0, 0.0, 0.0], [0.0, 0.0, 0.0, 1.0]),
+        ];
+        for (i, (start, axes_r)) in moves.into_iter().enumerate() {
+            trapq
+                .append_move(
+                    i as f64,
+                    [0.5, 0.0, 0.5],
+                    start.into(),
+                    axes_r.into(),
+                    0.0,
+                    2.0,
+                    4.0,
+                )
+                .unwrap();
+        }
+
+        let mut solver = IterativeSolver::new(
+            0.1,
+            ActiveFlags::new().with_axis(3),
+            0.0,
+            0.0,
+            AxisCallback(3),
+            (),
+        );
+        solver.set_coord(Coord::default());
+        assert_eq!(solver.check_active_time(&trapq, 2.0), Some(1.0));
+        let mut sc = StepCompressor::new(
+            0,
+            10,
+            RecordingSink {
+                commands: Vec::new(),
+            },
+        );
+        sc.set_time(0.0, 1_000_000.0);
+        solver.generate_steps(&mut sc, &trapq, 2.0).unwrap();
+        sc.flush(u64::MAX).unwrap();
+
+        let steps: Vec<_> = sc
+            .into_sink()
+            .commands
+            .into_iter()
+            .filter_map(|command| match command {
+                Command::QueueStep(step) => Some(step),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(steps.iter().map(|step| step.count as u32).sum::<u32>(), 10);
+        assert!(steps[0].first_clock > 1_000_000, "{steps:?}");
+        assert!((solver.commanded_pos() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn solves_quadratic_moves_like_secant_search() {
+        // Same position as LinearCallback, advertised as linear in X
+        struct QuadraticCallback;
+
+        impl CalcPositionCallback for QuadraticCallback {
+            fn calc_position(&mut self, m: &Move, move_time: f64) -> f64 {
+                LinearCallback.calc_position(m, move_time)
+            }
+
+            fn linear_coefficients(&self) -> Option<Coord> {
+                Some(Coord::new(1.0, 0.0, 0.0))
+            }
+        }
+
+        fn step_clocks<C: CalcPositionCallback>(callback: C) -> Vec<(bool, u64)> {
+            let mut solver =
+                IterativeSolver::new(0.0125, ActiveFlags::new().with_x(), 0.0, 0.0, callback, ());
+            let mut trapq = TrapQueue::new();
+            // Out and back along X, then a diagonal move
+            trapq
+                .append(
+                    0.0, 0.2, 0.3, 0.2, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 50.0, 250.0,
+                )
+                .unwrap();
+            trapq
+                .append(
+                    0.7, 0.1, 0.0, 0.1, 25.0, 0.0, 0.0, -1.0, 0.0, 0.0, 0.0, 30.0, 300.0,
+                )
+                .unwrap();
+            trapq
+                .append(
+                    0.9, 0.15, 0.1, 0.15, 22.0, 0.0, 0.0, 0.6, 0.8, 0.0, 0.0, 30.0, 200.0,
+                )
+                .unwrap();
+
+            let mut sc = StepCompressor::new(0, 25, RecordingSink { commands: vec![] });
+            sc.set_time(0.0, 1_000_000.0);
+            sc.set_strategy(Box::new(crate::step_compressor::ExactSteps));
+            for flush_time in [0.25, 0.5, 0.75, 1.0, 1.5] {
+                solver.generate_steps(&mut sc, &trapq, flush_time).unwrap();
+            }
+            sc.commit().unwrap();
+            sc.flush(u64::MAX).unwrap();
+
+            let mut dir = false;
+            let mut clocks = Vec::new();
+            for command in sc.into_sink().commands {
+                match command {
+                    Command::SetNextStepDir(set) => dir = set.dir,
+                    Command::QueueStep(step) => {
+                        let mut clock = step.first_clock;
+                        let mut interval = step.interval as i64;
+                        clocks.push((dir, clock));
+                        for _ in 1..step.count {
+                            interval += step.add as i64;
+                            clock = clock.wrapping_add_signed(interval);
+                            clocks.push((dir, clock));
+                        }
+                    }
+                }
+            }
+            clocks
+        }
+
+        let secant = step_clocks(LinearCallback);
+        let quadratic = step_clocks(QuadraticCallback);
+        assert!(secant.len() > 2500, "{} steps", secant.len());
+        assert_eq!(secant.len(), quadratic.len());
+        for (a, b) in secant.iter().zip(&quadratic) {
+            assert_eq!(a.0, b.0);
+            assert!(a.1.abs_diff(b.1) <= 1, "secant {a:?} vs quadratic {b:?}");
+        }
+    }
+
+    #[test]
+    fn quadratic_moves_take_no_secant_iterations() {
+        struct QuadraticCallback;
+
+        impl CalcPositionCallback for QuadraticCallback {
+            fn calc_position(&mut self, m: &Move, move_time: f64) -> f64 {
+                LinearCallback.calc_position(m, move_time)
+            }
+
+            fn linear_coefficients(&self) -> Option<Coord> {
+                Some(Coord::new(1.0, 0.0, 0.0))
+            }
+        }
+
+        let mut trapq = TrapQueue::new();
+        // Out and back along X
+        trapq
+            .append(
+                0.0, 0.2, 0.3, 0.2, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 50.0, 250.0,
+            )
+            .unwrap();
+        trapq
+            .append(
+                0.7, 0.1, 0.0, 0.1, 25.0, 0.0, 0.0, -1.0, 0.0, 0.0, 0.0, 30.0, 300.0,
+            )
+            .unwrap();
+        let mut sc = StepCompressor::new(0, 25, RecordingSink { commands: vec![] });
+        sc.set_time(0.0, 1_000_000.0);
+
+        let mut solver = IterativeSolver::new(
+            0.0125,
+            ActiveFlags::new().with_x(),
+            0.0,
+            0.0,
+            QuadraticCallback,
+            (),
+        );
+        solver.enable_stats();
+        for flush_time in [0.25, 0.5, 0.75, 1.0] {
+            solver.generate_steps(&mut sc, &trapq, flush_time).unwrap();
+        }
+        let stats = *solver.stats().unwrap();
+        assert!(stats.total.steps > 2000, "{stats:?}");
+        assert_eq!(stats.total.iterations, 0);
+    }
+
     #[test]
     fn detects_direction_changes() {
         struct OscillatingCallback;
@@ -514,9 +873,11 @@ mod tests {
             IterativeSolver::new(0.1, ActiveFlags::new().with_x(), 0.0, 0.0, callback, ());
 
         let mut trapq = TrapQueue::new();
-        trapq.append(
-            0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0,
-        );
+        trapq
+            .append(
+                0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0,
+            )
+            .unwrap();
 
         let sink = RecordingSink {
             commands: Vec::new(),
@@ -546,6 +907,62 @@ mod tests {
         );
     }
 
+    #[test]
+    fn collects_flush_stats() {
+        // Jitters across a step boundary faster than the step+dir+step filter
+        struct JitterCallback;
+
+        impl CalcPositionCallback for JitterCallback {
+            fn calc_position(&mut self, _m: &Move, move_time: f64) -> f64 {
+                0.05 + 0.001 * (move_time * 2000.0 * std::f64::consts::PI).sin()
+            }
+        }
+
+        let mut trapq = TrapQueue::new();
+        trapq
+            .append(
+                0.0, 0.5, 0.5, 0.5, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 10.0, 20.0,
+            )
+            .unwrap();
+        let mut sc = StepCompressor::new(0, 1000, RecordingSink { commands: vec![] });
+        sc.set_time(0.0, 1_000_000.0);
+
+        let mut solver = IterativeSolver::new(
+            0.1,
+            ActiveFlags::new().with_x(),
+            0.0,
+            0.0,
+            LinearCallback,
+            (),
+        );
+        solver.generate_steps(&mut sc, &trapq, 0.1).unwrap();
+        assert!(solver.stats().is_none());
+        solver.enable_stats();
+        solver.generate_steps(&mut sc, &trapq, 0.75).unwrap();
+        solver.generate_steps(&mut sc, &trapq, 1.5).unwrap();
+        let stats = *solver.stats().unwrap();
+        assert_eq!(stats.flushes, 2);
+        // 10mm in 0.1mm steps, less the first 0.1mm before stats began
+        assert_eq!(stats.total.steps, 99);
+        assert!(stats.last_flush.steps > 0 && stats.last_flush.steps < 99);
+        assert!(stats.total.iterations > stats.total.steps);
+        assert_eq!(stats.total.rollbacks, 0);
+
+        let mut solver = IterativeSolver::new(
+            0.1,
+            ActiveFlags::new().with_x(),
+            0.0,
+            0.0,
+            JitterCallback,
+            (),
+        );
+        solver.enable_stats();
+        solver.generate_steps(&mut sc, &trapq, 1.5).unwrap();
+        let stats = solver.stats().unwrap();
+        assert!(stats.total.rollbacks > 0);
+        assert_eq!(stats.total.rollbacks, sc.rollbacks());
+    }
+
     #[test]
     fn respects_axis_filtering() {
         let callback = LinearCallback;
@@ -560,9 +977,11 @@ mod tests {
 
         let mut trapq = TrapQueue::new();
         // Add move with only X motion
-        trapq.append(
-            0.0, 0.5, 0.5, 0.5, 0.0, 0.0, 0.0, 10.0, 0.0, 0.0, 0.0, 0.0, 20.0,
-        );
+        trapq
+            .append(
+                0.0, 0.5, 0.5, 0.5, 0.0, 0.0, 0.0, 10.0, 0.0, 0.0, 0.0, 0.0, 20.0,
+            )
+            .unwrap();
 
         let sink = RecordingSink {
             commands: Vec::new(),
@@ -587,7 +1006,7 @@ mod tests {
         impl CalcPositionCallback for CoordCallback {
             fn calc_position(&mut self, m: &Move, _move_time: f64) -> f64 {
                 // Return X + 2*Y + 3*Z as the position
-                m.start_pos.x + 2.0 * m.start_pos.y + 3.0 * m.start_pos.z
+                m.start_pos.x() + 2.0 * m.start_pos.y() + 3.0 * m.start_pos.z()
             }
         }
 
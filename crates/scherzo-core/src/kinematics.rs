@@ -1,6 +1,9 @@
 // Kinematics systems for various printer types
 
-use crate::trap_queue::{Coord, Move};
+use crate::{
+    itersolve::{ActiveFlags, CalcPositionCallback},
+    trap_queue::{Coord, Move},
+};
 
 // Submodules for each kinematics system
 pub mod cartesian;
@@ -14,19 +17,105 @@ pub mod idex;
 pub mod polar;
 pub mod rotary_delta;
 pub mod shaper;
+pub mod verify;
 pub mod winch;
 
 /// Calculate the distance traveled in a move at a given time
-pub fn move_get_distance(m: &Move, move_time: f64) -> f64 {
+pub fn move_get_distance<const N: usize>(m: &Move<N>, move_time: f64) -> f64 {
     (m.start_v + m.half_accel * move_time) * move_time
 }
 
 /// Calculate the coordinate at a given time in a move
-pub fn move_get_coord(m: &Move, move_time: f64) -> Coord {
-    let move_dist = move_get_distance(m, move_time);
-    Coord {
-        x: m.start_pos.x + m.axes_r.x * move_dist,
-        y: m.start_pos.y + m.axes_r.y * move_dist,
-        z: m.start_pos.z + m.axes_r.z * move_dist,
+pub fn move_get_coord<const N: usize>(m: &Move<N>, move_time: f64) -> Coord<N> {
+    m.start_pos
+        .offset(&m.axes_r, move_get_distance(m, move_time))
+}
+
+/// Kinematic layouts that can be assembled from a printer configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kinematics {
+    Cartesian,
+    CoreXY,
+    CoreXZ,
+}
+
+impl Kinematics {
+    /// Parse a kinematics name as used in printer configs.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "cartesian" => Some(Self::Cartesian),
+            "corexy" => Some(Self::CoreXY),
+            "corexz" => Some(Self::CoreXZ),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Cartesian => "cartesian",
+            Self::CoreXY => "corexy",
+            Self::CoreXZ => "corexz",
+        }
+    }
+
+    /// Position callback for the stepper configured on the rail of `axis`
+    /// (0 = X, 1 = Y, 2 = Z). On core kinematics the X and Y (or Z) rails
+    /// drive the A and B belts.
+    pub fn stepper(&self, axis: usize) -> StepperKinematics {
+        use cartesian::{Axis, CartesianKin};
+        let cart = |axis| StepperKinematics::Cartesian(CartesianKin::new(axis));
+        match (self, axis) {
+            (Self::CoreXY, 0) => {
+                StepperKinematics::CoreXY(corexy::CoreXYKin::new(corexy::StepperType::Plus))
+            }
+            (Self::CoreXY, 1) => {
+                StepperKinematics::CoreXY(corexy::CoreXYKin::new(corexy::StepperType::Minus))
+            }
+            (Self::CoreXZ, 0) => {
+                StepperKinematics::CoreXZ(corexz::CoreXZKin::new(corexz::StepperType::Plus))
+            }
+            (Self::CoreXZ, 2) => {
+                StepperKinematics::CoreXZ(corexz::CoreXZKin::new(corexz::StepperType::Minus))
+            }
+            (_, 0) => cart(Axis::X),
+            (_, 1) => cart(Axis::Y),
+            (_, 2) => cart(Axis::Z),
+            _ => panic!("axis index {axis} out of range"),
+        }
+    }
+}
+
+/// Position callback for any stepper of the supported [`Kinematics`].
+pub enum StepperKinematics {
+    Cartesian(cartesian::CartesianKin),
+    CoreXY(corexy::CoreXYKin),
+    CoreXZ(corexz::CoreXZKin),
+}
+
+impl StepperKinematics {
+    pub fn active_flags(&self) -> ActiveFlags {
+        match self {
+            Self::Cartesian(kin) => kin.active_flags(),
+            Self::CoreXY(kin) => kin.active_flags(),
+            Self::CoreXZ(kin) => kin.active_flags(),
+        }
+    }
+}
+
+impl CalcPositionCallback for StepperKinematics {
+    fn calc_position(&mut self, m: &Move, move_time: f64) -> f64 {
+        match self {
+            Self::Cartesian(kin) => kin.calc_position(m, move_time),
+            Self::CoreXY(kin) => kin.calc_position(m, move_time),
+            Self::CoreXZ(kin) => kin.calc_position(m, move_time),
+        }
+    }
+
+    fn linear_coefficients(&self) -> Option<Coord> {
+        match self {
+            Self::Cartesian(kin) => kin.linear_coefficients(),
+            Self::CoreXY(kin) => kin.linear_coefficients(),
+            Self::CoreXZ(kin) => kin.linear_coefficients(),
+        }
     }
 }
@@ -3,7 +3,7 @@
 use crate::{
     itersolve::{ActiveFlags, CalcPositionCallback},
     kinematics::move_get_coord,
-    trap_queue::Move,
+    trap_queue::{Coord, Move},
 };
 
 /// Which axis this stepper controls
@@ -49,17 +49,25 @@ impl CalcPositionCallback for CartesianKin {
     fn calc_position(&mut self, m: &Move, move_time: f64) -> f64 {
         let c = move_get_coord(m, move_time);
         match self.axis {
-            Axis::X => c.x,
-            Axis::Y => c.y,
-            Axis::Z => c.z,
+            Axis::X => c.x(),
+            Axis::Y => c.y(),
+            Axis::Z => c.z(),
         }
     }
+
+    fn linear_coefficients(&self) -> Option<Coord> {
+        let (x, y, z) = match self.axis {
+            Axis::X => (1.0, 0.0, 0.0),
+            Axis::Y => (0.0, 1.0, 0.0),
+            Axis::Z => (0.0, 0.0, 1.0),
+        };
+        Some(Coord::new(x, y, z))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::trap_queue::Coord;
 
     #[test]
     fn axis_parse() {
@@ -78,16 +86,9 @@ mod tests {
             move_t: 1.0,
             start_v: 0.0,
             half_accel: 0.0,
-            start_pos: Coord {
-                x: 10.0,
-                y: 20.0,
-                z: 30.0,
-            },
-            axes_r: Coord {
-                x: 1.0,
-                y: 0.0,
-                z: 0.0,
-            },
+            start_pos: Coord::new(10.0, 20.0, 30.0),
+            axes_r: Coord::new(1.0, 0.0, 0.0),
+            tag: None,
         };
         let pos = kin.calc_position(&m, 0.5);
         assert_eq!(pos, 10.0);
@@ -3,7 +3,7 @@
 use crate::{
     itersolve::{ActiveFlags, CalcPositionCallback},
     kinematics::move_get_coord,
-    trap_queue::Move,
+    trap_queue::{Coord, Move},
 };
 
 /// CoreXZ stepper type
@@ -45,16 +45,23 @@ impl CalcPositionCallback for CoreXZKin {
     fn calc_position(&mut self, m: &Move, move_time: f64) -> f64 {
         let c = move_get_coord(m, move_time);
         match self.stepper_type {
-            StepperType::Plus => c.x + c.z,
-            StepperType::Minus => c.x - c.z,
+            StepperType::Plus => c.x() + c.z(),
+            StepperType::Minus => c.x() - c.z(),
         }
     }
+
+    fn linear_coefficients(&self) -> Option<Coord> {
+        let sign = match self.stepper_type {
+            StepperType::Plus => 1.0,
+            StepperType::Minus => -1.0,
+        };
+        Some(Coord::new(1.0, 0.0, sign))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::trap_queue::Coord;
 
     #[test]
     fn stepper_type_parse() {
@@ -73,16 +80,9 @@ mod tests {
             move_t: 1.0,
             start_v: 0.0,
             half_accel: 0.0,
-            start_pos: Coord {
-                x: 10.0,
-                y: 20.0,
-                z: 30.0,
-            },
-            axes_r: Coord {
-                x: 0.0,
-                y: 0.0,
-                z: 0.0,
-            },
+            start_pos: Coord::new(10.0, 20.0, 30.0),
+            axes_r: Coord::new(0.0, 0.0, 0.0),
+            tag: None,
         };
         let pos = kin.calc_position(&m, 0.5);
         assert_eq!(pos, 40.0); // 10 + 30
@@ -96,16 +96,9 @@ mod tests {
             move_t: 1.0,
             start_v: 0.0,
             half_accel: 0.0,
-            start_pos: Coord {
-                x: 10.0,
-                y: 20.0,
-                z: 30.0,
-            },
-            axes_r: Coord {
-                x: 0.0,
-                y: 0.0,
-                z: 0.0,
-            },
+            start_pos: Coord::new(10.0, 20.0, 30.0),
+            axes_r: Coord::new(0.0, 0.0, 0.0),
+            tag: None,
         };
         let pos = kin.calc_position(&m, 0.5);
         assert_eq!(pos, -20.0); // 10 - 30
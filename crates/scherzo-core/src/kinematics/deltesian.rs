@@ -25,8 +25,8 @@ impl DeltesianKin {
 impl CalcPositionCallback for DeltesianKin {
     fn calc_position(&mut self, m: &Move, move_time: f64) -> f64 {
         let c = move_get_coord(m, move_time);
-        let dx = self.arm_x - c.x;
-        (self.arm2 - dx * dx).sqrt() + c.z
+        let dx = self.arm_x - c.x();
+        (self.arm2 - dx * dx).sqrt() + c.z()
     }
 }
 
@@ -43,16 +43,9 @@ mod tests {
             move_t: 1.0,
             start_v: 0.0,
             half_accel: 0.0,
-            start_pos: Coord {
-                x: 0.0,
-                y: 0.0,
-                z: 5.0,
-            },
-            axes_r: Coord {
-                x: 0.0,
-                y: 0.0,
-                z: 0.0,
-            },
+            start_pos: Coord::new(0.0, 0.0, 5.0),
+            axes_r: Coord::new(0.0, 0.0, 0.0),
+            tag: None,
         };
         let pos = kin.calc_position(&m, 0.5);
         assert_eq!(pos, 15.0); // sqrt(100) + 5
@@ -3,7 +3,7 @@
 use crate::{
     itersolve::{ActiveFlags, CalcPositionCallback},
     kinematics::move_get_coord,
-    trap_queue::Move,
+    trap_queue::{Coord, Move},
 };
 
 /// Generic cartesian kinematics with arbitrary coefficients
@@ -36,14 +36,17 @@ impl GenericCartesianKin {
 impl CalcPositionCallback for GenericCartesianKin {
     fn calc_position(&mut self, m: &Move, move_time: f64) -> f64 {
         let c = move_get_coord(m, move_time);
-        self.a_x * c.x + self.a_y * c.y + self.a_z * c.z
+        self.a_x * c.x() + self.a_y * c.y() + self.a_z * c.z()
+    }
+
+    fn linear_coefficients(&self) -> Option<Coord> {
+        Some(Coord::new(self.a_x, self.a_y, self.a_z))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::trap_queue::Coord;
 
     #[test]
     fn generic_calculates_weighted_sum() {
@@ -53,16 +56,9 @@ mod tests {
             move_t: 1.0,
             start_v: 0.0,
             half_accel: 0.0,
-            start_pos: Coord {
-                x: 10.0,
-                y: 20.0,
-                z: 30.0,
-            },
-            axes_r: Coord {
-                x: 0.0,
-                y: 0.0,
-                z: 0.0,
-            },
+            start_pos: Coord::new(10.0, 20.0, 30.0),
+            axes_r: Coord::new(0.0, 0.0, 0.0),
+            tag: None,
         };
         let pos = kin.calc_position(&m, 0.5);
         assert_eq!(pos, 140.0); // 1*10 + 2*20 + 3*30
@@ -50,8 +50,8 @@ impl CalcPositionCallback for PolarKin {
     fn calc_position(&mut self, m: &Move, move_time: f64) -> f64 {
         let c = move_get_coord(m, move_time);
         match self.axis {
-            PolarAxis::Radius => (c.x * c.x + c.y * c.y).sqrt(),
-            PolarAxis::Angle => c.y.atan2(c.x),
+            PolarAxis::Radius => (c.x() * c.x() + c.y() * c.y()).sqrt(),
+            PolarAxis::Angle => c.y().atan2(c.x()),
         }
     }
 }
@@ -87,16 +87,9 @@ mod tests {
             move_t: 1.0,
             start_v: 0.0,
             half_accel: 0.0,
-            start_pos: Coord {
-                x: 3.0,
-                y: 4.0,
-                z: 0.0,
-            },
-            axes_r: Coord {
-                x: 0.0,
-                y: 0.0,
-                z: 0.0,
-            },
+            start_pos: Coord::new(3.0, 4.0, 0.0),
+            axes_r: Coord::new(0.0, 0.0, 0.0),
+            tag: None,
         };
         let pos = kin.calc_position(&m, 0.5);
         assert_eq!(pos, 5.0); // sqrt(3^2 + 4^2)
@@ -110,16 +103,9 @@ mod tests {
             move_t: 1.0,
             start_v: 0.0,
             half_accel: 0.0,
-            start_pos: Coord {
-                x: 1.0,
-                y: 0.0,
-                z: 0.0,
-            },
-            axes_r: Coord {
-                x: 0.0,
-                y: 0.0,
-                z: 0.0,
-            },
+            start_pos: Coord::new(1.0, 0.0, 0.0),
+            axes_r: Coord::new(0.0, 0.0, 0.0),
+            tag: None,
         };
         let pos = kin.calc_position(&m, 0.5);
         assert_eq!(pos, 0.0); // atan2(0, 1) = 0
@@ -45,9 +45,9 @@ impl CalcPositionCallback for RotaryDeltaKin {
         let shoulder_y = self.shoulder_radius * self.angle.sin();
 
         // Vector from shoulder to effector
-        let dx = c.x - shoulder_x;
-        let dy = c.y - shoulder_y;
-        let dz = c.z - self.shoulder_height;
+        let dx = c.x() - shoulder_x;
+        let dy = c.y() - shoulder_y;
+        let dz = c.z() - self.shoulder_height;
 
         // Distance from shoulder to effector
         let dist = (dx * dx + dy * dy + dz * dz).sqrt();
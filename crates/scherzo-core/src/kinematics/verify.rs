@@ -0,0 +1,527 @@
+//! Numerical self-check of kinematics implementations.
+//!
+//! [`verify`] generates the steps of every stepper of a machine for the
+//! moves on a trapq, integrates them back into stepper positions, and
+//! solves those positions for the toolhead. At each sampled time every
+//! stepper must be within a step of its exact position, and the toolhead
+//! within what a step on each stepper can account for. This catches a
+//! position callback that disagrees with its linear coefficients, a solver
+//! that misses steps, and kinematics that cannot be inverted along a move.
+
+use crate::{
+    error::MotionError,
+    itersolve::{ActiveFlags, CalcPositionCallback, IterativeSolver},
+    kinematics::move_get_coord,
+    step_compressor::{Command, ExactSteps, RecordingSink, StepCompressor},
+    trap_queue::{Coord, Move, TrapQueue},
+};
+use thiserror::Error;
+
+/// Gauss-Newton iterations allowed to locate the toolhead.
+const MAX_ITERATIONS: usize = 50;
+/// Toolhead position change (mm) below which the solve has converged.
+const CONVERGED: f64 = 1e-10;
+/// Step (mm) of the finite differences of the stepper positions.
+const DIFF_STEP: f64 = 1e-5;
+
+/// How finely motion is stepped and sampled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VerifyConfig {
+    /// Distance (mm) each stepper moves per step.
+    pub step_dist: f64,
+    /// Frequency (Hz) of the clock steps are scheduled in.
+    pub mcu_freq: f64,
+    /// Times each move is sampled at.
+    pub samples_per_move: usize,
+}
+
+impl Default for VerifyConfig {
+    fn default() -> Self {
+        Self {
+            step_dist: 0.0125,
+            mcu_freq: 16_000_000.0,
+            samples_per_move: 32,
+        }
+    }
+}
+
+/// Largest errors found by [`verify`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VerifyReport {
+    /// Steps generated across all steppers.
+    pub steps: u64,
+    /// Times the positions were compared at.
+    pub samples: u64,
+    /// Largest distance (mm) between a stepper's integrated steps and its
+    /// exact position.
+    pub max_stepper_error: f64,
+    /// Largest distance (mm) along any axis between the toolhead position
+    /// solved from the steps and the commanded one.
+    pub max_position_error: f64,
+}
+
+#[derive(Debug, Error)]
+pub enum VerifyError {
+    #[error(transparent)]
+    Motion(#[from] MotionError),
+    #[error("at least three steppers are needed to locate the toolhead, got {0}")]
+    TooFewSteppers(usize),
+    /// The steps of a stepper drifted more than a step from its position.
+    #[error("stepper {stepper} is {error:.6} mm off at print time {print_time:.6}")]
+    StepperDrift {
+        stepper: usize,
+        print_time: f64,
+        error: f64,
+    },
+    /// No toolhead position matches the stepper positions.
+    #[error("toolhead position at print time {print_time:.6} cannot be solved")]
+    Singular { print_time: f64 },
+    /// The toolhead solved from the steps is further off than the steps
+    /// can account for.
+    #[error(
+        "toolhead at {actual:?} instead of {expected:?} at print time {print_time:.6} \
+         (tolerance {tolerance:?})"
+    )]
+    PositionDrift {
+        print_time: f64,
+        expected: Coord,
+        actual: Coord,
+        tolerance: Coord,
+    },
+}
+
+/// Check that the steps generated for `steppers` on the moves of `trapq`
+/// reproduce the moves, starting from the first move's start position.
+///
+/// Steps are kept exactly as solved, so the check covers the kinematics and
+/// the iterative solver but not step compression.
+pub fn verify(
+    steppers: &mut [&mut dyn CalcPositionCallback],
+    trapq: &TrapQueue,
+    config: &VerifyConfig,
+) -> Result<VerifyReport, VerifyError> {
+    if steppers.len() < 3 {
+        return Err(VerifyError::TooFewSteppers(steppers.len()));
+    }
+    let mut report = VerifyReport::default();
+    let moves = trapq.get_active_moves();
+    let Some(first) = moves.first() else {
+        return Ok(report);
+    };
+    let end_time = moves
+        .iter()
+        .map(|m| m.print_time + m.move_t)
+        .fold(first.print_time, f64::max);
+
+    let mut traces = Vec::with_capacity(steppers.len());
+    for (oid, kin) in steppers.iter_mut().enumerate() {
+        let trace = StepTrace::generate(
+            &mut **kin,
+            oid as u32,
+            first.start_pos,
+            trapq,
+            end_time,
+            config,
+        )?;
+        report.steps += trace.times.len() as u64;
+        traces.push(trace);
+    }
+
+    let mut guess = [
+        first.start_pos.x(),
+        first.start_pos.y(),
+        first.start_pos.z(),
+    ];
+    let mut positions = vec![0.0; steppers.len()];
+    for m in moves {
+        for sample in 0..config.samples_per_move {
+            let move_time = m.move_t * (sample as f64 + 0.5) / config.samples_per_move as f64;
+            let print_time = m.print_time + move_time;
+            for (stepper, (kin, trace)) in steppers.iter_mut().zip(&traces).enumerate() {
+                let position = trace.position_at(print_time, config.step_dist);
+                let error = (position - kin.calc_position(m, move_time)).abs();
+                if error > config.step_dist {
+                    return Err(VerifyError::StepperDrift {
+                        stepper,
+                        print_time,
+                        error,
+                    });
+                }
+                report.max_stepper_error = report.max_stepper_error.max(error);
+                positions[stepper] = position;
+            }
+
+            let (actual, sensitivity) =
+                locate(steppers, &positions, guess).ok_or(VerifyError::Singular { print_time })?;
+            let expected = move_get_coord(m, move_time);
+            let tolerance = sensitivity.map(|s| s * config.step_dist + 1e-9);
+            let errors = [
+                (actual[0] - expected.x()).abs(),
+                (actual[1] - expected.y()).abs(),
+                (actual[2] - expected.z()).abs(),
+            ];
+            if errors
+                .iter()
+                .zip(&tolerance)
+                .any(|(error, tol)| error > tol)
+            {
+                return Err(VerifyError::PositionDrift {
+                    print_time,
+                    expected,
+                    actual: coord(actual),
+                    tolerance: coord(tolerance),
+                });
+            }
+            report.max_position_error =
+                errors.into_iter().fold(report.max_position_error, f64::max);
+            report.samples += 1;
+            guess = actual;
+        }
+    }
+    Ok(report)
+}
+
+/// Step times of one stepper and its position after each step.
+struct StepTrace {
+    start_position: f64,
+    times: Vec<f64>,
+    /// Net steps taken up to and including each step.
+    net_steps: Vec<i64>,
+}
+
+impl StepTrace {
+    fn generate(
+        kin: &mut dyn CalcPositionCallback,
+        oid: u32,
+        start: Coord,
+        trapq: &TrapQueue,
+        end_time: f64,
+        config: &VerifyConfig,
+    ) -> Result<Self, MotionError> {
+        let flags = ActiveFlags::new().with_x().with_y().with_z();
+        let mut solver = IterativeSolver::new(config.step_dist, flags, 0.0, 0.0, kin, ());
+        solver.set_position(start.x(), start.y(), start.z());
+        let start_position = solver.commanded_pos();
+
+        let mut sc = StepCompressor::new(oid, 0, RecordingSink::default());
+        sc.set_time(0.0, config.mcu_freq);
+        sc.set_strategy(Box::new(ExactSteps));
+        solver.generate_steps(&mut sc, trapq, end_time)?;
+        sc.commit()
+            .and_then(|()| sc.flush(u64::MAX))
+            .map_err(|source| MotionError::StepCompress {
+                oid,
+                print_time: end_time,
+                source,
+            })?;
+
+        let mut trace = Self {
+            start_position,
+            times: Vec::new(),
+            net_steps: Vec::new(),
+        };
+        let mut dir = 1;
+        let mut net = 0;
+        for command in sc.into_sink().commands {
+            match command {
+                Command::SetNextStepDir(set) => dir = if set.dir { 1 } else { -1 },
+                Command::QueueStep(step) => {
+                    let mut clock = step.first_clock;
+                    let mut interval = i64::from(step.interval);
+                    for index in 0..step.count {
+                        if index > 0 {
+                            interval += i64::from(step.add);
+                            clock = clock.wrapping_add_signed(interval);
+                        }
+                        net += dir;
+                        trace.times.push(clock as f64 / config.mcu_freq);
+                        trace.net_steps.push(net);
+                    }
+                }
+            }
+        }
+        Ok(trace)
+    }
+
+    /// Stepper position (mm) once the steps scheduled up to `print_time`
+    /// have run.
+    fn position_at(&self, print_time: f64, step_dist: f64) -> f64 {
+        let taken = self.times.partition_point(|&time| time <= print_time);
+        let net = taken.checked_sub(1).map_or(0, |last| self.net_steps[last]);
+        self.start_position + net as f64 * step_dist
+    }
+}
+
+/// Solve for the toolhead position that puts the steppers at `positions`,
+/// by least squares from `guess`.
+///
+/// Also returns, per axis, how far the solution moves when each stepper is
+/// a step (of unit length) off.
+fn locate(
+    steppers: &mut [&mut dyn CalcPositionCallback],
+    positions: &[f64],
+    guess: [f64; 3],
+) -> Option<([f64; 3], [f64; 3])> {
+    let mut pos = guess;
+    for _ in 0..MAX_ITERATIONS {
+        let mut jtj = [[0.0; 3]; 3];
+        let mut jtr = [0.0; 3];
+        let mut rows = Vec::with_capacity(steppers.len());
+        for (kin, &target) in steppers.iter_mut().zip(positions) {
+            let row = gradient(&mut **kin, pos);
+            let residual = stationary_position(&mut **kin, pos) - target;
+            for i in 0..3 {
+                jtr[i] += row[i] * residual;
+                for j in 0..3 {
+                    jtj[i][j] += row[i] * row[j];
+                }
+            }
+            rows.push(row);
+        }
+        let inv = invert(jtj)?;
+        let delta: [f64; 3] = std::array::from_fn(|i| (0..3).map(|j| inv[i][j] * jtr[j]).sum());
+        for i in 0..3 {
+            pos[i] -= delta[i];
+        }
+        if !pos.iter().all(|p| p.is_finite()) {
+            return None;
+        }
+        if delta.iter().all(|d| d.abs() < CONVERGED) {
+            // Rows of the pseudo-inverse (J^T J)^-1 J^T, summed in magnitude
+            let sensitivity = std::array::from_fn(|i| {
+                rows.iter()
+                    .map(|row| (0..3).map(|j| inv[i][j] * row[j]).sum::<f64>().abs())
+                    .sum()
+            });
+            return Some((pos, sensitivity));
+        }
+    }
+    None
+}
+
+// Position of a stepper with the toolhead held at `pos`
+fn stationary_position(kin: &mut dyn CalcPositionCallback, pos: [f64; 3]) -> f64 {
+    let m = Move {
+        print_time: 0.0,
+        move_t: 1.0,
+        start_v: 0.0,
+        half_accel: 0.0,
+        start_pos: coord(pos),
+        axes_r: Coord::default(),
+        tag: None,
+    };
+    kin.calc_position(&m, 0.0)
+}
+
+// Central difference gradient of a stepper position in X, Y and Z
+fn gradient(kin: &mut dyn CalcPositionCallback, pos: [f64; 3]) -> [f64; 3] {
+    std::array::from_fn(|axis| {
+        let mut high = pos;
+        let mut low = pos;
+        high[axis] += DIFF_STEP;
+        low[axis] -= DIFF_STEP;
+        (stationary_position(kin, high) - stationary_position(kin, low)) / (2.0 * DIFF_STEP)
+    })
+}
+
+fn invert(m: [[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+    let cofactor = |r: usize, c: usize| {
+        let (r0, r1) = ((r + 1) % 3, (r + 2) % 3);
+        let (c0, c1) = ((c + 1) % 3, (c + 2) % 3);
+        m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+    };
+    let det: f64 = (0..3).map(|c| m[0][c] * cofactor(0, c)).sum();
+    if !det.is_normal() {
+        return None;
+    }
+    // The inverse is the transposed cofactor matrix over the determinant
+    Some(std::array::from_fn(|r| {
+        std::array::from_fn(|c| cofactor(c, r) / det)
+    }))
+}
+
+fn coord([x, y, z]: [f64; 3]) -> Coord {
+    Coord::new(x, y, z)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kinematics::{
+        cartesian::{Axis, CartesianKin},
+        corexy::{self, CoreXYKin},
+        corexz::{self, CoreXZKin},
+        delta::DeltaKin,
+        generic::GenericCartesianKin,
+        winch::WinchKin,
+    };
+    use std::panic::AssertUnwindSafe;
+
+    /// Random out-and-back moves within 50 mm of the origin
+    #[derive(Debug, bolero::TypeGenerator)]
+    struct Moves {
+        start: [i8; 3],
+        direction: [i8; 3],
+        cruise_v: u8,
+        accel: u8,
+        cruise_t: u8,
+        back: u8,
+    }
+
+    impl Moves {
+        fn trapq(&self) -> Option<TrapQueue> {
+            let norm = self
+                .direction
+                .iter()
+                .map(|&d| f64::from(d) * f64::from(d))
+                .sum::<f64>()
+                .sqrt();
+            if norm == 0.0 {
+                return None;
+            }
+            let axes_r = self.direction.map(|d| f64::from(d) / norm);
+            let mut pos = self.start.map(|p| f64::from(p) * 50.0 / 128.0);
+            let cruise_v = 5.0 + f64::from(self.cruise_v) * 0.5;
+            let accel = 500.0 + f64::from(self.accel) * 10.0;
+            let accel_t = cruise_v / accel;
+            let cruise_t = f64::from(self.cruise_t) * 0.000_5;
+            let back_t = f64::from(self.back) * 0.000_2;
+
+            let mut trapq = TrapQueue::new();
+            let mut print_time = 0.1;
+            for (sign, cruise_t) in [(1.0, cruise_t), (-1.0, back_t)] {
+                let [x, y, z] = pos;
+                let [rx, ry, rz] = axes_r.map(|r| r * sign);
+                trapq
+                    .append(
+                        print_time, accel_t, cruise_t, accel_t, x, y, z, rx, ry, rz, 0.0, cruise_v,
+                        accel,
+                    )
+                    .unwrap();
+                let move_d = cruise_v * (accel_t + cruise_t);
+                pos = [x + rx * move_d, y + ry * move_d, z + rz * move_d];
+                print_time += 2.0 * accel_t + cruise_t;
+            }
+            Some(trapq)
+        }
+    }
+
+    fn check(steppers: &mut [&mut dyn CalcPositionCallback]) {
+        // A failing case panics out of the test, so the steppers are not
+        // reused after a panic
+        let mut steppers = AssertUnwindSafe(steppers);
+        bolero::check!()
+            .with_type::<Moves>()
+            .with_iterations(32)
+            .for_each(move |moves| {
+                let Some(trapq) = moves.trapq() else {
+                    return;
+                };
+                let report = verify(&mut steppers[..], &trapq, &VerifyConfig::default()).unwrap();
+                assert!(report.steps > 0);
+            });
+    }
+
+    #[test]
+    fn verifies_cartesian() {
+        check(&mut [
+            &mut CartesianKin::new(Axis::X),
+            &mut CartesianKin::new(Axis::Y),
+            &mut CartesianKin::new(Axis::Z),
+        ]);
+    }
+
+    #[test]
+    fn verifies_corexy() {
+        check(&mut [
+            &mut CoreXYKin::new(corexy::StepperType::Plus),
+            &mut CoreXYKin::new(corexy::StepperType::Minus),
+            &mut CartesianKin::new(Axis::Z),
+        ]);
+    }
+
+    #[test]
+    fn verifies_corexz() {
+        check(&mut [
+            &mut CoreXZKin::new(corexz::StepperType::Plus),
+            &mut CartesianKin::new(Axis::Y),
+            &mut CoreXZKin::new(corexz::StepperType::Minus),
+        ]);
+    }
+
+    #[test]
+    fn verifies_generic_cartesian() {
+        check(&mut [
+            &mut GenericCartesianKin::new(1.0, 0.5, 0.0),
+            &mut GenericCartesianKin::new(-0.5, 1.0, 0.0),
+            &mut GenericCartesianKin::new(0.0, 0.25, 2.0),
+        ]);
+    }
+
+    #[test]
+    fn verifies_delta() {
+        let arm2 = 300.0 * 300.0;
+        let tower = |angle: f64| {
+            let angle = angle.to_radians();
+            DeltaKin::new(arm2, 150.0 * angle.cos(), 150.0 * angle.sin())
+        };
+        check(&mut [&mut tower(210.0), &mut tower(330.0), &mut tower(90.0)]);
+    }
+
+    #[test]
+    fn verifies_winch() {
+        check(&mut [
+            &mut WinchKin::new(-300.0, -300.0, 400.0),
+            &mut WinchKin::new(300.0, -300.0, 400.0),
+            &mut WinchKin::new(0.0, 300.0, 400.0),
+            &mut WinchKin::new(0.0, 0.0, 600.0),
+        ]);
+    }
+
+    #[test]
+    fn reports_kinematics_that_disagree_with_their_coefficients() {
+        // Claims to follow X alone while also moving with Y
+        struct Mislabeled;
+
+        impl CalcPositionCallback for Mislabeled {
+            fn calc_position(&mut self, m: &Move, move_time: f64) -> f64 {
+                let c = move_get_coord(m, move_time);
+                c.x() + 0.5 * c.y()
+            }
+
+            fn linear_coefficients(&self) -> Option<Coord> {
+                Some(Coord::new(1.0, 0.0, 0.0))
+            }
+        }
+
+        let mut trapq = TrapQueue::new();
+        trapq
+            .append(
+                0.1, 0.1, 0.1, 0.1, 0.0, 0.0, 0.0, 0.6, 0.8, 0.0, 0.0, 50.0, 500.0,
+            )
+            .unwrap();
+        let result = verify(
+            &mut [
+                &mut Mislabeled,
+                &mut CartesianKin::new(Axis::Y),
+                &mut CartesianKin::new(Axis::Z),
+            ],
+            &trapq,
+            &VerifyConfig::default(),
+        );
+        assert!(
+            matches!(result, Err(VerifyError::StepperDrift { stepper: 0, .. })),
+            "{result:?}"
+        );
+    }
+
+    #[test]
+    fn requires_three_steppers() {
+        let result = verify(
+            &mut [&mut CartesianKin::new(Axis::X)],
+            &TrapQueue::new(),
+            &VerifyConfig::default(),
+        );
+        assert!(matches!(result, Err(VerifyError::TooFewSteppers(1))));
+    }
+}
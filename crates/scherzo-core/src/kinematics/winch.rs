@@ -30,9 +30,9 @@ impl WinchKin {
 impl CalcPositionCallback for WinchKin {
     fn calc_position(&mut self, m: &Move, move_time: f64) -> f64 {
         let c = move_get_coord(m, move_time);
-        let dx = self.anchor_x - c.x;
-        let dy = self.anchor_y - c.y;
-        let dz = self.anchor_z - c.z;
+        let dx = self.anchor_x - c.x();
+        let dy = self.anchor_y - c.y();
+        let dz = self.anchor_z - c.z();
         (dx * dx + dy * dy + dz * dz).sqrt()
     }
 }
@@ -50,16 +50,9 @@ mod tests {
             move_t: 1.0,
             start_v: 0.0,
             half_accel: 0.0,
-            start_pos: Coord {
-                x: 3.0,
-                y: 4.0,
-                z: 0.0,
-            },
-            axes_r: Coord {
-                x: 0.0,
-                y: 0.0,
-                z: 0.0,
-            },
+            start_pos: Coord::new(3.0, 4.0, 0.0),
+            axes_r: Coord::new(0.0, 0.0, 0.0),
+            tag: None,
         };
         let pos = kin.calc_position(&m, 0.5);
         // sqrt(3^2 + 4^2 + 100^2) = sqrt(10025) ≈ 100.125
@@ -3,7 +3,26 @@
 //! This crate intentionally avoids any transport- or MCU-specific
 //! dependencies.
 
+pub mod bed_mesh;
+pub mod command_log;
+pub mod compensation;
+pub mod delta_calibrate;
+pub mod error;
+pub mod exclude_object;
+pub mod flush;
 pub mod itersolve;
 pub mod kinematics;
+pub mod pause_resume;
+pub mod planner;
+pub mod rail;
+pub mod resonance;
+pub mod retraction;
 pub mod step_compressor;
+pub mod stepper_phase;
+pub mod steppersync;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+pub mod toolhead;
 pub mod trap_queue;
+
+pub use error::MotionError;
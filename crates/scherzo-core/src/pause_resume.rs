@@ -0,0 +1,196 @@
+//! Pausing and resuming a print.
+//!
+//! Pausing mid-print (on filament runout, or when asked to) retracts the
+//! filament, lifts the nozzle off the part, and parks it out of the way, like
+//! the `PAUSE` macros commonly used with Klipper. Resuming travels back above
+//! the part, lowers the nozzle, and re-primes before the print carries on.
+
+/// Tunable pause and resume parameters.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PauseConfig {
+    /// Where to park (X/Y), or `None` to stay above the part.
+    pub park_position: Option<[f64; 2]>,
+    /// Distance (mm) to lift the nozzle.
+    pub z_lift: f64,
+    /// Highest Z (mm) the lift may reach.
+    pub z_max: f64,
+    /// Filament (mm) retracted while paused.
+    pub retract_length: f64,
+    /// Retract speed (mm/s).
+    pub retract_speed: f64,
+    /// Speed (mm/s) of the moves to and from the park position.
+    pub travel_speed: f64,
+    /// Speed (mm/s) of the lift and the descent back to the part.
+    pub z_speed: f64,
+    /// Extra filament (mm) pushed when resuming, to make up for ooze.
+    pub prime_length: f64,
+    /// Speed (mm/s) of the unretract and prime.
+    pub prime_speed: f64,
+}
+
+impl Default for PauseConfig {
+    fn default() -> Self {
+        Self {
+            park_position: None,
+            z_lift: 10.0,
+            z_max: f64::INFINITY,
+            retract_length: 1.0,
+            retract_speed: 20.0,
+            travel_speed: 100.0,
+            z_speed: 10.0,
+            prime_length: 0.0,
+            prime_speed: 10.0,
+        }
+    }
+}
+
+/// A move of the pause or resume sequence.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PauseMove {
+    /// X/Y/Z/E position to move to.
+    pub target: [f64; 4],
+    /// Speed (mm/s).
+    pub speed: f64,
+}
+
+/// Remembers where a paused print stopped.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PauseResume {
+    config: PauseConfig,
+    saved: Option<[f64; 4]>,
+}
+
+impl PauseResume {
+    pub fn new(config: PauseConfig) -> Self {
+        Self {
+            config,
+            saved: None,
+        }
+    }
+
+    pub fn config(&self) -> &PauseConfig {
+        &self.config
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.saved.is_some()
+    }
+
+    /// Position the print stopped at, while paused.
+    pub fn saved_position(&self) -> Option<[f64; 4]> {
+        self.saved
+    }
+
+    /// Pause at `position`, returning the retract, lift, and park moves. Does
+    /// nothing if already paused.
+    pub fn pause(&mut self, position: [f64; 4]) -> Vec<PauseMove> {
+        if self.saved.is_some() {
+            return Vec::new();
+        }
+        self.saved = Some(position);
+
+        let config = &self.config;
+        let mut pos = position;
+        let mut moves = Vec::with_capacity(3);
+        let mut push = |pos: [f64; 4], speed| {
+            if moves.last().map_or(position, |mv: &PauseMove| mv.target) != pos {
+                moves.push(PauseMove { target: pos, speed });
+            }
+        };
+        pos[3] -= config.retract_length;
+        push(pos, config.retract_speed);
+        pos[2] = (pos[2] + config.z_lift).min(config.z_max.max(pos[2]));
+        push(pos, config.z_speed);
+        if let Some([x, y]) = config.park_position {
+            pos[0] = x;
+            pos[1] = y;
+            push(pos, config.travel_speed);
+        }
+        moves
+    }
+
+    /// Resume, returning the moves back to the paused position and the
+    /// re-prime. The last move ends `prime_length` past the paused E
+    /// position. Does nothing if not paused.
+    pub fn resume(&mut self, position: [f64; 4]) -> Vec<PauseMove> {
+        let Some(saved) = self.saved.take() else {
+            return Vec::new();
+        };
+
+        let config = &self.config;
+        let mut moves = Vec::with_capacity(3);
+        let mut push = |pos: [f64; 4], speed| {
+            if moves.last().map_or(position, |mv: &PauseMove| mv.target) != pos {
+                moves.push(PauseMove { target: pos, speed });
+            }
+        };
+        let mut pos = position;
+        pos[0] = saved[0];
+        pos[1] = saved[1];
+        push(pos, config.travel_speed);
+        pos[2] = saved[2];
+        push(pos, config.z_speed);
+        pos[3] = saved[3] + config.prime_length;
+        push(pos, config.prime_speed);
+        moves
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pause_parks_and_resume_restores() {
+        let mut pause = PauseResume::new(PauseConfig {
+            park_position: Some([0.0, 200.0]),
+            z_max: 12.0,
+            prime_length: 0.5,
+            ..PauseConfig::default()
+        });
+        let position = [50.0, 60.0, 5.0, 100.0];
+
+        let moves = pause.pause(position);
+        let targets: Vec<_> = moves.iter().map(|mv| mv.target).collect();
+        assert_eq!(
+            targets,
+            [
+                [50.0, 60.0, 5.0, 99.0],
+                [50.0, 60.0, 12.0, 99.0],
+                [0.0, 200.0, 12.0, 99.0]
+            ]
+        );
+        assert!(pause.is_paused());
+        assert!(pause.pause(targets[2]).is_empty());
+
+        let moves = pause.resume(targets[2]);
+        let targets: Vec<_> = moves.iter().map(|mv| mv.target).collect();
+        assert_eq!(
+            targets,
+            [
+                [50.0, 60.0, 12.0, 99.0],
+                [50.0, 60.0, 5.0, 99.0],
+                [50.0, 60.0, 5.0, 100.5]
+            ]
+        );
+        assert_eq!(moves[2].speed, 10.0);
+        assert!(!pause.is_paused());
+        assert!(pause.resume(targets[2]).is_empty());
+    }
+
+    #[test]
+    fn pause_without_park_stays_above_the_part() {
+        let mut pause = PauseResume::new(PauseConfig {
+            retract_length: 0.0,
+            ..PauseConfig::default()
+        });
+        let moves = pause.pause([1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(
+            moves,
+            [PauseMove {
+                target: [1.0, 2.0, 13.0, 4.0],
+                speed: 10.0
+            }]
+        );
+    }
+}
@@ -0,0 +1,606 @@
+//! Lookahead motion planner.
+//!
+//! A port of the `Move` and `LookAheadQueue` classes from Klipper's
+//! `toolhead.py`. Moves are queued with their maximum cruise speed and the
+//! queue resolves junction velocities (using the "approximated centripetal
+//! velocity" junction model) into trapezoid accel/cruise/decel phases.
+
+use serde::{Deserialize, Serialize};
+
+/// Amount of queued move time required before a lazy flush is attempted.
+pub const LOOKAHEAD_FLUSH_TIME: f64 = 0.250;
+
+/// Kinematic limits applied to every planned move.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MachineLimits {
+    /// Maximum toolhead velocity (mm/s).
+    pub max_velocity: f64,
+    /// Maximum toolhead acceleration (mm/s^2).
+    pub max_accel: f64,
+    /// Fraction of a move that should be spent cruising when smoothing
+    /// short zig-zag moves (0.0 disables smoothing).
+    pub minimum_cruise_ratio: f64,
+    /// Maximum velocity (mm/s) through a 90 degree corner.
+    pub square_corner_velocity: f64,
+    /// Maximum velocity (mm/s) of travel moves, or `max_velocity` if unset.
+    pub max_travel_velocity: Option<f64>,
+    /// Maximum acceleration (mm/s^2) of travel moves, or `max_accel` if
+    /// unset.
+    pub max_travel_accel: Option<f64>,
+    /// Maximum filament velocity (mm/s) of moves that only extrude.
+    pub max_extrude_only_velocity: f64,
+    /// Maximum filament acceleration (mm/s^2) of moves that only extrude.
+    pub max_extrude_only_accel: f64,
+}
+
+impl Default for MachineLimits {
+    fn default() -> Self {
+        Self {
+            max_velocity: 300.0,
+            max_accel: 3000.0,
+            minimum_cruise_ratio: 0.5,
+            square_corner_velocity: 5.0,
+            max_travel_velocity: None,
+            max_travel_accel: None,
+            max_extrude_only_velocity: 50.0,
+            max_extrude_only_accel: 1000.0,
+        }
+    }
+}
+
+/// Class of a move, inferred from whether it extrudes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MoveType {
+    /// Toolhead motion without extrusion.
+    Travel,
+    /// Toolhead motion while the extruder moves.
+    Print,
+    /// Extruder motion alone, e.g. a retract or purge.
+    ExtrudeOnly,
+}
+
+impl MachineLimits {
+    /// Junction deviation derived from the square corner velocity.
+    pub fn junction_deviation(&self) -> f64 {
+        let scv2 = self.square_corner_velocity * self.square_corner_velocity;
+        scv2 * (2.0_f64.sqrt() - 1.0) / self.max_accel
+    }
+
+    /// Maximum velocity (mm/s) of moves of `move_type`.
+    pub fn max_velocity_for(&self, move_type: MoveType) -> f64 {
+        match move_type {
+            MoveType::Travel => self.max_travel_velocity.unwrap_or(self.max_velocity),
+            MoveType::Print => self.max_velocity,
+            MoveType::ExtrudeOnly => self.max_extrude_only_velocity,
+        }
+    }
+
+    /// Maximum acceleration (mm/s^2) of moves of `move_type`.
+    pub fn max_accel_for(&self, move_type: MoveType) -> f64 {
+        match move_type {
+            MoveType::Travel => self.max_travel_accel.unwrap_or(self.max_accel),
+            MoveType::Print => self.max_accel,
+            MoveType::ExtrudeOnly => self.max_extrude_only_accel,
+        }
+    }
+
+    /// Acceleration used by the zig-zag smoothing pass for moves of
+    /// `move_type`.
+    pub fn max_accel_to_decel(&self, move_type: MoveType) -> f64 {
+        self.max_accel_for(move_type) * (1.0 - self.minimum_cruise_ratio.clamp(0.0, 1.0))
+    }
+}
+
+/// A toolhead move in X/Y/Z/E space along with its planned velocity profile.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PlannedMove {
+    pub start_pos: [f64; 4],
+    pub end_pos: [f64; 4],
+    pub accel: f64,
+    pub junction_deviation: f64,
+    pub move_type: MoveType,
+    pub is_kinematic_move: bool,
+    pub axes_d: [f64; 4],
+    pub axes_r: [f64; 4],
+    pub move_d: f64,
+    pub min_move_t: f64,
+    max_start_v2: f64,
+    max_cruise_v2: f64,
+    delta_v2: f64,
+    max_smoothed_v2: f64,
+    smooth_delta_v2: f64,
+    next_junction_v2: f64,
+    // Resolved by the lookahead queue
+    pub start_v: f64,
+    pub cruise_v: f64,
+    pub end_v: f64,
+    pub accel_t: f64,
+    pub cruise_t: f64,
+    pub decel_t: f64,
+}
+
+impl PlannedMove {
+    /// Create a move from `start_pos` to `end_pos` at the requested speed (mm/s).
+    pub fn new(limits: &MachineLimits, start_pos: [f64; 4], end_pos: [f64; 4], speed: f64) -> Self {
+        let mut end_pos = end_pos;
+        let mut axes_d = [0.0; 4];
+        for (i, d) in axes_d.iter_mut().enumerate() {
+            *d = end_pos[i] - start_pos[i];
+        }
+        let mut move_d =
+            (axes_d[0] * axes_d[0] + axes_d[1] * axes_d[1] + axes_d[2] * axes_d[2]).sqrt();
+        let move_type;
+        if move_d < 0.000_000_001 {
+            // Extrude only move
+            end_pos = [start_pos[0], start_pos[1], start_pos[2], end_pos[3]];
+            axes_d[0] = 0.0;
+            axes_d[1] = 0.0;
+            axes_d[2] = 0.0;
+            move_d = axes_d[3].abs();
+            move_type = MoveType::ExtrudeOnly;
+        } else if axes_d[3] != 0.0 {
+            move_type = MoveType::Print;
+        } else {
+            move_type = MoveType::Travel;
+        }
+        let inv_move_d = if move_d > 0.0 { 1.0 / move_d } else { 0.0 };
+        let axes_r = axes_d.map(|d| d * inv_move_d);
+        let is_kinematic_move = move_type != MoveType::ExtrudeOnly;
+        let accel = limits.max_accel_for(move_type);
+        let velocity = speed.min(limits.max_velocity_for(move_type));
+        // Zig-zag smoothing only applies to toolhead motion
+        let smooth_accel = if is_kinematic_move {
+            limits.max_accel_to_decel(move_type)
+        } else {
+            accel
+        };
+
+        Self {
+            start_pos,
+            end_pos,
+            accel,
+            junction_deviation: limits.junction_deviation(),
+            move_type,
+            is_kinematic_move,
+            axes_d,
+            axes_r,
+            move_d,
+            min_move_t: move_d / velocity,
+            max_start_v2: 0.0,
+            max_cruise_v2: velocity * velocity,
+            delta_v2: 2.0 * move_d * accel,
+            max_smoothed_v2: 0.0,
+            smooth_delta_v2: 2.0 * move_d * smooth_accel,
+            next_junction_v2: 999_999_999.9,
+            start_v: 0.0,
+            cruise_v: 0.0,
+            end_v: 0.0,
+            accel_t: 0.0,
+            cruise_t: 0.0,
+            decel_t: 0.0,
+        }
+    }
+
+    /// Further restrict the cruise speed and acceleration of this move.
+    pub fn limit_speed(&mut self, speed: f64, accel: f64) {
+        let speed2 = speed * speed;
+        if speed2 < self.max_cruise_v2 {
+            self.max_cruise_v2 = speed2;
+            self.min_move_t = self.move_d / speed;
+        }
+        self.accel = self.accel.min(accel);
+        self.delta_v2 = 2.0 * self.move_d * self.accel;
+        self.smooth_delta_v2 = self.smooth_delta_v2.min(self.delta_v2);
+    }
+
+    /// Restrict the velocity at the junction with the following move.
+    pub fn limit_next_junction_speed(&mut self, speed: f64) {
+        self.next_junction_v2 = self.next_junction_v2.min(speed * speed);
+    }
+
+    /// Total duration of the planned move (valid once the queue has flushed it).
+    pub fn move_t(&self) -> f64 {
+        self.accel_t + self.cruise_t + self.decel_t
+    }
+
+    fn calc_junction(&mut self, prev: &PlannedMove) {
+        let max_start_v2 = match (self.is_kinematic_move, prev.is_kinematic_move) {
+            (true, true) => self.centripetal_junction_v2(prev),
+            // Extrude only moves feeding the same way form a straight line
+            (false, false) if self.axes_r[3] == prev.axes_r[3] => 99_999_999.9,
+            _ => return,
+        };
+        // Apply limits
+        self.max_start_v2 = max_start_v2
+            .min(self.max_cruise_v2)
+            .min(prev.max_cruise_v2)
+            .min(prev.next_junction_v2)
+            .min(prev.max_start_v2 + prev.delta_v2);
+        self.max_smoothed_v2 = self
+            .max_start_v2
+            .min(prev.max_smoothed_v2 + prev.smooth_delta_v2);
+    }
+
+    /// Maximum junction velocity squared from the previous kinematic move,
+    /// using the "approximated centripetal velocity".
+    fn centripetal_junction_v2(&self, prev: &PlannedMove) -> f64 {
+        let junction_cos_theta = -(self.axes_r[0] * prev.axes_r[0]
+            + self.axes_r[1] * prev.axes_r[1]
+            + self.axes_r[2] * prev.axes_r[2]);
+        let sin_theta_d2 = (0.5 * (1.0 - junction_cos_theta)).max(0.0).sqrt();
+        let cos_theta_d2 = (0.5 * (1.0 + junction_cos_theta)).max(0.0).sqrt();
+        let one_minus_sin_theta_d2 = 1.0 - sin_theta_d2;
+        if one_minus_sin_theta_d2 > 0.0 && cos_theta_d2 > 0.0 {
+            let r_jd = sin_theta_d2 / one_minus_sin_theta_d2;
+            let move_jd_v2 = r_jd * self.junction_deviation * self.accel;
+            let pmove_jd_v2 = r_jd * prev.junction_deviation * prev.accel;
+            // Approximated circle must contact moves no further than mid-move
+            let quarter_tan_theta_d2 = 0.25 * sin_theta_d2 / cos_theta_d2;
+            let move_centripetal_v2 = self.delta_v2 * quarter_tan_theta_d2;
+            let pmove_centripetal_v2 = prev.delta_v2 * quarter_tan_theta_d2;
+            move_jd_v2
+                .min(pmove_jd_v2)
+                .min(move_centripetal_v2)
+                .min(pmove_centripetal_v2)
+        } else {
+            99_999_999.9
+        }
+    }
+
+    fn set_junction(&mut self, start_v2: f64, cruise_v2: f64, end_v2: f64) {
+        // Determine accel, cruise, and decel portions of the move distance
+        let half_inv_accel = 0.5 / self.accel;
+        let accel_d = (cruise_v2 - start_v2) * half_inv_accel;
+        let decel_d = (cruise_v2 - end_v2) * half_inv_accel;
+        let cruise_d = self.move_d - accel_d - decel_d;
+        // Determine move velocities
+        self.start_v = start_v2.sqrt();
+        self.cruise_v = cruise_v2.sqrt();
+        self.end_v = end_v2.sqrt();
+        // Time is the distance divided by average velocity
+        self.accel_t = accel_d / ((self.start_v + self.cruise_v) * 0.5);
+        self.cruise_t = cruise_d / self.cruise_v;
+        self.decel_t = decel_d / ((self.end_v + self.cruise_v) * 0.5);
+    }
+}
+
+/// Queue of moves awaiting junction velocity resolution.
+#[derive(Debug)]
+pub struct LookAheadQueue {
+    queue: Vec<PlannedMove>,
+    junction_flush: f64,
+}
+
+/// Moves awaiting junction resolution, from [`LookAheadQueue::snapshot`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LookAheadState {
+    queue: Vec<PlannedMove>,
+    junction_flush: f64,
+}
+
+impl Default for LookAheadQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LookAheadQueue {
+    pub fn new() -> Self {
+        Self {
+            queue: Vec::new(),
+            junction_flush: LOOKAHEAD_FLUSH_TIME,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.queue.clear();
+        self.junction_flush = LOOKAHEAD_FLUSH_TIME;
+    }
+
+    /// Capture the queued moves.
+    pub fn snapshot(&self) -> LookAheadState {
+        LookAheadState {
+            queue: self.queue.clone(),
+            junction_flush: self.junction_flush,
+        }
+    }
+
+    /// Rebuild a queue from [`snapshot`](Self::snapshot).
+    pub fn from_state(state: LookAheadState) -> Self {
+        Self {
+            queue: state.queue,
+            junction_flush: state.junction_flush,
+        }
+    }
+
+    pub fn set_flush_time(&mut self, flush_time: f64) {
+        self.junction_flush = flush_time;
+    }
+
+    pub fn last(&self) -> Option<&PlannedMove> {
+        self.queue.last()
+    }
+
+    pub fn last_mut(&mut self) -> Option<&mut PlannedMove> {
+        self.queue.last_mut()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Queue a move. Returns true once enough move time is queued that a
+    /// lazy flush should be attempted.
+    pub fn add_move(&mut self, mut mv: PlannedMove) -> bool {
+        if let Some(prev) = self.queue.last() {
+            mv.calc_junction(prev);
+            self.junction_flush -= mv.min_move_t;
+            self.queue.push(mv);
+            return self.junction_flush <= 0.0;
+        }
+        self.queue.push(mv);
+        false
+    }
+
+    /// Resolve velocities and remove the moves whose profile is final.
+    ///
+    /// A full flush assumes the machine comes to a stop after the last
+    /// queued move. A lazy flush only returns the moves whose velocities
+    /// can no longer change as more moves are queued.
+    pub fn flush(&mut self, lazy: bool) -> Vec<PlannedMove> {
+        self.junction_flush = LOOKAHEAD_FLUSH_TIME;
+        let mut update_flush_count = lazy;
+        let mut flush_count = self.queue.len();
+        // Traverse queue from last to first move and determine maximum
+        // junction speed assuming the robot comes to a complete stop
+        // after the last move.
+        let mut delayed: Vec<(usize, f64, f64)> = Vec::new();
+        let mut next_end_v2 = 0.0;
+        let mut next_smoothed_v2 = 0.0;
+        let mut peak_cruise_v2 = 0.0;
+        for i in (0..flush_count).rev() {
+            let mv = self.queue[i];
+            let reachable_start_v2 = next_end_v2 + mv.delta_v2;
+            let start_v2 = mv.max_start_v2.min(reachable_start_v2);
+            let reachable_smoothed_v2 = next_smoothed_v2 + mv.smooth_delta_v2;
+            let smoothed_v2 = mv.max_smoothed_v2.min(reachable_smoothed_v2);
+            if smoothed_v2 < reachable_smoothed_v2 {
+                // It's possible for this move to accelerate
+                if smoothed_v2 + mv.smooth_delta_v2 > next_smoothed_v2 || !delayed.is_empty() {
+                    // This move can decelerate or this is a full accel
+                    // move after a full decel move
+                    if update_flush_count && peak_cruise_v2 != 0.0 {
+                        flush_count = i;
+                        update_flush_count = false;
+                    }
+                    peak_cruise_v2 = mv
+                        .max_cruise_v2
+                        .min((smoothed_v2 + reachable_smoothed_v2) * 0.5);
+                    if !delayed.is_empty() {
+                        // Propagate peak_cruise_v2 to any delayed moves
+                        if !update_flush_count && i < flush_count {
+                            let mut mc_v2 = peak_cruise_v2;
+                            for &(idx, ms_v2, me_v2) in delayed.iter().rev() {
+                                mc_v2 = mc_v2.min(ms_v2);
+                                self.queue[idx].set_junction(
+                                    ms_v2.min(mc_v2),
+                                    mc_v2,
+                                    me_v2.min(mc_v2),
+                                );
+                            }
+                        }
+                        delayed.clear();
+                    }
+                }
+                if !update_flush_count && i < flush_count {
+                    let cruise_v2 = ((start_v2 + reachable_start_v2) * 0.5)
+                        .min(mv.max_cruise_v2)
+                        .min(peak_cruise_v2);
+                    self.queue[i].set_junction(
+                        start_v2.min(cruise_v2),
+                        cruise_v2,
+                        next_end_v2.min(cruise_v2),
+                    );
+                }
+            } else {
+                // Delay calculating this move until peak_cruise_v2 is known
+                delayed.push((i, start_v2, next_end_v2));
+            }
+            next_end_v2 = start_v2;
+            next_smoothed_v2 = smoothed_v2;
+        }
+        if update_flush_count || flush_count == 0 {
+            return Vec::new();
+        }
+        // Remove processed moves from the queue
+        self.queue.drain(..flush_count).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> MachineLimits {
+        MachineLimits {
+            max_velocity: 100.0,
+            max_accel: 1000.0,
+            minimum_cruise_ratio: 0.0,
+            square_corner_velocity: 5.0,
+            max_travel_velocity: None,
+            max_travel_accel: None,
+            max_extrude_only_velocity: 50.0,
+            max_extrude_only_accel: 500.0,
+        }
+    }
+
+    #[test]
+    fn single_move_matches_trapezoid() {
+        let limits = limits();
+        let mut queue = LookAheadQueue::new();
+        queue.add_move(PlannedMove::new(
+            &limits,
+            [0.0; 4],
+            [100.0, 0.0, 0.0, 0.0],
+            100.0,
+        ));
+        let moves = queue.flush(false);
+        assert_eq!(moves.len(), 1);
+        let mv = &moves[0];
+        // 0.1s to reach 100mm/s, covering 5mm on each ramp
+        assert!((mv.accel_t - 0.1).abs() < 1e-9);
+        assert!((mv.decel_t - 0.1).abs() < 1e-9);
+        assert!((mv.cruise_t - 0.9).abs() < 1e-9);
+        assert_eq!(mv.start_v, 0.0);
+        assert_eq!(mv.end_v, 0.0);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn colinear_moves_keep_speed_through_junction() {
+        let limits = limits();
+        let mut queue = LookAheadQueue::new();
+        queue.add_move(PlannedMove::new(
+            &limits,
+            [0.0; 4],
+            [50.0, 0.0, 0.0, 0.0],
+            100.0,
+        ));
+        queue.add_move(PlannedMove::new(
+            &limits,
+            [50.0, 0.0, 0.0, 0.0],
+            [100.0, 0.0, 0.0, 0.0],
+            100.0,
+        ));
+        let moves = queue.flush(false);
+        assert_eq!(moves.len(), 2);
+        assert!((moves[0].end_v - 100.0).abs() < 1e-9);
+        assert!((moves[1].start_v - 100.0).abs() < 1e-9);
+        let total: f64 = moves.iter().map(PlannedMove::move_t).sum();
+        assert!((total - 1.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn square_corner_is_limited() {
+        let limits = limits();
+        let mut queue = LookAheadQueue::new();
+        queue.add_move(PlannedMove::new(
+            &limits,
+            [0.0; 4],
+            [50.0, 0.0, 0.0, 0.0],
+            100.0,
+        ));
+        queue.add_move(PlannedMove::new(
+            &limits,
+            [50.0, 0.0, 0.0, 0.0],
+            [50.0, 50.0, 0.0, 0.0],
+            100.0,
+        ));
+        let moves = queue.flush(false);
+        let corner_v = moves[0].end_v;
+        assert!((corner_v - limits.square_corner_velocity).abs() < 1e-6);
+        assert_eq!(moves[1].start_v, corner_v);
+    }
+
+    #[test]
+    fn lazy_flush_holds_back_unresolved_moves() {
+        let limits = limits();
+        let mut queue = LookAheadQueue::new();
+        let mut pos = [0.0; 4];
+        for _ in 0..10 {
+            let mut next = pos;
+            next[0] += 10.0;
+            queue.add_move(PlannedMove::new(&limits, pos, next, 100.0));
+            pos = next;
+        }
+        let flushed = queue.flush(true);
+        assert!(flushed.len() < 10);
+        let rest = queue.flush(false);
+        assert_eq!(flushed.len() + rest.len(), 10);
+    }
+
+    #[test]
+    fn extrude_only_move_uses_extruder_limits() {
+        let limits = limits();
+        let mut queue = LookAheadQueue::new();
+        let mv = PlannedMove::new(&limits, [0.0; 4], [0.0, 0.0, 0.0, -20.0], 100.0);
+        assert!(!mv.is_kinematic_move);
+        assert_eq!(mv.axes_r[3], -1.0);
+        queue.add_move(mv);
+        let moves = queue.flush(false);
+        let mv = &moves[0];
+        // 0.1s to reach 50mm/s at 500mm/s^2, covering 2.5mm on each ramp
+        assert!((mv.cruise_v - 50.0).abs() < 1e-9);
+        assert!((mv.accel_t - 0.1).abs() < 1e-9);
+        assert!((mv.decel_t - 0.1).abs() < 1e-9);
+        assert!((mv.cruise_t - 0.3).abs() < 1e-9);
+    }
+
+    #[test]
+    fn extrude_only_moves_blend_until_reversed() {
+        let limits = limits();
+        let mut queue = LookAheadQueue::new();
+        let mut e = 0.0;
+        for delta in [10.0, 10.0, -10.0] {
+            queue.add_move(PlannedMove::new(
+                &limits,
+                [0.0, 0.0, 0.0, e],
+                [0.0, 0.0, 0.0, e + delta],
+                50.0,
+            ));
+            e += delta;
+        }
+        let moves = queue.flush(false);
+        assert!((moves[0].end_v - 50.0).abs() < 1e-9);
+        assert!((moves[1].start_v - 50.0).abs() < 1e-9);
+        assert_eq!(moves[1].end_v, 0.0);
+        assert_eq!(moves[2].start_v, 0.0);
+    }
+
+    #[test]
+    fn extrude_only_move_stops_toolhead() {
+        let limits = limits();
+        let mut queue = LookAheadQueue::new();
+        queue.add_move(PlannedMove::new(
+            &limits,
+            [0.0; 4],
+            [50.0, 0.0, 0.0, 1.0],
+            100.0,
+        ));
+        queue.add_move(PlannedMove::new(
+            &limits,
+            [50.0, 0.0, 0.0, 1.0],
+            [50.0, 0.0, 0.0, 2.0],
+            50.0,
+        ));
+        let moves = queue.flush(false);
+        assert_eq!(moves[0].end_v, 0.0);
+        assert_eq!(moves[1].start_v, 0.0);
+    }
+
+    #[test]
+    fn travel_moves_use_travel_limits() {
+        let limits = MachineLimits {
+            max_travel_velocity: Some(200.0),
+            max_travel_accel: Some(4000.0),
+            ..limits()
+        };
+        let travel = PlannedMove::new(&limits, [0.0; 4], [100.0, 0.0, 0.0, 0.0], 300.0);
+        assert_eq!(travel.move_type, MoveType::Travel);
+        assert_eq!(travel.accel, 4000.0);
+        assert!((travel.min_move_t - 0.5).abs() < 1e-9);
+
+        let print = PlannedMove::new(&limits, [0.0; 4], [100.0, 0.0, 0.0, 1.0], 300.0);
+        assert_eq!(print.move_type, MoveType::Print);
+        assert_eq!(print.accel, 1000.0);
+        assert!((print.min_move_t - 1.0).abs() < 1e-9);
+
+        let retract = PlannedMove::new(&limits, [0.0; 4], [0.0, 0.0, 0.0, -1.0], 300.0);
+        assert_eq!(retract.move_type, MoveType::ExtrudeOnly);
+        assert_eq!(retract.accel, 500.0);
+    }
+}
@@ -0,0 +1,229 @@
+//! Printer rails.
+//!
+//! A rail is the stepper driving one axis together with its travel limits
+//! and homing setup, loosely following `PrinterRail` in Klipper's
+//! `stepper.py`.
+
+use crate::{
+    itersolve::IterativeSolver,
+    kinematics::{Kinematics, StepperKinematics},
+};
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum RailError {
+    #[error("{rail}: rotation_distance must be positive, got {value}")]
+    InvalidRotationDistance { rail: String, value: f64 },
+    #[error("{rail}: microsteps and full_steps_per_rotation must be positive")]
+    InvalidStepCount { rail: String },
+    #[error("{rail}: position_min ({min}) must be below position_max ({max})")]
+    InvalidRange { rail: String, min: f64, max: f64 },
+    #[error("{rail}: position_endstop ({endstop}) must be within {min}..={max}")]
+    EndstopOutOfRange {
+        rail: String,
+        endstop: f64,
+        min: f64,
+        max: f64,
+    },
+    #[error(
+        "{rail}: unable to infer homing_positive_dir because position_endstop is not at position_min or position_max"
+    )]
+    AmbiguousHomingDirection { rail: String },
+    #[error("{rail}: homing_speed must be positive, got {value}")]
+    InvalidHomingSpeed { rail: String, value: f64 },
+}
+
+/// How a rail finds its reference position.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Homing {
+    /// Axis position (mm) at which the endstop triggers.
+    pub position_endstop: f64,
+    /// Homing speed (mm/s).
+    pub speed: f64,
+    /// Whether homing moves toward position_max.
+    pub positive_dir: bool,
+}
+
+/// Klipper-style stepper parameters used to build a [`Rail`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct RailConfig {
+    /// Config section name, e.g. `stepper_x`.
+    pub name: String,
+    /// Distance (mm) the axis moves per full rotation of the stepper.
+    pub rotation_distance: f64,
+    pub full_steps_per_rotation: u32,
+    pub microsteps: u32,
+    pub position_min: f64,
+    pub position_max: f64,
+    /// Axis position at which the endstop triggers, if the rail has one.
+    pub position_endstop: Option<f64>,
+    /// Homing speed (mm/s).
+    pub homing_speed: f64,
+    /// Homing direction; inferred from `position_endstop` when not given.
+    pub homing_positive_dir: Option<bool>,
+}
+
+impl Default for RailConfig {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            rotation_distance: 40.0,
+            full_steps_per_rotation: 200,
+            microsteps: 16,
+            position_min: 0.0,
+            position_max: 200.0,
+            position_endstop: None,
+            homing_speed: 5.0,
+            homing_positive_dir: None,
+        }
+    }
+}
+
+/// A stepper-driven axis with its limits.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rail {
+    /// Config section name, e.g. `stepper_x`.
+    pub name: String,
+    /// Distance (mm) moved per microstep.
+    pub step_distance: f64,
+    pub position_min: f64,
+    pub position_max: f64,
+    /// Homing setup, if the rail has an endstop.
+    pub homing: Option<Homing>,
+}
+
+impl Rail {
+    /// Validate `config` and derive the rail's step distance and homing setup.
+    pub fn new(config: &RailConfig) -> Result<Self, RailError> {
+        let rail = config.name.clone();
+        let (min, max) = (config.position_min, config.position_max);
+
+        if config.rotation_distance <= 0.0 || !config.rotation_distance.is_finite() {
+            return Err(RailError::InvalidRotationDistance {
+                rail,
+                value: config.rotation_distance,
+            });
+        }
+        if config.full_steps_per_rotation == 0 || config.microsteps == 0 {
+            return Err(RailError::InvalidStepCount { rail });
+        }
+        if min >= max || !min.is_finite() || !max.is_finite() {
+            return Err(RailError::InvalidRange { rail, min, max });
+        }
+
+        let homing = match config.position_endstop {
+            Some(endstop) => {
+                if !(min..=max).contains(&endstop) {
+                    return Err(RailError::EndstopOutOfRange {
+                        rail,
+                        endstop,
+                        min,
+                        max,
+                    });
+                }
+                if config.homing_speed <= 0.0 || !config.homing_speed.is_finite() {
+                    return Err(RailError::InvalidHomingSpeed {
+                        rail,
+                        value: config.homing_speed,
+                    });
+                }
+                let positive_dir = match config.homing_positive_dir {
+                    Some(dir) => dir,
+                    None if endstop == max => true,
+                    None if endstop == min => false,
+                    None => return Err(RailError::AmbiguousHomingDirection { rail }),
+                };
+                Some(Homing {
+                    position_endstop: endstop,
+                    speed: config.homing_speed,
+                    positive_dir,
+                })
+            }
+            None => None,
+        };
+
+        let steps_per_rotation = config.full_steps_per_rotation * config.microsteps;
+        let step_distance = config.rotation_distance / steps_per_rotation as f64;
+
+        Ok(Self {
+            name: rail,
+            step_distance,
+            position_min: min,
+            position_max: max,
+            homing,
+        })
+    }
+
+    /// Whether `pos` is within the rail's travel.
+    pub fn contains(&self, pos: f64) -> bool {
+        (self.position_min..=self.position_max).contains(&pos)
+    }
+
+    /// Create the step solver for this rail's stepper.
+    pub fn solver(
+        &self,
+        kinematics: Kinematics,
+        axis: usize,
+    ) -> IterativeSolver<StepperKinematics> {
+        let kin = kinematics.stepper(axis);
+        let flags = kin.active_flags();
+        IterativeSolver::new(self.step_distance, flags, 0.0, 0.0, kin, ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(endstop: Option<f64>) -> RailConfig {
+        RailConfig {
+            name: "stepper_x".to_string(),
+            position_min: -2.0,
+            position_max: 235.0,
+            position_endstop: endstop,
+            ..RailConfig::default()
+        }
+    }
+
+    #[test]
+    fn step_distance_and_homing_direction() {
+        let rail = Rail::new(&config(Some(235.0))).unwrap();
+        assert_eq!(rail.step_distance, 40.0 / 3200.0);
+        assert!(rail.homing.unwrap().positive_dir);
+        assert!(rail.contains(100.0));
+        assert!(!rail.contains(-2.1));
+
+        let rail = Rail::new(&config(Some(-2.0))).unwrap();
+        assert!(!rail.homing.unwrap().positive_dir);
+
+        let rail = Rail::new(&RailConfig {
+            homing_positive_dir: Some(true),
+            ..config(Some(100.0))
+        })
+        .unwrap();
+        assert!(rail.homing.unwrap().positive_dir);
+    }
+
+    #[test]
+    fn rejects_invalid_rails() {
+        let err = Rail::new(&config(Some(100.0))).unwrap_err();
+        assert!(matches!(err, RailError::AmbiguousHomingDirection { .. }));
+
+        let err = Rail::new(&config(Some(300.0))).unwrap_err();
+        assert!(matches!(err, RailError::EndstopOutOfRange { .. }));
+
+        let err = Rail::new(&RailConfig {
+            rotation_distance: 0.0,
+            ..config(None)
+        })
+        .unwrap_err();
+        assert!(matches!(err, RailError::InvalidRotationDistance { .. }));
+
+        let err = Rail::new(&RailConfig {
+            position_max: -2.0,
+            ..config(None)
+        })
+        .unwrap_err();
+        assert!(matches!(err, RailError::InvalidRange { .. }));
+    }
+}
@@ -0,0 +1,558 @@
+//! Resonance measurement and input shaper selection.
+//!
+//! Follows Klipper's `resonance_tester.py`, `shaper_defs.py`, and
+//! `shaper_calibrate.py`. A resonance test shakes the toolhead along one axis
+//! at frequencies sweeping up through the range of interest while an
+//! accelerometer records it. The power spectral density of the recording
+//! shows which frequencies the printer rings at, and each shaper type is
+//! fitted to it: the shaper frequency that leaves the least vibration for
+//! the least smoothing wins.
+
+use std::f64::consts::PI;
+
+/// Damping ratio assumed when building shapers.
+pub const DEFAULT_DAMPING_RATIO: f64 = 0.1;
+/// Damping ratios the fitted shapers are checked against.
+const TEST_DAMPING_RATIOS: [f64; 3] = [0.075, 0.1, 0.15];
+/// Vibration tolerance of the EI shapers.
+const SHAPER_VIBRATION_TOLERANCE: f64 = 0.05;
+/// Shapers reduce vibrations at most this many times, so anything weaker
+/// than the peak divided by it is ignored.
+const SHAPER_VIBRATION_REDUCTION: f64 = 20.0;
+/// Highest frequency (Hz) of the spectrum considered.
+const MAX_FREQ: f64 = 200.0;
+/// Highest shaper frequency (Hz) tried.
+const MAX_SHAPER_FREQ: f64 = 150.0;
+/// Step (Hz) between the shaper frequencies tried.
+const SHAPER_FREQ_STEP: f64 = 0.2;
+/// Smoothing (mm) the recommended max_accel is chosen to stay within.
+const TARGET_SMOOTHING: f64 = 0.12;
+/// Acceleration (mm/s^2) the smoothing of shapers is compared at.
+const SMOOTHING_ACCEL: f64 = 5000.0;
+
+/// Input shaper types, in increasing order of smoothing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShaperType {
+    Zv,
+    Mzv,
+    Ei,
+    TwoHumpEi,
+    ThreeHumpEi,
+}
+
+impl ShaperType {
+    pub const ALL: [Self; 5] = [
+        Self::Zv,
+        Self::Mzv,
+        Self::Ei,
+        Self::TwoHumpEi,
+        Self::ThreeHumpEi,
+    ];
+
+    /// Parse a Klipper shaper name such as `mzv` or `2hump_ei`.
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|shaper| shaper.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Zv => "zv",
+            Self::Mzv => "mzv",
+            Self::Ei => "ei",
+            Self::TwoHumpEi => "2hump_ei",
+            Self::ThreeHumpEi => "3hump_ei",
+        }
+    }
+
+    /// Lowest frequency (Hz) this shaper is fitted at.
+    pub fn min_freq(&self) -> f64 {
+        match self {
+            Self::Zv => 21.0,
+            Self::Mzv => 23.0,
+            Self::Ei => 29.0,
+            Self::TwoHumpEi => 39.0,
+            Self::ThreeHumpEi => 48.0,
+        }
+    }
+
+    /// Shaper cancelling vibrations at `freq` Hz.
+    pub fn shaper(&self, freq: f64, damping_ratio: f64) -> Shaper {
+        let df = (1.0 - damping_ratio * damping_ratio).sqrt();
+        let k = (-damping_ratio * PI / df).exp();
+        let t_d = 1.0 / (freq * df);
+        let v_tol = SHAPER_VIBRATION_TOLERANCE;
+        let (amplitudes, times) = match self {
+            Self::Zv => (vec![1.0, k], vec![0.0, 0.5 * t_d]),
+            Self::Mzv => {
+                let k = (-0.75 * damping_ratio * PI / df).exp();
+                let a1 = 1.0 - 1.0 / 2f64.sqrt();
+                let a2 = (2f64.sqrt() - 1.0) * k;
+                let a3 = a1 * k * k;
+                (vec![a1, a2, a3], vec![0.0, 0.375 * t_d, 0.75 * t_d])
+            }
+            Self::Ei => {
+                let a1 = 0.25 * (1.0 + v_tol);
+                let a2 = 0.5 * (1.0 - v_tol) * k;
+                let a3 = a1 * k * k;
+                (vec![a1, a2, a3], vec![0.0, 0.5 * t_d, t_d])
+            }
+            Self::TwoHumpEi => {
+                let v2 = v_tol * v_tol;
+                let x = (v2 * ((1.0 - v2).sqrt() + 1.0)).powf(1.0 / 3.0);
+                let a1 = (3.0 * x * x + 2.0 * x + 3.0 * v2) / (16.0 * x);
+                let a2 = (0.5 - a1) * k;
+                let a3 = a2 * k;
+                let a4 = a1 * k * k * k;
+                let times = vec![0.0, 0.5 * t_d, t_d, 1.5 * t_d];
+                (vec![a1, a2, a3, a4], times)
+            }
+            Self::ThreeHumpEi => {
+                let k2 = k * k;
+                let a1 = 0.0625 * (1.0 + 3.0 * v_tol + 2.0 * (2.0 * (v_tol + 1.0) * v_tol).sqrt());
+                let a2 = 0.25 * (1.0 - v_tol) * k;
+                let a3 = (0.5 * (1.0 + v_tol) - 2.0 * a1) * k2;
+                let a4 = a2 * k2;
+                let a5 = a1 * k2 * k2;
+                let times = vec![0.0, 0.5 * t_d, t_d, 1.5 * t_d, 2.0 * t_d];
+                (vec![a1, a2, a3, a4, a5], times)
+            }
+        };
+        Shaper { amplitudes, times }
+    }
+}
+
+/// Impulses an input shaper convolves moves with.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Shaper {
+    pub amplitudes: Vec<f64>,
+    /// Impulse times (s), starting at 0.
+    pub times: Vec<f64>,
+}
+
+impl Shaper {
+    /// Fraction of vibrations at `freq` Hz left after shaping, for a system
+    /// with `damping_ratio`.
+    pub fn response(&self, freq: f64, damping_ratio: f64) -> f64 {
+        let inv_d = 1.0 / self.amplitudes.iter().sum::<f64>();
+        let omega = 2.0 * PI * freq;
+        let damping = damping_ratio * omega;
+        let omega_d = omega * (1.0 - damping_ratio * damping_ratio).sqrt();
+        let end = self.times.last().copied().unwrap_or(0.0);
+        let (mut s, mut c) = (0.0, 0.0);
+        for (a, t) in self.amplitudes.iter().zip(&self.times) {
+            let w = a * (-damping * (end - t)).exp();
+            s += w * (omega_d * t).sin();
+            c += w * (omega_d * t).cos();
+        }
+        s.hypot(c) * inv_d
+    }
+
+    /// Deviation (mm) of the toolhead from the path on turns at `accel`
+    /// mm/s^2 and square corner velocity `scv` mm/s.
+    pub fn smoothing(&self, accel: f64, scv: f64) -> f64 {
+        let half_accel = accel * 0.5;
+        let inv_d = 1.0 / self.amplitudes.iter().sum::<f64>();
+        let pulses = || self.amplitudes.iter().zip(&self.times);
+        let ts = pulses().map(|(a, t)| a * t).sum::<f64>() * inv_d;
+        let (mut offset_90, mut offset_180) = (0.0, 0.0);
+        for (a, t) in pulses() {
+            if *t >= ts {
+                offset_90 += a * (scv + half_accel * (t - ts)) * (t - ts);
+            }
+            offset_180 += a * half_accel * (t - ts) * (t - ts);
+        }
+        (offset_90 * inv_d * 2f64.sqrt()).max(offset_180 * inv_d)
+    }
+
+    /// Highest acceleration (mm/s^2) that keeps smoothing acceptable.
+    pub fn max_accel(&self, scv: f64) -> f64 {
+        bisect(|accel| self.smoothing(accel, scv) <= TARGET_SMOOTHING)
+    }
+}
+
+/// Largest value for which `ok` holds, assuming it holds below some limit.
+fn bisect(ok: impl Fn(f64) -> bool) -> f64 {
+    if !ok(1e-9) {
+        return 0.0;
+    }
+    let (mut left, mut right) = (1.0, 1.0);
+    while !ok(left) {
+        right = left;
+        left *= 0.5;
+    }
+    if right == left {
+        while ok(right) {
+            right *= 2.0;
+        }
+    }
+    while right - left > 1e-8 {
+        let middle = (left + right) * 0.5;
+        if ok(middle) {
+            left = middle;
+        } else {
+            right = middle;
+        }
+    }
+    left
+}
+
+/// Power spectral density of accelerometer readings.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PowerSpectrum {
+    /// Frequency (Hz) of each bin.
+    pub freqs: Vec<f64>,
+    /// Power of the X, Y, and Z axes summed, per bin.
+    pub psd: Vec<f64>,
+}
+
+impl PowerSpectrum {
+    /// Welch's estimate from `samples` (X/Y/Z, mm/s^2) taken at
+    /// `sample_rate` Hz, using half-second Hann windows overlapping by half.
+    /// Returns `None` if there are too few samples for one window.
+    pub fn from_samples(samples: &[[f64; 3]], sample_rate: f64) -> Option<Self> {
+        let window_len = ((sample_rate * 0.5) as usize).max(2).next_power_of_two();
+        if samples.len() < window_len {
+            return None;
+        }
+        let window: Vec<f64> = (0..window_len)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f64 / window_len as f64).cos())
+            .collect();
+        let scale = 1.0 / (sample_rate * window.iter().map(|w| w * w).sum::<f64>());
+        let bins = window_len / 2 + 1;
+        let step = window_len / 2;
+        let segments = (samples.len() - window_len) / step + 1;
+
+        let mut psd = vec![0.0; bins];
+        for axis in 0..3 {
+            let mean = samples.iter().map(|s| s[axis]).sum::<f64>() / samples.len() as f64;
+            for segment in 0..segments {
+                let start = segment * step;
+                let mut re: Vec<f64> = samples[start..start + window_len]
+                    .iter()
+                    .zip(&window)
+                    .map(|(sample, w)| (sample[axis] - mean) * w)
+                    .collect();
+                let mut im = vec![0.0; window_len];
+                fft(&mut re, &mut im);
+                for (bin, power) in psd.iter_mut().enumerate() {
+                    let mut p = (re[bin] * re[bin] + im[bin] * im[bin]) * scale;
+                    // Fold in the negative frequencies
+                    if bin != 0 && bin != bins - 1 {
+                        p *= 2.0;
+                    }
+                    *power += p / segments as f64;
+                }
+            }
+        }
+
+        let freqs: Vec<f64> = (0..bins)
+            .map(|bin| bin as f64 * sample_rate / window_len as f64)
+            .collect();
+        let keep = freqs.iter().take_while(|&&freq| freq <= MAX_FREQ).count();
+        psd.truncate(keep);
+        Some(Self {
+            freqs: freqs[..keep].to_vec(),
+            psd,
+        })
+    }
+
+    /// Frequency (Hz) with the most power.
+    pub fn peak_freq(&self) -> Option<f64> {
+        self.freqs
+            .iter()
+            .zip(&self.psd)
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .map(|(freq, _)| *freq)
+    }
+}
+
+/// In-place radix-2 FFT. The length must be a power of two.
+fn fft(re: &mut [f64], im: &mut [f64]) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f64;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f64).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+                let tr = re[b] * cos - im[b] * sin;
+                let ti = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - tr;
+                im[b] = im[a] - ti;
+                re[a] += tr;
+                im[a] += ti;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+/// A shaper fitted to a spectrum.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FittedShaper {
+    pub shaper_type: ShaperType,
+    /// Shaper frequency (Hz).
+    pub freq: f64,
+    /// Fraction of the vibrations left, in the worst tested damping.
+    pub vibrations: f64,
+    /// Smoothing (mm) at 5000 mm/s^2.
+    pub smoothing: f64,
+    /// Highest recommended acceleration (mm/s^2).
+    pub max_accel: f64,
+    /// Lower is better: smoothing weighted by remaining vibrations.
+    pub score: f64,
+}
+
+/// Fit `shaper_type` to `spectrum`, never exceeding `max_smoothing` (mm) if
+/// given. `scv` is the square corner velocity (mm/s).
+pub fn fit_shaper(
+    shaper_type: ShaperType,
+    spectrum: &PowerSpectrum,
+    max_smoothing: Option<f64>,
+    scv: f64,
+) -> Option<FittedShaper> {
+    let max_psd = spectrum.psd.iter().copied().fold(0.0, f64::max);
+    let threshold = max_psd / SHAPER_VIBRATION_REDUCTION;
+    let all_vibrations: f64 = spectrum
+        .psd
+        .iter()
+        .map(|psd| (psd - threshold).max(0.0))
+        .sum();
+    if all_vibrations <= 0.0 {
+        return None;
+    }
+
+    let steps = ((MAX_SHAPER_FREQ - shaper_type.min_freq()) / SHAPER_FREQ_STEP) as usize;
+    let mut results: Vec<FittedShaper> = Vec::new();
+    let mut best: Option<FittedShaper> = None;
+    // From high to low frequencies, as smoothing grows towards the bottom
+    for step in (0..=steps).rev() {
+        let freq = shaper_type.min_freq() + step as f64 * SHAPER_FREQ_STEP;
+        let shaper = shaper_type.shaper(freq, DEFAULT_DAMPING_RATIO);
+        let smoothing = shaper.smoothing(SMOOTHING_ACCEL, scv);
+        if let (Some(max), Some(best)) = (max_smoothing, best)
+            && smoothing > max
+        {
+            return Some(best);
+        }
+        let vibrations = TEST_DAMPING_RATIOS
+            .iter()
+            .map(|&damping| {
+                let remaining: f64 = spectrum
+                    .freqs
+                    .iter()
+                    .zip(&spectrum.psd)
+                    .map(|(&f, &psd)| (shaper.response(f, damping) * psd - threshold).max(0.0))
+                    .sum();
+                remaining / all_vibrations
+            })
+            .fold(0.0, f64::max);
+        let fitted = FittedShaper {
+            shaper_type,
+            freq,
+            vibrations,
+            smoothing,
+            max_accel: shaper.max_accel(scv),
+            score: smoothing * (vibrations.powf(1.5) + vibrations * 0.2 + 0.01),
+        };
+        if best.is_none_or(|best| best.vibrations > fitted.vibrations) {
+            best = Some(fitted);
+        }
+        results.push(fitted);
+    }
+
+    // Prefer less smoothing if it leaves almost as little vibration
+    let best = best?;
+    let mut selected = best;
+    for fitted in results.iter().rev() {
+        if fitted.vibrations < best.vibrations * 1.1 + 0.0005 && fitted.score < selected.score {
+            selected = *fitted;
+        }
+    }
+    Some(selected)
+}
+
+/// Fit every shaper type to `spectrum`, returning them all and the
+/// recommended one.
+pub fn find_best_shaper(
+    spectrum: &PowerSpectrum,
+    max_smoothing: Option<f64>,
+    scv: f64,
+) -> Option<(FittedShaper, Vec<FittedShaper>)> {
+    let mut best: Option<FittedShaper> = None;
+    let mut all = Vec::new();
+    for shaper_type in ShaperType::ALL {
+        let Some(fitted) = fit_shaper(shaper_type, spectrum, max_smoothing, scv) else {
+            continue;
+        };
+        let better = best.is_none_or(|best| {
+            fitted.score * 1.2 < best.score
+                || (fitted.score * 1.05 < best.score && fitted.smoothing * 1.1 < best.smoothing)
+        });
+        if better {
+            best = Some(fitted);
+        }
+        all.push(fitted);
+    }
+    best.map(|best| (best, all))
+}
+
+/// Frequency sweep of a resonance test.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ResonanceTest {
+    /// First frequency (Hz) tested.
+    pub min_freq: f64,
+    /// Last frequency (Hz) tested.
+    pub max_freq: f64,
+    /// Acceleration (mm/s^2) per Hz of the test moves.
+    pub accel_per_hz: f64,
+    /// How fast (Hz/s) the frequency sweeps up.
+    pub hz_per_sec: f64,
+}
+
+impl Default for ResonanceTest {
+    fn default() -> Self {
+        Self {
+            min_freq: 5.0,
+            max_freq: 133.33,
+            accel_per_hz: 75.0,
+            hz_per_sec: 1.0,
+        }
+    }
+}
+
+/// A test move, run at its own acceleration.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TestMove {
+    /// X/Y/Z position to move to.
+    pub target: [f64; 3],
+    /// Speed (mm/s).
+    pub speed: f64,
+    /// Acceleration (mm/s^2).
+    pub accel: f64,
+}
+
+impl ResonanceTest {
+    /// Moves shaking the toolhead around `center` along the X/Y direction
+    /// `axis`, alternating sides with each frequency.
+    pub fn moves(&self, center: [f64; 3], axis: [f64; 2]) -> Vec<TestMove> {
+        let norm = axis[0].hypot(axis[1]);
+        let axis = [axis[0] / norm, axis[1] / norm];
+        let mut moves = Vec::new();
+        let mut sign = 1.0;
+        let mut freq = self.min_freq;
+        while freq <= self.max_freq + 0.000_001 {
+            let t_seg = 0.25 / freq;
+            let accel = self.accel_per_hz * freq;
+            let speed = accel * t_seg;
+            let distance = 0.5 * accel * t_seg * t_seg;
+            let [x, y, z] = center;
+            let out = [
+                x + sign * axis[0] * distance,
+                y + sign * axis[1] * distance,
+                z,
+            ];
+            moves.push(TestMove {
+                target: out,
+                speed,
+                accel,
+            });
+            moves.push(TestMove {
+                target: center,
+                speed,
+                accel,
+            });
+            sign = -sign;
+            freq += 2.0 * t_seg * self.hz_per_sec;
+        }
+        moves
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shapers_have_unit_gain_and_cancel_their_frequency() {
+        for shaper_type in ShaperType::ALL {
+            assert_eq!(ShaperType::parse(shaper_type.name()), Some(shaper_type));
+            let shaper = shaper_type.shaper(50.0, DEFAULT_DAMPING_RATIO);
+            assert_eq!(shaper.amplitudes.len(), shaper.times.len());
+            let response = shaper.response(50.0, DEFAULT_DAMPING_RATIO);
+            assert!(response < 0.06, "{shaper_type:?}: {response}");
+            assert!(shaper.response(1.0, DEFAULT_DAMPING_RATIO) > 0.9);
+            assert!(shaper.max_accel(5.0) > 0.0);
+        }
+        // Later shapers cancel more but smooth more
+        let smoothing = |shaper_type: ShaperType| {
+            shaper_type
+                .shaper(50.0, DEFAULT_DAMPING_RATIO)
+                .smoothing(5000.0, 5.0)
+        };
+        assert!(smoothing(ShaperType::Zv) < smoothing(ShaperType::Mzv));
+        assert!(smoothing(ShaperType::Ei) < smoothing(ShaperType::ThreeHumpEi));
+    }
+
+    #[test]
+    fn spectrum_finds_the_resonance_and_a_shaper_near_it() {
+        let sample_rate = 1000.0;
+        let samples: Vec<[f64; 3]> = (0..4000)
+            .map(|i| {
+                let t = i as f64 / sample_rate;
+                let ring = (2.0 * PI * 42.0 * t).sin() * 1000.0;
+                [ring, 0.1 * ring, 9810.0]
+            })
+            .collect();
+        let spectrum = PowerSpectrum::from_samples(&samples, sample_rate).unwrap();
+        let peak = spectrum.peak_freq().unwrap();
+        assert!((peak - 42.0).abs() < 2.0, "{peak}");
+        assert!(*spectrum.freqs.last().unwrap() <= MAX_FREQ);
+
+        let (best, all) = find_best_shaper(&spectrum, None, 5.0).unwrap();
+        assert_eq!(all.len(), ShaperType::ALL.len());
+        assert!(best.vibrations < 0.1, "{best:?}");
+        assert!(best.max_accel > 0.0);
+        let mzv = all
+            .iter()
+            .find(|fitted| fitted.shaper_type == ShaperType::Mzv)
+            .unwrap();
+        assert!((30.0..60.0).contains(&mzv.freq), "{mzv:?}");
+
+        assert!(PowerSpectrum::from_samples(&samples[..10], sample_rate).is_none());
+    }
+
+    #[test]
+    fn test_moves_sweep_up_alternating_sides() {
+        let test = ResonanceTest {
+            min_freq: 10.0,
+            max_freq: 12.0,
+            accel_per_hz: 100.0,
+            hz_per_sec: 10.0,
+        };
+        let moves = test.moves([100.0, 100.0, 20.0], [1.0, 0.0]);
+        assert_eq!(moves[0].accel, 1000.0);
+        assert_eq!(moves[0].speed, 25.0);
+        assert!((moves[0].target[0] - 100.3125).abs() < 1e-12);
+        assert_eq!(moves[1].target, [100.0, 100.0, 20.0]);
+        assert!(moves[2].target[0] < 100.0);
+        assert!(moves.last().unwrap().accel <= 1200.0);
+        assert_eq!(moves.len() % 2, 0);
+    }
+}
@@ -0,0 +1,139 @@
+//! Firmware retraction.
+//!
+//! Slicers can emit `G10`/`G11` instead of explicit extruder moves and leave
+//! the retraction length and speeds to the printer. This follows Klipper's
+//! `firmware_retraction` module: a retract pulls the filament back by
+//! `retract_length`, and the matching unretract pushes it forward by the same
+//! length plus `unretract_extra_length`. Repeated retracts (or unretracts) do
+//! nothing until the state flips.
+
+/// Tunable retraction parameters.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetractionConfig {
+    /// Filament (mm) pulled back on retract.
+    pub retract_length: f64,
+    /// Retract speed (mm/s).
+    pub retract_speed: f64,
+    /// Extra filament (mm) pushed on unretract, to make up for ooze.
+    pub unretract_extra_length: f64,
+    /// Unretract speed (mm/s).
+    pub unretract_speed: f64,
+}
+
+impl Default for RetractionConfig {
+    fn default() -> Self {
+        Self {
+            retract_length: 0.0,
+            retract_speed: 20.0,
+            unretract_extra_length: 0.0,
+            unretract_speed: 10.0,
+        }
+    }
+}
+
+/// An extruder-only move.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExtruderMove {
+    /// Filament (mm) to move; negative values retract.
+    pub distance: f64,
+    /// Speed (mm/s).
+    pub speed: f64,
+}
+
+/// Tracks whether the filament is retracted.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FirmwareRetraction {
+    config: RetractionConfig,
+    /// Filament (mm) pulled back by the pending retract.
+    retracted: Option<f64>,
+}
+
+impl FirmwareRetraction {
+    pub fn new(config: RetractionConfig) -> Self {
+        Self {
+            config,
+            retracted: None,
+        }
+    }
+
+    pub fn config(&self) -> &RetractionConfig {
+        &self.config
+    }
+
+    /// Change the parameters, e.g. from `M207`/`M208`. A pending retract is
+    /// still undone by the length it actually pulled back.
+    pub fn set_config(&mut self, config: RetractionConfig) {
+        self.config = config;
+    }
+
+    pub fn is_retracted(&self) -> bool {
+        self.retracted.is_some()
+    }
+
+    /// The move for `G10`, if the filament is not already retracted.
+    pub fn retract(&mut self) -> Option<ExtruderMove> {
+        if self.is_retracted() {
+            return None;
+        }
+        let length = self.config.retract_length;
+        self.retracted = Some(length);
+        (length > 0.0).then_some(ExtruderMove {
+            distance: -length,
+            speed: self.config.retract_speed,
+        })
+    }
+
+    /// The move for `G11`, if the filament is retracted.
+    pub fn unretract(&mut self) -> Option<ExtruderMove> {
+        let length = self.retracted.take()? + self.config.unretract_extra_length;
+        (length > 0.0).then_some(ExtruderMove {
+            distance: length,
+            speed: self.config.unretract_speed,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retract_and_unretract_pair_up() {
+        let mut retraction = FirmwareRetraction::new(RetractionConfig {
+            retract_length: 0.8,
+            retract_speed: 35.0,
+            unretract_extra_length: 0.1,
+            unretract_speed: 25.0,
+        });
+
+        assert_eq!(retraction.unretract(), None);
+        assert_eq!(
+            retraction.retract(),
+            Some(ExtruderMove {
+                distance: -0.8,
+                speed: 35.0
+            })
+        );
+        assert!(retraction.is_retracted());
+        assert_eq!(retraction.retract(), None);
+
+        // Retuning mid-retract still undoes the original length
+        retraction.set_config(RetractionConfig {
+            retract_length: 2.0,
+            ..*retraction.config()
+        });
+        let unretract = retraction.unretract().unwrap();
+        assert!((unretract.distance - 0.9).abs() < 1e-9);
+        assert_eq!(unretract.speed, 25.0);
+        assert_eq!(retraction.unretract(), None);
+    }
+
+    #[test]
+    fn zero_length_retracts_do_not_move() {
+        let mut retraction = FirmwareRetraction::default();
+        assert_eq!(retraction.retract(), None);
+        assert!(retraction.is_retracted());
+        assert_eq!(retraction.unretract(), None);
+        assert!(!retraction.is_retracted());
+    }
+}
@@ -1,38 +1,85 @@
-use std::collections::VecDeque;
+use crate::stepper_phase::StepperPhase;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::mpsc::{self, Receiver, SyncSender, TrySendError},
+};
 use thiserror::Error;
 
-const QUEUE_START_SIZE: usize = 1024;
-const CLOCK_DIFF_MAX: u64 = 3 << 28;
+/// Default initial capacity of the step queue.
+pub const QUEUE_START_SIZE: usize = 1024;
+/// Default furthest a step may be scheduled past the last one sent (ticks).
+pub const CLOCK_DIFF_MAX: u64 = 3 << 28;
 const QUADRATIC_DEV: i64 = 11; // (6 + 4*sqrt(2)) ~= 11.65, but 11 is used upstream.
-const SDS_FILTER_TIME: f64 = 0.000_750;
-
-#[derive(Debug, Error)]
+/// Default window (seconds) of the step+dir+step filter.
+pub const SDS_FILTER_TIME: f64 = 0.000_750;
+/// Default most history entries kept.
+pub const HISTORY_MAX_ENTRIES: usize = 1 << 16;
+/// Default history kept (seconds) behind the newest step sent.
+pub const HISTORY_TIME: f64 = 30.0;
+/// Most steps a single `queue_step` command can carry.
+const MAX_QUEUE_STEP_COUNT: u32 = 0xffff;
+/// Most steps fitted as one sequence when splitting moves, keeping the
+/// bisect arithmetic well inside `i64`.
+const MAX_SPLIT_COUNT: usize = 1 << 20;
+/// Most recoveries kept for [`StepCompressor::take_recoveries`]; older ones
+/// are only counted.
+const MAX_PENDING_RECOVERIES: usize = 16;
+/// Resolution (per second) of fixed-point clock conversion: nanoseconds.
+const FIXED_POINT_SCALE: i128 = 1_000_000_000;
+/// Most queued steps [`LookaheadBisect`] plans over at once.
+const LOOKAHEAD_HORIZON: usize = 256;
+
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
 pub enum StepCompressError {
     #[error("invalid sequence i={interval} c={count} a={add}")]
-    InvalidSequence { interval: u32, count: u16, add: i16 },
+    InvalidSequence { interval: u32, count: u32, add: i16 },
     #[error(
         "point {index} out of range: {value} not in {min}:{max} for i={interval} c={count} a={add}"
     )]
     PointOutOfRange {
-        index: u16,
+        index: u32,
         value: i64,
         min: i64,
         max: i64,
         interval: u32,
-        count: u16,
+        count: u32,
         add: i16,
     },
     #[error("interval overflow at point {index} for i={interval} c={count} a={add}")]
     IntervalOverflow {
-        index: u16,
+        index: u32,
         interval: u32,
-        count: u16,
+        count: u32,
         add: i16,
     },
+    #[error("command receiver was dropped")]
+    SinkClosed,
 }
 
 pub type Result<T> = std::result::Result<T, StepCompressError>;
 
+/// Low 32 bits of `clock`, as an MCU with a 32-bit timer reports and
+/// schedules it.
+pub fn clock32(clock: u64) -> u32 {
+    clock as u32
+}
+
+/// The 64-bit clock whose low 32 bits are `clock32`, taken as the one
+/// nearest `reference` (within half a wrap either side) but never before
+/// clock zero. Follows `clock32_to_clock64` in Klipper's `clocksync.py`.
+pub fn clock64(clock32: u32, reference: u64) -> u64 {
+    let diff = clock32.wrapping_sub(reference as u32) as i32 as i64;
+    reference
+        .checked_add_signed(diff)
+        .unwrap_or(reference + (diff + (1 << 32)) as u64)
+}
+
+/// `seconds` rounded to the nanosecond.
+fn nanos(seconds: f64) -> i128 {
+    (seconds * FIXED_POINT_SCALE as f64).round() as i128
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct QueueStep {
     pub oid: u32,
@@ -45,6 +92,24 @@ pub struct QueueStep {
     pub min_clock: u64,
 }
 
+impl QueueStep {
+    pub fn first_clock32(&self) -> u32 {
+        clock32(self.first_clock)
+    }
+
+    pub fn last_clock32(&self) -> u32 {
+        clock32(self.last_clock)
+    }
+
+    pub fn req_clock32(&self) -> u32 {
+        clock32(self.req_clock)
+    }
+
+    pub fn min_clock32(&self) -> u32 {
+        clock32(self.min_clock)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct SetNextStepDir {
     pub oid: u32,
@@ -52,25 +117,255 @@ pub struct SetNextStepDir {
     pub req_clock: u64,
 }
 
+impl SetNextStepDir {
+    pub fn req_clock32(&self) -> u32 {
+        clock32(self.req_clock)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Command {
     QueueStep(QueueStep),
     SetNextStepDir(SetNextStepDir),
 }
 
+impl Command {
+    /// Stepper the command is for.
+    pub fn oid(&self) -> u32 {
+        match self {
+            Command::QueueStep(step) => step.oid,
+            Command::SetNextStepDir(dir) => dir.oid,
+        }
+    }
+
+    pub fn req_clock(&self) -> u64 {
+        match self {
+            Command::QueueStep(step) => step.req_clock,
+            Command::SetNextStepDir(dir) => dir.req_clock,
+        }
+    }
+
+    pub fn req_clock32(&self) -> u32 {
+        clock32(self.req_clock())
+    }
+}
+
+/// A step the MCU should take, sent alongside the commands of a
+/// [`StepCompressor`] built
+/// [`with_verify_steps`](StepCompressorConfig::with_verify_steps), so a
+/// simulator or hardware-in-the-loop test can check its stepping.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExpectedStep {
+    pub oid: u32,
+    /// Clock the step lands on when the `queue_step` it belongs to is
+    /// replayed.
+    pub clock: u64,
+    /// Stepper position (steps) after it.
+    pub position: i64,
+}
+
 pub trait CommandSink {
     fn push(&mut self, command: Command);
+
+    /// Take the steps the commands pushed so far should produce, in order,
+    /// each after the `queue_step` it belongs to. Ignored unless
+    /// overridden.
+    fn expect_step(&mut self, _step: ExpectedStep) {}
 }
 
 #[derive(Default, Debug)]
 pub struct RecordingSink {
     pub commands: Vec<Command>,
+    pub expected: Vec<ExpectedStep>,
 }
 
 impl CommandSink for RecordingSink {
     fn push(&mut self, command: Command) {
         self.commands.push(command);
     }
+
+    fn expect_step(&mut self, step: ExpectedStep) {
+        self.expected.push(step);
+    }
+}
+
+/// A [`CommandSink`] feeding a bounded channel.
+///
+/// Commands that do not fit in the channel are held back, in order, and
+/// the sink reports itself saturated. The caller should then stop
+/// appending steps and [`wait_ready`](Self::wait_ready), which blocks
+/// until the receiver has taken them. A caller that waits whenever the
+/// sink is saturated holds back at most one
+/// [`flush`](StepCompressor::flush) worth of commands.
+#[derive(Debug)]
+pub struct ChannelSink {
+    sender: SyncSender<Command>,
+    pending: VecDeque<Command>,
+    closed: bool,
+}
+
+impl ChannelSink {
+    /// A sink and the receiving end of its channel, which holds up to
+    /// `capacity` commands.
+    pub fn bounded(capacity: usize) -> (Self, Receiver<Command>) {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        let sink = Self {
+            sender,
+            pending: VecDeque::new(),
+            closed: false,
+        };
+        (sink, receiver)
+    }
+
+    /// Whether commands are waiting for room in the channel.
+    pub fn is_saturated(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Number of commands waiting for room in the channel.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Whether the receiver was dropped. Commands pushed since are discarded.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Send as many waiting commands as fit without blocking, returning
+    /// whether none are left.
+    pub fn try_drain(&mut self) -> Result<bool> {
+        while let Some(command) = self.pending.pop_front() {
+            match self.sender.try_send(command) {
+                Ok(()) => {}
+                Err(TrySendError::Full(command)) => {
+                    self.pending.push_front(command);
+                    return Ok(false);
+                }
+                Err(TrySendError::Disconnected(_)) => return Err(self.close()),
+            }
+        }
+        if self.closed {
+            return Err(StepCompressError::SinkClosed);
+        }
+        Ok(true)
+    }
+
+    /// Block until every waiting command is in the channel.
+    pub fn wait_ready(&mut self) -> Result<()> {
+        while let Some(command) = self.pending.pop_front() {
+            if self.sender.send(command).is_err() {
+                return Err(self.close());
+            }
+        }
+        if self.closed {
+            return Err(StepCompressError::SinkClosed);
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> StepCompressError {
+        self.closed = true;
+        self.pending.clear();
+        StepCompressError::SinkClosed
+    }
+}
+
+impl CommandSink for ChannelSink {
+    fn push(&mut self, command: Command) {
+        if self.closed {
+            return;
+        }
+        if !self.pending.is_empty() {
+            self.pending.push_back(command);
+            return;
+        }
+        match self.sender.try_send(command) {
+            Ok(()) => {}
+            Err(TrySendError::Full(command)) => self.pending.push_back(command),
+            Err(TrySendError::Disconnected(_)) => {
+                self.close();
+            }
+        }
+    }
+}
+
+/// A [`CommandSink`] feeding a bounded Tokio channel, for a flusher
+/// running as a task.
+///
+/// Like [`ChannelSink`], commands that do not fit are held back until
+/// [`ready`](Self::ready) sends them, which waits for room in the channel
+/// instead of blocking the thread.
+#[cfg(feature = "async")]
+#[derive(Debug)]
+pub struct AsyncChannelSink {
+    sender: tokio::sync::mpsc::Sender<Command>,
+    pending: VecDeque<Command>,
+    closed: bool,
+}
+
+#[cfg(feature = "async")]
+impl AsyncChannelSink {
+    /// A sink and the receiving end of its channel, which holds up to
+    /// `capacity` commands.
+    pub fn bounded(capacity: usize) -> (Self, tokio::sync::mpsc::Receiver<Command>) {
+        let (sender, receiver) = tokio::sync::mpsc::channel(capacity);
+        let sink = Self {
+            sender,
+            pending: VecDeque::new(),
+            closed: false,
+        };
+        (sink, receiver)
+    }
+
+    /// Whether commands are waiting for room in the channel.
+    pub fn is_saturated(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Number of commands waiting for room in the channel.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Whether the receiver was dropped. Commands pushed since are discarded.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Wait until every waiting command is in the channel.
+    pub async fn ready(&mut self) -> Result<()> {
+        while let Some(command) = self.pending.pop_front() {
+            if self.sender.send(command).await.is_err() {
+                self.closed = true;
+                self.pending.clear();
+            }
+        }
+        if self.closed {
+            return Err(StepCompressError::SinkClosed);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "async")]
+impl CommandSink for AsyncChannelSink {
+    fn push(&mut self, command: Command) {
+        use tokio::sync::mpsc::error::TrySendError;
+
+        if self.closed {
+            return;
+        }
+        if !self.pending.is_empty() {
+            self.pending.push_back(command);
+            return;
+        }
+        match self.sender.try_send(command) {
+            Ok(()) => {}
+            Err(TrySendError::Full(command)) => self.pending.push_back(command),
+            Err(TrySendError::Closed(_)) => self.closed = true,
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -83,7 +378,7 @@ pub struct PullHistoryStep {
     pub add: i16,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 struct HistoryEntry {
     first_clock: u64,
     last_clock: u64,
@@ -93,17 +388,136 @@ struct HistoryEntry {
     add: i16,
 }
 
-#[derive(Copy, Clone, Debug)]
-struct StepMove {
-    interval: u32,
-    count: u16,
-    add: i16,
+/// A fitted step sequence: `count` steps, the first `interval` ticks after
+/// the last step sent and each later one `add` ticks further apart than the
+/// one before. With move splitting the count may exceed what a single
+/// `queue_step` can carry.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct StepMove {
+    pub interval: u32,
+    pub count: u32,
+    pub add: i16,
+}
+
+/// Ticks after the last step sent that a queued step may be sent at.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Points {
+    pub minp: i64,
+    pub maxp: i64,
+}
+
+/// Queued step clocks a strategy fits the next sequence to.
+pub struct StepWindow<'a> {
+    clocks: &'a [u64],
+    last_step_clock: u64,
+    max_error: u32,
+}
+
+impl StepWindow<'_> {
+    /// Most steps the next sequence may cover.
+    pub fn len(&self) -> usize {
+        self.clocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clocks.is_empty()
+    }
+
+    pub fn max_error(&self) -> u32 {
+        self.max_error
+    }
+
+    /// Where step `idx` of the window may be sent: no later than its clock
+    /// and no earlier than `max_error`, or half the gap to the step before.
+    pub fn points(&self, idx: usize) -> Points {
+        let lsc = self.last_step_clock as i64;
+        let point = self.clocks[idx] as i64 - lsc;
+        let prevpoint = if idx > 0 {
+            self.clocks[idx - 1] as i64 - lsc
+        } else {
+            0
+        };
+        let max_error = ((point - prevpoint) / 2).min(self.max_error as i64);
+        Points {
+            minp: point - max_error,
+            maxp: point,
+        }
+    }
+
+    /// [`points`](Self::points) of every step in order, each computed from
+    /// the one before rather than from the window again.
+    pub fn iter_points(&self) -> impl Iterator<Item = Points> + '_ {
+        let lsc = self.last_step_clock as i64;
+        let max_error = self.max_error as i64;
+        let mut prevpoint = 0;
+        self.clocks.iter().map(move |&clock| {
+            let point = clock as i64 - lsc;
+            let max_error = ((point - prevpoint) / 2).min(max_error);
+            prevpoint = point;
+            Points {
+                minp: point - max_error,
+                maxp: point,
+            }
+        })
+    }
 }
 
-#[derive(Copy, Clone, Debug)]
-struct Points {
-    minp: i64,
-    maxp: i64,
+/// Fits queued step clocks to step sequences the MCU can replay.
+pub trait CompressionStrategy: Send {
+    /// Fit a sequence to the start of `window`, which is never empty. Each
+    /// step of the sequence must land within its [`StepWindow::points`].
+    fn compress(&self, window: &StepWindow) -> StepMove;
+}
+
+/// Klipper's algorithm: bisect the `add` that lets a sequence of steps
+/// with a changing interval reach furthest.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BisectAdd;
+
+/// Fit sequences of equally spaced steps only.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConstantInterval;
+
+/// Send each step on its own at its exact clock, e.g. for servo-like
+/// devices or debugging.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ExactSteps;
+
+/// Look ahead over the next queued steps rather than take the longest
+/// sequence each time as [`BisectAdd`] does. Dynamic programming picks the
+/// first of the fewest sequences covering up to 256 steps, where each
+/// sequence is a prefix of the one [`BisectAdd`] fits from its first step.
+///
+/// This is a heuristic, not an optimal fit: sequences other than those
+/// prefixes are never tried, and steps past the horizon are not planned
+/// for. Much costlier; for experimenting with fits.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LookaheadBisect;
+
+/// A [`CompressionStrategy`] to build a [`StepCompressor`] with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    /// [`BisectAdd`].
+    #[default]
+    BisectAdd,
+    /// [`ConstantInterval`].
+    ConstantInterval,
+    /// [`ExactSteps`].
+    ExactSteps,
+    /// [`LookaheadBisect`].
+    LookaheadBisect,
+}
+
+impl Compression {
+    pub fn strategy(self) -> Box<dyn CompressionStrategy> {
+        match self {
+            Compression::BisectAdd => Box::new(BisectAdd),
+            Compression::ConstantInterval => Box::new(ConstantInterval),
+            Compression::ExactSteps => Box::new(ExactSteps),
+            Compression::LookaheadBisect => Box::new(LookaheadBisect),
+        }
+    }
 }
 
 fn idiv_up(n: i64, d: i64) -> i64 {
@@ -114,11 +528,503 @@ fn idiv_down(n: i64, d: i64) -> i64 {
     if n >= 0 { n / d } else { (n - d + 1) / d }
 }
 
+impl CompressionStrategy for BisectAdd {
+    fn compress(&self, window: &StepWindow) -> StepMove {
+        let point = window.points(0);
+        let mut outer_mininterval = point.minp;
+        let mut outer_maxinterval = point.maxp;
+        let mut add: i64 = 0;
+        let mut minadd: i64 = -0x8000;
+        let mut maxadd: i64 = 0x7fff;
+        let mut bestinterval: i64 = 0;
+        let mut bestcount: i64 = 1;
+        let mut bestadd: i64 = 1;
+        let mut bestreach: i64 = i64::MIN;
+        let mut zerointerval: i64 = 0;
+        let mut zerocount: i64 = 0;
+
+        loop {
+            let fit = reach(window, add, outer_mininterval, outer_maxinterval);
+            let (count, interval) = (fit.count, fit.interval);
+            let Some(miss) = fit.miss else {
+                return StepMove {
+                    interval: interval as u32,
+                    count: count as u32,
+                    add: add as i16,
+                };
+            };
+            let nextpoint = miss.point;
+
+            let addfactor = count * (count - 1) / 2;
+            let reach = add * addfactor + interval * count;
+            if reach > bestreach || (reach == bestreach && interval > bestinterval) {
+                bestinterval = interval;
+                bestcount = count;
+                bestadd = add;
+                bestreach = reach;
+                if add == 0 {
+                    zerointerval = interval;
+                    zerocount = count;
+                }
+                if count > 0x200 {
+                    break;
+                }
+            }
+
+            let nextcount = count + 1;
+            let nextaddfactor = addfactor + count;
+            let nextreach = add * nextaddfactor + interval * nextcount;
+            if nextreach < nextpoint.minp {
+                minadd = add + 1;
+                outer_maxinterval = miss.maxinterval;
+            } else {
+                maxadd = add - 1;
+                outer_mininterval = miss.mininterval;
+            }
+
+            if count > 1 {
+                let errdelta = window.max_error() as i64 * QUADRATIC_DEV / (count * count);
+                if minadd < add - errdelta {
+                    minadd = add - errdelta;
+                }
+                if maxadd > add + errdelta {
+                    maxadd = add + errdelta;
+                }
+            }
+
+            let c = outer_maxinterval * nextcount;
+            if minadd * nextaddfactor < nextpoint.minp - c {
+                minadd = idiv_up(nextpoint.minp - c, nextaddfactor);
+            }
+            let c2 = outer_mininterval * nextcount;
+            if maxadd * nextaddfactor > nextpoint.maxp - c2 {
+                maxadd = idiv_down(nextpoint.maxp - c2, nextaddfactor);
+            }
+
+            if minadd > maxadd {
+                break;
+            }
+            add = maxadd - (maxadd - minadd) / 4;
+        }
+
+        if zerocount + zerocount / 16 >= bestcount {
+            return StepMove {
+                interval: zerointerval as u32,
+                count: zerocount as u32,
+                add: 0,
+            };
+        }
+
+        StepMove {
+            interval: bestinterval as u32,
+            count: bestcount as u32,
+            add: bestadd as i16,
+        }
+    }
+}
+
+/// How far a sequence with a given `add` fits into a [`StepWindow`].
+struct Reach {
+    /// Steps that fit.
+    count: i64,
+    /// Latest interval the first step may take for all of them to fit.
+    interval: i64,
+    /// The step after them, or `None` if the whole window fits.
+    miss: Option<Miss>,
+}
+
+/// The first step of a [`Reach`] that does not fit, and the intervals the
+/// steps up to it narrowed the sequence to.
+struct Miss {
+    point: Points,
+    mininterval: i64,
+    maxinterval: i64,
+}
+
+/// Narrow `mininterval..=maxinterval` over the steps of `window` until a
+/// sequence with `add` can no longer hit them all. This is where
+/// [`BisectAdd`] spends its time, once per `add` it tries, so it walks the
+/// points in order and keeps the running `add` term instead of recomputing
+/// either per step.
+///
+/// Each point costs a subtraction and a halving to work out, which is less
+/// than storing it: caching a window's points for the `add`s after the
+/// first made `flush_1m_steps` slower, and so did keeping the bounds as
+/// fractions to skip the division. What is left is the multiply, compare
+/// and the occasional division a tightened bound needs, per step walked.
+fn reach(window: &StepWindow, add: i64, mut mininterval: i64, mut maxinterval: i64) -> Reach {
+    let mut interval = maxinterval;
+    let mut count: i64 = 1;
+    let mut c: i64 = 0;
+    for point in window.iter_points().skip(1) {
+        // `add` times the steps before this one, summed: what the changing
+        // interval has moved it by
+        c += add * count;
+        count += 1;
+        if mininterval * count < point.minp - c {
+            mininterval = idiv_up(point.minp - c, count);
+        }
+        if maxinterval * count > point.maxp - c {
+            maxinterval = idiv_down(point.maxp - c, count);
+        }
+        if mininterval > maxinterval {
+            return Reach {
+                count: count - 1,
+                interval,
+                miss: Some(Miss {
+                    point,
+                    mininterval,
+                    maxinterval,
+                }),
+            };
+        }
+        interval = maxinterval;
+    }
+    Reach {
+        count,
+        interval,
+        miss: None,
+    }
+}
+
+impl CompressionStrategy for ConstantInterval {
+    fn compress(&self, window: &StepWindow) -> StepMove {
+        let point = window.points(0);
+        let (mut mininterval, mut maxinterval) = (point.minp, point.maxp);
+        let mut count = 1;
+        while count < window.len() {
+            let point = window.points(count);
+            let steps = count as i64 + 1;
+            let lo = idiv_up(point.minp, steps).max(mininterval);
+            let hi = idiv_down(point.maxp, steps).min(maxinterval);
+            if lo > hi || hi <= 0 {
+                break;
+            }
+            (mininterval, maxinterval) = (lo, hi);
+            count += 1;
+        }
+        StepMove {
+            interval: maxinterval as u32,
+            count: count as u32,
+            add: 0,
+        }
+    }
+}
+
+impl CompressionStrategy for ExactSteps {
+    fn compress(&self, window: &StepWindow) -> StepMove {
+        StepMove {
+            interval: window.points(0).maxp as u32,
+            count: 1,
+            add: 0,
+        }
+    }
+}
+
+impl CompressionStrategy for LookaheadBisect {
+    fn compress(&self, window: &StepWindow) -> StepMove {
+        let longest = BisectAdd.compress(window);
+        let len = window.len().min(LOOKAHEAD_HORIZON);
+        if longest.count as usize >= len {
+            return longest;
+        }
+
+        // `fewest[i]`: sequences needed for steps `i..len`, taking each to
+        // end on its last step's clock. Only the starts of the sequence
+        // BisectAdd fits from each step are tried.
+        let mut fewest = vec![0u32; len + 1];
+        let mut first = longest;
+        for start in (0..len).rev() {
+            let mv = if start == 0 {
+                longest
+            } else {
+                BisectAdd.compress(&StepWindow {
+                    clocks: &window.clocks[start..len],
+                    last_step_clock: window.clocks[start - 1],
+                    max_error: window.max_error,
+                })
+            };
+            let reach = (mv.count as usize).clamp(1, len - start);
+            // Longest first, so ties keep the longer sequence
+            let count = (1..=reach)
+                .rev()
+                .min_by_key(|&count| fewest[start + count])
+                .unwrap_or(1);
+            fewest[start] = fewest[start + count] + 1;
+            first = StepMove {
+                count: count as u32,
+                add: if count > 1 { mv.add } else { 0 },
+                ..mv
+            };
+        }
+        first
+    }
+}
+
+/// Work of a [`StepCompressor`] since it was built or its stats were last
+/// reset.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompressStats {
+    /// Steps that got past the step+dir+step filter into the queue.
+    pub steps_in: u64,
+    /// Steps sent in `queue_step` commands.
+    pub steps_out: u64,
+    /// `queue_step` commands sent.
+    pub queue_steps: u64,
+    /// Steps the step+dir+step filter dropped.
+    pub rollbacks: u64,
+    /// Calls to [`StepCompressor::flush`].
+    pub flushes: u64,
+    /// Sequences the strategy got wrong and were fitted again, with
+    /// [`with_error_recovery`](StepCompressorConfig::with_error_recovery).
+    #[serde(default)]
+    pub recoveries: u64,
+}
+
+impl CompressStats {
+    /// Steps per `queue_step` command, or 0 before any were sent.
+    pub fn average_count(&self) -> f64 {
+        if self.queue_steps == 0 {
+            return 0.0;
+        }
+        self.steps_out as f64 / self.queue_steps as f64
+    }
+}
+
+/// What a [`StepCompressor`] holds that has not been sent, from
+/// [`StepCompressor::status`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StepCompressorStatus {
+    /// Committed steps waiting in the queue for a flush.
+    pub queued_steps: usize,
+    /// Whether the newest step is held back, uncommitted, until the next
+    /// one shows it is not part of a step+dir+step.
+    pub pending_step: bool,
+    /// Steps not yet sent: the queue and any pending step.
+    pub buffered_steps: usize,
+    /// Clock of the earliest step not yet sent.
+    pub first_buffered_clock: Option<u64>,
+    /// Clock of the latest step not yet sent.
+    pub last_buffered_clock: Option<u64>,
+    /// Clock of the last step sent.
+    pub last_step_clock: u64,
+    /// Direction the MCU was last told to step in; `None` before any steps
+    /// were queued.
+    pub direction: Option<bool>,
+}
+
+/// Tuning of one [`StepCompressor`], built up from
+/// [`new`](Self::new) and turned into a compressor with
+/// [`build`](Self::build).
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StepCompressorConfig {
+    /// Furthest (ticks) a step may be sent from its requested time.
+    pub max_error: u32,
+    /// A step followed by a step back in the other direction within this
+    /// window (seconds) is dropped along with it, so the MCU never sees the
+    /// step+dir+step.
+    pub sds_filter_time: f64,
+    /// Step clocks the queue holds before it first grows.
+    pub queue_start_size: usize,
+    /// Furthest (ticks) a step may be scheduled past the last one sent
+    /// before the queue is flushed up to it.
+    pub clock_diff_max: u64,
+    /// Most history entries kept for
+    /// [`find_past_position`](StepCompressor::find_past_position); zero
+    /// keeps them all.
+    #[serde(default = "default_history_max_entries")]
+    pub history_max_entries: usize,
+    /// History kept (seconds) behind the newest step sent; zero keeps it
+    /// all.
+    #[serde(default = "default_history_time")]
+    pub history_time: f64,
+    /// Fit a sequence the strategy got wrong again, over fewer steps and
+    /// with a tighter `max_error`, instead of failing the flush.
+    #[serde(default)]
+    pub error_recovery: bool,
+    /// Convert step times to clocks in integer nanoseconds rather than
+    /// `f64` ticks, so the same steps give the same commands on any target.
+    #[serde(default)]
+    pub fixed_point_clock: bool,
+    /// Strategy step sequences are fitted with.
+    #[serde(default)]
+    pub compression: Compression,
+    /// Send an [`ExpectedStep`] to the sink for every step sent.
+    #[serde(default)]
+    pub verify_steps: bool,
+}
+
+fn default_history_max_entries() -> usize {
+    HISTORY_MAX_ENTRIES
+}
+
+fn default_history_time() -> f64 {
+    HISTORY_TIME
+}
+
+impl StepCompressorConfig {
+    /// Klipper's tuning with the given `max_error` (ticks).
+    pub fn new(max_error: u32) -> Self {
+        Self {
+            max_error,
+            sds_filter_time: SDS_FILTER_TIME,
+            queue_start_size: QUEUE_START_SIZE,
+            clock_diff_max: CLOCK_DIFF_MAX,
+            history_max_entries: HISTORY_MAX_ENTRIES,
+            history_time: HISTORY_TIME,
+            error_recovery: false,
+            fixed_point_clock: false,
+            compression: Compression::BisectAdd,
+            verify_steps: false,
+        }
+    }
+
+    /// Set the step+dir+step filter window (seconds); zero disables it.
+    pub fn with_sds_filter_time(mut self, seconds: f64) -> Self {
+        self.sds_filter_time = seconds.max(0.0);
+        self
+    }
+
+    /// Set the initial capacity of the step queue.
+    pub fn with_queue_start_size(mut self, size: usize) -> Self {
+        self.queue_start_size = size.max(1);
+        self
+    }
+
+    /// Set how far (ticks) a step may be scheduled past the last one sent.
+    pub fn with_clock_diff_max(mut self, ticks: u64) -> Self {
+        self.clock_diff_max = ticks.max(1);
+        self
+    }
+
+    /// Keep at most `entries` history entries; zero keeps them all.
+    pub fn with_history_max_entries(mut self, entries: usize) -> Self {
+        self.history_max_entries = entries;
+        self
+    }
+
+    /// Keep `seconds` of history behind the newest step sent; zero keeps it
+    /// all.
+    pub fn with_history_time(mut self, seconds: f64) -> Self {
+        self.history_time = seconds.max(0.0);
+        self
+    }
+
+    /// Recover from sequences the strategy gets wrong: fit the steps before
+    /// the bad one again with half the `max_error`, and so on down to one
+    /// step at a time, recording a [`CompressRecovery`] rather than
+    /// failing.
+    pub fn with_error_recovery(mut self, enabled: bool) -> Self {
+        self.error_recovery = enabled;
+        self
+    }
+
+    /// Convert step times to clocks in fixed point: each time is rounded to
+    /// the nanosecond and the rest done in integers against the MCU
+    /// frequency rounded to the hertz, so golden command streams match
+    /// across targets. Steps land on the nearest tick either way, but may
+    /// differ by one from the `f64` conversion.
+    pub fn with_fixed_point_clock(mut self, enabled: bool) -> Self {
+        self.fixed_point_clock = enabled;
+        self
+    }
+
+    /// Fit step sequences with the strategy `compression` names, e.g. to
+    /// compare strategies on the same steps.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Follow each `queue_step` sent with the [`ExpectedStep`]s it expands
+    /// to, replaying it as the MCU does, e.g. to cross-check firmware
+    /// stepping against the planned positions. Costs a call per step.
+    pub fn with_verify_steps(mut self, enabled: bool) -> Self {
+        self.verify_steps = enabled;
+        self
+    }
+
+    /// A compressor for stepper `oid` that sends to `sink`.
+    pub fn build<S: CommandSink>(self, oid: u32, sink: S) -> StepCompressor<S> {
+        StepCompressor {
+            oid,
+            config: self,
+            mcu_time_offset: 0.0,
+            mcu_freq: 1.0,
+            freq_known: false,
+            last_step_print_time: -0.5,
+            last_step_clock: 0,
+            sdir: -1,
+            invert_sdir: false,
+            next_step_clock: None,
+            next_step_dir: 0,
+            rollbacks: 0,
+            stats: CompressStats::default(),
+            strategy: self.compression.strategy(),
+            split_moves: false,
+            queue: Vec::with_capacity(self.queue_start_size),
+            queue_pos: 0,
+            last_position: 0,
+            history: VecDeque::new(),
+            phase: None,
+            recoveries: Vec::new(),
+            sink,
+        }
+    }
+}
+
+/// A sequence the strategy got wrong, and how it was replaced.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompressRecovery {
+    /// Clock of the last step sent before the sequence.
+    pub clock: u64,
+    /// Why the strategy's sequence was rejected.
+    pub error: StepCompressError,
+    /// Steps the replacement sequence covers.
+    pub count: u32,
+    /// `max_error` (ticks) the replacement was fitted with; zero if the
+    /// step was sent on its own at its exact clock.
+    pub max_error: u32,
+}
+
+/// Clock, direction, pending steps and history of a [`StepCompressor`],
+/// from [`StepCompressor::snapshot`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CompressorState {
+    oid: u32,
+    config: StepCompressorConfig,
+    mcu_time_offset: f64,
+    mcu_freq: f64,
+    /// Whether `mcu_freq` came from [`StepCompressor::set_time`].
+    #[serde(default)]
+    freq_known: bool,
+    last_step_print_time: f64,
+    last_step_clock: u64,
+    sdir: i32,
+    invert_sdir: bool,
+    next_step_clock: Option<u64>,
+    next_step_dir: i32,
+    rollbacks: u64,
+    #[serde(default)]
+    stats: CompressStats,
+    split_moves: bool,
+    /// Step clocks not yet sent.
+    queue: Vec<u64>,
+    last_position: i64,
+    /// Newest first.
+    history: Vec<HistoryEntry>,
+    #[serde(default)]
+    phase: Option<StepperPhase>,
+}
+
 pub struct StepCompressor<S: CommandSink> {
     oid: u32,
-    max_error: u32,
+    config: StepCompressorConfig,
     mcu_time_offset: f64,
     mcu_freq: f64,
+    // Whether `set_time` gave the real `mcu_freq`; until then, history is
+    // not expired by age
+    freq_known: bool,
     last_step_print_time: f64,
     last_step_clock: u64,
     // direction tracking
@@ -126,43 +1032,117 @@ pub struct StepCompressor<S: CommandSink> {
     invert_sdir: bool,
     next_step_clock: Option<u64>,
     next_step_dir: i32,
+    rollbacks: u64,
+    stats: CompressStats,
     // buffering
+    strategy: Box<dyn CompressionStrategy>,
+    split_moves: bool,
     queue: Vec<u64>,
     queue_pos: usize,
     // history
     last_position: i64,
     history: VecDeque<HistoryEntry>,
+    phase: Option<StepperPhase>,
+    recoveries: Vec<CompressRecovery>,
     // output
     sink: S,
 }
 
 impl<S: CommandSink> StepCompressor<S> {
+    /// A compressor with Klipper's tuning; see [`StepCompressorConfig`] to
+    /// change it.
     pub fn new(oid: u32, max_error: u32, sink: S) -> Self {
-        Self {
-            oid,
-            max_error,
-            mcu_time_offset: 0.0,
-            mcu_freq: 1.0,
-            last_step_print_time: -0.5,
-            last_step_clock: 0,
-            sdir: -1,
-            invert_sdir: false,
-            next_step_clock: None,
-            next_step_dir: 0,
-            queue: Vec::with_capacity(QUEUE_START_SIZE),
-            queue_pos: 0,
-            last_position: 0,
-            history: VecDeque::new(),
-            sink,
+        StepCompressorConfig::new(max_error).build(oid, sink)
+    }
+
+    pub fn oid(&self) -> u32 {
+        self.oid
+    }
+
+    pub fn config(&self) -> &StepCompressorConfig {
+        &self.config
+    }
+
+    /// Capture everything but the sink and compression strategy.
+    pub fn snapshot(&self) -> CompressorState {
+        CompressorState {
+            oid: self.oid,
+            config: self.config,
+            mcu_time_offset: self.mcu_time_offset,
+            mcu_freq: self.mcu_freq,
+            freq_known: self.freq_known,
+            last_step_print_time: self.last_step_print_time,
+            last_step_clock: self.last_step_clock,
+            sdir: self.sdir,
+            invert_sdir: self.invert_sdir,
+            next_step_clock: self.next_step_clock,
+            next_step_dir: self.next_step_dir,
+            rollbacks: self.rollbacks,
+            stats: self.stats,
+            split_moves: self.split_moves,
+            queue: self.queue[self.queue_pos..].to_vec(),
+            last_position: self.last_position,
+            history: self.history.iter().cloned().collect(),
+            phase: self.phase,
         }
     }
 
+    /// Rebuild a compressor from [`snapshot`](Self::snapshot) that sends
+    /// to `sink`. The strategy is the one the state's config names until
+    /// [`set_strategy`](Self::set_strategy) is called again.
+    pub fn from_state(state: CompressorState, sink: S) -> Self {
+        let mut compressor = state.config.build(state.oid, sink);
+        compressor.restore(state);
+        compressor
+    }
+
+    /// Return to the state captured by [`snapshot`](Self::snapshot), e.g.
+    /// to resume a paused job, keeping this compressor's sink and strategy.
+    /// Steps queued since are dropped without being sent.
+    pub fn restore(&mut self, state: CompressorState) {
+        let mut queue = state.queue;
+        queue.reserve(state.config.queue_start_size.saturating_sub(queue.len()));
+        self.oid = state.oid;
+        self.config = state.config;
+        self.mcu_time_offset = state.mcu_time_offset;
+        self.mcu_freq = state.mcu_freq;
+        self.freq_known = state.freq_known;
+        self.last_step_print_time = state.last_step_print_time;
+        self.last_step_clock = state.last_step_clock;
+        self.sdir = state.sdir;
+        self.invert_sdir = state.invert_sdir;
+        self.next_step_clock = state.next_step_clock;
+        self.next_step_dir = state.next_step_dir;
+        self.rollbacks = state.rollbacks;
+        self.stats = state.stats;
+        self.split_moves = state.split_moves;
+        self.queue = queue;
+        self.queue_pos = 0;
+        self.last_position = state.last_position;
+        self.history = state.history.into();
+        self.phase = state.phase;
+    }
+
     pub fn set_time(&mut self, time_offset: f64, mcu_freq: f64) {
         self.mcu_time_offset = time_offset;
         self.mcu_freq = mcu_freq;
+        self.freq_known = true;
         self.calc_last_step_print_time();
     }
 
+    /// Fit step sequences with `strategy` instead of the one the config
+    /// names.
+    pub fn set_strategy(&mut self, strategy: Box<dyn CompressionStrategy>) {
+        self.strategy = strategy;
+    }
+
+    /// Fit step sequences longer than 65535 steps, sending each as several
+    /// `queue_step` commands that continue its interval and add exactly.
+    /// Without it, sequences are cut at 65535 steps and the rest refitted.
+    pub fn set_split_moves(&mut self, split: bool) {
+        self.split_moves = split;
+    }
+
     pub fn set_invert_sdir(&mut self, invert: bool) {
         if self.invert_sdir != invert {
             self.invert_sdir = invert;
@@ -181,7 +1161,7 @@ impl<S: CommandSink> StepCompressor<S> {
     pub fn set_last_position(&mut self, clock: u64, last_position: i64) -> Result<()> {
         self.flush(u64::MAX)?;
         self.last_position = last_position;
-        self.history.push_front(HistoryEntry {
+        self.push_history(HistoryEntry {
             first_clock: clock,
             last_clock: clock,
             start_position: last_position,
@@ -200,19 +1180,41 @@ impl<S: CommandSink> StepCompressor<S> {
         Ok(())
     }
 
+    /// [`reset`](Self::reset) to a 32-bit MCU clock, extended to 64 bits
+    /// around the current last step clock.
+    pub fn reset_clock32(&mut self, last_step_clock32: u32) -> Result<()> {
+        self.reset(clock64(last_step_clock32, self.last_step_clock))
+    }
+
+    /// Extend a 32-bit MCU clock to 64 bits around the last step clock.
+    pub fn clock64(&self, clock32: u32) -> u64 {
+        clock64(clock32, self.last_step_clock)
+    }
+
+    /// [`find_past_position`](Self::find_past_position) at a 32-bit MCU
+    /// clock.
+    pub fn find_past_position_clock32(&self, clock32: u32) -> i64 {
+        self.find_past_position(self.clock64(clock32))
+    }
+
     pub fn append(&mut self, sdir: i32, print_time: f64, step_time: f64) -> Result<()> {
-        // Calculate step clock
-        let offset = print_time - self.last_step_print_time;
-        let rel_sc = (step_time + offset) * self.mcu_freq;
-        let step_clock = self.last_step_clock + rel_sc as u64;
+        let step_clock = if self.config.fixed_point_clock {
+            self.fixed_step_clock(print_time, step_time)
+        } else {
+            let offset = print_time - self.last_step_print_time;
+            let rel_sc = (step_time + offset) * self.mcu_freq;
+            self.last_step_clock + rel_sc as u64
+        };
 
         if let Some(prev_clock) = self.next_step_clock {
             if sdir != self.next_step_dir {
                 let diff = step_clock as i64 - prev_clock as i64;
-                if (diff as f64) < SDS_FILTER_TIME * self.mcu_freq {
+                if self.within_sds_filter(diff) {
                     // rollback last step to avoid rapid step+dir+step
                     self.next_step_clock = None;
                     self.next_step_dir = sdir;
+                    self.rollbacks += 1;
+                    self.stats.rollbacks += 1;
                     return Ok(());
                 }
             }
@@ -224,6 +1226,28 @@ impl<S: CommandSink> StepCompressor<S> {
         Ok(())
     }
 
+    /// Steps dropped by [`append`](Self::append) to avoid a step, a
+    /// direction change and a step back in quick succession.
+    pub fn rollbacks(&self) -> u64 {
+        self.rollbacks
+    }
+
+    pub fn stats(&self) -> &CompressStats {
+        &self.stats
+    }
+
+    /// Start counting [`stats`](Self::stats) from zero again.
+    pub fn reset_stats(&mut self) {
+        self.stats = CompressStats::default();
+    }
+
+    /// Recoveries since the last call, oldest first, for the caller to
+    /// warn about. Only the latest few are kept; `stats().recoveries`
+    /// counts them all.
+    pub fn take_recoveries(&mut self) -> Vec<CompressRecovery> {
+        std::mem::take(&mut self.recoveries)
+    }
+
     pub fn commit(&mut self) -> Result<()> {
         if self.next_step_clock.is_some() {
             self.queue_append()?;
@@ -231,7 +1255,21 @@ impl<S: CommandSink> StepCompressor<S> {
         Ok(())
     }
 
+    /// Drop the buffered steps after `clock`, and the pending step from
+    /// [`append`](Self::append) wherever it falls, returning how many were
+    /// dropped, so that motion can be replanned from `clock`. Steps already
+    /// sent stay sent.
+    pub fn truncate(&mut self, clock: u64) -> usize {
+        let queued = &self.queue[self.queue_pos..];
+        let keep = self.queue_pos + queued.partition_point(|&step| step <= clock);
+        let dropped = self.queue.len() - keep + self.next_step_clock.is_some() as usize;
+        self.queue.truncate(keep);
+        self.next_step_clock = None;
+        dropped
+    }
+
     pub fn flush(&mut self, move_clock: u64) -> Result<()> {
+        self.stats.flushes += 1;
         if let Some(next_clock) = self.next_step_clock
             && move_clock >= next_clock
         {
@@ -299,6 +1337,16 @@ impl<S: CommandSink> StepCompressor<S> {
         res
     }
 
+    /// Entries in the history.
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Bytes allocated for the history.
+    pub fn history_memory(&self) -> usize {
+        self.history.capacity() * std::mem::size_of::<HistoryEntry>()
+    }
+
     pub fn expire_history(&mut self, end_clock: u64) {
         while let Some(back) = self.history.back() {
             if back.last_clock > end_clock {
@@ -312,201 +1360,183 @@ impl<S: CommandSink> StepCompressor<S> {
         self.last_position
     }
 
-    pub fn last_step_clock(&self) -> u64 {
-        self.last_step_clock
+    /// Track the microstep phase of the motor with `phase`.
+    pub fn set_phase(&mut self, phase: StepperPhase) {
+        self.phase = Some(phase);
     }
 
-    pub fn into_sink(self) -> S {
-        self.sink
+    pub fn phase(&self) -> Option<&StepperPhase> {
+        self.phase.as_ref()
     }
 
-    // --- internals ---
-    fn calc_last_step_print_time(&mut self) {
-        let lsc = self.last_step_clock as f64;
-        self.last_step_print_time = self.mcu_time_offset + (lsc - 0.5) / self.mcu_freq;
+    /// The phase being tracked, e.g. to [`align`](StepperPhase::align) it
+    /// to the driver after homing.
+    pub fn phase_mut(&mut self) -> Option<&mut StepperPhase> {
+        self.phase.as_mut()
     }
 
-    fn minmax_point(&self, idx: usize) -> Points {
-        let lsc = self.last_step_clock as i64;
-        let point = self.queue[idx] as i64 - lsc;
-        let prevpoint = if idx > self.queue_pos {
-            self.queue[idx - 1] as i64 - lsc
-        } else {
-            0
-        };
-        let mut max_error = (point - prevpoint) / 2;
-        if max_error > self.max_error as i64 {
-            max_error = self.max_error as i64;
-        }
-        Points {
-            minp: point - max_error,
-            maxp: point,
-        }
+    /// Microstep phase of the motor at `clock`, as of the steps sent, or
+    /// `None` if no phase is tracked. Clocks older than the history report
+    /// the phase at the oldest position it still has.
+    pub fn phase_at(&self, clock: u64) -> Option<u32> {
+        let phase = self.phase.as_ref()?;
+        Some(phase.phase(self.find_past_position(clock)))
     }
 
-    fn compress_bisect_add(&self) -> StepMove {
-        let queue_len = self.queue.len();
-        let qlast = (self.queue_pos + 65_535).min(queue_len);
-        let point = self.minmax_point(self.queue_pos);
-        let mut outer_mininterval = point.minp;
-        let mut outer_maxinterval = point.maxp;
-        let mut add: i64 = 0;
-        let mut minadd: i64 = -0x8000;
-        let mut maxadd: i64 = 0x7fff;
-        let mut bestinterval: i64 = 0;
-        let mut bestcount: i64 = 1;
-        let mut bestadd: i64 = 1;
-        let mut bestreach: i64 = i64::MIN;
-        let mut zerointerval: i64 = 0;
-        let mut zerocount: i64 = 0;
-
-        loop {
-            let mut nextpoint;
-            let mut nextmininterval = outer_mininterval;
-            let mut nextmaxinterval = outer_maxinterval;
-            let mut interval = nextmaxinterval;
-            let mut nextcount: i64 = 1;
-            loop {
-                nextcount += 1;
-                if self.queue_pos + (nextcount as usize) > qlast {
-                    let count = nextcount - 1;
-                    return StepMove {
-                        interval: interval as u32,
-                        count: count as u16,
-                        add: add as i16,
-                    };
-                }
-                nextpoint = self.minmax_point(self.queue_pos + nextcount as usize - 1);
-                let nextaddfactor = nextcount * (nextcount - 1) / 2;
-                let c = add * nextaddfactor;
-                if nextmininterval * nextcount < nextpoint.minp - c {
-                    nextmininterval = idiv_up(nextpoint.minp - c, nextcount);
-                }
-                if nextmaxinterval * nextcount > nextpoint.maxp - c {
-                    nextmaxinterval = idiv_down(nextpoint.maxp - c, nextcount);
-                }
-                if nextmininterval > nextmaxinterval {
-                    break;
-                }
-                interval = nextmaxinterval;
-            }
-
-            let count = nextcount - 1;
-            let addfactor = count * (count - 1) / 2;
-            let reach = add * addfactor + interval * count;
-            if reach > bestreach || (reach == bestreach && interval > bestinterval) {
-                bestinterval = interval;
-                bestcount = count;
-                bestadd = add;
-                bestreach = reach;
-                if add == 0 {
-                    zerointerval = interval;
-                    zerocount = count;
-                }
-                if count > 0x200 {
-                    break;
-                }
-            }
+    pub fn last_step_clock(&self) -> u64 {
+        self.last_step_clock
+    }
 
-            let nextaddfactor = nextcount * (nextcount - 1) / 2;
-            let nextreach = add * nextaddfactor + interval * nextcount;
-            if nextreach < nextpoint.minp {
-                minadd = add + 1;
-                outer_maxinterval = nextmaxinterval;
-            } else {
-                maxadd = add - 1;
-                outer_mininterval = nextmininterval;
-            }
+    /// Steps buffered but not yet sent, and the clocks they span, e.g. to
+    /// pick the next flush deadline or report how far a stepper lags.
+    pub fn status(&self) -> StepCompressorStatus {
+        let queued = &self.queue[self.queue_pos..];
+        let first = queued.first().copied().or(self.next_step_clock);
+        let last = self.next_step_clock.or(queued.last().copied());
+        StepCompressorStatus {
+            queued_steps: queued.len(),
+            pending_step: self.next_step_clock.is_some(),
+            buffered_steps: queued.len() + self.next_step_clock.is_some() as usize,
+            first_buffered_clock: first,
+            last_buffered_clock: last,
+            last_step_clock: self.last_step_clock,
+            direction: (self.sdir >= 0).then_some(self.sdir != 0),
+        }
+    }
 
-            if count > 1 {
-                let errdelta = self.max_error as i64 * QUADRATIC_DEV / (count * count);
-                if minadd < add - errdelta {
-                    minadd = add - errdelta;
-                }
-                if maxadd > add + errdelta {
-                    maxadd = add + errdelta;
-                }
-            }
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
 
-            let c = outer_maxinterval * nextcount;
-            if minadd * nextaddfactor < nextpoint.minp - c {
-                minadd = idiv_up(nextpoint.minp - c, nextaddfactor);
-            }
-            let c2 = outer_mininterval * nextcount;
-            if maxadd * nextaddfactor > nextpoint.maxp - c2 {
-                maxadd = idiv_down(nextpoint.maxp - c2, nextaddfactor);
-            }
+    pub fn sink_mut(&mut self) -> &mut S {
+        &mut self.sink
+    }
 
-            if minadd > maxadd {
-                break;
-            }
-            add = maxadd - (maxadd - minadd) / 4;
+    pub fn into_sink(self) -> S {
+        self.sink
+    }
+
+    // --- internals ---
+    fn max_count(&self) -> usize {
+        if self.split_moves {
+            MAX_SPLIT_COUNT
+        } else {
+            MAX_QUEUE_STEP_COUNT as usize
         }
+    }
 
-        if zerocount + zerocount / 16 >= bestcount {
-            return StepMove {
-                interval: zerointerval as u32,
-                count: zerocount as u16,
-                add: 0,
-            };
+    /// Clock nearest `print_time + step_time`, and no earlier than the last
+    /// step sent, worked out in integer nanoseconds.
+    fn fixed_step_clock(&self, print_time: f64, step_time: f64) -> u64 {
+        let nanos = nanos(print_time) + nanos(step_time) - nanos(self.mcu_time_offset);
+        let freq = self.mcu_freq.round() as i128;
+        let clock = (nanos * freq + FIXED_POINT_SCALE / 2).div_euclid(FIXED_POINT_SCALE);
+        clock.clamp(self.last_step_clock as i128, u64::MAX as i128) as u64
+    }
+
+    /// Whether a step `diff` ticks after the previous one is close enough
+    /// for the step+dir+step filter to drop both.
+    fn within_sds_filter(&self, diff: i64) -> bool {
+        if self.config.fixed_point_clock {
+            let freq = self.mcu_freq.round() as i128;
+            let window = nanos(self.config.sds_filter_time) * freq / FIXED_POINT_SCALE;
+            (diff as i128) < window
+        } else {
+            (diff as f64) < self.config.sds_filter_time * self.mcu_freq
         }
+    }
 
-        StepMove {
-            interval: bestinterval as u32,
-            count: bestcount as u16,
-            add: bestadd as i16,
+    fn calc_last_step_print_time(&mut self) {
+        let lsc = self.last_step_clock as f64;
+        self.last_step_print_time = self.mcu_time_offset + (lsc - 0.5) / self.mcu_freq;
+    }
+
+    fn window(&self) -> StepWindow<'_> {
+        self.window_with(self.max_count(), self.config.max_error)
+    }
+
+    /// The next `len` queued steps at most, each allowed `max_error` ticks.
+    fn window_with(&self, len: usize, max_error: u32) -> StepWindow<'_> {
+        let qlast = (self.queue_pos + len).min(self.queue.len());
+        StepWindow {
+            clocks: &self.queue[self.queue_pos..qlast],
+            last_step_clock: self.last_step_clock,
+            max_error,
         }
     }
 
-    fn check_line(&self, mv: StepMove) -> Result<()> {
-        if mv.count == 0
-            || (mv.interval == 0 && mv.add == 0 && mv.count > 1)
-            || mv.interval >= 0x8000_0000
-        {
-            return Err(StepCompressError::InvalidSequence {
-                interval: mv.interval,
-                count: mv.count,
-                add: mv.add,
-            });
+    /// Fit the next sequence, recovering from a bad one if configured to.
+    fn next_move(&mut self) -> Result<StepMove> {
+        let window = self.window();
+        let mv = self.strategy.compress(&window);
+        let error = match check_line(&window, mv) {
+            Ok(()) => return Ok(mv),
+            Err(error) if self.config.error_recovery => error,
+            Err(error) => return Err(error),
+        };
+
+        // Only the steps before the bad one are known to fit
+        let mut len = match error {
+            StepCompressError::PointOutOfRange { index, .. }
+            | StepCompressError::IntervalOverflow { index, .. } => index as usize - 1,
+            _ => mv.count as usize,
         }
+        .clamp(1, window.len());
+        let mut max_error = self.config.max_error / 2;
+        let (mv, max_error) = loop {
+            if max_error == 0 {
+                let window = self.window_with(1, 0);
+                let mv = ExactSteps.compress(&window);
+                check_line(&window, mv)?;
+                break (mv, 0);
+            }
+            let window = self.window_with(len, max_error);
+            let mv = self.strategy.compress(&window);
+            if check_line(&window, mv).is_ok() {
+                break (mv, max_error);
+            }
+            len = (len / 2).max(1);
+            max_error /= 2;
+        };
 
-        let mut interval = mv.interval as i64;
-        let mut p: i64 = 0;
-        for i in 0..mv.count {
-            let point = self.minmax_point(self.queue_pos + i as usize);
-            p += interval;
-            if p < point.minp || p > point.maxp {
-                return Err(StepCompressError::PointOutOfRange {
-                    index: i + 1,
-                    value: p,
-                    min: point.minp,
-                    max: point.maxp,
-                    interval: mv.interval,
-                    count: mv.count,
-                    add: mv.add,
-                });
-            }
-            if interval >= 0x8000_0000 {
-                return Err(StepCompressError::IntervalOverflow {
-                    index: i + 1,
-                    interval: mv.interval,
-                    count: mv.count,
-                    add: mv.add,
-                });
-            }
-            interval += mv.add as i64;
+        self.stats.recoveries += 1;
+        if self.recoveries.len() == MAX_PENDING_RECOVERIES {
+            self.recoveries.remove(0);
         }
-        Ok(())
+        self.recoveries.push(CompressRecovery {
+            clock: self.last_step_clock,
+            error,
+            count: mv.count,
+            max_error,
+        });
+        Ok(mv)
     }
 
     fn add_move(&mut self, first_clock: u64, mv: &StepMove) {
-        let addfactor = mv.count as u64 * (mv.count as u64 - 1) / 2;
-        let ticks = mv.add as i64 * addfactor as i64 + mv.interval as i64 * (mv.count as i64 - 1);
+        let mut first_clock = first_clock;
+        let mut interval = mv.interval as i64;
+        let mut remaining = mv.count;
+        loop {
+            let count = remaining.min(MAX_QUEUE_STEP_COUNT);
+            self.add_queue_step(first_clock, interval as u32, count as u16, mv.add);
+            remaining -= count;
+            if remaining == 0 {
+                return;
+            }
+            // The next command continues the sequence where this one stopped
+            interval += mv.add as i64 * count as i64;
+            first_clock = self.last_step_clock + interval as u64;
+        }
+    }
+
+    fn add_queue_step(&mut self, first_clock: u64, interval: u32, count: u16, add: i16) {
+        let addfactor = count as u64 * (count as u64 - 1) / 2;
+        let ticks = add as i64 * addfactor as i64 + interval as i64 * (count as i64 - 1);
         let last_clock = first_clock + ticks as u64;
 
         let mut req_clock = self.last_step_clock;
         let min_clock = req_clock;
-        if mv.count == 1 && first_clock >= self.last_step_clock + CLOCK_DIFF_MAX {
+        if count == 1 && first_clock >= self.last_step_clock + self.config.clock_diff_max {
             req_clock = first_clock;
         }
 
@@ -514,29 +1544,68 @@ impl<S: CommandSink> StepCompressor<S> {
             oid: self.oid,
             first_clock,
             last_clock,
-            interval: mv.interval,
-            count: mv.count,
-            add: mv.add,
+            interval,
+            count,
+            add,
             req_clock,
             min_clock,
         }));
         self.last_step_clock = last_clock;
+        self.stats.queue_steps += 1;
+        self.stats.steps_out += count as u64;
 
         let step_count = if self.sdir != 0 {
-            mv.count as i32
+            count as i32
         } else {
-            -(mv.count as i32)
+            -(count as i32)
         };
         let entry = HistoryEntry {
             first_clock,
             last_clock,
             start_position: self.last_position,
             step_count,
-            interval: mv.interval,
-            add: mv.add,
+            interval,
+            add,
         };
+        if self.config.verify_steps {
+            self.expect_steps(&entry);
+        }
         self.last_position += step_count as i64;
+        self.push_history(entry);
+    }
+
+    /// Send the sink each step of `entry` as the MCU replays it: after the
+    /// first, `interval` grows by `add` and the next step is that many
+    /// ticks later.
+    fn expect_steps(&mut self, entry: &HistoryEntry) {
+        let dir = entry.step_count.signum() as i64;
+        let mut clock = entry.first_clock as i64;
+        let mut interval = entry.interval as i64;
+        for step in 1..=entry.step_count.unsigned_abs() as i64 {
+            self.sink.expect_step(ExpectedStep {
+                oid: self.oid,
+                clock: clock as u64,
+                position: entry.start_position + dir * step,
+            });
+            interval += entry.add as i64;
+            clock += interval;
+        }
+    }
+
+    /// Record `entry` as the newest history, then drop whatever the
+    /// configured retention no longer keeps.
+    fn push_history(&mut self, entry: HistoryEntry) {
+        let newest = entry.last_clock;
         self.history.push_front(entry);
+        if self.config.history_max_entries > 0 {
+            self.history.truncate(self.config.history_max_entries);
+        }
+        if self.config.history_time > 0.0 && self.freq_known {
+            let span = (self.config.history_time * self.mcu_freq) as u64;
+            if let Some(end_clock) = newest.checked_sub(span) {
+                self.expire_history(end_clock);
+            }
+        }
     }
 
     fn queue_flush(&mut self, move_clock: u64) -> Result<()> {
@@ -545,8 +1614,7 @@ impl<S: CommandSink> StepCompressor<S> {
         }
 
         while self.last_step_clock < move_clock {
-            let mv = self.compress_bisect_add();
-            self.check_line(mv)?;
+            let mv = self.next_move()?;
             let first_clock = self.last_step_clock + mv.interval as u64;
             self.add_move(first_clock, &mv);
 
@@ -586,8 +1654,14 @@ impl<S: CommandSink> StepCompressor<S> {
             .next_step_clock
             .take()
             .expect("pending step clock should exist");
-        self.queue_flush(step_clock.saturating_sub(CLOCK_DIFF_MAX).saturating_add(1))?;
-        if step_clock >= self.last_step_clock + CLOCK_DIFF_MAX {
+        self.queue_flush(
+            step_clock
+                .saturating_sub(self.config.clock_diff_max)
+                .saturating_add(1),
+        )?;
+        if step_clock >= self.last_step_clock + self.config.clock_diff_max {
+            // Like the MCU's 32-bit clock, the interval wraps on long gaps;
+            // req_clock makes it unambiguous
             let mv = StepMove {
                 interval: (step_clock - self.last_step_clock) as u32,
                 count: 1,
@@ -603,8 +1677,9 @@ impl<S: CommandSink> StepCompressor<S> {
 
     fn queue_append_extend(&mut self) -> Result<()> {
         let in_use = self.queue.len() - self.queue_pos;
-        if in_use > 65_535 + 2_000 {
-            let flush = self.queue[self.queue.len() - 65_535] - self.last_step_clock;
+        let max_count = self.max_count();
+        if in_use > max_count + 2_000 {
+            let flush = self.queue[self.queue.len() - max_count] - self.last_step_clock;
             self.queue_flush(self.last_step_clock + flush)?;
         }
 
@@ -612,7 +1687,7 @@ impl<S: CommandSink> StepCompressor<S> {
             self.queue.drain(0..self.queue_pos);
             self.queue_pos = 0;
         } else if self.queue.len() == self.queue.capacity() {
-            let new_cap = (self.queue.capacity().max(QUEUE_START_SIZE)) * 2;
+            let new_cap = (self.queue.capacity().max(self.config.queue_start_size)) * 2;
             self.queue.reserve(new_cap - self.queue.len());
         }
         Ok(())
@@ -626,7 +1701,8 @@ impl<S: CommandSink> StepCompressor<S> {
             .next_step_clock
             .take()
             .expect("pending step clock should exist");
-        if step_clock >= self.last_step_clock + CLOCK_DIFF_MAX {
+        self.stats.steps_in += 1;
+        if step_clock >= self.last_step_clock + self.config.clock_diff_max {
             self.next_step_clock = Some(step_clock);
             return self.queue_append_far();
         }
@@ -638,6 +1714,54 @@ impl<S: CommandSink> StepCompressor<S> {
     }
 }
 
+/// Check every step of `mv` lands within its [`StepWindow::points`].
+fn check_line(window: &StepWindow, mv: StepMove) -> Result<()> {
+    if mv.count == 0
+        || (mv.interval == 0 && mv.add == 0 && mv.count > 1)
+        || mv.interval >= 0x8000_0000
+    {
+        return Err(StepCompressError::InvalidSequence {
+            interval: mv.interval,
+            count: mv.count,
+            add: mv.add,
+        });
+    }
+
+    if mv.count as usize > window.len() {
+        return Err(StepCompressError::InvalidSequence {
+            interval: mv.interval,
+            count: mv.count,
+            add: mv.add,
+        });
+    }
+    let mut interval = mv.interval as i64;
+    let mut p: i64 = 0;
+    for (i, point) in (0..mv.count).zip(window.iter_points()) {
+        p += interval;
+        if p < point.minp || p > point.maxp {
+            return Err(StepCompressError::PointOutOfRange {
+                index: i + 1,
+                value: p,
+                min: point.minp,
+                max: point.maxp,
+                interval: mv.interval,
+                count: mv.count,
+                add: mv.add,
+            });
+        }
+        if interval >= 0x8000_0000 {
+            return Err(StepCompressError::IntervalOverflow {
+                index: i + 1,
+                interval: mv.interval,
+                count: mv.count,
+                add: mv.add,
+            });
+        }
+        interval += mv.add as i64;
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -672,6 +1796,33 @@ mod tests {
         assert_eq!(total, 5);
     }
 
+    #[test]
+    fn counts_compression_work() {
+        let mut sc = compressor_with_sink();
+        sc.set_time(0.0, 1_000_000.0);
+        for i in 0..100 {
+            sc.append(1, 0.0, 0.001 + i as f64 * 0.0001).unwrap();
+        }
+        // A step straight back is filtered out together with the last one
+        sc.append(0, 0.0, 0.010_95).unwrap();
+        sc.commit().unwrap();
+        sc.flush(u64::MAX).unwrap();
+
+        let stats = *sc.stats();
+        let commands = queue_steps(&sc.sink().commands);
+        assert_eq!(stats.steps_in, 99);
+        assert_eq!(stats.steps_out, 99);
+        assert_eq!(stats.rollbacks, 1);
+        assert_eq!(stats.flushes, 1);
+        assert_eq!(stats.queue_steps, commands.len() as u64);
+        assert!(stats.average_count() > 10.0, "{stats:?}");
+
+        sc.reset_stats();
+        assert_eq!(*sc.stats(), CompressStats::default());
+        assert_eq!(sc.stats().average_count(), 0.0);
+        assert_eq!(sc.rollbacks(), 1);
+    }
+
     #[test]
     fn sds_filter_rolls_back_direction_flip() {
         let mut sc = compressor_with_sink();
@@ -693,6 +1844,176 @@ mod tests {
         assert_eq!(total, 0);
     }
 
+    #[test]
+    fn sds_filter_window_is_configurable() {
+        let run = |config: StepCompressorConfig| {
+            let mut sc = config.build(1, RecordingSink::default());
+            sc.set_time(0.0, 1_000_000.0);
+            // A step, then one back 200us later
+            sc.append(1, 0.0, 0.000_1).unwrap();
+            sc.append(0, 0.0, 0.000_3).unwrap();
+            sc.commit().unwrap();
+            sc.flush(u64::MAX).unwrap();
+            let steps = queue_steps(&sc.sink().commands).len();
+            (sc.rollbacks(), steps)
+        };
+        assert_eq!(run(StepCompressorConfig::new(25)), (1, 0));
+        let narrow = StepCompressorConfig::new(25).with_sds_filter_time(0.000_1);
+        assert_eq!(run(narrow), (0, 2));
+
+        let config = StepCompressorConfig::new(25)
+            .with_queue_start_size(16)
+            .with_clock_diff_max(1 << 20);
+        let sc = config.build(3, RecordingSink::default());
+        let restored = StepCompressor::from_state(sc.snapshot(), RecordingSink::default());
+        assert_eq!(restored.config(), &config);
+        assert_eq!(restored.config().sds_filter_time, SDS_FILTER_TIME);
+    }
+
+    #[test]
+    fn fixed_point_clock_rounds_to_the_nearest_tick() {
+        let clocks = |config: StepCompressorConfig| {
+            let mut sc = config.build(1, RecordingSink::default());
+            sc.set_time(0.25, 16_000_000.0);
+            sc.set_strategy(Box::new(ExactSteps));
+            for i in 1..=500 {
+                // Accelerating, so most steps fall between ticks
+                let step_time = 0.000_1 * i as f64 - 0.000_000_07 * (i * i) as f64;
+                sc.append(1, 1.0, step_time).unwrap();
+            }
+            // A step back inside the filter window drops the last one
+            sc.append(0, 1.0, 0.032_6).unwrap();
+            sc.commit().unwrap();
+            sc.flush(u64::MAX).unwrap();
+            let steps = queue_steps(&sc.sink().commands);
+            steps
+                .iter()
+                .map(|step| step.first_clock)
+                .collect::<Vec<_>>()
+        };
+
+        let fixed = clocks(StepCompressorConfig::new(25).with_fixed_point_clock(true));
+        assert_eq!(fixed.len(), 499);
+        for (i, &clock) in (1..).zip(&fixed) {
+            // (0.75 s + 100 µs * i - 70 ns * i²) at 16 MHz, exactly
+            let exact = 12_000_000.0 + 1600.0 * i as f64 - 1.12 * (i * i) as f64;
+            assert_eq!(clock, exact.round() as u64, "step {i}");
+        }
+        let float = clocks(StepCompressorConfig::new(25));
+        for (fixed, float) in fixed.iter().zip(&float) {
+            assert!(fixed.abs_diff(*float) <= 1, "{fixed} vs {float}");
+        }
+    }
+
+    #[test]
+    fn status_reports_buffered_steps() {
+        let mut sc = compressor_with_sink();
+        sc.set_time(0.0, 1_000_000.0);
+        assert_eq!(sc.status(), StepCompressorStatus::default());
+
+        for i in 1..=10 {
+            sc.append(1, 0.0, i as f64 * 0.001).unwrap();
+        }
+        let status = sc.status();
+        assert_eq!(status.queued_steps, 9);
+        assert!(status.pending_step);
+        assert_eq!(status.buffered_steps, 10);
+        assert_eq!(status.first_buffered_clock, Some(1000));
+        assert_eq!(status.last_buffered_clock, Some(10_000));
+        assert_eq!(status.direction, Some(true));
+
+        // The sequence sent runs on past the flush clock
+        sc.flush(5000).unwrap();
+        let status = sc.status();
+        assert_eq!(status.queued_steps, 0);
+        assert_eq!(status.buffered_steps, 1);
+        assert_eq!(status.first_buffered_clock, Some(10_000));
+        assert_eq!(status.last_step_clock, 9000);
+
+        sc.flush(u64::MAX).unwrap();
+        let status = sc.status();
+        assert_eq!(status.buffered_steps, 0);
+        assert_eq!(status.last_buffered_clock, None);
+    }
+
+    #[test]
+    fn truncate_drops_unsent_steps_past_the_clock() {
+        let mut sc = compressor_with_sink();
+        sc.set_time(0.0, 1_000_000.0);
+        for i in 1..=10 {
+            sc.append(1, 0.0, i as f64 * 0.001).unwrap();
+        }
+
+        // Steps at 8000 and 9000 are queued and 10000 is pending
+        assert_eq!(sc.truncate(7000), 3);
+        let status = sc.status();
+        assert!(!status.pending_step);
+        assert_eq!(status.queued_steps, 7);
+        assert_eq!(status.last_buffered_clock, Some(7000));
+        assert_eq!(sc.truncate(7000), 0);
+
+        // Sent steps stay sent
+        sc.flush(u64::MAX).unwrap();
+        assert_eq!(sc.last_step_clock(), 7000);
+        assert_eq!(sc.truncate(0), 0);
+
+        // The replanned motion carries on from there
+        sc.append(1, 0.0, 0.012).unwrap();
+        sc.flush(u64::MAX).unwrap();
+        assert_eq!(sc.last_step_clock(), 12_000);
+        assert_eq!(queue_steps(&sc.sink().commands).len(), 2);
+    }
+
+    #[test]
+    fn history_is_bounded_without_manual_expiry() {
+        let steps = |config: StepCompressorConfig| {
+            let mut sc = config.build(1, RecordingSink::default());
+            sc.set_time(0.0, 1_000_000.0);
+            // Alternating directions keep every step its own history entry
+            for i in 1..=200u64 {
+                sc.append((i % 2) as i32, 0.0, i as f64 * 0.01).unwrap();
+                sc.commit().unwrap();
+            }
+            sc.flush(u64::MAX).unwrap();
+            sc
+        };
+
+        let unbounded = steps(
+            StepCompressorConfig::new(10)
+                .with_history_max_entries(0)
+                .with_history_time(0.0),
+        );
+        assert_eq!(unbounded.history_len(), 200);
+
+        let limited = steps(StepCompressorConfig::new(10).with_history_max_entries(16));
+        assert_eq!(limited.history_len(), 16);
+        assert!(limited.history_memory() < unbounded.history_memory());
+
+        // The default keeps 30s; these steps span 2s, so drop to 0.5s
+        let recent = steps(StepCompressorConfig::new(10).with_history_time(0.5));
+        assert_eq!(recent.history_len(), 50);
+
+        // Without the MCU's frequency, no clock span is known to be that long
+        let mut early = StepCompressorConfig::new(10)
+            .with_history_time(0.5)
+            .build(1, RecordingSink::default());
+        for i in 1..=200u64 {
+            early.append((i % 2) as i32, 0.0, i as f64).unwrap();
+            early.commit().unwrap();
+        }
+        early.flush(u64::MAX).unwrap();
+        assert_eq!(early.history_len(), 200);
+
+        // Recent positions are still found
+        let clock = 1_950_000;
+        for sc in [&limited, &recent] {
+            assert_eq!(
+                sc.find_past_position(clock),
+                unbounded.find_past_position(clock)
+            );
+        }
+    }
+
     #[test]
     fn history_lookup_matches_offset() {
         let mut sc = compressor_with_sink();
@@ -705,4 +2026,489 @@ mod tests {
         let pos = sc.find_past_position(sc.last_step_clock());
         assert_eq!(pos, 2);
     }
+
+    #[test]
+    fn phase_follows_steps_through_direction_changes() {
+        let mut sc = StepCompressor::new(1, 10, RecordingSink::default());
+        sc.set_time(0.0, 1_000_000.0);
+        assert_eq!(sc.phase_at(0), None);
+        sc.set_phase(StepperPhase::new(200, 16));
+        // 100 steps forward then 130 back, a millisecond apart
+        for i in 1..=230 {
+            let dir = if i <= 100 { 1 } else { 0 };
+            sc.append(dir, 0.0, i as f64 * 0.001).unwrap();
+        }
+        sc.commit().unwrap();
+        sc.flush(u64::MAX).unwrap();
+        assert_eq!(sc.last_position(), -30);
+
+        let clock = |step: u64| step * 1000;
+        assert_eq!(sc.phase_at(clock(40)), Some(40));
+        assert_eq!(sc.phase_at(clock(100)), Some(100 - 64));
+        assert_eq!(sc.phase_at(clock(200)), Some(0));
+        assert_eq!(sc.phase_at(clock(230)), Some(64 - 30));
+
+        // After homing the driver reports phase 10 where we stopped
+        sc.phase_mut().unwrap().align(-30, 10);
+        assert_eq!(sc.phase_at(clock(230)), Some(10));
+        assert_eq!(sc.phase_at(clock(220)), Some(20));
+        let restored = StepCompressor::from_state(sc.snapshot(), RecordingSink::default());
+        assert_eq!(restored.phase_at(clock(220)), Some(20));
+    }
+
+    fn queue_steps(commands: &[Command]) -> Vec<&QueueStep> {
+        commands
+            .iter()
+            .filter_map(|cmd| match cmd {
+                Command::QueueStep(step) => Some(step),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn restores_mid_stream_from_snapshot() {
+        let step_time = |i: u64| (1000 * i + i * i) as f64 / 1_000_000.0;
+        let mut sc = StepCompressor::new(7, 10, RecordingSink::default());
+        sc.set_time(0.0, 1_000_000.0);
+        for i in 1..=300 {
+            sc.append(1, 0.0, step_time(i)).unwrap();
+        }
+        sc.flush(100_000).unwrap();
+        sc.set_last_position(100_000, 42).unwrap();
+
+        let json = serde_json::to_string(&sc.snapshot()).unwrap();
+        let state: CompressorState = serde_json::from_str(&json).unwrap();
+        let mut restored = StepCompressor::from_state(state, RecordingSink::default());
+        assert_eq!(restored.oid(), 7);
+        assert_eq!(restored.last_position(), sc.last_position());
+        assert_eq!(
+            restored.find_past_position(50_000),
+            sc.find_past_position(50_000)
+        );
+
+        let sent = sc.sink().commands.len();
+        for sc in [&mut sc, &mut restored] {
+            for i in 301..=400 {
+                sc.append(0, 0.0, step_time(i)).unwrap();
+            }
+            sc.commit().unwrap();
+            sc.flush(u64::MAX).unwrap();
+        }
+        assert_eq!(sc.sink().commands[sent..], restored.sink().commands[..]);
+        assert_eq!(restored.last_position(), sc.last_position());
+    }
+
+    fn exact_steps<S: CommandSink>(sink: S, steps: u64) -> StepCompressor<S> {
+        let mut sc = StepCompressor::new(0, 10, sink);
+        sc.set_time(0.0, 1_000_000.0);
+        sc.set_strategy(Box::new(ExactSteps));
+        for i in 1..=steps {
+            sc.append(1, 0.0, (100 * i) as f64 / 1_000_000.0).unwrap();
+        }
+        sc.flush(u64::MAX).unwrap();
+        sc
+    }
+
+    #[test]
+    fn channel_sink_holds_back_what_does_not_fit() {
+        let expected = exact_steps(RecordingSink::default(), 20)
+            .into_sink()
+            .commands;
+        let (sink, receiver) = ChannelSink::bounded(4);
+        let mut sc = exact_steps(sink, 20);
+        assert!(sc.sink().is_saturated());
+        assert_eq!(sc.sink().pending(), expected.len() - 4);
+
+        let mut received: Vec<_> = receiver.try_iter().collect();
+        assert_eq!(received.len(), 4);
+        assert!(!sc.sink_mut().try_drain().unwrap());
+        assert_eq!(sc.sink().pending(), expected.len() - 8);
+
+        let reader = std::thread::spawn(move || receiver.iter().collect::<Vec<_>>());
+        sc.sink_mut().wait_ready().unwrap();
+        assert!(!sc.sink().is_saturated());
+        drop(sc);
+        received.extend(reader.join().unwrap());
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn channel_sink_reports_a_dropped_receiver() {
+        let (sink, receiver) = ChannelSink::bounded(4);
+        drop(receiver);
+        let mut sc = exact_steps(sink, 20);
+        assert!(sc.sink().is_closed());
+        assert!(!sc.sink().is_saturated());
+        assert!(matches!(
+            sc.sink_mut().wait_ready(),
+            Err(StepCompressError::SinkClosed)
+        ));
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_channel_sink_waits_for_room() {
+        let expected = exact_steps(RecordingSink::default(), 20)
+            .into_sink()
+            .commands;
+        let (sink, mut receiver) = AsyncChannelSink::bounded(4);
+        let reader = tokio::spawn(async move {
+            let mut received = Vec::new();
+            while let Some(command) = receiver.recv().await {
+                received.push(command);
+            }
+            received
+        });
+        let mut sc = exact_steps(sink, 20);
+        assert!(sc.sink().is_saturated());
+        sc.sink_mut().ready().await.unwrap();
+        assert!(!sc.sink().is_saturated());
+        drop(sc);
+        assert_eq!(reader.await.unwrap(), expected);
+    }
+
+    /// Fits every step at the first step's interval, plus `overshoot`, while
+    /// allowed at least `sloppy_above` ticks of error, and bisects otherwise.
+    struct Sloppy {
+        sloppy_above: u32,
+        overshoot: u32,
+    }
+
+    impl CompressionStrategy for Sloppy {
+        fn compress(&self, window: &StepWindow) -> StepMove {
+            if window.max_error() < self.sloppy_above {
+                return BisectAdd.compress(window);
+            }
+            StepMove {
+                interval: window.points(0).maxp as u32 + self.overshoot,
+                count: window.len() as u32,
+                add: 0,
+            }
+        }
+    }
+
+    fn accelerating(
+        strategy: Sloppy,
+        recover: bool,
+    ) -> (StepCompressor<RecordingSink>, Result<()>) {
+        let mut sc = StepCompressorConfig::new(10)
+            .with_error_recovery(recover)
+            .build(0, RecordingSink::default());
+        sc.set_time(0.0, 1_000_000.0);
+        sc.set_strategy(Box::new(strategy));
+        for i in 1..=40 {
+            sc.append(1, 0.0, 0.001 * (i as f64).sqrt()).unwrap();
+        }
+        let result = sc.flush(u64::MAX);
+        (sc, result)
+    }
+
+    #[test]
+    fn recovers_from_a_bad_sequence() {
+        let sloppy = || Sloppy {
+            sloppy_above: 10,
+            overshoot: 0,
+        };
+        let (_, result) = accelerating(sloppy(), false);
+        assert!(matches!(
+            result,
+            Err(StepCompressError::PointOutOfRange { .. })
+        ));
+
+        let (mut sc, result) = accelerating(sloppy(), true);
+        result.unwrap();
+        assert_eq!(sc.last_position(), 40);
+        // Only the latest recoveries are kept
+        assert!(sc.stats().recoveries > MAX_PENDING_RECOVERIES as u64);
+        let recoveries = sc.take_recoveries();
+        assert_eq!(recoveries.len(), MAX_PENDING_RECOVERIES);
+        assert!(recoveries.is_sorted_by_key(|recovery| recovery.clock));
+        for recovery in &recoveries {
+            assert!(matches!(
+                recovery.error,
+                StepCompressError::PointOutOfRange { index, .. } if recovery.count < index
+            ));
+            assert_eq!(recovery.max_error, 5);
+        }
+        assert!(sc.take_recoveries().is_empty());
+
+        // Every step still lands within max_error of its clock
+        let mut clocks = Vec::new();
+        for step in queue_steps(&sc.sink().commands) {
+            let mut clock = step.first_clock;
+            let mut interval = step.interval as i64;
+            for _ in 0..step.count {
+                clocks.push(clock);
+                interval += step.add as i64;
+                clock = (clock as i64 + interval) as u64;
+            }
+        }
+        for (i, clock) in clocks.into_iter().enumerate() {
+            let exact = (1000.0 * ((i + 1) as f64).sqrt()) as u64;
+            assert!(
+                clock <= exact + 1 && clock + 11 >= exact,
+                "step {i}: {clock} vs {exact}"
+            );
+        }
+    }
+
+    #[test]
+    fn recovers_with_single_steps_as_a_last_resort() {
+        let wrong = Sloppy {
+            sloppy_above: 0,
+            overshoot: 1,
+        };
+        let (mut sc, result) = accelerating(wrong, true);
+        result.unwrap();
+        assert_eq!(sc.last_position(), 40);
+        assert!(
+            queue_steps(&sc.sink().commands)
+                .iter()
+                .all(|step| step.count == 1)
+        );
+        assert_eq!(sc.stats().recoveries, 40);
+        let recoveries = sc.take_recoveries();
+        assert_eq!(recoveries.len(), MAX_PENDING_RECOVERIES);
+        assert!(recoveries.iter().all(|recovery| recovery.max_error == 0));
+    }
+
+    #[test]
+    fn load_keeps_sink_and_strategy() {
+        let step_time = |i: u64| (100 * i) as f64 / 1_000_000.0;
+        let mut sc = StepCompressor::new(3, 10, RecordingSink::default());
+        sc.set_time(0.0, 1_000_000.0);
+        sc.set_strategy(Box::new(ExactSteps));
+        for i in 1..=50 {
+            sc.append(1, 0.0, step_time(i)).unwrap();
+        }
+        sc.commit().unwrap();
+        let paused = sc.snapshot();
+
+        // Steps queued after the pause are abandoned
+        for i in 51..=80 {
+            sc.append(-1, 0.0, step_time(i)).unwrap();
+        }
+        sc.restore(paused.clone());
+        assert_eq!(sc.snapshot(), paused);
+
+        let sent = sc.sink().commands.len();
+        for i in 51..=60 {
+            sc.append(1, 0.0, step_time(i)).unwrap();
+        }
+        sc.flush(u64::MAX).unwrap();
+        assert_eq!(sc.last_position(), 60);
+        // Exact steps send one step per command
+        assert!(
+            queue_steps(&sc.sink().commands[sent..])
+                .iter()
+                .all(|step| step.count == 1)
+        );
+    }
+
+    #[test]
+    fn splits_long_sequences_with_exact_continuation() {
+        const STEPS: u64 = 150_000;
+        let run = |split: bool| {
+            let mut sc = StepCompressor::new(1, 10, RecordingSink::default());
+            sc.set_time(0.0, 1_000_000.0);
+            sc.set_split_moves(split);
+            for i in 1..=STEPS {
+                sc.append(1, 0.0, (i * 100) as f64 / 1_000_000.0).unwrap();
+            }
+            sc.commit().unwrap();
+            sc.flush(u64::MAX).unwrap();
+            assert_eq!(sc.last_position(), STEPS as i64);
+            sc.into_sink()
+        };
+
+        let sink = run(true);
+        let steps = queue_steps(&sink.commands);
+        assert_eq!(
+            steps.iter().map(|s| s.count).collect::<Vec<_>>(),
+            [65_535, 65_535, 18_930]
+        );
+        // Each command picks up where the previous one stopped
+        let mut clock = 0;
+        for step in &steps {
+            assert_eq!(step.first_clock, clock + step.interval as u64);
+            assert_eq!(step.min_clock, clock);
+            let mut interval = step.interval as i64;
+            clock = step.first_clock;
+            for _ in 1..step.count {
+                interval += step.add as i64;
+                clock += interval as u64;
+            }
+            assert_eq!(clock, step.last_clock);
+        }
+        assert!((clock as i64 - (STEPS * 100) as i64).abs() <= 10);
+
+        let sink = run(false);
+        let total: u64 = queue_steps(&sink.commands)
+            .iter()
+            .map(|s| s.count as u64)
+            .sum();
+        assert_eq!(total, STEPS);
+    }
+
+    #[test]
+    fn strategies_replay_accelerating_steps() {
+        // Steps speeding up from 1000 to ~500 ticks apart
+        let mut clocks = Vec::new();
+        let mut clock = 0.0;
+        for i in 0..200 {
+            clock += 1000.0 / (1.0 + i as f64 / 200.0);
+            clocks.push(clock);
+        }
+        let run = |strategy: Box<dyn CompressionStrategy>| {
+            let mut sc = StepCompressor::new(1, 20, RecordingSink::default());
+            sc.set_time(0.0, 1_000_000.0);
+            sc.set_strategy(strategy);
+            for clock in &clocks {
+                sc.append(1, 0.0, clock / 1_000_000.0).unwrap();
+            }
+            sc.commit().unwrap();
+            sc.flush(u64::MAX).unwrap();
+            let steps: Vec<_> = queue_steps(&sc.into_sink().commands)
+                .into_iter()
+                .cloned()
+                .collect();
+            let total: u32 = steps.iter().map(|s| s.count as u32).sum();
+            assert_eq!(total, 200);
+            steps
+        };
+
+        let bisect = run(Box::new(BisectAdd));
+        let constant = run(Box::new(ConstantInterval));
+        assert!(constant.iter().all(|s| s.add == 0));
+        assert!(bisect.len() < constant.len());
+        let exact = run(Box::new(ExactSteps));
+        assert_eq!(exact.len(), 200);
+        for (step, clock) in exact.iter().zip(&clocks) {
+            assert_eq!(step.count, 1);
+            assert_eq!(step.first_clock, clock.round() as u64);
+        }
+        let lookahead = run(Box::new(LookaheadBisect));
+        assert!(lookahead.len() <= bisect.len());
+    }
+
+    #[test]
+    fn verify_steps_replays_each_queue_step() {
+        let config = StepCompressorConfig::new(20).with_verify_steps(true);
+        let mut sc = config.build(4, RecordingSink::default());
+        sc.set_time(0.0, 1_000_000.0);
+        let mut clocks = Vec::new();
+        let mut clock = 0.0;
+        for i in 0..100 {
+            clock += 1000.0 / (1.0 + i as f64 / 100.0);
+            clocks.push(clock);
+            sc.append(1, 0.0, clock / 1_000_000.0).unwrap();
+        }
+        for i in 1..=20 {
+            sc.append(0, 0.0, (clock + 1000.0 * i as f64) / 1_000_000.0)
+                .unwrap();
+        }
+        sc.commit().unwrap();
+        sc.flush(u64::MAX).unwrap();
+
+        let sink = sc.sink();
+        assert_eq!(sink.expected.len(), 120);
+        assert!(sink.expected.iter().all(|step| step.oid == 4));
+        for (step, clock) in sink.expected.iter().zip(&clocks) {
+            assert!((step.clock as f64 - clock).abs() <= 21.0);
+        }
+        let positions: Vec<_> = sink.expected.iter().map(|step| step.position).collect();
+        assert_eq!(positions[..100], (1..=100).collect::<Vec<_>>());
+        assert_eq!(positions[100..], (80..100).rev().collect::<Vec<_>>());
+        assert_eq!(sc.last_position(), 80);
+        let last = queue_steps(&sink.commands).last().unwrap().last_clock;
+        assert_eq!(sink.expected.last().unwrap().clock, last);
+
+        // Off unless asked for
+        let mut sc = compressor_with_sink();
+        sc.append(1, 0.0, 0.001).unwrap();
+        sc.commit().unwrap();
+        sc.flush(u64::MAX).unwrap();
+        assert!(sc.sink().expected.is_empty());
+    }
+
+    #[test]
+    fn config_selects_the_strategy() {
+        let config = StepCompressorConfig::new(20).with_compression(Compression::ExactSteps);
+        let mut sc = config.build(1, RecordingSink::default());
+        sc.set_time(0.0, 1_000_000.0);
+        for i in 1..=10 {
+            sc.append(1, 0.0, (100 * i) as f64 / 1_000_000.0).unwrap();
+        }
+        sc.commit().unwrap();
+        let state = sc.snapshot();
+        sc.flush(u64::MAX).unwrap();
+        assert!(
+            queue_steps(&sc.sink().commands)
+                .iter()
+                .all(|step| step.count == 1)
+        );
+
+        // The strategy comes back with the config
+        let mut restored = StepCompressor::from_state(state, RecordingSink::default());
+        restored.flush(u64::MAX).unwrap();
+        assert_eq!(queue_steps(&restored.sink().commands).len(), 10);
+    }
+
+    #[test]
+    fn iter_points_matches_points() {
+        let clocks = [1_000, 1_010, 3_000, 3_001, 9_000];
+        let window = StepWindow {
+            clocks: &clocks,
+            last_step_clock: 900,
+            max_error: 300,
+        };
+        let points: Vec<_> = (0..clocks.len()).map(|i| window.points(i)).collect();
+        assert_eq!(window.iter_points().collect::<Vec<_>>(), points);
+    }
+
+    #[test]
+    fn clock32_round_trips_across_the_wrap() {
+        let wrap = 1u64 << 32;
+        assert_eq!(clock32(wrap + 5), 5);
+        assert_eq!(clock64(5, wrap - 10), wrap + 5);
+        assert_eq!(clock64(0xffff_fff6, wrap + 5), wrap - 10);
+        assert_eq!(clock64(100, 3 * wrap + 50), 3 * wrap + 100);
+        // Half a wrap either side of the reference
+        assert_eq!(clock64(0x8000_0000, wrap), wrap - 0x8000_0000);
+        assert_eq!(clock64(0x7fff_ffff, wrap), wrap + 0x7fff_ffff);
+        // Nothing comes before clock zero
+        assert_eq!(clock64(0xffff_fff0, 0), 0xffff_fff0);
+    }
+
+    #[test]
+    fn steps_across_the_wrap_keep_their_high_bits() {
+        let freq = 1000.0;
+        let start = 0xffff_fff0u64;
+        let mut sc = StepCompressor::new(1, 0, RecordingSink::default());
+        sc.set_time(0.0, freq);
+        sc.reset_clock32(clock32(start)).unwrap();
+        assert_eq!(sc.last_step_clock(), start);
+        for i in 1..=40 {
+            sc.append(1, 0.0, (start + 2 * i) as f64 / freq).unwrap();
+        }
+        sc.commit().unwrap();
+        sc.flush(u64::MAX).unwrap();
+        assert_eq!(sc.last_step_clock(), start + 80);
+        assert_eq!(sc.find_past_position_clock32(clock32(start + 40)), 20);
+
+        let commands = sc.into_sink().commands;
+        let steps = queue_steps(&commands);
+        assert!(steps[0].first_clock < 1 << 32);
+        assert!(steps[steps.len() - 1].last_clock > 1 << 32);
+        let mut reference = start;
+        for step in steps {
+            let first = clock64(step.first_clock32(), reference);
+            assert_eq!(first, step.first_clock);
+            assert_eq!(clock64(step.req_clock32(), reference), step.req_clock);
+            reference = clock64(step.last_clock32(), first);
+            assert_eq!(reference, step.last_clock);
+        }
+        assert_eq!(reference, start + 80);
+    }
 }
@@ -0,0 +1,130 @@
+//! Microstep phase of a stepper motor.
+//!
+//! A stepper driver energises its coils in a cycle of four full steps, so
+//! the motor is in the same electrical phase every `4 * microsteps` steps.
+//! Which of those microsteps it is on is what endstop-phase homing and
+//! stallguard alignment compare against the driver's own microstep
+//! counter. [`StepperPhase`] turns a step position into that phase; attached
+//! to a [`StepCompressor`](crate::step_compressor::StepCompressor) with
+//! [`set_phase`](crate::step_compressor::StepCompressor::set_phase), it
+//! reports the phase at any clock still in the step history. Positions
+//! count steps in the direction of the motor's dir pin, so they follow the
+//! driver through direction changes.
+
+use serde::{Deserialize, Serialize};
+
+/// Full steps in one electrical cycle of the coils.
+pub const FULL_STEPS_PER_CYCLE: u32 = 4;
+
+/// Maps step positions to the microstep phase of a motor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StepperPhase {
+    full_steps_per_rotation: u32,
+    microsteps: u32,
+    /// Phase at step position zero.
+    offset: u32,
+}
+
+impl StepperPhase {
+    /// The phase of a motor with `full_steps_per_rotation` full steps per
+    /// rotation driven at `microsteps`, starting at phase zero.
+    ///
+    /// # Panics
+    ///
+    /// If `microsteps` is zero or `full_steps_per_rotation` is not a
+    /// positive multiple of four.
+    pub fn new(full_steps_per_rotation: u32, microsteps: u32) -> Self {
+        assert!(microsteps > 0, "microsteps must be positive");
+        assert!(
+            full_steps_per_rotation > 0
+                && full_steps_per_rotation.is_multiple_of(FULL_STEPS_PER_CYCLE),
+            "full_steps_per_rotation must be a positive multiple of {FULL_STEPS_PER_CYCLE}",
+        );
+        Self {
+            full_steps_per_rotation,
+            microsteps,
+            offset: 0,
+        }
+    }
+
+    pub fn microsteps(&self) -> u32 {
+        self.microsteps
+    }
+
+    /// Distinct phases, the steps in one electrical cycle.
+    pub fn phases(&self) -> u32 {
+        FULL_STEPS_PER_CYCLE * self.microsteps
+    }
+
+    /// Steps in one rotation of the motor.
+    pub fn steps_per_rotation(&self) -> u32 {
+        self.full_steps_per_rotation * self.microsteps
+    }
+
+    /// Phase, from zero to [`phases`](Self::phases), at step `position`.
+    pub fn phase(&self, position: i64) -> u32 {
+        (position + self.offset as i64).rem_euclid(self.phases() as i64) as u32
+    }
+
+    /// Step, from zero to [`steps_per_rotation`](Self::steps_per_rotation),
+    /// of the rotation at `position`, where the rotation starts at position
+    /// zero.
+    pub fn rotation_step(&self, position: i64) -> u32 {
+        position.rem_euclid(self.steps_per_rotation() as i64) as u32
+    }
+
+    /// Whether the motor is at a full step, where every coil current is at
+    /// a whole step of its cycle, at `position`.
+    pub fn is_full_step(&self, position: i64) -> bool {
+        self.phase(position).is_multiple_of(self.microsteps)
+    }
+
+    /// Take the phase at `position` to be `phase`, e.g. as read from the
+    /// driver's microstep counter there. Phases outside one cycle wrap.
+    pub fn align(&mut self, position: i64, phase: u32) {
+        let phases = self.phases() as i64;
+        self.offset = (phase as i64 - position).rem_euclid(phases) as u32;
+    }
+
+    /// [`align`](Self::align) to a driver counter that counts `resolution`
+    /// per electrical cycle rather than [`phases`](Self::phases), like the
+    /// 1024 of a Trinamic `MSCNT`. Rounds to the nearest microstep.
+    pub fn align_counter(&mut self, position: i64, counter: u32, resolution: u32) {
+        let phase = (counter as u64 * self.phases() as u64 + resolution as u64 / 2)
+            / resolution.max(1) as u64;
+        self.align(position, phase as u32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phase_wraps_each_cycle_both_ways() {
+        let mut phase = StepperPhase::new(200, 16);
+        assert_eq!(phase.phases(), 64);
+        assert_eq!(phase.steps_per_rotation(), 3200);
+        assert_eq!(phase.phase(0), 0);
+        assert_eq!(phase.phase(65), 1);
+        assert_eq!(phase.phase(-1), 63);
+        assert_eq!(phase.rotation_step(-1), 3199);
+        assert!(phase.is_full_step(-16));
+        assert!(!phase.is_full_step(8));
+
+        phase.align(1000, 5);
+        assert_eq!(phase.phase(1000), 5);
+        assert_eq!(phase.phase(995), 0);
+        assert_eq!(phase.phase(984), 53);
+
+        // MSCNT 512 of 1024 is halfway through the cycle
+        phase.align_counter(-7, 512, 1024);
+        assert_eq!(phase.phase(-7), 32);
+    }
+
+    #[test]
+    #[should_panic(expected = "multiple of 4")]
+    fn rejects_partial_cycles_per_rotation() {
+        StepperPhase::new(198, 16);
+    }
+}
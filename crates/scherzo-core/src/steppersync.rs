@@ -0,0 +1,460 @@
+//! Step command ordering across the steppers of one MCU.
+//!
+//! Each [`StepCompressor`] produces its commands independently, but the MCU
+//! receives them over a single link and holds `queue_step` commands in a
+//! fixed number of move queue slots. [`StepperSync`] flushes every
+//! compressor to the same clock, merges their commands in `req_clock`
+//! order, and tracks when each slot frees up so no `queue_step` is sent
+//! before the MCU has room for it. This follows `steppersync.c` in Klipper.
+//!
+//! Klipper marks commands that take a slot with a non-zero `min_clock`;
+//! here every [`QueueStep`](crate::step_compressor::QueueStep) takes one and
+//! [`SetNextStepDir`](crate::step_compressor::SetNextStepDir) never does.
+//!
+//! [`StepperSync`] owns its compressors. When each compressor is driven
+//! from its own thread instead, they all push into clones of one
+//! [`SharedSink`], which merges their commands the same way.
+
+use crate::step_compressor::{Command, CommandSink, Result, StepCompressor};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap, VecDeque},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// Commands a compressor has released but [`StepperSync`] has not sent.
+#[derive(Debug, Default)]
+pub struct PendingCommands {
+    commands: VecDeque<Command>,
+}
+
+impl PendingCommands {
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+}
+
+impl CommandSink for PendingCommands {
+    fn push(&mut self, command: Command) {
+        self.commands.push_back(command);
+    }
+}
+
+/// Step compressors sharing one MCU, and the sink their merged commands go
+/// to.
+pub struct StepperSync<S> {
+    steppers: Vec<StepCompressor<PendingCommands>>,
+    /// Clock at which each move queue slot frees up.
+    move_clocks: BinaryHeap<Reverse<u64>>,
+    sink: S,
+}
+
+impl<S: CommandSink> StepperSync<S> {
+    /// Sync for an MCU with `move_num` move queue slots (at least one).
+    pub fn new(move_num: usize, sink: S) -> Self {
+        Self {
+            steppers: Vec::new(),
+            move_clocks: (0..move_num.max(1)).map(|_| Reverse(0)).collect(),
+            sink,
+        }
+    }
+
+    /// Add a compressor for stepper `oid`, returning its index.
+    pub fn add_stepper(&mut self, oid: u32, max_error: u32) -> usize {
+        self.steppers.push(StepCompressor::new(
+            oid,
+            max_error,
+            PendingCommands::default(),
+        ));
+        self.steppers.len() - 1
+    }
+
+    pub fn stepper(&self, index: usize) -> &StepCompressor<PendingCommands> {
+        &self.steppers[index]
+    }
+
+    pub fn stepper_mut(&mut self, index: usize) -> &mut StepCompressor<PendingCommands> {
+        &mut self.steppers[index]
+    }
+
+    pub fn steppers_mut(&mut self) -> &mut [StepCompressor<PendingCommands>] {
+        &mut self.steppers
+    }
+
+    /// Set the print time to MCU clock conversion of every stepper.
+    pub fn set_time(&mut self, time_offset: f64, mcu_freq: f64) {
+        for stepper in &mut self.steppers {
+            stepper.set_time(time_offset, mcu_freq);
+        }
+    }
+
+    /// Clock at which the next move queue slot frees up.
+    pub fn next_free_clock(&self) -> u64 {
+        self.move_clocks.peek().map_or(0, |&Reverse(clock)| clock)
+    }
+
+    /// Compress every stepper's steps up to `move_clock`, then send their
+    /// commands to the sink in `req_clock` order. Each `queue_step` is given
+    /// the clock its move queue slot frees up as its `min_clock`.
+    pub fn flush(&mut self, move_clock: u64) -> Result<()> {
+        for stepper in &mut self.steppers {
+            stepper.flush(move_clock)?;
+        }
+        loop {
+            let next = self
+                .steppers
+                .iter()
+                .enumerate()
+                .filter_map(|(index, stepper)| {
+                    let command = stepper.sink().commands.front()?;
+                    Some((command.req_clock(), index))
+                })
+                .min();
+            let Some((req_clock, index)) = next else {
+                break;
+            };
+            let front = &self.steppers[index].sink().commands[0];
+            let uses_slot = matches!(front, Command::QueueStep(_));
+            if uses_slot && req_clock > move_clock {
+                break;
+            }
+            let mut command = self.steppers[index]
+                .sink_mut()
+                .commands
+                .pop_front()
+                .expect("front command was just found");
+            if let Command::QueueStep(step) = &mut command {
+                let next_available = self.next_free_clock();
+                self.move_clocks.pop();
+                self.move_clocks.push(Reverse(step.min_clock));
+                step.min_clock = next_available;
+            }
+            self.sink.push(command);
+        }
+        Ok(())
+    }
+
+    /// Forget step history older than `clock`.
+    pub fn expire_history(&mut self, clock: u64) {
+        for stepper in &mut self.steppers {
+            stepper.expire_history(clock);
+        }
+    }
+
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
+
+    pub fn into_sink(self) -> S {
+        self.sink
+    }
+}
+
+/// Commands of several compressors, queued by OID, that any of them may
+/// push into at once.
+///
+/// Give each compressor a clone. Once every compressor has been flushed to
+/// a clock, [`flush`](Self::flush) to the same clock sends what they
+/// pushed on in `req_clock` order across OIDs, holding back a
+/// `queue_step` due after it as [`StepperSync::flush`] does. Commands
+/// pushed while a flush runs wait for the next one, and the queues are
+/// locked a batch at a time, so compressors are never held up for longer
+/// than it takes to merge `batch_size` commands.
+#[derive(Clone, Debug)]
+pub struct SharedSink {
+    queues: Arc<Mutex<BTreeMap<u32, VecDeque<Command>>>>,
+    /// Taken for a whole flush, so flushes never interleave.
+    flushing: Arc<Mutex<()>>,
+    batch_size: usize,
+}
+
+impl SharedSink {
+    /// Sink merging at most `batch_size` (at least one) commands per lock.
+    pub fn new(batch_size: usize) -> Self {
+        Self {
+            queues: Arc::default(),
+            flushing: Arc::default(),
+            batch_size: batch_size.max(1),
+        }
+    }
+
+    /// Commands pushed but not yet flushed, across every OID.
+    pub fn pending(&self) -> usize {
+        self.lock().values().map(VecDeque::len).sum()
+    }
+
+    /// Send the commands pushed so far, up to `move_clock`, on to `sink`
+    /// in `req_clock` order, ties by OID. Returns how many were sent.
+    pub fn flush(&self, move_clock: u64, sink: &mut impl CommandSink) -> usize {
+        let _flushing = self.flushing.lock().unwrap_or_else(|err| err.into_inner());
+        // Only what was pushed before the flush started is merged
+        let mut available: BTreeMap<u32, usize> = self
+            .lock()
+            .iter()
+            .map(|(&oid, queue)| (oid, queue.len()))
+            .collect();
+        let mut sent = 0;
+        let mut batch = Vec::new();
+        loop {
+            let mut held = false;
+            {
+                let mut queues = self.lock();
+                while batch.len() < self.batch_size {
+                    let next = available
+                        .iter()
+                        .filter(|&(_, &count)| count > 0)
+                        .filter_map(|(&oid, _)| Some((queues[&oid].front()?.req_clock(), oid)))
+                        .min();
+                    let Some((req_clock, oid)) = next else {
+                        break;
+                    };
+                    let queue = queues.get_mut(&oid).expect("queue was just found");
+                    if matches!(queue[0], Command::QueueStep(_)) && req_clock > move_clock {
+                        held = true;
+                        break;
+                    }
+                    batch.extend(queue.pop_front());
+                    *available.get_mut(&oid).expect("count was just found") -= 1;
+                }
+            }
+            let full = batch.len() == self.batch_size;
+            sent += batch.len();
+            for command in batch.drain(..) {
+                sink.push(command);
+            }
+            if held || !full {
+                return sent;
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<u32, VecDeque<Command>>> {
+        self.queues.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl CommandSink for SharedSink {
+    fn push(&mut self, command: Command) {
+        self.lock()
+            .entry(command.oid())
+            .or_default()
+            .push_back(command);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::step_compressor::RecordingSink;
+
+    const FREQ: f64 = 16_000_000.0;
+
+    /// Steps at a rate that keeps changing, so they compress into many
+    /// `queue_step` commands.
+    fn append_steps<S: CommandSink>(compressor: &mut StepCompressor<S>, offset: f64) {
+        let mut time = offset;
+        for i in 0..400 {
+            time += 0.0005 + 0.000_001 * (i % 37) as f64 * (i % 5) as f64;
+            compressor.append(1, 0.0, time).unwrap();
+        }
+    }
+
+    #[test]
+    fn merges_steppers_in_req_clock_order() {
+        let mut sync = StepperSync::new(16, RecordingSink::default());
+        for (oid, offset) in [(0, 0.0), (1, 0.000_25)] {
+            let index = sync.add_stepper(oid, 25);
+            sync.stepper_mut(index).set_time(0.0, FREQ);
+            append_steps(sync.stepper_mut(index), offset);
+        }
+        sync.flush((0.1 * FREQ) as u64).unwrap();
+        let partial = sync.sink().commands.len();
+        assert!(partial > 0);
+        sync.flush(u64::MAX).unwrap();
+
+        let commands = sync.into_sink().commands;
+        assert!(commands.len() > partial);
+        let clocks: Vec<_> = commands.iter().map(Command::req_clock).collect();
+        assert!(clocks.windows(2).all(|w| w[0] <= w[1]), "{clocks:?}");
+        for oid in 0..2 {
+            let steps: u32 = commands
+                .iter()
+                .filter_map(|command| match command {
+                    Command::QueueStep(step) if step.oid == oid => Some(step.count as u32),
+                    _ => None,
+                })
+                .sum();
+            assert_eq!(steps, 400);
+        }
+    }
+
+    #[test]
+    fn queue_steps_wait_for_a_free_slot() {
+        let mut reference = StepCompressor::new(0, 25, RecordingSink::default());
+        reference.set_time(0.0, FREQ);
+        append_steps(&mut reference, 0.0);
+        reference.flush(u64::MAX).unwrap();
+        let original: Vec<_> = reference
+            .into_sink()
+            .commands
+            .into_iter()
+            .filter_map(|command| match command {
+                Command::QueueStep(step) => Some(step),
+                Command::SetNextStepDir(_) => None,
+            })
+            .collect();
+
+        const SLOTS: usize = 4;
+        let mut sync = StepperSync::new(SLOTS, RecordingSink::default());
+        let index = sync.add_stepper(0, 25);
+        sync.set_time(0.0, FREQ);
+        append_steps(sync.stepper_mut(index), 0.0);
+        sync.flush(u64::MAX).unwrap();
+        let sent: Vec<_> = sync
+            .sink()
+            .commands
+            .iter()
+            .filter_map(|command| match command {
+                Command::QueueStep(step) => Some(step),
+                Command::SetNextStepDir(_) => None,
+            })
+            .collect();
+
+        assert!(original.len() > SLOTS);
+        assert_eq!(sent.len(), original.len());
+        for (k, step) in sent.iter().enumerate() {
+            // A slot frees up once the move SLOTS commands back starts
+            let expected = k.checked_sub(SLOTS).map_or(0, |k| original[k].min_clock);
+            assert_eq!(step.min_clock, expected, "command {k}");
+            assert_eq!(step.first_clock, original[k].first_clock);
+        }
+        assert_eq!(
+            sync.next_free_clock(),
+            original[original.len() - SLOTS].min_clock
+        );
+    }
+
+    /// Steps of stepper `oid` between `start` and `end` (seconds), at a
+    /// rate that keeps changing, reversing direction every other window.
+    fn append_window<S: CommandSink>(
+        compressor: &mut StepCompressor<S>,
+        oid: u32,
+        start: f64,
+        end: f64,
+    ) -> u32 {
+        let sdir = ((start / WINDOW).round() as i32 + oid as i32) % 2;
+        let mut time = start + 0.0001 * oid as f64;
+        let mut steps = 0;
+        while time < end {
+            compressor.append(sdir, 0.0, time).unwrap();
+            steps += 1;
+            time += 0.0004 + 0.000_02 * ((steps + oid) % 7) as f64;
+        }
+        steps
+    }
+
+    const WINDOW: f64 = 0.02;
+
+    fn move_clock(round: u32) -> u64 {
+        ((round + 1) as f64 * WINDOW * FREQ) as u64
+    }
+
+    #[test]
+    fn shared_sink_batches_merge_like_one_flush() {
+        let run = |batch_size| {
+            let shared = SharedSink::new(batch_size);
+            let mut compressors: Vec<_> = (0..3)
+                .map(|oid| {
+                    let mut sc = StepCompressor::new(oid, 25, shared.clone());
+                    sc.set_time(0.0, FREQ);
+                    sc
+                })
+                .collect();
+            let mut out = RecordingSink::default();
+            for round in 0..5 {
+                for (oid, sc) in compressors.iter_mut().enumerate() {
+                    let start = round as f64 * WINDOW;
+                    append_window(sc, oid as u32, start, start + WINDOW);
+                    sc.flush(move_clock(round)).unwrap();
+                }
+                shared.flush(move_clock(round), &mut out);
+            }
+            for sc in &mut compressors {
+                sc.flush(u64::MAX).unwrap();
+            }
+            shared.flush(u64::MAX, &mut out);
+            assert_eq!(shared.pending(), 0);
+            out.commands
+        };
+
+        let whole = run(usize::MAX);
+        assert_eq!(run(3), whole);
+        assert_eq!(run(1), whole);
+    }
+
+    #[test]
+    fn shared_sink_orders_interleaved_flushes() {
+        const ROUNDS: u32 = 8;
+        let shared = SharedSink::new(4);
+        let barrier = std::sync::Barrier::new(4);
+        let mut out = RecordingSink::default();
+        let mut flushes = Vec::new();
+        let appended: Vec<u32> = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..3)
+                .map(|oid| {
+                    let (shared, barrier) = (shared.clone(), &barrier);
+                    scope.spawn(move || {
+                        let mut sc = StepCompressor::new(oid, 25, shared);
+                        sc.set_time(0.0, FREQ);
+                        let mut steps = 0;
+                        for round in 0..ROUNDS {
+                            let start = round as f64 * WINDOW;
+                            steps += append_window(&mut sc, oid, start, start + WINDOW);
+                            sc.flush(move_clock(round)).unwrap();
+                            // The next round is pushed while this one is sent
+                            barrier.wait();
+                        }
+                        sc.flush(u64::MAX).unwrap();
+                        steps
+                    })
+                })
+                .collect();
+            for round in 0..ROUNDS {
+                barrier.wait();
+                let start = out.commands.len();
+                shared.flush(move_clock(round), &mut out);
+                flushes.push(start..out.commands.len());
+            }
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+        let start = out.commands.len();
+        shared.flush(u64::MAX, &mut out);
+        flushes.push(start..out.commands.len());
+
+        for (round, range) in flushes.iter().enumerate() {
+            let commands = &out.commands[range.clone()];
+            assert!(
+                commands
+                    .windows(2)
+                    .all(|w| (w[0].req_clock(), w[0].oid()) <= (w[1].req_clock(), w[1].oid())),
+                "flush {round} is out of order"
+            );
+        }
+        for (oid, &appended) in appended.iter().enumerate() {
+            let steps: u32 = out
+                .commands
+                .iter()
+                .filter_map(|command| match command {
+                    Command::QueueStep(step) if step.oid == oid as u32 => Some(step.count as u32),
+                    _ => None,
+                })
+                .sum();
+            assert_eq!(steps, appended);
+        }
+        assert_eq!(shared.pending(), 0);
+    }
+}
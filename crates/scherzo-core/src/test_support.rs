@@ -0,0 +1,274 @@
+//! Differential testing of [`StepCompressor`] against a naive reference.
+//!
+//! [`ReferenceCompressor`] sends every step as a `queue_step` of its own, so
+//! replaying its commands gives back exactly the step clocks it was given.
+//! [`check_step_train`] feeds a [`StepTrain`] to both it and a
+//! [`StepCompressor`], replays both command streams the way the MCU would,
+//! and checks the compressor takes the same steps in the same directions,
+//! each no later than the reference and no more than `max_error` earlier.
+//! [`check_against_reference`] does that for random trains, which include
+//! gaps around [`CLOCK_DIFF_MAX`] and past a 32-bit clock wrap.
+//!
+//! Enabled by the `test-support` feature.
+
+use crate::step_compressor::{
+    CLOCK_DIFF_MAX, Command, CommandSink, QueueStep, RecordingSink, SetNextStepDir, StepCompressor,
+    StepCompressorConfig, clock32, clock64,
+};
+
+/// MCU clock frequency the trains are scheduled at.
+pub const MCU_FREQ: f64 = 1_000_000.0;
+
+/// Sends one `queue_step` per step, with no compression at all.
+pub struct ReferenceCompressor<S: CommandSink> {
+    oid: u32,
+    last_step_clock: u64,
+    dir: Option<bool>,
+    sink: S,
+}
+
+impl<S: CommandSink> ReferenceCompressor<S> {
+    pub fn new(oid: u32, sink: S) -> Self {
+        Self {
+            oid,
+            last_step_clock: 0,
+            dir: None,
+            sink,
+        }
+    }
+
+    /// Send a step at `clock`, which must not be before the last one.
+    pub fn step(&mut self, dir: bool, clock: u64) {
+        assert!(clock >= self.last_step_clock, "steps must be in order");
+        if self.dir != Some(dir) {
+            self.dir = Some(dir);
+            self.sink.push(Command::SetNextStepDir(SetNextStepDir {
+                oid: self.oid,
+                dir,
+                req_clock: self.last_step_clock,
+            }));
+        }
+        // As the compressor does, a step far enough out that its interval
+        // could wrap is requested at its own clock
+        let req_clock = if clock >= self.last_step_clock + CLOCK_DIFF_MAX {
+            clock
+        } else {
+            self.last_step_clock
+        };
+        self.sink.push(Command::QueueStep(QueueStep {
+            oid: self.oid,
+            first_clock: clock,
+            last_clock: clock,
+            interval: (clock - self.last_step_clock) as u32,
+            count: 1,
+            add: 0,
+            req_clock,
+            min_clock: self.last_step_clock,
+        }));
+        self.last_step_clock = clock;
+    }
+
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
+
+    pub fn into_sink(self) -> S {
+        self.sink
+    }
+}
+
+/// The steps `commands` make the MCU take, as direction and clock, starting
+/// from clock zero. Each `queue_step` is replayed from its 32-bit interval
+/// and `add`, extended to 64 bits around its `req_clock`, as the MCU would
+/// schedule it, rather than trusting its `first_clock`.
+pub fn replay(commands: &[Command]) -> Vec<(bool, u64)> {
+    let mut steps = Vec::new();
+    let mut last_step_clock = 0u64;
+    let mut dir = false;
+    for command in commands {
+        match command {
+            Command::SetNextStepDir(next) => dir = next.dir,
+            Command::QueueStep(step) => {
+                let first = clock32(last_step_clock).wrapping_add(step.interval);
+                let mut clock = clock64(first, step.req_clock) as i64;
+                let mut interval = step.interval as i64;
+                for i in 0..step.count {
+                    if i > 0 {
+                        interval += step.add as i64;
+                        clock += interval;
+                    }
+                    steps.push((dir, clock as u64));
+                }
+                last_step_clock = clock as u64;
+            }
+        }
+    }
+    steps
+}
+
+/// How the start of a [`StepSegment`] follows the step before it.
+#[derive(Clone, Copy, Debug, bolero::TypeGenerator)]
+pub enum Gap {
+    /// Up to 65536 ticks.
+    Short(u16),
+    /// Within a 16-bit offset either side of [`CLOCK_DIFF_MAX`].
+    NearClockDiffMax(i16),
+    /// Just past a whole 32-bit clock wrap.
+    PastWrap(u16),
+}
+
+impl Gap {
+    fn ticks(self) -> u64 {
+        match self {
+            Gap::Short(ticks) => 1 + ticks as u64,
+            Gap::NearClockDiffMax(offset) => CLOCK_DIFF_MAX.saturating_add_signed(offset as i64),
+            Gap::PastWrap(ticks) => (1 << 32) + ticks as u64,
+        }
+    }
+}
+
+/// A run of steps in one direction at a steadily changing interval.
+#[derive(Clone, Copy, Debug, bolero::TypeGenerator)]
+pub struct StepSegment {
+    pub dir: bool,
+    pub gap: Gap,
+    /// Steps after the first.
+    pub count: u8,
+    pub interval: u16,
+    /// Ticks added to the interval after each step.
+    pub add: i8,
+    /// Whether to flush the compressor up to the segment's last step.
+    pub flush: bool,
+}
+
+/// Random steps for [`check_step_train`].
+#[derive(Clone, Debug, bolero::TypeGenerator)]
+pub struct StepTrain {
+    pub segments: Vec<StepSegment>,
+}
+
+impl StepTrain {
+    /// Clock of every step, with whether to flush after it.
+    pub fn steps(&self) -> Vec<(bool, u64, bool)> {
+        let mut steps = Vec::new();
+        let mut clock = 0u64;
+        for segment in &self.segments {
+            clock += segment.gap.ticks();
+            let count = segment.count as usize + 1;
+            let mut interval = segment.interval.max(2) as i64;
+            for i in 0..count {
+                if i > 0 {
+                    interval = (interval + segment.add as i64).max(2);
+                    clock += interval as u64;
+                }
+                steps.push((segment.dir, clock, segment.flush && i + 1 == count));
+            }
+        }
+        steps
+    }
+}
+
+/// Run `train` through a [`StepCompressor`] built from `config` and through
+/// a [`ReferenceCompressor`], and assert the compressor's steps match.
+///
+/// The step+dir+step filter is turned off, since it drops steps on
+/// purpose, and step times are converted with fixed-point clocks, so both
+/// see the same clocks.
+pub fn check_step_train(config: StepCompressorConfig, train: &StepTrain) {
+    let config = config
+        .with_sds_filter_time(0.0)
+        .with_fixed_point_clock(true);
+    let max_error = config.max_error as u64;
+    let mut compressor: StepCompressor<_> = config.build(1, RecordingSink::default());
+    compressor.set_time(0.0, MCU_FREQ);
+    let mut reference = ReferenceCompressor::new(1, RecordingSink::default());
+
+    for (dir, clock, flush) in train.steps() {
+        compressor
+            .append(dir as i32, 0.0, clock as f64 / MCU_FREQ)
+            .unwrap();
+        reference.step(dir, clock);
+        if flush {
+            compressor.flush(clock).unwrap();
+        }
+    }
+    compressor.commit().unwrap();
+    compressor.flush(u64::MAX).unwrap();
+
+    let expected = replay(&reference.into_sink().commands);
+    let actual = replay(&compressor.into_sink().commands);
+    assert_eq!(actual.len(), expected.len(), "steps taken");
+    for (i, ((dir, clock), (expected_dir, expected_clock))) in
+        actual.into_iter().zip(expected).enumerate()
+    {
+        assert_eq!(dir, expected_dir, "direction of step {i}");
+        assert!(
+            clock <= expected_clock && clock + max_error >= expected_clock,
+            "step {i} at {clock}, expected within {max_error} before {expected_clock}",
+        );
+    }
+}
+
+/// [`check_step_train`] for `iterations` random trains.
+pub fn check_against_reference(config: StepCompressorConfig, iterations: usize) {
+    bolero::check!()
+        .with_type::<StepTrain>()
+        .with_iterations(iterations)
+        .for_each(|train| check_step_train(config, train));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::step_compressor::Compression;
+
+    #[test]
+    fn replays_reference_steps_exactly() {
+        let mut reference = ReferenceCompressor::new(1, RecordingSink::default());
+        let steps = [
+            (true, 10),
+            (true, 25),
+            (false, 25 + CLOCK_DIFF_MAX),
+            (false, (5 << 32) + 7),
+        ];
+        for (dir, clock) in steps {
+            reference.step(dir, clock);
+        }
+        assert_eq!(replay(&reference.into_sink().commands), steps);
+    }
+
+    #[test]
+    fn gaps_around_clock_diff_max() {
+        let segment = |gap, dir| StepSegment {
+            dir,
+            gap,
+            count: 20,
+            interval: 300,
+            add: -7,
+            flush: true,
+        };
+        let train = StepTrain {
+            segments: vec![
+                segment(Gap::Short(100), true),
+                segment(Gap::NearClockDiffMax(-1), true),
+                segment(Gap::NearClockDiffMax(0), false),
+                segment(Gap::NearClockDiffMax(1), false),
+                segment(Gap::PastWrap(3), true),
+            ],
+        };
+        check_step_train(StepCompressorConfig::new(25), &train);
+    }
+
+    #[test]
+    fn strategies_match_the_reference() {
+        for compression in [
+            Compression::BisectAdd,
+            Compression::ConstantInterval,
+            Compression::ExactSteps,
+            Compression::LookaheadBisect,
+        ] {
+            let config = StepCompressorConfig::new(25).with_compression(compression);
+            check_against_reference(config, 64);
+        }
+    }
+}
@@ -0,0 +1,366 @@
+//! Toolhead motion controller.
+//!
+//! Ties the lookahead planner to the trapezoid queues, loosely following
+//! the `ToolHead` class in Klipper's `toolhead.py`. Planned moves are
+//! assigned a print time and appended to an XYZ trapq and a separate
+//! extruder trapq, which the iterative solvers then consume to generate
+//! step times.
+
+use crate::{
+    error::{MotionError, Result},
+    planner::{LookAheadQueue, LookAheadState, MachineLimits, PlannedMove},
+    trap_queue::{TrapQueue, TrapQueueState},
+};
+use serde::{Deserialize, Serialize};
+
+/// Plans toolhead moves and schedules them onto trapezoid queues.
+pub struct MotionController {
+    limits: MachineLimits,
+    lookahead: LookAheadQueue,
+    trapq: TrapQueue,
+    extruder_trapq: TrapQueue,
+    commanded_pos: [f64; 4],
+    print_time: f64,
+}
+
+/// Planner and trapq state of a [`MotionController`], from
+/// [`MotionController::snapshot`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MotionState {
+    limits: MachineLimits,
+    lookahead: LookAheadState,
+    trapq: TrapQueueState,
+    extruder_trapq: TrapQueueState,
+    commanded_pos: [f64; 4],
+    print_time: f64,
+}
+
+impl MotionController {
+    pub fn new(limits: MachineLimits) -> Self {
+        Self {
+            limits,
+            lookahead: LookAheadQueue::new(),
+            trapq: TrapQueue::new(),
+            extruder_trapq: TrapQueue::new(),
+            commanded_pos: [0.0; 4],
+            print_time: 0.0,
+        }
+    }
+
+    pub fn limits(&self) -> &MachineLimits {
+        &self.limits
+    }
+
+    /// Change the limits of moves queued from now on. Queued moves keep the
+    /// limits they were planned with.
+    pub fn set_limits(&mut self, limits: MachineLimits) {
+        self.limits = limits;
+    }
+
+    /// Last commanded X/Y/Z/E position.
+    pub fn position(&self) -> [f64; 4] {
+        self.commanded_pos
+    }
+
+    /// Print time at the end of the last move scheduled onto the trapqs.
+    pub fn print_time(&self) -> f64 {
+        self.print_time
+    }
+
+    /// Advance the print time of the next scheduled move. Pending lookahead
+    /// moves are flushed first so the gap lands after them.
+    pub fn set_print_time(&mut self, print_time: f64) -> Result<()> {
+        self.flush_lookahead()?;
+        self.print_time = self.print_time.max(print_time);
+        Ok(())
+    }
+
+    /// Keep the toolhead still for `seconds` after the queued moves, as
+    /// `G4` does. Negative durations are treated as zero.
+    pub fn dwell(&mut self, seconds: f64) -> Result<()> {
+        if !seconds.is_finite() {
+            return Err(MotionError::InvalidDwell { seconds });
+        }
+        self.flush_lookahead()?;
+        self.print_time += seconds.max(0.0);
+        Ok(())
+    }
+
+    /// Schedule every queued move and return the print time at which the
+    /// toolhead comes to rest. Callers that must wait for motion to finish,
+    /// e.g. before waiting on a temperature, wait for the MCU to reach it.
+    pub fn wait_moves(&mut self) -> Result<f64> {
+        self.flush_lookahead()?;
+        Ok(self.print_time)
+    }
+
+    /// Return the print time at which a command issued now takes effect in
+    /// order with the queued motion, never earlier than `est_print_time`.
+    ///
+    /// Moves queued afterwards start no earlier than the returned time, so
+    /// after a wait the toolhead resumes from where the MCU is rather than
+    /// from a print time already in the past.
+    pub fn sync_print_time(&mut self, est_print_time: f64) -> Result<f64> {
+        self.set_print_time(est_print_time)?;
+        Ok(self.print_time)
+    }
+
+    /// Moves waiting in the lookahead queue to be planned onto the trapqs.
+    pub fn lookahead_len(&self) -> usize {
+        self.lookahead.len()
+    }
+
+    pub fn trapq(&self) -> &TrapQueue {
+        &self.trapq
+    }
+
+    pub fn extruder_trapq(&self) -> &TrapQueue {
+        &self.extruder_trapq
+    }
+
+    /// Redefine the current position without moving.
+    pub fn set_position(&mut self, pos: [f64; 4]) -> Result<()> {
+        self.flush_lookahead()?;
+        self.trapq
+            .set_position(self.print_time, pos[0], pos[1], pos[2]);
+        self.extruder_trapq
+            .set_position(self.print_time, pos[3], 0.0, 0.0);
+        self.commanded_pos = pos;
+        Ok(())
+    }
+
+    /// Queue a move to `end_pos` at `speed` mm/s.
+    ///
+    /// Returns true if the move caused previously queued moves to be
+    /// scheduled onto the trapqs.
+    pub fn move_to(&mut self, end_pos: [f64; 4], speed: f64) -> Result<bool> {
+        let valid = end_pos.iter().all(|pos| pos.is_finite()) && speed > 0.0 && speed.is_finite();
+        if !valid {
+            return Err(MotionError::InvalidMove {
+                target: end_pos,
+                speed,
+            });
+        }
+        let mv = PlannedMove::new(&self.limits, self.commanded_pos, end_pos, speed);
+        if mv.move_d == 0.0 {
+            return Ok(false);
+        }
+        self.commanded_pos = mv.end_pos;
+        if !self.lookahead.add_move(mv) {
+            return Ok(false);
+        }
+        let moves = self.lookahead.flush(true);
+        let flushed = !moves.is_empty();
+        self.process_moves(&moves)?;
+        Ok(flushed)
+    }
+
+    /// Schedule every queued move, assuming the toolhead stops afterwards.
+    pub fn flush_lookahead(&mut self) -> Result<()> {
+        let moves = self.lookahead.flush(false);
+        self.process_moves(&moves)
+    }
+
+    /// Capture the queued moves, position and print time.
+    pub fn snapshot(&self) -> MotionState {
+        MotionState {
+            limits: self.limits,
+            lookahead: self.lookahead.snapshot(),
+            trapq: self.trapq.snapshot(),
+            extruder_trapq: self.extruder_trapq.snapshot(),
+            commanded_pos: self.commanded_pos,
+            print_time: self.print_time,
+        }
+    }
+
+    /// Rebuild a controller from [`snapshot`](Self::snapshot), ready to
+    /// queue more moves after the saved ones.
+    pub fn from_state(state: MotionState) -> Self {
+        Self {
+            limits: state.limits,
+            lookahead: LookAheadQueue::from_state(state.lookahead),
+            trapq: TrapQueue::from_state(state.trapq),
+            extruder_trapq: TrapQueue::from_state(state.extruder_trapq),
+            commanded_pos: state.commanded_pos,
+            print_time: state.print_time,
+        }
+    }
+
+    /// Retire trapq moves that end before `print_time`.
+    pub fn finalize_moves(&mut self, print_time: f64, clear_history_time: f64) {
+        self.trapq.finalize_moves(print_time, clear_history_time);
+        self.extruder_trapq
+            .finalize_moves(print_time, clear_history_time);
+    }
+
+    fn process_moves(&mut self, moves: &[PlannedMove]) -> Result<()> {
+        for mv in moves {
+            if mv.is_kinematic_move {
+                self.trapq.append(
+                    self.print_time,
+                    mv.accel_t,
+                    mv.cruise_t,
+                    mv.decel_t,
+                    mv.start_pos[0],
+                    mv.start_pos[1],
+                    mv.start_pos[2],
+                    mv.axes_r[0],
+                    mv.axes_r[1],
+                    mv.axes_r[2],
+                    mv.start_v,
+                    mv.cruise_v,
+                    mv.accel,
+                )?;
+            }
+            if mv.axes_d[3] != 0.0 {
+                // The extruder trapq tracks filament position on its X axis
+                let axis_r = mv.axes_r[3];
+                self.extruder_trapq.append(
+                    self.print_time,
+                    mv.accel_t,
+                    mv.cruise_t,
+                    mv.decel_t,
+                    mv.start_pos[3],
+                    0.0,
+                    0.0,
+                    1.0,
+                    0.0,
+                    0.0,
+                    mv.start_v * axis_r,
+                    mv.cruise_v * axis_r,
+                    mv.accel * axis_r,
+                )?;
+            }
+            self.print_time += mv.move_t();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schedules_moves_back_to_back() {
+        let mut toolhead = MotionController::new(MachineLimits::default());
+        toolhead.move_to([10.0, 0.0, 0.0, 0.0], 100.0).unwrap();
+        toolhead.move_to([10.0, 10.0, 0.0, 1.0], 100.0).unwrap();
+        toolhead.flush_lookahead().unwrap();
+
+        let moves = toolhead.trapq().get_active_moves();
+        assert!(!moves.is_empty());
+        let last = moves.last().unwrap();
+        let end = last.print_time + last.move_t;
+        assert!((end - toolhead.print_time()).abs() < 1e-9);
+        assert_eq!(toolhead.position(), [10.0, 10.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn extrusion_goes_to_extruder_trapq() {
+        let mut toolhead = MotionController::new(MachineLimits::default());
+        toolhead.move_to([10.0, 0.0, 0.0, 0.0], 100.0).unwrap();
+        toolhead.flush_lookahead().unwrap();
+        assert_eq!(toolhead.extruder_trapq().active_len(), 0);
+
+        toolhead.move_to([20.0, 0.0, 0.0, 2.0], 100.0).unwrap();
+        toolhead.flush_lookahead().unwrap();
+        let moves = toolhead.extruder_trapq().get_active_moves();
+        let last = moves.last().unwrap();
+        let end = crate::kinematics::move_get_coord(last, last.move_t);
+        assert!((end.x() - 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn rejects_invalid_moves() {
+        let mut toolhead = MotionController::new(MachineLimits::default());
+        for (target, speed) in [
+            ([f64::NAN, 0.0, 0.0, 0.0], 100.0),
+            ([10.0, 0.0, 0.0, 0.0], 0.0),
+            ([10.0, 0.0, 0.0, 0.0], f64::INFINITY),
+        ] {
+            let err = toolhead.move_to(target, speed).unwrap_err();
+            assert!(matches!(err, MotionError::InvalidMove { .. }));
+        }
+        assert_eq!(toolhead.position(), [0.0; 4]);
+    }
+
+    #[test]
+    fn restores_mid_print_from_snapshot() {
+        let mut toolhead = MotionController::new(MachineLimits::default());
+        for i in 1..=20 {
+            let x = (i * 7 % 50) as f64;
+            toolhead
+                .move_to([x, i as f64, 0.0, i as f64], 150.0)
+                .unwrap();
+        }
+        toolhead.finalize_moves(0.2, 0.0);
+
+        let json = serde_json::to_string(&toolhead.snapshot()).unwrap();
+        let state: MotionState = serde_json::from_str(&json).unwrap();
+        let mut restored = MotionController::from_state(state);
+        assert_eq!(restored.snapshot(), toolhead.snapshot());
+
+        for toolhead in [&mut toolhead, &mut restored] {
+            toolhead.move_to([0.0, 0.0, 0.0, 25.0], 100.0).unwrap();
+            toolhead.flush_lookahead().unwrap();
+        }
+        assert_eq!(restored.print_time(), toolhead.print_time());
+        assert_eq!(
+            restored.trapq().get_active_moves(),
+            toolhead.trapq().get_active_moves()
+        );
+        assert_eq!(
+            restored.extruder_trapq().get_history_moves(),
+            toolhead.extruder_trapq().get_history_moves()
+        );
+    }
+
+    #[test]
+    fn set_print_time_inserts_gap() {
+        let mut toolhead = MotionController::new(MachineLimits::default());
+        toolhead.move_to([10.0, 0.0, 0.0, 0.0], 100.0).unwrap();
+        toolhead.set_print_time(5.0).unwrap();
+        assert_eq!(toolhead.print_time(), 5.0);
+
+        toolhead.move_to([20.0, 0.0, 0.0, 0.0], 100.0).unwrap();
+        toolhead.flush_lookahead().unwrap();
+        let moves = toolhead.trapq().get_active_moves();
+        assert!(moves.last().unwrap().print_time >= 5.0);
+    }
+
+    #[test]
+    fn dwell_follows_queued_moves() {
+        let mut toolhead = MotionController::new(MachineLimits::default());
+        toolhead.move_to([10.0, 0.0, 0.0, 0.0], 100.0).unwrap();
+        toolhead.dwell(0.5).unwrap();
+        let last = *toolhead.trapq().get_active_moves().last().unwrap();
+        let end = last.print_time + last.move_t;
+        assert!((toolhead.print_time() - (end + 0.5)).abs() < 1e-9);
+
+        toolhead.dwell(-1.0).unwrap();
+        assert!((toolhead.print_time() - (end + 0.5)).abs() < 1e-9);
+        assert!(matches!(
+            toolhead.dwell(f64::NAN),
+            Err(MotionError::InvalidDwell { .. })
+        ));
+    }
+
+    #[test]
+    fn waits_and_syncs_print_time() {
+        let mut toolhead = MotionController::new(MachineLimits::default());
+        toolhead.move_to([10.0, 0.0, 0.0, 0.0], 100.0).unwrap();
+        let end = toolhead.wait_moves().unwrap();
+        assert!(!toolhead.trapq().get_active_moves().is_empty());
+        assert_eq!(end, toolhead.print_time());
+
+        // The MCU has not caught up yet, so commands follow the motion
+        assert_eq!(toolhead.sync_print_time(end - 0.1).unwrap(), end);
+        // After waiting past the end of motion, moves resume from the MCU
+        assert_eq!(toolhead.sync_print_time(end + 3.0).unwrap(), end + 3.0);
+        toolhead.move_to([20.0, 0.0, 0.0, 0.0], 100.0).unwrap();
+        toolhead.wait_moves().unwrap();
+        let moves = toolhead.trapq().get_active_moves();
+        assert!(moves.last().unwrap().print_time >= end + 3.0);
+    }
+}
@@ -5,137 +5,462 @@
 //! null moves for numerical stability, maintains history, and can
 //! expose both in-flight and historical moves for diagnostics.
 
-use std::collections::VecDeque;
+use crate::error::{MotionError, Result};
+use serde::{
+    Deserialize, Deserializer, Serialize, Serializer,
+    de::{self, SeqAccess},
+    ser::SerializeTuple,
+};
+use std::{
+    collections::{VecDeque, vec_deque},
+    fmt, slice,
+};
 
 const NEVER_TIME: f64 = 9_999_999_999_999_999.9;
 const MAX_NULL_MOVE: f64 = 1.0;
+/// Slack (seconds) for rounding when a move starts where the last one ends.
+const OVERLAP_TOLERANCE: f64 = 0.000_000_001;
 
-#[derive(Clone, Copy, Debug, Default, PartialEq)]
-pub struct Coord {
-    pub x: f64,
-    pub y: f64,
-    pub z: f64,
+/// Axes of a toolhead: X, Y and Z.
+pub const XYZ: usize = 3;
+
+/// A position or direction over `N` axes, of which the first three are X, Y
+/// and Z. Machines that move an extruder as an axis or rotary A/B/C axes
+/// queue moves over more.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Coord<const N: usize = XYZ> {
+    pub axes: [f64; N],
 }
 
-#[derive(Clone, Copy, Debug, Default, PartialEq)]
-pub struct Move {
+impl Coord {
+    pub const fn new(x: f64, y: f64, z: f64) -> Self {
+        Self { axes: [x, y, z] }
+    }
+}
+
+impl<const N: usize> Coord<N> {
+    pub const fn from_axes(axes: [f64; N]) -> Self {
+        Self { axes }
+    }
+
+    pub fn x(&self) -> f64 {
+        const { assert!(N > 0, "no X axis") };
+        self.axes[0]
+    }
+
+    pub fn y(&self) -> f64 {
+        const { assert!(N > 1, "no Y axis") };
+        self.axes[1]
+    }
+
+    pub fn z(&self) -> f64 {
+        const { assert!(N > 2, "no Z axis") };
+        self.axes[2]
+    }
+
+    /// The position `distance` along `direction` from this one.
+    pub fn offset(&self, direction: &Self, distance: f64) -> Self {
+        Self {
+            axes: std::array::from_fn(|i| self.axes[i] + direction.axes[i] * distance),
+        }
+    }
+
+    /// Sum of the products of each axis.
+    pub fn dot(&self, other: &Self) -> f64 {
+        self.axes.iter().zip(&other.axes).map(|(a, b)| a * b).sum()
+    }
+}
+
+impl<const N: usize> Default for Coord<N> {
+    fn default() -> Self {
+        Self { axes: [0.0; N] }
+    }
+}
+
+impl<const N: usize> From<[f64; N]> for Coord<N> {
+    fn from(axes: [f64; N]) -> Self {
+        Self { axes }
+    }
+}
+
+/// Serialized as a sequence of the axes.
+impl<const N: usize> Serialize for Coord<N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(N)?;
+        for axis in &self.axes {
+            tuple.serialize_element(axis)?;
+        }
+        tuple.end()
+    }
+}
+
+impl<'de, const N: usize> Deserialize<'de> for Coord<N> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct AxesVisitor<const N: usize>;
+
+        impl<'de, const N: usize> de::Visitor<'de> for AxesVisitor<N> {
+            type Value = Coord<N>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a sequence of {N} axes")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> std::result::Result<Coord<N>, A::Error> {
+                let mut axes = [0.0; N];
+                for (i, axis) in axes.iter_mut().enumerate() {
+                    *axis = seq
+                        .next_element()?
+                        .ok_or_else(|| de::Error::invalid_length(i, &self))?;
+                }
+                Ok(Coord { axes })
+            }
+        }
+
+        deserializer.deserialize_tuple(N, AxesVisitor::<N>)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Move<const N: usize = XYZ> {
     pub print_time: f64,
     pub move_t: f64,
     pub start_v: f64,
     pub half_accel: f64,
-    pub start_pos: Coord,
-    pub axes_r: Coord,
+    pub start_pos: Coord<N>,
+    pub axes_r: Coord<N>,
+    /// Caller's id for what queued the move, like the G-code statement it
+    /// came from; see [`TrapQueue::append_tagged`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<u64>,
 }
 
-#[derive(Clone, Copy, Debug, Default, PartialEq)]
-pub struct PullMove {
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PullMove<const N: usize = XYZ> {
     pub print_time: f64,
     pub move_t: f64,
     pub start_v: f64,
     pub accel: f64,
-    pub start_x: f64,
-    pub start_y: f64,
-    pub start_z: f64,
-    pub x_r: f64,
-    pub y_r: f64,
-    pub z_r: f64,
+    pub start_pos: Coord<N>,
+    pub axes_r: Coord<N>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<u64>,
 }
 
-fn move_get_distance(m: &Move, move_time: f64) -> f64 {
+/// Queue the move again, e.g. when replaying a [`TrapQueueDump`].
+impl<const N: usize> From<PullMove<N>> for Move<N> {
+    fn from(m: PullMove<N>) -> Self {
+        Self {
+            print_time: m.print_time,
+            move_t: m.move_t,
+            start_v: m.start_v,
+            half_accel: 0.5 * m.accel,
+            start_pos: m.start_pos,
+            axes_r: m.axes_r,
+            tag: m.tag,
+        }
+    }
+}
+
+/// Where a move puts the toolhead at one instant; see
+/// [`TrapQueue::state_at`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct KinematicState<const N: usize = XYZ> {
+    pub pos: Coord<N>,
+    /// Velocity of each axis.
+    pub velocity: Coord<N>,
+    /// Acceleration of each axis.
+    pub accel: Coord<N>,
+}
+
+fn move_get_distance<const N: usize>(m: &Move<N>, move_time: f64) -> f64 {
     (m.start_v + m.half_accel * move_time) * move_time
 }
 
-fn move_get_coord(m: &Move, move_time: f64) -> Coord {
-    let move_dist = move_get_distance(m, move_time);
-    Coord {
-        x: m.start_pos.x + m.axes_r.x * move_dist,
-        y: m.start_pos.y + m.axes_r.y * move_dist,
-        z: m.start_pos.z + m.axes_r.z * move_dist,
+fn move_get_coord<const N: usize>(m: &Move<N>, move_time: f64) -> Coord<N> {
+    m.start_pos
+        .offset(&m.axes_r, move_get_distance(m, move_time))
+}
+
+fn pull_move<const N: usize>(m: &Move<N>) -> PullMove<N> {
+    PullMove {
+        print_time: m.print_time,
+        move_t: m.move_t,
+        start_v: m.start_v,
+        accel: 2.0 * m.half_accel,
+        start_pos: m.start_pos,
+        axes_r: m.axes_r,
+        tag: m.tag,
     }
 }
 
-#[allow(dead_code)]
-fn copy_pull_move(p: &mut PullMove, m: &Move) {
-    p.print_time = m.print_time;
-    p.move_t = m.move_t;
-    p.start_v = m.start_v;
-    p.accel = 2.0 * m.half_accel;
-    p.start_x = m.start_pos.x;
-    p.start_y = m.start_pos.y;
-    p.start_z = m.start_pos.z;
-    p.x_r = m.axes_r.x;
-    p.y_r = m.axes_r.y;
-    p.z_r = m.axes_r.z;
+fn move_end<const N: usize>(m: &Move<N>) -> f64 {
+    m.print_time + m.move_t
+}
+
+/// Finalized moves kept at the front of the buffer before it is compacted.
+const COMPACT_THRESHOLD: usize = 1024;
+
+/// Stands in for the move before the first active one, like Klipper's head
+/// sentinel.
+const fn head_sentinel<const N: usize>() -> Move<N> {
+    Move {
+        print_time: -1.0,
+        move_t: 0.0,
+        start_v: 0.0,
+        half_accel: 0.0,
+        start_pos: Coord::from_axes([0.0; N]),
+        axes_r: Coord::from_axes([0.0; N]),
+        tag: None,
+    }
 }
 
-pub struct TrapQueue {
-    moves: VecDeque<Move>, // includes head and tail sentinels
-    history: VecDeque<Move>,
+/// Active moves are kept contiguous and in time order, so they can be
+/// borrowed as a slice and searched by time. Finalizing a move only advances
+/// an offset; the finalized prefix is dropped once it grows large enough that
+/// doing so is amortized O(1) per move.
+///
+/// Queues moves over `N` axes; see [`Coord`].
+pub struct TrapQueue<const N: usize = XYZ> {
+    /// Moves from `first` on are active; those before it are finalized.
+    moves: Vec<Move<N>>,
+    first: usize,
+    /// Marks where the active moves end; a print time of 0 means stale.
+    tail: Move<N>,
+    /// Finalized moves, newest first.
+    history: VecDeque<Move<N>>,
+    /// Merge moves continuing the last active one; see
+    /// [`set_merge_tolerance`](Self::set_merge_tolerance).
+    merge: Option<MergeTolerance>,
+    totals: TrapQueueTotals<N>,
 }
 
-impl Default for TrapQueue {
+/// What a [`TrapQueue`] has been given to move, from
+/// [`TrapQueue::totals`].
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TrapQueueTotals<const N: usize = XYZ> {
+    /// Distance (mm) travelled along each axis, counting both directions.
+    pub distance: Coord<N>,
+    /// Duration (s) of the moves, not counting null moves.
+    pub move_time: f64,
+    /// Fastest speed (mm/s) of any move.
+    pub max_velocity: f64,
+    /// Largest acceleration or deceleration (mm/s^2) of any move.
+    pub max_accel: f64,
+    /// Moves added, each part of an appended move counting separately.
+    pub moves: u64,
+    /// Null moves, holding the toolhead still, added or filling gaps.
+    pub null_moves: u64,
+}
+
+impl<const N: usize> Default for TrapQueueTotals<N> {
     fn default() -> Self {
-        Self::new()
+        Self {
+            distance: Coord::default(),
+            move_time: 0.0,
+            max_velocity: 0.0,
+            max_accel: 0.0,
+            moves: 0,
+            null_moves: 0,
+        }
     }
 }
 
-impl TrapQueue {
-    pub fn new() -> Self {
-        let mut moves = VecDeque::new();
-        // Head sentinel
-        moves.push_back(Move {
-            print_time: -1.0,
-            ..Move::default()
-        });
-        // Tail sentinel
-        moves.push_back(Move {
-            print_time: NEVER_TIME,
-            move_t: NEVER_TIME,
-            ..Move::default()
-        });
+impl<const N: usize> TrapQueueTotals<N> {
+    fn add(&mut self, m: &Move<N>) {
+        if m.start_v == 0.0 && m.half_accel == 0.0 {
+            self.null_moves += 1;
+            return;
+        }
+        self.moves += 1;
+        self.move_time += m.move_t;
+        let distance = move_get_distance(m, m.move_t).abs();
+        for (total, r) in self.distance.axes.iter_mut().zip(m.axes_r.axes) {
+            *total += r.abs() * distance;
+        }
+        let end_v = m.start_v + 2.0 * m.half_accel * m.move_t;
+        self.max_velocity = self.max_velocity.max(m.start_v.abs()).max(end_v.abs());
+        self.max_accel = self.max_accel.max(2.0 * m.half_accel.abs());
+    }
+}
+
+/// How closely a move must continue the one before it for a [`TrapQueue`]
+/// to merge them; see [`TrapQueue::set_merge_tolerance`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MergeTolerance {
+    /// Largest distance (mm) between where the merged move would be and
+    /// where the new move starts or ends.
+    pub position: f64,
+    /// Largest change in speed (mm/s) where the moves join.
+    pub velocity: f64,
+    /// Largest change in acceleration (mm/s^2).
+    pub accel: f64,
+}
+
+impl Default for MergeTolerance {
+    fn default() -> Self {
         Self {
-            moves,
+            position: 1e-6,
+            velocity: 1e-6,
+            accel: 1e-6,
+        }
+    }
+}
+
+impl MergeTolerance {
+    /// Whether `next` continues `prev` closely enough to extend `prev` by
+    /// its duration instead.
+    fn continues<const N: usize>(&self, prev: &Move<N>, next: &Move<N>) -> bool {
+        let end_v = prev.start_v + 2.0 * prev.half_accel * prev.move_t;
+        let within = |a: &Coord<N>, b: &Coord<N>| {
+            a.axes
+                .iter()
+                .zip(&b.axes)
+                .all(|(a, b)| (a - b).abs() <= self.position)
+        };
+        let merged_end = move_get_coord(prev, prev.move_t + next.move_t);
+        prev.tag == next.tag
+            && (move_end(prev) - next.print_time).abs() <= OVERLAP_TOLERANCE
+            && (end_v - next.start_v).abs() <= self.velocity
+            && (2.0 * (prev.half_accel - next.half_accel)).abs() <= self.accel
+            && within(&move_get_coord(prev, prev.move_t), &next.start_pos)
+            && within(&merged_end, &move_get_coord(next, next.move_t))
+    }
+}
+
+/// Active moves and history of a [`TrapQueue`], from [`TrapQueue::snapshot`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TrapQueueState<const N: usize = XYZ> {
+    moves: Vec<Move<N>>,
+    /// Newest first.
+    history: Vec<Move<N>>,
+}
+
+/// Moves of a [`TrapQueue`] for diagnostics, from [`TrapQueue::dump`].
+///
+/// Unlike [`TrapQueueState`], which restores a queue exactly, this lists
+/// the moves as [`PullMove`]s, with their real acceleration, oldest first.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TrapQueueDump<const N: usize = XYZ> {
+    /// Start of the oldest move, or 0 if there are none.
+    pub start_time: f64,
+    /// End of the newest move, or 0 if there are none.
+    pub end_time: f64,
+    /// Finalized moves, oldest first.
+    pub history: Vec<PullMove<N>>,
+    /// Moves not yet finalized, in time order.
+    pub active: Vec<PullMove<N>>,
+}
+
+impl<const N: usize> Default for TrapQueue<N> {
+    fn default() -> Self {
+        Self {
+            moves: Vec::new(),
+            first: 0,
+            tail: Move {
+                print_time: NEVER_TIME,
+                move_t: NEVER_TIME,
+                ..Move::default()
+            },
             history: VecDeque::new(),
+            merge: None,
+            totals: TrapQueueTotals::default(),
         }
     }
+}
 
-    fn tail_index(&self) -> usize {
-        self.moves.len() - 1
+impl TrapQueue {
+    /// A queue of X, Y and Z moves; other axis counts start from
+    /// [`Default`].
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    fn head_index(&self) -> usize {
-        0
+    /// Convenience builder mirroring the C `trapq_append` helper.
+    #[allow(clippy::too_many_arguments)]
+    pub fn append(
+        &mut self,
+        print_time: f64,
+        accel_t: f64,
+        cruise_t: f64,
+        decel_t: f64,
+        start_pos_x: f64,
+        start_pos_y: f64,
+        start_pos_z: f64,
+        axes_r_x: f64,
+        axes_r_y: f64,
+        axes_r_z: f64,
+        start_v: f64,
+        cruise_v: f64,
+        accel: f64,
+    ) -> Result<()> {
+        self.append_move(
+            print_time,
+            [accel_t, cruise_t, decel_t],
+            Coord::new(start_pos_x, start_pos_y, start_pos_z),
+            Coord::new(axes_r_x, axes_r_y, axes_r_z),
+            start_v,
+            cruise_v,
+            accel,
+        )
     }
 
-    fn tail_mut(&mut self) -> &mut Move {
-        let idx = self.tail_index();
-        self.moves.get_mut(idx).expect("tail sentinel")
+    /// Note a position change; flush pending moves and mark a history entry.
+    pub fn set_position(&mut self, print_time: f64, pos_x: f64, pos_y: f64, pos_z: f64) {
+        self.set_coord(print_time, Coord::new(pos_x, pos_y, pos_z));
     }
+}
 
+impl<const N: usize> TrapQueue<N> {
     /// Update the tail sentinel's print_time and start_pos if it's marked stale.
     pub fn check_sentinels(&mut self) {
-        let tail_idx = self.tail_index();
-        if self.moves[tail_idx].print_time != 0.0 {
+        if self.tail.print_time != 0.0 {
             return;
         }
-        let prev_idx = tail_idx - 1;
-        if prev_idx == self.head_index() {
-            self.moves[tail_idx].print_time = NEVER_TIME;
-            self.moves[tail_idx].move_t = NEVER_TIME;
+        let Some(prev) = self.get_active_moves().last().copied() else {
+            self.tail.print_time = NEVER_TIME;
+            self.tail.move_t = NEVER_TIME;
             return;
-        }
-        let prev = self.moves[prev_idx];
-        let tail = self.tail_mut();
-        tail.print_time = prev.print_time + prev.move_t;
-        tail.move_t = 0.0;
-        tail.start_pos = move_get_coord(&prev, prev.move_t);
+        };
+        self.tail.print_time = move_end(&prev);
+        self.tail.move_t = 0.0;
+        self.tail.start_pos = move_get_coord(&prev, prev.move_t);
     }
 
     /// Add a fully-prepared move, filling gaps with a null move when necessary.
-    pub fn add_move(&mut self, m: Move) {
-        let tail_idx = self.tail_index();
-        let prev = self.moves[tail_idx - 1];
-        if prev.print_time + prev.move_t < m.print_time {
+    ///
+    /// Fails if the move starts before the queued moves end, which would
+    /// leave them out of time order.
+    pub fn add_move(&mut self, m: Move<N>) -> Result<()> {
+        let prev_end = self
+            .get_active_moves()
+            .last()
+            .map_or(head_sentinel::<N>().print_time, move_end);
+        if m.print_time < prev_end - OVERLAP_TOLERANCE {
+            return Err(MotionError::MoveOverlap {
+                print_time: m.print_time,
+                queue_end: prev_end,
+            });
+        }
+        self.totals.add(&m);
+        if let Some(merge) = self.merge
+            && self.first < self.moves.len()
+            && let Some(prev) = self.moves.last_mut()
+            && merge.continues(prev, &m)
+        {
+            prev.move_t = m.print_time + m.move_t - prev.print_time;
+            self.tail.print_time = 0.0;
+            self.tail.move_t = 0.0;
+            return Ok(());
+        }
+        let prev = self
+            .get_active_moves()
+            .last()
+            .copied()
+            .unwrap_or(head_sentinel());
+        if move_end(&prev) < m.print_time {
             let mut null_move = Move {
                 start_pos: m.start_pos,
                 ..Move::default()
@@ -143,49 +468,101 @@ impl TrapQueue {
             if prev.print_time <= 0.0 && m.print_time > MAX_NULL_MOVE {
                 null_move.print_time = m.print_time - MAX_NULL_MOVE;
             } else {
-                null_move.print_time = prev.print_time + prev.move_t;
+                null_move.print_time = move_end(&prev);
             }
             null_move.move_t = m.print_time - null_move.print_time;
-            let insert_at = self.tail_index();
-            self.moves.insert(insert_at, null_move);
+            self.totals.null_moves += 1;
+            self.moves.push(null_move);
         }
-        let insert_at = self.tail_index();
-        self.moves.insert(insert_at, m);
+        self.moves.push(m);
         // mark tail stale so check_sentinels recomputes
-        let tail = self.tail_mut();
-        tail.print_time = 0.0;
-        tail.move_t = 0.0;
+        self.tail.print_time = 0.0;
+        self.tail.move_t = 0.0;
+        Ok(())
     }
 
-    /// Convenience builder mirroring the C `trapq_append` helper.
+    /// Merge each added move into the last active one when it continues it
+    /// within `tolerance`: in time, speed, acceleration, and along the same
+    /// path. Slicers split curves and straight runs into many short
+    /// segments; merging them leaves fewer moves for the iterative solvers to
+    /// search. Moves with different tags are never merged. `None`, the
+    /// default, keeps every move.
+    pub fn set_merge_tolerance(&mut self, tolerance: Option<MergeTolerance>) {
+        self.merge = tolerance;
+    }
+
+    /// Distance, time, peak speed and acceleration, and move counts of every
+    /// move added since the queue was made or
+    /// [`reset_totals`](Self::reset_totals) was last called.
+    pub fn totals(&self) -> TrapQueueTotals<N> {
+        self.totals
+    }
+
+    /// Start counting [`totals`](Self::totals) again from zero.
+    pub fn reset_totals(&mut self) {
+        self.totals = TrapQueueTotals::default();
+    }
+
+    /// Add the accelerating, cruising and decelerating parts of a move
+    /// lasting `[accel_t, cruise_t, decel_t]` from `start_pos` along
+    /// `axes_r`, skipping those of no duration.
     #[allow(clippy::too_many_arguments)]
-    pub fn append(
+    pub fn append_move(
         &mut self,
         print_time: f64,
-        accel_t: f64,
-        cruise_t: f64,
-        decel_t: f64,
-        start_pos_x: f64,
-        start_pos_y: f64,
-        start_pos_z: f64,
-        axes_r_x: f64,
-        axes_r_y: f64,
-        axes_r_z: f64,
+        durations: [f64; 3],
+        start_pos: Coord<N>,
+        axes_r: Coord<N>,
+        start_v: f64,
+        cruise_v: f64,
+        accel: f64,
+    ) -> Result<()> {
+        self.append_parts(
+            None, print_time, durations, start_pos, axes_r, start_v, cruise_v, accel,
+        )
+    }
+
+    /// [`append_move`](Self::append_move), marking each part with `tag` so
+    /// [`tag_at`](Self::tag_at) can tell what queued the move running at a
+    /// given time. The tag is kept when the move is finalized into history.
+    #[allow(clippy::too_many_arguments)]
+    pub fn append_tagged(
+        &mut self,
+        tag: u64,
+        print_time: f64,
+        durations: [f64; 3],
+        start_pos: Coord<N>,
+        axes_r: Coord<N>,
+        start_v: f64,
+        cruise_v: f64,
+        accel: f64,
+    ) -> Result<()> {
+        self.append_parts(
+            Some(tag),
+            print_time,
+            durations,
+            start_pos,
+            axes_r,
+            start_v,
+            cruise_v,
+            accel,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn append_parts(
+        &mut self,
+        tag: Option<u64>,
+        print_time: f64,
+        [accel_t, cruise_t, decel_t]: [f64; 3],
+        start_pos: Coord<N>,
+        axes_r: Coord<N>,
         start_v: f64,
         cruise_v: f64,
         accel: f64,
-    ) {
+    ) -> Result<()> {
         let mut cur_time = print_time;
-        let mut cur_pos = Coord {
-            x: start_pos_x,
-            y: start_pos_y,
-            z: start_pos_z,
-        };
-        let axes_r = Coord {
-            x: axes_r_x,
-            y: axes_r_y,
-            z: axes_r_z,
-        };
+        let mut cur_pos = start_pos;
 
         if accel_t > 0.0 {
             let m = Move {
@@ -195,8 +572,9 @@ impl TrapQueue {
                 half_accel: 0.5 * accel,
                 start_pos: cur_pos,
                 axes_r,
+                tag,
             };
-            self.add_move(m);
+            self.add_move(m)?;
             cur_time += accel_t;
             cur_pos = move_get_coord(&m, accel_t);
         }
@@ -209,8 +587,9 @@ impl TrapQueue {
                 half_accel: 0.0,
                 start_pos: cur_pos,
                 axes_r,
+                tag,
             };
-            self.add_move(m);
+            self.add_move(m)?;
             cur_time += cruise_t;
             cur_pos = move_get_coord(&m, cruise_t);
         }
@@ -223,34 +602,39 @@ impl TrapQueue {
                 half_accel: -0.5 * accel,
                 start_pos: cur_pos,
                 axes_r,
+                tag,
             };
-            self.add_move(m);
+            self.add_move(m)?;
         }
+        Ok(())
     }
 
     /// Expire any moves older than `print_time`, moving them into history.
     pub fn finalize_moves(&mut self, print_time: f64, clear_history_time: f64) {
-        while self.moves.len() > 2 {
-            let m = self.moves[1];
-            if m.print_time + m.move_t > print_time {
-                break;
-            }
-            let moved = self.moves.remove(1).unwrap();
+        let count = self.find_move(print_time);
+        for moved in &self.moves[self.first..self.first + count] {
             if moved.start_v != 0.0 || moved.half_accel != 0.0 {
-                self.history.push_front(moved);
+                self.history.push_front(*moved);
             }
         }
+        self.first += count;
+        if self.first == self.moves.len() {
+            self.moves.clear();
+            self.first = 0;
+        } else if self.first >= COMPACT_THRESHOLD && self.first * 2 >= self.moves.len() {
+            self.moves.drain(..self.first);
+            self.first = 0;
+        }
 
-        if self.moves.len() == 2 {
-            let tail = self.tail_mut();
-            tail.print_time = NEVER_TIME;
-            tail.move_t = NEVER_TIME;
+        if self.moves.is_empty() {
+            self.tail.print_time = NEVER_TIME;
+            self.tail.move_t = NEVER_TIME;
         }
 
         if let Some(latest) = self.history.front().cloned() {
             while self.history.len() > 1 {
                 let last = *self.history.back().unwrap();
-                if last.print_time + last.move_t > clear_history_time {
+                if move_end(&last) > clear_history_time {
                     break;
                 }
                 if last == latest {
@@ -261,8 +645,9 @@ impl TrapQueue {
         }
     }
 
-    /// Note a position change; flush pending moves and mark a history entry.
-    pub fn set_position(&mut self, print_time: f64, pos_x: f64, pos_y: f64, pos_z: f64) {
+    /// Note a position change to `pos`; flush pending moves and mark a
+    /// history entry.
+    pub fn set_coord(&mut self, print_time: f64, pos: Coord<N>) {
         self.finalize_moves(NEVER_TIME, 0.0);
 
         while let Some(first) = self.history.front_mut() {
@@ -277,100 +662,148 @@ impl TrapQueue {
 
         self.history.push_front(Move {
             print_time,
-            start_pos: Coord {
-                x: pos_x,
-                y: pos_y,
-                z: pos_z,
-            },
+            start_pos: pos,
             ..Move::default()
         });
     }
 
     /// Return in-flight and historical moves that overlap the given time window.
-    pub fn extract_old(&self, max: usize, start_time: f64, end_time: f64) -> Vec<PullMove> {
-        let mut result = Vec::new();
-
-        // Iterate active moves (skip head sentinel at index 0, tail sentinel at len-1)
-        for i in (1..self.moves.len() - 1).rev() {
-            let m = &self.moves[i];
-            if m.print_time > end_time {
-                continue;
-            }
-            if m.print_time + m.move_t < start_time {
-                break;
-            }
-            result.push(PullMove {
-                print_time: m.print_time,
-                move_t: m.move_t,
-                start_v: m.start_v,
-                accel: 2.0 * m.half_accel,
-                start_x: m.start_pos.x,
-                start_y: m.start_pos.y,
-                start_z: m.start_pos.z,
-                x_r: m.axes_r.x,
-                y_r: m.axes_r.y,
-                z_r: m.axes_r.z,
-            });
-            if result.len() >= max {
-                break;
-            }
-        }
+    pub fn extract_old(&self, max: usize, start_time: f64, end_time: f64) -> Vec<PullMove<N>> {
+        self.iter_active_range(start_time, end_time)
+            .rev()
+            .chain(self.iter_history_range(start_time, end_time))
+            .take(max)
+            .map(pull_move)
+            .collect()
+    }
 
-        // Iterate history moves
-        for m in self.history.iter().rev() {
-            if m.print_time > end_time {
-                continue;
-            }
-            if m.print_time + m.move_t < start_time {
-                break;
-            }
-            result.push(PullMove {
-                print_time: m.print_time,
-                move_t: m.move_t,
-                start_v: m.start_v,
-                accel: 2.0 * m.half_accel,
-                start_x: m.start_pos.x,
-                start_y: m.start_pos.y,
-                start_z: m.start_pos.z,
-                x_r: m.axes_r.x,
-                y_r: m.axes_r.y,
-                z_r: m.axes_r.z,
-            });
-            if result.len() >= max {
-                break;
-            }
-        }
+    /// Active moves, in time order (for itersolve).
+    pub fn get_active_moves(&self) -> &[Move<N>] {
+        &self.moves[self.first..]
+    }
 
-        result
+    /// Index into [`get_active_moves`](Self::get_active_moves) of the first
+    /// move still running after `print_time`, or the number of active moves
+    /// if all have finished.
+    pub fn find_move(&self, print_time: f64) -> usize {
+        self.get_active_moves()
+            .partition_point(|m| move_end(m) <= print_time)
     }
 
-    /// Get active moves as references (for itersolve)
-    /// Returns moves between start and end sentinels
-    pub fn get_active_moves(&self) -> Vec<&Move> {
-        if self.moves.len() <= 2 {
-            Vec::new()
-        } else {
-            // Skip head sentinel at 0 and tail sentinel at len-1
-            self.moves.range(1..self.moves.len() - 1).collect()
-        }
+    /// Active moves overlapping `start_time..=end_time`.
+    pub fn get_active_range(&self, start_time: f64, end_time: f64) -> &[Move<N>] {
+        let moves = self.get_active_moves();
+        let start = moves.partition_point(|m| move_end(m) < start_time);
+        let end = moves.partition_point(|m| m.print_time <= end_time);
+        &moves[start..end.max(start)]
+    }
+
+    /// Active moves, in time order, without collecting them.
+    pub fn iter_active(&self) -> slice::Iter<'_, Move<N>> {
+        self.get_active_moves().iter()
+    }
+
+    /// Active moves overlapping `start_time..=end_time`, in time order.
+    pub fn iter_active_range(&self, start_time: f64, end_time: f64) -> slice::Iter<'_, Move<N>> {
+        self.get_active_range(start_time, end_time).iter()
+    }
+
+    /// Finalized moves, newest first.
+    pub fn iter_history(&self) -> vec_deque::Iter<'_, Move<N>> {
+        self.history.iter()
+    }
+
+    /// Finalized moves overlapping `start_time..=end_time`, newest first.
+    pub fn iter_history_range(
+        &self,
+        start_time: f64,
+        end_time: f64,
+    ) -> impl Iterator<Item = &Move<N>> + '_ {
+        // History is newest first, so skip the moves starting after the window
+        let skip = self.history.partition_point(|m| m.print_time > end_time);
+        self.history
+            .range(skip..)
+            .take_while(move |m| move_end(m) >= start_time)
+    }
+
+    /// Tag of the move running at `print_time`, if it was queued with
+    /// [`append_tagged`](Self::append_tagged) and is still active or in
+    /// history. Null moves filling gaps are untagged.
+    pub fn tag_at(&self, print_time: f64) -> Option<u64> {
+        self.move_at(print_time).and_then(|m| m.tag)
+    }
+
+    /// Position, velocity and acceleration at `print_time`, if a move still
+    /// active or in history covers it.
+    pub fn state_at(&self, print_time: f64) -> Option<KinematicState<N>> {
+        let m = self.move_at(print_time)?;
+        let move_time = print_time - m.print_time;
+        let speed = m.start_v + 2.0 * m.half_accel * move_time;
+        Some(KinematicState {
+            pos: move_get_coord(m, move_time),
+            velocity: Coord::default().offset(&m.axes_r, speed),
+            accel: Coord::default().offset(&m.axes_r, 2.0 * m.half_accel),
+        })
+    }
+
+    /// The active or finalized move running at `print_time`.
+    fn move_at(&self, print_time: f64) -> Option<&Move<N>> {
+        self.iter_active_range(print_time, print_time)
+            .chain(self.iter_history_range(print_time, print_time))
+            .find(|m| m.print_time <= print_time && print_time < move_end(m))
     }
 
     /// Get history moves as references
-    pub fn get_history_moves(&self) -> Vec<&Move> {
-        self.history.iter().collect()
+    pub fn get_history_moves(&self) -> Vec<&Move<N>> {
+        self.iter_history().collect()
     }
 
     /// Current active moves (excluding sentinels). Useful for tests/inspection.
     pub fn active_len(&self) -> usize {
-        self.moves.len().saturating_sub(2)
+        self.moves.len() - self.first
     }
 
     pub fn history_len(&self) -> usize {
         self.history.len()
     }
 
-    pub fn tail_sentinel(&self) -> Move {
-        *self.moves.back().expect("tail sentinel")
+    pub fn tail_sentinel(&self) -> Move<N> {
+        self.tail
+    }
+
+    /// Capture the active moves and history.
+    pub fn snapshot(&self) -> TrapQueueState<N> {
+        TrapQueueState {
+            moves: self.get_active_moves().to_vec(),
+            history: self.history.iter().copied().collect(),
+        }
+    }
+
+    /// The active moves and history, for exporting as JSON or replaying
+    /// offline by adding them to a new queue.
+    pub fn dump(&self) -> TrapQueueDump<N> {
+        let history: Vec<_> = self.iter_history().rev().map(pull_move).collect();
+        let active: Vec<_> = self.iter_active().map(pull_move).collect();
+        let first = history.first().or(active.first());
+        let last = active.last().or(history.last());
+        TrapQueueDump {
+            start_time: first.map_or(0.0, |m| m.print_time),
+            end_time: last.map_or(0.0, |m| m.print_time + m.move_t),
+            history,
+            active,
+        }
+    }
+
+    /// Rebuild a queue from [`snapshot`](Self::snapshot).
+    pub fn from_state(state: TrapQueueState<N>) -> Self {
+        let mut tq = Self {
+            moves: state.moves,
+            history: state.history.into(),
+            ..Self::default()
+        };
+        tq.tail.print_time = 0.0;
+        tq.check_sentinels();
+        tq
     }
 }
 
@@ -383,7 +816,8 @@ mod tests {
         let mut tq = TrapQueue::new();
         tq.append(
             0.0, 1.0, 2.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 2.0,
-        );
+        )
+        .unwrap();
         assert_eq!(tq.active_len(), 4); // initial null move + 3 segments
         tq.check_sentinels();
         let tail = tq.tail_sentinel();
@@ -398,22 +832,50 @@ mod tests {
             move_t: 0.5,
             ..Move::default()
         };
-        tq.add_move(m1);
+        tq.add_move(m1).unwrap();
         let m2 = Move {
             print_time: 2.0,
             move_t: 0.5,
             ..Move::default()
         };
-        tq.add_move(m2);
+        tq.add_move(m2).unwrap();
         assert_eq!(tq.active_len(), 4); // initial null + m1 + gap null + m2
     }
 
+    #[test]
+    fn rejects_overlapping_moves() {
+        let mut tq = TrapQueue::new();
+        tq.append(
+            1.0, 0.5, 0.0, 0.5, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 10.0, 20.0,
+        )
+        .unwrap();
+        let err = tq
+            .append(
+                1.5, 0.5, 0.0, 0.5, 5.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 10.0, 20.0,
+            )
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            MotionError::MoveOverlap {
+                print_time: 1.5,
+                queue_end: 2.0
+            }
+        ));
+        assert_eq!(tq.active_len(), 3);
+        // Starting exactly where the queue ends is fine
+        tq.append(
+            2.0, 0.5, 0.0, 0.5, 5.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 10.0, 20.0,
+        )
+        .unwrap();
+    }
+
     #[test]
     fn finalizes_into_history() {
         let mut tq = TrapQueue::new();
         tq.append(
             0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.5, 0.0, 1.0,
-        );
+        )
+        .unwrap();
         tq.finalize_moves(2.0, 0.0);
         assert_eq!(tq.active_len(), 0);
         assert!(tq.history_len() >= 1);
@@ -426,7 +888,8 @@ mod tests {
         let mut tq = TrapQueue::new();
         tq.append(
             0.0, 0.5, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 1.0,
-        );
+        )
+        .unwrap();
 
         // Before finalize, should have null move + actual move
         let pulled = tq.extract_old(4, 0.0, 2.0);
@@ -444,12 +907,350 @@ mod tests {
         let mut tq = TrapQueue::new();
         tq.append(
             0.0, 0.5, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 1.0,
-        );
+        )
+        .unwrap();
         tq.finalize_moves(2.0, 0.0);
         tq.set_position(0.25, 1.0, 2.0, 3.0);
         assert!(tq.history_len() >= 1);
         let marker = tq.history.front().unwrap();
         assert_eq!(marker.print_time, 0.25);
-        assert_eq!(marker.start_pos.x, 1.0);
+        assert_eq!(marker.start_pos.x(), 1.0);
+    }
+
+    #[test]
+    fn queues_moves_over_more_axes() {
+        let mut tq = TrapQueue::<5>::default();
+        let start = Coord::from([1.0, 2.0, 3.0, 4.0, 5.0]);
+        let axes_r = Coord::from([0.0, 0.0, 0.0, 0.6, 0.8]);
+        tq.append_move(0.0, [1.0, 0.0, 1.0], start, axes_r, 0.0, 1.0, 1.0)
+            .unwrap();
+        tq.check_sentinels();
+        let end = tq.tail_sentinel().start_pos;
+        assert_eq!([end.x(), end.y(), end.z()], [1.0, 2.0, 3.0]);
+        assert!((end.axes[3] - 4.6).abs() < 1e-9 && (end.axes[4] - 5.8).abs() < 1e-9);
+
+        let json = serde_json::to_string(&tq.snapshot()).unwrap();
+        let restored = TrapQueue::from_state(serde_json::from_str(&json).unwrap());
+        assert_eq!(restored.get_active_moves(), tq.get_active_moves());
+        assert!(serde_json::from_str::<Coord<5>>("[1, 2, 3]").is_err());
+    }
+
+    #[test]
+    fn finds_moves_by_time_across_compaction() {
+        let mut tq = TrapQueue::new();
+        let count = 3 * COMPACT_THRESHOLD;
+        for i in 0..count {
+            tq.add_move(Move {
+                print_time: i as f64,
+                move_t: 1.0,
+                start_v: 1.0,
+                ..Move::default()
+            })
+            .unwrap();
+        }
+        // After the initial null move
+        assert_eq!(tq.active_len(), count + 1);
+        assert_eq!(tq.find_move(10.5), 11);
+        assert_eq!(tq.find_move(11.0), 12);
+        let range = tq.get_active_range(10.5, 12.0);
+        let starts: Vec<_> = range.iter().map(|m| m.print_time).collect();
+        assert_eq!(starts, [10.0, 11.0, 12.0]);
+        assert!(tq.get_active_range(-5.0, -2.0).is_empty());
+
+        tq.finalize_moves(2000.0, 1990.0);
+        assert_eq!(tq.active_len(), count - 2000);
+        assert_eq!(tq.get_active_moves()[0].print_time, 2000.0);
+        assert_eq!(tq.find_move(2500.5), 500);
+        let pulled = tq.extract_old(3, 1998.5, 2001.0);
+        let starts: Vec<_> = pulled.iter().map(|m| m.print_time).collect();
+        assert_eq!(starts, [2001.0, 2000.0, 1999.0]);
+
+        tq.finalize_moves(count as f64, 0.0);
+        assert_eq!(tq.active_len(), 0);
+        assert!(tq.get_active_moves().is_empty());
+    }
+
+    #[test]
+    fn tags_follow_moves_into_history() {
+        let mut tq = TrapQueue::new();
+        let axes_r = Coord::new(1.0, 0.0, 0.0);
+        tq.append_tagged(
+            7,
+            1.0,
+            [0.5, 1.0, 0.5],
+            Coord::default(),
+            axes_r,
+            0.0,
+            2.0,
+            4.0,
+        )
+        .unwrap();
+        tq.append_move(
+            3.0,
+            [0.0, 1.0, 0.0],
+            Coord::new(3.0, 0.0, 0.0),
+            axes_r,
+            0.0,
+            2.0,
+            0.0,
+        )
+        .unwrap();
+        tq.append_tagged(
+            9,
+            5.0,
+            [0.0, 1.0, 0.0],
+            Coord::new(5.0, 0.0, 0.0),
+            axes_r,
+            0.0,
+            2.0,
+            0.0,
+        )
+        .unwrap();
+
+        // The null move filling the gap before 5.0 is untagged
+        assert_eq!(tq.tag_at(0.5), None);
+        assert_eq!(tq.tag_at(1.2), Some(7));
+        assert_eq!(tq.tag_at(2.9), Some(7));
+        assert_eq!(tq.tag_at(3.5), None);
+        assert_eq!(tq.tag_at(4.5), None);
+        assert_eq!(tq.tag_at(5.5), Some(9));
+
+        tq.finalize_moves(4.0, 0.0);
+        assert_eq!(tq.tag_at(2.0), Some(7));
+        assert_eq!(tq.tag_at(5.5), Some(9));
+        let pulled = tq.extract_old(8, 0.0, 10.0);
+        let tags: Vec<_> = pulled.iter().map(|m| m.tag).collect();
+        assert_eq!(tags, [Some(9), None, None, Some(7), Some(7), Some(7)]);
+    }
+
+    #[test]
+    fn evaluates_state_within_moves() {
+        let mut tq = TrapQueue::new();
+        let axes_r = Coord::new(0.6, 0.8, 0.0);
+        tq.append_move(
+            1.0,
+            [0.5, 1.0, 0.5],
+            Coord::new(10.0, 20.0, 5.0),
+            axes_r,
+            0.0,
+            2.0,
+            4.0,
+        )
+        .unwrap();
+
+        // Halfway through the acceleration: 0.125 along at 1.0 per second
+        let state = tq.state_at(1.25).unwrap();
+        let close = |a: Coord, b: [f64; 3]| a.axes.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-9);
+        assert!(close(state.pos, [10.075, 20.1, 5.0]), "{state:?}");
+        assert!(close(state.velocity, [0.6, 0.8, 0.0]), "{state:?}");
+        assert!(close(state.accel, [2.4, 3.2, 0.0]), "{state:?}");
+
+        // Cruising, then decelerating
+        let state = tq.state_at(2.0).unwrap();
+        assert!(close(state.pos, [10.9, 21.2, 5.0]));
+        assert!(close(state.accel, [0.0; 3]));
+        let state = tq.state_at(2.75).unwrap();
+        assert!(close(state.velocity, [0.6, 0.8, 0.0]));
+        assert!(close(state.accel, [-2.4, -3.2, 0.0]));
+
+        // Still answers once the moves are history, but not past the end
+        tq.finalize_moves(5.0, 0.0);
+        assert!(close(tq.state_at(2.0).unwrap().pos, [10.9, 21.2, 5.0]));
+        assert_eq!(tq.state_at(3.5), None);
+    }
+
+    #[test]
+    fn dump_exports_and_replays_moves() {
+        let mut tq = TrapQueue::new();
+        tq.append_tagged(
+            3,
+            1.0,
+            [0.5, 1.0, 0.5],
+            Coord::default(),
+            Coord::new(1.0, 0.0, 0.0),
+            0.0,
+            2.0,
+            4.0,
+        )
+        .unwrap();
+        tq.finalize_moves(1.5, 0.0);
+
+        let dump = tq.dump();
+        assert_eq!(dump.start_time, 1.0);
+        assert_eq!(dump.end_time, 3.0);
+        let starts: Vec<_> = dump.history.iter().map(|m| m.print_time).collect();
+        assert_eq!(starts, [1.0]);
+        let starts: Vec<_> = dump.active.iter().map(|m| m.print_time).collect();
+        assert_eq!(starts, [1.5, 2.5]);
+        assert_eq!(dump.active[1].accel, -4.0);
+
+        let json = serde_json::to_string(&dump).unwrap();
+        let dump: TrapQueueDump = serde_json::from_str(&json).unwrap();
+        let mut replay = TrapQueue::new();
+        for m in dump.history.iter().chain(&dump.active) {
+            replay.add_move((*m).into()).unwrap();
+        }
+        // After the null move filling the gap from the head sentinel
+        let moves = [dump.history, dump.active].concat();
+        assert!(replay.dump().active.ends_with(&moves));
+        assert_eq!(replay.state_at(2.0), tq.state_at(2.0));
+        assert_eq!(replay.tag_at(2.75), Some(3));
+
+        assert_eq!(TrapQueue::new().dump(), TrapQueueDump::default());
+    }
+
+    #[test]
+    fn merges_continuing_moves_when_enabled() {
+        let segments = |tq: &mut TrapQueue| {
+            // A straight run split into 1024 cruising segments, then a corner
+            let axes_r = Coord::new(0.6, 0.8, 0.0);
+            for i in 0..1024 {
+                let t = i as f64 / 1024.0;
+                let pos = Coord::default().offset(&axes_r, 100.0 * t);
+                tq.append_move(
+                    1.0 + t,
+                    [0.0, 1.0 / 1024.0, 0.0],
+                    pos,
+                    axes_r,
+                    0.0,
+                    100.0,
+                    0.0,
+                )
+                .unwrap();
+            }
+            tq.append_move(
+                2.0,
+                [0.0, 0.5, 0.0],
+                Coord::new(60.0, 80.0, 0.0),
+                Coord::new(1.0, 0.0, 0.0),
+                0.0,
+                100.0,
+                0.0,
+            )
+            .unwrap();
+        };
+        let mut plain = TrapQueue::new();
+        segments(&mut plain);
+        let mut merged = TrapQueue::new();
+        merged.set_merge_tolerance(Some(MergeTolerance::default()));
+        segments(&mut merged);
+
+        // The null move before the first segment, the run, and the corner
+        assert_eq!(plain.active_len(), 1026);
+        assert_eq!(merged.active_len(), 3);
+        for t in [1.0, 1.2345, 1.999, 2.2] {
+            let (a, b) = (plain.state_at(t).unwrap(), merged.state_at(t).unwrap());
+            let dist = a.pos.offset(&b.pos, -1.0);
+            assert!(dist.dot(&dist).sqrt() < 1e-6, "{a:?} {b:?}");
+            assert_eq!(a.velocity, b.velocity);
+        }
+
+        // Tagged moves only merge with moves of the same statement
+        let mut tagged = TrapQueue::new();
+        tagged.set_merge_tolerance(Some(MergeTolerance::default()));
+        let axes_r = Coord::new(1.0, 0.0, 0.0);
+        for (tag, start) in [(1, 0.0), (1, 1.0), (2, 2.0)] {
+            let pos = Coord::new(start, 0.0, 0.0);
+            tagged
+                .append_tagged(
+                    tag,
+                    1.0 + start,
+                    [0.0, 1.0, 0.0],
+                    pos,
+                    axes_r,
+                    0.0,
+                    1.0,
+                    0.0,
+                )
+                .unwrap();
+        }
+        assert_eq!(tagged.active_len(), 3);
+        assert_eq!(tagged.tag_at(2.5), Some(1));
+        assert_eq!(tagged.tag_at(3.5), Some(2));
+    }
+
+    #[test]
+    fn totals_count_moves_since_reset() {
+        let mut tq = TrapQueue::new();
+        tq.set_merge_tolerance(Some(MergeTolerance::default()));
+        tq.append_move(
+            1.0,
+            [0.5, 1.0, 0.5],
+            Coord::default(),
+            Coord::new(0.6, -0.8, 0.0),
+            0.0,
+            2.0,
+            4.0,
+        )
+        .unwrap();
+        // After a pause, back along X
+        tq.append_move(
+            5.0,
+            [0.0, 2.0, 0.0],
+            Coord::new(1.8, -2.4, 0.0),
+            Coord::new(-1.0, 0.0, 0.0),
+            1.0,
+            1.0,
+            0.0,
+        )
+        .unwrap();
+
+        let totals = tq.totals();
+        // 0.5 + 2 + 0.5 along the first move, then 2 back
+        let expected = [0.6 * 3.0 + 2.0, 0.8 * 3.0, 0.0];
+        for (axis, expected) in totals.distance.axes.iter().zip(expected) {
+            assert!((axis - expected).abs() < 1e-9, "{totals:?}");
+        }
+        assert_eq!(totals.move_time, 4.0);
+        assert_eq!(totals.max_velocity, 2.0);
+        assert_eq!(totals.max_accel, 4.0);
+        // Merging does not change what was added
+        assert_eq!(totals.moves, 4);
+        // Before the first move and in the pause
+        assert_eq!(totals.null_moves, 2);
+
+        tq.reset_totals();
+        tq.append_move(
+            7.0,
+            [0.0, 1.0, 0.0],
+            Coord::new(-0.2, -2.4, 0.0),
+            Coord::new(0.0, 0.0, 1.0),
+            3.0,
+            3.0,
+            0.0,
+        )
+        .unwrap();
+        let totals = tq.totals();
+        assert_eq!(totals.distance, Coord::new(0.0, 0.0, 3.0));
+        assert_eq!((totals.moves, totals.null_moves), (1, 0));
+        assert_eq!(totals.max_velocity, 3.0);
+    }
+
+    #[test]
+    fn iterates_moves_in_time_windows() {
+        let mut tq = TrapQueue::new();
+        for i in 0..10 {
+            tq.add_move(Move {
+                print_time: i as f64,
+                move_t: 1.0,
+                start_v: 1.0,
+                ..Move::default()
+            })
+            .unwrap();
+        }
+        tq.finalize_moves(5.0, 0.0);
+
+        let starts = |moves: &mut dyn Iterator<Item = &Move>| -> Vec<f64> {
+            moves.map(|m| m.print_time).collect()
+        };
+        assert_eq!(starts(&mut tq.iter_active()), [5.0, 6.0, 7.0, 8.0, 9.0]);
+        assert_eq!(starts(&mut tq.iter_active_range(6.5, 8.0)), [6.0, 7.0, 8.0]);
+        assert_eq!(starts(&mut tq.iter_history()), [4.0, 3.0, 2.0, 1.0, 0.0]);
+        assert_eq!(
+            starts(&mut tq.iter_history_range(1.5, 3.5)),
+            [3.0, 2.0, 1.0]
+        );
+        assert!(tq.iter_history_range(20.0, 30.0).next().is_none());
+        assert!(tq.iter_history().eq(tq.get_history_moves()));
     }
 }
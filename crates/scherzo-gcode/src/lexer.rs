@@ -73,6 +73,19 @@ pub enum LexError {
     UnterminatedString { line: usize, column: usize },
 }
 
+impl LexError {
+    /// Line and column at which the error was detected.
+    pub fn position(&self) -> (usize, usize) {
+        match self {
+            LexError::UnexpectedChar { line, column, .. }
+            | LexError::InvalidNumber { line, column, .. }
+            | LexError::InvalidChecksum { line, column, .. }
+            | LexError::UnterminatedComment { line, column }
+            | LexError::UnterminatedString { line, column } => (*line, *column),
+        }
+    }
+}
+
 pub fn lex(input: &str) -> Lexer<'_> {
     Lexer::new(input)
 }
@@ -30,6 +30,21 @@ pub enum ParseError {
     MultipleChecksums { line: usize },
 }
 
+impl ParseError {
+    /// Line (and column, when known) at which the error was detected.
+    pub fn position(&self) -> (usize, Option<usize>) {
+        match self {
+            ParseError::Lex(err) => {
+                let (line, column) = err.position();
+                (line, Some(column))
+            }
+            ParseError::MultipleComments { line } | ParseError::MultipleChecksums { line } => {
+                (*line, None)
+            }
+        }
+    }
+}
+
 /// Parse G-code from a string using the lexer.
 pub fn parse(input: &str) -> Result<Vec<Statement>, ParseError> {
     let lines: Vec<String> = input.lines().map(|l| l.to_string()).collect();
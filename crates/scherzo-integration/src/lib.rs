@@ -0,0 +1,229 @@
+//! Harness for end-to-end tests of the whole pipeline.
+//!
+//! [`Server`] runs the real `scherzo start` binary against a generated
+//! config with the virtual MCU backend, and talks to it over HTTP.
+//! [`replay_on_virtual_mcu`] sends a simulated job's step commands to a
+//! [`VirtualMcu`] over the wire protocol and reports where each stepper
+//! ended up.
+
+use anyhow::{Context, Result, bail};
+use scherzo_compile::simulate::Simulation;
+use scherzo_core::step_compressor::{Command, CommandSink};
+use scherzo_mcu::{
+    steps::StepSink,
+    virtual_mcu::{Loopback, VirtualMcu},
+};
+use serde_json::Value;
+use std::{
+    fs,
+    net::TcpListener,
+    path::PathBuf,
+    process::{Child, Command as Process, Stdio},
+    sync::OnceLock,
+    thread,
+    time::{Duration, Instant},
+};
+use tempfile::TempDir;
+use ureq::{Agent, http::Response};
+
+/// How long the server gets to start listening.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Path of the `scherzo` binary, built on first use.
+///
+/// `SCHERZO_BIN` overrides it, e.g. to test a release build.
+pub fn scherzo_binary() -> Result<PathBuf> {
+    static BINARY: OnceLock<Result<PathBuf, String>> = OnceLock::new();
+    BINARY
+        .get_or_init(|| build_scherzo().map_err(|e| format!("{e:#}")))
+        .clone()
+        .map_err(anyhow::Error::msg)
+}
+
+fn build_scherzo() -> Result<PathBuf> {
+    if let Some(path) = std::env::var_os("SCHERZO_BIN") {
+        return Ok(path.into());
+    }
+    let cargo = std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let output = Process::new(cargo)
+        .args([
+            "build",
+            "--package",
+            "scherzo",
+            "--bin",
+            "scherzo",
+            "--message-format=json-render-diagnostics",
+        ])
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .stderr(Stdio::inherit())
+        .output()
+        .context("failed to run cargo build")?;
+    if !output.status.success() {
+        bail!("cargo build of scherzo failed");
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .find(|message| message["target"]["name"] == "scherzo" && message["executable"].is_string())
+        .and_then(|message| message["executable"].as_str().map(PathBuf::from))
+        .context("cargo build did not report the scherzo executable")
+}
+
+/// A running `scherzo start`, stopped when dropped.
+pub struct Server {
+    child: Child,
+    agent: Agent,
+    base_url: String,
+    /// Holds the config and job storage.
+    _dir: TempDir,
+}
+
+impl Server {
+    /// Start the server with `sections` (TOML) appended to a config that
+    /// sets the port, the job storage and the virtual MCU.
+    pub fn start(sections: &str) -> Result<Self> {
+        let binary = scherzo_binary()?;
+        let dir = tempfile::tempdir()?;
+        let port = free_port()?;
+        let config = format!(
+            "[server]\nport = {port}\n\n[jobs]\nstorage_dir = {jobs:?}\n\n[mcu]\nserial = \"virtual\"\n\n{sections}",
+            jobs = dir.path().join("jobs"),
+        );
+        let config_path = dir.path().join("scherzo.toml");
+        fs::write(&config_path, config)?;
+
+        let child = Process::new(&binary)
+            .arg("start")
+            .arg(&config_path)
+            .stdout(Stdio::null())
+            .spawn()
+            .with_context(|| format!("failed to start {}", binary.display()))?;
+        // Non-2xx responses carry useful messages, so handle them ourselves
+        let agent = Agent::config_builder()
+            .http_status_as_error(false)
+            .build()
+            .into();
+        let mut server = Self {
+            child,
+            agent,
+            base_url: format!("http://127.0.0.1:{port}"),
+            _dir: dir,
+        };
+        server.wait_until_ready()?;
+        Ok(server)
+    }
+
+    fn wait_until_ready(&mut self) -> Result<()> {
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        loop {
+            if let Some(status) = self.child.try_wait()? {
+                bail!("server exited during startup with {status}");
+            }
+            let health = self.agent.get(self.url("/health")).call();
+            if health.is_ok_and(|response| response.status().is_success()) {
+                return Ok(());
+            }
+            if Instant::now() > deadline {
+                bail!("server did not start within {STARTUP_TIMEOUT:?}");
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.base_url)
+    }
+
+    pub fn get(&self, path: &str) -> Result<Value> {
+        handle_response("GET", path, self.agent.get(self.url(path)).call())
+    }
+
+    pub fn post(&self, path: &str, content_type: &str, body: &[u8]) -> Result<Value> {
+        let request = self.agent.post(self.url(path)).content_type(content_type);
+        handle_response("POST", path, request.send(body))
+    }
+
+    pub fn post_empty(&self, path: &str) -> Result<Value> {
+        handle_response("POST", path, self.agent.post(self.url(path)).send_empty())
+    }
+
+    pub fn post_json(&self, path: &str, body: &Value) -> Result<Value> {
+        handle_response(
+            "POST",
+            path,
+            self.agent.post(self.url(path)).send_json(body),
+        )
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn handle_response(
+    method: &str,
+    path: &str,
+    response: Result<Response<ureq::Body>, ureq::Error>,
+) -> Result<Value> {
+    let mut response = response.with_context(|| format!("{method} {path} failed"))?;
+    let status = response.status();
+    let body = response.body_mut().read_to_string()?;
+    if !status.is_success() {
+        bail!("{method} {path}: {status}: {}", body.trim());
+    }
+    serde_json::from_str(&body)
+        .with_context(|| format!("invalid JSON in response to {method} {path}"))
+}
+
+/// A TCP port nothing is listening on right now.
+fn free_port() -> Result<u16> {
+    Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+}
+
+/// Send every step command of `simulation` to a virtual MCU running at
+/// `mcu_freq` and return each stepper's final position (steps), in the
+/// simulation's stepper order.
+pub fn replay_on_virtual_mcu(simulation: &Simulation, mcu_freq: f64) -> Result<Vec<i64>> {
+    const CONFIG_STEPPER: &str =
+        "config_stepper oid=%c step_pin=%c dir_pin=%c invert_step=%c step_pulse_ticks=%u";
+
+    let mut link = Loopback::new(VirtualMcu::new(mcu_freq));
+    let dictionary = link.mcu.dictionary().clone();
+    let mut queues = Vec::new();
+    for oid in 0..simulation.steps.len() as u32 {
+        link.send(
+            CONFIG_STEPPER,
+            &[
+                oid.into(),
+                (2 * oid).into(),
+                (2 * oid + 1).into(),
+                0u32.into(),
+                0u32.into(),
+            ],
+        )?;
+        queues.push(link.host.alloc_command_queue());
+    }
+    link.run_until(0.0);
+
+    for record in &simulation.trace {
+        let oid = match &record.command {
+            Command::QueueStep(step) => step.oid,
+            Command::SetNextStepDir(dir) => dir.oid,
+        };
+        let queue = *queues
+            .get(oid as usize)
+            .with_context(|| format!("no stepper with oid {oid}"))?;
+        StepSink::new(&dictionary, &mut link.host, queue)?.push(record.command.clone());
+    }
+    link.run_until(simulation.print_time + 0.5);
+
+    if let Some(reason) = link.mcu.shutdown_reason() {
+        bail!("virtual MCU shut down: {reason}");
+    }
+    Ok((0..simulation.steps.len())
+        .map(|oid| link.mcu.position(oid as u8).unwrap_or_default())
+        .collect())
+}
@@ -0,0 +1,104 @@
+use scherzo_compile::simulate::{SimulationConfig, simulate_gcode};
+use scherzo_core::planner::MachineLimits;
+use scherzo_integration::{Server, replay_on_virtual_mcu};
+use serde_json::json;
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+/// 80 steps/mm on X and Y, 400 on Z and 100 on the extruder, matching
+/// [`SimulationConfig::default`].
+const PRINTER: &str = r#"
+[printer]
+kinematics = "cartesian"
+max_velocity = 300
+max_accel = 3000
+
+[stepper_x]
+rotation_distance = 40
+microsteps = 16
+position_max = 200
+
+[stepper_y]
+rotation_distance = 40
+microsteps = 16
+position_max = 200
+
+[stepper_z]
+rotation_distance = 8
+microsteps = 16
+position_max = 200
+
+[extruder]
+rotation_distance = 32
+microsteps = 16
+"#;
+
+/// One square perimeter, ending lifted above its first corner.
+const JOB: &str = "\
+G21
+G90
+M82
+G92 E0
+G1 Z0.3 F600
+G1 X10 Y10 F6000
+G1 X50 Y10 E2.0 F1800
+G1 X50 Y50 E4.0
+G1 X10 Y50 E6.0
+G1 X10 Y10 E8.0
+G1 Z5 F600
+";
+
+const FINAL_POSITION: [f64; 4] = [10.0, 10.0, 5.0, 8.0];
+
+#[test]
+fn uploaded_job_runs_to_its_final_position() {
+    let server = Server::start(PRINTER).unwrap();
+
+    // Upload: the server compiles the G-code into a job component
+    let upload = server
+        .post("/jobs", "text/x-gcode", JOB.as_bytes())
+        .unwrap();
+    assert_eq!(upload["compiled_from"], "gcode");
+    let id = upload["job_id"].as_str().unwrap().to_string();
+    let job = server.get(&format!("/jobs/{id}")).unwrap();
+    assert_eq!(job["status"], "uploaded");
+    assert_eq!(job["original_format"], "gcode");
+
+    // Enqueue: the server runs the job's moves on its motion system
+    let job = server.post_empty(&format!("/jobs/{id}/enqueue")).unwrap();
+    assert_eq!(job["status"], "enqueued");
+    let deadline = Instant::now() + Duration::from_secs(30);
+    let job = loop {
+        let job = server.get(&format!("/jobs/{id}")).unwrap();
+        if job["status"] != "enqueued" && job["status"] != "running" {
+            break job;
+        }
+        assert!(Instant::now() < deadline, "job did not finish: {job}");
+        thread::sleep(Duration::from_millis(20));
+    };
+    assert_eq!(job["status"], "completed");
+    assert_eq!(job["print_stats"]["progress"], 1.0);
+    let queue = server.get("/queue").unwrap();
+    assert_eq!(queue["jobs"], json!([]));
+    let printer = server.get("/printer").unwrap();
+    let position: Vec<f64> = serde_json::from_value(printer["position"].clone()).unwrap();
+    for (axis, (actual, expected)) in position.iter().zip(FINAL_POSITION).enumerate() {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "axis {axis}: {position:?}"
+        );
+    }
+
+    // Step the same job on a virtual MCU over the wire protocol
+    let config = SimulationConfig::default();
+    let simulation = simulate_gcode(JOB, &MachineLimits::default(), &config).unwrap();
+    let steps = replay_on_virtual_mcu(&simulation, config.mcu_freq).unwrap();
+    let expected: Vec<i64> = FINAL_POSITION
+        .iter()
+        .zip(config.steps_per_mm)
+        .map(|(position, steps_per_mm)| (position * steps_per_mm).round() as i64)
+        .collect();
+    assert_eq!(steps, expected);
+}
@@ -0,0 +1,626 @@
+//! MCU data dictionaries.
+//!
+//! Each firmware build embeds a zlib-compressed JSON dictionary listing the
+//! commands it accepts, the responses it sends, and build constants such as
+//! `CLOCK_FREQ`. Messages are a variable-length message id followed by the
+//! parameters declared in the message's format string, e.g.
+//! `queue_step oid=%c interval=%u count=%hu add=%hi`.
+
+use crate::msgblock::{MESSAGE_PAYLOAD_MAX, decode_int, encode_int};
+use flate2::read::ZlibDecoder;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Read,
+};
+use thiserror::Error;
+
+/// Format of the command used to fetch the dictionary.
+pub const IDENTIFY: &str = "identify offset=%u count=%c";
+/// Format of the MCU's reply to [`IDENTIFY`].
+pub const IDENTIFY_RESPONSE: &str = "identify_response offset=%u data=%.*s";
+
+#[derive(Debug, Error)]
+pub enum ProtocolError {
+    #[error("invalid data dictionary: {0}")]
+    InvalidDictionary(String),
+    #[error("invalid message format `{0}`")]
+    InvalidFormat(String),
+    #[error("MCU does not support command `{0}`")]
+    UnknownCommand(String),
+    #[error("MCU declares `{found}` but `{expected}` was expected")]
+    FormatMismatch { expected: String, found: String },
+    #[error("unknown message id {0}")]
+    UnknownMessageId(u32),
+    #[error("message `{0}` is truncated")]
+    Truncated(String),
+    #[error("`{command}` takes {expected} argument(s) but {found} were given")]
+    ArgumentCount {
+        command: String,
+        expected: usize,
+        found: usize,
+    },
+    #[error("`{command}`: invalid value for `{param}`")]
+    InvalidArgument { command: String, param: String },
+    #[error("unknown {enumeration} `{value}`")]
+    UnknownEnumeration { enumeration: String, value: String },
+}
+
+pub type Result<T, E = ProtocolError> = std::result::Result<T, E>;
+
+/// Wire type of a message parameter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParamType {
+    /// `%u`
+    U32,
+    /// `%i`
+    I32,
+    /// `%hu`
+    U16,
+    /// `%hi`
+    I16,
+    /// `%c`
+    Byte,
+    /// `%s`
+    String,
+    /// `%.*s`
+    ProgmemBuffer,
+    /// `%*s`
+    Buffer,
+}
+
+impl ParamType {
+    /// Longest specifiers first so prefixes do not shadow them.
+    const SPECIFIERS: [(&'static str, ParamType); 8] = [
+        ("%.*s", ParamType::ProgmemBuffer),
+        ("%*s", ParamType::Buffer),
+        ("%hu", ParamType::U16),
+        ("%hi", ParamType::I16),
+        ("%u", ParamType::U32),
+        ("%i", ParamType::I32),
+        ("%c", ParamType::Byte),
+        ("%s", ParamType::String),
+    ];
+
+    fn parse_prefix(s: &str) -> Option<(Self, usize)> {
+        Self::SPECIFIERS
+            .iter()
+            .find(|(spec, _)| s.starts_with(spec))
+            .map(|&(spec, ty)| (ty, spec.len()))
+    }
+
+    fn is_bytes(self) -> bool {
+        matches!(
+            self,
+            ParamType::String | ParamType::ProgmemBuffer | ParamType::Buffer
+        )
+    }
+
+    fn encode(self, out: &mut Vec<u8>, value: &Value) -> Option<()> {
+        match (self.is_bytes(), value) {
+            (false, Value::Int(v)) => {
+                if *v < i32::MIN as i64 || *v > u32::MAX as i64 {
+                    return None;
+                }
+                encode_int(out, *v as u32);
+            }
+            (true, Value::Bytes(bytes)) => {
+                if bytes.len() > MESSAGE_PAYLOAD_MAX {
+                    return None;
+                }
+                out.push(bytes.len() as u8);
+                out.extend_from_slice(bytes);
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn decode(self, data: &[u8], pos: &mut usize) -> Option<Value> {
+        if self.is_bytes() {
+            let len = *data.get(*pos)? as usize;
+            let bytes = data.get(*pos + 1..*pos + 1 + len)?.to_vec();
+            *pos += 1 + len;
+            return Some(Value::Bytes(bytes));
+        }
+        let v = decode_int(data, pos)?;
+        Some(Value::Int(match self {
+            ParamType::U32 => v as i64,
+            ParamType::I32 => v as i32 as i64,
+            ParamType::U16 => v as u16 as i64,
+            ParamType::I16 => v as i16 as i64,
+            ParamType::Byte => v as u8 as i64,
+            _ => unreachable!(),
+        }))
+    }
+}
+
+/// A parameter value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+}
+
+impl Value {
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(v) => Some(*v),
+            Value::Bytes(_) => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Int(_) => None,
+            Value::Bytes(bytes) => Some(bytes),
+        }
+    }
+}
+
+impl From<i64> for Value {
+    fn from(v: i64) -> Self {
+        Value::Int(v)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(v: u32) -> Self {
+        Value::Int(v as i64)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(v: i32) -> Self {
+        Value::Int(v as i64)
+    }
+}
+
+impl From<u8> for Value {
+    fn from(v: u8) -> Self {
+        Value::Int(v as i64)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(v: bool) -> Self {
+        Value::Int(v as i64)
+    }
+}
+
+impl From<&[u8]> for Value {
+    fn from(v: &[u8]) -> Self {
+        Value::Bytes(v.to_vec())
+    }
+}
+
+impl From<&str> for Value {
+    fn from(v: &str) -> Self {
+        Value::Bytes(v.as_bytes().to_vec())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Command,
+    Response,
+    /// `printf`-style debug output; parameters are unnamed.
+    Output,
+}
+
+/// A command or response declared by the dictionary.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MessageFormat {
+    pub id: u32,
+    pub name: String,
+    pub format: String,
+    pub params: Vec<(String, ParamType)>,
+    kind: Kind,
+}
+
+impl MessageFormat {
+    fn parse(format: &str, id: u32, kind: Kind) -> Result<Self> {
+        let invalid = || ProtocolError::InvalidFormat(format.to_string());
+
+        if kind == Kind::Output {
+            let mut params = Vec::new();
+            let mut rest = format;
+            while let Some(pos) = rest.find('%') {
+                rest = &rest[pos..];
+                let (ty, len) = ParamType::parse_prefix(rest).ok_or_else(invalid)?;
+                params.push((String::new(), ty));
+                rest = &rest[len..];
+            }
+            return Ok(Self {
+                id,
+                name: "#output".to_string(),
+                format: format.to_string(),
+                params,
+                kind,
+            });
+        }
+
+        let mut parts = format.split_whitespace();
+        let name = parts.next().ok_or_else(invalid)?.to_string();
+        let params = parts
+            .map(|part| {
+                let (param, spec) = part.split_once('=').ok_or_else(invalid)?;
+                match ParamType::parse_prefix(spec) {
+                    Some((ty, len)) if len == spec.len() => Ok((param.to_string(), ty)),
+                    _ => Err(invalid()),
+                }
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            id,
+            name,
+            format: format.to_string(),
+            params,
+            kind,
+        })
+    }
+
+    /// Encode this message with positional `args`.
+    pub fn encode(&self, args: &[Value]) -> Result<Vec<u8>> {
+        if args.len() != self.params.len() {
+            return Err(ProtocolError::ArgumentCount {
+                command: self.name.clone(),
+                expected: self.params.len(),
+                found: args.len(),
+            });
+        }
+        let mut out = Vec::new();
+        encode_int(&mut out, self.id);
+        for ((param, ty), arg) in self.params.iter().zip(args) {
+            ty.encode(&mut out, arg)
+                .ok_or_else(|| ProtocolError::InvalidArgument {
+                    command: self.name.clone(),
+                    param: param.clone(),
+                })?;
+        }
+        Ok(out)
+    }
+
+    fn decode(&self, data: &[u8], pos: &mut usize) -> Result<Message> {
+        let mut params = Vec::with_capacity(self.params.len());
+        for (param, ty) in &self.params {
+            let value = ty
+                .decode(data, pos)
+                .ok_or_else(|| ProtocolError::Truncated(self.name.clone()))?;
+            params.push((param.clone(), value));
+        }
+
+        if self.kind == Kind::Output {
+            let text = format_output(&self.format, &params);
+            params = vec![("#msg".to_string(), Value::Bytes(text.into_bytes()))];
+        }
+
+        Ok(Message {
+            name: self.name.clone(),
+            params,
+        })
+    }
+}
+
+/// Substitute decoded values into an output format string.
+fn format_output(format: &str, params: &[(String, Value)]) -> String {
+    let mut out = String::new();
+    let mut values = params.iter().map(|(_, value)| value);
+    let mut rest = format;
+    while let Some(pos) = rest.find('%') {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        let Some((_, len)) = ParamType::parse_prefix(rest) else {
+            break;
+        };
+        match values.next() {
+            Some(Value::Int(v)) => out.push_str(&v.to_string()),
+            Some(Value::Bytes(bytes)) => out.push_str(&String::from_utf8_lossy(bytes)),
+            None => {}
+        }
+        rest = &rest[len..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// A decoded message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub name: String,
+    pub params: Vec<(String, Value)>,
+}
+
+impl Message {
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.params
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value)
+    }
+
+    pub fn int(&self, name: &str) -> Option<i64> {
+        self.get(name).and_then(Value::as_int)
+    }
+
+    pub fn bytes(&self, name: &str) -> Option<&[u8]> {
+        self.get(name).and_then(Value::as_bytes)
+    }
+}
+
+/// The messages and constants supported by one MCU.
+#[derive(Clone, Debug, Default)]
+pub struct Dictionary {
+    by_id: HashMap<u32, MessageFormat>,
+    commands: HashMap<String, u32>,
+    responses: HashMap<String, u32>,
+    /// Build constants such as `CLOCK_FREQ` and `MCU`.
+    pub config: BTreeMap<String, serde_json::Value>,
+    /// Value-to-id mappings, e.g. pin names. Ranges are stored as
+    /// `[first id, count]` keyed by the first name.
+    pub enumerations: BTreeMap<String, BTreeMap<String, serde_json::Value>>,
+    pub version: String,
+    pub build_versions: String,
+}
+
+impl Dictionary {
+    /// The minimal dictionary every MCU understands, used to fetch its full
+    /// dictionary.
+    pub fn identify() -> Self {
+        let mut dictionary = Self::default();
+        dictionary
+            .insert(IDENTIFY_RESPONSE, 0, Kind::Response)
+            .unwrap();
+        dictionary.insert(IDENTIFY, 1, Kind::Command).unwrap();
+        dictionary
+    }
+
+    /// Parse a zlib-compressed dictionary as returned by `identify`.
+    pub fn from_compressed(data: &[u8]) -> Result<Self> {
+        let mut json = Vec::new();
+        ZlibDecoder::new(data)
+            .read_to_end(&mut json)
+            .map_err(|err| ProtocolError::InvalidDictionary(err.to_string()))?;
+        Self::from_json(&json)
+    }
+
+    pub fn from_json(json: &[u8]) -> Result<Self> {
+        let invalid = |msg: String| ProtocolError::InvalidDictionary(msg);
+        let root: serde_json::Value =
+            serde_json::from_slice(json).map_err(|err| invalid(err.to_string()))?;
+
+        let mut dictionary = Self::identify();
+        for (section, kind) in [
+            ("commands", Kind::Command),
+            ("responses", Kind::Response),
+            ("output", Kind::Output),
+        ] {
+            let Some(messages) = root.get(section) else {
+                continue;
+            };
+            let messages = messages
+                .as_object()
+                .ok_or_else(|| invalid(format!("`{section}` must be an object")))?;
+            for (format, id) in messages {
+                let id = id
+                    .as_u64()
+                    .and_then(|id| u32::try_from(id).ok())
+                    .ok_or_else(|| invalid(format!("invalid id for `{format}`")))?;
+                dictionary.insert(format, id, kind)?;
+            }
+        }
+
+        if let Some(config) = root.get("config").and_then(|c| c.as_object()) {
+            dictionary.config = config.clone().into_iter().collect();
+        }
+        if let Some(enumerations) = root.get("enumerations").and_then(|e| e.as_object()) {
+            for (name, values) in enumerations {
+                let values = values
+                    .as_object()
+                    .ok_or_else(|| invalid(format!("enumeration `{name}` must be an object")))?;
+                dictionary
+                    .enumerations
+                    .insert(name.clone(), values.clone().into_iter().collect());
+            }
+        }
+        let string = |key: &str| {
+            root.get(key)
+                .and_then(|v| v.as_str())
+                .unwrap_or_default()
+                .to_string()
+        };
+        dictionary.version = string("version");
+        dictionary.build_versions = string("build_versions");
+
+        Ok(dictionary)
+    }
+
+    fn insert(&mut self, format: &str, id: u32, kind: Kind) -> Result<()> {
+        let message = MessageFormat::parse(format, id, kind)?;
+        match kind {
+            Kind::Command => {
+                self.commands.insert(message.name.clone(), id);
+            }
+            Kind::Response => {
+                self.responses.insert(message.name.clone(), id);
+            }
+            Kind::Output => {}
+        }
+        self.by_id.insert(id, message);
+        Ok(())
+    }
+
+    /// Look up a command by its full format string, checking that the MCU
+    /// declares the same parameters.
+    pub fn command(&self, format: &str) -> Result<&MessageFormat> {
+        let name = format.split_whitespace().next().unwrap_or_default();
+        let message = self
+            .commands
+            .get(name)
+            .and_then(|id| self.by_id.get(id))
+            .ok_or_else(|| ProtocolError::UnknownCommand(name.to_string()))?;
+        if message
+            .format
+            .split_whitespace()
+            .ne(format.split_whitespace())
+        {
+            return Err(ProtocolError::FormatMismatch {
+                expected: format.to_string(),
+                found: message.format.clone(),
+            });
+        }
+        Ok(message)
+    }
+
+    /// Look up a response by name.
+    pub fn response(&self, name: &str) -> Option<&MessageFormat> {
+        self.responses.get(name).and_then(|id| self.by_id.get(id))
+    }
+
+    /// Encode the command with the given format string.
+    pub fn encode(&self, format: &str, args: &[Value]) -> Result<Vec<u8>> {
+        self.command(format)?.encode(args)
+    }
+
+    /// Decode every message in a block payload.
+    pub fn decode(&self, payload: &[u8]) -> Result<Vec<Message>> {
+        let mut messages = Vec::new();
+        let mut pos = 0;
+        while pos < payload.len() {
+            let id = decode_int(payload, &mut pos)
+                .ok_or_else(|| ProtocolError::Truncated("message id".to_string()))?;
+            let format = self
+                .by_id
+                .get(&id)
+                .ok_or(ProtocolError::UnknownMessageId(id))?;
+            messages.push(format.decode(payload, &mut pos)?);
+        }
+        Ok(messages)
+    }
+
+    pub fn config_f64(&self, key: &str) -> Option<f64> {
+        match self.config.get(key)? {
+            serde_json::Value::Number(n) => n.as_f64(),
+            serde_json::Value::String(s) => s.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// MCU clock frequency in Hz.
+    pub fn clock_freq(&self) -> Option<f64> {
+        self.config_f64("CLOCK_FREQ")
+    }
+
+    /// Bytes the MCU can buffer before it must acknowledge, if declared.
+    pub fn receive_window(&self) -> Option<usize> {
+        self.config_f64("RECEIVE_WINDOW").map(|v| v as usize)
+    }
+
+    /// Resolve an enumerated value such as a pin name to its id.
+    ///
+    /// Ranges like `"PA0": [0, 16]` cover `PA0` through `PA15`.
+    pub fn enumeration(&self, enumeration: &str, key: &str) -> Option<i64> {
+        let values = self.enumerations.get(enumeration)?;
+        if let Some(value) = values.get(key).and_then(|v| v.as_i64()) {
+            return Some(value);
+        }
+
+        let digits = key.len() - key.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+        let (prefix, index) = key.split_at(key.len() - digits);
+        let index: i64 = index.parse().ok()?;
+        values.iter().find_map(|(start, range)| {
+            let [first, count] = range.as_array()?.as_slice() else {
+                return None;
+            };
+            let (first, count) = (first.as_i64()?, count.as_i64()?);
+            let start_digits =
+                start.len() - start.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+            let (start_prefix, start_index) = start.split_at(start.len() - start_digits);
+            let offset = index - start_index.parse::<i64>().ok()?;
+            (start_prefix == prefix && (0..count).contains(&offset)).then_some(first + offset)
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use flate2::{Compression, write::ZlibEncoder};
+    use std::io::Write;
+
+    pub(crate) const DICTIONARY: &str = r#"{
+        "commands": {
+            "get_clock": 80,
+            "queue_step oid=%c interval=%u count=%hu add=%hi": 81,
+            "set_next_step_dir oid=%c dir=%c": 82
+        },
+        "responses": {
+            "clock clock=%u": 90,
+            "shutdown clock=%u static_string_id=%hu": 91
+        },
+        "output": {
+            "Stats %u heap=%s": 95
+        },
+        "config": {"CLOCK_FREQ": 16000000, "MCU": "linux", "RECEIVE_WINDOW": 192},
+        "enumerations": {"pin": {"PA0": [0, 16], "analog": 40}},
+        "version": "v0.12.0"
+    }"#;
+
+    #[test]
+    fn parses_compressed_dictionary() {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(DICTIONARY.as_bytes()).unwrap();
+        let dictionary = Dictionary::from_compressed(&encoder.finish().unwrap()).unwrap();
+
+        assert_eq!(dictionary.clock_freq(), Some(16_000_000.0));
+        assert_eq!(dictionary.receive_window(), Some(192));
+        assert_eq!(dictionary.version, "v0.12.0");
+        assert_eq!(dictionary.enumeration("pin", "PA15"), Some(15));
+        assert_eq!(dictionary.enumeration("pin", "PA16"), None);
+        assert_eq!(dictionary.enumeration("pin", "analog"), Some(40));
+        assert!(dictionary.command(IDENTIFY).is_ok());
+    }
+
+    #[test]
+    fn encodes_and_decodes_messages() {
+        let dictionary = Dictionary::from_json(DICTIONARY.as_bytes()).unwrap();
+        let format = "queue_step oid=%c interval=%u count=%hu add=%hi";
+        let encoded = dictionary
+            .encode(
+                format,
+                &[3.into(), 40_000u32.into(), 12u32.into(), (-7).into()],
+            )
+            .unwrap();
+
+        let [message] = dictionary.decode(&encoded).unwrap().try_into().unwrap();
+        assert_eq!(message.name, "queue_step");
+        assert_eq!(message.int("interval"), Some(40_000));
+        assert_eq!(message.int("add"), Some(-7));
+
+        let mut payload = Vec::new();
+        payload.extend(
+            dictionary
+                .response("clock")
+                .unwrap()
+                .encode(&[5u32.into()])
+                .unwrap(),
+        );
+        payload.extend(
+            dictionary.by_id[&95]
+                .encode(&[1u32.into(), "ok".into()])
+                .unwrap(),
+        );
+        let messages = dictionary.decode(&payload).unwrap();
+        assert_eq!(messages[0].int("clock"), Some(5));
+        assert_eq!(messages[1].bytes("#msg"), Some(&b"Stats 1 heap=ok"[..]));
+
+        assert!(matches!(
+            dictionary.command("queue_step oid=%c interval=%u"),
+            Err(ProtocolError::FormatMismatch { .. })
+        ));
+        assert!(matches!(
+            dictionary.encode(format, &[1.into()]),
+            Err(ProtocolError::ArgumentCount { .. })
+        ));
+    }
+}
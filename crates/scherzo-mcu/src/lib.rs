@@ -0,0 +1,30 @@
+//! Transport to MCUs that speak the Klipper serial protocol.
+//!
+//! The protocol is split into layers so that each can be exercised without
+//! hardware:
+//!
+//! - [`msgblock`] frames payloads into sequenced, CRC-checked blocks and
+//!   encodes the variable-length integers used inside them.
+//! - [`dictionary`] describes the commands and responses an MCU supports and
+//!   encodes/decodes messages against it.
+//! - [`serialqueue`] is a sans-IO state machine that schedules queued
+//!   commands by MCU clock and handles acks, the receive window, and
+//!   retransmits.
+//! - [`serial`] drives a [`serialqueue::SerialQueue`] over a serial port (or
+//!   any byte stream) on a background thread.
+//! - [`mcu`] connects to an MCU, retrieves its data dictionary, and sends
+//!   commands by format.
+//! - [`steps`] adapts the step compressor's [`Command`] stream into
+//!   `queue_step` and `set_next_step_dir` messages.
+//! - [`virtual_mcu`] simulates the MCU side of the protocol for end-to-end
+//!   tests without hardware.
+//!
+//! [`Command`]: scherzo_core::step_compressor::Command
+
+pub mod dictionary;
+pub mod mcu;
+pub mod msgblock;
+pub mod serial;
+pub mod serialqueue;
+pub mod steps;
+pub mod virtual_mcu;
@@ -0,0 +1,140 @@
+//! A connected MCU.
+
+use crate::{
+    dictionary::{Dictionary, IDENTIFY, IDENTIFY_RESPONSE, Message, ProtocolError, Value},
+    serial::{Transport, TransportError, TransportHandle},
+    steps::StepSink,
+};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Dictionary bytes requested per `identify` command.
+const IDENTIFY_CHUNK: u32 = 40;
+/// How long to wait for each `identify_response` before asking again.
+const IDENTIFY_TIMEOUT: Duration = Duration::from_millis(500);
+/// Requests per chunk before giving up on the MCU.
+const IDENTIFY_ATTEMPTS: usize = 10;
+
+#[derive(Debug, Error)]
+pub enum McuError {
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+    #[error("MCU did not respond to identify at offset {offset}")]
+    IdentifyTimeout { offset: u32 },
+    #[error("MCU dictionary does not declare CLOCK_FREQ")]
+    MissingClockFreq,
+}
+
+pub type Result<T, E = McuError> = std::result::Result<T, E>;
+
+pub struct Mcu {
+    transport: Transport,
+    dictionary: Dictionary,
+    clock_freq: f64,
+}
+
+impl Mcu {
+    /// Open `path` and fetch the MCU's data dictionary.
+    pub fn connect(path: &str, baud: u32) -> Result<Self> {
+        Self::identify(Transport::open(path, baud)?)
+    }
+
+    /// Fetch the data dictionary over an open transport.
+    pub fn identify(transport: Transport) -> Result<Self> {
+        let identify = Dictionary::identify();
+        let mut data = Vec::new();
+
+        loop {
+            let offset = data.len() as u32;
+            let request = identify.encode(IDENTIFY, &[offset.into(), IDENTIFY_CHUNK.into()])?;
+            let chunk = 'attempts: {
+                for _ in 0..IDENTIFY_ATTEMPTS {
+                    transport.handle().send_now(request.clone());
+                    let deadline = Instant::now() + IDENTIFY_TIMEOUT;
+                    while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+                        let Some(payload) = transport.recv_timeout(timeout)? else {
+                            break;
+                        };
+                        // Anything else the MCU sends before it is
+                        // identified is undecodable noise
+                        let Ok(messages) = identify.decode(&payload) else {
+                            continue;
+                        };
+                        let chunk = messages.into_iter().find(|m| {
+                            m.name == "identify_response" && m.int("offset") == Some(offset as i64)
+                        });
+                        if let Some(chunk) = chunk {
+                            break 'attempts chunk;
+                        }
+                    }
+                }
+                return Err(McuError::IdentifyTimeout { offset });
+            };
+
+            let chunk = chunk
+                .bytes("data")
+                .ok_or_else(|| ProtocolError::Truncated(IDENTIFY_RESPONSE.to_string()))?;
+            data.extend_from_slice(chunk);
+            if chunk.len() < IDENTIFY_CHUNK as usize {
+                break;
+            }
+        }
+
+        let dictionary = Dictionary::from_compressed(&data)?;
+        let clock_freq = dictionary.clock_freq().ok_or(McuError::MissingClockFreq)?;
+        if let Some(receive_window) = dictionary.receive_window() {
+            transport.handle().set_receive_window(receive_window);
+        }
+
+        Ok(Self {
+            transport,
+            dictionary,
+            clock_freq,
+        })
+    }
+
+    pub fn dictionary(&self) -> &Dictionary {
+        &self.dictionary
+    }
+
+    pub fn handle(&self) -> &TransportHandle {
+        self.transport.handle()
+    }
+
+    /// MCU clock frequency (Hz).
+    pub fn clock_freq(&self) -> f64 {
+        self.clock_freq
+    }
+
+    /// Firmware version reported by the dictionary.
+    pub fn version(&self) -> &str {
+        &self.dictionary.version
+    }
+
+    /// Send the command with `format` as soon as possible.
+    pub fn send(&self, format: &str, args: &[Value]) -> Result<()> {
+        let data = self.dictionary.encode(format, args)?;
+        self.handle().send_now(data);
+        Ok(())
+    }
+
+    /// Wait up to `timeout` for the next block of messages from the MCU.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<Vec<Message>>> {
+        match self.transport.recv_timeout(timeout)? {
+            Some(payload) => Ok(Some(self.dictionary.decode(&payload)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Create a sink that sends compressed steps on a new command queue.
+    pub fn step_sink(&self) -> Result<StepSink<TransportHandle>> {
+        let queue = self.handle().alloc_command_queue();
+        Ok(StepSink::new(
+            &self.dictionary,
+            self.handle().clone(),
+            queue,
+        )?)
+    }
+}
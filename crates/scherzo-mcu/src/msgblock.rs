@@ -0,0 +1,277 @@
+//! Message block framing.
+//!
+//! Every transmission is a block of the form
+//! `<len> <seq> <payload...> <crc16 hi> <crc16 lo> <sync>`, where `len` counts
+//! the whole block, the low four bits of `seq` carry the sequence number, and
+//! the CRC covers everything before it. This mirrors `msgblock.c` in Klipper.
+
+/// Smallest possible block: header and trailer with an empty payload.
+pub const MESSAGE_MIN: usize = 5;
+/// Largest block the MCU accepts.
+pub const MESSAGE_MAX: usize = 64;
+pub const MESSAGE_HEADER_SIZE: usize = 2;
+pub const MESSAGE_TRAILER_SIZE: usize = 3;
+/// Largest payload that fits in one block.
+pub const MESSAGE_PAYLOAD_MAX: usize = MESSAGE_MAX - MESSAGE_MIN;
+pub const MESSAGE_SEQ_MASK: u8 = 0x0f;
+/// Marker bits that must be set in the sequence byte.
+pub const MESSAGE_DEST: u8 = 0x10;
+pub const MESSAGE_SYNC: u8 = 0x7e;
+
+/// CRC-16/CCITT as computed by the MCU firmware.
+pub fn crc16_ccitt(buf: &[u8]) -> u16 {
+    let mut crc: u16 = 0xffff;
+    for &byte in buf {
+        let mut data = byte ^ (crc & 0xff) as u8;
+        data ^= data << 4;
+        let data = data as u16;
+        crc = ((data << 8) | (crc >> 8)) ^ (data >> 4) ^ (data << 3);
+    }
+    crc
+}
+
+/// Frame `payload` as a block with sequence number `seq`.
+///
+/// The payload must be at most [`MESSAGE_PAYLOAD_MAX`] bytes.
+pub fn encode_block(seq: u64, payload: &[u8]) -> Vec<u8> {
+    debug_assert!(payload.len() <= MESSAGE_PAYLOAD_MAX);
+    let len = payload.len() + MESSAGE_MIN;
+    let mut block = Vec::with_capacity(len);
+    block.push(len as u8);
+    block.push(MESSAGE_DEST | (seq as u8 & MESSAGE_SEQ_MASK));
+    block.extend_from_slice(payload);
+    let crc = crc16_ccitt(&block);
+    block.extend_from_slice(&crc.to_be_bytes());
+    block.push(MESSAGE_SYNC);
+    block
+}
+
+/// Append `v` using the protocol's variable-length encoding.
+///
+/// Values are treated as 32-bit; negative numbers round-trip through their
+/// two's complement representation.
+pub fn encode_int(out: &mut Vec<u8>, v: u32) {
+    let sv = v as i32;
+    let groups = if (-(1 << 5)..(3 << 5)).contains(&sv) {
+        1
+    } else if (-(1 << 12)..(3 << 12)).contains(&sv) {
+        2
+    } else if (-(1 << 19)..(3 << 19)).contains(&sv) {
+        3
+    } else if (-(1 << 26)..(3 << 26)).contains(&sv) {
+        4
+    } else {
+        5
+    };
+    for i in (1..groups).rev() {
+        out.push(((v >> (7 * i)) & 0x7f) as u8 | 0x80);
+    }
+    out.push((v & 0x7f) as u8);
+}
+
+/// Decode a variable-length integer at `*pos`, advancing past it.
+pub fn decode_int(data: &[u8], pos: &mut usize) -> Option<u32> {
+    let mut c = *data.get(*pos)?;
+    *pos += 1;
+    let mut v = (c & 0x7f) as u32;
+    if c & 0x60 == 0x60 {
+        // Negative numbers are sign-extended from the first group
+        v |= (-0x20i32) as u32;
+    }
+    while c & 0x80 != 0 {
+        c = *data.get(*pos)?;
+        *pos += 1;
+        v = (v << 7) | (c & 0x7f) as u32;
+    }
+    Some(v)
+}
+
+/// Packs messages into blocks with consecutive sequence numbers.
+///
+/// Unlike [`SerialQueue`](crate::serialqueue::SerialQueue), nothing is
+/// scheduled, acknowledged, or retransmitted: messages go out in the order
+/// they are pushed, as many to a block as fit. This suits transports that
+/// handle delivery themselves, or recording the wire bytes of a step stream.
+#[derive(Debug, Default)]
+pub struct BlockWriter {
+    seq: u64,
+    payload: Vec<u8>,
+    out: Vec<u8>,
+}
+
+impl BlockWriter {
+    /// Writer whose first block has sequence number `seq`.
+    pub fn new(seq: u64) -> Self {
+        Self {
+            seq,
+            ..Self::default()
+        }
+    }
+
+    /// Sequence number of the next block.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Add an encoded message, which must be at most
+    /// [`MESSAGE_PAYLOAD_MAX`] bytes. Messages never span blocks.
+    pub fn push(&mut self, message: &[u8]) {
+        debug_assert!(message.len() <= MESSAGE_PAYLOAD_MAX);
+        if self.payload.len() + message.len() > MESSAGE_PAYLOAD_MAX {
+            self.flush();
+        }
+        self.payload.extend_from_slice(message);
+    }
+
+    /// Frame the pending messages, if any, into a block.
+    pub fn flush(&mut self) {
+        if self.payload.is_empty() {
+            return;
+        }
+        self.out.extend(encode_block(self.seq, &self.payload));
+        self.seq += 1;
+        self.payload.clear();
+    }
+
+    /// Flush, and return the bytes of every block framed since the last call.
+    pub fn take(&mut self) -> Vec<u8> {
+        self.flush();
+        std::mem::take(&mut self.out)
+    }
+}
+
+/// A validated block received from the MCU.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Block {
+    /// Low four bits of the sequence byte.
+    pub seq: u8,
+    pub payload: Vec<u8>,
+}
+
+/// Splits a byte stream into blocks, resynchronizing on corrupt data.
+#[derive(Debug, Default)]
+pub struct BlockReader {
+    buf: Vec<u8>,
+    need_sync: bool,
+    /// Bytes discarded while resynchronizing.
+    pub invalid_bytes: u64,
+}
+
+impl BlockReader {
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Return the next complete block, if one has arrived.
+    pub fn next_block(&mut self) -> Option<Block> {
+        loop {
+            if self.need_sync {
+                let sync = self.buf.iter().position(|&b| b == MESSAGE_SYNC);
+                let discard = sync.map_or(self.buf.len(), |pos| pos + 1);
+                self.invalid_bytes += discard as u64;
+                self.buf.drain(..discard);
+                sync?;
+                self.need_sync = false;
+            }
+
+            let &len = self.buf.first()?;
+            let len = len as usize;
+            if !(MESSAGE_MIN..=MESSAGE_MAX).contains(&len) {
+                self.need_sync = true;
+                continue;
+            }
+            if self.buf.len() < len {
+                return None;
+            }
+
+            let block = &self.buf[..len];
+            let crc = crc16_ccitt(&block[..len - MESSAGE_TRAILER_SIZE]);
+            let valid = block[1] & !MESSAGE_SEQ_MASK == MESSAGE_DEST
+                && block[len - 3..len - 1] == crc.to_be_bytes()
+                && block[len - 1] == MESSAGE_SYNC;
+            if !valid {
+                self.need_sync = true;
+                continue;
+            }
+
+            let block = Block {
+                seq: block[1] & MESSAGE_SEQ_MASK,
+                payload: block[MESSAGE_HEADER_SIZE..len - MESSAGE_TRAILER_SIZE].to_vec(),
+            };
+            self.buf.drain(..len);
+            return Some(block);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn int_encoding_round_trips() {
+        bolero::check!().with_type::<u32>().for_each(|&v| {
+            let mut out = Vec::new();
+            encode_int(&mut out, v);
+            assert!(out.len() <= 5);
+            let mut pos = 0;
+            assert_eq!(decode_int(&out, &mut pos), Some(v));
+            assert_eq!(pos, out.len());
+        });
+    }
+
+    #[test]
+    fn int_encoding_matches_firmware() {
+        let encode = |v: i32| {
+            let mut out = Vec::new();
+            encode_int(&mut out, v as u32);
+            out
+        };
+        assert_eq!(encode(0), [0x00]);
+        assert_eq!(encode(95), [0x5f]);
+        assert_eq!(encode(-1), [0x7f]);
+        assert_eq!(encode(96), [0x80, 0x60]);
+        assert_eq!(encode(-33), [0xff, 0x5f]);
+    }
+
+    #[test]
+    fn writer_packs_messages_into_sequenced_blocks() {
+        let mut writer = BlockWriter::new(15);
+        for _ in 0..3 {
+            writer.push(&[0xaa; 25]);
+        }
+        assert_eq!(writer.seq(), 16);
+        let data = writer.take();
+        assert_eq!(writer.seq(), 17);
+        assert!(writer.take().is_empty());
+
+        let mut reader = BlockReader::default();
+        reader.push(&data);
+        let block = reader.next_block().unwrap();
+        assert_eq!((block.seq, block.payload.len()), (15, 50));
+        let block = reader.next_block().unwrap();
+        assert_eq!((block.seq, block.payload.len()), (0, 25));
+        assert!(reader.next_block().is_none());
+        assert_eq!(reader.invalid_bytes, 0);
+    }
+
+    #[test]
+    fn reader_resyncs_after_corruption() {
+        let first = encode_block(1, &[1, 2, 3]);
+        let mut corrupt = encode_block(2, &[4, 5]);
+        corrupt[3] ^= 0xff;
+        let last = encode_block(3, &[]);
+
+        let mut reader = BlockReader::default();
+        for bytes in [&first[..], &[0xaa, 0xbb], &corrupt, &last] {
+            reader.push(bytes);
+        }
+
+        let block = reader.next_block().unwrap();
+        assert_eq!((block.seq, block.payload.as_slice()), (1, &[1, 2, 3][..]));
+        let block = reader.next_block().unwrap();
+        assert_eq!((block.seq, block.payload.len()), (3, 0));
+        assert!(reader.next_block().is_none());
+        assert!(reader.invalid_bytes > 0);
+    }
+}
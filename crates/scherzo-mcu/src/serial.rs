@@ -0,0 +1,248 @@
+//! Serial port IO for a [`SerialQueue`].
+//!
+//! A background thread reads from the port, feeds the queue, and writes
+//! acknowledgements, retransmits, and newly due commands. Commands queued
+//! through a [`TransportHandle`] are written immediately when the queue
+//! allows it.
+
+use crate::serialqueue::{ClockEstimate, CommandQueue, QueuedMessage, SerialQueue, SerialStats};
+use std::{
+    io::{self, Read, Write},
+    sync::{
+        Arc, Mutex, MutexGuard,
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+
+/// How long a read blocks before the IO thread services timers.
+pub const READ_TIMEOUT: Duration = Duration::from_millis(5);
+
+#[derive(Debug, Error)]
+pub enum TransportError {
+    #[error("failed to open serial port {path}: {source}")]
+    Open {
+        path: String,
+        source: serialport::Error,
+    },
+    #[error("serial IO failed: {0}")]
+    Io(#[from] io::Error),
+    #[error("connection closed: {0}")]
+    Closed(String),
+}
+
+pub type Result<T, E = TransportError> = std::result::Result<T, E>;
+
+struct State {
+    queue: SerialQueue,
+    writer: Box<dyn Write + Send>,
+    /// Why the connection stopped, once it has.
+    error: Option<String>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    start: Instant,
+    shutdown: AtomicBool,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn now(&self) -> f64 {
+        self.start.elapsed().as_secs_f64()
+    }
+
+    /// Write everything the queue has ready.
+    fn flush(&self, state: &mut State) {
+        if state.error.is_some() {
+            return;
+        }
+        let now = self.now();
+        while let Some(data) = state.queue.poll_transmit(now) {
+            if let Err(err) = state
+                .writer
+                .write_all(&data)
+                .and_then(|_| state.writer.flush())
+            {
+                state.error = Some(err.to_string());
+                return;
+            }
+        }
+    }
+}
+
+/// A connection to one MCU.
+pub struct Transport {
+    handle: TransportHandle,
+    receiver: mpsc::Receiver<Vec<u8>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Transport {
+    /// Open a serial device such as `/dev/ttyACM0`.
+    ///
+    /// USB devices ignore the baud rate.
+    pub fn open(path: &str, baud: u32) -> Result<Self> {
+        let open_error = |source| TransportError::Open {
+            path: path.to_string(),
+            source,
+        };
+        let port = serialport::new(path, baud)
+            .timeout(READ_TIMEOUT)
+            .open()
+            .map_err(open_error)?;
+        let reader = port.try_clone().map_err(open_error)?;
+        Ok(Self::new(reader, port))
+    }
+
+    /// Run the protocol over an arbitrary byte stream.
+    ///
+    /// Reads should time out periodically (returning
+    /// [`io::ErrorKind::TimedOut`] or [`io::ErrorKind::WouldBlock`]) so that
+    /// retransmits and scheduled commands are serviced while the MCU is
+    /// quiet.
+    pub fn new(reader: impl Read + Send + 'static, writer: impl Write + Send + 'static) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                queue: SerialQueue::new(),
+                writer: Box::new(writer),
+                error: None,
+            }),
+            start: Instant::now(),
+            shutdown: AtomicBool::new(false),
+        });
+        let (sender, receiver) = mpsc::channel();
+        let thread = {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("scherzo-mcu-io".to_string())
+                .spawn(move || io_loop(&shared, reader, sender))
+                .expect("failed to spawn MCU IO thread")
+        };
+        Self {
+            handle: TransportHandle { shared },
+            receiver,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn handle(&self) -> &TransportHandle {
+        &self.handle
+    }
+
+    /// Wait up to `timeout` for the next non-empty block payload.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<Vec<u8>>> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(payload) => Ok(Some(payload)),
+            Err(mpsc::RecvTimeoutError::Timeout) => match self.handle.error() {
+                Some(err) => Err(err),
+                None => Ok(None),
+            },
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(self
+                .handle
+                .error()
+                .unwrap_or_else(|| TransportError::Closed("IO thread exited".to_string()))),
+        }
+    }
+}
+
+impl Drop for Transport {
+    fn drop(&mut self) {
+        self.handle.shared.shutdown.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn io_loop(shared: &Shared, mut reader: impl Read, sender: mpsc::Sender<Vec<u8>>) {
+    let mut buf = [0u8; 4096];
+    while !shared.shutdown.load(Ordering::Relaxed) {
+        let read = reader.read(&mut buf);
+        let mut state = shared.lock();
+        match read {
+            Ok(0) => {
+                state.error = Some("end of stream".to_string());
+                return;
+            }
+            Ok(len) => {
+                for payload in state.queue.receive(&buf[..len], shared.now()) {
+                    // The transport may be shutting down, in which case
+                    // nobody is listening anymore
+                    let _ = sender.send(payload);
+                }
+            }
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::TimedOut
+                        | io::ErrorKind::WouldBlock
+                        | io::ErrorKind::Interrupted
+                ) => {}
+            Err(err) => {
+                state.error = Some(err.to_string());
+                return;
+            }
+        }
+        shared.flush(&mut state);
+        if state.error.is_some() {
+            return;
+        }
+    }
+}
+
+/// Cloneable handle for queueing commands on a [`Transport`].
+#[derive(Clone)]
+pub struct TransportHandle {
+    shared: Arc<Shared>,
+}
+
+impl TransportHandle {
+    /// Host time (seconds) on the transport's clock.
+    pub fn now(&self) -> f64 {
+        self.shared.now()
+    }
+
+    pub fn alloc_command_queue(&self) -> CommandQueue {
+        self.shared.lock().queue.alloc_command_queue()
+    }
+
+    /// Queue `message`, writing it right away if it is due.
+    ///
+    /// Failures surface through [`TransportHandle::error`] and
+    /// [`Transport::recv_timeout`].
+    pub fn send(&self, queue: CommandQueue, message: QueuedMessage) {
+        let mut state = self.shared.lock();
+        state.queue.send(queue, message);
+        self.shared.flush(&mut state);
+    }
+
+    pub fn send_now(&self, data: Vec<u8>) {
+        let mut state = self.shared.lock();
+        state.queue.send_now(data);
+        self.shared.flush(&mut state);
+    }
+
+    pub fn set_receive_window(&self, receive_window: usize) {
+        self.shared.lock().queue.set_receive_window(receive_window);
+    }
+
+    pub fn set_clock_estimate(&self, clock: ClockEstimate) {
+        self.shared.lock().queue.set_clock_estimate(clock);
+    }
+
+    pub fn stats(&self) -> SerialStats {
+        self.shared.lock().queue.stats()
+    }
+
+    /// Why the connection stopped, if it has.
+    pub fn error(&self) -> Option<TransportError> {
+        self.shared.lock().error.clone().map(TransportError::Closed)
+    }
+}
@@ -0,0 +1,528 @@
+//! Host side of the serial protocol, without any IO.
+//!
+//! [`SerialQueue`] owns the outgoing command queues and the reliability
+//! layer, following Klipper's `serialqueue.c`:
+//!
+//! - Commands are queued with a `min_clock` (do not send before the MCU
+//!   clock reaches it) and a `req_clock` (the MCU needs it by then). Ready
+//!   commands are packed into blocks in `req_clock` order and held back until
+//!   they are due or a block is full.
+//! - Blocks stay in flight until acknowledged. At most
+//!   [`MAX_PENDING_BLOCKS`] blocks and `receive_window` bytes may be
+//!   unacknowledged at once.
+//! - A duplicate ack is a NAK and triggers a fast retransmit; otherwise
+//!   blocks are retransmitted after an adaptive timeout.
+//!
+//! Callers feed received bytes into [`SerialQueue::receive`] and write
+//! whatever [`SerialQueue::poll_transmit`] returns, calling it again by
+//! [`SerialQueue::poll_timeout`]. Times are host seconds.
+
+use crate::msgblock::{
+    BlockReader, MESSAGE_MIN, MESSAGE_PAYLOAD_MAX, MESSAGE_SEQ_MASK, MESSAGE_SYNC, encode_block,
+};
+use std::collections::VecDeque;
+
+/// Lower bound on the retransmit timeout (seconds).
+pub const MIN_RTO: f64 = 0.025;
+/// Upper bound on the retransmit timeout (seconds).
+pub const MAX_RTO: f64 = 5.0;
+/// Ready commands are sent once their `req_clock` is this close (seconds).
+pub const MIN_REQTIME_DELTA: f64 = 0.250;
+/// Receive window assumed until the MCU's dictionary says otherwise.
+pub const DEFAULT_RECEIVE_WINDOW: usize = 192;
+/// Blocks that may be in flight; sequence numbers are only four bits.
+pub const MAX_PENDING_BLOCKS: u64 = 12;
+
+/// Linear mapping between host time and MCU clock.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClockEstimate {
+    /// Host time at which `clock` was observed.
+    pub time: f64,
+    pub clock: u64,
+    /// Estimated MCU clock frequency (Hz).
+    pub freq: f64,
+}
+
+impl ClockEstimate {
+    pub fn clock_at(&self, time: f64) -> u64 {
+        (self.clock as f64 + (time - self.time) * self.freq).max(0.0) as u64
+    }
+
+    pub fn time_of(&self, clock: u64) -> f64 {
+        self.time + (clock as f64 - self.clock as f64) / self.freq
+    }
+}
+
+/// Handle to one ordered command queue.
+///
+/// Messages in the same queue are sent in the order they were queued;
+/// messages in different queues are interleaved by `req_clock`. The default
+/// is [`SerialQueue::default_queue`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct CommandQueue(usize);
+
+/// An encoded command waiting to be sent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueuedMessage {
+    pub data: Vec<u8>,
+    /// Do not send before the MCU clock reaches this value.
+    pub min_clock: u64,
+    /// MCU clock by which the message must have arrived; 0 means "now".
+    pub req_clock: u64,
+}
+
+impl QueuedMessage {
+    /// A message to send as soon as possible.
+    pub fn now(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            min_clock: 0,
+            req_clock: 0,
+        }
+    }
+}
+
+/// Transport counters.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SerialStats {
+    pub bytes_write: u64,
+    pub bytes_read: u64,
+    pub bytes_retransmit: u64,
+    pub bytes_invalid: u64,
+    pub retransmits: u64,
+    /// Smoothed round-trip time (seconds).
+    pub srtt: f64,
+    /// Current retransmit timeout (seconds).
+    pub rto: f64,
+    pub send_seq: u64,
+    pub receive_seq: u64,
+}
+
+#[derive(Debug)]
+struct SentBlock {
+    seq: u64,
+    payload: Vec<u8>,
+    data: Vec<u8>,
+    sent_time: f64,
+    retransmitted: bool,
+}
+
+#[derive(Debug)]
+pub struct SerialQueue {
+    queues: Vec<VecDeque<QueuedMessage>>,
+    clock: Option<ClockEstimate>,
+    receive_window: usize,
+    reader: BlockReader,
+    /// Sequence number of the next block to send.
+    send_seq: u64,
+    /// Sequence number the MCU expects next.
+    receive_seq: u64,
+    last_ack_seq: u64,
+    ignore_nak_seq: u64,
+    retransmit_seq: u64,
+    /// Whether the MCU has confirmed a sequence number yet.
+    synced: bool,
+    sent: VecDeque<SentBlock>,
+    in_flight_bytes: usize,
+    retransmit_at: Option<f64>,
+    srtt: f64,
+    rttvar: f64,
+    rto: f64,
+    stats: SerialStats,
+}
+
+impl Default for SerialQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SerialQueue {
+    pub fn new() -> Self {
+        Self {
+            queues: vec![VecDeque::new()],
+            clock: None,
+            receive_window: DEFAULT_RECEIVE_WINDOW,
+            reader: BlockReader::default(),
+            send_seq: 0,
+            receive_seq: 0,
+            last_ack_seq: 0,
+            ignore_nak_seq: 0,
+            retransmit_seq: 0,
+            synced: false,
+            sent: VecDeque::new(),
+            in_flight_bytes: 0,
+            retransmit_at: None,
+            srtt: 0.0,
+            rttvar: 0.0,
+            rto: MIN_RTO,
+            stats: SerialStats::default(),
+        }
+    }
+
+    /// The queue used by [`SerialQueue::send_now`].
+    pub fn default_queue(&self) -> CommandQueue {
+        CommandQueue(0)
+    }
+
+    pub fn alloc_command_queue(&mut self) -> CommandQueue {
+        self.queues.push(VecDeque::new());
+        CommandQueue(self.queues.len() - 1)
+    }
+
+    pub fn set_receive_window(&mut self, receive_window: usize) {
+        self.receive_window = receive_window;
+    }
+
+    pub fn set_clock_estimate(&mut self, clock: ClockEstimate) {
+        self.clock = Some(clock);
+    }
+
+    pub fn clock_estimate(&self) -> Option<ClockEstimate> {
+        self.clock
+    }
+
+    pub fn send(&mut self, queue: CommandQueue, message: QueuedMessage) {
+        debug_assert!(message.data.len() <= MESSAGE_PAYLOAD_MAX);
+        self.queues[queue.0].push_back(message);
+    }
+
+    pub fn send_now(&mut self, data: Vec<u8>) {
+        self.send(self.default_queue(), QueuedMessage::now(data));
+    }
+
+    /// Messages that have not been sent yet.
+    pub fn pending_messages(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+
+    /// Whether every sent block has been acknowledged.
+    pub fn is_idle(&self) -> bool {
+        self.sent.is_empty()
+    }
+
+    pub fn stats(&self) -> SerialStats {
+        SerialStats {
+            srtt: self.srtt,
+            rto: self.rto,
+            send_seq: self.send_seq,
+            receive_seq: self.receive_seq,
+            bytes_invalid: self.stats.bytes_invalid + self.reader.invalid_bytes,
+            ..self.stats
+        }
+    }
+
+    /// Process bytes read from the MCU, returning the payloads of the
+    /// non-empty blocks received.
+    pub fn receive(&mut self, data: &[u8], now: f64) -> Vec<Vec<u8>> {
+        self.reader.push(data);
+        let mut payloads = Vec::new();
+        while let Some(block) = self.reader.next_block() {
+            let len = block.payload.len() + MESSAGE_MIN;
+            let Some(rseq) = self.receive_sequence(block.seq, now) else {
+                self.stats.bytes_invalid += len as u64;
+                continue;
+            };
+            self.stats.bytes_read += len as u64;
+
+            if block.payload.is_empty() {
+                if self.last_ack_seq < rseq {
+                    self.last_ack_seq = rseq;
+                } else if rseq > self.ignore_nak_seq && !self.sent.is_empty() {
+                    // A duplicate ack is a NAK
+                    self.retransmit_at = Some(now);
+                }
+            } else {
+                payloads.push(block.payload);
+            }
+        }
+        payloads
+    }
+
+    /// Map a block's four-bit sequence number onto the full sequence and
+    /// process the acknowledgement it carries.
+    fn receive_sequence(&mut self, seq: u8, now: f64) -> Option<u64> {
+        let delta = (seq.wrapping_sub(self.receive_seq as u8) & MESSAGE_SEQ_MASK) as u64;
+        let rseq = self.receive_seq + delta;
+        if rseq > self.send_seq {
+            if self.synced {
+                // An ack for a block that was never sent
+                return None;
+            }
+            // The MCU kept its sequence from an earlier connection
+            self.resync(seq, now);
+            return Some(self.receive_seq);
+        }
+        self.synced = true;
+        if rseq != self.receive_seq {
+            self.acknowledge(rseq, now);
+        }
+        Some(rseq)
+    }
+
+    fn acknowledge(&mut self, rseq: u64, now: f64) {
+        while let Some(block) = self.sent.front() {
+            if block.seq >= rseq {
+                break;
+            }
+            let block = self.sent.pop_front().unwrap();
+            self.in_flight_bytes -= block.data.len();
+            if block.seq + 1 == rseq && !block.retransmitted {
+                self.update_rto(now - block.sent_time);
+            }
+        }
+        self.receive_seq = rseq;
+        self.retransmit_at = (!self.sent.is_empty()).then_some(now + self.rto);
+    }
+
+    /// Renumber unacknowledged blocks to continue from the MCU's sequence.
+    fn resync(&mut self, seq: u8, now: f64) {
+        let base = (((self.send_seq >> 4) + 1) << 4) | seq as u64;
+        for (i, block) in self.sent.iter_mut().enumerate() {
+            block.seq = base + i as u64;
+            block.data = encode_block(block.seq, &block.payload);
+        }
+        self.synced = true;
+        self.receive_seq = base;
+        self.last_ack_seq = base;
+        self.ignore_nak_seq = base;
+        self.retransmit_seq = base;
+        self.send_seq = base + self.sent.len() as u64;
+        if !self.sent.is_empty() {
+            self.retransmit_at = Some(now);
+        }
+    }
+
+    fn update_rto(&mut self, rtt: f64) {
+        if self.srtt == 0.0 {
+            self.srtt = rtt;
+            self.rttvar = rtt / 2.0;
+        } else {
+            self.rttvar = 0.75 * self.rttvar + 0.25 * (self.srtt - rtt).abs();
+            self.srtt = 0.875 * self.srtt + 0.125 * rtt;
+        }
+        self.rto = (self.srtt + 4.0 * self.rttvar).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// Return the next bytes to write, if any. Call repeatedly until it
+    /// returns `None`.
+    pub fn poll_transmit(&mut self, now: f64) -> Option<Vec<u8>> {
+        if self.retransmit_at.is_some_and(|at| at <= now) && !self.sent.is_empty() {
+            return Some(self.retransmit(now));
+        }
+
+        if self.send_seq - self.receive_seq >= MAX_PENDING_BLOCKS
+            || self.in_flight_bytes + MESSAGE_PAYLOAD_MAX + MESSAGE_MIN > self.receive_window
+        {
+            return None;
+        }
+
+        let payload = self.build_payload(now)?;
+        let data = encode_block(self.send_seq, &payload);
+        self.stats.bytes_write += data.len() as u64;
+        self.in_flight_bytes += data.len();
+        self.sent.push_back(SentBlock {
+            seq: self.send_seq,
+            payload,
+            data: data.clone(),
+            sent_time: now,
+            retransmitted: false,
+        });
+        self.send_seq += 1;
+        self.retransmit_at.get_or_insert(now + self.rto);
+        Some(data)
+    }
+
+    fn retransmit(&mut self, now: f64) -> Vec<u8> {
+        // Lead with a sync byte in case the MCU is mid-way through a block
+        let mut data = vec![MESSAGE_SYNC];
+        for block in &mut self.sent {
+            block.retransmitted = true;
+            data.extend_from_slice(&block.data);
+        }
+        self.stats.retransmits += 1;
+        self.stats.bytes_retransmit += data.len() as u64;
+
+        self.ignore_nak_seq = self.receive_seq.max(self.retransmit_seq);
+        self.retransmit_seq = self.send_seq;
+        self.rto = (self.rto * 2.0).min(MAX_RTO);
+        self.retransmit_at = Some(now + self.rto);
+        data
+    }
+
+    /// Pack due messages into a payload, most urgent first.
+    fn build_payload(&mut self, now: f64) -> Option<Vec<u8>> {
+        let ack_clock = self.clock.map(|clock| clock.clock_at(now));
+        let ready = |message: &QueuedMessage| ack_clock.is_none_or(|ack| message.min_clock <= ack);
+
+        // Only send once something is due or a block would be full
+        let mut ready_bytes = 0;
+        let mut min_req_clock = u64::MAX;
+        for queue in &self.queues {
+            for message in queue.iter().take_while(|m| ready(m)) {
+                ready_bytes += message.data.len();
+                min_req_clock = min_req_clock.min(message.req_clock);
+            }
+        }
+        if ready_bytes == 0 {
+            return None;
+        }
+        if let (Some(clock), Some(ack_clock)) = (self.clock, ack_clock) {
+            let due = ack_clock + (MIN_REQTIME_DELTA * clock.freq) as u64;
+            if ready_bytes < MESSAGE_PAYLOAD_MAX && min_req_clock > due {
+                return None;
+            }
+        }
+
+        let mut payload = Vec::new();
+        loop {
+            let next = self
+                .queues
+                .iter()
+                .enumerate()
+                .filter_map(|(i, queue)| queue.front().filter(|m| ready(m)).map(|m| (i, m)))
+                .filter(|(_, m)| payload.len() + m.data.len() <= MESSAGE_PAYLOAD_MAX)
+                .min_by_key(|(_, m)| m.req_clock)
+                .map(|(i, _)| i);
+            let Some(index) = next else {
+                break;
+            };
+            let message = self.queues[index].pop_front().unwrap();
+            payload.extend_from_slice(&message.data);
+        }
+        Some(payload)
+    }
+
+    /// Host time at which [`SerialQueue::poll_transmit`] should next be
+    /// called, if something is waiting on a timer.
+    pub fn poll_timeout(&self) -> Option<f64> {
+        let mut timeout = self.retransmit_at.filter(|_| !self.sent.is_empty());
+        if let Some(clock) = self.clock {
+            for message in self.queues.iter().filter_map(VecDeque::front) {
+                let ack_clock_needed = if message.min_clock > clock.clock {
+                    message.min_clock
+                } else {
+                    let delta = (MIN_REQTIME_DELTA * clock.freq) as u64;
+                    message.req_clock.saturating_sub(delta)
+                };
+                let at = clock.time_of(ack_clock_needed);
+                timeout = Some(timeout.map_or(at, |t: f64| t.min(at)));
+            }
+        }
+        timeout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msgblock::{Block, BlockReader};
+
+    /// Decode the blocks in `data` as the MCU would see them.
+    fn blocks(data: &[u8]) -> Vec<Block> {
+        let mut reader = BlockReader::default();
+        reader.push(data);
+        std::iter::from_fn(|| reader.next_block()).collect()
+    }
+
+    fn ack(seq: u64) -> Vec<u8> {
+        encode_block(seq, &[])
+    }
+
+    #[test]
+    fn acks_and_retransmits() {
+        let mut queue = SerialQueue::new();
+        queue.send_now(vec![1, 2, 3]);
+        let first = queue.poll_transmit(0.0).unwrap();
+        assert_eq!(blocks(&first)[0].payload, [1, 2, 3]);
+        assert!(queue.poll_transmit(0.0).is_none());
+
+        // Nothing arrives, so the block is resent after the timeout
+        let timeout = queue.poll_timeout().unwrap();
+        assert!(queue.poll_transmit(timeout - 0.001).is_none());
+        let resent = queue.poll_transmit(timeout).unwrap();
+        assert_eq!(blocks(&resent), blocks(&first));
+        assert_eq!(queue.stats().retransmits, 1);
+
+        // A response block acknowledges the sent block and is returned
+        let response = encode_block(1, &[9]);
+        assert_eq!(queue.receive(&response, timeout + 0.01), [vec![9]]);
+        assert!(queue.is_idle());
+        assert_eq!(queue.poll_timeout(), None);
+
+        // A duplicate ack is a NAK and triggers an immediate retransmit
+        queue.send_now(vec![4]);
+        queue.send_now(vec![5; MESSAGE_PAYLOAD_MAX]);
+        assert!(queue.poll_transmit(1.0).is_some());
+        assert!(queue.poll_transmit(1.0).is_some());
+        assert!(queue.receive(&ack(2), 1.001).is_empty());
+        assert!(queue.receive(&ack(2), 1.002).is_empty());
+        let resent = queue.poll_transmit(1.002).unwrap();
+        assert_eq!(blocks(&resent)[0].payload, vec![5; MESSAGE_PAYLOAD_MAX]);
+    }
+
+    #[test]
+    fn adopts_mcu_sequence_on_first_contact() {
+        let mut queue = SerialQueue::new();
+        queue.send_now(vec![1]);
+        queue.poll_transmit(0.0).unwrap();
+
+        // The MCU expects sequence 6 and NAKs our block numbered 0
+        queue.receive(&ack(6), 0.01);
+        let resent = blocks(&queue.poll_transmit(0.01).unwrap());
+        assert_eq!(resent[0].seq, 6);
+        assert_eq!(resent[0].payload, [1]);
+
+        queue.receive(&ack(7), 0.02);
+        assert!(queue.is_idle());
+    }
+
+    #[test]
+    fn schedules_by_clock() {
+        let mut queue = SerialQueue::new();
+        let freq = 1_000_000.0;
+        queue.set_clock_estimate(ClockEstimate {
+            time: 0.0,
+            clock: 0,
+            freq,
+        });
+        let steps = queue.alloc_command_queue();
+        let at = |seconds: f64| (seconds * freq) as u64;
+
+        queue.send(
+            steps,
+            QueuedMessage {
+                data: vec![1],
+                min_clock: 0,
+                req_clock: at(2.0),
+            },
+        );
+        queue.send(
+            steps,
+            QueuedMessage {
+                data: vec![2],
+                min_clock: at(3.0),
+                req_clock: at(3.0),
+            },
+        );
+
+        // Not due until MIN_REQTIME_DELTA before its req_clock
+        assert!(queue.poll_transmit(0.0).is_none());
+        let due = queue.poll_timeout().unwrap();
+        assert!((due - (2.0 - MIN_REQTIME_DELTA)).abs() < 1e-6);
+
+        // Unscheduled commands go out immediately, ahead of queued ones, and
+        // anything else that is ready rides along
+        queue.send_now(vec![3]);
+        assert_eq!(
+            blocks(&queue.poll_transmit(0.0).unwrap())[0].payload,
+            [3, 1]
+        );
+        queue.receive(&ack(1), 0.001);
+
+        // The second message waits for its min_clock
+        assert!(queue.poll_transmit(2.9).is_none());
+        let block = blocks(&queue.poll_transmit(3.0).unwrap());
+        assert_eq!(block[0].payload, [2]);
+    }
+}
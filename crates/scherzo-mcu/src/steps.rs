@@ -0,0 +1,241 @@
+//! Step commands for the MCU.
+//!
+//! [`StepSink`] receives the step compressor's [`Command`] stream and queues
+//! the matching `queue_step` and `set_next_step_dir` messages with the
+//! compressor's scheduling clocks. Over a [`BlockWriter`] the stream comes
+//! out already framed for the wire.
+
+use crate::{
+    dictionary::{Dictionary, MessageFormat, ProtocolError},
+    msgblock::BlockWriter,
+    serial::TransportHandle,
+    serialqueue::{CommandQueue, QueuedMessage, SerialQueue},
+};
+use scherzo_core::step_compressor::{Command, CommandSink};
+
+pub const QUEUE_STEP: &str = "queue_step oid=%c interval=%u count=%hu add=%hi";
+pub const SET_NEXT_STEP_DIR: &str = "set_next_step_dir oid=%c dir=%c";
+
+/// Destination for encoded, scheduled messages.
+pub trait MessageSink {
+    fn send(&mut self, queue: CommandQueue, message: QueuedMessage);
+}
+
+impl<S: MessageSink + ?Sized> MessageSink for &mut S {
+    fn send(&mut self, queue: CommandQueue, message: QueuedMessage) {
+        (**self).send(queue, message);
+    }
+}
+
+impl MessageSink for SerialQueue {
+    fn send(&mut self, queue: CommandQueue, message: QueuedMessage) {
+        SerialQueue::send(self, queue, message);
+    }
+}
+
+impl MessageSink for BlockWriter {
+    fn send(&mut self, _queue: CommandQueue, message: QueuedMessage) {
+        self.push(&message.data);
+    }
+}
+
+impl MessageSink for TransportHandle {
+    fn send(&mut self, queue: CommandQueue, message: QueuedMessage) {
+        TransportHandle::send(self, queue, message);
+    }
+}
+
+/// Encodes step compressor output for one MCU.
+pub struct StepSink<S> {
+    sink: S,
+    queue: CommandQueue,
+    queue_step: MessageFormat,
+    set_next_step_dir: MessageFormat,
+}
+
+impl<S: MessageSink> StepSink<S> {
+    /// Fails if the MCU does not support the step commands.
+    pub fn new(
+        dictionary: &Dictionary,
+        sink: S,
+        queue: CommandQueue,
+    ) -> Result<Self, ProtocolError> {
+        Ok(Self {
+            sink,
+            queue,
+            queue_step: dictionary.command(QUEUE_STEP)?.clone(),
+            set_next_step_dir: dictionary.command(SET_NEXT_STEP_DIR)?.clone(),
+        })
+    }
+
+    pub fn into_inner(self) -> S {
+        self.sink
+    }
+}
+
+impl StepSink<BlockWriter> {
+    /// Encode steps straight into message blocks, the first numbered `seq`.
+    /// Collect the bytes with [`BlockWriter::take`].
+    pub fn framed(dictionary: &Dictionary, seq: u64) -> Result<Self, ProtocolError> {
+        Self::new(dictionary, BlockWriter::new(seq), CommandQueue::default())
+    }
+}
+
+impl<S: MessageSink> CommandSink for StepSink<S> {
+    fn push(&mut self, command: Command) {
+        let (data, min_clock, req_clock) = match command {
+            Command::QueueStep(step) => (
+                self.queue_step.encode(&[
+                    step.oid.into(),
+                    step.interval.into(),
+                    (step.count as u32).into(),
+                    (step.add as i32).into(),
+                ]),
+                step.min_clock,
+                step.req_clock,
+            ),
+            Command::SetNextStepDir(dir) => (
+                self.set_next_step_dir
+                    .encode(&[dir.oid.into(), dir.dir.into()]),
+                0,
+                dir.req_clock,
+            ),
+        };
+        // Every field is an integer within its parameter's range
+        let data = data.expect("step commands always encode");
+        self.sink.send(
+            self.queue,
+            QueuedMessage {
+                data,
+                min_clock,
+                req_clock,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dictionary::tests::DICTIONARY,
+        msgblock::BlockReader,
+        serialqueue::{ClockEstimate, QueuedMessage},
+    };
+    use scherzo_core::step_compressor::StepCompressor;
+
+    #[derive(Default)]
+    struct Recorder(Vec<QueuedMessage>);
+
+    impl MessageSink for Recorder {
+        fn send(&mut self, _queue: CommandQueue, message: QueuedMessage) {
+            self.0.push(message);
+        }
+    }
+
+    #[test]
+    fn compressed_steps_become_mcu_commands() {
+        let dictionary = Dictionary::from_json(DICTIONARY.as_bytes()).unwrap();
+        let queue = SerialQueue::new().default_queue();
+        let sink = StepSink::new(&dictionary, Recorder::default(), queue).unwrap();
+
+        let mut compressor = StepCompressor::new(7, 25, sink);
+        compressor.set_time(0.0, 16_000_000.0);
+        for i in 1..=20 {
+            compressor.append(1, 0.0, i as f64 * 0.001).unwrap();
+        }
+        compressor.flush(u64::MAX).unwrap();
+
+        let messages = compressor.into_sink().into_inner().0;
+        let decoded: Vec<_> = messages
+            .iter()
+            .flat_map(|m| dictionary.decode(&m.data).unwrap())
+            .collect();
+        assert_eq!(decoded[0].name, "set_next_step_dir");
+        assert_eq!(decoded[0].int("oid"), Some(7));
+
+        let steps: i64 = decoded
+            .iter()
+            .filter(|m| m.name == "queue_step")
+            .map(|m| m.int("count").unwrap())
+            .sum();
+        assert_eq!(steps, 20);
+        assert!(
+            messages
+                .windows(2)
+                .all(|w| w[0].req_clock <= w[1].req_clock)
+        );
+    }
+
+    #[test]
+    fn framed_steps_decode_from_the_wire() {
+        let dictionary = Dictionary::from_json(DICTIONARY.as_bytes()).unwrap();
+        let sink = StepSink::framed(&dictionary, 3).unwrap();
+        let mut compressor = StepCompressor::new(2, 25, sink);
+        compressor.set_time(0.0, 16_000_000.0);
+        for i in 1..=200 {
+            let dir = if i <= 100 { 1 } else { 0 };
+            compressor.append(dir, 0.0, i as f64 * 0.001).unwrap();
+        }
+        compressor.flush(u64::MAX).unwrap();
+        let data = compressor.into_sink().into_inner().take();
+
+        let mut reader = BlockReader::default();
+        reader.push(&data);
+        let mut seqs = Vec::new();
+        let mut steps = 0;
+        let mut dirs = Vec::new();
+        while let Some(block) = reader.next_block() {
+            seqs.push(block.seq);
+            for message in dictionary.decode(&block.payload).unwrap() {
+                match message.name.as_str() {
+                    "queue_step" => steps += message.int("count").unwrap(),
+                    "set_next_step_dir" => dirs.push(message.int("dir").unwrap()),
+                    name => panic!("unexpected {name}"),
+                }
+            }
+        }
+        assert_eq!(reader.invalid_bytes, 0);
+        assert_eq!(seqs[0], 3);
+        assert!(seqs.windows(2).all(|w| w[1] == (w[0] + 1) % 16));
+        assert_eq!(steps, 200);
+        assert_eq!(dirs, [1, 0]);
+    }
+
+    #[test]
+    fn step_messages_flow_through_serial_queue() {
+        let dictionary = Dictionary::from_json(DICTIONARY.as_bytes()).unwrap();
+        let mut serial = SerialQueue::new();
+        serial.set_clock_estimate(ClockEstimate {
+            time: 0.0,
+            clock: 0,
+            freq: 16_000_000.0,
+        });
+        let queue = serial.alloc_command_queue();
+        let mut sink = StepSink::new(&dictionary, serial, queue).unwrap();
+
+        sink.push(Command::QueueStep(
+            scherzo_core::step_compressor::QueueStep {
+                oid: 1,
+                first_clock: 16_000_000,
+                last_clock: 16_000_000,
+                interval: 16_000_000,
+                count: 1,
+                add: 0,
+                req_clock: 16_000_000,
+                min_clock: 0,
+            },
+        ));
+
+        let mut serial = sink.into_inner();
+        assert!(serial.poll_transmit(0.0).is_none());
+        let data = serial.poll_transmit(1.0).unwrap();
+        let mut reader = BlockReader::default();
+        reader.push(&data);
+        let messages = dictionary
+            .decode(&reader.next_block().unwrap().payload)
+            .unwrap();
+        assert_eq!(messages[0].name, "queue_step");
+        assert_eq!(messages[0].int("interval"), Some(16_000_000));
+    }
+}
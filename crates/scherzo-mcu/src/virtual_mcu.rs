@@ -0,0 +1,969 @@
+//! A simulated MCU for testing without hardware.
+//!
+//! [`VirtualMcu`] speaks the MCU side of the protocol: it serves its data
+//! dictionary, acknowledges (and NAKs) blocks, and executes a subset of the
+//! firmware's commands against a model of its clock, steppers, endstops, and
+//! ADC inputs. Executed step schedules are kept so tests can check where a
+//! stepper was at any clock and how fast it stepped.
+//!
+//! Two drivers are provided:
+//!
+//! - [`Loopback`] connects a [`SerialQueue`] to a virtual MCU in virtual time,
+//!   which is deterministic and runs as fast as the host can compute.
+//! - [`spawn`] runs the MCU on a thread in real time behind a [`Transport`],
+//!   exercising the same path as a serial port.
+
+use crate::{
+    dictionary::{Dictionary, Message, ProtocolError, Value},
+    msgblock::{BlockReader, MESSAGE_SEQ_MASK, encode_block},
+    serial::{READ_TIMEOUT, Transport},
+    serialqueue::{ClockEstimate, SerialQueue},
+};
+use flate2::{Compression, write::ZlibEncoder};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{self, Read, Write},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::Instant,
+};
+
+const COMMANDS: &[&str] = &[
+    "identify offset=%u count=%c",
+    "get_clock",
+    "get_uptime",
+    "emergency_stop",
+    "config_stepper oid=%c step_pin=%c dir_pin=%c invert_step=%c step_pulse_ticks=%u",
+    "queue_step oid=%c interval=%u count=%hu add=%hi",
+    "set_next_step_dir oid=%c dir=%c",
+    "reset_step_clock oid=%c clock=%u",
+    "stepper_get_position oid=%c",
+    "config_endstop oid=%c pin=%c pull_up=%c",
+    "endstop_query_state oid=%c",
+    "config_analog_in oid=%c pin=%u",
+    "query_analog_in oid=%c clock=%u sample_ticks=%u sample_count=%c rest_ticks=%u min_value=%hu max_value=%hu range_check_count=%c",
+];
+
+const RESPONSES: &[&str] = &[
+    "identify_response offset=%u data=%.*s",
+    "clock clock=%u",
+    "uptime high=%u clock=%u",
+    "shutdown clock=%u static_string_id=%hu",
+    "is_shutdown static_string_id=%hu",
+    "stepper_position oid=%c pos=%i",
+    "endstop_state oid=%c homing=%c next_clock=%u pin_value=%c",
+    "analog_in_state oid=%c next_clock=%u value=%hu",
+];
+
+/// Reasons the virtual MCU shuts down, reported by `static_string_id`.
+const SHUTDOWN_REASONS: &[&str] = &[
+    "Command request",
+    "Stepper too far in past",
+    "ADC out of range",
+    "Invalid oid",
+    "Invalid command",
+];
+
+/// One `queue_step` as executed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StepSegment {
+    /// Clock of the first step.
+    pub first_clock: u64,
+    pub interval: u32,
+    pub count: u16,
+    pub add: i16,
+    /// Whether the steps move in the positive direction.
+    pub dir: bool,
+    /// Position (in steps) before the first step.
+    pub start_position: i64,
+}
+
+impl StepSegment {
+    /// Clock of step `k` (0-based).
+    pub fn step_clock(&self, k: u64) -> u64 {
+        let k = k as i64;
+        (self.first_clock as i64 + k * self.interval as i64 + self.add as i64 * k * (k + 1) / 2)
+            as u64
+    }
+
+    pub fn last_clock(&self) -> u64 {
+        self.step_clock(self.count as u64 - 1)
+    }
+
+    /// Shortest time between steps, including the gap before the first.
+    pub fn min_interval(&self) -> i64 {
+        let last = self.interval as i64 + (self.count as i64 - 1) * self.add as i64;
+        last.min(self.interval as i64)
+    }
+
+    fn end_position(&self) -> i64 {
+        self.position_after(self.count as i64)
+    }
+
+    fn position_after(&self, steps: i64) -> i64 {
+        if self.dir {
+            self.start_position + steps
+        } else {
+            self.start_position - steps
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct VirtualStepper {
+    /// Clock of the most recent (possibly future) scheduled step.
+    last_step_clock: u64,
+    next_dir: bool,
+    position: i64,
+    segments: Vec<StepSegment>,
+}
+
+impl VirtualStepper {
+    fn position_at(&self, clock: u64) -> i64 {
+        let started = self
+            .segments
+            .partition_point(|segment| segment.first_clock <= clock);
+        let Some(segment) = started.checked_sub(1).map(|i| &self.segments[i]) else {
+            return self
+                .segments
+                .first()
+                .map_or(self.position, |segment| segment.start_position);
+        };
+        // Binary search for the number of steps taken by `clock`
+        let (mut lo, mut hi) = (1u64, segment.count as u64);
+        while lo < hi {
+            let mid = (lo + hi).div_ceil(2);
+            if segment.step_clock(mid - 1) <= clock {
+                lo = mid;
+            } else {
+                hi = mid - 1;
+            }
+        }
+        segment.position_after(lo as i64)
+    }
+}
+
+#[derive(Debug)]
+struct AnalogIn {
+    pin: u32,
+    next_report: Option<u64>,
+    sample_count: u8,
+    rest_ticks: u32,
+    min_value: u16,
+    max_value: u16,
+    range_check_count: u8,
+    out_of_range: u8,
+}
+
+/// Where an endstop pin's level comes from.
+#[derive(Clone, Copy, Debug)]
+enum PinModel {
+    Level(bool),
+    /// Triggered once the stepper reaches `position` moving in `positive_dir`.
+    Stepper {
+        oid: u8,
+        position: i64,
+        positive_dir: bool,
+    },
+}
+
+/// MCU-side protocol and hardware model.
+pub struct VirtualMcu {
+    dictionary: Dictionary,
+    compressed_dictionary: Vec<u8>,
+    freq: f64,
+    clock: u64,
+    next_sequence: u8,
+    reader: BlockReader,
+    output: Vec<u8>,
+    steppers: BTreeMap<u8, VirtualStepper>,
+    endstops: BTreeMap<u8, u32>,
+    analog: BTreeMap<u8, AnalogIn>,
+    pins: BTreeMap<u32, PinModel>,
+    adc: BTreeMap<u32, u16>,
+    shutdown: Option<&'static str>,
+    commands: Vec<Message>,
+}
+
+impl VirtualMcu {
+    /// Create an MCU whose clock runs at `freq` Hz.
+    pub fn new(freq: f64) -> Self {
+        let numbered = |formats: &[&str], first: u32| {
+            formats
+                .iter()
+                .zip(first..)
+                .map(|(format, id)| (format.to_string(), serde_json::json!(id)))
+                .collect::<serde_json::Map<_, _>>()
+        };
+        let static_strings = SHUTDOWN_REASONS
+            .iter()
+            .zip(0..)
+            .map(|(reason, id)| (reason.to_string(), serde_json::json!(id)))
+            .collect::<serde_json::Map<_, _>>();
+        // identify and identify_response keep the ids every MCU uses
+        let mut responses = numbered(&RESPONSES[1..], COMMANDS.len() as u32 + 1);
+        responses.insert(RESPONSES[0].to_string(), serde_json::json!(0));
+        let json = serde_json::json!({
+            "commands": numbered(COMMANDS, 1),
+            "responses": responses,
+            "config": {
+                "CLOCK_FREQ": freq,
+                "MCU": "virtual",
+                "RECEIVE_WINDOW": 192,
+            },
+            "enumerations": {
+                "pin": {"PA0": [0, 32], "PB0": [32, 32], "PC0": [64, 32]},
+                "static_string_id": static_strings,
+            },
+            "version": concat!("scherzo-virtual-", env!("CARGO_PKG_VERSION")),
+        });
+        let json = serde_json::to_vec(&json).unwrap();
+        let dictionary = Dictionary::from_json(&json).expect("virtual dictionary is valid");
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&json).unwrap();
+
+        Self {
+            dictionary,
+            compressed_dictionary: encoder.finish().unwrap(),
+            freq,
+            clock: 0,
+            next_sequence: 0,
+            reader: BlockReader::default(),
+            output: Vec::new(),
+            steppers: BTreeMap::new(),
+            endstops: BTreeMap::new(),
+            analog: BTreeMap::new(),
+            pins: BTreeMap::new(),
+            adc: BTreeMap::new(),
+            shutdown: None,
+            commands: Vec::new(),
+        }
+    }
+
+    pub fn dictionary(&self) -> &Dictionary {
+        &self.dictionary
+    }
+
+    pub fn freq(&self) -> f64 {
+        self.freq
+    }
+
+    pub fn clock(&self) -> u64 {
+        self.clock
+    }
+
+    /// Why the MCU shut down, if it has.
+    pub fn shutdown_reason(&self) -> Option<&'static str> {
+        self.shutdown
+    }
+
+    /// Every command received so far.
+    pub fn commands(&self) -> &[Message] {
+        &self.commands
+    }
+
+    fn pin(&self, name: &str) -> Result<u32, ProtocolError> {
+        self.dictionary
+            .enumeration("pin", name)
+            .map(|pin| pin as u32)
+            .ok_or_else(|| ProtocolError::UnknownEnumeration {
+                enumeration: "pin".to_string(),
+                value: name.to_string(),
+            })
+    }
+
+    /// Drive a pin to a fixed level.
+    pub fn set_pin(&mut self, pin: &str, value: bool) -> Result<(), ProtocolError> {
+        let pin = self.pin(pin)?;
+        self.pins.insert(pin, PinModel::Level(value));
+        Ok(())
+    }
+
+    /// Make `pin` read high once stepper `oid` reaches `position` (in steps)
+    /// travelling in `positive_dir`, like an endstop at the end of a rail.
+    pub fn attach_endstop(
+        &mut self,
+        pin: &str,
+        oid: u8,
+        position: i64,
+        positive_dir: bool,
+    ) -> Result<(), ProtocolError> {
+        let pin = self.pin(pin)?;
+        self.pins.insert(
+            pin,
+            PinModel::Stepper {
+                oid,
+                position,
+                positive_dir,
+            },
+        );
+        Ok(())
+    }
+
+    /// Set the raw reading of an ADC pin, e.g. a thermistor divider.
+    pub fn set_adc(&mut self, pin: &str, value: u16) -> Result<(), ProtocolError> {
+        let pin = self.pin(pin)?;
+        self.adc.insert(pin, value);
+        Ok(())
+    }
+
+    fn pin_value(&self, pin: u32) -> bool {
+        match self.pins.get(&pin) {
+            Some(PinModel::Level(value)) => *value,
+            Some(PinModel::Stepper {
+                oid,
+                position,
+                positive_dir,
+            }) => {
+                let current = self.position(*oid).unwrap_or_default();
+                if *positive_dir {
+                    current >= *position
+                } else {
+                    current <= *position
+                }
+            }
+            None => false,
+        }
+    }
+
+    /// Stepper position (in steps) at the current clock.
+    pub fn position(&self, oid: u8) -> Option<i64> {
+        self.position_at(oid, self.clock)
+    }
+
+    /// Stepper position (in steps) at `clock`.
+    pub fn position_at(&self, oid: u8, clock: u64) -> Option<i64> {
+        Some(self.steppers.get(&oid)?.position_at(clock))
+    }
+
+    /// Every step segment the stepper was sent, in order.
+    pub fn step_segments(&self, oid: u8) -> &[StepSegment] {
+        self.steppers
+            .get(&oid)
+            .map_or(&[], |stepper| stepper.segments.as_slice())
+    }
+
+    /// Total steps the stepper was told to take.
+    pub fn step_count(&self, oid: u8) -> u64 {
+        self.step_segments(oid)
+            .iter()
+            .map(|segment| segment.count as u64)
+            .sum()
+    }
+
+    /// Highest instantaneous step rate (steps/s) of the stepper.
+    pub fn max_step_rate(&self, oid: u8) -> Option<f64> {
+        let min_interval = self
+            .step_segments(oid)
+            .iter()
+            .map(StepSegment::min_interval)
+            .min()?;
+        Some(self.freq / min_interval.max(1) as f64)
+    }
+
+    /// Move the clock forward, sending any periodic reports that fall due.
+    pub fn advance(&mut self, clock: u64) {
+        while let Some((oid, at)) = self
+            .analog
+            .iter()
+            .filter_map(|(oid, analog)| Some((*oid, analog.next_report?)))
+            .filter(|(_, at)| *at <= clock)
+            .min_by_key(|(_, at)| *at)
+        {
+            self.clock = self.clock.max(at);
+            self.report_analog(oid);
+        }
+        self.clock = self.clock.max(clock);
+    }
+
+    /// Clock of the next periodic report, if any.
+    pub fn next_event(&self) -> Option<u64> {
+        self.analog
+            .values()
+            .filter_map(|analog| analog.next_report)
+            .min()
+    }
+
+    fn report_analog(&mut self, oid: u8) {
+        let analog = &self.analog[&oid];
+        let sample = self.adc.get(&analog.pin).copied().unwrap_or_default();
+        let value = (sample as u32 * analog.sample_count as u32).min(u16::MAX as u32) as u16;
+        let next_report = analog.next_report.unwrap() + analog.rest_ticks as u64;
+
+        let analog = self.analog.get_mut(&oid).unwrap();
+        analog.next_report = (analog.rest_ticks > 0).then_some(next_report);
+        if !(analog.min_value..=analog.max_value).contains(&value) {
+            analog.out_of_range += 1;
+            if analog.range_check_count > 0 && analog.out_of_range >= analog.range_check_count {
+                self.do_shutdown("ADC out of range");
+                return;
+            }
+        } else {
+            analog.out_of_range = 0;
+        }
+        self.respond(
+            "analog_in_state",
+            &[
+                oid.into(),
+                (next_report as u32).into(),
+                (value as u32).into(),
+            ],
+        );
+    }
+
+    /// Process bytes from the host at the current clock.
+    pub fn receive(&mut self, data: &[u8]) {
+        self.reader.push(data);
+        while let Some(block) = self.reader.next_block() {
+            if block.seq != self.next_sequence {
+                // Out of order: NAK and wait for the retransmit
+                self.send_block(&[]);
+                continue;
+            }
+            self.next_sequence = (self.next_sequence + 1) & MESSAGE_SEQ_MASK;
+            match self.dictionary.decode(&block.payload) {
+                Ok(messages) => {
+                    for message in messages {
+                        self.dispatch(&message);
+                        self.commands.push(message);
+                    }
+                }
+                Err(_) => self.do_shutdown("Invalid command"),
+            }
+            self.send_block(&[]);
+        }
+    }
+
+    /// Bytes waiting to be sent to the host.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    fn send_block(&mut self, payload: &[u8]) {
+        let block = encode_block(self.next_sequence as u64, payload);
+        self.output.extend_from_slice(&block);
+    }
+
+    fn respond(&mut self, name: &str, args: &[Value]) {
+        let payload = self
+            .dictionary
+            .response(name)
+            .and_then(|format| format.encode(args).ok())
+            .expect("virtual MCU responses are well formed");
+        self.send_block(&payload);
+    }
+
+    fn do_shutdown(&mut self, reason: &'static str) {
+        if self.shutdown.is_some() {
+            return;
+        }
+        self.shutdown = Some(reason);
+        for analog in self.analog.values_mut() {
+            analog.next_report = None;
+        }
+        let id = SHUTDOWN_REASONS.iter().position(|r| *r == reason).unwrap() as u32;
+        self.respond("shutdown", &[(self.clock as u32).into(), id.into()]);
+    }
+
+    /// Extend a 32-bit clock from the host to the nearest 64-bit clock.
+    fn extend_clock(&self, clock: u32) -> u64 {
+        let delta = clock.wrapping_sub(self.clock as u32) as i32;
+        (self.clock as i64 + delta as i64).max(0) as u64
+    }
+
+    fn dispatch(&mut self, message: &Message) {
+        let int = |name: &str| message.int(name).unwrap_or_default();
+        let oid = int("oid") as u8;
+
+        if let Some(reason) = self.shutdown
+            && !matches!(
+                message.name.as_str(),
+                "identify" | "get_clock" | "get_uptime"
+            )
+        {
+            let id = SHUTDOWN_REASONS.iter().position(|r| *r == reason).unwrap() as u32;
+            self.respond("is_shutdown", &[id.into()]);
+            return;
+        }
+
+        match message.name.as_str() {
+            "identify" => {
+                let offset = (int("offset") as usize).min(self.compressed_dictionary.len());
+                let end = (offset + int("count") as usize).min(self.compressed_dictionary.len());
+                let data = self.compressed_dictionary[offset..end].to_vec();
+                self.respond(
+                    "identify_response",
+                    &[(offset as u32).into(), Value::Bytes(data)],
+                );
+            }
+            "get_clock" => self.respond("clock", &[(self.clock as u32).into()]),
+            "get_uptime" => self.respond(
+                "uptime",
+                &[
+                    ((self.clock >> 32) as u32).into(),
+                    (self.clock as u32).into(),
+                ],
+            ),
+            "emergency_stop" => self.do_shutdown("Command request"),
+            "config_stepper" => {
+                self.steppers.insert(oid, VirtualStepper::default());
+            }
+            "queue_step" => {
+                let now = self.clock;
+                let Some(stepper) = self.steppers.get_mut(&oid) else {
+                    return self.do_shutdown("Invalid oid");
+                };
+                let (interval, count, add) = (int("interval"), int("count"), int("add"));
+                let first_clock = stepper.last_step_clock + interval as u64;
+                if first_clock < now {
+                    return self.do_shutdown("Stepper too far in past");
+                }
+                if count == 0 {
+                    return;
+                }
+                let segment = StepSegment {
+                    first_clock,
+                    interval: interval as u32,
+                    count: count as u16,
+                    add: add as i16,
+                    dir: stepper.next_dir,
+                    start_position: stepper.position,
+                };
+                stepper.last_step_clock = segment.last_clock();
+                stepper.position = segment.end_position();
+                stepper.segments.push(segment);
+            }
+            "set_next_step_dir" => match self.steppers.get_mut(&oid) {
+                Some(stepper) => stepper.next_dir = int("dir") != 0,
+                None => self.do_shutdown("Invalid oid"),
+            },
+            "reset_step_clock" => {
+                let clock = self.extend_clock(int("clock") as u32);
+                match self.steppers.get_mut(&oid) {
+                    Some(stepper) => stepper.last_step_clock = clock,
+                    None => self.do_shutdown("Invalid oid"),
+                }
+            }
+            "stepper_get_position" => match self.position(oid) {
+                Some(position) => {
+                    self.respond("stepper_position", &[oid.into(), (position as i32).into()])
+                }
+                None => self.do_shutdown("Invalid oid"),
+            },
+            "config_endstop" => {
+                self.endstops.insert(oid, int("pin") as u32);
+            }
+            "endstop_query_state" => match self.endstops.get(&oid) {
+                Some(&pin) => {
+                    let value = self.pin_value(pin);
+                    self.respond(
+                        "endstop_state",
+                        &[
+                            oid.into(),
+                            false.into(),
+                            (self.clock as u32).into(),
+                            value.into(),
+                        ],
+                    );
+                }
+                None => self.do_shutdown("Invalid oid"),
+            },
+            "config_analog_in" => {
+                self.analog.insert(
+                    oid,
+                    AnalogIn {
+                        pin: int("pin") as u32,
+                        next_report: None,
+                        sample_count: 1,
+                        rest_ticks: 0,
+                        min_value: 0,
+                        max_value: u16::MAX,
+                        range_check_count: 0,
+                        out_of_range: 0,
+                    },
+                );
+            }
+            "query_analog_in" => {
+                let clock = self.extend_clock(int("clock") as u32);
+                let Some(analog) = self.analog.get_mut(&oid) else {
+                    return self.do_shutdown("Invalid oid");
+                };
+                analog.sample_count = int("sample_count") as u8;
+                analog.rest_ticks = int("rest_ticks") as u32;
+                analog.min_value = int("min_value") as u16;
+                analog.max_value = int("max_value") as u16;
+                analog.range_check_count = int("range_check_count") as u8;
+                analog.out_of_range = 0;
+                analog.next_report = (analog.rest_ticks > 0).then_some(clock);
+            }
+            _ => self.do_shutdown("Invalid command"),
+        }
+    }
+}
+
+/// A [`SerialQueue`] wired directly to a [`VirtualMcu`] in virtual time.
+///
+/// Blocks are delivered instantly, and time only moves when
+/// [`Loopback::run_until`] is called.
+pub struct Loopback {
+    pub host: SerialQueue,
+    pub mcu: VirtualMcu,
+    time: f64,
+    responses: Vec<Message>,
+}
+
+impl Loopback {
+    pub fn new(mcu: VirtualMcu) -> Self {
+        let mut host = SerialQueue::new();
+        host.set_clock_estimate(ClockEstimate {
+            time: 0.0,
+            clock: 0,
+            freq: mcu.freq(),
+        });
+        if let Some(receive_window) = mcu.dictionary().receive_window() {
+            host.set_receive_window(receive_window);
+        }
+        Self {
+            host,
+            mcu,
+            time: 0.0,
+            responses: Vec::new(),
+        }
+    }
+
+    /// Current virtual time (seconds).
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+
+    /// Encode and queue a command for immediate sending.
+    pub fn send(&mut self, format: &str, args: &[Value]) -> Result<(), ProtocolError> {
+        let data = self.mcu.dictionary().encode(format, args)?;
+        self.host.send_now(data);
+        Ok(())
+    }
+
+    /// Run both sides until `time`, delivering everything that falls due.
+    pub fn run_until(&mut self, time: f64) {
+        let tick = 1.0 / self.mcu.freq();
+        loop {
+            self.exchange();
+            let mcu_event = self
+                .mcu
+                .next_event()
+                .map(|clock| clock as f64 / self.mcu.freq());
+            let next = [self.host.poll_timeout(), mcu_event]
+                .into_iter()
+                .flatten()
+                .fold(f64::INFINITY, f64::min)
+                // Never stall on an event that rounds to the current time
+                .max(self.time + tick);
+            if next > time {
+                break;
+            }
+            self.time = next;
+            self.mcu.advance((next * self.mcu.freq()) as u64);
+        }
+        self.time = self.time.max(time);
+        self.mcu.advance((self.time * self.mcu.freq()) as u64);
+        self.exchange();
+    }
+
+    /// Deliver blocks in both directions until neither side has more.
+    fn exchange(&mut self) {
+        loop {
+            let mut progressed = false;
+            while let Some(data) = self.host.poll_transmit(self.time) {
+                self.mcu.receive(&data);
+                progressed = true;
+            }
+            let output = self.mcu.take_output();
+            if !output.is_empty() {
+                for payload in self.host.receive(&output, self.time) {
+                    if let Ok(messages) = self.mcu.dictionary().decode(&payload) {
+                        self.responses.extend(messages);
+                    }
+                }
+                progressed = true;
+            }
+            if !progressed {
+                break;
+            }
+        }
+    }
+
+    /// Responses received by the host since the last call.
+    pub fn take_responses(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.responses)
+    }
+}
+
+/// Run `mcu` on a background thread in real time and connect a transport to
+/// it. The returned handle can be used to inspect or drive the MCU's model;
+/// the thread exits once the transport is dropped.
+pub fn spawn(mcu: VirtualMcu) -> (Transport, Arc<Mutex<VirtualMcu>>) {
+    let to_mcu = Pipe::default();
+    let to_host = Pipe::default();
+    let mcu = Arc::new(Mutex::new(mcu));
+
+    {
+        let mcu = mcu.clone();
+        let mut reader = PipeReader(to_mcu.clone());
+        let mut writer = PipeWriter(to_host.clone());
+        thread::Builder::new()
+            .name("scherzo-virtual-mcu".to_string())
+            .spawn(move || {
+                let start = Instant::now();
+                let mut buf = [0u8; 4096];
+                loop {
+                    let read = match reader.read(&mut buf) {
+                        Ok(0) => return,
+                        Ok(len) => len,
+                        Err(err) if err.kind() == io::ErrorKind::TimedOut => 0,
+                        Err(_) => return,
+                    };
+                    let output = {
+                        let mut mcu = mcu.lock().unwrap_or_else(|err| err.into_inner());
+                        let clock = (start.elapsed().as_secs_f64() * mcu.freq()) as u64;
+                        mcu.advance(clock);
+                        mcu.receive(&buf[..read]);
+                        mcu.take_output()
+                    };
+                    if !output.is_empty() && writer.write_all(&output).is_err() {
+                        return;
+                    }
+                }
+            })
+            .expect("failed to spawn virtual MCU thread");
+    }
+
+    let transport = Transport::new(PipeReader(to_host), PipeWriter(to_mcu));
+    (transport, mcu)
+}
+
+/// In-memory byte stream between the host and the virtual MCU.
+#[derive(Clone, Default)]
+struct Pipe(Arc<(Mutex<PipeState>, Condvar)>);
+
+#[derive(Default)]
+struct PipeState {
+    buf: VecDeque<u8>,
+    closed: bool,
+}
+
+struct PipeReader(Pipe);
+
+impl Read for PipeReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let (state, ready) = &*self.0.0;
+        let state = state.lock().unwrap_or_else(|err| err.into_inner());
+        let (mut state, _) = ready
+            .wait_timeout_while(state, READ_TIMEOUT, |state| {
+                state.buf.is_empty() && !state.closed
+            })
+            .unwrap_or_else(|err| err.into_inner());
+        if state.buf.is_empty() {
+            if state.closed {
+                return Ok(0);
+            }
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        let len = out.len().min(state.buf.len());
+        for (dst, src) in out.iter_mut().zip(state.buf.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
+
+struct PipeWriter(Pipe);
+
+impl Write for PipeWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let (state, ready) = &*self.0.0;
+        let mut state = state.lock().unwrap_or_else(|err| err.into_inner());
+        if state.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        state.buf.extend(data);
+        ready.notify_all();
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        let (state, ready) = &*self.0.0;
+        state.lock().unwrap_or_else(|err| err.into_inner()).closed = true;
+        ready.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mcu::Mcu, steps::StepSink};
+    use scherzo_core::step_compressor::StepCompressor;
+    use std::time::Duration;
+
+    const FREQ: f64 = 1_000_000.0;
+    const CONFIG_STEPPER: &str =
+        "config_stepper oid=%c step_pin=%c dir_pin=%c invert_step=%c step_pulse_ticks=%u";
+
+    #[test]
+    fn executes_compressed_steps() {
+        let mut link = Loopback::new(VirtualMcu::new(FREQ));
+        link.send(
+            CONFIG_STEPPER,
+            &[0.into(), 1.into(), 2.into(), 0.into(), 0.into()],
+        )
+        .unwrap();
+        link.run_until(0.0);
+
+        // 100 steps at 1 kHz, then 50 back at 2 kHz after a pause
+        let queue = link.host.alloc_command_queue();
+        let dictionary = link.mcu.dictionary().clone();
+        let sink = StepSink::new(&dictionary, &mut link.host, queue).unwrap();
+        let mut compressor = StepCompressor::new(0, 10, sink);
+        compressor.set_time(0.0, FREQ);
+        for i in 1..=100 {
+            compressor.append(1, 0.0, i as f64 * 0.001).unwrap();
+        }
+        for i in 1..=50 {
+            compressor.append(0, 0.2, i as f64 * 0.0005).unwrap();
+        }
+        compressor.flush(u64::MAX).unwrap();
+        drop(compressor);
+
+        link.run_until(0.0505);
+        assert_eq!(link.mcu.position(0), Some(50));
+        link.run_until(1.0);
+        assert_eq!(link.mcu.shutdown_reason(), None);
+        assert_eq!(link.mcu.position_at(0, 100_200), Some(100));
+        assert_eq!(link.mcu.position(0), Some(50));
+        assert_eq!(link.mcu.step_count(0), 150);
+        let rate = link.mcu.max_step_rate(0).unwrap();
+        assert!((1900.0..2100.0).contains(&rate), "{rate}");
+
+        link.send("stepper_get_position oid=%c", &[0.into()])
+            .unwrap();
+        link.run_until(1.0);
+        let responses = link.take_responses();
+        assert_eq!(responses[0].int("pos"), Some(50));
+    }
+
+    #[test]
+    fn late_steps_shut_down() {
+        let mut link = Loopback::new(VirtualMcu::new(FREQ));
+        link.send(
+            CONFIG_STEPPER,
+            &[0.into(), 1.into(), 2.into(), 0.into(), 0.into()],
+        )
+        .unwrap();
+        link.run_until(1.0);
+
+        // A step due at clock 1000 arrives at clock 1_000_000
+        link.send(
+            "queue_step oid=%c interval=%u count=%hu add=%hi",
+            &[0.into(), 1000u32.into(), 1u32.into(), 0.into()],
+        )
+        .unwrap();
+        link.run_until(1.0);
+        assert_eq!(link.mcu.shutdown_reason(), Some("Stepper too far in past"));
+        let responses = link.take_responses();
+        assert_eq!(responses[0].name, "shutdown");
+    }
+
+    #[test]
+    fn models_endstops_and_adc() {
+        let mut mcu = VirtualMcu::new(FREQ);
+        mcu.attach_endstop("PA3", 0, 10, true).unwrap();
+        mcu.set_adc("PB1", 1000).unwrap();
+        let pin = |name| mcu.dictionary().enumeration("pin", name).unwrap() as u32;
+        let (endstop_pin, adc_pin) = (pin("PA3"), pin("PB1"));
+
+        let mut link = Loopback::new(mcu);
+        link.send(
+            CONFIG_STEPPER,
+            &[0.into(), 1.into(), 2.into(), 0.into(), 0.into()],
+        )
+        .unwrap();
+        link.send("set_next_step_dir oid=%c dir=%c", &[0.into(), 1.into()])
+            .unwrap();
+        link.send(
+            "queue_step oid=%c interval=%u count=%hu add=%hi",
+            &[0.into(), 1000u32.into(), 20u32.into(), 0.into()],
+        )
+        .unwrap();
+        link.send(
+            "config_endstop oid=%c pin=%c pull_up=%c",
+            &[1.into(), endstop_pin.into(), 0.into()],
+        )
+        .unwrap();
+        link.send(
+            "config_analog_in oid=%c pin=%u",
+            &[2.into(), adc_pin.into()],
+        )
+        .unwrap();
+        link.send(
+            "query_analog_in oid=%c clock=%u sample_ticks=%u sample_count=%c rest_ticks=%u min_value=%hu max_value=%hu range_check_count=%c",
+            &[
+                2.into(),
+                0u32.into(),
+                100u32.into(),
+                8u32.into(),
+                100_000u32.into(),
+                0u32.into(),
+                10_000u32.into(),
+                1u32.into(),
+            ],
+        )
+        .unwrap();
+
+        let endstop = |link: &mut Loopback| {
+            link.send("endstop_query_state oid=%c", &[1.into()])
+                .unwrap();
+            link.run_until(link.time());
+            let responses = link.take_responses();
+            let state = responses.iter().rfind(|m| m.name == "endstop_state");
+            state.unwrap().int("pin_value").unwrap()
+        };
+
+        link.run_until(0.005);
+        assert_eq!(endstop(&mut link), 0);
+        link.run_until(0.015);
+        assert_eq!(endstop(&mut link), 1);
+
+        link.run_until(0.35);
+        let reports: Vec<_> = link
+            .take_responses()
+            .into_iter()
+            .filter(|m| m.name == "analog_in_state")
+            .collect();
+        assert_eq!(reports.len(), 3);
+        assert_eq!(reports[0].int("value"), Some(8000));
+
+        // Readings beyond max_value trip the range check
+        link.mcu.set_adc("PB1", 2000).unwrap();
+        link.run_until(0.45);
+        assert_eq!(link.mcu.shutdown_reason(), Some("ADC out of range"));
+    }
+
+    #[test]
+    fn serves_dictionary_over_transport() {
+        let (transport, virtual_mcu) = spawn(VirtualMcu::new(FREQ));
+        let mcu = Mcu::identify(transport).unwrap();
+        assert_eq!(mcu.clock_freq(), FREQ);
+        assert_eq!(mcu.dictionary().config["MCU"], "virtual");
+
+        mcu.send("get_clock", &[]).unwrap();
+        let messages = mcu.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+        assert_eq!(messages[0].name, "clock");
+        assert!(virtual_mcu.lock().unwrap().commands().len() > 1);
+        assert_eq!(mcu.handle().stats().retransmits, 0);
+    }
+}
@@ -0,0 +1,428 @@
+//! Job bundles uploaded as archives
+//!
+//! Some slicers export a print as a zip or tar archive holding the G-code
+//! along with thumbnails and a snapshot of the slicer's settings. A
+//! [`Bundle`] is such an archive split into the G-code to compile and the
+//! assets to keep with the job. Zip archives may store or deflate their
+//! entries; tar archives may be gzipped. Everything extracted together must
+//! fit in the size limit given, so a small archive cannot expand into an
+//! unbounded amount of data.
+
+use anyhow::{Context, Result, bail, ensure};
+use flate2::read::{DeflateDecoder, GzDecoder};
+use std::io::Read;
+
+/// Archive formats accepted as job uploads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    /// Tar, optionally gzipped
+    Tar,
+}
+
+impl ArchiveFormat {
+    /// The format uploaded with `content_type`, if it is an archive
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let mime = content_type.split(';').next().unwrap_or_default().trim();
+        match mime {
+            "application/zip" | "application/x-zip-compressed" => Some(Self::Zip),
+            "application/x-tar" | "application/gzip" | "application/x-gzip"
+            | "application/x-gtar" => Some(Self::Tar),
+            _ => None,
+        }
+    }
+
+    /// Name recorded as the job's original format
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Zip => "zip",
+            Self::Tar => "tar",
+        }
+    }
+}
+
+/// A file from an archive other than the G-code
+#[derive(Debug, Clone, PartialEq)]
+pub struct Asset {
+    /// Relative path in the archive, with `/` separators
+    pub path: String,
+    pub data: Vec<u8>,
+}
+
+/// An archive's G-code and the assets exported with it
+#[derive(Debug)]
+pub struct Bundle {
+    /// Path of the G-code file in the archive
+    pub gcode_path: String,
+    pub gcode: String,
+    pub assets: Vec<Asset>,
+}
+
+impl Bundle {
+    /// Extract `bytes`, which must hold exactly one G-code file and expand
+    /// to at most `max_size` bytes
+    pub fn extract(format: ArchiveFormat, bytes: &[u8], max_size: u64) -> Result<Self> {
+        let mut budget = max_size;
+        let files = match format {
+            ArchiveFormat::Zip => read_zip(bytes, &mut budget)?,
+            ArchiveFormat::Tar if bytes.starts_with(&[0x1f, 0x8b]) => {
+                // Only the files count against the limit, but headers and
+                // padding can make the tar itself up to twice their size
+                let tar = inflate(GzDecoder::new(bytes), &mut max_size.saturating_mul(2))
+                    .context("failed to decompress gzipped tar")?;
+                read_tar(&tar, &mut budget)?
+            }
+            ArchiveFormat::Tar => read_tar(bytes, &mut budget)?,
+        };
+
+        let (gcode, assets): (Vec<_>, Vec<_>) =
+            files.into_iter().partition(|file| is_gcode(&file.path));
+        let gcode = match <[Asset; 1]>::try_from(gcode) {
+            Ok([gcode]) => gcode,
+            Err(gcode) if gcode.is_empty() => bail!("archive has no G-code file"),
+            Err(gcode) => {
+                let paths: Vec<_> = gcode.iter().map(|file| file.path.as_str()).collect();
+                bail!("archive has several G-code files: {}", paths.join(", "))
+            }
+        };
+        Ok(Self {
+            gcode: String::from_utf8(gcode.data)
+                .with_context(|| format!("{} is not valid UTF-8", gcode.path))?,
+            gcode_path: gcode.path,
+            assets,
+        })
+    }
+}
+
+fn is_gcode(path: &str) -> bool {
+    path.rsplit_once('.').is_some_and(|(_, ext)| {
+        ["gcode", "gco", "g"]
+            .iter()
+            .any(|gcode| ext.eq_ignore_ascii_case(gcode))
+    })
+}
+
+/// `name` as a relative path inside the archive, or `None` for directories
+/// and metadata to skip. Fails on paths that would escape the job's assets.
+fn entry_path(name: &str) -> Result<Option<String>> {
+    let name = name.replace('\\', "/");
+    if name.ends_with('/') || name.starts_with("__MACOSX/") {
+        return Ok(None);
+    }
+    let parts: Vec<_> = name
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect();
+    ensure!(
+        !name.starts_with('/') && !parts.contains(&".."),
+        "archive entry `{name}` is outside the archive"
+    );
+    Ok((!parts.is_empty()).then(|| parts.join("/")))
+}
+
+/// Read all of `reader`, spending its length from `budget`
+fn inflate(reader: impl Read, budget: &mut u64) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    reader
+        .take(budget.saturating_add(1))
+        .read_to_end(&mut data)?;
+    take_budget(budget, data.len() as u64)?;
+    Ok(data)
+}
+
+fn take_budget(budget: &mut u64, size: u64) -> Result<()> {
+    *budget = budget
+        .checked_sub(size)
+        .context("archive expands past the job size limit")?;
+    Ok(())
+}
+
+const ZIP_END: u32 = 0x0605_4b50;
+const ZIP_CENTRAL: u32 = 0x0201_4b50;
+const ZIP_LOCAL: u32 = 0x0403_4b50;
+const ZIP_END_LEN: usize = 22;
+
+fn read_zip(bytes: &[u8], budget: &mut u64) -> Result<Vec<Asset>> {
+    // The end of central directory record is last, before a comment of up
+    // to 64 KiB
+    let end = (0..=bytes.len().saturating_sub(ZIP_END_LEN))
+        .rev()
+        .take(u16::MAX as usize + 1)
+        .find(|&at| u32_at(bytes, at).ok() == Some(ZIP_END))
+        .context("not a zip archive")?;
+    let count = u16_at(bytes, end + 10)?;
+    let mut at = u32_at(bytes, end + 16)? as usize;
+    ensure!(
+        count != u16::MAX && at != u32::MAX as usize,
+        "zip64 archives are not supported"
+    );
+
+    let mut files = Vec::new();
+    for _ in 0..count {
+        ensure!(
+            u32_at(bytes, at)? == ZIP_CENTRAL,
+            "corrupt zip central directory"
+        );
+        let flags = u16_at(bytes, at + 8)?;
+        let method = u16_at(bytes, at + 10)?;
+        let crc = u32_at(bytes, at + 16)?;
+        let compressed = u32_at(bytes, at + 20)? as usize;
+        let name_len = u16_at(bytes, at + 28)? as usize;
+        let extra_len = u16_at(bytes, at + 30)? as usize;
+        let comment_len = u16_at(bytes, at + 32)? as usize;
+        let local = u32_at(bytes, at + 42)? as usize;
+        let name = slice(bytes, at + 46, name_len)?;
+        let name = String::from_utf8_lossy(name).into_owned();
+        at += 46 + name_len + extra_len + comment_len;
+
+        let Some(path) = entry_path(&name)? else {
+            continue;
+        };
+        ensure!(flags & 1 == 0, "{path} is encrypted");
+        ensure!(
+            u32_at(bytes, local)? == ZIP_LOCAL,
+            "corrupt zip entry {path}"
+        );
+        let start =
+            local + 30 + u16_at(bytes, local + 26)? as usize + u16_at(bytes, local + 28)? as usize;
+        let raw = slice(bytes, start, compressed)?;
+        let data = match method {
+            0 => {
+                take_budget(budget, raw.len() as u64)?;
+                raw.to_vec()
+            }
+            8 => inflate(DeflateDecoder::new(raw), budget)
+                .with_context(|| format!("failed to decompress {path}"))?,
+            _ => bail!("{path} uses unsupported zip compression method {method}"),
+        };
+        ensure!(crc32fast::hash(&data) == crc, "{path} is corrupt");
+        files.push(Asset { path, data });
+    }
+    Ok(files)
+}
+
+const TAR_BLOCK: usize = 512;
+
+fn read_tar(bytes: &[u8], budget: &mut u64) -> Result<Vec<Asset>> {
+    let mut files = Vec::new();
+    let mut at = 0;
+    // Name from a GNU long name or pax header, for the entry that follows
+    let mut long_name = None;
+    while at + TAR_BLOCK <= bytes.len() {
+        let header = &bytes[at..at + TAR_BLOCK];
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        let size = octal(&header[124..136]).context("corrupt tar header")?;
+        let data = slice(bytes, at + TAR_BLOCK, size)?;
+        at += TAR_BLOCK + size.next_multiple_of(TAR_BLOCK);
+
+        match header[156] {
+            b'L' => long_name = Some(cstr(data)),
+            b'x' => long_name = pax_path(data).or(long_name),
+            b'0' | 0 => {
+                let name = long_name.take().unwrap_or_else(|| {
+                    let prefix = cstr(&header[345..500]);
+                    let name = cstr(&header[..100]);
+                    if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                        format!("{prefix}/{name}")
+                    } else {
+                        name
+                    }
+                });
+                if let Some(path) = entry_path(&name)? {
+                    take_budget(budget, data.len() as u64)?;
+                    files.push(Asset {
+                        path,
+                        data: data.to_vec(),
+                    });
+                }
+            }
+            // Directories, links and other special entries
+            _ => long_name = None,
+        }
+    }
+    Ok(files)
+}
+
+/// `path` of a pax extended header, made of `<len> <key>=<value>\n` records
+fn pax_path(data: &[u8]) -> Option<String> {
+    String::from_utf8_lossy(data).lines().find_map(|record| {
+        let (_, field) = record.split_once(' ')?;
+        field.strip_prefix("path=").map(str::to_string)
+    })
+}
+
+fn octal(field: &[u8]) -> Option<usize> {
+    let digits = cstr(field);
+    usize::from_str_radix(digits.trim(), 8).ok()
+}
+
+fn cstr(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+fn slice(bytes: &[u8], at: usize, len: usize) -> Result<&[u8]> {
+    at.checked_add(len)
+        .and_then(|end| bytes.get(at..end))
+        .context("archive is truncated")
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> Result<u16> {
+    Ok(u16::from_le_bytes(slice(bytes, at, 2)?.try_into()?))
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> Result<u32> {
+    Ok(u32::from_le_bytes(slice(bytes, at, 4)?.try_into()?))
+}
+
+/// A zip archive storing `files` uncompressed, for tests
+#[cfg(test)]
+pub fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut central = Vec::new();
+    for (name, data) in files {
+        let crc = crc32fast::hash(data);
+        let sizes = [crc, data.len() as u32, data.len() as u32];
+        central.extend(ZIP_CENTRAL.to_le_bytes());
+        central.extend([20, 0, 20, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        sizes.iter().for_each(|v| central.extend(v.to_le_bytes()));
+        central.extend((name.len() as u16).to_le_bytes());
+        central.extend([0; 12]);
+        central.extend((out.len() as u32).to_le_bytes());
+        central.extend(name.as_bytes());
+
+        out.extend(ZIP_LOCAL.to_le_bytes());
+        out.extend([20, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        sizes.iter().for_each(|v| out.extend(v.to_le_bytes()));
+        out.extend((name.len() as u16).to_le_bytes());
+        out.extend([0, 0]);
+        out.extend(name.as_bytes());
+        out.extend(*data);
+    }
+    let offset = out.len() as u32;
+    out.extend(&central);
+    out.extend(ZIP_END.to_le_bytes());
+    out.extend([0; 4]);
+    out.extend((files.len() as u16).to_le_bytes());
+    out.extend((files.len() as u16).to_le_bytes());
+    out.extend((central.len() as u32).to_le_bytes());
+    out.extend(offset.to_le_bytes());
+    out.extend([0, 0]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{Compression, write::GzEncoder};
+    use std::io::Write;
+
+    fn tar(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (name, data) in files {
+            let mut header = [0u8; TAR_BLOCK];
+            header[..name.len()].copy_from_slice(name.as_bytes());
+            let size = format!("{:011o}\0", data.len());
+            header[124..136].copy_from_slice(size.as_bytes());
+            header[156] = b'0';
+            header[257..263].copy_from_slice(b"ustar\0");
+            out.extend(header);
+            out.extend(*data);
+            out.resize(out.len().next_multiple_of(TAR_BLOCK), 0);
+        }
+        out.extend([0; 2 * TAR_BLOCK]);
+        out
+    }
+
+    #[test]
+    fn extracts_gcode_and_assets_from_zip() {
+        let archive = zip(&[
+            ("Metadata/", b""),
+            ("Metadata/plate_1.gcode", b"G28\nG1 X10\n"),
+            ("Metadata/plate_1.png", b"\x89PNG"),
+            ("__MACOSX/._plate_1.gcode", b"junk"),
+            ("config.ini", b"layer_height = 0.2\n"),
+        ]);
+        let bundle = Bundle::extract(ArchiveFormat::Zip, &archive, 1 << 20).unwrap();
+        assert_eq!(bundle.gcode_path, "Metadata/plate_1.gcode");
+        assert_eq!(bundle.gcode, "G28\nG1 X10\n");
+        let paths: Vec<_> = bundle.assets.iter().map(|a| a.path.as_str()).collect();
+        assert_eq!(paths, ["Metadata/plate_1.png", "config.ini"]);
+
+        // Everything extracted counts against the limit
+        let err = Bundle::extract(ArchiveFormat::Zip, &archive, 20).unwrap_err();
+        assert!(format!("{err:#}").contains("size limit"), "{err:#}");
+    }
+
+    #[test]
+    fn extracts_gzipped_tar() {
+        let archive = tar(&[("./part.gcode", b"G28\n"), ("./thumb.png", b"png")]);
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(&archive).unwrap();
+        let gz = gz.finish().unwrap();
+
+        for bytes in [&archive, &gz] {
+            let bundle = Bundle::extract(ArchiveFormat::Tar, bytes, 1 << 20).unwrap();
+            assert_eq!(bundle.gcode_path, "part.gcode");
+            assert_eq!(bundle.assets[0].path, "thumb.png");
+            assert_eq!(bundle.assets[0].data, b"png");
+        }
+    }
+
+    #[test]
+    fn rejects_unusable_archives() {
+        let err = |files: &[(&str, &[u8])]| {
+            let archive = zip(files);
+            format!(
+                "{:#}",
+                Bundle::extract(ArchiveFormat::Zip, &archive, 1 << 20).unwrap_err()
+            )
+        };
+        assert!(err(&[("a.png", b"")]).contains("no G-code"));
+        assert!(err(&[("a.gcode", b""), ("b.gco", b"")]).contains("a.gcode, b.gco"));
+        assert!(err(&[("a.gcode", b""), ("../evil.sh", b"")]).contains("outside"));
+        assert_eq!(
+            ArchiveFormat::from_content_type("application/zip; charset=binary"),
+            Some(ArchiveFormat::Zip)
+        );
+        assert_eq!(ArchiveFormat::from_content_type("text/x-gcode"), None);
+    }
+
+    #[test]
+    fn arbitrary_bytes_never_panic() {
+        bolero::check!().with_type::<Vec<u8>>().for_each(|bytes| {
+            for format in [ArchiveFormat::Zip, ArchiveFormat::Tar] {
+                let _ = Bundle::extract(format, bytes, 1 << 16);
+                let _ = Bundle::extract(format, bytes, u64::MAX);
+            }
+        });
+    }
+
+    #[test]
+    fn corrupted_archives_never_panic() {
+        let zip = zip(&[("a.gcode", b"G28\n"), ("b.png", b"png")]);
+        let tar = tar(&[("a.gcode", b"G28\n"), ("b.png", b"png")]);
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(&tar).unwrap();
+        let gz = gz.finish().unwrap();
+        let archives = [
+            (ArchiveFormat::Zip, zip),
+            (ArchiveFormat::Tar, tar),
+            (ArchiveFormat::Tar, gz),
+        ];
+
+        // Flip one byte, then cut the archive short
+        bolero::check!()
+            .with_type::<(usize, u8, usize)>()
+            .for_each(|&(at, xor, len)| {
+                for (format, archive) in &archives {
+                    let mut bytes = archive.clone();
+                    bytes[at % archive.len()] ^= xor;
+                    bytes.truncate(len % (archive.len() + 1));
+                    let _ = Bundle::extract(*format, &bytes, 1 << 16);
+                }
+            });
+    }
+}
@@ -0,0 +1,320 @@
+//! Bed mesh calibration
+//!
+//! Calibration visits each point of the `[bed_mesh]` grid at
+//! `horizontal_move_z` and waits for the bed height there to be reported,
+//! measured with a probe or by hand. Once every point has a height the mesh
+//! is activated and written to `saved_config`, so it is loaded again at the
+//! next start.
+
+use crate::{
+    config::{BedMeshConfig, Config, MeshConfig, save_config_value},
+    executor::Executor,
+};
+use anyhow::{Context, Result, bail};
+use scherzo_core::bed_mesh::{BedMesh, MeshGrid};
+use serde::Serialize;
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// Progress of a running calibration
+#[derive(Debug, Clone, Serialize)]
+pub struct CalibrationStatus {
+    /// Index of the point waiting for a height
+    pub point: usize,
+    /// Number of points to probe
+    pub points: usize,
+    /// X/Y of the point waiting for a height
+    pub position: [f64; 2],
+    /// Heights reported so far, in probing order
+    pub heights: Vec<f64>,
+}
+
+/// Bed mesh state reported by the API
+#[derive(Debug, Clone, Serialize)]
+pub struct BedMeshStatus {
+    /// Mesh moves follow, if any
+    pub mesh: Option<MeshConfig>,
+    /// Difference (mm) between the highest and lowest points of the mesh
+    pub range: Option<f64>,
+    pub calibration: Option<CalibrationStatus>,
+}
+
+struct Calibration {
+    grid: MeshGrid,
+    points: Vec<[f64; 2]>,
+    heights: Vec<f64>,
+    /// Mesh to restore if the calibration is cancelled
+    previous: Option<BedMesh>,
+}
+
+impl Calibration {
+    fn status(&self) -> CalibrationStatus {
+        let point = self.heights.len();
+        CalibrationStatus {
+            point,
+            points: self.points.len(),
+            position: self.points[point],
+            heights: self.heights.clone(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    mesh: Option<BedMesh>,
+    calibration: Option<Calibration>,
+}
+
+struct Inner {
+    executor: Option<Arc<Executor>>,
+    config: Option<BedMeshConfig>,
+    saved_config: Option<PathBuf>,
+    state: Mutex<State>,
+}
+
+/// Runs bed mesh calibration and switches the active mesh
+#[derive(Clone)]
+pub struct BedMeshController {
+    inner: Arc<Inner>,
+}
+
+impl BedMeshController {
+    /// Start with the mesh saved in `config`, moving the machine through
+    /// `executor` if there is one
+    pub fn new(config: &Config, executor: Option<Arc<Executor>>) -> Result<Self> {
+        let mesh = config
+            .bed_mesh
+            .as_ref()
+            .and_then(|bed_mesh| bed_mesh.mesh.as_ref())
+            .map(BedMesh::try_from)
+            .transpose()
+            .context("invalid bed_mesh.mesh")?;
+        Ok(Self {
+            inner: Arc::new(Inner {
+                executor,
+                config: config.bed_mesh.clone(),
+                saved_config: config.saved_config.as_ref().map(PathBuf::from),
+                state: Mutex::new(State {
+                    mesh,
+                    calibration: None,
+                }),
+            }),
+        })
+    }
+
+    /// Start calibrating, moving to the first point. The active mesh is
+    /// switched off until the calibration finishes or is cancelled.
+    pub fn start_calibration(&self) -> Result<CalibrationStatus> {
+        let Some(config) = &self.inner.config else {
+            bail!("no [bed_mesh] is configured");
+        };
+        let executor = self.executor()?;
+        let grid = MeshGrid::new(config.mesh_min, config.mesh_max, config.probe_count)?;
+        let points = grid.probe_points();
+
+        let mut state = self.state();
+        let previous = match state.calibration.take() {
+            Some(calibration) => calibration.previous,
+            None => state.mesh.take(),
+        };
+        state.mesh = None;
+        executor.set_bed_mesh(None);
+
+        // Lift before travelling to the first point
+        let [x, y, z, _] = executor.position();
+        let z = z.max(config.horizontal_move_z);
+        executor.travel_to([x, y, z], config.speed)?;
+        let [x, y] = points[0];
+        executor.travel_to([x, y, z], config.speed)?;
+        executor.travel_to([x, y, config.horizontal_move_z], config.speed)?;
+
+        let calibration = Calibration {
+            grid,
+            points,
+            heights: Vec::new(),
+            previous,
+        };
+        let status = calibration.status();
+        state.calibration = Some(calibration);
+        tracing::info!("Bed mesh calibration started: {} points", status.points);
+        Ok(status)
+    }
+
+    /// Record the bed height (mm) at the current point and move to the next.
+    /// After the last point the mesh is activated and saved.
+    pub fn report_height(&self, height: f64) -> Result<BedMeshStatus> {
+        if !height.is_finite() {
+            bail!("bed height must be finite");
+        }
+        let executor = self.executor()?;
+        let mut state = self.state();
+        let Some(calibration) = &mut state.calibration else {
+            bail!("bed mesh calibration is not running");
+        };
+        calibration.heights.push(height);
+
+        if let Some(&[x, y]) = calibration.points.get(calibration.heights.len()) {
+            let config = self
+                .inner
+                .config
+                .as_ref()
+                .expect("calibrating without config");
+            executor.travel_to([x, y, config.horizontal_move_z], config.speed)?;
+            return Ok(self.status_of(&state));
+        }
+
+        let calibration = state.calibration.take().expect("calibration is running");
+        let mesh = calibration.grid.mesh(&calibration.heights)?;
+        tracing::info!("Bed mesh calibrated: range {:.3} mm", mesh.range());
+        executor.set_bed_mesh(Some(mesh.clone()));
+        state.mesh = Some(mesh.clone());
+        drop(state);
+
+        match &self.inner.saved_config {
+            Some(path) => {
+                let saved = toml::Value::try_from(MeshConfig::from(&mesh))?;
+                save_config_value(path, "bed_mesh.mesh", saved)
+                    .context("bed mesh is active but could not be saved")?;
+            }
+            None => tracing::warn!("No saved_config is configured; the bed mesh is not saved"),
+        }
+        Ok(self.status())
+    }
+
+    /// Stop calibrating and restore the mesh active before, returning false
+    /// if not calibrating
+    pub fn cancel_calibration(&self) -> bool {
+        let mut state = self.state();
+        let Some(calibration) = state.calibration.take() else {
+            return false;
+        };
+        if let Some(executor) = &self.inner.executor {
+            executor.set_bed_mesh(calibration.previous.clone());
+        }
+        state.mesh = calibration.previous;
+        true
+    }
+
+    /// Stop following the bed until the next calibration or restart
+    pub fn clear(&self) {
+        self.state().mesh = None;
+        if let Some(executor) = &self.inner.executor {
+            executor.set_bed_mesh(None);
+        }
+    }
+
+    pub fn status(&self) -> BedMeshStatus {
+        self.status_of(&self.state())
+    }
+
+    fn status_of(&self, state: &State) -> BedMeshStatus {
+        BedMeshStatus {
+            mesh: state.mesh.as_ref().map(Into::into),
+            range: state.mesh.as_ref().map(BedMesh::range),
+            calibration: state.calibration.as_ref().map(Calibration::status),
+        }
+    }
+
+    fn executor(&self) -> Result<&Executor> {
+        self.inner
+            .executor
+            .as_deref()
+            .context("no [printer] is configured")
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.inner
+            .state
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        executor::{HOST_CLOCK_FREQ, HostClock},
+        machine::Machine,
+    };
+
+    const PRINTER: &str = r#"
+[printer]
+kinematics = "cartesian"
+max_velocity = 300
+max_accel = 3000
+buffer_time_start = 0.05
+
+[stepper_x]
+rotation_distance = 40
+microsteps = 16
+position_max = 100
+
+[stepper_y]
+rotation_distance = 40
+microsteps = 16
+position_max = 100
+
+[stepper_z]
+rotation_distance = 8
+microsteps = 16
+position_max = 50
+
+[bed_mesh]
+mesh_min = [10, 10]
+mesh_max = [20, 20]
+probe_count = [2, 2]
+horizontal_move_z = 1
+speed = 200
+"#;
+
+    #[test]
+    fn test_calibration_activates_and_saves_mesh() {
+        let dir = tempfile::tempdir().unwrap();
+        let saved = dir.path().join("saved.toml");
+        let mut config = Config::from_toml(PRINTER).unwrap();
+        config.saved_config = Some(saved.display().to_string());
+        let machine = Machine::from_config(&config).unwrap().unwrap();
+        let machine = Arc::new(Mutex::new(machine));
+        let executor = Arc::new(Executor::spawn(
+            machine.clone(),
+            HostClock::new(HOST_CLOCK_FREQ),
+        ));
+        let bed_mesh = BedMeshController::new(&config, Some(executor.clone())).unwrap();
+
+        assert!(bed_mesh.report_height(0.0).is_err());
+        let status = bed_mesh.start_calibration().unwrap();
+        assert_eq!(status.points, 4);
+        assert_eq!(status.position, [10.0, 10.0]);
+        assert_eq!(executor.position()[..3], [10.0, 10.0, 1.0]);
+
+        for height in [0.0, 0.1, 0.3] {
+            bed_mesh.report_height(height).unwrap();
+        }
+        assert_eq!(executor.position()[..3], [10.0, 20.0, 1.0]);
+        let status = bed_mesh.report_height(0.2).unwrap();
+        assert!(status.calibration.is_none());
+        let mesh = status.mesh.unwrap();
+        assert_eq!(mesh.points, [vec![0.0, 0.1], vec![0.2, 0.3]]);
+        assert!(machine.lock().unwrap().compensation.bed_mesh().is_some());
+
+        // The next start loads the saved mesh
+        let saved: toml::Table = toml::from_str(&std::fs::read_to_string(&saved).unwrap()).unwrap();
+        let saved: MeshConfig = saved["bed_mesh"]["mesh"].clone().try_into().unwrap();
+        assert_eq!(saved, mesh);
+
+        // Moves now follow the bed
+        executor.move_to([20.0, 20.0, 0.2, 0.0], 200.0).unwrap();
+        let position = machine.lock().unwrap().toolhead.position();
+        assert!((position[2] - 0.5).abs() < 1e-9, "{position:?}");
+
+        bed_mesh.start_calibration().unwrap();
+        assert!(machine.lock().unwrap().compensation.bed_mesh().is_none());
+        assert!(bed_mesh.cancel_calibration());
+        assert!(machine.lock().unwrap().compensation.bed_mesh().is_some());
+        bed_mesh.clear();
+        assert!(bed_mesh.status().mesh.is_none());
+    }
+}
@@ -0,0 +1,164 @@
+//! Power loss recovery checkpoints
+//!
+//! While a job runs, how far it got is saved next to the job every
+//! `jobs.checkpoint_interval` seconds: the number of statements it submitted,
+//! the toolhead position, and the temperatures and fan speed it set. After a
+//! power loss the job can continue from its last checkpoint through a resume
+//! program generated by the compiler.
+
+use anyhow::{Context, Result};
+use scherzo_compile::{
+    resume::{ResumePoint, ThermalState},
+    source_map::SourceMap,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// How far a job got, saved while it runs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Statements the job had submitted
+    pub statement: usize,
+    /// Source line of the last statement submitted, if the job is G-code
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    /// Toolhead position (machine coordinates)
+    pub position: [f64; 4],
+    /// Extruder target temperature (°C)
+    pub extruder_temp: f64,
+    /// Bed target temperature (°C)
+    pub bed_temp: f64,
+    /// Part cooling fan speed, from 0 to 1
+    pub fan_speed: f64,
+    /// When the checkpoint was saved (RFC 3339)
+    pub saved_at: String,
+}
+
+impl Checkpoint {
+    /// Checkpoint a job that has submitted `submitted` statements and moved
+    /// the toolhead to `position`
+    pub fn capture(submitted: usize, source_map: &SourceMap, position: [f64; 4]) -> Self {
+        let entry = submitted
+            .checked_sub(1)
+            .and_then(|i| source_map.entries.get(i));
+        let thermal = entry.map(|entry| entry.thermal).unwrap_or_default();
+        Self {
+            statement: submitted,
+            line: entry.map(|entry| entry.line),
+            position,
+            extruder_temp: thermal.extruder_temp,
+            bed_temp: thermal.bed_temp,
+            fan_speed: thermal.fan_speed,
+            saved_at: chrono::Utc::now().to_rfc3339(),
+        }
+    }
+
+    /// Where to resume the job's program
+    pub fn resume_point(&self) -> ResumePoint {
+        ResumePoint {
+            statement: self.statement,
+            position: self.position,
+            thermal: ThermalState {
+                extruder_temp: self.extruder_temp,
+                bed_temp: self.bed_temp,
+                fan_speed: self.fan_speed,
+            },
+        }
+    }
+
+    /// Load the checkpoint at `path`, if one was saved
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let json = match fs::read(path) {
+            Ok(json) => json,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()));
+            }
+        };
+        let checkpoint = serde_json::from_slice(&json)
+            .with_context(|| format!("invalid checkpoint {}", path.display()))?;
+        Ok(Some(checkpoint))
+    }
+
+    /// Save to `path`, replacing any earlier checkpoint only once this one is
+    /// on disk
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        let json = serde_json::to_vec(self)?;
+        let mut file =
+            File::create(&tmp).with_context(|| format!("failed to create {}", tmp.display()))?;
+        file.write_all(&json)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path).with_context(|| format!("failed to save {}", path.display()))
+    }
+}
+
+/// Saves a running job's checkpoints at the configured interval
+#[derive(Debug)]
+pub struct CheckpointWriter {
+    path: PathBuf,
+    interval: Option<Duration>,
+    last_saved: Option<Instant>,
+}
+
+impl CheckpointWriter {
+    /// Save to `path` every `interval` seconds, or never if it is 0
+    pub fn new(path: PathBuf, interval: f64) -> Self {
+        Self {
+            path,
+            interval: (interval > 0.0).then(|| Duration::from_secs_f64(interval)),
+            last_saved: None,
+        }
+    }
+
+    /// Save the checkpoint made by `capture` if the interval has passed since
+    /// the last one, returning whether it was saved
+    pub fn save_if_due(&mut self, capture: impl FnOnce() -> Checkpoint) -> Result<bool> {
+        let Some(interval) = self.interval else {
+            return Ok(false);
+        };
+        if self
+            .last_saved
+            .is_some_and(|last| last.elapsed() < interval)
+        {
+            return Ok(false);
+        }
+        capture().save(&self.path)?;
+        self.last_saved = Some(Instant::now());
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_saves_checkpoints_at_interval() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("job.checkpoint.json");
+        assert_eq!(Checkpoint::load(&path).unwrap(), None);
+
+        let statements =
+            scherzo_gcode::parse("M104 S210\nM106\nG1 Z0.2 F600\nG1 X10 E1\n").unwrap();
+        let map = SourceMap::build(&statements);
+        let checkpoint = Checkpoint::capture(4, &map, [10.0, 0.0, 0.2, 1.0]);
+        assert_eq!(checkpoint.line, Some(4));
+        assert_eq!(checkpoint.extruder_temp, 210.0);
+        assert_eq!(checkpoint.fan_speed, 1.0);
+
+        let mut writer = CheckpointWriter::new(path.clone(), 3600.0);
+        assert!(writer.save_if_due(|| checkpoint.clone()).unwrap());
+        assert!(!writer.save_if_due(|| unreachable!()).unwrap());
+        assert_eq!(Checkpoint::load(&path).unwrap(), Some(checkpoint.clone()));
+        assert_eq!(checkpoint.resume_point().thermal.extruder_temp, 210.0);
+
+        let mut disabled = CheckpointWriter::new(dir.path().join("other.json"), 0.0);
+        assert!(!disabled.save_if_due(|| unreachable!()).unwrap());
+    }
+}
@@ -0,0 +1,69 @@
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use scherzo_compile::arc_fit::{ArcFitConfig, fit_arcs, to_gcode};
+use scherzo_gcode::parse;
+use std::{fs, path::PathBuf};
+
+#[derive(Args)]
+pub struct ArcFitArgs {
+    /// Path to the input G-code file.
+    pub input: PathBuf,
+
+    /// Path where the fitted G-code will be written.
+    ///
+    /// Defaults to stdout.
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Largest distance (mm) of the original path from the arcs and lines
+    /// replacing it.
+    #[arg(long, default_value_t = ArcFitConfig::default().tolerance)]
+    pub tolerance: f64,
+
+    /// Fewest segments replaced by one arc.
+    #[arg(long, default_value_t = ArcFitConfig::default().min_arc_segments)]
+    pub min_arc_segments: usize,
+
+    /// Largest arc radius (mm).
+    #[arg(long, default_value_t = ArcFitConfig::default().max_radius)]
+    pub max_radius: f64,
+}
+
+impl ArcFitArgs {
+    pub fn run(&self) -> Result<()> {
+        if self.tolerance <= 0.0 || self.max_radius <= 0.0 {
+            bail!("--tolerance and --max-radius must be positive");
+        }
+        let source = fs::read_to_string(&self.input)
+            .with_context(|| format!("failed to read input {}", self.input.display()))?;
+        let statements = parse(&source).context("failed to parse gcode")?;
+        let config = ArcFitConfig {
+            tolerance: self.tolerance,
+            min_arc_segments: self.min_arc_segments.max(2),
+            max_radius: self.max_radius,
+            ..ArcFitConfig::default()
+        };
+        let (fitted, stats) = fit_arcs(&statements, &config);
+        let text = to_gcode(&fitted);
+
+        match &self.output {
+            Some(output) => {
+                if let Some(parent) = output.parent() {
+                    fs::create_dir_all(parent).with_context(|| {
+                        format!("failed to create output directory {}", parent.display())
+                    })?;
+                }
+                fs::write(output, text)
+                    .with_context(|| format!("failed to write {}", output.display()))?;
+                eprintln!("Wrote {}", output.display());
+            }
+            None => print!("{text}"),
+        }
+        eprintln!(
+            "{} statements -> {} ({} arcs, {} lines)",
+            stats.statements_before, stats.statements_after, stats.arcs, stats.lines
+        );
+
+        Ok(())
+    }
+}
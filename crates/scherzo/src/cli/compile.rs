@@ -1,43 +1,398 @@
-use anyhow::{Context, Result};
-use clap::Args;
-use scherzo_compile::compile_gcode;
-use std::{fs, path::PathBuf};
+use anyhow::{Context, Result, bail};
+use clap::{Args, ValueEnum};
+use notify::{EventKind, RecursiveMode, Watcher};
+use scherzo_compile::{Compilation, CompileOptions, arc_fit::ArcFitConfig, compile_gcode_with};
+use std::{
+    collections::HashSet,
+    fs,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Artifacts that `compile` can write for each input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Emit {
+    /// WIT document describing the per-job host interface (`.wit`).
+    Wit,
+    /// Text format of the core module (`.wat`).
+    Wat,
+    /// Core wasm module before component encoding (`.core.wasm`).
+    Wasm,
+    /// Component with embedded WIT (`.wasm`).
+    Component,
+    /// JSON summary of verbs, parameter types, and sizes (`.json`).
+    Metadata,
+}
+
+impl Emit {
+    fn name(self) -> &'static str {
+        match self {
+            Emit::Wit => "WIT",
+            Emit::Wat => "WAT",
+            Emit::Wasm => "core module",
+            Emit::Component => "component",
+            Emit::Metadata => "metadata",
+        }
+    }
+
+    /// Path of this artifact given the component output path.
+    fn path(self, output: &Path) -> PathBuf {
+        let extension = match self {
+            Emit::Wit => "wit",
+            Emit::Wat => "wat",
+            Emit::Wasm => "core.wasm",
+            Emit::Component => return output.to_path_buf(),
+            Emit::Metadata => "json",
+        };
+        output.with_extension(extension)
+    }
+
+    fn render(self, compilation: &Compilation) -> Result<Vec<u8>> {
+        Ok(match self {
+            Emit::Wit => compilation.wit.clone().into_bytes(),
+            Emit::Wat => wasmprinter::print_bytes(&compilation.wasm)
+                .context("failed to print core module as WAT")?
+                .into_bytes(),
+            Emit::Wasm => compilation.wasm.clone(),
+            Emit::Component => compilation.component.clone(),
+            Emit::Metadata => {
+                let mut json = serde_json::to_string_pretty(&compilation.metadata_json())?;
+                json.push('\n');
+                json.into_bytes()
+            }
+        })
+    }
+}
 
 #[derive(Args)]
 pub struct CompileArgs {
-    /// Path to the input G-code file.
-    pub input: PathBuf,
+    /// Paths or glob patterns of the input G-code files.
+    #[arg(required = true)]
+    pub inputs: Vec<String>,
 
     /// Path where output artifacts will be written.
     ///
-    /// Defaults to the input file name with a `wasm` extension.
+    /// Defaults to the input file name with a `wasm` extension. When more than
+    /// one input is given this is a directory that receives one component per
+    /// input. Other `--emit` artifacts are written next to the component with
+    /// their own extension.
     #[arg(long)]
     pub output: Option<PathBuf>,
+
+    /// Comma-separated list of artifacts to write.
+    #[arg(long, value_delimiter = ',', default_value = "component")]
+    pub emit: Vec<Emit>,
+
+    /// Number of files to compile in parallel.
+    ///
+    /// Defaults to the number of available CPUs.
+    #[arg(short, long)]
+    pub jobs: Option<NonZeroUsize>,
+
+    /// Keep running and recompile inputs whenever they change.
+    #[arg(long)]
+    pub watch: bool,
+
+    /// Replace runs of short `G1` segments with arcs and lines that stay
+    /// within this distance (mm) of the original path.
+    #[arg(long, value_name = "MM")]
+    pub arc_tolerance: Option<f64>,
+
+    /// Treat lines that start with a coordinate, like `X10 Y5`, as repeating
+    /// the last `G0`-`G3`.
+    #[arg(long)]
+    pub modal_motion: bool,
+
+    /// Fail if the component would take more than this many bytes.
+    #[arg(long, value_name = "BYTES")]
+    pub max_component_size: Option<usize>,
+
+    /// Fail if the function making every command's host call would take
+    /// more than this many bytes.
+    #[arg(long, value_name = "BYTES")]
+    pub max_function_size: Option<usize>,
 }
 
 impl CompileArgs {
     pub fn run(&self) -> Result<()> {
-        let source = fs::read_to_string(&self.input)
-            .with_context(|| format!("failed to read input {}", self.input.display()))?;
-        let compilation = compile_gcode(&source)?;
-
-        let output = self.output.as_ref().cloned().unwrap_or_else(|| {
-            let mut default_output = self.input.clone();
-            default_output.set_extension("wasm");
-            default_output
+        if self.arc_tolerance.is_some_and(|tolerance| tolerance <= 0.0) {
+            bail!("--arc-tolerance must be positive");
+        }
+        let inputs = expand_inputs(&self.inputs)?;
+
+        if let [input] = inputs.as_slice()
+            && !self.watch
+        {
+            let output = self.output.clone().unwrap_or_else(|| default_output(input));
+            for (emit, path) in self.compile_file(input, &output)? {
+                println!("Wrote {} to {}", emit.name(), path.display());
+            }
+            return Ok(());
+        }
+
+        let outputs = match (inputs.as_slice(), &self.output) {
+            ([_], Some(output)) => vec![output.clone()],
+            (_, Some(dir)) => inputs
+                .iter()
+                .map(|input| dir.join(default_output(input).file_name().unwrap()))
+                .collect(),
+            (_, None) => inputs.iter().map(|input| default_output(input)).collect(),
+        };
+
+        let mut seen = HashSet::new();
+        for output in &outputs {
+            if !seen.insert(output.with_extension("")) {
+                bail!(
+                    "multiple inputs would be written to {}; compile them separately",
+                    output.display()
+                );
+            }
+        }
+
+        let failed = self.compile_batch(&inputs, &outputs);
+
+        if self.watch {
+            return self.watch(&inputs, &outputs);
+        }
+
+        if failed > 0 {
+            bail!("{failed} of {} file(s) failed to compile", inputs.len());
+        }
+
+        Ok(())
+    }
+
+    /// Compile `inputs` in parallel, printing a status line per file and a
+    /// summary. Returns the number of inputs that failed.
+    fn compile_batch(&self, inputs: &[PathBuf], outputs: &[PathBuf]) -> usize {
+        let jobs = self
+            .jobs
+            .or_else(|| thread::available_parallelism().ok())
+            .map_or(1, NonZeroUsize::get)
+            .min(inputs.len());
+
+        let start = Instant::now();
+        let next = AtomicUsize::new(0);
+        let failed = AtomicUsize::new(0);
+
+        thread::scope(|scope| {
+            for _ in 0..jobs {
+                scope.spawn(|| {
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(input) = inputs.get(index) else {
+                            break;
+                        };
+                        let output = &outputs[index];
+                        match self.compile_file(input, output) {
+                            Ok(written) => {
+                                let written = written
+                                    .iter()
+                                    .map(|(_, path)| path.display().to_string())
+                                    .collect::<Vec<_>>();
+                                println!("OK     {} -> {}", input.display(), written.join(", "))
+                            }
+                            Err(err) => {
+                                println!("FAILED {}: {err:#}", input.display());
+                                failed.fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                    }
+                });
+            }
         });
 
+        let failed = failed.into_inner();
+        println!(
+            "Compiled {} of {} file(s) in {:.2}s using {jobs} worker(s)",
+            inputs.len() - failed,
+            inputs.len(),
+            start.elapsed().as_secs_f64()
+        );
+        failed
+    }
+
+    /// Recompile inputs whenever they change on disk. Runs until interrupted.
+    fn watch(&self, inputs: &[PathBuf], outputs: &[PathBuf]) -> Result<()> {
+        // Editors often replace files rather than writing them in place, so
+        // watch the parent directories and match events against the inputs.
+        let watched = inputs
+            .iter()
+            .map(|input| {
+                fs::canonicalize(input)
+                    .with_context(|| format!("failed to resolve input {}", input.display()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let dirs = watched
+            .iter()
+            .filter_map(|path| path.parent())
+            .collect::<HashSet<_>>();
+
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx).context("failed to create watcher")?;
+        for dir in dirs {
+            watcher
+                .watch(dir, RecursiveMode::NonRecursive)
+                .with_context(|| format!("failed to watch {}", dir.display()))?;
+        }
+
+        println!("Watching {} file(s) for changes", inputs.len());
+
+        while let Ok(event) = rx.recv() {
+            let mut changed = HashSet::new();
+            collect_changes(event?, &watched, &mut changed);
+
+            // Let a burst of writes settle before recompiling
+            while let Ok(event) = rx.recv_timeout(WATCH_DEBOUNCE) {
+                collect_changes(event?, &watched, &mut changed);
+            }
+            if changed.is_empty() {
+                continue;
+            }
+
+            let mut changed: Vec<_> = changed.into_iter().collect();
+            changed.sort_unstable();
+            let inputs: Vec<_> = changed.iter().map(|&i| inputs[i].clone()).collect();
+            let outputs: Vec<_> = changed.iter().map(|&i| outputs[i].clone()).collect();
+
+            println!();
+            println!(
+                "[{}] change detected",
+                chrono::Local::now().format("%H:%M:%S")
+            );
+            self.compile_batch(&inputs, &outputs);
+        }
+
+        Ok(())
+    }
+
+    /// Compile one input and write every requested artifact, returning the
+    /// paths that were written.
+    fn compile_file(&self, input: &Path, output: &Path) -> Result<Vec<(Emit, PathBuf)>> {
+        let source = fs::read_to_string(input)
+            .with_context(|| format!("failed to read input {}", input.display()))?;
+        let options = CompileOptions {
+            arc_fit: self.arc_tolerance.map(|tolerance| ArcFitConfig {
+                tolerance,
+                ..ArcFitConfig::default()
+            }),
+            modal_motion: self.modal_motion,
+            max_component_size: self.max_component_size,
+            max_function_size: self.max_function_size,
+            ..CompileOptions::default()
+        };
+        let compilation = compile_gcode_with(&source, &options)?;
+
         if let Some(parent) = output.parent() {
             fs::create_dir_all(parent).with_context(|| {
                 format!("failed to create output directory {}", parent.display())
             })?;
         }
 
-        fs::write(&output, &compilation.component)
-            .with_context(|| format!("failed to write {}", output.display()))?;
+        let mut written: Vec<(Emit, PathBuf)> = Vec::new();
+        for &emit in &self.emit {
+            if written.iter().any(|(done, _)| *done == emit) {
+                continue;
+            }
+            let path = emit.path(output);
+            fs::write(&path, emit.render(&compilation)?)
+                .with_context(|| format!("failed to write {}", path.display()))?;
+            written.push((emit, path));
+        }
+
+        Ok(written)
+    }
+}
 
-        println!("Wrote component to {}", output.display());
+/// How long the watcher waits for further events before recompiling
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(100);
 
-        Ok(())
+/// Record which watched inputs a filesystem event touched
+fn collect_changes(event: notify::Event, watched: &[PathBuf], changed: &mut HashSet<usize>) {
+    if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+        return;
+    }
+    for path in &event.paths {
+        if let Some(index) = watched.iter().position(|watched| watched == path) {
+            changed.insert(index);
+        }
+    }
+}
+
+fn default_output(input: &Path) -> PathBuf {
+    let mut output = input.to_path_buf();
+    output.set_extension("wasm");
+    output
+}
+
+/// Expand glob patterns into a sorted, de-duplicated list of files. Plain paths
+/// are passed through untouched so a missing file still reports a read error.
+fn expand_inputs(patterns: &[String]) -> Result<Vec<PathBuf>> {
+    let mut inputs = Vec::new();
+    let mut seen = HashSet::new();
+
+    for pattern in patterns {
+        if !pattern.contains(['*', '?', '[']) {
+            if seen.insert(PathBuf::from(pattern)) {
+                inputs.push(PathBuf::from(pattern));
+            }
+            continue;
+        }
+
+        let mut matches = glob::glob(pattern)
+            .with_context(|| format!("invalid glob pattern `{pattern}`"))?
+            .filter_map(|entry| entry.ok())
+            .filter(|path| path.is_file())
+            .collect::<Vec<_>>();
+        if matches.is_empty() {
+            bail!("no files match `{pattern}`");
+        }
+        matches.sort();
+        for path in matches {
+            if seen.insert(path.clone()) {
+                inputs.push(path);
+            }
+        }
+    }
+
+    Ok(inputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_emit_paths() {
+        let output = Path::new("out/part.wasm");
+        assert_eq!(Emit::Component.path(output), output);
+        assert_eq!(Emit::Wasm.path(output), Path::new("out/part.core.wasm"));
+        assert_eq!(Emit::Wit.path(output), Path::new("out/part.wit"));
+        assert_eq!(Emit::Metadata.path(output), Path::new("out/part.json"));
+    }
+
+    #[test]
+    fn test_expand_inputs() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["b.gcode", "a.gcode", "notes.txt"] {
+            fs::write(dir.path().join(name), "G28\n").unwrap();
+        }
+
+        let pattern = dir.path().join("*.gcode").to_string_lossy().into_owned();
+        let explicit = dir.path().join("a.gcode").to_string_lossy().into_owned();
+        let inputs = expand_inputs(&[pattern.clone(), explicit]).unwrap();
+        assert_eq!(
+            inputs,
+            [dir.path().join("a.gcode"), dir.path().join("b.gcode")]
+        );
+
+        let missing = dir.path().join("*.gco").to_string_lossy().into_owned();
+        assert!(expand_inputs(&[missing]).is_err());
     }
 }
@@ -0,0 +1,123 @@
+use crate::{
+    config::{Config, TEMPLATE},
+    plugin::PluginManager,
+};
+use anyhow::{Context, Result, bail};
+use clap::{Args, Subcommand};
+use std::{fs, path::PathBuf};
+use wasmtime::{Config as WasmtimeConfig, Engine};
+
+#[derive(Args)]
+pub struct ConfigArgs {
+    #[command(subcommand)]
+    pub command: ConfigCommand,
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Write a commented configuration file with every default filled in.
+    Init(InitArgs),
+    /// Validate a configuration file, including plugin config sections.
+    Check(CheckArgs),
+}
+
+#[derive(Args)]
+pub struct InitArgs {
+    /// Path where the configuration file will be written.
+    #[arg(default_value = "scherzo.toml")]
+    pub output: PathBuf,
+
+    /// Overwrite the file if it already exists.
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(Args)]
+pub struct CheckArgs {
+    /// Path to the configuration file (TOML or JSON).
+    pub config: PathBuf,
+}
+
+impl ConfigArgs {
+    pub fn run(&self) -> Result<()> {
+        match &self.command {
+            ConfigCommand::Init(args) => args.run(),
+            ConfigCommand::Check(args) => args.run(),
+        }
+    }
+}
+
+impl InitArgs {
+    pub fn run(&self) -> Result<()> {
+        if self.output.exists() && !self.force {
+            bail!(
+                "{} already exists; pass --force to overwrite it",
+                self.output.display()
+            );
+        }
+
+        if let Some(parent) = self.output.parent() {
+            fs::create_dir_all(parent).with_context(|| {
+                format!("failed to create output directory {}", parent.display())
+            })?;
+        }
+
+        fs::write(&self.output, TEMPLATE)
+            .with_context(|| format!("failed to write {}", self.output.display()))?;
+
+        println!("Wrote configuration to {}", self.output.display());
+        Ok(())
+    }
+}
+
+impl CheckArgs {
+    pub fn run(&self) -> Result<()> {
+        let config = Config::from_file(&self.config)?;
+        config.validate()?;
+
+        let mut problems = Vec::new();
+
+        let mut wasmtime_config = WasmtimeConfig::new();
+        wasmtime_config.wasm_component_model(true);
+        let engine = Engine::new(&wasmtime_config).context("failed to create wasmtime engine")?;
+        let mut plugin_manager = PluginManager::new(engine);
+
+        for plugin_path in &config.plugins {
+            if let Err(err) = plugin_manager.load_plugin(plugin_path, "{}") {
+                problems.push(format!("plugins: {err:#}"));
+            }
+        }
+
+        let schemas = plugin_manager.registry().get_config_schemas();
+        for (namespace, value) in &config.plugin_config {
+            let Some(schema) = schemas.get(namespace) else {
+                println!(
+                    "warning: plugin_config.{namespace}: no loaded plugin registers a schema for this namespace"
+                );
+                continue;
+            };
+            let errors = schema
+                .validate(value)
+                .with_context(|| format!("plugin schema for `{namespace}` is invalid"))?;
+            problems.extend(
+                errors
+                    .into_iter()
+                    .map(|error| format!("plugin_config.{namespace}{error}")),
+            );
+        }
+
+        if !problems.is_empty() {
+            for problem in &problems {
+                println!("error: {problem}");
+            }
+            bail!(
+                "{} problem(s) found in {}",
+                problems.len(),
+                self.config.display()
+            );
+        }
+
+        println!("OK {}", self.config.display());
+        Ok(())
+    }
+}
@@ -0,0 +1,59 @@
+use anyhow::{Context, Result};
+use clap::Args;
+use scherzo_compile::{
+    decompile::{decompile, extract_wit},
+    describe::Description,
+};
+use std::{fs, path::PathBuf};
+
+#[derive(Args)]
+pub struct DecompileArgs {
+    /// Path to a compiled job component (or core module).
+    pub input: PathBuf,
+
+    /// Path where the recovered G-code will be written.
+    ///
+    /// Defaults to stdout.
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Dump the embedded WIT document instead of G-code.
+    #[arg(long)]
+    pub wit: bool,
+
+    /// Dump the job's interfaces and parameter kinds as JSON instead of
+    /// G-code.
+    #[arg(long, conflicts_with = "wit")]
+    pub describe: bool,
+}
+
+impl DecompileArgs {
+    pub fn run(&self) -> Result<()> {
+        let bytes = fs::read(&self.input)
+            .with_context(|| format!("failed to read input {}", self.input.display()))?;
+
+        let text = if self.wit {
+            extract_wit(&bytes)?
+        } else if self.describe {
+            serde_json::to_string_pretty(&Description::read(&bytes)?)? + "\n"
+        } else {
+            decompile(&bytes)?
+        };
+
+        match &self.output {
+            Some(output) => {
+                if let Some(parent) = output.parent() {
+                    fs::create_dir_all(parent).with_context(|| {
+                        format!("failed to create output directory {}", parent.display())
+                    })?;
+                }
+                fs::write(output, text)
+                    .with_context(|| format!("failed to write {}", output.display()))?;
+                eprintln!("Wrote {}", output.display());
+            }
+            None => print!("{text}"),
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+use anyhow::{Context, Result, bail};
+use clap::{Args, Subcommand};
+use scherzo_core::delta_calibrate::{self, DEFAULT_ANGLES, DeltaGeometry};
+use serde::Deserialize;
+use std::{fs, path::PathBuf};
+
+#[derive(Args)]
+pub struct DeltaCalibrateArgs {
+    #[command(subcommand)]
+    pub command: DeltaCalibrateCommand,
+}
+
+#[derive(Subcommand)]
+pub enum DeltaCalibrateCommand {
+    /// Print the X/Y points to probe.
+    Points(PointsArgs),
+    /// Fit the delta geometry to probed points and print the corrected values.
+    Solve(SolveArgs),
+}
+
+#[derive(Args)]
+pub struct PointsArgs {
+    /// Distance (mm) from the center the probe can reach on the bed.
+    #[arg(long)]
+    pub radius: f64,
+}
+
+#[derive(Args)]
+pub struct SolveArgs {
+    /// TOML file with the geometry the points were probed with and the
+    /// probed `samples` as `[x, y, z]`, with the probe's Z offset applied.
+    pub input: PathBuf,
+}
+
+/// Geometry and probed points read by `solve`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProbeFile {
+    delta_radius: f64,
+    /// Diagonal rod length, or one per tower
+    arm_length: ArmLength,
+    #[serde(default = "default_angles")]
+    angles: [f64; 3],
+    position_endstop: [f64; 3],
+    samples: Vec<[f64; 3]>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ArmLength {
+    All(f64),
+    PerTower([f64; 3]),
+}
+
+fn default_angles() -> [f64; 3] {
+    DEFAULT_ANGLES
+}
+
+impl DeltaCalibrateArgs {
+    pub fn run(&self) -> Result<()> {
+        match &self.command {
+            DeltaCalibrateCommand::Points(args) => args.run(),
+            DeltaCalibrateCommand::Solve(args) => args.run(),
+        }
+    }
+}
+
+impl PointsArgs {
+    pub fn run(&self) -> Result<()> {
+        if self.radius <= 0.0 {
+            bail!("--radius must be positive");
+        }
+        for [x, y] in delta_calibrate::probe_points(self.radius) {
+            println!("{x:.3}, {y:.3}");
+        }
+        Ok(())
+    }
+}
+
+impl SolveArgs {
+    pub fn run(&self) -> Result<()> {
+        let content = fs::read_to_string(&self.input)
+            .with_context(|| format!("failed to read {}", self.input.display()))?;
+        let file: ProbeFile = toml::from_str(&content)
+            .with_context(|| format!("failed to parse {}", self.input.display()))?;
+        let geometry = DeltaGeometry {
+            radius: file.delta_radius,
+            angles: file.angles,
+            arms: match file.arm_length {
+                ArmLength::All(arm) => [arm; 3],
+                ArmLength::PerTower(arms) => arms,
+            },
+            endstops: file.position_endstop,
+        };
+        if geometry.radius <= 0.0 || geometry.arms.iter().any(|&arm| arm <= geometry.radius) {
+            bail!("delta_radius must be positive and shorter than arm_length");
+        }
+
+        let calibration = delta_calibrate::calibrate(&geometry, &file.samples)?;
+        let fitted = calibration.geometry;
+        println!(
+            "Probed height RMS: {:.4} mm -> {:.4} mm ({} iterations)",
+            calibration.rms_before, calibration.rms_after, calibration.iterations
+        );
+        println!();
+        println!("[printer]");
+        println!("delta_radius = {:.6}", fitted.radius);
+        for (tower, i) in ["a", "b", "c"].into_iter().zip(0..) {
+            println!();
+            println!("[stepper_{tower}]");
+            println!("angle = {:.6}", fitted.angles[i]);
+            println!("position_endstop = {:.6}", fitted.endstops[i]);
+        }
+        Ok(())
+    }
+}
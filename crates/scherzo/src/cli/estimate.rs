@@ -0,0 +1,189 @@
+use crate::{config::FirmwareRetractionConfig, server::format_duration};
+use anyhow::{Context, Result};
+use clap::Args;
+use scherzo_compile::estimate::{MotionInterpreter, estimate_with};
+use scherzo_core::{planner::MachineLimits, retraction::RetractionConfig};
+use serde::Deserialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+#[derive(Args)]
+pub struct EstimateArgs {
+    /// Path to the input G-code file.
+    pub input: PathBuf,
+
+    /// Machine profile (TOML) with the printer's velocity and acceleration limits.
+    ///
+    /// Defaults to a generic 300mm/s, 3000mm/s^2 machine when omitted.
+    #[arg(long)]
+    pub profile: Option<PathBuf>,
+}
+
+/// Machine profile describing the limits used for estimation.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MachineProfile {
+    #[serde(default = "default_max_velocity")]
+    pub max_velocity: f64,
+    #[serde(default = "default_max_accel")]
+    pub max_accel: f64,
+    #[serde(default = "default_minimum_cruise_ratio")]
+    pub minimum_cruise_ratio: f64,
+    #[serde(default = "default_square_corner_velocity")]
+    pub square_corner_velocity: f64,
+    #[serde(default)]
+    pub max_travel_velocity: Option<f64>,
+    #[serde(default)]
+    pub max_travel_accel: Option<f64>,
+    #[serde(default = "default_max_extrude_only_velocity")]
+    pub max_extrude_only_velocity: f64,
+    #[serde(default = "default_max_extrude_only_accel")]
+    pub max_extrude_only_accel: f64,
+    /// Applied to `G10`/`G11`, which are ignored without it
+    #[serde(default)]
+    pub firmware_retraction: Option<FirmwareRetractionConfig>,
+}
+
+fn default_max_velocity() -> f64 {
+    MachineLimits::default().max_velocity
+}
+
+fn default_max_accel() -> f64 {
+    MachineLimits::default().max_accel
+}
+
+fn default_minimum_cruise_ratio() -> f64 {
+    MachineLimits::default().minimum_cruise_ratio
+}
+
+fn default_square_corner_velocity() -> f64 {
+    MachineLimits::default().square_corner_velocity
+}
+
+fn default_max_extrude_only_velocity() -> f64 {
+    MachineLimits::default().max_extrude_only_velocity
+}
+
+fn default_max_extrude_only_accel() -> f64 {
+    MachineLimits::default().max_extrude_only_accel
+}
+
+impl From<&MachineProfile> for MachineLimits {
+    fn from(profile: &MachineProfile) -> Self {
+        Self {
+            max_velocity: profile.max_velocity,
+            max_accel: profile.max_accel,
+            minimum_cruise_ratio: profile.minimum_cruise_ratio,
+            square_corner_velocity: profile.square_corner_velocity,
+            max_travel_velocity: profile.max_travel_velocity,
+            max_travel_accel: profile.max_travel_accel,
+            max_extrude_only_velocity: profile.max_extrude_only_velocity,
+            max_extrude_only_accel: profile.max_extrude_only_accel,
+        }
+    }
+}
+
+impl MachineProfile {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("failed to read profile {}", path.display()))?;
+        let profile: Self = toml::from_str(&content)
+            .with_context(|| format!("failed to parse profile {}", path.display()))?;
+        if profile.max_velocity <= 0.0 || profile.max_accel <= 0.0 {
+            anyhow::bail!("max_velocity and max_accel must be positive");
+        }
+        if profile.max_travel_velocity.is_some_and(|v| v <= 0.0)
+            || profile.max_travel_accel.is_some_and(|a| a <= 0.0)
+        {
+            anyhow::bail!("max_travel_velocity and max_travel_accel must be positive");
+        }
+        if profile.max_extrude_only_velocity <= 0.0 || profile.max_extrude_only_accel <= 0.0 {
+            anyhow::bail!("max_extrude_only_velocity and max_extrude_only_accel must be positive");
+        }
+        profile.retraction()?;
+        Ok(profile)
+    }
+
+    /// Load `path`, or the default profile if none was given
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        match path {
+            Some(path) => Self::from_file(path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn retraction(&self) -> Result<Option<RetractionConfig>> {
+        self.firmware_retraction
+            .as_ref()
+            .map(RetractionConfig::try_from)
+            .transpose()
+            .context("invalid firmware_retraction")
+    }
+}
+
+impl Default for MachineProfile {
+    fn default() -> Self {
+        Self {
+            max_velocity: default_max_velocity(),
+            max_accel: default_max_accel(),
+            minimum_cruise_ratio: default_minimum_cruise_ratio(),
+            square_corner_velocity: default_square_corner_velocity(),
+            max_travel_velocity: None,
+            max_travel_accel: None,
+            max_extrude_only_velocity: default_max_extrude_only_velocity(),
+            max_extrude_only_accel: default_max_extrude_only_accel(),
+            firmware_retraction: None,
+        }
+    }
+}
+
+impl EstimateArgs {
+    pub fn run(&self) -> Result<()> {
+        let source = fs::read_to_string(&self.input)
+            .with_context(|| format!("failed to read input {}", self.input.display()))?;
+        let profile = MachineProfile::load(self.profile.as_deref())?;
+        let mut interp = MotionInterpreter::new();
+        if let Some(retraction) = profile.retraction()? {
+            interp = interp.with_retraction(retraction);
+        }
+
+        let statements = scherzo_gcode::parse(&source).context("failed to parse gcode")?;
+        let estimate = estimate_with(&statements, &(&profile).into(), interp);
+
+        println!(
+            "Estimated time: {} ({:.1}s)",
+            format_duration(estimate.total_seconds),
+            estimate.total_seconds
+        );
+        println!("Filament used:  {:.1} mm", estimate.filament_mm);
+        println!("Moves planned:  {}", estimate.moves);
+        println!("Layers:         {}", estimate.layers.len());
+        if let Some(bbox) = estimate.bounding_box {
+            println!(
+                "Bounding box:   X {:.2}..{:.2}  Y {:.2}..{:.2}  Z {:.2}..{:.2}",
+                bbox.min[0], bbox.max[0], bbox.min[1], bbox.max[1], bbox.min[2], bbox.max[2]
+            );
+        }
+
+        if !estimate.layers.is_empty() {
+            println!();
+            println!(
+                "{:>6} {:>9} {:>12} {:>14}",
+                "layer", "z", "time", "filament"
+            );
+            for (idx, layer) in estimate.layers.iter().enumerate() {
+                println!(
+                    "{:>6} {:>9.3} {:>12} {:>11.1} mm",
+                    idx + 1,
+                    layer.z,
+                    format_duration(layer.seconds),
+                    layer.filament_mm
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+use crate::config::hash_password;
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use std::io::{self, BufRead};
+
+#[derive(Args)]
+pub struct HashPasswordArgs {
+    /// Read the password from the first line of stdin instead of prompting.
+    #[arg(long)]
+    pub stdin: bool,
+}
+
+impl HashPasswordArgs {
+    pub fn run(&self) -> Result<()> {
+        let password = if self.stdin {
+            let mut line = String::new();
+            io::stdin()
+                .lock()
+                .read_line(&mut line)
+                .context("failed to read password from stdin")?;
+            line.trim_end_matches(['\r', '\n']).to_string()
+        } else {
+            let password =
+                rpassword::prompt_password("Password: ").context("failed to read password")?;
+            let confirm = rpassword::prompt_password("Confirm password: ")
+                .context("failed to read password")?;
+            if password != confirm {
+                bail!("passwords do not match");
+            }
+            password
+        };
+
+        if password.is_empty() {
+            bail!("password cannot be empty");
+        }
+
+        let hash = hash_password(&password)?;
+        println!("password_hash = \"{hash}\"");
+
+        Ok(())
+    }
+}
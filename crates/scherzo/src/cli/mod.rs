@@ -1,2 +1,12 @@
+pub mod arc_fit;
 pub mod compile;
+pub mod config;
+pub mod decompile;
+pub mod delta_calibrate;
+pub mod estimate;
+pub mod hash_password;
+pub mod plugins;
+pub mod remote;
+pub mod simulate;
 pub mod start;
+pub mod validate;
@@ -0,0 +1,386 @@
+use crate::{
+    config::Config,
+    plugin::{PluginInfo, PluginManager},
+};
+use anyhow::{Context, Result, bail};
+use clap::{Args, Subcommand};
+use scherzo_compile::wasm_util;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use wasmtime::{Config as WasmtimeConfig, Engine};
+
+#[derive(Args)]
+pub struct PluginsArgs {
+    #[command(subcommand)]
+    pub command: PluginsCommand,
+}
+
+#[derive(Subcommand)]
+pub enum PluginsCommand {
+    /// List the plugins in a configuration file and check that each one loads.
+    List(ListArgs),
+    /// Show a plugin component's WIT, metadata, and registered schemas.
+    Inspect(InspectArgs),
+    /// Add a plugin to a configuration file.
+    Add(AddArgs),
+    /// Remove a plugin from a configuration file.
+    Remove(RemoveArgs),
+}
+
+#[derive(Args)]
+pub struct ListArgs {
+    /// Path to the configuration file (TOML or JSON).
+    pub config: PathBuf,
+}
+
+#[derive(Args)]
+pub struct InspectArgs {
+    /// Path to the plugin component.
+    pub plugin: PathBuf,
+}
+
+#[derive(Args)]
+pub struct AddArgs {
+    /// Path to the configuration file (TOML or JSON).
+    pub config: PathBuf,
+
+    /// Path to the plugin component, as it should appear in the config.
+    pub plugin: String,
+
+    /// Add the plugin without checking that it loads.
+    #[arg(long)]
+    pub no_verify: bool,
+}
+
+#[derive(Args)]
+pub struct RemoveArgs {
+    /// Path to the configuration file (TOML or JSON).
+    pub config: PathBuf,
+
+    /// Plugin path to remove, exactly as it appears in the config.
+    pub plugin: String,
+}
+
+impl PluginsArgs {
+    pub fn run(&self) -> Result<()> {
+        match &self.command {
+            PluginsCommand::List(args) => args.run(),
+            PluginsCommand::Inspect(args) => args.run(),
+            PluginsCommand::Add(args) => args.run(),
+            PluginsCommand::Remove(args) => args.run(),
+        }
+    }
+}
+
+impl ListArgs {
+    pub fn run(&self) -> Result<()> {
+        let config = Config::from_file(&self.config)?;
+        if config.plugins.is_empty() {
+            println!("No plugins configured in {}", self.config.display());
+            return Ok(());
+        }
+
+        let mut manager = plugin_manager()?;
+        let mut failed = 0usize;
+        for path in &config.plugins {
+            match manager.load_plugin(path, "{}") {
+                Ok(info) => println!("OK     {path}  {}", describe(&info)),
+                Err(err) => {
+                    failed += 1;
+                    println!("FAILED {path}  {err:#}");
+                }
+            }
+        }
+
+        if failed > 0 {
+            bail!(
+                "{failed} of {} plugin(s) failed to load",
+                config.plugins.len()
+            );
+        }
+        Ok(())
+    }
+}
+
+impl InspectArgs {
+    pub fn run(&self) -> Result<()> {
+        let bytes = fs::read(&self.plugin)
+            .with_context(|| format!("failed to read plugin {}", self.plugin.display()))?;
+        let report = wasm_util::inspect(&bytes)?;
+        if !report.component {
+            bail!("input is a core module, not a plugin component");
+        }
+        if let Some(wit) = &report.wit {
+            println!("{wit}");
+        }
+        print_items("Imports", &report.imports);
+        print_items("Exports", &report.exports);
+        println!("Sections ({} bytes):", report.size);
+        for section in &report.sections {
+            println!(
+                "  {:<24} {:>3} x {:>9} bytes",
+                section.name, section.count, section.size
+            );
+        }
+        println!();
+
+        let path = self.plugin.to_string_lossy();
+        let mut manager = plugin_manager()?;
+        let info = manager.load_plugin(&path, "{}")?;
+        println!("Plugin: {}", describe(&info));
+        if let Some(description) = &info.description {
+            println!("  {description}");
+        }
+
+        let registry = manager.registry();
+        let mut schemas: Vec<_> = registry.get_config_schemas().into_iter().collect();
+        schemas.sort_by(|a, b| a.0.cmp(&b.0));
+        for (namespace, schema) in schemas {
+            println!();
+            println!("Config schema `{namespace}`:");
+            if let Some(description) = &schema.description {
+                println!("  {description}");
+            }
+            println!("{}", pretty_json(&schema.json_schema));
+        }
+
+        let mut handlers: Vec<_> = registry.get_command_handlers().into_values().collect();
+        handlers.sort_by(|a, b| a.command.cmp(&b.command));
+        if !handlers.is_empty() {
+            println!();
+            println!("Command handlers:");
+        }
+        for handler in handlers {
+            let params: Vec<_> = handler.params.iter().map(|p| p.name.as_str()).collect();
+            println!(
+                "  {} [{}] ({})",
+                handler.command,
+                handler.scheduling_class,
+                params.join(", ")
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl AddArgs {
+    pub fn run(&self) -> Result<()> {
+        if !self.no_verify {
+            let info = plugin_manager()?.load_plugin(&self.plugin, "{}")?;
+            println!("Verified {}", describe(&info));
+        }
+
+        let added = edit_plugins(&self.config, |plugins| {
+            if plugins.iter().any(|p| p == &self.plugin) {
+                return false;
+            }
+            plugins.push(self.plugin.clone());
+            true
+        })?;
+
+        if added {
+            println!("Added {} to {}", self.plugin, self.config.display());
+        } else {
+            println!("{} is already in {}", self.plugin, self.config.display());
+        }
+        Ok(())
+    }
+}
+
+impl RemoveArgs {
+    pub fn run(&self) -> Result<()> {
+        let removed = edit_plugins(&self.config, |plugins| {
+            let before = plugins.len();
+            plugins.retain(|p| p != &self.plugin);
+            plugins.len() != before
+        })?;
+
+        if !removed {
+            bail!("{} is not in {}", self.plugin, self.config.display());
+        }
+        println!("Removed {} from {}", self.plugin, self.config.display());
+        Ok(())
+    }
+}
+
+fn plugin_manager() -> Result<PluginManager> {
+    let mut wasmtime_config = WasmtimeConfig::new();
+    wasmtime_config.wasm_component_model(true);
+    let engine = Engine::new(&wasmtime_config).context("failed to create wasmtime engine")?;
+    Ok(PluginManager::new(engine))
+}
+
+fn describe(info: &PluginInfo) -> String {
+    format!("{} {} ({})", info.name, info.version, info.id)
+}
+
+fn pretty_json(json: &str) -> String {
+    serde_json::from_str::<serde_json::Value>(json)
+        .and_then(|value| serde_json::to_string_pretty(&value))
+        .unwrap_or_else(|_| json.to_string())
+}
+
+fn print_items(heading: &str, items: &[wasm_util::Item]) {
+    println!("{heading}:");
+    for item in items {
+        if item.functions.is_empty() {
+            println!("  {}", item.name);
+        } else {
+            println!("  {} ({})", item.name, item.functions.join(", "));
+        }
+    }
+}
+
+/// Apply `edit` to the `plugins` list of a config file, preserving the rest of
+/// the document. Returns whatever `edit` returns; the file is only rewritten
+/// when it reports a change.
+fn edit_plugins(path: &Path, edit: impl FnOnce(&mut Vec<String>) -> bool) -> Result<bool> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("failed to read config file {}", path.display()))?;
+
+    let is_json = path.extension().is_some_and(|ext| ext == "json");
+    let (changed, updated) = if is_json {
+        edit_json(&content, edit)?
+    } else {
+        edit_toml(&content, edit)?
+    };
+
+    if changed {
+        fs::write(path, updated)
+            .with_context(|| format!("failed to write config file {}", path.display()))?;
+
+        // Make sure the result is still a loadable config (includes and all),
+        // putting the original back if it is not
+        if let Err(err) = Config::from_file(path) {
+            fs::write(path, content)
+                .with_context(|| format!("failed to restore config file {}", path.display()))?;
+            return Err(err);
+        }
+    }
+    Ok(changed)
+}
+
+fn edit_toml(content: &str, edit: impl FnOnce(&mut Vec<String>) -> bool) -> Result<(bool, String)> {
+    let mut doc: toml_edit::DocumentMut =
+        content.parse().context("failed to parse config as TOML")?;
+
+    let mut plugins = match doc.get("plugins") {
+        Some(item) => item
+            .as_array()
+            .context("`plugins` must be an array")?
+            .iter()
+            .map(|value| {
+                value
+                    .as_str()
+                    .map(str::to_string)
+                    .context("`plugins` entries must be strings")
+            })
+            .collect::<Result<Vec<_>>>()?,
+        None => Vec::new(),
+    };
+
+    if !edit(&mut plugins) {
+        return Ok((false, content.to_string()));
+    }
+
+    match doc.get_mut("plugins").and_then(|item| item.as_array_mut()) {
+        Some(array) => {
+            // Keep existing entries (and their decor) and only touch the delta
+            array.retain(|value| {
+                value
+                    .as_str()
+                    .is_some_and(|s| plugins.iter().any(|p| p == s))
+            });
+            for plugin in &plugins {
+                if !array.iter().any(|value| value.as_str() == Some(plugin)) {
+                    array.push(plugin.as_str());
+                }
+            }
+        }
+        None => {
+            let array: toml_edit::Array = plugins.iter().map(String::as_str).collect();
+            // Root keys must precede any table headers
+            doc.insert("plugins", toml_edit::value(array));
+        }
+    }
+
+    Ok((true, doc.to_string()))
+}
+
+fn edit_json(content: &str, edit: impl FnOnce(&mut Vec<String>) -> bool) -> Result<(bool, String)> {
+    let mut doc: serde_json::Value =
+        serde_json::from_str(content).context("failed to parse config as JSON")?;
+    let root = doc
+        .as_object_mut()
+        .context("config must be a JSON object")?;
+
+    let mut plugins: Vec<String> = match root.get("plugins") {
+        Some(value) => {
+            serde_json::from_value(value.clone()).context("`plugins` must be a list of strings")?
+        }
+        None => Vec::new(),
+    };
+
+    if !edit(&mut plugins) {
+        return Ok((false, content.to_string()));
+    }
+
+    root.insert("plugins".to_string(), serde_json::json!(plugins));
+    let mut out = serde_json::to_string_pretty(&doc)?;
+    out.push('\n');
+    Ok((true, out))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toml_edits_preserve_comments() {
+        let content = r#"# top comment
+plugins = [
+    # "/disabled.wasm",
+    "/a.wasm",
+]
+
+[jobs]
+storage_dir = "./jobs" # where jobs go
+"#;
+
+        let (changed, added) = edit_toml(content, |plugins| {
+            plugins.push("/b.wasm".to_string());
+            true
+        })
+        .unwrap();
+        assert!(changed);
+        assert!(added.contains("# top comment"));
+        assert!(added.contains("# where jobs go"));
+        let config = Config::from_toml(&added).unwrap();
+        assert_eq!(config.plugins, ["/a.wasm", "/b.wasm"]);
+
+        let (_, removed) = edit_toml(&added, |plugins| {
+            plugins.retain(|p| p != "/a.wasm");
+            true
+        })
+        .unwrap();
+        let config = Config::from_toml(&removed).unwrap();
+        assert_eq!(config.plugins, ["/b.wasm"]);
+        assert_eq!(config.jobs.storage_dir, "./jobs");
+    }
+
+    #[test]
+    fn toml_edit_adds_missing_plugins_key() {
+        let content = "[jobs]\nstorage_dir = \"./jobs\"\n";
+        let (_, updated) = edit_toml(content, |plugins| {
+            plugins.push("/a.wasm".to_string());
+            true
+        })
+        .unwrap();
+        let config = Config::from_toml(&updated).unwrap();
+        assert_eq!(config.plugins, ["/a.wasm"]);
+        assert_eq!(config.jobs.storage_dir, "./jobs");
+    }
+}
@@ -0,0 +1,226 @@
+use anyhow::{Context, Result};
+use clap::{Args, Subcommand};
+use scherzo_client::{Client, JobFormat, JobMetadata};
+use serde::Serialize;
+use serde_json::Value;
+use std::{fs, path::PathBuf};
+use uuid::Uuid;
+
+mod stress;
+
+#[derive(Args)]
+pub struct RemoteArgs {
+    /// Base URL of the running Scherzo server.
+    #[arg(long, env = "SCHERZO_URL", default_value = "http://127.0.0.1:3000")]
+    pub url: String,
+
+    /// API key sent as a bearer token (see `server.auth.api_keys`).
+    #[arg(long, env = "SCHERZO_API_KEY", hide_env_values = true)]
+    pub api_key: Option<String>,
+
+    /// Print the server's JSON responses instead of human-readable output.
+    #[arg(long, global = true)]
+    pub json: bool,
+
+    #[command(subcommand)]
+    pub command: RemoteCommand,
+}
+
+#[derive(Subcommand)]
+pub enum RemoteCommand {
+    /// Upload a G-code file or compiled component as a new job.
+    Upload(UploadArgs),
+    /// List the jobs stored on the server.
+    List,
+    /// Queue a job for execution.
+    Enqueue(EnqueueArgs),
+    /// Show the status of a job.
+    Status(JobArgs),
+    /// Pause an enqueued or running job.
+    Pause(JobArgs),
+    /// Cancel a job that has not finished yet.
+    Cancel(JobArgs),
+    /// Hammer the job API with concurrent requests and check its invariants.
+    Stress(stress::StressArgs),
+}
+
+#[derive(Args)]
+pub struct UploadArgs {
+    /// Path to a G-code file (.gcode, .gco, .g), an archive of G-code and
+    /// assets (.zip, .tar, .tar.gz), or a wasm component.
+    pub input: PathBuf,
+
+    /// Name to give the job after uploading it.
+    #[arg(long)]
+    pub name: Option<String>,
+
+    /// Enqueue the job once it has been uploaded.
+    #[arg(long)]
+    pub enqueue: bool,
+}
+
+#[derive(Args)]
+pub struct EnqueueArgs {
+    /// ID of the job.
+    pub id: Uuid,
+
+    /// Value for a template variable of the job; repeat for each variable.
+    /// Values are parsed as JSON, falling back to a string.
+    #[arg(long = "var", value_name = "NAME=VALUE", value_parser = parse_variable)]
+    pub variables: Vec<(String, Value)>,
+}
+
+#[derive(Args)]
+pub struct JobArgs {
+    /// ID of the job.
+    pub id: Uuid,
+}
+
+impl RemoteArgs {
+    #[tokio::main]
+    pub async fn run(&self) -> Result<()> {
+        let mut client = Client::new(&self.url);
+        if let Some(key) = &self.api_key {
+            client = client.with_api_key(key);
+        }
+        match &self.command {
+            RemoteCommand::Upload(args) => self.upload(&client, args).await,
+            RemoteCommand::List => {
+                let jobs = client.jobs().await?;
+                if self.json {
+                    return print_json(&jobs);
+                }
+                print_job_table(&jobs);
+                Ok(())
+            }
+            RemoteCommand::Enqueue(args) => {
+                let job = if args.variables.is_empty() {
+                    client.enqueue(args.id).await?
+                } else {
+                    let variables = args.variables.iter().cloned().collect();
+                    client.enqueue_with_variables(args.id, &variables).await?
+                };
+                self.print_job(job)
+            }
+            RemoteCommand::Status(args) => self.print_job(client.job(args.id).await?),
+            RemoteCommand::Pause(args) => self.print_job(client.pause(args.id).await?),
+            RemoteCommand::Cancel(args) => self.print_job(client.cancel(args.id).await?),
+            RemoteCommand::Stress(args) => args.run(&client, self.json).await,
+        }
+    }
+
+    async fn upload(&self, client: &Client, args: &UploadArgs) -> Result<()> {
+        let bytes = fs::read(&args.input)
+            .with_context(|| format!("failed to read input {}", args.input.display()))?;
+
+        let name = args
+            .input
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default();
+        let format = match name.rsplit_once('.').map(|(_, ext)| ext) {
+            Some("gcode" | "gco" | "g") => JobFormat::GCode,
+            Some("zip") => JobFormat::Zip,
+            Some("tar" | "tgz") => JobFormat::Tar,
+            Some("gz") if name.ends_with(".tar.gz") => JobFormat::Tar,
+            _ => JobFormat::Wasm,
+        };
+
+        let response = client.upload(bytes, format).await?;
+
+        let mut job = None;
+        if let Some(name) = &args.name {
+            job = Some(client.rename_job(response.job_id, name).await?);
+        }
+        if args.enqueue {
+            job = Some(client.enqueue(response.job_id).await?);
+        }
+
+        if self.json {
+            return match &job {
+                Some(job) => print_json(job),
+                None => print_json(&response),
+            };
+        }
+
+        match &response.compiled_from {
+            Some(format) => println!("Uploaded {} (compiled from {format})", response.job_id),
+            None => println!("Uploaded {}", response.job_id),
+        }
+        match job {
+            Some(job) => self.print_job(job),
+            None => Ok(()),
+        }
+    }
+
+    fn print_job(&self, job: JobMetadata) -> Result<()> {
+        if self.json {
+            return print_json(&job);
+        }
+        println!("ID:      {}", job.id);
+        println!("Name:    {}", job.name);
+        println!("Status:  {}", status_label(&job));
+        println!("Size:    {} bytes", job.size_bytes);
+        println!("Created: {}", job.created_at);
+        if let Some(format) = &job.original_format {
+            println!("Format:  {format}");
+        }
+        for variable in &job.template_variables {
+            let value = job
+                .template_values
+                .get(&variable.name)
+                .unwrap_or(&variable.default);
+            println!("Var:     {} = {value}", variable.name);
+        }
+        Ok(())
+    }
+}
+
+fn parse_variable(arg: &str) -> Result<(String, Value), String> {
+    let (name, value) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=VALUE, found `{arg}`"))?;
+    let value = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()));
+    Ok((name.to_string(), value))
+}
+
+fn print_json(value: &impl Serialize) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+fn status_label(job: &JobMetadata) -> String {
+    serde_json::to_value(job.status)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .unwrap_or_else(|| format!("{:?}", job.status))
+}
+
+fn print_job_table(jobs: &[JobMetadata]) {
+    if jobs.is_empty() {
+        println!("No jobs");
+        return;
+    }
+
+    let name_width = jobs
+        .iter()
+        .map(|job| job.name.len())
+        .max()
+        .unwrap_or(0)
+        .max("NAME".len());
+
+    println!(
+        "{:<36}  {:<name_width$}  {:<9}  {:>10}  CREATED",
+        "ID", "NAME", "STATUS", "SIZE"
+    );
+    for job in jobs {
+        println!(
+            "{:<36}  {:<name_width$}  {:<9}  {:>10}  {}",
+            job.id,
+            job.name,
+            status_label(job),
+            job.size_bytes,
+            job.created_at
+        );
+    }
+}
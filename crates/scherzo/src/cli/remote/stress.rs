@@ -0,0 +1,408 @@
+//! Concurrent API torture test
+//!
+//! Workers hammer the job endpoints with random uploads, renames, deletes,
+//! enqueues and queue reorders. Each request either succeeds or is rejected
+//! with a 4xx the race explains (the job was just deleted, the queue changed
+//! underneath a reorder); a 5xx or a dropped connection, which is what a
+//! panicking handler or a poisoned lock looks like from outside, is a
+//! failure. Afterwards the job list, each job and the queue are checked
+//! against each other and against what the workers saw succeed.
+
+use anyhow::{Result, bail};
+use clap::Args;
+use futures::future;
+use scherzo_client::{Client, JobFormat, JobMetadata, JobStatus};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Mutex,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use uuid::Uuid;
+
+#[derive(Args)]
+pub struct StressArgs {
+    /// Concurrent workers.
+    #[arg(long, default_value_t = 8)]
+    pub workers: usize,
+
+    /// Requests sent by each worker.
+    #[arg(long, default_value_t = 200)]
+    pub operations: usize,
+
+    /// Seed for the random operation mix; defaults to the current time.
+    #[arg(long)]
+    pub seed: Option<u64>,
+}
+
+/// What to send and how much of it
+#[derive(Debug, Clone, Copy)]
+struct StressOptions {
+    workers: usize,
+    operations: usize,
+    seed: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Operation {
+    Upload,
+    Rename,
+    Delete,
+    Enqueue,
+    Get,
+    List,
+    Queue,
+    Reorder,
+}
+
+impl Operation {
+    /// Relative weights of the operation mix
+    const MIX: [(Operation, u64); 8] = [
+        (Operation::Upload, 25),
+        (Operation::Rename, 15),
+        (Operation::Delete, 15),
+        (Operation::Enqueue, 20),
+        (Operation::Get, 10),
+        (Operation::List, 5),
+        (Operation::Queue, 5),
+        (Operation::Reorder, 5),
+    ];
+
+    fn pick(rng: &mut Rng) -> Self {
+        let total: u64 = Self::MIX.iter().map(|(_, weight)| weight).sum();
+        let mut roll = rng.below(total);
+        for (operation, weight) in Self::MIX {
+            if roll < weight {
+                return operation;
+            }
+            roll -= weight;
+        }
+        unreachable!("roll is below the total weight")
+    }
+}
+
+/// Responses to one kind of operation
+#[derive(Debug, Clone, Default, Serialize)]
+struct OperationCounts {
+    ok: u64,
+    /// 4xx responses, expected when requests race
+    rejected: u64,
+    /// 5xx responses and failed connections
+    failed: u64,
+}
+
+/// Outcome of a stress run
+#[derive(Debug, Default, Serialize)]
+struct StressReport {
+    seed: u64,
+    seconds: f64,
+    operations: BTreeMap<Operation, OperationCounts>,
+    /// Server errors and dropped connections, with the request that got them
+    failures: Vec<String>,
+    /// Invariants that did not hold once the workers were done
+    violations: Vec<String>,
+}
+
+impl StressReport {
+    fn passed(&self) -> bool {
+        self.failures.is_empty() && self.violations.is_empty()
+    }
+}
+
+/// What the workers learned about the jobs while running
+#[derive(Default)]
+struct Shared {
+    /// Jobs uploaded and not yet deleted, as far as the workers know
+    live: Vec<Uuid>,
+    uploaded: HashSet<Uuid>,
+    deleted: HashSet<Uuid>,
+}
+
+/// Small xorshift generator, so runs can be repeated from their seed
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // Xorshift gets stuck at zero
+        Self(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n.max(1)
+    }
+}
+
+impl StressArgs {
+    pub async fn run(&self, client: &Client, json: bool) -> Result<()> {
+        let seed = self.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+        });
+        let options = StressOptions {
+            workers: self.workers.max(1),
+            operations: self.operations,
+            seed,
+        };
+        let report = stress(client, &options).await?;
+
+        if json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            print_report(&report);
+        }
+        if !report.passed() {
+            bail!(
+                "{} failed requests and {} invariant violations (seed {seed})",
+                report.failures.len(),
+                report.violations.len()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Run `options.workers` workers against the server behind `client`, then
+/// check the server's state
+async fn stress(client: &Client, options: &StressOptions) -> Result<StressReport> {
+    let started = Instant::now();
+    let shared = Mutex::new(Shared::default());
+    let report = Mutex::new(StressReport {
+        seed: options.seed,
+        ..StressReport::default()
+    });
+
+    let workers = (0..options.workers).map(|worker| {
+        let (shared, report) = (&shared, &report);
+        let mut rng = Rng::new(options.seed.wrapping_add(worker as u64));
+        async move {
+            for _ in 0..options.operations {
+                let operation = Operation::pick(&mut rng);
+                let (path, result) = run_operation(client, operation, &mut rng, shared).await;
+                let mut report = report.lock().unwrap_or_else(|err| err.into_inner());
+                let counts = report.operations.entry(operation).or_default();
+                match result {
+                    Ok(()) => counts.ok += 1,
+                    Err(e) if e.status().is_some_and(|status| status.is_client_error()) => {
+                        counts.rejected += 1
+                    }
+                    Err(e) => {
+                        counts.failed += 1;
+                        report.failures.push(format!("{path}: {e}"));
+                    }
+                }
+            }
+        }
+    });
+    future::join_all(workers).await;
+
+    let mut report = report.into_inner().unwrap_or_else(|err| err.into_inner());
+    let shared = shared.into_inner().unwrap_or_else(|err| err.into_inner());
+    report.violations = check_invariants(client, &shared).await?;
+    report.seconds = started.elapsed().as_secs_f64();
+    Ok(report)
+}
+
+/// Send one request, returning its path and whether it succeeded
+async fn run_operation(
+    client: &Client,
+    operation: Operation,
+    rng: &mut Rng,
+    shared: &Mutex<Shared>,
+) -> (String, Result<(), scherzo_client::Error>) {
+    let target = {
+        let shared = shared.lock().unwrap_or_else(|err| err.into_inner());
+        (!shared.live.is_empty()).then(|| shared.live[rng.below(shared.live.len() as u64) as usize])
+    };
+    // Operations on a job fall back to an unknown one, which must be a 404
+    let id = target.unwrap_or_else(Uuid::new_v4);
+
+    match operation {
+        Operation::Upload => {
+            let gcode = format!("G1 X{} F600\nG1 Y{}\n", rng.below(200), rng.below(200));
+            let result = client
+                .upload(gcode.into_bytes(), JobFormat::GCode)
+                .await
+                .map(|upload| {
+                    let mut shared = shared.lock().unwrap_or_else(|err| err.into_inner());
+                    shared.live.push(upload.job_id);
+                    shared.uploaded.insert(upload.job_id);
+                });
+            ("/jobs".to_string(), result)
+        }
+        Operation::Rename => {
+            let name = format!("stress-{}", rng.next());
+            let result = client.rename_job(id, &name).await.map(drop);
+            (format!("/jobs/{id}/rename"), result)
+        }
+        Operation::Delete => {
+            let result = client.delete_job(id).await.map(|_| {
+                let mut shared = shared.lock().unwrap_or_else(|err| err.into_inner());
+                shared.live.retain(|job| *job != id);
+                shared.deleted.insert(id);
+            });
+            (format!("/jobs/{id}"), result)
+        }
+        Operation::Enqueue => {
+            let result = client.enqueue(id).await.map(drop);
+            (format!("/jobs/{id}/enqueue"), result)
+        }
+        Operation::Get => (format!("/jobs/{id}"), client.job(id).await.map(drop)),
+        Operation::List => ("/jobs".to_string(), client.jobs().await.map(drop)),
+        Operation::Queue => ("/queue".to_string(), client.queue().await.map(drop)),
+        Operation::Reorder => {
+            let result = match client.queue().await {
+                Ok(queue) => {
+                    let mut jobs = queue.jobs;
+                    jobs.reverse();
+                    client.reorder_queue(&jobs).await.map(drop)
+                }
+                Err(e) => Err(e),
+            };
+            ("/queue".to_string(), result)
+        }
+    }
+}
+
+/// Compare the job list, each job and the queue with each other and with
+/// what the workers saw succeed
+async fn check_invariants(client: &Client, shared: &Shared) -> Result<Vec<String>> {
+    let mut violations = Vec::new();
+    let jobs = client.jobs().await?;
+    let listed: HashMap<Uuid, &JobMetadata> = jobs.iter().map(|job| (job.id, job)).collect();
+    if listed.len() != jobs.len() {
+        violations.push("the job list has duplicate IDs".to_string());
+    }
+
+    for id in &shared.deleted {
+        if listed.contains_key(id) {
+            violations.push(format!("deleted job {id} is still listed"));
+        }
+    }
+    for id in shared.uploaded.difference(&shared.deleted) {
+        if !listed.contains_key(id) {
+            violations.push(format!("uploaded job {id} is missing from the list"));
+        }
+    }
+    for job in &jobs {
+        match client.job(job.id).await {
+            Ok(fetched) => {
+                if fetched.name != job.name || fetched.status != job.status {
+                    violations.push(format!("job {} differs from its list entry", job.id));
+                }
+            }
+            Err(e) => violations.push(format!("listed job {} cannot be fetched: {e}", job.id)),
+        }
+    }
+
+    let queued = client.queue().await?.jobs;
+    let queued_set: HashSet<_> = queued.iter().collect();
+    if queued_set.len() != queued.len() {
+        violations.push("a job is queued more than once".to_string());
+    }
+    for id in &queued {
+        match listed.get(id) {
+            None => violations.push(format!("queued job {id} does not exist")),
+            Some(job) if job.status != JobStatus::Enqueued => {
+                violations.push(format!("queued job {id} is {:?}, not enqueued", job.status))
+            }
+            Some(_) => {}
+        }
+    }
+    for job in &jobs {
+        if job.status == JobStatus::Enqueued && !queued_set.contains(&job.id) {
+            violations.push(format!("enqueued job {} is not in the queue", job.id));
+        }
+    }
+    Ok(violations)
+}
+
+fn print_report(report: &StressReport) {
+    println!(
+        "Sent {} requests in {:.1}s (seed {})",
+        report
+            .operations
+            .values()
+            .map(|counts| counts.ok + counts.rejected + counts.failed)
+            .sum::<u64>(),
+        report.seconds,
+        report.seed
+    );
+    println!(
+        "{:<10}  {:>6}  {:>8}  {:>6}",
+        "OPERATION", "OK", "REJECTED", "FAILED"
+    );
+    for (operation, counts) in &report.operations {
+        let name = serde_json::to_value(operation)
+            .ok()
+            .and_then(|value| value.as_str().map(str::to_string))
+            .unwrap_or_default();
+        println!(
+            "{name:<10}  {:>6}  {:>8}  {:>6}",
+            counts.ok, counts.rejected, counts.failed
+        );
+    }
+    for failure in &report.failures {
+        println!("FAILED {failure}");
+    }
+    for violation in &report.violations {
+        println!("VIOLATION {violation}");
+    }
+    if report.passed() {
+        println!("All invariants held");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bed_mesh::BedMeshController, config::Config, console::Console, crash::CrashReporter,
+        input_shaper::InputShaperController, pause::PauseController, plugin::PluginRegistry,
+        server::AppState,
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_stress_keeps_job_store_and_queue_consistent() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = format!("[jobs]\nstorage_dir = {:?}\n", dir.path());
+        let config = Config::from_toml(&config).unwrap();
+        let pause = PauseController::new(&config, None);
+        let bed_mesh = BedMeshController::new(&config, None).unwrap();
+        let input_shaper = InputShaperController::new(&config, None);
+        let console = Console::new(&config, None, PluginRegistry::new());
+        let crash = CrashReporter::new(&config, PluginRegistry::new());
+        let state =
+            AppState::new(config, None, pause, bed_mesh, input_shaper, console, crash).unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = crate::server::create_router(state);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = Client::new(&format!("http://{addr}"));
+        let options = StressOptions {
+            workers: 6,
+            operations: 60,
+            seed: 7,
+        };
+        let report = stress(&client, &options).await.unwrap();
+        assert!(report.passed(), "{report:#?}");
+        let sent: u64 = report
+            .operations
+            .values()
+            .map(|counts| counts.ok + counts.rejected)
+            .sum();
+        assert_eq!(sent, 360);
+        assert!(report.operations[&Operation::Upload].ok > 0);
+        assert!(report.operations[&Operation::Delete].ok > 0);
+    }
+}
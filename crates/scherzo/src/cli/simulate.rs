@@ -0,0 +1,187 @@
+use crate::{cli::estimate::MachineProfile, server::format_duration};
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use scherzo_compile::{
+    compile_gcode,
+    simulate::{Kinematics, Simulation, SimulationConfig, simulate_gcode},
+};
+use scherzo_core::{
+    planner::MachineLimits,
+    step_compressor::{Command, CommandSink},
+};
+use scherzo_mcu::{
+    steps::StepSink,
+    virtual_mcu::{Loopback, VirtualMcu},
+};
+use std::{
+    fs,
+    io::{self, BufWriter},
+    path::PathBuf,
+};
+
+#[derive(Args)]
+pub struct SimulateArgs {
+    /// Path to the input G-code file.
+    pub input: PathBuf,
+
+    /// Printer kinematics (cartesian, corexy, or corexz).
+    #[arg(long, default_value = "cartesian")]
+    pub kinematics: String,
+
+    /// Steps per mm, either one value for every stepper or four
+    /// comma-separated values for X,Y,Z,E (A,B,Z,E for core kinematics).
+    #[arg(long, value_delimiter = ',', default_value = "80,80,400,100")]
+    pub steps_per_mm: Vec<f64>,
+
+    /// MCU clock frequency in Hz.
+    #[arg(long, default_value_t = 16_000_000.0)]
+    pub mcu_freq: f64,
+
+    /// Machine profile (TOML) with the printer's velocity and acceleration limits.
+    #[arg(long)]
+    pub profile: Option<PathBuf>,
+
+    /// Path where the CSV step trace will be written.
+    ///
+    /// Defaults to stdout.
+    #[arg(long)]
+    pub output: Option<PathBuf>,
+
+    /// Replay the encoded step commands through a virtual MCU and report
+    /// what it executed.
+    #[arg(long)]
+    pub virtual_mcu: bool,
+
+    /// Fail if any stepper exceeds this step rate (steps/s) on the virtual
+    /// MCU. Implies --virtual-mcu.
+    #[arg(long, value_name = "HZ")]
+    pub max_step_rate: Option<f64>,
+}
+
+impl SimulateArgs {
+    pub fn run(&self) -> Result<()> {
+        let source = fs::read_to_string(&self.input)
+            .with_context(|| format!("failed to read input {}", self.input.display()))?;
+        let profile = MachineProfile::load(self.profile.as_deref())?;
+        let limits = MachineLimits::from(&profile);
+        let config = SimulationConfig {
+            retraction: profile.retraction()?,
+            ..self.config()?
+        };
+
+        // Make sure the job would actually be accepted before simulating it
+        compile_gcode(&source).context("job failed to compile")?;
+        let simulation = simulate_gcode(&source, &limits, &config)?;
+
+        match &self.output {
+            Some(path) => {
+                let file = fs::File::create(path)
+                    .with_context(|| format!("failed to create {}", path.display()))?;
+                simulation
+                    .write_csv(BufWriter::new(file))
+                    .with_context(|| format!("failed to write {}", path.display()))?;
+                eprintln!("Wrote trace to {}", path.display());
+            }
+            None => simulation
+                .write_csv(BufWriter::new(io::stdout().lock()))
+                .context("failed to write trace")?,
+        }
+
+        eprintln!(
+            "Simulated print time: {} ({:.3}s)",
+            format_duration(simulation.print_time),
+            simulation.print_time
+        );
+        for (stepper, steps) in &simulation.steps {
+            eprintln!("{stepper:>10}: {steps} steps");
+        }
+
+        if self.virtual_mcu || self.max_step_rate.is_some() {
+            self.run_virtual_mcu(&simulation)?;
+        }
+
+        Ok(())
+    }
+
+    /// Send the simulation's step commands to a virtual MCU over the real
+    /// protocol and check what it executed.
+    fn run_virtual_mcu(&self, simulation: &Simulation) -> Result<()> {
+        const CONFIG_STEPPER: &str =
+            "config_stepper oid=%c step_pin=%c dir_pin=%c invert_step=%c step_pulse_ticks=%u";
+
+        let mut link = Loopback::new(VirtualMcu::new(self.mcu_freq));
+        let dictionary = link.mcu.dictionary().clone();
+        let mut queues = Vec::new();
+        for oid in 0..simulation.steps.len() as u32 {
+            let (step_pin, dir_pin) = (2 * oid, 2 * oid + 1);
+            link.send(
+                CONFIG_STEPPER,
+                &[
+                    oid.into(),
+                    step_pin.into(),
+                    dir_pin.into(),
+                    0u32.into(),
+                    0u32.into(),
+                ],
+            )?;
+            queues.push(link.host.alloc_command_queue());
+        }
+        link.run_until(0.0);
+
+        for record in &simulation.trace {
+            let oid = match &record.command {
+                Command::QueueStep(step) => step.oid,
+                Command::SetNextStepDir(dir) => dir.oid,
+            };
+            let queue = *queues
+                .get(oid as usize)
+                .with_context(|| format!("no stepper with oid {oid}"))?;
+            StepSink::new(&dictionary, &mut link.host, queue)?.push(record.command.clone());
+        }
+        link.run_until(simulation.print_time + 0.5);
+
+        if let Some(reason) = link.mcu.shutdown_reason() {
+            bail!("virtual MCU shut down: {reason}");
+        }
+        eprintln!("Virtual MCU:");
+        let mut too_fast = Vec::new();
+        for (oid, (stepper, _)) in simulation.steps.iter().enumerate() {
+            let oid = oid as u8;
+            let position = link.mcu.position(oid).unwrap_or_default();
+            let rate = link.mcu.max_step_rate(oid).unwrap_or_default();
+            eprintln!("{stepper:>10}: position {position} steps, max {rate:.0} steps/s");
+            if self.max_step_rate.is_some_and(|max| rate > max) {
+                too_fast.push(*stepper);
+            }
+        }
+        if !too_fast.is_empty() {
+            bail!(
+                "step rate limit of {:.0} steps/s exceeded by {}",
+                self.max_step_rate.unwrap_or_default(),
+                too_fast.join(", ")
+            );
+        }
+
+        Ok(())
+    }
+
+    fn config(&self) -> Result<SimulationConfig> {
+        let Some(kinematics) = Kinematics::parse(&self.kinematics) else {
+            bail!("unsupported kinematics `{}`", self.kinematics);
+        };
+        let steps_per_mm = match self.steps_per_mm.as_slice() {
+            [all] => [*all; 4],
+            [x, y, z, e] => [*x, *y, *z, *e],
+            _ => bail!("--steps-per-mm takes either 1 or 4 values"),
+        };
+        if steps_per_mm.iter().any(|s| *s <= 0.0) || self.mcu_freq <= 0.0 {
+            bail!("steps per mm and MCU frequency must be positive");
+        }
+        Ok(SimulationConfig {
+            kinematics,
+            steps_per_mm,
+            mcu_freq: self.mcu_freq,
+            ..SimulationConfig::default()
+        })
+    }
+}
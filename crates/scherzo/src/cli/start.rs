@@ -1,13 +1,37 @@
-use crate::{config::Config, plugin::PluginManager};
+use crate::{
+    bed_mesh::BedMeshController,
+    config::{Config, McuConfig},
+    console::Console,
+    crash::{CrashReporter, FaultKind, MotionSnapshot},
+    executor::{Executor, HOST_CLOCK_FREQ, HostClock},
+    input_shaper::InputShaperController,
+    machine::Machine,
+    pause::PauseController,
+    plugin::PluginManager,
+    telemetry,
+};
 use anyhow::{Context, Result};
 use clap::Args;
-use std::path::PathBuf;
+use scherzo_mcu::{
+    mcu::Mcu,
+    virtual_mcu::{self, VirtualMcu},
+};
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
 use wasmtime::{
     Config as WasmtimeConfig, Engine,
     component::{Linker, ResourceTable},
 };
 use wasmtime_wasi::{WasiCtx, WasiCtxView, WasiView};
 
+/// `[mcu]` `serial` value that selects the in-process virtual MCU
+const VIRTUAL_MCU: &str = "virtual";
+
+/// Clock frequency (Hz) of the virtual MCU
+const VIRTUAL_MCU_FREQ: f64 = 16_000_000.0;
+
 #[derive(Args)]
 pub struct StartArgs {
     /// Path to the configuration file (TOML or JSON).
@@ -31,13 +55,13 @@ impl WasiView for JobState {
 
 impl StartArgs {
     pub fn run(&self) -> Result<()> {
-        // Initialize tracing
-        tracing_subscriber::fmt::init();
-
         // Load and parse the config file
         let config = Config::from_file(&self.config)?;
         config.validate()?;
 
+        // Initialize tracing, exporting spans until the server stops
+        let _telemetry = telemetry::init(&config.telemetry)?;
+
         tracing::info!("Starting scherzo with config: {}", self.config.display());
         tracing::info!(
             "Server will bind to {}:{}",
@@ -52,8 +76,85 @@ impl StartArgs {
 
         let engine = Engine::new(&wasmtime_config).context("failed to create wasmtime engine")?;
 
+        // Create print job environment
+        let _job_linker = create_job_linker(&engine)?;
+
+        // Assemble the motion system from the printer and stepper sections
+        let machine = Machine::from_config(&config)?;
+        match &machine {
+            Some(machine) => {
+                tracing::info!(
+                    "Printer: {} kinematics, max_velocity={} max_accel={}",
+                    machine.kinematics.name(),
+                    machine.limits.max_velocity,
+                    machine.limits.max_accel
+                );
+                for rail in &machine.rails {
+                    tracing::info!(
+                        "Rail {}: {}..{} mm, {} mm/step",
+                        rail.name,
+                        rail.position_min,
+                        rail.position_max,
+                        rail.step_distance
+                    );
+                }
+            }
+            None => tracing::warn!("No [printer] section configured; motion is disabled"),
+        }
+
+        // Connect to the MCU and fetch its data dictionary
+        let mcu = match &config.mcu {
+            Some(mcu_config) => {
+                let mcu = connect_mcu(mcu_config).with_context(|| {
+                    format!("failed to connect to MCU on {}", mcu_config.serial)
+                })?;
+                let dictionary = mcu.dictionary();
+                tracing::info!(
+                    "Connected to MCU {} on {}: {} at {} Hz",
+                    dictionary
+                        .config
+                        .get("MCU")
+                        .and_then(|mcu| mcu.as_str())
+                        .unwrap_or("unknown"),
+                    mcu_config.serial,
+                    mcu.version(),
+                    mcu.clock_freq()
+                );
+                Some(mcu)
+            }
+            None => {
+                tracing::warn!("No [mcu] section configured; running without hardware");
+                None
+            }
+        };
+
+        // Run the flush heartbeat for as long as the server is up. Print time
+        // follows the host clock until MCU clock synchronization is in place.
+        let machine = machine.map(|machine| Arc::new(Mutex::new(machine)));
+        let clock_freq = mcu.as_ref().map_or(HOST_CLOCK_FREQ, Mcu::clock_freq);
+        let executor = machine
+            .clone()
+            .map(|machine| Arc::new(Executor::spawn(machine, HostClock::new(clock_freq))));
+        let bed_mesh = BedMeshController::new(&config, executor.clone())?;
+        let input_shaper = InputShaperController::new(&config, executor.clone());
+        let pause = PauseController::new(&config, executor.clone());
+
         // Create plugin manager
         let mut plugin_manager = PluginManager::new(engine.clone());
+        plugin_manager.set_pause_controller(pause.clone());
+        plugin_manager.set_input_shaper_controller(input_shaper.clone());
+
+        // Write a crash report when step generation fails or a plugin traps
+        let crash = CrashReporter::new(&config, plugin_manager.registry().clone());
+        if let Some(executor) = &executor {
+            let crash = crash.clone();
+            executor.set_fault_hook(move |machine, kind, message| {
+                let motion = Some(MotionSnapshot::capture(machine));
+                if let Err(e) = crash.report(kind, message, motion) {
+                    tracing::error!("Failed to write crash report: {e:#}");
+                }
+            });
+        }
 
         // Load boot plugins if specified in config
         for plugin_path in &config.plugins {
@@ -65,6 +166,12 @@ impl StartArgs {
                 }
                 Err(e) => {
                     tracing::error!("Failed to load plugin {}: {}", plugin_path, e);
+                    if e.downcast_ref::<wasmtime::Trap>().is_some() {
+                        let message = format!("plugin {plugin_path} trapped: {e:#}");
+                        if let Err(e) = crash.report(FaultKind::PluginTrap, &message, None) {
+                            tracing::error!("Failed to write crash report: {e:#}");
+                        }
+                    }
                     // Continue loading other plugins instead of failing completely
                 }
             }
@@ -76,20 +183,38 @@ impl StartArgs {
         let handlers = registry.get_command_handlers();
         tracing::info!("Registered {} config schemas", schemas.len());
         tracing::info!("Registered {} command handlers", handlers.len());
-
-        // Create print job environment
-        let _job_linker = create_job_linker(&engine)?;
+        let console = Console::new(&config, executor, registry.clone());
+        console.set_command_hook({
+            let crash = crash.clone();
+            move |line| crash.record_command("console", line)
+        });
 
         tracing::info!("Scherzo runtime initialized");
 
         // Start the HTTP server
-        start_server(config)
+        start_server(
+            config,
+            machine,
+            pause,
+            bed_mesh,
+            input_shaper,
+            console,
+            crash,
+        )
     }
 }
 
 /// Start the HTTP server
 #[tokio::main]
-async fn start_server(config: Config) -> Result<()> {
+async fn start_server(
+    config: Config,
+    machine: Option<Arc<Mutex<Machine>>>,
+    pause: PauseController,
+    bed_mesh: BedMeshController,
+    input_shaper: InputShaperController,
+    console: Console,
+    crash: CrashReporter,
+) -> Result<()> {
     let addr = format!("{}:{}", config.server.host, config.server.port);
     let listener = tokio::net::TcpListener::bind(&addr)
         .await
@@ -98,7 +223,16 @@ async fn start_server(config: Config) -> Result<()> {
     tracing::info!("Server listening on {}", addr);
 
     // Create app state and router
-    let state = crate::server::AppState::new(config)?;
+    let state = crate::server::AppState::new(
+        config,
+        machine,
+        pause,
+        bed_mesh,
+        input_shaper,
+        console,
+        crash,
+    )?;
+    state.spawn_job_runner()?;
     let app = crate::server::create_router(state);
 
     // Run the server
@@ -107,6 +241,18 @@ async fn start_server(config: Config) -> Result<()> {
     Ok(())
 }
 
+/// Open the configured MCU, or run a virtual one in-process when `serial` is
+/// [`VIRTUAL_MCU`]
+fn connect_mcu(config: &McuConfig) -> Result<Mcu> {
+    let mcu = if config.serial == VIRTUAL_MCU {
+        let (transport, _) = virtual_mcu::spawn(VirtualMcu::new(VIRTUAL_MCU_FREQ));
+        Mcu::identify(transport)?
+    } else {
+        Mcu::connect(&config.serial, config.baud)?
+    };
+    Ok(mcu)
+}
+
 /// Create a linker for print jobs with command dispatch support
 fn create_job_linker(engine: &Engine) -> Result<Linker<JobState>> {
     let linker = Linker::new(engine);
@@ -0,0 +1,242 @@
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use scherzo_gcode::{Statement, parse};
+use std::{fmt, fs, path::PathBuf};
+use wasmparser::{Parser, Validator};
+
+#[derive(Args)]
+pub struct ValidateArgs {
+    /// Paths to G-code files or wasm components to validate.
+    #[arg(required = true)]
+    pub inputs: Vec<PathBuf>,
+}
+
+impl ValidateArgs {
+    pub fn run(&self) -> Result<()> {
+        let mut failed = 0usize;
+
+        for input in &self.inputs {
+            let bytes = fs::read(input)
+                .with_context(|| format!("failed to read input {}", input.display()))?;
+            let diagnostics = validate_bytes(&bytes);
+
+            let path = input.display();
+            for diagnostic in &diagnostics {
+                println!("{path}:{diagnostic}");
+            }
+
+            if diagnostics.iter().any(Diagnostic::is_error) {
+                failed += 1;
+            } else {
+                println!("OK {path}");
+            }
+        }
+
+        if failed > 0 {
+            bail!(
+                "{failed} of {} input(s) failed validation",
+                self.inputs.len()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A single validation finding, optionally anchored to a source position.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn error(message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            line: None,
+            column: None,
+            message: message.into(),
+        }
+    }
+
+    fn warning(message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            ..Self::error(message)
+        }
+    }
+
+    fn at(mut self, line: usize, column: Option<usize>) -> Self {
+        self.line = Some(line);
+        self.column = column;
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "{line}:")?;
+            if let Some(column) = self.column {
+                write!(f, "{column}:")?;
+            }
+        }
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, " {severity}: {}", self.message)
+    }
+}
+
+/// Validate either a wasm binary or G-code source, detected from the magic bytes.
+pub fn validate_bytes(bytes: &[u8]) -> Vec<Diagnostic> {
+    if bytes.starts_with(b"\0asm") {
+        return validate_wasm(bytes);
+    }
+
+    match std::str::from_utf8(bytes) {
+        Ok(source) => validate_gcode(source),
+        Err(err) => vec![Diagnostic::error(format!(
+            "input is neither wasm nor UTF-8 G-code: {err}"
+        ))],
+    }
+}
+
+fn validate_gcode(source: &str) -> Vec<Diagnostic> {
+    let statements = match parse(source) {
+        Ok(statements) => statements,
+        Err(err) => {
+            let (line, column) = err.position();
+            return vec![Diagnostic::error(err.to_string()).at(line, column)];
+        }
+    };
+
+    let mut diagnostics: Vec<_> = statements.iter().filter_map(check_checksum).collect();
+
+    if let Err(err) = scherzo_compile::compile_gcode(source) {
+        diagnostics.push(Diagnostic::error(format!("{err:#}")));
+    }
+
+    diagnostics
+}
+
+/// Verify a `*NN` checksum against the XOR of every byte preceding the `*`.
+fn check_checksum(stmt: &Statement) -> Option<Diagnostic> {
+    let expected = stmt.checksum?;
+    let (payload, _) = stmt.raw.split_once('*')?;
+    let actual = payload.bytes().fold(0u8, |acc, b| acc ^ b);
+    if actual == expected {
+        return None;
+    }
+    let column = payload.len() + 1;
+    Some(
+        Diagnostic::warning(format!(
+            "checksum mismatch: line declares {expected} but contents hash to {actual}"
+        ))
+        .at(stmt.line, Some(column)),
+    )
+}
+
+/// Worlds a scherzo component can target, identified by its exports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum World {
+    Job,
+    Plugin,
+}
+
+const JOB_IMPORT_PREFIX: &str = "job:print/";
+const PLUGIN_IMPORT_PREFIXES: &[&str] = &["scherzo:plugin/", "wasi:"];
+const PLUGIN_LIFECYCLE_EXPORT: &str = "scherzo:plugin/lifecycle";
+
+fn validate_wasm(bytes: &[u8]) -> Vec<Diagnostic> {
+    if let Err(err) = Validator::new().validate_all(bytes) {
+        return vec![Diagnostic::error(format!("invalid WebAssembly: {err}"))];
+    }
+
+    if !Parser::is_component(bytes) {
+        return vec![Diagnostic::error(
+            "core wasm module; jobs and plugins must be encoded as components",
+        )];
+    }
+
+    let report = match scherzo_compile::wasm_util::inspect(bytes) {
+        Ok(report) => report,
+        Err(err) => return vec![Diagnostic::error(format!("{err:#}"))],
+    };
+
+    let world = if report.exports.iter().any(|item| item.name == "run") {
+        World::Job
+    } else if report
+        .exports
+        .iter()
+        .any(|item| item.name.starts_with(PLUGIN_LIFECYCLE_EXPORT))
+    {
+        World::Plugin
+    } else {
+        return vec![Diagnostic::error(
+            "component exports neither `run` (job world) nor `scherzo:plugin/lifecycle` (plugin world)",
+        )];
+    };
+
+    report
+        .imports
+        .iter()
+        .map(|item| &item.name)
+        .filter(|name| match world {
+            World::Job => !name.starts_with(JOB_IMPORT_PREFIX),
+            World::Plugin => !PLUGIN_IMPORT_PREFIXES
+                .iter()
+                .any(|prefix| name.starts_with(prefix)),
+        })
+        .map(|name| {
+            Diagnostic::error(format!(
+                "import `{name}` is not provided by the {world:?} world"
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_lex_error_position() {
+        let diagnostics = validate_bytes(b"G1 X1\nM117 \"unterminated\n");
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].is_error());
+        assert_eq!(diagnostics[0].line, Some(2));
+        assert!(diagnostics[0].column.is_some());
+    }
+
+    #[test]
+    fn warns_on_checksum_mismatch() {
+        let diagnostics = validate_bytes(b"N1 M110 *2\nN2 M110 *3\n");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert_eq!(diagnostics[0].line, Some(2));
+    }
+
+    #[test]
+    fn accepts_compiled_job_component() {
+        let compilation = scherzo_compile::compile_gcode("G1 X1 Y2\nM104 S200\n").unwrap();
+        assert!(validate_bytes(&compilation.component).is_empty());
+
+        let diagnostics = validate_bytes(&compilation.wasm);
+        assert!(diagnostics.iter().any(Diagnostic::is_error));
+    }
+}
@@ -0,0 +1,312 @@
+//! Compiled job components, kept on disk to skip compiling a file again
+//!
+//! Entries are keyed by a SHA-256 of everything the component depends on: the
+//! G-code source, the compile options, the scherzo version, and the commands
+//! jobs may import, which plugins add to. Re-uploading an identical file, even
+//! after a restart, reuses the component. When the entries outgrow the size
+//! limit, the least recently used ones are evicted.
+
+use anyhow::{Context, Result};
+use scherzo_compile::CompileOptions;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::{self, File},
+    path::PathBuf,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+/// Hash of a compile's inputs, naming its cache entry
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey(String);
+
+impl CacheKey {
+    /// Key of compiling `source` with `options` for a server providing
+    /// `commands`
+    pub fn new(source: &str, options: &CompileOptions, commands: &BTreeSet<String>) -> Self {
+        let options = format!("{options:?}");
+        let fields = [env!("CARGO_PKG_VERSION").as_bytes(), options.as_bytes()]
+            .into_iter()
+            .chain(commands.iter().map(|command| command.as_bytes()))
+            .chain([source.as_bytes()]);
+        Self(digest(fields))
+    }
+}
+
+/// Hash of the compile options and the commands jobs may import, which
+/// decide what a source compiles to along with the compiler version
+pub fn options_hash(options: &CompileOptions, commands: &BTreeSet<String>) -> String {
+    let options = format!("{options:?}");
+    digest(
+        [options.as_bytes()]
+            .into_iter()
+            .chain(commands.iter().map(|c| c.as_bytes())),
+    )
+}
+
+/// Hash of a compiled source
+pub fn input_hash(source: &str) -> String {
+    digest([source.as_bytes()])
+}
+
+/// Hex SHA-256 of `fields`
+fn digest<'a>(fields: impl IntoIterator<Item = &'a [u8]>) -> String {
+    let mut hasher = Sha256::new();
+    for bytes in fields {
+        // Length-prefixed, so no two sets of fields hash the same bytes
+        hasher.update((bytes.len() as u64).to_le_bytes());
+        hasher.update(bytes);
+    }
+    let digest = hasher.finalize();
+    digest.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Hits, misses and size of a [`CompileCache`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: usize,
+    pub size_bytes: u64,
+    pub max_size_bytes: u64,
+}
+
+struct Entry {
+    size: u64,
+    /// Tick of the last use; the lowest is evicted first
+    last_used: u64,
+}
+
+struct CacheState {
+    entries: HashMap<CacheKey, Entry>,
+    /// Incremented on every use
+    clock: u64,
+    stats: CacheStats,
+}
+
+/// Size-bounded cache of compiled components in a directory
+pub struct CompileCache {
+    dir: PathBuf,
+    state: Mutex<CacheState>,
+}
+
+impl CompileCache {
+    /// Open the cache in `dir`, keeping at most `max_size_bytes` of
+    /// components; 0 disables it. Entries left by an earlier run are kept,
+    /// oldest first in line for eviction.
+    pub fn open(dir: PathBuf, max_size_bytes: u64) -> Result<Self> {
+        fs::create_dir_all(&dir).context("failed to create compile cache directory")?;
+        let mut found = Vec::new();
+        for entry in fs::read_dir(&dir).context("failed to read compile cache directory")? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "wasm") {
+                // Leftovers of an interrupted write
+                if path.extension().is_some_and(|ext| ext == "tmp") {
+                    let _ = fs::remove_file(&path);
+                }
+                continue;
+            }
+            let Some(key) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            let metadata = fs::metadata(&path)?;
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            found.push((modified, CacheKey(key.to_string()), metadata.len()));
+        }
+        found.sort_by_key(|(modified, _, _)| *modified);
+
+        let cache = Self {
+            dir,
+            state: Mutex::new(CacheState {
+                entries: HashMap::new(),
+                clock: 0,
+                stats: CacheStats {
+                    max_size_bytes,
+                    ..CacheStats::default()
+                },
+            }),
+        };
+        let mut state = cache.state.lock().unwrap();
+        for (_, key, size) in found {
+            state.clock += 1;
+            let last_used = state.clock;
+            state.stats.size_bytes += size;
+            state.entries.insert(key, Entry { size, last_used });
+        }
+        let evicted = evict(&mut state);
+        state.stats.entries = state.entries.len();
+        drop(state);
+        cache.remove_files(evicted);
+        Ok(cache)
+    }
+
+    /// The component cached under `key`, if any
+    pub fn get(&self, key: &CacheKey) -> Option<Vec<u8>> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if !state.entries.contains_key(key) {
+                state.stats.misses += 1;
+                return None;
+            }
+        }
+        // Read outside the lock; a concurrent eviction makes this a miss
+        let path = self.path(key);
+        let component = fs::read(&path).ok();
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+        match (&component, state.entries.get_mut(key)) {
+            (Some(_), Some(entry)) => {
+                entry.last_used = clock;
+                state.stats.hits += 1;
+                drop(state);
+                // Keep the order of use across restarts
+                if let Ok(file) = File::options().append(true).open(&path) {
+                    let _ = file.set_modified(SystemTime::now());
+                }
+                component
+            }
+            _ => {
+                state.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Cache `component` under `key`, evicting the least recently used
+    /// entries if the cache grows past its limit
+    pub fn insert(&self, key: &CacheKey, component: &[u8]) {
+        let size = component.len() as u64;
+        if size > self.state.lock().unwrap().stats.max_size_bytes {
+            return;
+        }
+        let path = self.path(key);
+        let tmp = path.with_extension("tmp");
+        let written = fs::write(&tmp, component).and_then(|()| fs::rename(&tmp, &path));
+        if let Err(e) = written {
+            tracing::warn!("Failed to cache compiled component: {e}");
+            let _ = fs::remove_file(&tmp);
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let last_used = state.clock;
+        if let Some(old) = state.entries.insert(key.clone(), Entry { size, last_used }) {
+            state.stats.size_bytes -= old.size;
+        }
+        state.stats.size_bytes += size;
+        let evicted = evict(&mut state);
+        state.stats.entries = state.entries.len();
+        drop(state);
+        self.remove_files(evicted);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.state.lock().unwrap().stats
+    }
+
+    fn path(&self, key: &CacheKey) -> PathBuf {
+        self.dir.join(format!("{}.wasm", key.0))
+    }
+
+    fn remove_files(&self, keys: Vec<CacheKey>) {
+        for key in keys {
+            let _ = fs::remove_file(self.path(&key));
+        }
+    }
+}
+
+/// Drop the least recently used entries until the cache fits its limit,
+/// returning their keys
+fn evict(state: &mut CacheState) -> Vec<CacheKey> {
+    let mut evicted = Vec::new();
+    while state.stats.size_bytes > state.stats.max_size_bytes {
+        let Some(key) = state
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(key, _)| key.clone())
+        else {
+            break;
+        };
+        if let Some(entry) = state.entries.remove(&key) {
+            state.stats.size_bytes -= entry.size;
+            state.stats.evictions += 1;
+        }
+        evicted.push(key);
+    }
+    evicted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(source: &str) -> CacheKey {
+        CacheKey::new(source, &CompileOptions::default(), &BTreeSet::new())
+    }
+
+    #[test]
+    fn test_key_covers_options_and_commands() {
+        let options = CompileOptions::default();
+        let commands: BTreeSet<_> = ["G1".to_string()].into();
+        let base = CacheKey::new("G1 X1\n", &options, &commands);
+        assert_eq!(base, CacheKey::new("G1 X1\n", &options, &commands));
+        assert_ne!(base, CacheKey::new("G1 X2\n", &options, &commands));
+        assert_ne!(base, CacheKey::new("G1 X1\n", &options, &BTreeSet::new()));
+        let arc_fit = CompileOptions {
+            arc_fit: Some(Default::default()),
+            ..CompileOptions::default()
+        };
+        assert_ne!(base, CacheKey::new("G1 X1\n", &arc_fit, &commands));
+        let modal = CompileOptions {
+            modal_motion: true,
+            ..CompileOptions::default()
+        };
+        assert_ne!(base, CacheKey::new("G1 X1\n", &modal, &commands));
+    }
+
+    #[test]
+    fn test_hits_misses_and_eviction() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CompileCache::open(dir.path().to_path_buf(), 10).unwrap();
+        assert_eq!(cache.get(&key("a")), None);
+        cache.insert(&key("a"), b"aaaa");
+        cache.insert(&key("b"), b"bbbb");
+        assert_eq!(cache.get(&key("a")).unwrap(), b"aaaa");
+
+        // "b" is the least recently used
+        cache.insert(&key("c"), b"cccc");
+        assert_eq!(cache.get(&key("b")), None);
+        assert_eq!(cache.get(&key("a")).unwrap(), b"aaaa");
+        // Too large to cache at all
+        cache.insert(&key("d"), &[0; 11]);
+        assert_eq!(cache.get(&key("d")), None);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (2, 3, 1));
+        assert_eq!((stats.entries, stats.size_bytes), (2, 8));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_entries_survive_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CompileCache::open(dir.path().to_path_buf(), 100).unwrap();
+        cache.insert(&key("a"), b"aaaa");
+        cache.insert(&key("b"), b"bbbb");
+        // Written within the same tick of a coarse clock, "a" could look newer
+        let a = File::options().append(true).open(cache.path(&key("a")));
+        a.unwrap().set_modified(SystemTime::UNIX_EPOCH).unwrap();
+        drop(cache);
+
+        // A smaller limit evicts the oldest entry on opening
+        let cache = CompileCache::open(dir.path().to_path_buf(), 4).unwrap();
+        assert_eq!(cache.stats().entries, 1);
+        assert_eq!(cache.get(&key("b")).unwrap(), b"bbbb");
+    }
+}
@@ -1,6 +1,28 @@
-use anyhow::{Context, Result};
+use crate::{console::check_macro, machine::Machine};
+use anyhow::{Context, Result, bail};
+use scherzo_core::{
+    bed_mesh::BedMesh,
+    compensation::CompensationConfig,
+    pause_resume::PauseConfig,
+    planner::MachineLimits,
+    resonance::{ResonanceTest, ShaperType},
+    retraction::RetractionConfig,
+    step_compressor,
+};
 use serde::{Deserialize, Serialize};
-use std::{fs, path::Path};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+/// Commented configuration template with every default spelled out
+pub const TEMPLATE: &str = include_str!("../../../example.toml");
+
+/// Prefix for environment variables that override config values, e.g.
+/// `SCHERZO__SERVER__PORT=8080` sets `server.port`
+const ENV_OVERRIDE_PREFIX: &str = "SCHERZO__";
 
 /// Main configuration for the Scherzo runtime
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -16,6 +38,132 @@ pub struct Config {
     /// Job storage configuration
     #[serde(default)]
     pub jobs: JobsConfig,
+
+    /// What happens between queued jobs
+    #[serde(default)]
+    pub job_queue: JobQueueConfig,
+
+    /// Where crash reports are written
+    #[serde(default)]
+    pub crash_reports: CrashReportConfig,
+
+    /// Span export for tracing jobs and plugins
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+
+    /// Per-plugin configuration, keyed by the namespace of the plugin's
+    /// registered config schema
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub plugin_config: BTreeMap<String, serde_json::Value>,
+
+    /// Printer kinematics and motion limits. Without it the runtime has no
+    /// motion system.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub printer: Option<PrinterConfig>,
+
+    /// Stepper driving the X axis (the A belt on core kinematics)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stepper_x: Option<StepperConfig>,
+
+    /// Stepper driving the Y axis (the B belt on CoreXY)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stepper_y: Option<StepperConfig>,
+
+    /// Stepper driving the Z axis (the B belt on CoreXZ)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stepper_z: Option<StepperConfig>,
+
+    /// Extruder stepper
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extruder: Option<ExtruderConfig>,
+
+    /// Extruder moves performed for `G10`/`G11`. Without it both are ignored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub firmware_retraction: Option<FirmwareRetractionConfig>,
+
+    /// Park and re-prime sequence run when a print pauses and resumes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pause_resume: Option<PauseResumeConfig>,
+
+    /// Filament sensors by name, reported by plugins or the API
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub filament_sensor: BTreeMap<String, FilamentSensorConfig>,
+
+    /// Bed mesh probing grid, and the mesh measured by the last calibration
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bed_mesh: Option<BedMeshConfig>,
+
+    /// Skew, X twist and custom transforms applied to every move, along with
+    /// the bed mesh
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compensation: Option<CompensationConfig>,
+
+    /// G-code macros by name, run through the console
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub macros: BTreeMap<String, MacroConfig>,
+
+    /// Resonance test run by input shaper calibration
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resonance_tester: Option<ResonanceTesterConfig>,
+
+    /// Input shaper settings recommended by the last calibration of each axis
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input_shaper: Option<InputShaperConfig>,
+
+    /// Serial connection to the printer's MCU
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mcu: Option<McuConfig>,
+
+    /// TOML file holding values referenced as `${secret:NAME}`. It must not
+    /// be accessible to group or others (e.g. mode 0600).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secrets_file: Option<String>,
+
+    /// TOML file the printer saves calibration results to, such as the bed
+    /// mesh. When loading from a file it is merged over everything else.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub saved_config: Option<String>,
+
+    /// Where each value was set, for error messages
+    #[serde(skip)]
+    pub provenance: Provenance,
+}
+
+/// Tracks which file (or environment variable) last set each config key when
+/// layering includes and overrides
+#[derive(Debug, Clone, Default)]
+pub struct Provenance {
+    sources: BTreeMap<String, String>,
+}
+
+impl Provenance {
+    /// Source that set `key`, or the closest enclosing table that was set as a
+    /// whole
+    pub fn source(&self, key: &str) -> Option<&str> {
+        let mut key = key;
+        loop {
+            if let Some(source) = self.sources.get(key) {
+                return Some(source);
+            }
+            key = &key[..key.rfind(['.', '['])?];
+        }
+    }
+
+    /// Render `key` along with where it was set, if known
+    pub fn describe(&self, key: &str) -> String {
+        match self.source(key) {
+            Some(source) => format!("{key} (from {source})"),
+            None => key.to_string(),
+        }
+    }
+
+    /// Record that `source` set `key`, replacing anything recorded beneath it
+    fn record(&mut self, key: &str, source: &str) {
+        let nested = format!("{key}.");
+        self.sources
+            .retain(|k, _| k != key && !k.starts_with(&nested));
+        self.sources.insert(key.to_string(), source.to_string());
+    }
 }
 
 /// Server configuration
@@ -51,6 +199,10 @@ pub struct AuthConfig {
 
     /// Password hash (bcrypt) for basic auth
     pub password_hash: String,
+
+    /// API keys accepted as `Authorization: Bearer <key>`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub api_keys: Vec<String>,
 }
 
 /// Jobs configuration
@@ -63,6 +215,31 @@ pub struct JobsConfig {
     /// Maximum job size in bytes (default 100MB)
     #[serde(default = "default_max_job_size")]
     pub max_size_bytes: u64,
+
+    /// Seconds between power loss recovery checkpoints of a running job, or
+    /// 0 to disable them
+    #[serde(default = "default_checkpoint_interval")]
+    pub checkpoint_interval: f64,
+
+    /// Maximum size in bytes of the components kept to skip compiling an
+    /// identical upload again (default 256MB), or 0 to disable the cache
+    #[serde(default = "default_compile_cache_size")]
+    pub compile_cache_size_bytes: u64,
+
+    /// Whether lines that start with a coordinate, like `X10 Y5`, repeat the
+    /// last `G0`-`G3` as in dialects that leave out a repeated verb
+    #[serde(default)]
+    pub modal_motion: bool,
+
+    /// Maximum size in bytes of the component a G-code job compiles to, or
+    /// of an uploaded component (default unlimited)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_component_size: Option<usize>,
+
+    /// Maximum size in bytes of the function a G-code job compiles its
+    /// commands into (default unlimited)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_function_size: Option<usize>,
 }
 
 impl Default for JobsConfig {
@@ -70,10 +247,737 @@ impl Default for JobsConfig {
         Self {
             storage_dir: default_jobs_dir(),
             max_size_bytes: default_max_job_size(),
+            checkpoint_interval: default_checkpoint_interval(),
+            compile_cache_size_bytes: default_compile_cache_size(),
+            modal_motion: false,
+            max_component_size: None,
+            max_function_size: None,
+        }
+    }
+}
+
+/// What happens between queued jobs
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct JobQueueConfig {
+    /// G-code run through the console after each completed job, e.g. a macro
+    /// ejecting the part
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub between_jobs_gcode: Option<String>,
+
+    /// Hold the queue after each completed job until the next one is
+    /// confirmed through the API
+    #[serde(default)]
+    pub confirm_between_jobs: bool,
+}
+
+/// Where crash reports are written and what they keep
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrashReportConfig {
+    /// Directory to write crash reports to
+    #[serde(default = "default_crash_report_dir")]
+    pub dir: String,
+
+    /// Number of recent commands kept for the next report
+    #[serde(default = "default_command_history")]
+    pub command_history: usize,
+}
+
+impl Default for CrashReportConfig {
+    fn default() -> Self {
+        Self {
+            dir: default_crash_report_dir(),
+            command_history: default_command_history(),
+        }
+    }
+}
+
+/// Span export for tracing jobs and plugins
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TelemetryConfig {
+    /// OTLP/HTTP traces endpoint, e.g. `http://localhost:4318/v1/traces`.
+    /// Spans are only exported when set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub otlp_endpoint: Option<String>,
+
+    /// Service name spans are exported under
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+
+    /// Which spans to export, in `RUST_LOG` syntax
+    #[serde(default = "default_telemetry_filter")]
+    pub filter: String,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            service_name: default_service_name(),
+            filter: default_telemetry_filter(),
+        }
+    }
+}
+
+/// Printer kinematics and motion limits
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrinterConfig {
+    /// Kinematics type: `cartesian`, `corexy`, or `corexz`
+    pub kinematics: String,
+
+    /// Maximum toolhead velocity (mm/s)
+    pub max_velocity: f64,
+
+    /// Maximum toolhead acceleration (mm/s^2)
+    pub max_accel: f64,
+
+    /// Fraction of short zig-zag moves spent cruising (default 0.5)
+    #[serde(default = "default_minimum_cruise_ratio")]
+    pub minimum_cruise_ratio: f64,
+
+    /// Maximum velocity through a 90 degree corner (default 5 mm/s)
+    #[serde(default = "default_square_corner_velocity")]
+    pub square_corner_velocity: f64,
+
+    /// Maximum velocity (mm/s) of moves that do not extrude (default
+    /// max_velocity)
+    #[serde(default)]
+    pub max_travel_velocity: Option<f64>,
+
+    /// Maximum acceleration (mm/s^2) of moves that do not extrude (default
+    /// max_accel)
+    #[serde(default)]
+    pub max_travel_accel: Option<f64>,
+
+    /// Queued motion (seconds) below which lookahead is flushed so the MCU
+    /// does not run dry (default 1.0)
+    #[serde(default = "default_buffer_time_low")]
+    pub buffer_time_low: f64,
+
+    /// Queued motion (seconds) above which jobs wait before queueing more
+    /// moves (default 2.0)
+    #[serde(default = "default_buffer_time_high")]
+    pub buffer_time_high: f64,
+
+    /// Delay (seconds) before the first move after the toolhead was idle
+    /// (default 0.25)
+    #[serde(default = "default_buffer_time_start")]
+    pub buffer_time_start: f64,
+
+    /// How far (seconds) step generation runs ahead of what is sent to the
+    /// MCU (default 0.05)
+    #[serde(default = "default_move_flush_time")]
+    pub move_flush_time: f64,
+
+    /// File every step command sent to the MCU is logged to, with its
+    /// clocks, so motion bugs can be replayed exactly (default none)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command_log: Option<String>,
+}
+
+/// A `[stepper_x]`-style section describing one rail
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepperConfig {
+    /// Distance (mm) the axis moves per full rotation of the stepper
+    pub rotation_distance: f64,
+
+    /// Microsteps per full step
+    pub microsteps: u32,
+
+    /// Full steps per rotation of the stepper (default 200)
+    #[serde(default = "default_full_steps_per_rotation")]
+    pub full_steps_per_rotation: u32,
+
+    /// Minimum valid position (default 0)
+    #[serde(default)]
+    pub position_min: f64,
+
+    /// Maximum valid position
+    pub position_max: f64,
+
+    /// Pin of the endstop used for homing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endstop_pin: Option<String>,
+
+    /// Position at which the endstop triggers; required with `endstop_pin`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position_endstop: Option<f64>,
+
+    /// Homing speed in mm/s (default 5)
+    #[serde(default = "default_homing_speed")]
+    pub homing_speed: f64,
+
+    /// Home toward position_max; inferred from position_endstop by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub homing_positive_dir: Option<bool>,
+
+    /// How step times are compressed into MCU commands (default bisect_add)
+    #[serde(default)]
+    pub step_compression: StepCompression,
+
+    /// Largest error (seconds) of a compressed step's time (default 0.000025)
+    #[serde(default = "default_max_step_error")]
+    pub max_step_error: f64,
+
+    /// A step followed by a step back within this window (seconds) is
+    /// dropped along with it; 0 disables the filter (default 0.00075)
+    #[serde(default = "default_sds_filter_time")]
+    pub sds_filter_time: f64,
+
+    /// Step times buffered before the queue first grows (default 1024)
+    #[serde(default = "default_step_queue_size")]
+    pub step_queue_size: usize,
+
+    /// Refit a step sequence that fails to compress with a tighter error,
+    /// down to single steps, logging a warning instead of failing the
+    /// print (default true)
+    #[serde(default = "default_true")]
+    pub step_error_recovery: bool,
+}
+
+/// How a stepper's step times are compressed into MCU commands
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepCompression {
+    /// Sequences of steps with a changing interval, as Klipper sends them
+    #[default]
+    BisectAdd,
+    /// Sequences of equally spaced steps only
+    ConstantInterval,
+    /// Each step on its own at its exact time, e.g. for servo-like devices
+    /// or debugging
+    Exact,
+    /// Looks a few hundred steps ahead to try for fewer sequences than
+    /// `bisect_add`; a heuristic, not an optimal fit, and much costlier,
+    /// for experiments
+    LookaheadBisect,
+}
+
+/// Step compressor tuning of one stepper or the extruder
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompressorTuning {
+    pub compression: StepCompression,
+    pub max_step_error: f64,
+    pub sds_filter_time: f64,
+    pub step_queue_size: usize,
+    pub error_recovery: bool,
+}
+
+impl Default for CompressorTuning {
+    fn default() -> Self {
+        Self {
+            compression: StepCompression::default(),
+            max_step_error: default_max_step_error(),
+            sds_filter_time: default_sds_filter_time(),
+            step_queue_size: default_step_queue_size(),
+            error_recovery: true,
+        }
+    }
+}
+
+impl StepperConfig {
+    pub fn compressor_tuning(&self) -> CompressorTuning {
+        CompressorTuning {
+            compression: self.step_compression,
+            max_step_error: self.max_step_error,
+            sds_filter_time: self.sds_filter_time,
+            step_queue_size: self.step_queue_size,
+            error_recovery: self.step_error_recovery,
+        }
+    }
+}
+
+impl ExtruderConfig {
+    pub fn compressor_tuning(&self) -> CompressorTuning {
+        CompressorTuning {
+            compression: self.step_compression,
+            max_step_error: self.max_step_error,
+            sds_filter_time: self.sds_filter_time,
+            step_queue_size: self.step_queue_size,
+            error_recovery: self.step_error_recovery,
+        }
+    }
+}
+
+/// Extruder stepper configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExtruderConfig {
+    /// Filament length (mm) fed per full rotation of the stepper
+    pub rotation_distance: f64,
+
+    /// Microsteps per full step
+    pub microsteps: u32,
+
+    /// Full steps per rotation of the stepper (default 200)
+    #[serde(default = "default_full_steps_per_rotation")]
+    pub full_steps_per_rotation: u32,
+
+    /// How step times are compressed into MCU commands (default bisect_add)
+    #[serde(default)]
+    pub step_compression: StepCompression,
+
+    /// Largest error (seconds) of a compressed step's time (default 0.000025)
+    #[serde(default = "default_max_step_error")]
+    pub max_step_error: f64,
+
+    /// A step followed by a step back within this window (seconds) is
+    /// dropped along with it; 0 disables the filter (default 0.00075)
+    #[serde(default = "default_sds_filter_time")]
+    pub sds_filter_time: f64,
+
+    /// Step times buffered before the queue first grows (default 1024)
+    #[serde(default = "default_step_queue_size")]
+    pub step_queue_size: usize,
+
+    /// Refit a step sequence that fails to compress with a tighter error,
+    /// down to single steps, logging a warning instead of failing the
+    /// print (default true)
+    #[serde(default = "default_true")]
+    pub step_error_recovery: bool,
+
+    /// Maximum filament velocity (mm/s) of moves that only extrude, such as
+    /// retracts and purges (default 50)
+    #[serde(default = "default_max_extrude_only_velocity")]
+    pub max_extrude_only_velocity: f64,
+
+    /// Maximum filament acceleration (mm/s^2) of moves that only extrude
+    /// (default 1000)
+    #[serde(default = "default_max_extrude_only_accel")]
+    pub max_extrude_only_accel: f64,
+}
+
+/// Firmware retraction parameters, tunable at runtime with `M207`/`M208`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FirmwareRetractionConfig {
+    /// Filament (mm) pulled back on `G10` (default 0)
+    #[serde(default)]
+    pub retract_length: f64,
+
+    /// Retract speed (default 20 mm/s)
+    #[serde(default = "default_retract_speed")]
+    pub retract_speed: f64,
+
+    /// Extra filament (mm) pushed on `G11` after a retract (default 0)
+    #[serde(default)]
+    pub unretract_extra_length: f64,
+
+    /// Unretract speed (default 10 mm/s)
+    #[serde(default = "default_unretract_speed")]
+    pub unretract_speed: f64,
+}
+
+impl TryFrom<&FirmwareRetractionConfig> for RetractionConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(config: &FirmwareRetractionConfig) -> Result<Self> {
+        if config.retract_length < 0.0 || config.unretract_extra_length < 0.0 {
+            bail!("retract_length and unretract_extra_length cannot be negative");
+        }
+        if config.retract_speed <= 0.0 || config.unretract_speed <= 0.0 {
+            bail!("retract_speed and unretract_speed must be positive");
+        }
+        Ok(Self {
+            retract_length: config.retract_length,
+            retract_speed: config.retract_speed,
+            unretract_extra_length: config.unretract_extra_length,
+            unretract_speed: config.unretract_speed,
+        })
+    }
+}
+
+impl From<RetractionConfig> for FirmwareRetractionConfig {
+    fn from(config: RetractionConfig) -> Self {
+        Self {
+            retract_length: config.retract_length,
+            retract_speed: config.retract_speed,
+            unretract_extra_length: config.unretract_extra_length,
+            unretract_speed: config.unretract_speed,
+        }
+    }
+}
+
+/// What a print does when it pauses and resumes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PauseResumeConfig {
+    /// X position to park at. The nozzle only lifts unless both `park_x`
+    /// and `park_y` are set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub park_x: Option<f64>,
+
+    /// Y position to park at
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub park_y: Option<f64>,
+
+    /// Distance (mm) to lift the nozzle, up to `stepper_z.position_max`
+    /// (default 10)
+    #[serde(default = "default_z_lift")]
+    pub z_lift: f64,
+
+    /// Filament (mm) to retract while paused (default 1)
+    #[serde(default = "default_pause_retract_length")]
+    pub retract_length: f64,
+
+    /// Retract speed (default 20 mm/s)
+    #[serde(default = "default_pause_retract_speed")]
+    pub retract_speed: f64,
+
+    /// Speed of the moves to and from the park position (default 100 mm/s)
+    #[serde(default = "default_travel_speed")]
+    pub travel_speed: f64,
+
+    /// Speed of the lift and descent (default 10 mm/s)
+    #[serde(default = "default_z_speed")]
+    pub z_speed: f64,
+
+    /// Extra filament (mm) pushed when resuming (default 0)
+    #[serde(default)]
+    pub prime_length: f64,
+
+    /// Speed of the unretract and prime when resuming (default 10 mm/s)
+    #[serde(default = "default_prime_speed")]
+    pub prime_speed: f64,
+}
+
+impl Default for PauseResumeConfig {
+    fn default() -> Self {
+        let config = PauseConfig::default();
+        Self {
+            park_x: None,
+            park_y: None,
+            z_lift: config.z_lift,
+            retract_length: config.retract_length,
+            retract_speed: config.retract_speed,
+            travel_speed: config.travel_speed,
+            z_speed: config.z_speed,
+            prime_length: config.prime_length,
+            prime_speed: config.prime_speed,
+        }
+    }
+}
+
+impl TryFrom<&PauseResumeConfig> for PauseConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(config: &PauseResumeConfig) -> Result<Self> {
+        if config.z_lift < 0.0 || config.retract_length < 0.0 || config.prime_length < 0.0 {
+            bail!("z_lift, retract_length, and prime_length cannot be negative");
+        }
+        if [
+            config.retract_speed,
+            config.travel_speed,
+            config.z_speed,
+            config.prime_speed,
+        ]
+        .iter()
+        .any(|speed| *speed <= 0.0)
+        {
+            bail!("speeds must be positive");
+        }
+        let park_position = match (config.park_x, config.park_y) {
+            (Some(x), Some(y)) => Some([x, y]),
+            (None, None) => None,
+            _ => bail!("park_x and park_y must be set together"),
+        };
+        Ok(Self {
+            park_position,
+            z_lift: config.z_lift,
+            retract_length: config.retract_length,
+            retract_speed: config.retract_speed,
+            travel_speed: config.travel_speed,
+            z_speed: config.z_speed,
+            prime_length: config.prime_length,
+            prime_speed: config.prime_speed,
+            ..PauseConfig::default()
+        })
+    }
+}
+
+/// A sensor that reports whether filament is loaded
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FilamentSensorConfig {
+    /// Pause the running print when the filament runs out (default true)
+    #[serde(default = "default_true")]
+    pub pause_on_runout: bool,
+}
+
+/// Grid probed by bed mesh calibration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BedMeshConfig {
+    /// Lowest X/Y corner of the probed area
+    pub mesh_min: [f64; 2],
+
+    /// Highest X/Y corner of the probed area
+    pub mesh_max: [f64; 2],
+
+    /// Points to probe along X and Y (default 3 by 3)
+    #[serde(default = "default_probe_count")]
+    pub probe_count: [usize; 2],
+
+    /// Z height of the moves between points (default 5)
+    #[serde(default = "default_horizontal_move_z")]
+    pub horizontal_move_z: f64,
+
+    /// Speed of the moves between points (default 50 mm/s)
+    #[serde(default = "default_mesh_speed")]
+    pub speed: f64,
+
+    /// Longest segment (mm) a compensated move is split into (default 5)
+    #[serde(default = "default_move_check_distance")]
+    pub move_check_distance: f64,
+
+    /// Mesh measured by the last calibration, written to `saved_config`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mesh: Option<MeshConfig>,
+}
+
+/// Measured bed heights
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MeshConfig {
+    /// Lowest X/Y corner of the mesh
+    pub min: [f64; 2],
+
+    /// Highest X/Y corner of the mesh
+    pub max: [f64; 2],
+
+    /// Bed heights (mm), one row per Y position from `min` to `max`, each
+    /// with one height per X position
+    pub points: Vec<Vec<f64>>,
+}
+
+impl TryFrom<&MeshConfig> for BedMesh {
+    type Error = anyhow::Error;
+
+    fn try_from(config: &MeshConfig) -> Result<Self> {
+        Ok(BedMesh::new(config.min, config.max, config.points.clone())?)
+    }
+}
+
+impl From<&BedMesh> for MeshConfig {
+    fn from(mesh: &BedMesh) -> Self {
+        Self {
+            min: mesh.min(),
+            max: mesh.max(),
+            points: mesh.points().to_vec(),
+        }
+    }
+}
+
+/// G-code macro, run by sending its name with `NAME=value` parameters
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MacroConfig {
+    /// What the macro does, shown when listing macros
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+
+    /// G-code to run, templated like Klipper's `gcode_macro` with `params`
+    /// and `printer`
+    pub gcode: String,
+}
+
+/// Frequency sweep shaking the toolhead while an accelerometer records it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResonanceTesterConfig {
+    /// Accelerometer to record, named as its plugin or API client reports it
+    pub accel_chip: String,
+
+    /// X/Y/Z position to shake the toolhead around
+    pub probe_point: [f64; 3],
+
+    /// First frequency tested (default 5 Hz)
+    #[serde(default = "default_resonance_min_freq")]
+    pub min_freq: f64,
+
+    /// Last frequency tested (default 133.33 Hz)
+    #[serde(default = "default_resonance_max_freq")]
+    pub max_freq: f64,
+
+    /// Acceleration of the test moves per Hz (default 75 mm/s^2)
+    #[serde(default = "default_accel_per_hz")]
+    pub accel_per_hz: f64,
+
+    /// How fast the frequency sweeps up (default 1 Hz/s)
+    #[serde(default = "default_hz_per_sec")]
+    pub hz_per_sec: f64,
+
+    /// Speed of the move to the probe point (default 50 mm/s)
+    #[serde(default = "default_resonance_move_speed")]
+    pub move_speed: f64,
+
+    /// Most smoothing (mm) a recommended shaper may cause; unlimited if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_smoothing: Option<f64>,
+}
+
+impl From<&ResonanceTesterConfig> for ResonanceTest {
+    fn from(config: &ResonanceTesterConfig) -> Self {
+        Self {
+            min_freq: config.min_freq,
+            max_freq: config.max_freq,
+            accel_per_hz: config.accel_per_hz,
+            hz_per_sec: config.hz_per_sec,
         }
     }
 }
 
+/// Input shaper type and frequency for each axis, written to `saved_config`
+/// by calibration
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct InputShaperConfig {
+    /// Shaper type for X, e.g. `mzv` or `2hump_ei`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shaper_type_x: Option<String>,
+
+    /// Shaper frequency for X (Hz)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shaper_freq_x: Option<f64>,
+
+    /// Shaper type for Y
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shaper_type_y: Option<String>,
+
+    /// Shaper frequency for Y (Hz)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shaper_freq_y: Option<f64>,
+}
+
+/// Serial connection to a Klipper-protocol MCU
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct McuConfig {
+    /// Serial device, e.g. `/dev/serial/by-id/usb-Klipper_...`
+    pub serial: String,
+
+    /// Baud rate for UART connections; ignored over USB (default 250000)
+    #[serde(default = "default_baud")]
+    pub baud: u32,
+}
+
+fn default_minimum_cruise_ratio() -> f64 {
+    0.5
+}
+
+fn default_square_corner_velocity() -> f64 {
+    5.0
+}
+
+fn default_buffer_time_low() -> f64 {
+    1.0
+}
+
+fn default_buffer_time_high() -> f64 {
+    2.0
+}
+
+fn default_buffer_time_start() -> f64 {
+    0.25
+}
+
+fn default_move_flush_time() -> f64 {
+    0.05
+}
+
+fn default_probe_count() -> [usize; 2] {
+    [3, 3]
+}
+
+fn default_horizontal_move_z() -> f64 {
+    5.0
+}
+
+fn default_mesh_speed() -> f64 {
+    50.0
+}
+
+pub(crate) fn default_move_check_distance() -> f64 {
+    5.0
+}
+
+fn default_z_lift() -> f64 {
+    PauseConfig::default().z_lift
+}
+
+fn default_pause_retract_length() -> f64 {
+    PauseConfig::default().retract_length
+}
+
+fn default_pause_retract_speed() -> f64 {
+    PauseConfig::default().retract_speed
+}
+
+fn default_travel_speed() -> f64 {
+    PauseConfig::default().travel_speed
+}
+
+fn default_z_speed() -> f64 {
+    PauseConfig::default().z_speed
+}
+
+fn default_prime_speed() -> f64 {
+    PauseConfig::default().prime_speed
+}
+
+pub(crate) fn default_true() -> bool {
+    true
+}
+
+fn default_retract_speed() -> f64 {
+    RetractionConfig::default().retract_speed
+}
+
+fn default_unretract_speed() -> f64 {
+    RetractionConfig::default().unretract_speed
+}
+
+fn default_full_steps_per_rotation() -> u32 {
+    200
+}
+
+fn default_max_step_error() -> f64 {
+    0.000_025
+}
+
+fn default_sds_filter_time() -> f64 {
+    step_compressor::SDS_FILTER_TIME
+}
+
+fn default_step_queue_size() -> usize {
+    step_compressor::QUEUE_START_SIZE
+}
+
+fn default_max_extrude_only_velocity() -> f64 {
+    MachineLimits::default().max_extrude_only_velocity
+}
+
+fn default_max_extrude_only_accel() -> f64 {
+    MachineLimits::default().max_extrude_only_accel
+}
+
+fn default_homing_speed() -> f64 {
+    5.0
+}
+
+fn default_resonance_min_freq() -> f64 {
+    5.0
+}
+
+fn default_resonance_max_freq() -> f64 {
+    133.33
+}
+
+fn default_accel_per_hz() -> f64 {
+    75.0
+}
+
+fn default_hz_per_sec() -> f64 {
+    1.0
+}
+
+fn default_resonance_move_speed() -> f64 {
+    50.0
+}
+
+fn default_baud() -> u32 {
+    250_000
+}
+
 fn default_port() -> u16 {
     3000
 }
@@ -86,63 +990,572 @@ fn default_jobs_dir() -> String {
     "./jobs".to_string()
 }
 
+fn default_crash_report_dir() -> String {
+    "./crash_reports".to_string()
+}
+
+fn default_command_history() -> usize {
+    100
+}
+
+fn default_service_name() -> String {
+    "scherzo".to_string()
+}
+
+fn default_telemetry_filter() -> String {
+    "info".to_string()
+}
+
 fn default_max_job_size() -> u64 {
     100 * 1024 * 1024 // 100MB
 }
 
-impl Config {
-    /// Load configuration from a file, auto-detecting TOML or JSON format
-    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let path = path.as_ref();
-        let content = fs::read_to_string(path)
-            .with_context(|| format!("failed to read config file {}", path.display()))?;
+fn default_checkpoint_interval() -> f64 {
+    30.0
+}
 
-        // Try to determine format from extension
-        let extension = path.extension().and_then(|s| s.to_str());
+fn default_compile_cache_size() -> u64 {
+    256 * 1024 * 1024 // 256MB
+}
 
-        match extension {
-            Some("toml") => Self::from_toml(&content),
-            Some("json") => Self::from_json(&content),
-            _ => {
-                // Try TOML first (preferred), fall back to JSON
-                Self::from_toml(&content).or_else(|_| Self::from_json(&content))
+impl Config {
+    /// Load configuration from a file, auto-detecting TOML or JSON format.
+    ///
+    /// Files listed in a top-level `include` array are loaded first, relative
+    /// to the including file, and the including file is merged on top.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut provenance = Provenance::default();
+        let mut value = load_layered(path.as_ref(), &mut Vec::new(), &mut provenance)?;
+        if let Some(Value::String(saved)) = value.get("saved_config") {
+            let saved = PathBuf::from(saved);
+            if saved.exists() {
+                let content = fs::read_to_string(&saved)
+                    .with_context(|| format!("failed to read {}", saved.display()))?;
+                let layer = parse_document(&saved, &content)?;
+                let source = saved.display().to_string();
+                merge(&mut value, layer, "", Some(&source), &mut provenance);
             }
         }
+        Self::from_value(value, provenance, env::vars())
     }
 
     /// Parse configuration from TOML string
+    #[allow(dead_code)]
     pub fn from_toml(content: &str) -> Result<Self> {
-        toml::from_str(content).context("failed to parse config as TOML")
+        let value = toml::from_str(content).context("failed to parse config as TOML")?;
+        Self::from_document(value)
     }
 
     /// Parse configuration from JSON string
+    #[allow(dead_code)]
     pub fn from_json(content: &str) -> Result<Self> {
-        serde_json::from_str(content).context("failed to parse config as JSON")
+        let value = serde_json::from_str(content).context("failed to parse config as JSON")?;
+        Self::from_document(value)
+    }
+
+    fn from_document(value: Value) -> Result<Self> {
+        if value.get("include").is_some() {
+            bail!("`include` is only supported when loading config from a file");
+        }
+        Self::from_value(value, Provenance::default(), env::vars())
+    }
+
+    /// Build the configuration from a parsed document, expanding `${VAR}`
+    /// references and applying `SCHERZO__*` overrides from `vars`
+    fn from_value(
+        mut value: Value,
+        mut provenance: Provenance,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self> {
+        let vars: BTreeMap<String, String> = vars.into_iter().collect();
+
+        let secrets = match value.get("secrets_file") {
+            Some(Value::String(path)) => {
+                let path =
+                    interpolate_str(path, &Substitutions::env(&vars)).with_context(|| {
+                        format!("{}: invalid value", provenance.describe("secrets_file"))
+                    })?;
+                let secrets = Secrets::load(Path::new(&path)).with_context(|| {
+                    format!("failed to load {}", provenance.describe("secrets_file"))
+                })?;
+                value["secrets_file"] = Value::String(path);
+                Some(secrets)
+            }
+            Some(_) => bail!("{} must be a path", provenance.describe("secrets_file")),
+            None => None,
+        };
+
+        let substitutions = Substitutions {
+            vars: &vars,
+            secrets: secrets.as_ref(),
+        };
+        interpolate(&mut value, &substitutions, "", &provenance)?;
+        apply_overrides(&mut value, &vars, &mut provenance)?;
+        let mut config: Self = serde_json::from_value(value).context("invalid configuration")?;
+        config.provenance = provenance;
+        Ok(config)
     }
 
     /// Validate the configuration
     pub fn validate(&self) -> Result<()> {
+        let invalid = |key: &str, message: &str| {
+            anyhow::anyhow!("{} {message}", self.provenance.describe(key))
+        };
+
         // Ensure storage directory is valid
         if self.jobs.storage_dir.is_empty() {
-            anyhow::bail!("jobs.storage_dir cannot be empty");
+            return Err(invalid("jobs.storage_dir", "cannot be empty"));
+        }
+        if self.crash_reports.dir.is_empty() {
+            return Err(invalid("crash_reports.dir", "cannot be empty"));
+        }
+        if self.crash_reports.command_history == 0 {
+            return Err(invalid("crash_reports.command_history", "must be positive"));
+        }
+        if let Some(endpoint) = &self.telemetry.otlp_endpoint
+            && !(endpoint.starts_with("http://") || endpoint.starts_with("https://"))
+        {
+            return Err(invalid("telemetry.otlp_endpoint", "must be an http(s) URL"));
+        }
+        if self.telemetry.service_name.is_empty() {
+            return Err(invalid("telemetry.service_name", "cannot be empty"));
+        }
+        if let Err(err) = tracing_subscriber::EnvFilter::try_new(&self.telemetry.filter) {
+            return Err(invalid("telemetry.filter", &format!("is invalid: {err}")));
+        }
+        let interval = self.jobs.checkpoint_interval;
+        if !interval.is_finite() || interval < 0.0 {
+            return Err(invalid(
+                "jobs.checkpoint_interval",
+                "must be a non-negative number of seconds",
+            ));
         }
 
         // Validate auth if present
         if let Some(auth) = &self.server.auth {
             if auth.username.is_empty() {
-                anyhow::bail!("server.auth.username cannot be empty");
+                return Err(invalid("server.auth.username", "cannot be empty"));
             }
             if auth.password_hash.is_empty() {
-                anyhow::bail!("server.auth.password_hash cannot be empty");
+                return Err(invalid("server.auth.password_hash", "cannot be empty"));
+            }
+            if auth.api_keys.iter().any(String::is_empty) {
+                return Err(invalid("server.auth.api_keys", "cannot contain empty keys"));
+            }
+        }
+
+        if let Some(script) = &self.job_queue.between_jobs_gcode
+            && script.trim().is_empty()
+        {
+            return Err(invalid("job_queue.between_jobs_gcode", "cannot be empty"));
+        }
+
+        // Macro names are matched case-insensitively, like other commands
+        let mut macro_names = BTreeSet::new();
+        for (name, macro_config) in &self.macros {
+            let key = format!("macros.{name}");
+            check_macro(name, macro_config).map_err(|err| invalid(&key, &format!("{err:#}")))?;
+            if !macro_names.insert(name.to_ascii_uppercase()) {
+                return Err(invalid(&key, "differs from another macro only in case"));
             }
         }
 
+        if let Some(shaper) = &self.input_shaper {
+            let types = [
+                ("input_shaper.shaper_type_x", &shaper.shaper_type_x),
+                ("input_shaper.shaper_type_y", &shaper.shaper_type_y),
+            ];
+            for (key, shaper_type) in types {
+                if let Some(name) = shaper_type
+                    && ShaperType::parse(name).is_none()
+                {
+                    return Err(invalid(key, "is not a known shaper type"));
+                }
+            }
+            let freqs = [
+                ("input_shaper.shaper_freq_x", shaper.shaper_freq_x),
+                ("input_shaper.shaper_freq_y", shaper.shaper_freq_y),
+            ];
+            for (key, freq) in freqs {
+                if freq.is_some_and(|freq| !freq.is_finite() || freq < 0.0) {
+                    return Err(invalid(key, "must be a non-negative frequency"));
+                }
+            }
+        }
+
+        if let Some(mcu) = &self.mcu {
+            if mcu.serial.is_empty() {
+                return Err(invalid("mcu.serial", "cannot be empty"));
+            }
+            if mcu.baud == 0 {
+                return Err(invalid("mcu.baud", "must be positive"));
+            }
+        }
+
+        // Building the machine checks the printer and stepper sections
+        Machine::from_config(self)?;
+
         Ok(())
     }
 }
 
+/// Set `key` (a dotted path) to `value` in the saved config file at `path`,
+/// keeping everything else it holds
+pub fn save_config_value(path: &Path, key: &str, value: toml::Value) -> Result<()> {
+    let mut document = match fs::read_to_string(path) {
+        Ok(content) => toml::from_str::<toml::Table>(&content)
+            .with_context(|| format!("failed to parse {} as TOML", path.display()))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => toml::Table::new(),
+        Err(e) => {
+            return Err(e).with_context(|| format!("failed to read {}", path.display()));
+        }
+    };
+
+    let mut keys: Vec<_> = key.split('.').collect();
+    let last = keys.pop().context("empty config key")?;
+    let mut table = &mut document;
+    for key in keys {
+        let entry = table
+            .entry(key)
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        table = entry
+            .as_table_mut()
+            .with_context(|| format!("{}: `{key}` is not a table", path.display()))?;
+    }
+    table.insert(last.to_string(), value);
+
+    let content = format!(
+        "# Saved by scherzo; values here override the rest of the configuration\n{}",
+        toml::to_string(&document)?
+    );
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, content).with_context(|| format!("failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("failed to save {}", path.display()))
+}
+
+/// Parse a config file and everything it includes into one merged document.
+/// `stack` holds the files currently being loaded, to reject include cycles.
+fn load_layered(
+    path: &Path,
+    stack: &mut Vec<PathBuf>,
+    provenance: &mut Provenance,
+) -> Result<Value> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("failed to read config file {}", path.display()))?;
+    let mut value = parse_document(path, &content)?;
+
+    let canonical = fs::canonicalize(path)
+        .with_context(|| format!("failed to resolve config file {}", path.display()))?;
+    if stack.contains(&canonical) {
+        bail!("config include cycle through {}", path.display());
+    }
+    stack.push(canonical);
+
+    let includes = match value.as_object_mut().and_then(|map| map.remove("include")) {
+        Some(includes) => serde_json::from_value::<Vec<String>>(includes)
+            .with_context(|| format!("{}: `include` must be a list of paths", path.display()))?,
+        None => Vec::new(),
+    };
+
+    let base = path.parent().unwrap_or(Path::new(""));
+
+    // Resolve the secrets and saved config files relative to the config
+    // file that names them
+    for key in ["secrets_file", "saved_config"] {
+        if let Some(Value::String(file)) = value.get_mut(key)
+            && !file.starts_with("${")
+        {
+            *file = base.join(&*file).display().to_string();
+        }
+    }
+
+    let mut merged = Value::Object(Default::default());
+    for include in includes {
+        let included = load_layered(&base.join(include), stack, provenance)?;
+        merge(&mut merged, included, "", None, provenance);
+    }
+    let source = path.display().to_string();
+    merge(&mut merged, value, "", Some(&source), provenance);
+
+    stack.pop();
+    Ok(merged)
+}
+
+fn parse_document(path: &Path, content: &str) -> Result<Value> {
+    let parse_toml = || {
+        toml::from_str(content)
+            .with_context(|| format!("failed to parse {} as TOML", path.display()))
+    };
+    let parse_json = || {
+        serde_json::from_str(content)
+            .with_context(|| format!("failed to parse {} as JSON", path.display()))
+    };
+
+    // Try to determine format from extension
+    match path.extension().and_then(|s| s.to_str()) {
+        Some("toml") => parse_toml(),
+        Some("json") => parse_json(),
+        // Try TOML first (preferred), fall back to JSON
+        _ => parse_toml().or_else(|_| parse_json()),
+    }
+}
+
+/// Deep-merge `layer` into `base`: tables merge key by key, everything else
+/// (including arrays) is replaced. When `source` is set, each replaced key is
+/// attributed to it; included layers already recorded their own sources.
+fn merge(
+    base: &mut Value,
+    layer: Value,
+    path: &str,
+    source: Option<&str>,
+    provenance: &mut Provenance,
+) {
+    match (base, layer) {
+        (Value::Object(base), Value::Object(layer)) => {
+            for (key, value) in layer {
+                let path = join_key(path, &key);
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value, &path, source, provenance),
+                    None => {
+                        record_leaves(&value, &path, source, provenance);
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, layer) => {
+            record_leaves(&layer, path, source, provenance);
+            *base = layer;
+        }
+    }
+}
+
+fn record_leaves(value: &Value, path: &str, source: Option<&str>, provenance: &mut Provenance) {
+    let Some(source) = source else {
+        return;
+    };
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            for (key, value) in map {
+                record_leaves(value, &join_key(path, key), Some(source), provenance);
+            }
+        }
+        _ => provenance.record(path, source),
+    }
+}
+
+fn join_key(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{path}.{key}")
+    }
+}
+
+/// Values substituted into `${...}` references
+struct Substitutions<'a> {
+    vars: &'a BTreeMap<String, String>,
+    secrets: Option<&'a Secrets>,
+}
+
+impl<'a> Substitutions<'a> {
+    fn env(vars: &'a BTreeMap<String, String>) -> Self {
+        Self {
+            vars,
+            secrets: None,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Option<&'a str>> {
+        let Some(secret) = name.strip_prefix("secret:") else {
+            return Ok(self.vars.get(name).map(String::as_str));
+        };
+        let Some(secrets) = self.secrets else {
+            bail!("`${{secret:{secret}}}` is used but no secrets_file is configured");
+        };
+        Ok(secrets.values.get(secret).map(String::as_str))
+    }
+
+    fn missing(&self, name: &str) -> anyhow::Error {
+        match (name.strip_prefix("secret:"), self.secrets) {
+            (Some(secret), Some(secrets)) => anyhow::anyhow!(
+                "secret `{secret}` is not defined in {}",
+                secrets.path.display()
+            ),
+            _ => anyhow::anyhow!("environment variable `{name}` is not set"),
+        }
+    }
+}
+
+/// String values loaded from a `secrets_file`, keyed by dotted path
+struct Secrets {
+    path: PathBuf,
+    values: BTreeMap<String, String>,
+}
+
+impl Secrets {
+    fn load(path: &Path) -> Result<Self> {
+        check_secret_permissions(path)?;
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("failed to read secrets file {}", path.display()))?;
+        let table: Value = toml::from_str(&content)
+            .with_context(|| format!("failed to parse secrets file {} as TOML", path.display()))?;
+
+        let mut values = BTreeMap::new();
+        flatten_secrets(&table, "", &mut values)
+            .with_context(|| format!("invalid secrets file {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            values,
+        })
+    }
+}
+
+fn flatten_secrets(value: &Value, path: &str, out: &mut BTreeMap<String, String>) -> Result<()> {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                flatten_secrets(value, &join_key(path, key), out)?;
+            }
+        }
+        Value::String(s) => {
+            out.insert(path.to_string(), s.clone());
+        }
+        _ => bail!("{path}: secrets must be strings"),
+    }
+    Ok(())
+}
+
+/// Refuse secrets files that group or others can access
+#[cfg(unix)]
+fn check_secret_permissions(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let metadata = fs::metadata(path)
+        .with_context(|| format!("failed to read secrets file {}", path.display()))?;
+    let mode = metadata.permissions().mode() & 0o777;
+    if mode & 0o077 != 0 {
+        bail!(
+            "secrets file {path} has permissions {mode:04o} but must only be accessible by its owner; run `chmod 600 {path}`",
+            path = path.display()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_secret_permissions(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Replace `${VAR}` (or `${VAR:-default}`) and `${secret:NAME}` in every
+/// string value. `$$` is an escaped `$`.
+fn interpolate(
+    value: &mut Value,
+    substitutions: &Substitutions,
+    path: &str,
+    provenance: &Provenance,
+) -> Result<()> {
+    match value {
+        Value::String(s) if s.contains('$') => {
+            *s = interpolate_str(s, substitutions)
+                .with_context(|| format!("{}: invalid value", provenance.describe(path)))?;
+        }
+        Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                interpolate(item, substitutions, &format!("{path}[{i}]"), provenance)?;
+            }
+        }
+        Value::Object(map) => {
+            for (key, item) in map.iter_mut() {
+                interpolate(item, substitutions, &join_key(path, key), provenance)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn interpolate_str(input: &str, substitutions: &Substitutions) -> Result<String> {
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+
+        if let Some(tail) = rest.strip_prefix("$$") {
+            out.push('$');
+            rest = tail;
+        } else if let Some(tail) = rest.strip_prefix("${") {
+            let Some(end) = tail.find('}') else {
+                bail!("unterminated `${{` in `{input}`");
+            };
+            let (name, default) = match tail[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&tail[..end], None),
+            };
+            if name.is_empty() {
+                bail!("empty variable name in `{input}`");
+            }
+            match (substitutions.lookup(name)?, default) {
+                (Some(value), _) => out.push_str(value),
+                (None, Some(default)) => out.push_str(default),
+                (None, None) => return Err(substitutions.missing(name)),
+            }
+            rest = &tail[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Apply `SCHERZO__SECTION__KEY=value` overrides. Values are parsed as TOML
+/// literals when possible (`8080`, `true`, `["a"]`) and used as strings
+/// otherwise.
+fn apply_overrides(
+    value: &mut Value,
+    vars: &BTreeMap<String, String>,
+    provenance: &mut Provenance,
+) -> Result<()> {
+    for (name, raw) in vars {
+        let Some(path) = name.strip_prefix(ENV_OVERRIDE_PREFIX) else {
+            continue;
+        };
+        let keys: Vec<String> = path.split("__").map(str::to_lowercase).collect();
+        if keys.iter().any(String::is_empty) {
+            bail!("{name}: invalid config override name");
+        }
+
+        let mut target = &mut *value;
+        for key in &keys {
+            if !target.is_object() {
+                bail!("{name}: `{}` is not a table", keys.join("."));
+            }
+            target = target
+                .as_object_mut()
+                .unwrap()
+                .entry(key.clone())
+                .or_insert_with(|| Value::Object(Default::default()));
+        }
+        *target = parse_override(raw);
+        provenance.record(&keys.join("."), &format!("environment variable {name}"));
+    }
+    Ok(())
+}
+
+fn parse_override(raw: &str) -> Value {
+    toml::from_str::<BTreeMap<String, Value>>(&format!("value = {raw}"))
+        .ok()
+        .and_then(|mut doc| doc.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.to_string()))
+}
+
 /// Helper function to hash a password with bcrypt
-#[allow(dead_code)]
 pub fn hash_password(password: &str) -> Result<String> {
     bcrypt::hash(password, bcrypt::DEFAULT_COST).context("failed to hash password")
 }
@@ -210,6 +1623,197 @@ max_size_bytes = 52428800
         assert_eq!(config.jobs.storage_dir, "./jobs");
     }
 
+    #[test]
+    fn test_template_matches_defaults() {
+        let config = Config::from_toml(TEMPLATE).unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.server.port, default_port());
+        assert_eq!(config.server.host, default_host());
+        assert_eq!(config.jobs.storage_dir, default_jobs_dir());
+        assert_eq!(config.jobs.max_size_bytes, default_max_job_size());
+        assert!(config.plugin_config.is_empty());
+    }
+
+    #[test]
+    fn test_telemetry() {
+        let config = Config::from_toml("").unwrap();
+        assert_eq!(config.telemetry.otlp_endpoint, None);
+        assert_eq!(config.telemetry.filter, "info");
+
+        let config = "[telemetry]\notlp_endpoint = \"localhost:4318\"\n";
+        let err = Config::from_toml(config).unwrap().validate().unwrap_err();
+        assert!(err.to_string().contains("telemetry.otlp_endpoint"), "{err}");
+        let config = "[telemetry]\nfilter = \"scherzo=loud\"\n";
+        let err = Config::from_toml(config).unwrap().validate().unwrap_err();
+        assert!(err.to_string().contains("telemetry.filter"), "{err}");
+    }
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let value = serde_json::json!({
+            "jobs": { "storage_dir": "${DATA}/jobs" },
+            "plugins": ["${PLUGINS:-/opt/plugins}/a.wasm", "$$HOME/b.wasm"],
+        });
+        let config = Config::from_value(
+            value.clone(),
+            Provenance::default(),
+            vars(&[("DATA", "/srv")]),
+        )
+        .unwrap();
+        assert_eq!(config.jobs.storage_dir, "/srv/jobs");
+        assert_eq!(config.plugins, ["/opt/plugins/a.wasm", "$HOME/b.wasm"]);
+
+        let err = Config::from_value(value, Provenance::default(), vars(&[])).unwrap_err();
+        assert!(format!("{err:#}").contains("jobs.storage_dir"));
+        assert!(format!("{err:#}").contains("`DATA` is not set"));
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let value = serde_json::json!({ "server": { "port": 8080 } });
+        let config = Config::from_value(
+            value,
+            Provenance::default(),
+            vars(&[
+                ("SCHERZO__SERVER__PORT", "9090"),
+                ("SCHERZO__SERVER__HOST", "0.0.0.0"),
+                ("SCHERZO__PLUGINS", r#"["/a.wasm"]"#),
+                ("SCHERZO_URL", "http://ignored"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(config.server.port, 9090);
+        assert_eq!(config.server.host, "0.0.0.0");
+        assert_eq!(config.plugins, ["/a.wasm"]);
+
+        let value = serde_json::json!({ "server": { "port": 8080 } });
+        let overrides = vars(&[("SCHERZO__SERVER__PORT__X", "1")]);
+        assert!(Config::from_value(value, Provenance::default(), overrides).is_err());
+    }
+
+    #[test]
+    fn test_includes() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("printers")).unwrap();
+        fs::write(
+            dir.path().join("printers/base.toml"),
+            "plugins = [\"/base.wasm\"]\n[server]\nport = 4000\nhost = \"0.0.0.0\"\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("printers/jobs.json"),
+            r#"{ "jobs": { "storage_dir": "" } }"#,
+        )
+        .unwrap();
+        let main = dir.path().join("scherzo.toml");
+        fs::write(
+            &main,
+            "include = [\"printers/base.toml\", \"printers/jobs.json\"]\nplugins = [\"/main.wasm\"]\n[server]\nport = 5000\n",
+        )
+        .unwrap();
+
+        let config = Config::from_file(&main).unwrap();
+        assert_eq!(config.server.port, 5000);
+        assert_eq!(config.server.host, "0.0.0.0");
+        assert_eq!(config.plugins, ["/main.wasm"]);
+
+        let source = |key| config.provenance.source(key).unwrap().to_string();
+        assert!(source("server.port").ends_with("scherzo.toml"));
+        assert!(source("server.host").ends_with("base.toml"));
+        assert!(source("jobs.storage_dir").ends_with("jobs.json"));
+
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.starts_with("jobs.storage_dir (from "), "{err}");
+        assert!(err.contains("jobs.json"), "{err}");
+    }
+
+    #[test]
+    fn test_saved_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let main = dir.path().join("scherzo.toml");
+        fs::write(
+            &main,
+            "saved_config = \"saved.toml\"\n[server]\nport = 5000\n[bed_mesh]\nmesh_min = [0, 0]\nmesh_max = [10, 10]\n",
+        )
+        .unwrap();
+        let config = Config::from_file(&main).unwrap();
+        assert!(config.bed_mesh.unwrap().mesh.is_none());
+
+        let saved = dir.path().join("saved.toml");
+        let mesh = MeshConfig {
+            min: [0.0, 0.0],
+            max: [10.0, 10.0],
+            points: vec![vec![0.0, 0.1], vec![0.2, 0.3]],
+        };
+        let value = toml::Value::try_from(&mesh).unwrap();
+        save_config_value(&saved, "server.port", toml::Value::Integer(6000)).unwrap();
+        save_config_value(&saved, "bed_mesh.mesh", value).unwrap();
+
+        let config = Config::from_file(&main).unwrap();
+        assert_eq!(config.server.port, 6000);
+        assert_eq!(config.bed_mesh.unwrap().mesh, Some(mesh));
+        let source = config.provenance.source("bed_mesh.mesh.points").unwrap();
+        assert!(source.ends_with("saved.toml"));
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.toml"), "include = [\"b.toml\"]\n").unwrap();
+        fs::write(dir.path().join("b.toml"), "include = [\"a.toml\"]\n").unwrap();
+        let err = Config::from_file(dir.path().join("a.toml")).unwrap_err();
+        assert!(format!("{err:#}").contains("cycle"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_secrets_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let secrets = dir.path().join("secrets.toml");
+        fs::write(
+            &secrets,
+            "password_hash = \"$2b$12$abc\"\n[mqtt]\npassword = \"hunter2\"\n",
+        )
+        .unwrap();
+        fs::set_permissions(&secrets, fs::Permissions::from_mode(0o600)).unwrap();
+
+        let main = dir.path().join("scherzo.toml");
+        fs::write(
+            &main,
+            r#"secrets_file = "secrets.toml"
+plugin_config = { mqtt = { password = "${secret:mqtt.password}" } }
+
+[server.auth]
+username = "admin"
+password_hash = "${secret:password_hash}"
+"#,
+        )
+        .unwrap();
+
+        let config = Config::from_file(&main).unwrap();
+        assert_eq!(config.server.auth.unwrap().password_hash, "$2b$12$abc");
+        assert_eq!(config.plugin_config["mqtt"]["password"], "hunter2");
+
+        fs::set_permissions(&secrets, fs::Permissions::from_mode(0o644)).unwrap();
+        let err = format!("{:#}", Config::from_file(&main).unwrap_err());
+        assert!(err.contains("0644"), "{err}");
+        assert!(err.contains("chmod 600"), "{err}");
+
+        fs::set_permissions(&secrets, fs::Permissions::from_mode(0o600)).unwrap();
+        fs::write(&main, "[jobs]\nstorage_dir = \"${secret:missing}\"\n").unwrap();
+        let err = format!("{:#}", Config::from_file(&main).unwrap_err());
+        assert!(err.contains("no secrets_file"), "{err}");
+    }
+
     #[test]
     fn test_password_hashing() {
         let password = "test123";
@@ -0,0 +1,566 @@
+//! G-code console and macros
+//!
+//! Scripts sent to the console run a line at a time, without compiling to a
+//! component. Classic commands go straight to their handlers in the
+//! [`CommandRegistry`]: moves, dwells, and the modal commands that shape them
+//! (`G90`, `G92`, `M83`, ...) are interpreted as in jobs and queued on the
+//! executor, `M400` waits for queued moves to finish, and `M118` or
+//! `RESPOND MSG=...` add a line to the output. The `EXCLUDE_OBJECT` markers
+//! tell the machine which object the moves after them belong to, so those of
+//! excluded objects are skipped.
+//!
+//! Scripts run one at a time, except that a script made only of immediate or
+//! out-of-band commands, like `M112` or `M114`, runs at once, even while
+//! another script is running.
+//!
+//! A line naming a macro, from `[macros]` or registered by a plugin, renders
+//! the macro's template with the line's `NAME=value` parameters as `params`
+//! and the printer state as `printer`, as Klipper's `gcode_macro` does. The
+//! result runs the same way, so macros can call other macros.
+
+use crate::{
+    config::{Config, MacroConfig},
+    dispatch::{Command, CommandRegistry, DispatchContext, classic_command},
+    executor::Executor,
+    overrides::OverrideController,
+    plugin::PluginRegistry,
+};
+use anyhow::{Context, Result, bail};
+use scherzo_compile::{estimate::MotionInterpreter, objects::ObjectCommand, template::Template};
+use serde::Serialize;
+use serde_json::{Map, Value, json};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+/// Deepest macros may call each other
+const MAX_MACRO_DEPTH: usize = 16;
+
+/// Extended commands the console handles itself
+const BUILTIN_COMMANDS: [&str; 1] = ["RESPOND"];
+
+/// Printer state macros see as `printer`
+type StatusHook = Box<dyn Fn() -> Map<String, Value> + Send + Sync>;
+
+/// Told of each line before it runs
+type CommandHook = Box<dyn Fn(&str) + Send + Sync>;
+
+/// A macro the console can run
+#[derive(Debug, Clone, Serialize)]
+pub struct MacroInfo {
+    pub name: String,
+    pub description: Option<String>,
+    /// Where the macro is defined: `config` or `plugin`
+    pub source: &'static str,
+}
+
+struct Inner {
+    executor: Option<Arc<Executor>>,
+    /// Macros from `[macros]` by upper-cased name
+    macros: BTreeMap<String, MacroConfig>,
+    plugins: PluginRegistry,
+    commands: CommandRegistry,
+    interpreter: Mutex<MotionInterpreter>,
+    overrides: OverrideController,
+    status_hook: Mutex<Option<StatusHook>>,
+    command_hook: Mutex<Option<CommandHook>>,
+}
+
+/// Runs G-code scripts and macros on the machine
+#[derive(Clone)]
+pub struct Console {
+    inner: Arc<Inner>,
+}
+
+impl Console {
+    /// Run the macros in `config` and those registered with `plugins`,
+    /// moving the machine through `executor` if there is one. Macros in the
+    /// config take precedence over plugin macros of the same name.
+    pub fn new(config: &Config, executor: Option<Arc<Executor>>, plugins: PluginRegistry) -> Self {
+        let macros = config
+            .macros
+            .iter()
+            .map(|(name, macro_config)| (name.to_ascii_uppercase(), macro_config.clone()))
+            .collect();
+        let mut interpreter = MotionInterpreter::new();
+        interpreter.set_retraction(executor.as_ref().and_then(|executor| executor.retraction()));
+        Self {
+            inner: Arc::new(Inner {
+                executor,
+                macros,
+                commands: CommandRegistry::new(plugins.clone()),
+                plugins,
+                interpreter: Mutex::new(interpreter),
+                overrides: OverrideController::default(),
+                status_hook: Mutex::new(None),
+                command_hook: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Call `hook` for the printer state macros see as `printer`
+    pub fn set_status_hook(&self, hook: impl Fn() -> Map<String, Value> + Send + Sync + 'static) {
+        *self
+            .inner
+            .status_hook
+            .lock()
+            .unwrap_or_else(|err| err.into_inner()) = Some(Box::new(hook));
+    }
+
+    /// Call `hook` with each line before it runs, including the lines of
+    /// macros
+    pub fn set_command_hook(&self, hook: impl Fn(&str) + Send + Sync + 'static) {
+        *self
+            .inner
+            .command_hook
+            .lock()
+            .unwrap_or_else(|err| err.into_inner()) = Some(Box::new(hook));
+    }
+
+    /// Handlers of the commands scripts run, which jobs share
+    pub fn commands(&self) -> &CommandRegistry {
+        &self.inner.commands
+    }
+
+    /// Overrides applied to the moves scripts and jobs make
+    pub fn overrides(&self) -> &OverrideController {
+        &self.inner.overrides
+    }
+
+    /// Turn `G10`/`G11` into the printer's firmware retraction moves, or
+    /// ignore them. Either way the filament starts out unretracted.
+    pub fn set_firmware_retraction(&self, enabled: bool) {
+        let config = self
+            .inner
+            .executor
+            .as_ref()
+            .and_then(|executor| executor.retraction())
+            .filter(|_| enabled);
+        self.inner
+            .interpreter
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .set_retraction(config);
+    }
+
+    /// Wait for the moves queued so far to finish, if there is a printer
+    pub fn wait_moves(&self) -> Result<()> {
+        match &self.inner.executor {
+            Some(executor) => executor.wait_moves(),
+            None => Ok(()),
+        }
+    }
+
+    /// Every macro the console can run, by name
+    pub fn macros(&self) -> Vec<MacroInfo> {
+        let mut macros: BTreeMap<String, MacroInfo> = BTreeMap::new();
+        for (name, macro_config) in self.inner.plugins.get_macros() {
+            let info = MacroInfo {
+                name: name.clone(),
+                description: macro_config.description,
+                source: "plugin",
+            };
+            macros.insert(name, info);
+        }
+        for (name, macro_config) in &self.inner.macros {
+            let info = MacroInfo {
+                name: name.clone(),
+                description: macro_config.description.clone(),
+                source: "config",
+            };
+            macros.insert(name.clone(), info);
+        }
+        macros.into_values().collect()
+    }
+
+    /// Run `script`, returning the lines it responded with. Scripts run one
+    /// at a time, waiting for any running one to finish, unless none of
+    /// their commands are ordered.
+    pub fn run_script(&self, script: &str) -> Result<Vec<String>> {
+        if self.is_unordered(script) {
+            // Such commands leave the motion state alone
+            let mut interpreter = MotionInterpreter::new();
+            let mut output = Vec::new();
+            self.run(script, &mut interpreter, &mut output, 0)?;
+            return Ok(output);
+        }
+        let mut interpreter = self
+            .inner
+            .interpreter
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        let mut output = Vec::new();
+        self.run(script, &mut interpreter, &mut output, 0)?;
+        Ok(output)
+    }
+
+    /// Whether `script` is only commands that run as soon as they are sent
+    fn is_unordered(&self, script: &str) -> bool {
+        let mut lines = script
+            .lines()
+            .map(|line| strip_comment(line).trim())
+            .filter(|line| !line.is_empty())
+            .peekable();
+        lines.peek().is_some()
+            && lines.all(|line| {
+                let class = Command::parse(line)
+                    .and_then(|command| self.inner.commands.scheduling_class(&command.verb));
+                class.is_some_and(|class| !class.is_ordered())
+            })
+    }
+
+    fn run(
+        &self,
+        script: &str,
+        interpreter: &mut MotionInterpreter,
+        output: &mut Vec<String>,
+        depth: usize,
+    ) -> Result<()> {
+        for (index, line) in script.lines().enumerate() {
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(hook) = &*self
+                .inner
+                .command_hook
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+            {
+                hook(line);
+            }
+            self.run_line(line, interpreter, output, depth)
+                .with_context(|| format!("line {}: {line}", index + 1))?;
+        }
+        Ok(())
+    }
+
+    fn run_line(
+        &self,
+        line: &str,
+        interpreter: &mut MotionInterpreter,
+        output: &mut Vec<String>,
+        depth: usize,
+    ) -> Result<()> {
+        if let Some(command) = Command::parse(line) {
+            let mut ctx = DispatchContext {
+                executor: self.inner.executor.as_deref(),
+                interpreter,
+                overrides: Some(&self.inner.overrides),
+                output,
+            };
+            return self.inner.commands.dispatch(&command, &mut ctx);
+        }
+
+        let (command, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+
+        let command = command.to_ascii_uppercase();
+        let params = extended_params(args)?;
+        if command == "RESPOND" {
+            let message = params.get("MSG").map(|msg| msg.to_string());
+            output.push(message.unwrap_or_default());
+            return Ok(());
+        }
+        if let Some(object) = object_command(line) {
+            if let Some(executor) = &self.inner.executor {
+                match object {
+                    ObjectCommand::Define(_) => {}
+                    ObjectCommand::Start(name) => executor.set_current_object(Some(&name)),
+                    ObjectCommand::End => executor.set_current_object(None),
+                    ObjectCommand::Exclude(name) => executor.exclude_object(&name),
+                }
+            }
+            return Ok(());
+        }
+
+        let macro_config = self
+            .find_macro(&command)
+            .with_context(|| format!("unknown command `{command}`"))?;
+        if depth >= MAX_MACRO_DEPTH {
+            bail!("macros are nested more than {MAX_MACRO_DEPTH} deep");
+        }
+        let template = Template::parse(&macro_config.gcode)
+            .with_context(|| format!("invalid template in macro {command}"))?;
+        let mut context = Map::new();
+        let params = params.into_iter().map(|(k, v)| (k, Value::String(v)));
+        context.insert("params".into(), Value::Object(params.collect()));
+        context.insert("printer".into(), Value::Object(self.printer(interpreter)));
+        let script = template
+            .render(&context)
+            .with_context(|| format!("in macro {command}"))?;
+        self.run(&script, interpreter, output, depth + 1)
+            .with_context(|| format!("in macro {command}"))
+    }
+
+    fn find_macro(&self, name: &str) -> Option<MacroConfig> {
+        match self.inner.macros.get(name) {
+            Some(macro_config) => Some(macro_config.clone()),
+            None => self.inner.plugins.get_macro(name),
+        }
+    }
+
+    /// Printer state for macro templates, with the console's own G-code
+    /// state as `gcode_move`
+    fn printer(&self, interpreter: &mut MotionInterpreter) -> Map<String, Value> {
+        let mut printer = match &*self
+            .inner
+            .status_hook
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+        {
+            Some(hook) => hook(),
+            None => Map::new(),
+        };
+        if let Some(executor) = &self.inner.executor {
+            interpreter.sync_position(executor.position());
+        }
+        let position = interpreter.position();
+        let offsets = interpreter.offsets();
+        let gcode_position = std::array::from_fn(|axis| position[axis] - offsets[axis]);
+        printer.insert(
+            "gcode_move".into(),
+            json!({
+                "position": coord(position),
+                "gcode_position": coord(gcode_position),
+                "homing_origin": coord(offsets),
+                "absolute_coordinates": interpreter.is_absolute(),
+                "absolute_extrude": interpreter.is_absolute_e(),
+                "speed": interpreter.speed(),
+            }),
+        );
+        printer
+    }
+}
+
+/// Check that `name` can be sent as a command and that the macro's template
+/// parses
+pub fn check_macro(name: &str, macro_config: &MacroConfig) -> Result<()> {
+    let is_word = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    let upper = name.to_ascii_uppercase();
+    if !is_word || classic_command(name).is_some() || BUILTIN_COMMANDS.contains(&upper.as_str()) {
+        bail!("`{name}` cannot be used as a macro name");
+    }
+    Template::parse(&macro_config.gcode).context("invalid template")?;
+    Ok(())
+}
+
+/// The `EXCLUDE_OBJECT` marker `line` is, if it is one
+fn object_command(line: &str) -> Option<ObjectCommand> {
+    let statements = scherzo_gcode::parse(&format!("{line}\n")).ok()?;
+    ObjectCommand::parse(statements.first()?)
+}
+
+/// `line` up to a `;` or `#` comment outside quotes
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, ';' | '#') => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// `NAME=value` parameters of an extended command, by upper-cased name.
+/// Values may be quoted to include spaces.
+fn extended_params(args: &str) -> Result<BTreeMap<String, String>> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quote = None;
+    let mut in_word = false;
+    for c in args.chars() {
+        match (quote, c) {
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (Some(q), c) if c == q => quote = None,
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (_, c) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if quote.is_some() {
+        bail!("unterminated quote in `{args}`");
+    }
+    if in_word {
+        words.push(word);
+    }
+    words
+        .into_iter()
+        .map(|word| {
+            let (name, value) = word
+                .split_once('=')
+                .with_context(|| format!("malformed parameter `{word}`, expected NAME=value"))?;
+            Ok((name.to_ascii_uppercase(), value.to_string()))
+        })
+        .collect()
+}
+
+fn coord(position: [f64; 4]) -> Value {
+    let [x, y, z, e] = position;
+    json!({ "x": x, "y": y, "z": z, "e": e })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        executor::{HOST_CLOCK_FREQ, HostClock},
+        machine::Machine,
+        plugin::{CommandHandler, SchedulingClass},
+    };
+    use std::{sync::mpsc, time::Duration};
+
+    const PRINTER: &str = r#"
+[printer]
+kinematics = "cartesian"
+max_velocity = 300
+max_accel = 3000
+buffer_time_start = 0.05
+
+[stepper_x]
+rotation_distance = 40
+microsteps = 16
+position_max = 100
+
+[stepper_y]
+rotation_distance = 40
+microsteps = 16
+position_max = 100
+
+[stepper_z]
+rotation_distance = 8
+microsteps = 16
+position_max = 50
+
+[macros.park]
+description = "Move to a corner"
+gcode = """
+{% set x = params.X|default(90)|float %}
+G90
+G1 X{x} Y{printer.gcode_move.position.y + 5} F6000
+RESPOND MSG="parked at {x}"
+"""
+
+[macros.LOOP]
+gcode = "LOOP"
+"#;
+
+    #[test]
+    fn test_runs_macros_and_moves() {
+        let config = Config::from_toml(PRINTER).unwrap();
+        config.validate().unwrap();
+        let machine = Machine::from_config(&config).unwrap().unwrap();
+        let machine = Arc::new(Mutex::new(machine));
+        let executor = Arc::new(Executor::spawn(machine, HostClock::new(HOST_CLOCK_FREQ)));
+        let plugins = PluginRegistry::new();
+        let prime = MacroConfig {
+            description: None,
+            gcode: "M83\nG1 E{params.LENGTH} F300\nPARK X={params.LENGTH|float * 10}".into(),
+        };
+        plugins.register_macro("PRIME", prime).unwrap();
+        let console = Console::new(&config, Some(executor.clone()), plugins);
+        console.set_status_hook(|| {
+            let mut printer = Map::new();
+            printer.insert("pause_resume".into(), json!({ "is_paused": false }));
+            printer
+        });
+
+        let names: Vec<_> = console.macros().into_iter().map(|m| m.name).collect();
+        assert_eq!(names, ["LOOP", "PARK", "PRIME"]);
+
+        let output = console
+            .run_script("G91\nG1 X10 Y10 F6000 ; relative\nM118 hello\nprime length=2")
+            .unwrap();
+        assert_eq!(output, ["hello", "parked at 20.0"]);
+        assert_eq!(executor.position(), [20.0, 15.0, 0.0, 2.0]);
+
+        // Modal state carries over to the next script
+        console.run_script("G92 X0\nG1 X5\nM400").unwrap();
+        assert_eq!(executor.position()[0], 25.0);
+
+        let err = |script: &str| format!("{:#}", console.run_script(script).unwrap_err());
+        assert!(err("FROB").contains("unknown command `FROB`"));
+        assert!(err("M104 S200").contains("unknown command `M104`"));
+        assert!(err("LOOP").contains("nested more than 16 deep"));
+        assert!(err("PARK X=\"1").contains("unterminated quote"));
+
+        assert!(
+            check_macro(
+                "G29",
+                &MacroConfig {
+                    description: None,
+                    gcode: String::new()
+                }
+            )
+            .is_err()
+        );
+        assert!(
+            check_macro(
+                "respond",
+                &MacroConfig {
+                    description: None,
+                    gcode: String::new()
+                }
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_unordered_commands_skip_the_running_script() {
+        let config = Config::from_toml("").unwrap();
+        let plugins = PluginRegistry::new();
+        for (command, scheduling_class) in [
+            ("M108", SchedulingClass::Immediate),
+            ("M104", SchedulingClass::Queued),
+        ] {
+            let handler = CommandHandler {
+                command: command.to_string(),
+                params: Vec::new(),
+                description: None,
+                scheduling_class,
+            };
+            plugins.register_command_handler(handler).unwrap();
+        }
+        let console = Console::new(&config, None, plugins);
+
+        // As if a script were running
+        let _running = console.inner.interpreter.lock().unwrap();
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn({
+            let console = console.clone();
+            move || {
+                tx.send(
+                    console
+                        .run_script("M108 ; stop heating")
+                        .map_err(|e| format!("{e:#}")),
+                )
+            }
+        });
+        let result = rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("M108 waited for the running script");
+        assert!(result.unwrap_err().contains("handled by a plugin"));
+
+        assert!(console.is_unordered("M112\nM114"));
+        assert!(!console.is_unordered("M108\nM104 S200"));
+        assert!(!console.is_unordered("; nothing"));
+    }
+}
@@ -0,0 +1,297 @@
+//! Crash reports
+//!
+//! When step generation fails or its thread panics, or a plugin traps, a
+//! crash report is written to `crash_reports.dir`: the commands run just
+//! before, the moves the toolhead was making, the loaded plugins, and a hash
+//! of the configuration. Reports are kept across restarts and the latest is
+//! served by `GET /debug/crash_report`.
+
+use crate::{
+    config::Config,
+    machine::Machine,
+    plugin::{PluginInfo, PluginRegistry},
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// Most trapq moves kept in a report
+const TRAPQ_MOVES: usize = 50;
+
+/// What went wrong
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultKind {
+    /// The flush thread panicked
+    ExecutorPanic,
+    /// Step generation failed
+    MotionError,
+    /// A plugin trapped
+    PluginTrap,
+}
+
+/// A command run before the fault
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandRecord {
+    /// When the command ran (RFC 3339)
+    pub time: String,
+    /// What ran the command, e.g. `console`
+    pub source: String,
+    pub command: String,
+}
+
+/// A move on the toolhead trapq
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrapqMove {
+    pub print_time: f64,
+    pub move_t: f64,
+    pub start_v: f64,
+    pub accel: f64,
+    pub start: [f64; 3],
+    /// Direction of the move, as a unit vector
+    pub axes_r: [f64; 3],
+}
+
+/// The motion system when the fault happened
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MotionSnapshot {
+    /// Position moves were last queued to
+    pub position: [f64; 4],
+    pub print_time: f64,
+    /// Latest moves on the toolhead trapq, oldest first
+    pub moves: Vec<TrapqMove>,
+}
+
+impl MotionSnapshot {
+    pub fn capture(machine: &Machine) -> Self {
+        let mut moves: Vec<_> = machine
+            .toolhead
+            .trapq()
+            .extract_old(TRAPQ_MOVES, f64::NEG_INFINITY, f64::INFINITY)
+            .into_iter()
+            .map(|mv| TrapqMove {
+                print_time: mv.print_time,
+                move_t: mv.move_t,
+                start_v: mv.start_v,
+                accel: mv.accel,
+                start: mv.start_pos.axes,
+                axes_r: mv.axes_r.axes,
+            })
+            .collect();
+        moves.sort_by(|a, b| a.print_time.total_cmp(&b.print_time));
+        Self {
+            position: machine.commanded_position(),
+            print_time: machine.toolhead.print_time(),
+            moves,
+        }
+    }
+}
+
+/// Everything saved about a fault
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrashReport {
+    /// When the fault happened (RFC 3339)
+    pub time: String,
+    pub kind: FaultKind,
+    pub message: String,
+    /// FNV-1a hash of the configuration in effect, to tell apart reports
+    /// from different configurations
+    pub config_hash: String,
+    /// Commands run before the fault, oldest first
+    pub commands: Vec<CommandRecord>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motion: Option<MotionSnapshot>,
+    pub plugins: Vec<PluginInfo>,
+}
+
+struct Inner {
+    dir: PathBuf,
+    command_history: usize,
+    config_hash: String,
+    plugins: PluginRegistry,
+    commands: Mutex<VecDeque<CommandRecord>>,
+}
+
+/// Keeps recent commands and writes crash reports
+#[derive(Clone)]
+pub struct CrashReporter {
+    inner: Arc<Inner>,
+}
+
+impl CrashReporter {
+    /// Write reports to the directory in `config`, listing the plugins in
+    /// `plugins`
+    pub fn new(config: &Config, plugins: PluginRegistry) -> Self {
+        let json = serde_json::to_vec(config).unwrap_or_default();
+        Self {
+            inner: Arc::new(Inner {
+                dir: PathBuf::from(&config.crash_reports.dir),
+                command_history: config.crash_reports.command_history,
+                config_hash: format!("{:016x}", fnv1a(&json)),
+                plugins,
+                commands: Mutex::new(VecDeque::new()),
+            }),
+        }
+    }
+
+    /// Remember `command`, run by `source`, for the next report
+    pub fn record_command(&self, source: &str, command: &str) {
+        let mut commands = self.commands();
+        while commands.len() >= self.inner.command_history.max(1) {
+            commands.pop_front();
+        }
+        commands.push_back(CommandRecord {
+            time: chrono::Utc::now().to_rfc3339(),
+            source: source.to_string(),
+            command: command.to_string(),
+        });
+    }
+
+    /// Write a report of a fault, returning where it was saved
+    pub fn report(
+        &self,
+        kind: FaultKind,
+        message: &str,
+        motion: Option<MotionSnapshot>,
+    ) -> Result<PathBuf> {
+        let now = chrono::Utc::now();
+        let mut plugins: Vec<_> = self.inner.plugins.get_plugins().into_values().collect();
+        plugins.sort_by(|a, b| a.id.cmp(&b.id));
+        let report = CrashReport {
+            time: now.to_rfc3339(),
+            kind,
+            message: message.to_string(),
+            config_hash: self.inner.config_hash.clone(),
+            commands: self.commands().iter().cloned().collect(),
+            motion,
+            plugins,
+        };
+
+        fs::create_dir_all(&self.inner.dir).context("failed to create crash report directory")?;
+        let name = format!("crash-{}.json", now.format("%Y%m%dT%H%M%S%.6fZ"));
+        let path = self.inner.dir.join(name);
+        let json = serde_json::to_vec_pretty(&report)?;
+        fs::write(&path, json)
+            .with_context(|| format!("failed to write crash report {}", path.display()))?;
+        tracing::error!("Crash report written to {}", path.display());
+        Ok(path)
+    }
+
+    /// The most recent report, if any was written
+    pub fn latest(&self) -> Result<Option<CrashReport>> {
+        let entries = match fs::read_dir(&self.inner.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("failed to read crash report directory"),
+        };
+        // Names sort by the time they were written
+        let mut latest = None;
+        for entry in entries {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if name.starts_with("crash-")
+                && name.ends_with(".json")
+                && latest.as_ref().is_none_or(|latest| name > *latest)
+            {
+                latest = Some(name);
+            }
+        }
+        let Some(name) = latest else {
+            return Ok(None);
+        };
+        let path = self.inner.dir.join(name);
+        let json = fs::read(&path)
+            .with_context(|| format!("failed to read crash report {}", path.display()))?;
+        Ok(Some(serde_json::from_slice(&json)?))
+    }
+
+    fn commands(&self) -> MutexGuard<'_, VecDeque<CommandRecord>> {
+        self.inner
+            .commands
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// 64-bit FNV-1a, which unlike the standard library's hashers is the same
+/// across releases
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRINTER: &str = r#"
+[printer]
+kinematics = "cartesian"
+max_velocity = 300
+max_accel = 3000
+
+[stepper_x]
+rotation_distance = 40
+microsteps = 16
+position_max = 100
+
+[stepper_y]
+rotation_distance = 40
+microsteps = 16
+position_max = 100
+
+[stepper_z]
+rotation_distance = 8
+microsteps = 16
+position_max = 50
+"#;
+
+    #[test]
+    fn test_reports_recent_commands_and_moves() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::from_toml(PRINTER).unwrap();
+        config.crash_reports.dir = dir.path().display().to_string();
+        config.crash_reports.command_history = 2;
+        let crash = CrashReporter::new(&config, PluginRegistry::new());
+        assert!(crash.latest().unwrap().is_none());
+
+        for command in ["G28", "G1 X10", "G1 Y10"] {
+            crash.record_command("console", command);
+        }
+        let mut machine = Machine::from_config(&config).unwrap().unwrap();
+        machine.queue_move([10.0, 0.0, 0.0, 0.0], 100.0).unwrap();
+        machine.queue_move([10.0, 10.0, 0.0, 0.0], 100.0).unwrap();
+        machine.flush_moves(0.0).unwrap();
+        let motion = MotionSnapshot::capture(&machine);
+        crash
+            .report(FaultKind::MotionError, "first", Some(motion))
+            .unwrap();
+        crash.report(FaultKind::PluginTrap, "second", None).unwrap();
+
+        let latest = crash.latest().unwrap().unwrap();
+        assert_eq!(latest.kind, FaultKind::PluginTrap);
+        assert_eq!(latest.message, "second");
+        let commands: Vec<_> = latest.commands.iter().map(|c| &c.command[..]).collect();
+        assert_eq!(commands, ["G1 X10", "G1 Y10"]);
+        assert_eq!(latest.config_hash.len(), 16);
+
+        let first = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .min()
+            .unwrap();
+        let first: CrashReport = serde_json::from_slice(&fs::read(first).unwrap()).unwrap();
+        let motion = first.motion.unwrap();
+        assert_eq!(motion.position, [10.0, 10.0, 0.0, 0.0]);
+        // Each move is split into its acceleration, cruise, and deceleration
+        let starts: Vec<_> = motion.moves.iter().map(|mv| mv.start).collect();
+        assert_eq!(starts.len(), 6);
+        assert_eq!(starts[0], [0.0, 0.0, 0.0]);
+        assert_eq!(starts[3], [10.0, 0.0, 0.0]);
+    }
+}
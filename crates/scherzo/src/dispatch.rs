@@ -0,0 +1,490 @@
+//! Direct command dispatch
+//!
+//! Console lines and short scripts run without compiling to a component:
+//! each `X<number>` line becomes a [`Command`] and runs on the host handler
+//! registered for its verb. The same [`CommandRegistry`] lists the commands
+//! uploaded jobs may import, so anything the console can run, a job can too.
+//! Commands registered by plugins count as handled, but plugins cannot be
+//! called yet, so dispatching one fails.
+//!
+//! Each command has a [`SchedulingClass`]. Ordered commands run in turn, a
+//! script or job at a time; immediate and out-of-band commands run as soon
+//! as they are sent, without waiting for the commands ahead of them.
+//!
+//! The heater targets `M104`, `M109`, `M140` and `M190` set are offset by
+//! the running job's temperature overrides before they reach a handler.
+
+use crate::{
+    executor::Executor,
+    overrides::{JobOverrides, OverrideController},
+    plugin::{PluginRegistry, SchedulingClass},
+};
+use anyhow::{Context, Result, bail};
+use scherzo_compile::estimate::{MOTION_VERBS, MotionCommand, MotionInterpreter};
+use scherzo_core::MotionError;
+use scherzo_gcode::{Number, Statement, Value, Word};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap},
+};
+
+/// Verbs that set a heater's target with `S`, and the heater they set
+/// unless a `T` word picks another extruder
+const HEATER_VERBS: [(&str, &str); 4] = [
+    ("M104", "extruder"),
+    ("M109", "extruder"),
+    ("M140", "heater_bed"),
+    ("M190", "heater_bed"),
+];
+
+/// Runs a command on the host
+type Handler = Box<dyn Fn(&Command, &mut DispatchContext) -> Result<()> + Send + Sync>;
+
+/// A classic G-code command, e.g. `G1 X10 F600`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Command {
+    /// Verb normalized like `G01` to `G1`
+    pub verb: String,
+    /// Everything after the verb
+    pub args: String,
+    line: String,
+}
+
+impl Command {
+    /// Parse a line, or return `None` if it is not an `X<number>` command
+    pub fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        let (verb, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        Some(Self {
+            verb: classic_command(verb)?,
+            args: args.trim().to_string(),
+            line: line.to_string(),
+        })
+    }
+
+    /// The command with its heater target offset by `overrides`, if it sets
+    /// one that they change
+    fn with_temperature_override(&self, overrides: &JobOverrides) -> Option<Self> {
+        let (_, heater) = HEATER_VERBS.iter().find(|(verb, _)| *verb == self.verb)?;
+        let mut words: Vec<String> = self.args.split_whitespace().map(String::from).collect();
+        let heater = match words.iter().find_map(|word| word_value(word, 'T')) {
+            Some(tool) if tool > 0.0 => format!("{heater}{tool}"),
+            _ => heater.to_string(),
+        };
+        let (index, target) = words
+            .iter()
+            .enumerate()
+            .find_map(|(index, word)| Some((index, word_value(word, 'S')?)))?;
+        let offset = overrides.temperature(&heater, target);
+        if offset == target {
+            return None;
+        }
+        words[index] = format!("S{offset}");
+        let args = words.join(" ");
+        Some(Self {
+            line: format!("{} {args}", self.verb),
+            verb: self.verb.clone(),
+            args,
+        })
+    }
+
+    /// The command parsed as G-code words
+    pub fn statement(&self) -> Result<Statement> {
+        let statements = scherzo_gcode::parse(&format!("{}\n", self.line))?;
+        statements
+            .into_iter()
+            .next()
+            .with_context(|| format!("`{}` is not a command", self.line))
+    }
+}
+
+/// What a handler can act on
+pub struct DispatchContext<'a> {
+    pub executor: Option<&'a Executor>,
+    pub interpreter: &'a mut MotionInterpreter,
+    /// Overrides of the running job, applied to each motion command
+    pub overrides: Option<&'a OverrideController>,
+    /// Lines to respond with
+    pub output: &'a mut Vec<String>,
+}
+
+impl DispatchContext<'_> {
+    pub fn executor(&self) -> Result<&Executor> {
+        self.executor.context("no [printer] is configured")
+    }
+}
+
+/// Host handlers by verb, and the plugins that may handle other commands
+pub struct CommandRegistry {
+    handlers: BTreeMap<String, (SchedulingClass, Handler)>,
+    plugins: PluginRegistry,
+}
+
+impl CommandRegistry {
+    /// Registry with the built-in handlers: the motion interpreter's verbs,
+    /// `M400` and `M118`, all ordered, and `M112` and `M114`, which run at
+    /// once. `G28` fails until homing is supported.
+    pub fn new(plugins: PluginRegistry) -> Self {
+        let mut registry = Self {
+            handlers: BTreeMap::new(),
+            plugins,
+        };
+        for verb in MOTION_VERBS {
+            registry.register(verb, SchedulingClass::RealtimeMotion, run_motion);
+        }
+        registry.register("G28", SchedulingClass::RealtimeMotion, |_, _| {
+            Err(MotionError::HomingUnsupported.into())
+        });
+        registry.register("M400", SchedulingClass::Queued, |_, ctx| {
+            ctx.executor()?.wait_moves()
+        });
+        registry.register("M118", SchedulingClass::Queued, |command, ctx| {
+            ctx.output.push(command.args.clone());
+            Ok(())
+        });
+        registry.register("M112", SchedulingClass::Immediate, |_, ctx| {
+            ctx.executor()?.emergency_stop();
+            ctx.output.push("emergency stop".to_string());
+            Ok(())
+        });
+        // Where the queued moves end, without waiting for them
+        registry.register("M114", SchedulingClass::OutOfBand, |_, ctx| {
+            let [x, y, z, e] = ctx.executor()?.position();
+            ctx.output
+                .push(format!("X:{x:.3} Y:{y:.3} Z:{z:.3} E:{e:.3}"));
+            Ok(())
+        });
+        registry
+    }
+
+    /// Run `verb` with `handler`, scheduled as `class`, replacing any
+    /// handler it had
+    pub fn register(
+        &mut self,
+        verb: &str,
+        class: SchedulingClass,
+        handler: impl Fn(&Command, &mut DispatchContext) -> Result<()> + Send + Sync + 'static,
+    ) {
+        self.handlers
+            .insert(verb.to_string(), (class, Box::new(handler)));
+    }
+
+    /// When `verb` runs, if the host or a plugin handles it
+    pub fn scheduling_class(&self, verb: &str) -> Option<SchedulingClass> {
+        if let Some((class, _)) = self.handlers.get(verb) {
+            return Some(*class);
+        }
+        let plugins = self.plugins.get_command_handlers().into_values();
+        plugins
+            .filter(|handler| handler.command == verb)
+            .map(|handler| handler.scheduling_class)
+            .next()
+    }
+
+    /// Every command the host or a plugin handles
+    pub fn commands(&self) -> BTreeSet<String> {
+        let plugins = self.plugins.get_command_handlers().into_values();
+        self.handlers
+            .keys()
+            .cloned()
+            .chain(plugins.map(|handler| handler.command))
+            .collect()
+    }
+
+    /// Parameters of `statements` outside the range the plugin handling
+    /// their verb declares, by source line, as messages like `S280 exceeds
+    /// configured max 260`
+    pub fn range_warnings(&self, statements: &[Statement]) -> Vec<(usize, String)> {
+        let handlers: HashMap<_, _> = self
+            .plugins
+            .get_command_handlers()
+            .into_values()
+            .filter(|handler| !self.handlers.contains_key(&handler.command))
+            .map(|handler| (handler.command.to_ascii_uppercase(), handler))
+            .collect();
+        let mut warnings = Vec::new();
+        for statement in statements {
+            let Some((verb, params)) = statement.words.split_first() else {
+                continue;
+            };
+            let Some(handler) = statement_verb(verb).and_then(|verb| handlers.get(&verb)) else {
+                continue;
+            };
+            for param in params {
+                let Some(Value::Number(number)) = &param.value else {
+                    continue;
+                };
+                let value = match *number {
+                    Number::Int(i) => i as f64,
+                    Number::Float(f) => f,
+                };
+                let (name, word) = match (&param.name, param.letter) {
+                    (Some(name), _) => (name.clone(), format!("{name}={value}")),
+                    (None, Some(letter)) => (letter.to_string(), format!("{letter}{value}")),
+                    (None, None) => continue,
+                };
+                let message = handler
+                    .params
+                    .iter()
+                    .filter(|field| field.name.eq_ignore_ascii_case(&name))
+                    .find_map(|field| field.check_range(value));
+                if let Some(message) = message {
+                    warnings.push((statement.line, format!("{word} {message}")));
+                }
+            }
+        }
+        warnings
+    }
+
+    /// Run `command` on its host handler, with any heater target it sets
+    /// offset by the running job's overrides
+    pub fn dispatch(&self, command: &Command, ctx: &mut DispatchContext) -> Result<()> {
+        let command = match ctx
+            .overrides
+            .and_then(|overrides| command.with_temperature_override(&overrides.get()))
+        {
+            Some(offset) => Cow::Owned(offset),
+            None => Cow::Borrowed(command),
+        };
+        if let Some((_, handler)) = self.handlers.get(&command.verb) {
+            return handler(&command, ctx);
+        }
+        let plugins = self.plugins.get_command_handlers();
+        if plugins
+            .values()
+            .any(|handler| handler.command == command.verb)
+        {
+            bail!(
+                "`{}` is handled by a plugin, which cannot be run directly yet",
+                command.verb
+            );
+        }
+        bail!("unknown command `{}`", command.verb)
+    }
+}
+
+/// Interpret a motion command and queue its moves
+fn run_motion(command: &Command, ctx: &mut DispatchContext) -> Result<()> {
+    let statement = command.statement()?;
+    if let Some(overrides) = ctx.overrides {
+        overrides.get().apply(ctx.interpreter);
+    }
+    if let Some(executor) = ctx.executor {
+        ctx.interpreter.sync_position(executor.position());
+    }
+    let speed_factor = ctx.interpreter.speed_factor();
+    let extrude_factor = ctx.interpreter.extrude_factor();
+    let motions = ctx.interpreter.interpret(&statement);
+    // `M220`/`M221` change the running job's overrides, as the API does
+    let interpreter = &*ctx.interpreter;
+    if let Some(overrides) = ctx.overrides
+        && (interpreter.speed_factor() != speed_factor
+            || interpreter.extrude_factor() != extrude_factor)
+    {
+        overrides.update(|live| {
+            if interpreter.speed_factor() != speed_factor {
+                live.speed_factor = interpreter.speed_factor();
+            }
+            if interpreter.extrude_factor() != extrude_factor {
+                live.extrude_factor = interpreter.extrude_factor();
+            }
+        })?;
+    }
+    for motion in motions {
+        let executor = ctx.executor()?;
+        match motion {
+            MotionCommand::Move { target, speed, .. } => executor.move_to(target, speed)?,
+            MotionCommand::Dwell { seconds } => executor.dwell(seconds)?,
+        }
+    }
+    Ok(())
+}
+
+/// Value of `word` if it is `letter` followed by a number, e.g. `S200`
+fn word_value(word: &str, letter: char) -> Option<f64> {
+    let value = word.strip_prefix([letter, letter.to_ascii_lowercase()])?;
+    value.parse().ok()
+}
+
+/// Verb of a statement whose first word is `word`, normalized like
+/// [`Command::verb`]
+fn statement_verb(word: &Word) -> Option<String> {
+    if let Some(name) = &word.name {
+        return Some(name.to_ascii_uppercase());
+    }
+    let number = match word.value {
+        Some(Value::Number(Number::Int(i))) => i.to_string(),
+        Some(Value::Number(Number::Float(f))) => f.to_string(),
+        _ => return None,
+    };
+    classic_command(&format!("{}{number}", word.letter?))
+}
+
+/// `X<number>` commands, normalized like `G01` to `G1`
+pub fn classic_command(command: &str) -> Option<String> {
+    let mut chars = command.chars();
+    let letter = chars.next().filter(char::is_ascii_alphabetic)?;
+    let number = chars.as_str();
+    if number.is_empty() || !number.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        return None;
+    }
+    let number: f64 = number.parse().ok()?;
+    let number = if number.fract() == 0.0 {
+        (number as i64).to_string()
+    } else {
+        number.to_string()
+    };
+    Some(format!("{}{number}", letter.to_ascii_uppercase()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::CommandHandler;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_dispatches_to_host_handlers() {
+        let plugins = PluginRegistry::new();
+        let mut registry = CommandRegistry::new(plugins.clone());
+        registry.register("M117", SchedulingClass::Queued, |command, ctx| {
+            ctx.output.push(format!("display: {}", command.args));
+            Ok(())
+        });
+        plugins
+            .register_command_handler(CommandHandler {
+                command: "M104".to_string(),
+                params: Vec::new(),
+                description: None,
+                scheduling_class: SchedulingClass::Queued,
+            })
+            .unwrap();
+
+        let mut interpreter = MotionInterpreter::new();
+        let mut output = Vec::new();
+        let mut ctx = DispatchContext {
+            executor: None,
+            interpreter: &mut interpreter,
+            overrides: None,
+            output: &mut output,
+        };
+        for line in ["m117 hello", "G91", "M118 done"] {
+            let command = Command::parse(line).unwrap();
+            registry.dispatch(&command, &mut ctx).unwrap();
+        }
+        assert!(!ctx.interpreter.is_absolute());
+        assert_eq!(output, ["display: hello", "done"]);
+
+        let mut ctx = DispatchContext {
+            executor: None,
+            interpreter: &mut interpreter,
+            overrides: None,
+            output: &mut output,
+        };
+        let err = registry
+            .dispatch(&Command::parse("G1 X10").unwrap(), &mut ctx)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "no [printer] is configured");
+        let err = registry
+            .dispatch(&Command::parse("M104 S200").unwrap(), &mut ctx)
+            .unwrap_err();
+        assert!(err.to_string().contains("handled by a plugin"));
+        let err = registry
+            .dispatch(&Command::parse("M999").unwrap(), &mut ctx)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "unknown command `M999`");
+        assert!(Command::parse("PARK X=10").is_none());
+
+        let commands = registry.commands();
+        assert!(commands.contains("G1") && commands.contains("M104"));
+        assert!(!commands.contains("M999"));
+        let class = |verb| registry.scheduling_class(verb);
+        assert_eq!(class("G1"), Some(SchedulingClass::RealtimeMotion));
+        assert_eq!(class("M104"), Some(SchedulingClass::Queued));
+        assert_eq!(class("M112"), Some(SchedulingClass::Immediate));
+        assert_eq!(class("M114"), Some(SchedulingClass::OutOfBand));
+        assert_eq!(class("M999"), None);
+    }
+
+    #[test]
+    fn test_heater_targets_take_temperature_offsets() {
+        let targets = Arc::new(Mutex::new(Vec::new()));
+        let mut registry = CommandRegistry::new(PluginRegistry::new());
+        for verb in ["M104", "M140"] {
+            let targets = targets.clone();
+            registry.register(verb, SchedulingClass::Queued, move |command, _| {
+                targets.lock().unwrap().push(command.args.clone());
+                Ok(())
+            });
+        }
+        let overrides = OverrideController::default();
+        overrides
+            .update(|live| {
+                live.temperature_offsets = BTreeMap::from([
+                    ("extruder".to_string(), -5.0),
+                    ("extruder1".to_string(), 10.0),
+                    ("heater_bed".to_string(), 2.5),
+                ]);
+            })
+            .unwrap();
+
+        let mut interpreter = MotionInterpreter::new();
+        let mut output = Vec::new();
+        for (line, live) in [
+            ("M104 S210", true),
+            ("m104 t1 s200", true),
+            ("M104 S0", true),
+            ("M140 S60", true),
+            ("M104 S210", false),
+        ] {
+            let mut ctx = DispatchContext {
+                executor: None,
+                interpreter: &mut interpreter,
+                overrides: live.then_some(&overrides),
+                output: &mut output,
+            };
+            let command = Command::parse(line).unwrap();
+            registry.dispatch(&command, &mut ctx).unwrap();
+        }
+        assert_eq!(
+            *targets.lock().unwrap(),
+            ["S205", "t1 S210", "S0", "S62.5", "S210"]
+        );
+    }
+
+    #[test]
+    fn test_speed_and_flow_commands_change_live_overrides() {
+        let registry = CommandRegistry::new(PluginRegistry::new());
+        let overrides = OverrideController::default();
+        let mut interpreter = MotionInterpreter::new();
+        let mut output = Vec::new();
+        let mut ctx = DispatchContext {
+            executor: None,
+            interpreter: &mut interpreter,
+            overrides: Some(&overrides),
+            output: &mut output,
+        };
+        let mut run = |line: &str| registry.dispatch(&Command::parse(line).unwrap(), &mut ctx);
+
+        run("M220 S150").unwrap();
+        run("M221 S95").unwrap();
+        assert_eq!(overrides.get().speed_factor, 1.5);
+        assert_eq!(overrides.get().extrude_factor, 0.95);
+
+        // An override set through the API since holds for the next command
+        overrides.update(|live| live.speed_factor = 0.8).unwrap();
+        run("M82").unwrap();
+        assert_eq!(interpreter.speed_factor(), 0.8);
+        assert_eq!(interpreter.extrude_factor(), 0.95);
+
+        let mut ctx = DispatchContext {
+            executor: None,
+            interpreter: &mut interpreter,
+            overrides: Some(&overrides),
+            output: &mut output,
+        };
+        let command = Command::parse("M220 S5000").unwrap();
+        assert!(registry.dispatch(&command, &mut ctx).is_err());
+        assert_eq!(overrides.get().speed_factor, 0.8);
+    }
+}
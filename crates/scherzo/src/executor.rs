@@ -0,0 +1,422 @@
+//! Motion execution heartbeat
+//!
+//! Moves are queued on the shared [`Machine`] by whatever drives the printer,
+//! while a background thread runs its flush timer so steps are generated a
+//! short time before the MCU needs them. Producers are throttled once the
+//! configured buffer is full. If step generation fails or panics the
+//! heartbeat stops and the fault hook is told, with the machine as it was.
+//!
+//! An emergency stop takes the machine between two flushes, so the steps
+//! already generated are whole, and stops the heartbeat there. Moves queued
+//! but not yet flushed are never sent.
+
+use crate::{crash::FaultKind, machine::Machine};
+use anyhow::{Result, anyhow};
+use scherzo_core::{
+    MotionError, bed_mesh::BedMesh, resonance::TestMove, retraction::RetractionConfig,
+};
+use std::{
+    any::Any,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        Arc, Condvar, Mutex, MutexGuard,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Clock ticks per second when no MCU sets the step timing
+pub const HOST_CLOCK_FREQ: f64 = 1_000_000.0;
+
+/// Maps host time onto the MCU's print time
+pub trait PrintClock: Send + Sync {
+    /// Print time the MCU has reached by now
+    fn estimated_print_time(&self) -> f64;
+
+    /// Frequency (Hz) of the clock steps are scheduled in
+    fn clock_freq(&self) -> f64;
+}
+
+/// Print time that follows the host's monotonic clock, starting at zero
+pub struct HostClock {
+    start: Instant,
+    clock_freq: f64,
+}
+
+impl HostClock {
+    pub fn new(clock_freq: f64) -> Self {
+        Self {
+            start: Instant::now(),
+            clock_freq,
+        }
+    }
+}
+
+impl PrintClock for HostClock {
+    fn estimated_print_time(&self) -> f64 {
+        self.start.elapsed().as_secs_f64()
+    }
+
+    fn clock_freq(&self) -> f64 {
+        self.clock_freq
+    }
+}
+
+/// Called with the machine when step generation faults
+type FaultHook = Box<dyn Fn(&Machine, FaultKind, &str) + Send + Sync>;
+
+struct Shared {
+    machine: Arc<Mutex<Machine>>,
+    clock: Box<dyn PrintClock>,
+    /// Signalled when new moves may need the flush timer sooner
+    kick: Condvar,
+    shutdown: AtomicBool,
+    /// Why step generation stopped, once it has
+    error: Mutex<Option<String>>,
+    fault_hook: Mutex<Option<FaultHook>>,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Machine> {
+        self.machine.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn fault(&self, machine: &Machine, kind: FaultKind, message: &str) {
+        tracing::error!("Step generation failed: {message}");
+        if let Some(hook) = &*self
+            .fault_hook
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+        {
+            hook(machine, kind, message);
+        }
+    }
+}
+
+/// Runs the flush timer of a machine on a background thread
+pub struct Executor {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Executor {
+    /// Start the heartbeat for `machine`, timing steps with `clock`
+    pub fn spawn(machine: Arc<Mutex<Machine>>, clock: impl PrintClock + 'static) -> Self {
+        machine
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .set_clock_freq(clock.clock_freq());
+        let shared = Arc::new(Shared {
+            machine,
+            clock: Box::new(clock),
+            kick: Condvar::new(),
+            shutdown: AtomicBool::new(false),
+            error: Mutex::new(None),
+            fault_hook: Mutex::new(None),
+        });
+        let thread = {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("scherzo-flush".to_string())
+                .spawn(move || heartbeat(&shared))
+                .expect("failed to spawn flush thread")
+        };
+        Self {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// Call `hook` with the machine when step generation fails or panics
+    pub fn set_fault_hook(&self, hook: impl Fn(&Machine, FaultKind, &str) + Send + Sync + 'static) {
+        *self
+            .shared
+            .fault_hook
+            .lock()
+            .unwrap_or_else(|err| err.into_inner()) = Some(Box::new(hook));
+    }
+
+    /// Queue a move to `target` (X/Y/Z/E) at `speed` mm/s, waiting first if
+    /// the buffer is full
+    pub fn move_to(&self, target: [f64; 4], speed: f64) -> Result<()> {
+        self.queue(|machine| machine.queue_move(target, speed))
+    }
+
+    /// Position moves were last queued to
+    pub fn position(&self) -> [f64; 4] {
+        self.shared.lock().commanded_position()
+    }
+
+    /// The printer's firmware retraction, if configured
+    pub fn retraction(&self) -> Option<RetractionConfig> {
+        self.shared.lock().retraction
+    }
+
+    /// Queue a move to X/Y/Z `target` at `speed` mm/s without extruding
+    pub fn travel_to(&self, target: [f64; 3], speed: f64) -> Result<()> {
+        self.queue(|machine| machine.travel_to(target, speed))
+    }
+
+    /// Keep the toolhead still for `seconds` after the queued moves
+    pub fn dwell(&self, seconds: f64) -> Result<()> {
+        self.queue(|machine| machine.toolhead.dwell(seconds))
+    }
+
+    /// Follow `mesh` with the moves queued from now on, or stop following
+    /// the bed if `None`
+    pub fn set_bed_mesh(&self, mesh: Option<BedMesh>) {
+        self.shared.lock().compensation.set_bed_mesh(mesh);
+    }
+
+    /// Count the moves queued from now on as those of object `name`, or of
+    /// no object if `None`, skipping them while it is excluded
+    pub fn set_current_object(&self, name: Option<&str>) {
+        let mut machine = self.shared.lock();
+        match name {
+            Some(name) => machine.exclude_object.start(name),
+            None => machine.exclude_object.end(),
+        }
+    }
+
+    /// Skip the moves of object `name` queued from now on
+    pub fn exclude_object(&self, name: &str) {
+        self.shared.lock().exclude_object.exclude(name);
+    }
+
+    /// Queue the moves of a resonance test, each at its own acceleration,
+    /// then restore the configured limits
+    pub fn run_test_moves(&self, moves: &[TestMove]) -> Result<()> {
+        let result = moves.iter().try_for_each(|mv| {
+            self.queue(|machine| {
+                machine.set_test_accel(Some(mv.accel));
+                machine.travel_to(mv.target, mv.speed)
+            })
+        });
+        self.shared.lock().set_test_accel(None);
+        result
+    }
+
+    /// Run the machine's pause sequence, returning false if already paused
+    pub fn pause(&self) -> Result<bool> {
+        self.queue(Machine::pause)
+    }
+
+    /// Run the machine's resume sequence, returning false if not paused
+    pub fn resume(&self) -> Result<bool> {
+        self.queue(Machine::resume)
+    }
+
+    /// Queue moves with `f` once the buffer has room
+    fn queue<R>(&self, f: impl FnOnce(&mut Machine) -> Result<R, MotionError>) -> Result<R> {
+        let mut machine = self.shared.lock();
+        loop {
+            self.check_error()?;
+            let now = self.shared.clock.estimated_print_time();
+            let Some(wait) = machine.scheduler.throttle_time(&machine.toolhead, now) else {
+                break;
+            };
+            drop(machine);
+            thread::sleep(Duration::from_secs_f64(wait));
+            machine = self.shared.lock();
+        }
+        let now = self.shared.clock.estimated_print_time();
+        machine.prepare_moves(now)?;
+        let result = f(&mut machine);
+        self.shared.kick.notify_all();
+        Ok(result?)
+    }
+
+    /// Stop generating steps, e.g. for `M112`, failing every move queued
+    /// from now on. Returns once no flush is running.
+    pub fn emergency_stop(&self) {
+        let machine = self.shared.lock();
+        *self
+            .shared
+            .error
+            .lock()
+            .unwrap_or_else(|err| err.into_inner()) = Some("emergency stop".to_string());
+        self.shared.shutdown.store(true, Ordering::Relaxed);
+        drop(machine);
+        self.shared.kick.notify_all();
+        tracing::warn!("Emergency stop");
+    }
+
+    /// Send every queued move and wait until the MCU has executed them
+    pub fn wait_moves(&self) -> Result<()> {
+        self.check_error()?;
+        let end = {
+            let mut machine = self.shared.lock();
+            let now = self.shared.clock.estimated_print_time();
+            if let Err(err) = machine.flush_moves(now) {
+                self.shared
+                    .fault(&machine, FaultKind::MotionError, &err.to_string());
+                return Err(err.into());
+            }
+            machine.toolhead.print_time()
+        };
+        self.shared.kick.notify_all();
+        let remaining = end - self.shared.clock.estimated_print_time();
+        if remaining > 0.0 {
+            thread::sleep(Duration::from_secs_f64(remaining));
+        }
+        self.check_error()
+    }
+
+    fn check_error(&self) -> Result<()> {
+        match &*self
+            .shared
+            .error
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+        {
+            Some(err) => Err(anyhow!("step generation failed: {err}")),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Executor {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::Relaxed);
+        // Take the lock so the heartbeat is either waiting or sees the flag
+        drop(self.shared.lock());
+        self.shared.kick.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn heartbeat(shared: &Shared) {
+    let mut machine = shared.lock();
+    while !shared.shutdown.load(Ordering::Relaxed) {
+        let now = shared.clock.estimated_print_time();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| machine.flush_timer(now)));
+        let timeout = match result {
+            Ok(Ok(Some(wake))) => Some(Duration::from_secs_f64((wake - now).max(0.0))),
+            // Idle until the next move arrives
+            Ok(Ok(None)) => None,
+            Ok(Err(err)) => {
+                let message = err.to_string();
+                *shared.error.lock().unwrap_or_else(|err| err.into_inner()) = Some(message.clone());
+                shared.fault(&machine, FaultKind::MotionError, &message);
+                return;
+            }
+            Err(payload) => {
+                let message = format!("flush thread panicked: {}", panic_message(&*payload));
+                *shared.error.lock().unwrap_or_else(|err| err.into_inner()) = Some(message.clone());
+                shared.fault(&machine, FaultKind::ExecutorPanic, &message);
+                return;
+            }
+        };
+        machine = match timeout {
+            Some(timeout) => {
+                shared
+                    .kick
+                    .wait_timeout(machine, timeout)
+                    .unwrap_or_else(|err| err.into_inner())
+                    .0
+            }
+            None => shared
+                .kick
+                .wait(machine)
+                .unwrap_or_else(|err| err.into_inner()),
+        };
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    const PRINTER: &str = r#"
+[printer]
+kinematics = "cartesian"
+max_velocity = 300
+max_accel = 3000
+buffer_time_start = 0.05
+
+[stepper_x]
+rotation_distance = 40
+microsteps = 16
+position_max = 235
+
+[stepper_y]
+rotation_distance = 40
+microsteps = 16
+position_max = 235
+
+[stepper_z]
+rotation_distance = 8
+microsteps = 16
+position_max = 250
+"#;
+
+    #[test]
+    fn test_heartbeat_generates_steps() {
+        let config = Config::from_toml(PRINTER).unwrap();
+        let machine = Machine::from_config(&config).unwrap().unwrap();
+        let machine = Arc::new(Mutex::new(machine));
+        let executor = Executor::spawn(machine.clone(), HostClock::new(HOST_CLOCK_FREQ));
+
+        executor.move_to([4.0, 2.0, 0.0, 0.0], 200.0).unwrap();
+        executor.move_to([4.0, 0.0, 0.0, 0.0], 200.0).unwrap();
+        executor.wait_moves().unwrap();
+
+        let info = machine.lock().unwrap().info();
+        let steps: Vec<_> = info.motion.steps.iter().map(|(_, steps)| *steps).collect();
+        assert_eq!(steps, [320, 320, 0]);
+        let solved: Vec<_> = info.motion.solvers.iter().map(|s| s.total.steps).collect();
+        assert_eq!(solved, [320, 320, 0]);
+        let compressed: Vec<_> = info
+            .motion
+            .compressors
+            .iter()
+            .map(|c| c.stats.steps_out)
+            .collect();
+        assert_eq!(compressed, [320, 320, 0]);
+        assert!(info.motion.compressors[0].average_count > 1.0);
+        assert!(info.motion.flushes > 0);
+        assert!(info.motion.flush_time >= info.motion.print_time);
+        assert!(machine.lock().unwrap().scheduler.is_idle());
+    }
+
+    #[test]
+    fn test_emergency_stop_drops_unflushed_moves() {
+        let config = Config::from_toml(PRINTER).unwrap();
+        let machine = Machine::from_config(&config).unwrap().unwrap();
+        let machine = Arc::new(Mutex::new(machine));
+        let executor = Executor::spawn(machine.clone(), HostClock::new(HOST_CLOCK_FREQ));
+
+        executor.move_to([4.0, 0.0, 0.0, 0.0], 200.0).unwrap();
+        executor.wait_moves().unwrap();
+        executor.move_to([4.0, 4.0, 0.0, 0.0], 200.0).unwrap();
+        executor.emergency_stop();
+
+        let err = executor.wait_moves().unwrap_err();
+        assert_eq!(err.to_string(), "step generation failed: emergency stop");
+        assert!(executor.move_to([0.0, 0.0, 0.0, 0.0], 200.0).is_err());
+        // Nothing is flushed once stopped
+        let steps = || {
+            let info = machine.lock().unwrap().info();
+            let steps: Vec<_> = info.motion.steps.iter().map(|(_, steps)| *steps).collect();
+            steps
+        };
+        let stopped = steps();
+        assert_eq!(stopped[0], 320);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(steps(), stopped);
+    }
+}
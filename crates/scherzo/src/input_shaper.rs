@@ -0,0 +1,466 @@
+//! Input shaper calibration
+//!
+//! Calibrating an axis moves to the `[resonance_tester]` probe point and
+//! shakes the toolhead along the axis with a frequency sweep, while the
+//! configured accelerometer reports samples through a plugin or the API. The
+//! spectrum of the recording is fitted with every shaper type, and the
+//! recommended one is written to `saved_config` as that axis' `[input_shaper]`
+//! settings.
+
+use crate::{
+    config::{Config, InputShaperConfig, ResonanceTesterConfig, save_config_value},
+    executor::Executor,
+};
+use anyhow::{Context, Result, bail};
+use scherzo_core::resonance::{FittedShaper, PowerSpectrum, ResonanceTest, find_best_shaper};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+};
+
+/// Axis to calibrate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Axis {
+    X,
+    Y,
+}
+
+impl Axis {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::X => "x",
+            Self::Y => "y",
+        }
+    }
+
+    fn direction(&self) -> [f64; 2] {
+        match self {
+            Self::X => [1.0, 0.0],
+            Self::Y => [0.0, 1.0],
+        }
+    }
+}
+
+/// Accelerometer reading
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AccelSample {
+    /// When the sample was taken (s), on the accelerometer's own clock
+    pub time: f64,
+    /// Acceleration along X (mm/s^2)
+    pub x: f64,
+    /// Acceleration along Y (mm/s^2)
+    pub y: f64,
+    /// Acceleration along Z (mm/s^2)
+    pub z: f64,
+}
+
+/// How well a shaper fits the measured resonances
+#[derive(Debug, Clone, Serialize)]
+pub struct ShaperFit {
+    pub shaper_type: &'static str,
+    /// Shaper frequency (Hz)
+    pub shaper_freq: f64,
+    /// Fraction of the vibrations left
+    pub vibrations: f64,
+    /// Smoothing (mm) at 5000 mm/s^2
+    pub smoothing: f64,
+    /// Highest recommended acceleration (mm/s^2)
+    pub max_accel: f64,
+}
+
+impl From<&FittedShaper> for ShaperFit {
+    fn from(fitted: &FittedShaper) -> Self {
+        Self {
+            shaper_type: fitted.shaper_type.name(),
+            shaper_freq: fitted.freq,
+            vibrations: fitted.vibrations,
+            smoothing: fitted.smoothing,
+            max_accel: fitted.max_accel,
+        }
+    }
+}
+
+/// Result of calibrating an axis
+#[derive(Debug, Clone, Serialize)]
+pub struct ShaperRecommendation {
+    /// Samples the accelerometer reported during the test
+    pub samples: usize,
+    /// Sample rate (Hz) of the accelerometer
+    pub sample_rate: f64,
+    /// Strongest resonance (Hz)
+    pub peak_freq: f64,
+    /// Shaper written to the config
+    pub recommended: ShaperFit,
+    /// Best fit of every shaper type
+    pub shapers: Vec<ShaperFit>,
+}
+
+/// Progress of the last calibration
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum CalibrationState {
+    Measuring {
+        axis: Axis,
+    },
+    Done {
+        axis: Axis,
+        result: ShaperRecommendation,
+    },
+    Failed {
+        axis: Axis,
+        error: String,
+    },
+}
+
+/// Input shaper state reported by the API
+#[derive(Debug, Clone, Serialize)]
+pub struct InputShaperStatus {
+    /// Settings saved by the last calibration of each axis
+    pub input_shaper: InputShaperConfig,
+    pub calibration: Option<CalibrationState>,
+}
+
+#[derive(Default)]
+struct State {
+    shaper: InputShaperConfig,
+    calibration: Option<CalibrationState>,
+    /// Samples of the test running now
+    recording: Option<Vec<AccelSample>>,
+}
+
+struct Inner {
+    executor: Option<Arc<Executor>>,
+    config: Option<ResonanceTesterConfig>,
+    square_corner_velocity: f64,
+    saved_config: Option<PathBuf>,
+    state: Mutex<State>,
+}
+
+/// Runs resonance tests and recommends input shaper settings
+#[derive(Clone)]
+pub struct InputShaperController {
+    inner: Arc<Inner>,
+}
+
+impl InputShaperController {
+    /// Test with the `[resonance_tester]` in `config`, moving the machine
+    /// through `executor` if there is one
+    pub fn new(config: &Config, executor: Option<Arc<Executor>>) -> Self {
+        let square_corner_velocity = config
+            .printer
+            .as_ref()
+            .map_or(5.0, |printer| printer.square_corner_velocity);
+        Self {
+            inner: Arc::new(Inner {
+                executor,
+                config: config.resonance_tester.clone(),
+                square_corner_velocity,
+                saved_config: config.saved_config.as_ref().map(PathBuf::from),
+                state: Mutex::new(State {
+                    shaper: config.input_shaper.clone().unwrap_or_default(),
+                    ..State::default()
+                }),
+            }),
+        }
+    }
+
+    /// Record samples from accelerometer `chip`, returning how many were
+    /// kept. Samples are only kept while a test is shaking the toolhead.
+    pub fn report_samples(&self, chip: &str, samples: &[AccelSample]) -> Result<usize> {
+        let Some(config) = &self.inner.config else {
+            bail!("no [resonance_tester] is configured");
+        };
+        if chip != config.accel_chip {
+            bail!("unknown accelerometer `{chip}`");
+        }
+        if samples.iter().any(|sample| {
+            ![sample.time, sample.x, sample.y, sample.z]
+                .iter()
+                .all(|value| value.is_finite())
+        }) {
+            bail!("accelerometer samples must be finite");
+        }
+        match &mut self.state().recording {
+            Some(recording) => {
+                recording.extend_from_slice(samples);
+                Ok(samples.len())
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// Start calibrating `axis` in the background. Progress and the result
+    /// are reported by [`Self::status`].
+    pub fn start_calibration(&self, axis: Axis) -> Result<CalibrationState> {
+        if self.inner.config.is_none() {
+            bail!("no [resonance_tester] is configured");
+        }
+        self.executor()?;
+        let state = CalibrationState::Measuring { axis };
+        {
+            let mut locked = self.state();
+            if matches!(locked.calibration, Some(CalibrationState::Measuring { .. })) {
+                bail!("input shaper calibration is already running");
+            }
+            locked.calibration = Some(state.clone());
+        }
+
+        let this = self.clone();
+        thread::spawn(move || {
+            let result = this.calibrate(axis);
+            let mut state = this.state();
+            state.recording = None;
+            state.calibration = Some(match result {
+                Ok(result) => CalibrationState::Done { axis, result },
+                Err(err) => {
+                    tracing::warn!(
+                        "Input shaper calibration of {} failed: {err:#}",
+                        axis.name()
+                    );
+                    CalibrationState::Failed {
+                        axis,
+                        error: format!("{err:#}"),
+                    }
+                }
+            });
+        });
+        Ok(state)
+    }
+
+    pub fn status(&self) -> InputShaperStatus {
+        let state = self.state();
+        InputShaperStatus {
+            input_shaper: state.shaper.clone(),
+            calibration: state.calibration.clone(),
+        }
+    }
+
+    fn calibrate(&self, axis: Axis) -> Result<ShaperRecommendation> {
+        let config = self
+            .inner
+            .config
+            .as_ref()
+            .expect("calibrating without config");
+        let executor = self.executor()?;
+
+        executor.travel_to(config.probe_point, config.move_speed)?;
+        executor.wait_moves()?;
+        tracing::info!("Testing resonances along {}", axis.name());
+        self.state().recording = Some(Vec::new());
+        let moves = ResonanceTest::from(config).moves(config.probe_point, axis.direction());
+        executor.run_test_moves(&moves)?;
+        executor.wait_moves()?;
+        let samples = self.state().recording.take().unwrap_or_default();
+
+        let result = analyze(
+            &samples,
+            config.max_smoothing,
+            self.inner.square_corner_velocity,
+        )
+        .with_context(|| format!("no shaper fits accelerometer `{}`", config.accel_chip))?;
+        let recommended = &result.recommended;
+        tracing::info!(
+            "Recommended input shaper for {}: {} at {:.1} Hz",
+            axis.name(),
+            recommended.shaper_type,
+            recommended.shaper_freq
+        );
+
+        {
+            let mut state = self.state();
+            let shaper = &mut state.shaper;
+            let (shaper_type, shaper_freq) = match axis {
+                Axis::X => (&mut shaper.shaper_type_x, &mut shaper.shaper_freq_x),
+                Axis::Y => (&mut shaper.shaper_type_y, &mut shaper.shaper_freq_y),
+            };
+            *shaper_type = Some(recommended.shaper_type.to_string());
+            *shaper_freq = Some(recommended.shaper_freq);
+        }
+        match &self.inner.saved_config {
+            Some(path) => {
+                let name = axis.name();
+                let shaper_type = toml::Value::String(recommended.shaper_type.to_string());
+                save_config_value(
+                    path,
+                    &format!("input_shaper.shaper_type_{name}"),
+                    shaper_type,
+                )?;
+                let shaper_freq = toml::Value::Float(recommended.shaper_freq);
+                save_config_value(
+                    path,
+                    &format!("input_shaper.shaper_freq_{name}"),
+                    shaper_freq,
+                )?;
+            }
+            None => tracing::warn!("No saved_config is configured; the input shaper is not saved"),
+        }
+        Ok(result)
+    }
+
+    fn executor(&self) -> Result<&Executor> {
+        self.inner
+            .executor
+            .as_deref()
+            .context("no [printer] is configured")
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.inner
+            .state
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Fit every shaper type to the vibrations in `samples`
+pub fn analyze(
+    samples: &[AccelSample],
+    max_smoothing: Option<f64>,
+    square_corner_velocity: f64,
+) -> Result<ShaperRecommendation> {
+    let (Some(first), Some(last)) = (samples.first(), samples.last()) else {
+        bail!("the accelerometer reported no samples");
+    };
+    let duration = last.time - first.time;
+    if duration <= 0.0 {
+        bail!("accelerometer sample times must increase");
+    }
+    let sample_rate = (samples.len() - 1) as f64 / duration;
+    let readings: Vec<[f64; 3]> = samples
+        .iter()
+        .map(|sample| [sample.x, sample.y, sample.z])
+        .collect();
+    let spectrum = PowerSpectrum::from_samples(&readings, sample_rate).with_context(|| {
+        format!(
+            "{} samples at {sample_rate:.0} Hz are too few",
+            samples.len()
+        )
+    })?;
+    let (best, all) = find_best_shaper(&spectrum, max_smoothing, square_corner_velocity)
+        .context("no vibrations were measured")?;
+    Ok(ShaperRecommendation {
+        samples: samples.len(),
+        sample_rate,
+        peak_freq: spectrum.peak_freq().unwrap_or_default(),
+        recommended: ShaperFit::from(&best),
+        shapers: all.iter().map(ShaperFit::from).collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        executor::{HOST_CLOCK_FREQ, HostClock},
+        machine::Machine,
+    };
+    use std::{
+        f64::consts::PI,
+        time::{Duration, Instant},
+    };
+
+    const PRINTER: &str = r#"
+[printer]
+kinematics = "cartesian"
+max_velocity = 300
+max_accel = 3000
+buffer_time_start = 0.05
+
+[stepper_x]
+rotation_distance = 40
+microsteps = 16
+position_max = 100
+
+[stepper_y]
+rotation_distance = 40
+microsteps = 16
+position_max = 100
+
+[stepper_z]
+rotation_distance = 8
+microsteps = 16
+position_max = 50
+
+[resonance_tester]
+accel_chip = "adxl345"
+probe_point = [50, 50, 10]
+min_freq = 40
+max_freq = 44
+hz_per_sec = 40
+move_speed = 300
+"#;
+
+    /// Samples ringing at 42 Hz along Y, from `start` s at 1 kHz
+    fn ringing(start: usize, count: usize) -> Vec<AccelSample> {
+        (start..start + count)
+            .map(|i| {
+                let time = i as f64 / 1000.0;
+                let ring = (2.0 * PI * 42.0 * time).sin() * 2000.0;
+                AccelSample {
+                    time,
+                    x: 0.05 * ring,
+                    y: ring,
+                    z: 9810.0,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_calibration_recommends_and_saves_shaper() {
+        let dir = tempfile::tempdir().unwrap();
+        let saved = dir.path().join("saved.toml");
+        let mut config = Config::from_toml(PRINTER).unwrap();
+        config.saved_config = Some(saved.display().to_string());
+        let machine = Machine::from_config(&config).unwrap().unwrap();
+        let machine = Arc::new(Mutex::new(machine));
+        let executor = Arc::new(Executor::spawn(
+            machine.clone(),
+            HostClock::new(HOST_CLOCK_FREQ),
+        ));
+        let shaper = InputShaperController::new(&config, Some(executor.clone()));
+
+        assert!(shaper.report_samples("lis2dw", &ringing(0, 1)).is_err());
+        assert_eq!(shaper.report_samples("adxl345", &ringing(0, 1)).unwrap(), 0);
+
+        shaper.start_calibration(Axis::Y).unwrap();
+        assert!(shaper.start_calibration(Axis::X).is_err());
+        let deadline = Instant::now() + Duration::from_secs(30);
+        let mut sent = 0;
+        while matches!(
+            shaper.status().calibration,
+            Some(CalibrationState::Measuring { .. })
+        ) {
+            assert!(Instant::now() < deadline, "calibration did not finish");
+            sent += shaper
+                .report_samples("adxl345", &ringing(sent, 100))
+                .unwrap();
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let status = shaper.status();
+        let Some(CalibrationState::Done { axis, result }) = status.calibration else {
+            panic!("calibration failed: {:?}", status.calibration);
+        };
+        assert_eq!(axis, Axis::Y);
+        assert!((result.peak_freq - 42.0).abs() < 2.0, "{result:?}");
+        assert_eq!(result.shapers.len(), 5);
+        assert_eq!(
+            status.input_shaper.shaper_type_y.as_deref(),
+            Some(result.recommended.shaper_type)
+        );
+        assert!(status.input_shaper.shaper_type_x.is_none());
+        // Test moves ran at their own acceleration, then the limits came back
+        assert_eq!(executor.position()[..3], [50.0, 50.0, 10.0]);
+        assert_eq!(machine.lock().unwrap().toolhead.limits().max_accel, 3000.0);
+
+        let saved: toml::Table = toml::from_str(&std::fs::read_to_string(&saved).unwrap()).unwrap();
+        let saved: InputShaperConfig = saved["input_shaper"].clone().try_into().unwrap();
+        assert_eq!(saved, status.input_shaper);
+
+        assert!(analyze(&ringing(0, 10), None, 5.0).is_err());
+    }
+}
@@ -0,0 +1,175 @@
+//! Job queue
+//!
+//! Enqueued jobs print one after another, so belt printers and setups that
+//! eject their parts can print continuously. After each job the
+//! `[job_queue]` `between_jobs_gcode` script runs through the console, e.g. a
+//! macro sweeping the part off the bed. With `confirm_between_jobs` the queue
+//! then holds until someone confirms the printer is ready for the next job.
+//! A job that fails or is cancelled, or a between-jobs script that fails,
+//! always holds the queue.
+
+use crate::config::JobQueueConfig;
+use anyhow::{Result, bail};
+use serde::Serialize;
+use std::collections::{HashSet, VecDeque};
+use uuid::Uuid;
+
+/// Why the queue is not starting the next job
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "reason")]
+pub enum QueueHold {
+    /// `confirm_between_jobs` is set and the last job completed
+    Confirmation { job_id: Uuid },
+    /// The last job failed or was cancelled
+    JobStopped { job_id: Uuid },
+    /// The between-jobs script after the last job failed
+    ScriptFailed { job_id: Uuid, error: String },
+}
+
+/// Jobs waiting to print and what the queue is doing
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueStatus {
+    /// Enqueued jobs in the order they will print
+    pub jobs: Vec<Uuid>,
+    pub printing: Option<Uuid>,
+    pub hold: Option<QueueHold>,
+}
+
+/// Orders enqueued jobs and decides when the next one may start
+#[derive(Debug)]
+pub struct JobQueue {
+    config: JobQueueConfig,
+    jobs: VecDeque<Uuid>,
+    printing: Option<Uuid>,
+    hold: Option<QueueHold>,
+}
+
+impl JobQueue {
+    pub fn new(config: &JobQueueConfig) -> Self {
+        Self {
+            config: config.clone(),
+            jobs: VecDeque::new(),
+            printing: None,
+            hold: None,
+        }
+    }
+
+    /// Add `id` to the end of the queue, unless it is already queued
+    pub fn push(&mut self, id: Uuid) {
+        if !self.jobs.contains(&id) {
+            self.jobs.push_back(id);
+        }
+    }
+
+    /// Take `id` out of the queue, returning false if it was not queued
+    pub fn remove(&mut self, id: &Uuid) -> bool {
+        let len = self.jobs.len();
+        self.jobs.retain(|job| job != id);
+        self.jobs.len() != len
+    }
+
+    /// Put the queued jobs in the order of `order`, which must list each of
+    /// them once
+    pub fn reorder(&mut self, order: &[Uuid]) -> Result<()> {
+        let queued: HashSet<_> = self.jobs.iter().collect();
+        let ordered: HashSet<_> = order.iter().collect();
+        if order.len() != self.jobs.len() || queued != ordered {
+            bail!("the new order must list each queued job once");
+        }
+        self.jobs = order.iter().copied().collect();
+        Ok(())
+    }
+
+    /// Whether [`next`](Self::next) would start a job
+    pub fn has_next(&self) -> bool {
+        self.printing.is_none() && self.hold.is_none() && !self.jobs.is_empty()
+    }
+
+    /// Take the next job to print, unless a job is printing or the queue is
+    /// held
+    pub fn next(&mut self) -> Option<Uuid> {
+        if self.printing.is_some() || self.hold.is_some() {
+            return None;
+        }
+        self.printing = self.jobs.pop_front();
+        self.printing
+    }
+
+    /// Record that the printing job `id` stopped, returning the script to
+    /// run before the next job if it completed
+    pub fn finish(&mut self, id: &Uuid, completed: bool) -> Option<String> {
+        if self.printing != Some(*id) {
+            return None;
+        }
+        self.printing = None;
+        if !completed {
+            self.hold = Some(QueueHold::JobStopped { job_id: *id });
+            return None;
+        }
+        if self.config.confirm_between_jobs {
+            self.hold = Some(QueueHold::Confirmation { job_id: *id });
+        }
+        self.config.between_jobs_gcode.clone()
+    }
+
+    /// Hold the queue because the script run after `id` failed
+    pub fn script_failed(&mut self, id: &Uuid, error: String) {
+        self.hold = Some(QueueHold::ScriptFailed { job_id: *id, error });
+    }
+
+    /// Let the queue start the next job, returning false if it was not held
+    pub fn confirm(&mut self) -> bool {
+        self.hold.take().is_some()
+    }
+
+    pub fn status(&self) -> QueueStatus {
+        QueueStatus {
+            jobs: self.jobs.iter().copied().collect(),
+            printing: self.printing,
+            hold: self.hold.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chains_jobs_with_script_and_confirmation() {
+        let config = JobQueueConfig {
+            between_jobs_gcode: Some("EJECT_PART".to_string()),
+            confirm_between_jobs: true,
+        };
+        let mut queue = JobQueue::new(&config);
+        let [a, b, c] = [Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+        for id in [a, b, c, a] {
+            queue.push(id);
+        }
+        assert!(queue.reorder(&[a, b]).is_err());
+        queue.reorder(&[b, a, c]).unwrap();
+
+        assert_eq!(queue.next(), Some(b));
+        assert_eq!(queue.next(), None);
+        assert_eq!(queue.finish(&a, true), None);
+        assert_eq!(queue.finish(&b, true).as_deref(), Some("EJECT_PART"));
+        assert_eq!(
+            queue.status().hold,
+            Some(QueueHold::Confirmation { job_id: b })
+        );
+        assert_eq!(queue.next(), None);
+        assert!(queue.confirm());
+        assert!(!queue.confirm());
+
+        // A cancelled job holds the queue even without confirmation mode
+        assert_eq!(queue.next(), Some(a));
+        assert_eq!(queue.finish(&a, false), None);
+        assert_eq!(
+            queue.status().hold,
+            Some(QueueHold::JobStopped { job_id: a })
+        );
+        assert!(queue.confirm());
+        assert!(queue.remove(&c));
+        assert_eq!(queue.next(), None);
+    }
+}
@@ -0,0 +1,1065 @@
+use crate::config::{
+    CompressorTuning, Config, ExtruderConfig, FirmwareRetractionConfig, MeshConfig,
+    StepCompression, StepperConfig, default_move_check_distance,
+};
+use anyhow::{Context, Result, anyhow, bail};
+use scherzo_core::{
+    MotionError,
+    bed_mesh::{BedMesh, MeshGrid},
+    command_log::CommandLogWriter,
+    compensation::{CompensationStack, Stage},
+    exclude_object::ExcludeObject,
+    flush::{BufferConfig, FlushScheduler, FlushStats, StepGenerator},
+    itersolve::{IterativeSolver, SolverCounts},
+    kinematics::{Kinematics, StepperKinematics, cartesian},
+    pause_resume::{PauseConfig, PauseResume},
+    planner::MachineLimits,
+    rail::{Rail, RailConfig},
+    retraction::RetractionConfig,
+    step_compressor::{
+        Command, CommandSink, CompressStats, Compression, StepCompressor, StepCompressorConfig,
+        StepCompressorStatus,
+    },
+    toolhead::MotionController,
+};
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::BufWriter,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// Axis names, in rail order
+const AXES: [&str; 3] = ["x", "y", "z"];
+
+/// Motion system assembled from the `[printer]` and stepper config sections
+pub struct Machine {
+    pub kinematics: Kinematics,
+    pub limits: MachineLimits,
+    /// Rails for the X, Y, and Z axes
+    pub rails: [Rail; 3],
+    /// Filament (mm) fed per extruder microstep, if an extruder is configured
+    pub extruder_step_distance: Option<f64>,
+    /// Extruder moves for `G10`/`G11`, if firmware retraction is configured
+    pub retraction: Option<RetractionConfig>,
+    pub toolhead: MotionController,
+    /// Drops the moves of objects excluded mid-print
+    pub exclude_object: ExcludeObject,
+    /// Park and re-prime sequence for pausing a print
+    pub pause_resume: PauseResume,
+    /// Skew, bed mesh, twist and custom transforms applied to queued moves
+    pub compensation: CompensationStack,
+    /// Longest segment (mm) moves are split into to follow the bed mesh or
+    /// X twist
+    segment_length: f64,
+    /// Position moves were last queued to, including excluded ones
+    commanded: [f64; 4],
+    /// Filament (mm) the extruder is ahead of the queued moves, e.g. from
+    /// priming on resume
+    extruder_offset: f64,
+    /// Step generation for each rail, then the extruder
+    pub steppers: Vec<MachineStepper>,
+    /// Decides when queued moves become steps
+    pub scheduler: FlushScheduler,
+    /// Where every stepper's commands are logged, if configured
+    command_log: Option<SharedCommandLog>,
+}
+
+/// The step command log all steppers write to
+type SharedCommandLog = Arc<Mutex<CommandLogWriter<BufWriter<File>>>>;
+
+/// Solver and step compressor for one stepper
+pub struct MachineStepper {
+    pub name: String,
+    oid: u32,
+    extruder: bool,
+    clock_freq: f64,
+    tuning: CompressorTuning,
+    solver: IterativeSolver<StepperKinematics>,
+    compressor: StepCompressor<StepCounter>,
+}
+
+/// Counts the steps sent to an MCU, logging the commands if configured
+#[derive(Default)]
+pub struct StepCounter {
+    pub steps: u64,
+    log: Option<SharedCommandLog>,
+}
+
+impl CommandSink for StepCounter {
+    fn push(&mut self, command: Command) {
+        let logged = self.log.as_ref().map(|log| lock_log(log).record(&command));
+        if let Some(Err(e)) = logged {
+            tracing::warn!("Failed to log step command, no longer logging: {e}");
+            self.log = None;
+        }
+        if let Command::QueueStep(step) = command {
+            self.steps += step.count as u64;
+        }
+    }
+}
+
+/// Summary of the machine reported by the API
+#[derive(Debug, Clone, Serialize)]
+pub struct MachineInfo {
+    pub kinematics: &'static str,
+    pub max_velocity: f64,
+    pub max_accel: f64,
+    pub max_travel_velocity: Option<f64>,
+    pub max_travel_accel: Option<f64>,
+    pub minimum_cruise_ratio: f64,
+    pub square_corner_velocity: f64,
+    pub rails: Vec<RailInfo>,
+    pub extruder_step_distance: Option<f64>,
+    pub firmware_retraction: Option<FirmwareRetractionConfig>,
+    pub position: [f64; 4],
+    pub paused: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bed_mesh: Option<MeshConfig>,
+    /// Active compensation stages, in the order they are applied
+    pub compensation: Vec<Stage>,
+    pub current_object: Option<String>,
+    pub excluded_objects: Vec<String>,
+    pub motion: MotionInfo,
+}
+
+/// Step generation progress and how well it keeps ahead of the MCU
+#[derive(Debug, Clone, Serialize)]
+pub struct MotionInfo {
+    /// Print time at the end of the last scheduled move
+    pub print_time: f64,
+    /// Print time up to which steps have been sent
+    pub flush_time: f64,
+    /// Motion queued ahead of the MCU at the last flush timer (seconds)
+    pub buffer_time: f64,
+    pub flushes: u64,
+    /// Flushes that started after the MCU had caught up with the steps
+    pub underruns: u64,
+    /// Smallest lead (seconds) of sent steps over the MCU, if any were sent
+    pub min_flush_lead: Option<f64>,
+    /// Lead (seconds) of the steps over the MCU at the last flush, if any;
+    /// negative if they were late
+    pub flush_lead: Option<f64>,
+    /// Largest delay (seconds) of the flush timer past its schedule
+    pub max_timer_lateness: f64,
+    /// Moves waiting to be planned
+    pub lookahead_moves: usize,
+    /// Planned moves not yet stepped past, on the toolhead and extruder
+    /// trapqs
+    pub trapq_moves: usize,
+    pub extruder_trapq_moves: usize,
+    /// Steps generated for each stepper
+    pub steps: Vec<(String, u64)>,
+    /// Step solver work for each stepper
+    pub solvers: Vec<SolverInfo>,
+    /// How well each stepper's steps compress into MCU commands
+    pub compressors: Vec<CompressorInfo>,
+}
+
+/// Step solver work for one stepper, in the last flush and overall
+#[derive(Debug, Clone, Serialize)]
+pub struct SolverInfo {
+    pub stepper: String,
+    pub flushes: u64,
+    pub last_flush: SolverCountsInfo,
+    pub total: SolverCountsInfo,
+}
+
+/// Step compression of one stepper since the MCU clock was set
+#[derive(Debug, Clone, Serialize)]
+pub struct CompressorInfo {
+    pub stepper: String,
+    #[serde(flatten)]
+    pub stats: CompressStats,
+    /// Steps per `queue_step` command
+    pub average_count: f64,
+    /// Steps generated but not yet sent, and the clocks they span
+    pub status: StepCompressorStatus,
+}
+
+/// Work of the step solver over some flushes
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct SolverCountsInfo {
+    /// Positions evaluated by the secant search, which linear kinematics skip
+    pub iterations: u64,
+    /// Steps dropped to avoid a step+dir+step
+    pub rollbacks: u64,
+    pub steps: u64,
+}
+
+impl From<SolverCounts> for SolverCountsInfo {
+    fn from(counts: SolverCounts) -> Self {
+        Self {
+            iterations: counts.iterations,
+            rollbacks: counts.rollbacks,
+            steps: counts.steps,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RailInfo {
+    pub name: String,
+    pub axis: &'static str,
+    pub step_distance: f64,
+    pub position_min: f64,
+    pub position_max: f64,
+    pub position_endstop: Option<f64>,
+    pub homing_positive_dir: Option<bool>,
+}
+
+impl Machine {
+    /// Build the machine described by `config`, or `None` if there is no
+    /// `[printer]` section.
+    pub fn from_config(config: &Config) -> Result<Option<Self>> {
+        let Some(printer) = &config.printer else {
+            let section = [&config.stepper_x, &config.stepper_y, &config.stepper_z]
+                .into_iter()
+                .zip(AXES)
+                .find_map(|(stepper, axis)| stepper.as_ref().map(|_| axis));
+            if let Some(axis) = section {
+                bail!("[stepper_{axis}] requires a [printer] section");
+            }
+            return Ok(None);
+        };
+        let describe = |key: &str| config.provenance.describe(key);
+
+        let kinematics = Kinematics::parse(&printer.kinematics).ok_or_else(|| {
+            anyhow!(
+                "{}: unknown kinematics `{}` (expected cartesian, corexy, or corexz)",
+                describe("printer.kinematics"),
+                printer.kinematics
+            )
+        })?;
+
+        if printer.max_velocity <= 0.0 || printer.max_accel <= 0.0 {
+            bail!(
+                "{}: max_velocity and max_accel must be positive",
+                describe("printer")
+            );
+        }
+        if !(0.0..1.0).contains(&printer.minimum_cruise_ratio) {
+            bail!(
+                "{} must be in 0..1",
+                describe("printer.minimum_cruise_ratio")
+            );
+        }
+        if printer.square_corner_velocity < 0.0 {
+            bail!(
+                "{} cannot be negative",
+                describe("printer.square_corner_velocity")
+            );
+        }
+        if printer.max_travel_velocity.is_some_and(|v| v <= 0.0)
+            || printer.max_travel_accel.is_some_and(|a| a <= 0.0)
+        {
+            bail!(
+                "{}: max_travel_velocity and max_travel_accel must be positive",
+                describe("printer")
+            );
+        }
+        if printer.buffer_time_low <= 0.0 || printer.buffer_time_high <= printer.buffer_time_low {
+            bail!(
+                "{}: buffer_time_low must be positive and below buffer_time_high",
+                describe("printer")
+            );
+        }
+        if printer.buffer_time_start <= 0.0 || printer.move_flush_time <= 0.0 {
+            bail!(
+                "{}: buffer_time_start and move_flush_time must be positive",
+                describe("printer")
+            );
+        }
+        let buffer = BufferConfig {
+            low_time: printer.buffer_time_low,
+            high_time: printer.buffer_time_high,
+            start_time: printer.buffer_time_start,
+            step_flush_time: printer.move_flush_time,
+            ..BufferConfig::default()
+        };
+        let mut limits = MachineLimits {
+            max_velocity: printer.max_velocity,
+            max_accel: printer.max_accel,
+            minimum_cruise_ratio: printer.minimum_cruise_ratio,
+            square_corner_velocity: printer.square_corner_velocity,
+            max_travel_velocity: printer.max_travel_velocity,
+            max_travel_accel: printer.max_travel_accel,
+            ..MachineLimits::default()
+        };
+
+        let rail = |stepper: &Option<StepperConfig>, axis: &str| -> Result<Rail> {
+            let name = format!("stepper_{axis}");
+            let stepper = stepper
+                .as_ref()
+                .with_context(|| format!("[printer] requires a [{name}] section"))?;
+            if stepper.endstop_pin.is_some() && stepper.position_endstop.is_none() {
+                bail!(
+                    "{}: position_endstop is required with endstop_pin",
+                    describe(&name)
+                );
+            }
+            let rail = Rail::new(&RailConfig {
+                name: name.clone(),
+                rotation_distance: stepper.rotation_distance,
+                full_steps_per_rotation: stepper.full_steps_per_rotation,
+                microsteps: stepper.microsteps,
+                position_min: stepper.position_min,
+                position_max: stepper.position_max,
+                position_endstop: stepper.position_endstop,
+                homing_speed: stepper.homing_speed,
+                homing_positive_dir: stepper.homing_positive_dir,
+            })
+            .with_context(|| format!("invalid {}", describe(&name)))?;
+            Ok(rail)
+        };
+        let rails = [
+            rail(&config.stepper_x, "x")?,
+            rail(&config.stepper_y, "y")?,
+            rail(&config.stepper_z, "z")?,
+        ];
+
+        let extruder_step_distance = match &config.extruder {
+            Some(extruder) => {
+                if extruder.rotation_distance <= 0.0
+                    || extruder.microsteps == 0
+                    || extruder.full_steps_per_rotation == 0
+                {
+                    bail!(
+                        "{}: rotation_distance, microsteps, and full_steps_per_rotation must be positive",
+                        describe("extruder")
+                    );
+                }
+                if extruder.max_extrude_only_velocity <= 0.0
+                    || extruder.max_extrude_only_accel <= 0.0
+                {
+                    bail!(
+                        "{}: max_extrude_only_velocity and max_extrude_only_accel must be positive",
+                        describe("extruder")
+                    );
+                }
+                limits.max_extrude_only_velocity = extruder.max_extrude_only_velocity;
+                limits.max_extrude_only_accel = extruder.max_extrude_only_accel;
+                let steps = extruder.full_steps_per_rotation * extruder.microsteps;
+                Some(extruder.rotation_distance / steps as f64)
+            }
+            None => None,
+        };
+
+        let retraction = config
+            .firmware_retraction
+            .as_ref()
+            .map(RetractionConfig::try_from)
+            .transpose()
+            .with_context(|| format!("invalid {}", describe("firmware_retraction")))?;
+
+        let pause = config.pause_resume.clone().unwrap_or_default();
+        let pause = PauseConfig {
+            z_max: rails[2].position_max,
+            ..PauseConfig::try_from(&pause)
+                .with_context(|| format!("invalid {}", describe("pause_resume")))?
+        };
+        if let Some(park) = pause.park_position
+            && (0..2).any(|axis| {
+                let rail = &rails[axis];
+                !(rail.position_min..=rail.position_max).contains(&park[axis])
+            })
+        {
+            bail!(
+                "{}: park position is outside the X/Y travel",
+                describe("pause_resume")
+            );
+        }
+
+        let mut compensation = match &config.compensation {
+            Some(compensation) => CompensationStack::new(compensation)
+                .with_context(|| format!("invalid {}", describe("compensation")))?,
+            None => CompensationStack::default(),
+        };
+        let mut segment_length = default_move_check_distance();
+        if let Some(mesh) = &config.bed_mesh {
+            MeshGrid::new(mesh.mesh_min, mesh.mesh_max, mesh.probe_count)
+                .with_context(|| format!("invalid {}", describe("bed_mesh")))?;
+            let z = &rails[2];
+            if !(z.position_min..=z.position_max).contains(&mesh.horizontal_move_z) {
+                bail!(
+                    "{} is outside the Z travel",
+                    describe("bed_mesh.horizontal_move_z")
+                );
+            }
+            if mesh.speed <= 0.0 || mesh.move_check_distance <= 0.0 {
+                bail!(
+                    "{}: speed and move_check_distance must be positive",
+                    describe("bed_mesh")
+                );
+            }
+            compensation.set_bed_mesh(
+                mesh.mesh
+                    .as_ref()
+                    .map(BedMesh::try_from)
+                    .transpose()
+                    .with_context(|| format!("invalid {}", describe("bed_mesh.mesh")))?,
+            );
+            segment_length = mesh.move_check_distance;
+        }
+
+        if let Some(tester) = &config.resonance_tester {
+            if tester.accel_chip.is_empty() {
+                bail!(
+                    "{} cannot be empty",
+                    describe("resonance_tester.accel_chip")
+                );
+            }
+            if (0..3).any(|axis| {
+                let rail = &rails[axis];
+                !(rail.position_min..=rail.position_max).contains(&tester.probe_point[axis])
+            }) {
+                bail!(
+                    "{} is outside the travel",
+                    describe("resonance_tester.probe_point")
+                );
+            }
+            if !(tester.min_freq > 0.0 && tester.min_freq < tester.max_freq) {
+                bail!(
+                    "{}: min_freq must be positive and below max_freq",
+                    describe("resonance_tester")
+                );
+            }
+            if [tester.accel_per_hz, tester.hz_per_sec, tester.move_speed]
+                .iter()
+                .any(|&value| value <= 0.0)
+            {
+                bail!(
+                    "{}: accel_per_hz, hz_per_sec, and move_speed must be positive",
+                    describe("resonance_tester")
+                );
+            }
+        }
+
+        let tunings = [
+            (
+                "stepper_x",
+                config
+                    .stepper_x
+                    .as_ref()
+                    .map(StepperConfig::compressor_tuning),
+            ),
+            (
+                "stepper_y",
+                config
+                    .stepper_y
+                    .as_ref()
+                    .map(StepperConfig::compressor_tuning),
+            ),
+            (
+                "stepper_z",
+                config
+                    .stepper_z
+                    .as_ref()
+                    .map(StepperConfig::compressor_tuning),
+            ),
+            (
+                "extruder",
+                config
+                    .extruder
+                    .as_ref()
+                    .map(ExtruderConfig::compressor_tuning),
+            ),
+        ];
+        for (section, tuning) in tunings {
+            let Some(tuning) = tuning else { continue };
+            if tuning.max_step_error < 0.0
+                || tuning.sds_filter_time < 0.0
+                || tuning.step_queue_size == 0
+            {
+                bail!(
+                    "{}: max_step_error and sds_filter_time cannot be negative, and step_queue_size must be positive",
+                    describe(section)
+                );
+            }
+        }
+
+        let command_log = printer
+            .command_log
+            .as_ref()
+            .map(|path| -> Result<SharedCommandLog> {
+                let log = File::create(path)
+                    .map(BufWriter::new)
+                    .and_then(CommandLogWriter::new)
+                    .with_context(|| {
+                        format!(
+                            "{}: failed to create `{path}`",
+                            describe("printer.command_log")
+                        )
+                    })?;
+                Ok(Arc::new(Mutex::new(log)))
+            })
+            .transpose()?;
+
+        let stepper_configs = [&config.stepper_x, &config.stepper_y, &config.stepper_z];
+        let mut steppers: Vec<_> = rails
+            .iter()
+            .zip(stepper_configs)
+            .enumerate()
+            .map(|(axis, (rail, stepper))| {
+                let solver = rail.solver(kinematics, axis);
+                let mut generator = MachineStepper::new(&rail.name, axis as u32, solver);
+                if let Some(stepper) = stepper {
+                    generator.tuning = stepper.compressor_tuning();
+                }
+                generator
+            })
+            .collect();
+        if let Some(step_distance) = extruder_step_distance {
+            // The extruder trapq tracks filament position on its X axis
+            let kin =
+                StepperKinematics::Cartesian(cartesian::CartesianKin::new(cartesian::Axis::X));
+            let flags = kin.active_flags();
+            let solver = IterativeSolver::new(step_distance, flags, 0.0, 0.0, kin, ());
+            let mut extruder = MachineStepper::new("extruder", 3, solver);
+            extruder.extruder = true;
+            if let Some(config) = &config.extruder {
+                extruder.tuning = config.compressor_tuning();
+            }
+            steppers.push(extruder);
+        }
+
+        Ok(Some(Self {
+            kinematics,
+            limits,
+            rails,
+            extruder_step_distance,
+            retraction,
+            toolhead: MotionController::new(limits),
+            exclude_object: ExcludeObject::new(),
+            pause_resume: PauseResume::new(pause),
+            compensation,
+            segment_length,
+            commanded: [0.0; 4],
+            extruder_offset: 0.0,
+            steppers,
+            scheduler: FlushScheduler::new(buffer),
+            command_log,
+        }))
+    }
+
+    /// Time steps in ticks of the MCU clock
+    pub fn set_clock_freq(&mut self, clock_freq: f64) {
+        if let Some(log) = &self.command_log
+            && let Err(e) = lock_log(log).clock_freq(clock_freq)
+        {
+            tracing::warn!("Failed to log step command clock: {e}");
+        }
+        for stepper in &mut self.steppers {
+            let tuning = &stepper.tuning;
+            let mut compressor =
+                StepCompressorConfig::new((tuning.max_step_error * clock_freq) as u32)
+                    .with_sds_filter_time(tuning.sds_filter_time)
+                    .with_queue_start_size(tuning.step_queue_size)
+                    .with_error_recovery(tuning.error_recovery)
+                    .with_compression(match tuning.compression {
+                        StepCompression::BisectAdd => Compression::BisectAdd,
+                        StepCompression::ConstantInterval => Compression::ConstantInterval,
+                        StepCompression::Exact => Compression::ExactSteps,
+                        StepCompression::LookaheadBisect => Compression::LookaheadBisect,
+                    })
+                    .build(
+                        stepper.oid,
+                        StepCounter {
+                            steps: 0,
+                            log: self.command_log.clone(),
+                        },
+                    );
+            compressor.set_time(0.0, clock_freq);
+            stepper.compressor = compressor;
+            stepper.clock_freq = clock_freq;
+        }
+    }
+
+    /// Prepare to queue moves at `est_print_time`, returning when the flush
+    /// timer should next run
+    pub fn prepare_moves(&mut self, est_print_time: f64) -> Result<f64, MotionError> {
+        self.scheduler.prepare(&mut self.toolhead, est_print_time)
+    }
+
+    /// Queue a move to `target` (X/Y/Z/E) at `speed` mm/s, unless it belongs
+    /// to an excluded object. The move is compensated by the active stages,
+    /// e.g. it follows the bed once a mesh is active.
+    pub fn queue_move(&mut self, target: [f64; 4], speed: f64) -> Result<(), MotionError> {
+        let start = std::mem::replace(&mut self.commanded, target);
+        let mut from = self.exclude_object.toolhead_position(start);
+        for point in self.exclude_object.filter_move(start, target) {
+            for mut segment in self
+                .compensation
+                .compensate(from, point, self.segment_length)
+            {
+                segment[3] += self.extruder_offset;
+                self.toolhead.move_to(segment, speed)?;
+            }
+            from = point;
+        }
+        Ok(())
+    }
+
+    /// Position moves were last queued to, before any bed mesh or
+    /// extruder adjustments
+    pub fn commanded_position(&self) -> [f64; 4] {
+        self.commanded
+    }
+
+    /// Queue a move to X/Y/Z `target` at `speed` mm/s without extruding
+    pub fn travel_to(&mut self, target: [f64; 3], speed: f64) -> Result<(), MotionError> {
+        let [x, y, z] = target;
+        self.queue_move([x, y, z, self.commanded[3]], speed)
+    }
+
+    /// Plan moves queued from now on with `accel` mm/s^2 and no minimum
+    /// cruise, as resonance tests need, or with the configured limits again
+    /// if `None`
+    pub fn set_test_accel(&mut self, accel: Option<f64>) {
+        let limits = match accel {
+            Some(accel) => MachineLimits {
+                max_accel: accel,
+                // Test moves do not extrude but must not use travel limits
+                max_travel_velocity: None,
+                max_travel_accel: None,
+                minimum_cruise_ratio: 0.0,
+                ..self.limits
+            },
+            None => self.limits,
+        };
+        self.toolhead.set_limits(limits);
+    }
+
+    /// Retract, lift, and park, returning false if already paused
+    pub fn pause(&mut self) -> Result<bool, MotionError> {
+        if self.pause_resume.is_paused() {
+            return Ok(false);
+        }
+        for mv in self.pause_resume.pause(self.toolhead.position()) {
+            self.toolhead.move_to(mv.target, mv.speed)?;
+        }
+        Ok(true)
+    }
+
+    /// Return to where the print paused and re-prime, returning false if
+    /// not paused
+    pub fn resume(&mut self) -> Result<bool, MotionError> {
+        if !self.pause_resume.is_paused() {
+            return Ok(false);
+        }
+        for mv in self.pause_resume.resume(self.toolhead.position()) {
+            self.toolhead.move_to(mv.target, mv.speed)?;
+        }
+        self.extruder_offset += self.pause_resume.config().prime_length;
+        Ok(true)
+    }
+
+    /// Run the flush timer, returning the print time to run it again or
+    /// `None` once all motion has been flushed
+    pub fn flush_timer(&mut self, est_print_time: f64) -> Result<Option<f64>, MotionError> {
+        let wake = self
+            .scheduler
+            .on_timer(&mut self.toolhead, &mut self.steppers, est_print_time);
+        self.flush_command_log();
+        wake
+    }
+
+    /// Generate and send the steps for every queued move
+    pub fn flush_moves(&mut self, est_print_time: f64) -> Result<(), MotionError> {
+        let flushed =
+            self.scheduler
+                .flush_all(&mut self.toolhead, &mut self.steppers, est_print_time);
+        self.flush_command_log();
+        flushed
+    }
+
+    /// Write out the commands logged so far, so a crash loses none
+    fn flush_command_log(&self) {
+        if let Some(log) = &self.command_log
+            && let Err(e) = lock_log(log).flush()
+        {
+            tracing::warn!("Failed to write step command log: {e}");
+        }
+    }
+
+    pub fn info(&self) -> MachineInfo {
+        MachineInfo {
+            kinematics: self.kinematics.name(),
+            max_velocity: self.limits.max_velocity,
+            max_accel: self.limits.max_accel,
+            max_travel_velocity: self.limits.max_travel_velocity,
+            max_travel_accel: self.limits.max_travel_accel,
+            minimum_cruise_ratio: self.limits.minimum_cruise_ratio,
+            square_corner_velocity: self.limits.square_corner_velocity,
+            rails: self
+                .rails
+                .iter()
+                .zip(AXES)
+                .map(|(rail, axis)| RailInfo {
+                    name: rail.name.clone(),
+                    axis,
+                    step_distance: rail.step_distance,
+                    position_min: rail.position_min,
+                    position_max: rail.position_max,
+                    position_endstop: rail.homing.map(|homing| homing.position_endstop),
+                    homing_positive_dir: rail.homing.map(|homing| homing.positive_dir),
+                })
+                .collect(),
+            extruder_step_distance: self.extruder_step_distance,
+            firmware_retraction: self.retraction.map(Into::into),
+            position: self.toolhead.position(),
+            paused: self.pause_resume.is_paused(),
+            bed_mesh: self.compensation.bed_mesh().map(Into::into),
+            compensation: self.compensation.stages(),
+            current_object: self.exclude_object.current().map(str::to_string),
+            excluded_objects: self.exclude_object.excluded().map(str::to_string).collect(),
+            motion: self.motion_info(),
+        }
+    }
+
+    /// Step generation progress and the depth of each stage of it
+    pub fn motion_info(&self) -> MotionInfo {
+        let stats: FlushStats = self.scheduler.stats();
+        MotionInfo {
+            print_time: self.toolhead.print_time(),
+            flush_time: stats.last_flush_time,
+            buffer_time: stats.buffer_time,
+            flushes: stats.flushes,
+            underruns: stats.underruns,
+            min_flush_lead: stats
+                .min_flush_lead
+                .is_finite()
+                .then_some(stats.min_flush_lead),
+            flush_lead: (stats.flushes > 0).then_some(stats.last_flush_lead),
+            max_timer_lateness: stats.max_timer_lateness,
+            lookahead_moves: self.toolhead.lookahead_len(),
+            trapq_moves: self.toolhead.trapq().active_len(),
+            extruder_trapq_moves: self.toolhead.extruder_trapq().active_len(),
+            steps: self
+                .steppers
+                .iter()
+                .map(|stepper| (stepper.name.clone(), stepper.compressor.sink().steps))
+                .collect(),
+            solvers: self
+                .steppers
+                .iter()
+                .filter_map(|stepper| {
+                    let stats = stepper.solver.stats()?;
+                    Some(SolverInfo {
+                        stepper: stepper.name.clone(),
+                        flushes: stats.flushes,
+                        last_flush: stats.last_flush.into(),
+                        total: stats.total.into(),
+                    })
+                })
+                .collect(),
+            compressors: self
+                .steppers
+                .iter()
+                .map(|stepper| {
+                    let stats = *stepper.compressor.stats();
+                    CompressorInfo {
+                        stepper: stepper.name.clone(),
+                        stats,
+                        average_count: stats.average_count(),
+                        status: stepper.compressor.status(),
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+fn lock_log(log: &SharedCommandLog) -> MutexGuard<'_, CommandLogWriter<BufWriter<File>>> {
+    log.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+impl MachineStepper {
+    fn new(name: &str, oid: u32, mut solver: IterativeSolver<StepperKinematics>) -> Self {
+        solver.enable_stats();
+        Self {
+            name: name.to_string(),
+            oid,
+            extruder: false,
+            clock_freq: 1.0,
+            tuning: CompressorTuning::default(),
+            solver,
+            compressor: StepCompressor::new(oid, 0, StepCounter::default()),
+        }
+    }
+}
+
+impl StepGenerator for MachineStepper {
+    fn generate(
+        &mut self,
+        toolhead: &MotionController,
+        step_gen_time: f64,
+        flush_time: f64,
+    ) -> Result<(), MotionError> {
+        let trapq = if self.extruder {
+            toolhead.extruder_trapq()
+        } else {
+            toolhead.trapq()
+        };
+        self.solver
+            .generate_steps(&mut self.compressor, trapq, step_gen_time)?;
+        self.compressor
+            .flush((flush_time * self.clock_freq) as u64)
+            .map_err(|source| MotionError::StepCompress {
+                oid: self.oid,
+                print_time: flush_time,
+                source,
+            })?;
+        for recovery in self.compressor.take_recoveries() {
+            tracing::warn!(
+                stepper = %self.name,
+                clock = recovery.clock,
+                max_error = recovery.max_error,
+                "step compression recovered: {}",
+                recovery.error
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scherzo_core::planner::MoveType;
+
+    const PRINTER: &str = r#"
+[printer]
+kinematics = "corexy"
+max_velocity = 300
+max_accel = 3000
+
+[stepper_x]
+rotation_distance = 40
+microsteps = 16
+position_max = 235
+endstop_pin = "PA1"
+position_endstop = 235
+
+[stepper_y]
+rotation_distance = 40
+microsteps = 16
+position_max = 235
+position_endstop = 0
+
+[stepper_z]
+rotation_distance = 8
+microsteps = 16
+position_min = -2
+position_max = 250
+position_endstop = 0.5
+homing_positive_dir = false
+
+[extruder]
+rotation_distance = 22.6789511
+microsteps = 16
+step_compression = "exact"
+
+[firmware_retraction]
+retract_length = 0.8
+retract_speed = 35
+"#;
+
+    #[test]
+    fn test_machine_from_config() {
+        let config = Config::from_toml(PRINTER).unwrap();
+        config.validate().unwrap();
+        let machine = Machine::from_config(&config).unwrap().unwrap();
+
+        assert_eq!(machine.kinematics, Kinematics::CoreXY);
+        assert_eq!(machine.limits.max_accel, 3000.0);
+        assert_eq!(machine.limits.square_corner_velocity, 5.0);
+        assert_eq!(machine.rails[0].step_distance, 40.0 / 3200.0);
+        assert!(machine.rails[0].homing.unwrap().positive_dir);
+        assert!(!machine.rails[2].homing.unwrap().positive_dir);
+        assert!(machine.extruder_step_distance.is_some());
+        assert_eq!(
+            machine.steppers[0].tuning.compression,
+            StepCompression::BisectAdd
+        );
+        assert_eq!(
+            machine.steppers[3].tuning.compression,
+            StepCompression::Exact
+        );
+        let retraction = machine.retraction.unwrap();
+        assert_eq!(retraction.retract_length, 0.8);
+        assert_eq!(retraction.unretract_speed, 10.0);
+
+        let info = machine.info();
+        assert_eq!(info.kinematics, "corexy");
+        assert_eq!(info.rails[2].position_endstop, Some(0.5));
+    }
+
+    #[test]
+    fn test_travel_limits_from_config() {
+        let source = PRINTER.replace(
+            "max_accel = 3000",
+            "max_accel = 3000\nmax_travel_accel = 5000",
+        );
+        let config = Config::from_toml(&source).unwrap();
+        let mut machine = Machine::from_config(&config).unwrap().unwrap();
+        assert_eq!(machine.toolhead.limits().max_travel_accel, Some(5000.0));
+
+        // Resonance test moves are travels, but run at the test accel
+        machine.set_test_accel(Some(1000.0));
+        let limits = *machine.toolhead.limits();
+        assert_eq!(limits.max_accel_for(MoveType::Travel), 1000.0);
+        machine.set_test_accel(None);
+        assert_eq!(machine.toolhead.limits().max_travel_accel, Some(5000.0));
+
+        let source = PRINTER.replace("max_accel = 3000", "max_accel = 3000\nmax_travel_accel = 0");
+        let config = Config::from_toml(&source).unwrap();
+        assert!(Machine::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_queue_move_skips_excluded_objects() {
+        let config = Config::from_toml(PRINTER).unwrap();
+        let mut machine = Machine::from_config(&config).unwrap().unwrap();
+        machine.exclude_object.exclude("part_2");
+
+        machine.exclude_object.start("part_1");
+        machine.queue_move([10.0, 0.0, 0.0, 1.0], 100.0).unwrap();
+        machine.exclude_object.start("part_2");
+        machine.queue_move([20.0, 0.0, 0.0, 2.0], 100.0).unwrap();
+        assert_eq!(machine.toolhead.position(), [10.0, 0.0, 0.0, 1.0]);
+        machine.exclude_object.start("part_1");
+        machine.queue_move([20.0, 10.0, 0.0, 3.0], 100.0).unwrap();
+        assert_eq!(machine.toolhead.position(), [20.0, 10.0, 0.0, 2.0]);
+
+        let info = machine.info();
+        assert_eq!(info.current_object.as_deref(), Some("PART_1"));
+        assert_eq!(info.excluded_objects, ["PART_2"]);
+    }
+
+    #[test]
+    fn test_compensation_from_config() {
+        let source = format!(
+            "{PRINTER}\n[compensation]\ntwist = {{ start_x = 0, end_x = 20, z_compensations = [0, 0.1] }}\n\
+             [[compensation.transforms]]\nname = \"lift\"\noffset = [0, 0, 1]\n"
+        );
+        let config = Config::from_toml(&source).unwrap();
+        let mut machine = Machine::from_config(&config).unwrap().unwrap();
+        assert_eq!(
+            machine.info().compensation,
+            vec![
+                Stage::Twist,
+                Stage::User {
+                    name: "lift".into()
+                }
+            ]
+        );
+
+        // Twist raises Z along X, so the move is split to follow it
+        machine.queue_move([20.0, 0.0, 0.0, 1.0], 100.0).unwrap();
+        let [x, _, z, e] = machine.toolhead.position();
+        assert_eq!((x, e), (20.0, 1.0));
+        assert!((z - 1.1).abs() < 1e-12, "{z}");
+
+        let bad = source.replace("end_x = 20", "end_x = -20");
+        let err = Machine::from_config(&Config::from_toml(&bad).unwrap())
+            .err()
+            .unwrap();
+        assert!(format!("{err:#}").contains("start_x"), "{err:#}");
+    }
+
+    #[test]
+    fn test_machine_config_errors() {
+        assert!(
+            Machine::from_config(&Config::from_toml("").unwrap())
+                .unwrap()
+                .is_none()
+        );
+
+        let missing = PRINTER.replace("[stepper_y]", "[unused]");
+        let config = Config::from_toml(&missing).unwrap();
+        let err = Machine::from_config(&config).err().unwrap();
+        assert!(err.to_string().contains("[stepper_y]"), "{err}");
+
+        let bad = PRINTER.replace("\"corexy\"", "\"scara\"");
+        let config = Config::from_toml(&bad).unwrap();
+        assert!(config.validate().is_err());
+
+        let bad = PRINTER.replace("retract_speed = 35", "retract_speed = 0");
+        let err = Machine::from_config(&Config::from_toml(&bad).unwrap())
+            .err()
+            .unwrap();
+        assert!(
+            format!("{err:#}").contains("retract_speed and unretract_speed"),
+            "{err:#}"
+        );
+
+        let orphan = "[stepper_x]\nrotation_distance = 40\nmicrosteps = 16\nposition_max = 200\n";
+        assert!(Config::from_toml(orphan).unwrap().validate().is_err());
+
+        let bad = PRINTER.replace("step_compression = \"exact\"", "sds_filter_time = -1");
+        let err = Machine::from_config(&Config::from_toml(&bad).unwrap())
+            .err()
+            .unwrap();
+        assert!(format!("{err:#}").contains("sds_filter_time"), "{err:#}");
+    }
+
+    #[test]
+    fn test_compressor_tuning_from_config() {
+        let tuned = PRINTER.replace(
+            "step_compression = \"exact\"",
+            "max_step_error = 0.00001\nsds_filter_time = 0\nstep_queue_size = 64",
+        );
+        let mut machine = Machine::from_config(&Config::from_toml(&tuned).unwrap())
+            .unwrap()
+            .unwrap();
+        machine.set_clock_freq(1_000_000.0);
+
+        let x = machine.steppers[0].compressor.config();
+        assert_eq!(x.max_error, 25);
+        assert_eq!(x.sds_filter_time, 0.000_75);
+        let extruder = machine.steppers[3].compressor.config();
+        assert_eq!(extruder.max_error, 10);
+        assert_eq!(extruder.sds_filter_time, 0.0);
+        assert_eq!(extruder.queue_start_size, 64);
+    }
+
+    #[test]
+    fn test_command_log_records_every_step() {
+        use scherzo_core::command_log::{CommandLogReader, LogRecord};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("commands.bin");
+        let logged = PRINTER.replace(
+            "max_accel = 3000\n",
+            &format!(
+                "max_accel = 3000\ncommand_log = {:?}\n",
+                path.to_str().unwrap()
+            ),
+        );
+        let mut machine = Machine::from_config(&Config::from_toml(&logged).unwrap())
+            .unwrap()
+            .unwrap();
+        machine.set_clock_freq(1_000_000.0);
+        machine.prepare_moves(0.0).unwrap();
+        machine.queue_move([10.0, 0.0, 0.0, 0.0], 100.0).unwrap();
+        machine.queue_move([10.0, 10.0, 0.0, 0.0], 100.0).unwrap();
+        machine.flush_moves(0.0).unwrap();
+
+        let records: Vec<_> = CommandLogReader::new(File::open(&path).unwrap())
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(records[0], LogRecord::ClockFreq(1_000_000.0));
+        let logged_steps: u64 = records
+            .iter()
+            .filter_map(|record| match record {
+                LogRecord::Command(Command::QueueStep(step)) => Some(step.count as u64),
+                _ => None,
+            })
+            .sum();
+        let steps: u64 = machine.motion_info().steps.iter().map(|(_, n)| n).sum();
+        assert!(steps > 0);
+        assert_eq!(logged_steps, steps);
+    }
+}
@@ -1,16 +1,42 @@
 use anyhow::Result;
 use clap::{Parser, Subcommand};
 
+mod archive;
+mod bed_mesh;
+mod checkpoint;
 mod cli;
+mod compile_cache;
 mod config;
+mod console;
+mod crash;
+mod dispatch;
+mod executor;
+mod input_shaper;
+mod job_queue;
+mod machine;
+mod metrics;
+mod overrides;
+mod pause;
 mod plugin;
+mod print_stats;
 mod server;
+mod telemetry;
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
     match cli.command {
+        Command::ArcFit(args) => args.run(),
         Command::Compile(args) => args.run(),
+        Command::Config(args) => args.run(),
+        Command::Decompile(args) => args.run(),
+        Command::DeltaCalibrate(args) => args.run(),
+        Command::Estimate(args) => args.run(),
+        Command::HashPassword(args) => args.run(),
+        Command::Plugins(args) => args.run(),
+        Command::Remote(args) => args.run(),
+        Command::Simulate(args) => args.run(),
         Command::Start(args) => args.run(),
+        Command::Validate(args) => args.run(),
     }
 }
 
@@ -23,8 +49,28 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Command {
+    /// Replace runs of short G1 segments in a G-code file with arcs.
+    ArcFit(cli::arc_fit::ArcFitArgs),
     /// Compile a G-code job into WIT, core wasm, and a component.
     Compile(cli::compile::CompileArgs),
+    /// Create or check Scherzo configuration files.
+    Config(cli::config::ConfigArgs),
+    /// Recover G-code (or the embedded WIT) from a compiled job.
+    Decompile(cli::decompile::DecompileArgs),
+    /// Solve delta endstops, radius, and tower angles from probed points.
+    DeltaCalibrate(cli::delta_calibrate::DeltaCalibrateArgs),
+    /// Estimate print time, filament usage, and extents of a G-code job.
+    Estimate(cli::estimate::EstimateArgs),
+    /// Hash a password for the `server.auth.password_hash` config field.
+    HashPassword(cli::hash_password::HashPasswordArgs),
+    /// Inspect plugin components and manage the plugins in a config file.
+    Plugins(cli::plugins::PluginsArgs),
+    /// Talk to a running Scherzo server.
+    Remote(cli::remote::RemoteArgs),
+    /// Run a G-code job against a virtual printer and write its step trace.
+    Simulate(cli::simulate::SimulateArgs),
     /// Start the Scherzo runtime with the specified configuration.
     Start(cli::start::StartArgs),
+    /// Check G-code files or wasm components for errors without running them.
+    Validate(cli::validate::ValidateArgs),
 }
@@ -0,0 +1,221 @@
+//! Prometheus metrics
+//!
+//! `GET /metrics` reports the step pipeline in Prometheus' text format, to
+//! trace stutter in the field to where motion backs up: moves waiting for
+//! the lookahead planner, planned moves on the trapqs, steps buffered in
+//! each stepper's compressor, and how far ahead of the MCU steps are
+//! flushed.
+
+use crate::machine::MotionInfo;
+use std::fmt::Write;
+
+/// Content type of the text exposition format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Metrics of the step pipeline in the text exposition format
+pub fn render(motion: &MotionInfo) -> String {
+    let mut out = Exposition::default();
+
+    out.family(
+        "scherzo_lookahead_moves",
+        "gauge",
+        "Moves waiting for the lookahead planner",
+    );
+    out.sample(
+        "scherzo_lookahead_moves",
+        &[],
+        motion.lookahead_moves as f64,
+    );
+
+    out.family(
+        "scherzo_trapq_moves",
+        "gauge",
+        "Planned moves not yet stepped past",
+    );
+    for (trapq, moves) in [
+        ("toolhead", motion.trapq_moves),
+        ("extruder", motion.extruder_trapq_moves),
+    ] {
+        out.sample("scherzo_trapq_moves", &[("trapq", trapq)], moves as f64);
+    }
+
+    out.family(
+        "scherzo_step_queue_steps",
+        "gauge",
+        "Steps generated but not yet sent to the MCU",
+    );
+    for compressor in &motion.compressors {
+        out.sample(
+            "scherzo_step_queue_steps",
+            &[("stepper", &compressor.stepper)],
+            compressor.status.buffered_steps as f64,
+        );
+    }
+
+    out.family("scherzo_steps_total", "counter", "Steps sent to the MCU");
+    for (stepper, steps) in &motion.steps {
+        out.sample(
+            "scherzo_steps_total",
+            &[("stepper", stepper)],
+            *steps as f64,
+        );
+    }
+
+    out.family(
+        "scherzo_buffer_seconds",
+        "gauge",
+        "Motion queued ahead of the MCU at the last flush timer",
+    );
+    out.sample("scherzo_buffer_seconds", &[], motion.buffer_time);
+
+    if let Some(lead) = motion.flush_lead {
+        out.family(
+            "scherzo_flush_lead_seconds",
+            "gauge",
+            "Lead of the steps over the MCU at the last flush, negative if late",
+        );
+        out.sample("scherzo_flush_lead_seconds", &[], lead);
+    }
+    if let Some(lead) = motion.min_flush_lead {
+        out.family(
+            "scherzo_flush_lead_min_seconds",
+            "gauge",
+            "Smallest lead of the steps over the MCU at any flush",
+        );
+        out.sample("scherzo_flush_lead_min_seconds", &[], lead);
+    }
+
+    out.family(
+        "scherzo_flush_timer_lateness_max_seconds",
+        "gauge",
+        "Largest delay of the flush timer past its schedule",
+    );
+    out.sample(
+        "scherzo_flush_timer_lateness_max_seconds",
+        &[],
+        motion.max_timer_lateness,
+    );
+
+    out.family("scherzo_flushes_total", "counter", "Step generation passes");
+    out.sample("scherzo_flushes_total", &[], motion.flushes as f64);
+
+    out.family(
+        "scherzo_flush_underruns_total",
+        "counter",
+        "Flushes that started after the MCU had caught up with the steps",
+    );
+    out.sample(
+        "scherzo_flush_underruns_total",
+        &[],
+        motion.underruns as f64,
+    );
+
+    out.text
+}
+
+#[derive(Default)]
+struct Exposition {
+    text: String,
+}
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.text, "# HELP {name} {help}");
+        let _ = writeln!(self.text, "# TYPE {name} {kind}");
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.text.push_str(name);
+        if !labels.is_empty() {
+            self.text.push('{');
+            for (i, (label, value)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.text.push(',');
+                }
+                let value = value
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n");
+                let _ = write!(self.text, "{label}=\"{value}\"");
+            }
+            self.text.push('}');
+        }
+        let _ = match value {
+            v if v.is_nan() => writeln!(self.text, " NaN"),
+            v if v == f64::INFINITY => writeln!(self.text, " +Inf"),
+            v if v == f64::NEG_INFINITY => writeln!(self.text, " -Inf"),
+            v => writeln!(self.text, " {v}"),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, machine::Machine};
+
+    const PRINTER: &str = r#"
+[printer]
+kinematics = "cartesian"
+max_velocity = 300
+max_accel = 3000
+
+[stepper_x]
+rotation_distance = 40
+microsteps = 16
+position_max = 235
+
+[stepper_y]
+rotation_distance = 40
+microsteps = 16
+position_max = 235
+
+[stepper_z]
+rotation_distance = 8
+microsteps = 16
+position_max = 250
+"#;
+
+    /// Value of the sample starting with `series`
+    fn value(text: &str, series: &str) -> Option<f64> {
+        text.lines()
+            .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+            .map(|value| value.parse().unwrap())
+    }
+
+    #[test]
+    fn test_reports_the_step_pipeline() {
+        let config = Config::from_toml(PRINTER).unwrap();
+        let mut machine = Machine::from_config(&config).unwrap().unwrap();
+        machine.set_clock_freq(1_000_000.0);
+        machine.prepare_moves(0.0).unwrap();
+        machine.queue_move([10.0, 0.0, 0.0, 0.0], 100.0).unwrap();
+        machine.queue_move([10.0, 10.0, 0.0, 0.0], 100.0).unwrap();
+
+        let queued = render(&machine.motion_info());
+        assert!(queued.contains("# TYPE scherzo_lookahead_moves gauge\n"));
+        assert_eq!(value(&queued, "scherzo_lookahead_moves"), Some(2.0));
+        assert_eq!(value(&queued, "scherzo_flushes_total"), Some(0.0));
+        assert!(!queued.contains("scherzo_flush_lead_seconds"));
+
+        machine.flush_moves(0.0).unwrap();
+        let flushed = render(&machine.motion_info());
+        assert_eq!(value(&flushed, "scherzo_lookahead_moves"), Some(0.0));
+        assert_eq!(value(&flushed, "scherzo_flushes_total"), Some(1.0));
+        assert!(value(&flushed, "scherzo_flush_lead_seconds").is_some());
+        assert_eq!(
+            value(&flushed, r#"scherzo_step_queue_steps{stepper="stepper_x"}"#),
+            Some(0.0)
+        );
+        let steps = value(&flushed, r#"scherzo_steps_total{stepper="stepper_x"}"#);
+        assert!(steps.unwrap() > 0.0, "{flushed}");
+    }
+
+    #[test]
+    fn test_escapes_label_values() {
+        let mut out = Exposition::default();
+        out.sample("m", &[("stepper", "a\"b\\c\nd")], 1.5);
+        out.sample("m", &[], f64::NAN);
+        assert_eq!(out.text, "m{stepper=\"a\\\"b\\\\c\\nd\"} 1.5\nm NaN\n");
+    }
+}
@@ -0,0 +1,170 @@
+//! Live job overrides
+//!
+//! A job's speed factor, extrusion factor, and heater temperature offsets can
+//! be changed through the API, before or while it prints, much as `M220` and
+//! `M221` change the first two from G-code. They are saved with the job and
+//! made live when it starts; the running job's overrides apply to each motion
+//! command dispatched after they change. `M220` and `M221` in the job change
+//! the live overrides too, so whichever of the job and the API spoke last
+//! wins. Those changes last until the job ends and are not saved.
+
+use anyhow::{Result, bail};
+use scherzo_compile::estimate::MotionInterpreter;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+/// Largest speed or extrusion factor accepted
+const MAX_FACTOR: f64 = 10.0;
+
+/// Largest temperature offset (°C) accepted, either way
+const MAX_TEMPERATURE_OFFSET: f64 = 50.0;
+
+/// Adjustments to a job's commanded speeds, extrusion, and temperatures
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobOverrides {
+    /// Multiplies the feedrate of moves
+    #[serde(default = "default_factor")]
+    pub speed_factor: f64,
+    /// Multiplies the extruder distance of moves
+    #[serde(default = "default_factor")]
+    pub extrude_factor: f64,
+    /// Added to the target of each heater (°C) by name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub temperature_offsets: BTreeMap<String, f64>,
+}
+
+fn default_factor() -> f64 {
+    1.0
+}
+
+impl Default for JobOverrides {
+    fn default() -> Self {
+        Self {
+            speed_factor: 1.0,
+            extrude_factor: 1.0,
+            temperature_offsets: BTreeMap::new(),
+        }
+    }
+}
+
+impl JobOverrides {
+    /// Whether these change nothing
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Check the factors are positive and every value is in range
+    pub fn validate(&self) -> Result<()> {
+        for (name, factor) in [
+            ("speed_factor", self.speed_factor),
+            ("extrude_factor", self.extrude_factor),
+        ] {
+            if factor.is_nan() || factor <= 0.0 || factor > MAX_FACTOR {
+                bail!("{name} must be above 0 and at most {MAX_FACTOR}, got {factor}");
+            }
+        }
+        for (heater, offset) in &self.temperature_offsets {
+            if !(-MAX_TEMPERATURE_OFFSET..=MAX_TEMPERATURE_OFFSET).contains(offset) {
+                bail!(
+                    "temperature offset of `{heater}` must be within \
+                     ±{MAX_TEMPERATURE_OFFSET}, got {offset}"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Have `interpreter` scale the moves it produces from now on
+    pub fn apply(&self, interpreter: &mut MotionInterpreter) {
+        if interpreter.speed_factor() != self.speed_factor {
+            interpreter.set_speed_factor(self.speed_factor);
+        }
+        if interpreter.extrude_factor() != self.extrude_factor {
+            interpreter.set_extrude_factor(self.extrude_factor);
+        }
+    }
+
+    /// Target (°C) to set `heater` to when `target` is commanded. A heater
+    /// commanded off stays off.
+    pub fn temperature(&self, heater: &str, target: f64) -> f64 {
+        match self.temperature_offsets.get(heater) {
+            Some(offset) if target > 0.0 => (target + offset).max(0.0),
+            _ => target,
+        }
+    }
+}
+
+/// The overrides of the running job, shared by whatever dispatches its
+/// commands
+#[derive(Clone, Default)]
+pub struct OverrideController {
+    live: Arc<Mutex<JobOverrides>>,
+}
+
+impl OverrideController {
+    /// The overrides in effect
+    pub fn get(&self) -> JobOverrides {
+        self.lock().clone()
+    }
+
+    /// Put `overrides` in effect for the commands dispatched from now on
+    pub fn set(&self, overrides: JobOverrides) {
+        *self.lock() = overrides;
+    }
+
+    /// Change the overrides in effect with `f`, keeping them as they were
+    /// if the result is out of range
+    pub fn update(&self, f: impl FnOnce(&mut JobOverrides)) -> Result<JobOverrides> {
+        let mut live = self.lock();
+        let mut overrides = live.clone();
+        f(&mut overrides);
+        overrides.validate()?;
+        *live = overrides.clone();
+        Ok(overrides)
+    }
+
+    /// Go back to commands as the job gives them
+    pub fn clear(&self) {
+        self.set(JobOverrides::default());
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, JobOverrides> {
+        self.live.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validates_ranges() {
+        assert!(JobOverrides::default().validate().is_ok());
+
+        let slow = JobOverrides {
+            speed_factor: 0.0,
+            ..JobOverrides::default()
+        };
+        assert!(slow.validate().is_err());
+
+        let hot = JobOverrides {
+            temperature_offsets: BTreeMap::from([("extruder".to_string(), 80.0)]),
+            ..JobOverrides::default()
+        };
+        assert!(hot.validate().is_err());
+    }
+
+    #[test]
+    fn test_offsets_heaters_that_are_on() {
+        let overrides = JobOverrides {
+            temperature_offsets: BTreeMap::from([("extruder".to_string(), -5.0)]),
+            ..JobOverrides::default()
+        };
+        assert_eq!(overrides.temperature("extruder", 210.0), 205.0);
+        assert_eq!(overrides.temperature("extruder", 0.0), 0.0);
+        assert_eq!(overrides.temperature("heater_bed", 60.0), 60.0);
+    }
+}
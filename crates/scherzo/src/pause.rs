@@ -0,0 +1,292 @@
+//! Filament runout and pausing
+//!
+//! Filament sensors report through plugins or the API. When a sensor that
+//! pauses on runout reports the filament has run out while a job is running,
+//! the job is paused and the machine retracts, lifts, and parks. Resuming
+//! returns to where the print stopped and re-primes, once every such sensor
+//! reports filament again.
+
+use crate::{config::Config, executor::Executor};
+use anyhow::{Result, anyhow, bail};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// Why a print paused
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PauseReason {
+    /// A filament sensor reported the filament ran out
+    Runout { sensor: String },
+    /// Paused through the API
+    Requested,
+}
+
+/// Last reading of a filament sensor
+#[derive(Debug, Clone, Serialize)]
+pub struct SensorState {
+    pub filament_present: bool,
+    pub pause_on_runout: bool,
+    /// Times the filament ran out
+    pub runouts: u64,
+}
+
+/// Pause state reported by the API
+#[derive(Debug, Clone, Serialize)]
+pub struct PauseStatus {
+    pub paused: bool,
+    pub reason: Option<PauseReason>,
+    /// When the print paused (RFC 3339)
+    pub paused_at: Option<String>,
+    pub sensors: BTreeMap<String, SensorState>,
+}
+
+/// Pauses the running job, returning whether one was running
+type JobHook = Box<dyn Fn(&PauseReason) -> bool + Send + Sync>;
+
+#[derive(Default)]
+struct State {
+    sensors: BTreeMap<String, SensorState>,
+    paused: Option<(PauseReason, String)>,
+}
+
+struct Inner {
+    executor: Option<Arc<Executor>>,
+    state: Mutex<State>,
+    job_hook: Mutex<Option<JobHook>>,
+}
+
+/// Pauses and resumes prints, on request or on filament runout
+#[derive(Clone)]
+pub struct PauseController {
+    inner: Arc<Inner>,
+}
+
+impl PauseController {
+    /// Track the filament sensors in `config`, moving the machine through
+    /// `executor` if there is one
+    pub fn new(config: &Config, executor: Option<Arc<Executor>>) -> Self {
+        let sensors = config
+            .filament_sensor
+            .iter()
+            .map(|(name, sensor)| {
+                let state = SensorState {
+                    filament_present: true,
+                    pause_on_runout: sensor.pause_on_runout,
+                    runouts: 0,
+                };
+                (name.clone(), state)
+            })
+            .collect();
+        Self {
+            inner: Arc::new(Inner {
+                executor,
+                state: Mutex::new(State {
+                    sensors,
+                    paused: None,
+                }),
+                job_hook: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Call `hook` on runout to pause the running job. It returns whether a
+    /// job was running, and so whether the machine should park.
+    pub fn set_job_hook(&self, hook: impl Fn(&PauseReason) -> bool + Send + Sync + 'static) {
+        *self
+            .inner
+            .job_hook
+            .lock()
+            .unwrap_or_else(|err| err.into_inner()) = Some(Box::new(hook));
+    }
+
+    /// Record a reading from `sensor`, returning whether it paused the print
+    pub fn report_sensor(&self, sensor: &str, filament_present: bool) -> Result<bool> {
+        let runout = {
+            let mut state = self.state();
+            let reading = state
+                .sensors
+                .get_mut(sensor)
+                .ok_or_else(|| anyhow!("unknown filament sensor `{sensor}`"))?;
+            let runout = reading.filament_present && !filament_present;
+            reading.filament_present = filament_present;
+            if runout {
+                reading.runouts += 1;
+                tracing::warn!("Filament runout detected by sensor {sensor}");
+            }
+            runout && reading.pause_on_runout && state.paused.is_none()
+        };
+        if !runout {
+            return Ok(false);
+        }
+
+        let reason = PauseReason::Runout {
+            sensor: sensor.to_string(),
+        };
+        let printing = self
+            .inner
+            .job_hook
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .as_ref()
+            .is_some_and(|hook| hook(&reason));
+        if !printing {
+            return Ok(false);
+        }
+        self.pause(reason)
+    }
+
+    /// Pause the machine, returning false if it already was
+    pub fn pause(&self, reason: PauseReason) -> Result<bool> {
+        {
+            let mut state = self.state();
+            if state.paused.is_some() {
+                return Ok(false);
+            }
+            tracing::warn!("Pausing print: {reason:?}");
+            state.paused = Some((reason, chrono::Utc::now().to_rfc3339()));
+        }
+        match &self.inner.executor {
+            Some(executor) => executor.pause(),
+            None => Ok(true),
+        }
+    }
+
+    /// Re-prime and return to where the print paused, returning false if it
+    /// was not paused
+    pub fn resume(&self) -> Result<bool> {
+        {
+            let mut state = self.state();
+            if state.paused.is_none() {
+                return Ok(false);
+            }
+            if let Some((name, _)) = state
+                .sensors
+                .iter()
+                .find(|(_, sensor)| sensor.pause_on_runout && !sensor.filament_present)
+            {
+                bail!("filament sensor `{name}` reports no filament");
+            }
+            state.paused = None;
+        }
+        tracing::info!("Resuming print");
+        match &self.inner.executor {
+            Some(executor) => executor.resume(),
+            None => Ok(true),
+        }
+    }
+
+    pub fn status(&self) -> PauseStatus {
+        let state = self.state();
+        PauseStatus {
+            paused: state.paused.is_some(),
+            reason: state.paused.as_ref().map(|(reason, _)| reason.clone()),
+            paused_at: state.paused.as_ref().map(|(_, at)| at.clone()),
+            sensors: state.sensors.clone(),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.inner
+            .state
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        executor::{HOST_CLOCK_FREQ, HostClock},
+        machine::Machine,
+    };
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    const PRINTER: &str = r#"
+[printer]
+kinematics = "cartesian"
+max_velocity = 300
+max_accel = 3000
+buffer_time_start = 0.05
+
+[stepper_x]
+rotation_distance = 40
+microsteps = 16
+position_max = 235
+
+[stepper_y]
+rotation_distance = 40
+microsteps = 16
+position_max = 235
+
+[stepper_z]
+rotation_distance = 8
+microsteps = 16
+position_max = 250
+
+[extruder]
+rotation_distance = 22.6789511
+microsteps = 16
+
+[pause_resume]
+park_x = 10
+park_y = 30
+z_lift = 2
+travel_speed = 300
+z_speed = 50
+prime_length = 0.5
+
+[filament_sensor.runout]
+"#;
+
+    #[test]
+    fn test_runout_pauses_running_job() {
+        let config = Config::from_toml(PRINTER).unwrap();
+        let machine = Machine::from_config(&config).unwrap().unwrap();
+        let machine = Arc::new(Mutex::new(machine));
+        let executor = Arc::new(Executor::spawn(
+            machine.clone(),
+            HostClock::new(HOST_CLOCK_FREQ),
+        ));
+        let pause = PauseController::new(&config, Some(executor.clone()));
+
+        // Nothing is printing, so a runout only gets recorded
+        assert!(!pause.report_sensor("runout", false).unwrap());
+        assert!(pause.report_sensor("missing", false).is_err());
+        pause.report_sensor("runout", true).unwrap();
+
+        let running = Arc::new(AtomicBool::new(true));
+        pause.set_job_hook({
+            let running = running.clone();
+            move |_| running.swap(false, Ordering::Relaxed)
+        });
+        executor.move_to([20.0, 20.0, 0.3, 2.0], 100.0).unwrap();
+        assert!(pause.report_sensor("runout", false).unwrap());
+        let status = pause.status();
+        assert_eq!(
+            status.reason,
+            Some(PauseReason::Runout {
+                sensor: "runout".to_string()
+            })
+        );
+        assert_eq!(status.sensors["runout"].runouts, 2);
+        assert!(machine.lock().unwrap().pause_resume.is_paused());
+        assert_eq!(
+            machine.lock().unwrap().toolhead.position(),
+            [10.0, 30.0, 2.3, 1.0]
+        );
+
+        // Resuming waits for filament, then restores the position
+        assert!(pause.resume().is_err());
+        pause.report_sensor("runout", true).unwrap();
+        assert!(pause.resume().unwrap());
+        assert!(!pause.status().paused);
+        executor.move_to([30.0, 20.0, 0.3, 3.0], 100.0).unwrap();
+        executor.wait_moves().unwrap();
+        let position = machine.lock().unwrap().toolhead.position();
+        assert!((position[3] - 3.5).abs() < 1e-9, "{position:?}");
+    }
+}
@@ -2,15 +2,23 @@
 ///
 /// This module handles loading WebAssembly plugins, managing their lifecycle,
 /// and maintaining registries for config schemas and command handlers.
-use anyhow::{Context, Result, bail};
+use crate::{
+    config::MacroConfig,
+    console::check_macro,
+    input_shaper::{AccelSample, InputShaperController},
+    pause::PauseController,
+};
+use anyhow::{Context, Result, anyhow, bail};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
+    fmt,
+    str::FromStr,
     sync::{Arc, RwLock},
 };
 use wasmtime::{
     Engine, Store,
-    component::{Component, Linker, ResourceTable},
+    component::{Component, HasSelf, Linker, ResourceTable},
 };
 use wasmtime_wasi::{WasiCtx, WasiCtxBuilder, WasiView};
 
@@ -22,8 +30,8 @@ wasmtime::component::bindgen!({
 
 // Re-export types from the generated bindings for the host side
 pub use scherzo::plugin::types::{
-    CommandHandler as WitCommandHandler, FieldDef as WitFieldDef, FieldType as WitFieldType,
-    Schema as WitSchema,
+    AccelSample as WitAccelSample, CommandHandler as WitCommandHandler, FieldDef as WitFieldDef,
+    FieldType as WitFieldType, Schema as WitSchema,
 };
 
 /// Plugin metadata
@@ -35,6 +43,17 @@ pub struct PluginInfo {
     pub description: Option<String>,
 }
 
+impl From<exports::scherzo::plugin::lifecycle::PluginInfo> for PluginInfo {
+    fn from(info: exports::scherzo::plugin::lifecycle::PluginInfo) -> Self {
+        Self {
+            id: info.id,
+            name: info.name,
+            version: info.version,
+            description: info.description,
+        }
+    }
+}
+
 /// Schema definition for configuration or command parameters
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Schema {
@@ -53,6 +72,170 @@ impl From<WitSchema> for Schema {
     }
 }
 
+impl Schema {
+    /// Validate a config value against this schema
+    ///
+    /// Supports the commonly used subset of JSON Schema: `type`, `enum`,
+    /// `const`, `properties`, `required`, `additionalProperties`, `items`
+    /// and the numeric, string and array bounds. Returns one message per
+    /// violation, prefixed with the JSON pointer of the offending value.
+    pub fn validate(&self, value: &serde_json::Value) -> Result<Vec<String>> {
+        let schema: serde_json::Value =
+            serde_json::from_str(&self.json_schema).context("invalid JSON schema")?;
+        let mut errors = Vec::new();
+        validate_value(&schema, value, "", &mut errors);
+        Ok(errors)
+    }
+}
+
+fn validate_value(
+    schema: &serde_json::Value,
+    value: &serde_json::Value,
+    path: &str,
+    errors: &mut Vec<String>,
+) {
+    use serde_json::Value;
+
+    let Value::Object(schema) = schema else {
+        // `true`/`false` schemas accept or reject everything
+        if schema == &Value::Bool(false) {
+            errors.push(format!("{}: not allowed", display_path(path)));
+        }
+        return;
+    };
+    let mut fail = |message: String| errors.push(format!("{}: {message}", display_path(path)));
+
+    if let Some(ty) = schema.get("type") {
+        let allowed: Vec<&str> = match ty {
+            Value::String(ty) => vec![ty.as_str()],
+            Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !allowed.is_empty() && !allowed.iter().any(|ty| matches_type(ty, value)) {
+            fail(format!("expected {}", allowed.join(" or ")));
+            return;
+        }
+    }
+
+    if let Some(Value::Array(options)) = schema.get("enum")
+        && !options.contains(value)
+    {
+        fail(format!("must be one of {}", Value::Array(options.clone())));
+    }
+    if let Some(expected) = schema.get("const")
+        && expected != value
+    {
+        fail(format!("must be {expected}"));
+    }
+
+    if let Some(n) = value.as_f64() {
+        let bound = |key: &str| schema.get(key).and_then(Value::as_f64);
+        if let Some(min) = bound("minimum")
+            && n < min
+        {
+            fail(format!("must be >= {min}"));
+        }
+        if let Some(max) = bound("maximum")
+            && n > max
+        {
+            fail(format!("must be <= {max}"));
+        }
+        if let Some(min) = bound("exclusiveMinimum")
+            && n <= min
+        {
+            fail(format!("must be > {min}"));
+        }
+        if let Some(max) = bound("exclusiveMaximum")
+            && n >= max
+        {
+            fail(format!("must be < {max}"));
+        }
+    }
+
+    let len_bound = |key: &str| schema.get(key).and_then(Value::as_u64);
+    if let Value::String(s) = value {
+        let len = s.chars().count() as u64;
+        if let Some(min) = len_bound("minLength")
+            && len < min
+        {
+            fail(format!("must be at least {min} characters"));
+        }
+        if let Some(max) = len_bound("maxLength")
+            && len > max
+        {
+            fail(format!("must be at most {max} characters"));
+        }
+    }
+
+    match value {
+        Value::Array(items) => {
+            let len = items.len() as u64;
+            if let Some(min) = len_bound("minItems")
+                && len < min
+            {
+                fail(format!("must have at least {min} items"));
+            }
+            if let Some(max) = len_bound("maxItems")
+                && len > max
+            {
+                fail(format!("must have at most {max} items"));
+            }
+            if let Some(item_schema) = schema.get("items") {
+                for (idx, item) in items.iter().enumerate() {
+                    validate_value(item_schema, item, &format!("{path}/{idx}"), errors);
+                }
+            }
+        }
+        Value::Object(fields) => {
+            if let Some(Value::Array(required)) = schema.get("required") {
+                for name in required.iter().filter_map(Value::as_str) {
+                    if !fields.contains_key(name) {
+                        fail(format!("missing required property `{name}`"));
+                    }
+                }
+            }
+            let properties = schema.get("properties").and_then(Value::as_object);
+            for (name, field) in fields {
+                let field_path = format!("{path}/{name}");
+                match properties.and_then(|p| p.get(name)) {
+                    Some(field_schema) => validate_value(field_schema, field, &field_path, errors),
+                    None => match schema.get("additionalProperties") {
+                        Some(Value::Bool(false)) => {
+                            errors.push(format!("{field_path}: unknown property"))
+                        }
+                        Some(extra) => validate_value(extra, field, &field_path, errors),
+                        None => {}
+                    },
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn matches_type(ty: &str, value: &serde_json::Value) -> bool {
+    use serde_json::Value;
+    match ty {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => match value {
+            Value::Number(n) => {
+                n.is_i64() || n.is_u64() || n.as_f64().is_some_and(|f| f.fract() == 0.0)
+            }
+            _ => false,
+        },
+        _ => true,
+    }
+}
+
+fn display_path(path: &str) -> &str {
+    if path.is_empty() { "/" } else { path }
+}
+
 /// Field type for command parameters
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
@@ -88,6 +271,29 @@ pub struct FieldDef {
     pub required: bool,
     pub description: Option<String>,
     pub default_value: Option<String>,
+    /// Smallest value expected of a numeric field
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub minimum: Option<f64>,
+    /// Largest value expected of a numeric field
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maximum: Option<f64>,
+}
+
+impl FieldDef {
+    /// Why `value` is outside the range this field declares, if it is
+    pub fn check_range(&self, value: f64) -> Option<String> {
+        if let Some(max) = self.maximum
+            && value > max
+        {
+            return Some(format!("exceeds configured max {max}"));
+        }
+        if let Some(min) = self.minimum
+            && value < min
+        {
+            return Some(format!("is below configured min {min}"));
+        }
+        None
+    }
 }
 
 impl From<WitFieldDef> for FieldDef {
@@ -98,27 +304,93 @@ impl From<WitFieldDef> for FieldDef {
             required: fd.required,
             description: fd.description,
             default_value: fd.default_value,
+            minimum: fd.minimum,
+            maximum: fd.maximum,
         }
     }
 }
 
+/// When a command runs relative to the rest of the job
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SchedulingClass {
+    /// Queues motion, so it runs in order and its moves follow the moves
+    /// before it (`rt`)
+    #[serde(alias = "rt")]
+    RealtimeMotion,
+    /// Runs in order with the other commands (`be`)
+    #[serde(alias = "be")]
+    Queued,
+    /// Runs as soon as it is sent, ahead of commands still waiting to run,
+    /// e.g. `M112`
+    Immediate,
+    /// Runs as soon as it is sent, outside the order of the other commands
+    /// and without holding them up, e.g. a status report
+    OutOfBand,
+}
+
+impl SchedulingClass {
+    /// Whether the command waits its turn behind the commands sent before it
+    pub fn is_ordered(self) -> bool {
+        matches!(self, Self::RealtimeMotion | Self::Queued)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::RealtimeMotion => "realtime-motion",
+            Self::Queued => "queued",
+            Self::Immediate => "immediate",
+            Self::OutOfBand => "out-of-band",
+        }
+    }
+}
+
+impl FromStr for SchedulingClass {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "realtime-motion" | "rt" => Self::RealtimeMotion,
+            "queued" | "be" => Self::Queued,
+            "immediate" => Self::Immediate,
+            "out-of-band" => Self::OutOfBand,
+            _ => bail!(
+                "unknown scheduling class '{s}'; expected realtime-motion, queued, immediate \
+                 or out-of-band"
+            ),
+        })
+    }
+}
+
+impl fmt::Display for SchedulingClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
 /// Handler for a G-code command or high-level command
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CommandHandler {
     pub command: String,
     pub params: Vec<FieldDef>,
     pub description: Option<String>,
-    pub scheduling_class: String,
+    pub scheduling_class: SchedulingClass,
 }
 
-impl From<WitCommandHandler> for CommandHandler {
-    fn from(ch: WitCommandHandler) -> Self {
-        Self {
+impl TryFrom<WitCommandHandler> for CommandHandler {
+    type Error = anyhow::Error;
+
+    fn try_from(ch: WitCommandHandler) -> Result<Self> {
+        let scheduling_class = ch
+            .scheduling_class
+            .parse()
+            .with_context(|| format!("Command handler '{}'", ch.command))?;
+        Ok(Self {
             command: ch.command,
             params: ch.params.into_iter().map(Into::into).collect(),
             description: ch.description,
-            scheduling_class: ch.scheduling_class,
-        }
+            scheduling_class,
+        })
     }
 }
 
@@ -130,10 +402,11 @@ pub struct PluginRegistry {
     /// Registered command handlers by handler ID
     command_handlers: Arc<RwLock<HashMap<u32, CommandHandler>>>,
     /// Next handler ID to assign
-    #[allow(dead_code)] // Used by register_command_handler
     next_handler_id: Arc<RwLock<u32>>,
     /// Loaded plugins by plugin ID
     plugins: Arc<RwLock<HashMap<String, PluginInfo>>>,
+    /// Registered G-code macros by upper-cased name
+    macros: Arc<RwLock<HashMap<String, MacroConfig>>>,
 }
 
 impl PluginRegistry {
@@ -142,7 +415,6 @@ impl PluginRegistry {
     }
 
     /// Register a configuration schema
-    #[allow(dead_code)] // Part of public plugin API, will be used by WIT bindings
     pub fn register_config_schema(&self, namespace: String, schema: Schema) -> Result<()> {
         let mut schemas = self.config_schemas.write().unwrap();
         if schemas.contains_key(&namespace) {
@@ -156,7 +428,6 @@ impl PluginRegistry {
     }
 
     /// Register a command handler
-    #[allow(dead_code)] // Part of public plugin API, will be used by WIT bindings
     pub fn register_command_handler(&self, handler: CommandHandler) -> Result<u32> {
         let mut handlers = self.command_handlers.write().unwrap();
         let mut next_id = self.next_handler_id.write().unwrap();
@@ -169,7 +440,6 @@ impl PluginRegistry {
     }
 
     /// Unregister a command handler
-    #[allow(dead_code)] // Part of public plugin API, will be used by WIT bindings
     pub fn unregister_command_handler(&self, handler_id: u32) -> Result<()> {
         let mut handlers = self.command_handlers.write().unwrap();
         if handlers.remove(&handler_id).is_none() {
@@ -178,6 +448,29 @@ impl PluginRegistry {
         Ok(())
     }
 
+    /// Register a G-code macro
+    pub fn register_macro(&self, name: &str, macro_config: MacroConfig) -> Result<()> {
+        check_macro(name, &macro_config).with_context(|| format!("Macro '{name}'"))?;
+        let mut macros = self.macros.write().unwrap();
+        let name = name.to_ascii_uppercase();
+        if macros.contains_key(&name) {
+            bail!("Macro '{}' already registered", name);
+        }
+        macros.insert(name, macro_config);
+        Ok(())
+    }
+
+    /// Get a registered macro by name, in any case
+    pub fn get_macro(&self, name: &str) -> Option<MacroConfig> {
+        let macros = self.macros.read().unwrap();
+        macros.get(&name.to_ascii_uppercase()).cloned()
+    }
+
+    /// Get all registered macros
+    pub fn get_macros(&self) -> HashMap<String, MacroConfig> {
+        self.macros.read().unwrap().clone()
+    }
+
     /// Register a plugin
     pub fn register_plugin(&self, info: PluginInfo) -> Result<()> {
         let mut plugins = self.plugins.write().unwrap();
@@ -209,12 +502,17 @@ impl PluginRegistry {
 pub struct PluginState {
     wasi: WasiCtx,
     table: ResourceTable,
-    #[allow(dead_code)] // Will be used by host function implementations
     registry: PluginRegistry,
+    pause: Option<PauseController>,
+    input_shaper: Option<InputShaperController>,
 }
 
 impl PluginState {
-    pub fn new(registry: PluginRegistry) -> Self {
+    pub fn new(
+        registry: PluginRegistry,
+        pause: Option<PauseController>,
+        input_shaper: Option<InputShaperController>,
+    ) -> Self {
         let wasi = WasiCtxBuilder::new().inherit_stdio().inherit_env().build();
         let table = ResourceTable::new();
 
@@ -222,10 +520,91 @@ impl PluginState {
             wasi,
             table,
             registry,
+            pause,
+            input_shaper,
         }
     }
 }
 
+impl scherzo::plugin::types::Host for PluginState {}
+
+impl scherzo::plugin::registry::Host for PluginState {
+    fn register_config_schema(
+        &mut self,
+        namespace: String,
+        schema: WitSchema,
+    ) -> std::result::Result<(), String> {
+        self.registry
+            .register_config_schema(namespace, schema.into())
+            .map_err(|err| err.to_string())
+    }
+
+    fn register_command_handler(
+        &mut self,
+        handler: WitCommandHandler,
+    ) -> std::result::Result<u32, String> {
+        CommandHandler::try_from(handler)
+            .and_then(|handler| self.registry.register_command_handler(handler))
+            .map_err(|err| format!("{err:#}"))
+    }
+
+    fn unregister_command_handler(&mut self, handler_id: u32) -> std::result::Result<(), String> {
+        self.registry
+            .unregister_command_handler(handler_id)
+            .map_err(|err| err.to_string())
+    }
+
+    fn register_macro(
+        &mut self,
+        name: String,
+        description: Option<String>,
+        gcode: String,
+    ) -> std::result::Result<(), String> {
+        self.registry
+            .register_macro(&name, MacroConfig { description, gcode })
+            .map_err(|err| format!("{err:#}"))
+    }
+
+    fn report_filament_sensor(
+        &mut self,
+        sensor: String,
+        filament_present: bool,
+    ) -> std::result::Result<(), String> {
+        let pause = self
+            .pause
+            .as_ref()
+            .ok_or_else(|| "filament sensors are not available".to_string())?;
+        pause
+            .report_sensor(&sensor, filament_present)
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    }
+
+    fn report_accelerometer_samples(
+        &mut self,
+        chip: String,
+        samples: Vec<WitAccelSample>,
+    ) -> std::result::Result<u32, String> {
+        let input_shaper = self
+            .input_shaper
+            .as_ref()
+            .ok_or_else(|| "accelerometers are not available".to_string())?;
+        let samples: Vec<AccelSample> = samples
+            .into_iter()
+            .map(|sample| AccelSample {
+                time: sample.time,
+                x: sample.x,
+                y: sample.y,
+                z: sample.z,
+            })
+            .collect();
+        input_shaper
+            .report_samples(&chip, &samples)
+            .map(|recorded| recorded as u32)
+            .map_err(|err| format!("{err:#}"))
+    }
+}
+
 impl WasiView for PluginState {
     fn ctx(&mut self) -> wasmtime_wasi::WasiCtxView<'_> {
         wasmtime_wasi::WasiCtxView {
@@ -239,6 +618,10 @@ impl WasiView for PluginState {
 pub struct PluginManager {
     engine: Engine,
     registry: PluginRegistry,
+    /// Where plugins report filament sensor readings
+    pause: Option<PauseController>,
+    /// Where plugins report accelerometer samples
+    input_shaper: Option<InputShaperController>,
 }
 
 impl PluginManager {
@@ -246,16 +629,31 @@ impl PluginManager {
         Self {
             engine,
             registry: PluginRegistry::new(),
+            pause: None,
+            input_shaper: None,
         }
     }
 
+    /// Let plugins loaded from now on report filament sensors to `pause`
+    pub fn set_pause_controller(&mut self, pause: PauseController) {
+        self.pause = Some(pause);
+    }
+
+    /// Let plugins loaded from now on report accelerometer samples to
+    /// `input_shaper`
+    pub fn set_input_shaper_controller(&mut self, input_shaper: InputShaperController) {
+        self.input_shaper = Some(input_shaper);
+    }
+
     /// Get a reference to the plugin registry
     pub fn registry(&self) -> &PluginRegistry {
         &self.registry
     }
 
     /// Load a plugin from a WebAssembly component file
-    pub fn load_plugin(&mut self, path: &str, _config: &str) -> Result<PluginInfo> {
+    pub fn load_plugin(&mut self, path: &str, config: &str) -> Result<PluginInfo> {
+        let span = tracing::info_span!("plugin", path, plugin_id = tracing::field::Empty);
+        let _entered = span.enter();
         tracing::info!("Loading plugin from: {}", path);
 
         // Read the plugin file
@@ -270,23 +668,28 @@ impl PluginManager {
         let linker = self.create_plugin_linker()?;
 
         // Create store with plugin state
-        let state = PluginState::new(self.registry.clone());
+        let state = PluginState::new(
+            self.registry.clone(),
+            self.pause.clone(),
+            self.input_shaper.clone(),
+        );
         let mut store = Store::new(&self.engine, state);
 
         // Instantiate the component
-        let _instance = linker
-            .instantiate(&mut store, &component)
+        let plugin = Plugin::instantiate(&mut store, &component, &linker)
             .with_context(|| format!("Failed to instantiate plugin: {}", path))?;
 
-        // TODO: Call get-info to get plugin metadata
-        // TODO: Call init with the config
-        // For now, create placeholder info
-        let info = PluginInfo {
-            id: format!("plugin-{}", path),
-            name: path.to_string(),
-            version: "0.1.0".to_string(),
-            description: Some(format!("Plugin loaded from {}", path)),
-        };
+        let lifecycle = plugin.scherzo_plugin_lifecycle();
+        let info: PluginInfo = lifecycle
+            .call_get_info(&mut store)
+            .with_context(|| format!("Failed to get plugin info: {}", path))?
+            .into();
+        span.record("plugin_id", &info.id);
+
+        lifecycle
+            .call_init(&mut store, config)
+            .with_context(|| format!("Failed to initialize plugin: {}", path))?
+            .map_err(|err| anyhow!("Plugin '{}' failed to initialize: {}", info.id, err))?;
 
         // Register the plugin
         self.registry.register_plugin(info.clone())?;
@@ -303,9 +706,9 @@ impl PluginManager {
         wasmtime_wasi::p2::add_to_linker_sync(&mut linker)
             .context("Failed to add WASI to plugin linker")?;
 
-        // TODO: Add plugin registry functions
-        // This will require using wasmtime's component model bindings
-        // For now, we have the structure in place
+        // Add the host registry interface
+        Plugin::add_to_linker::<_, HasSelf<_>>(&mut linker, |state| state)
+            .context("Failed to add registry to plugin linker")?;
 
         Ok(linker)
     }
@@ -315,6 +718,27 @@ impl PluginManager {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_scheduling_class_names() {
+        for class in [
+            SchedulingClass::RealtimeMotion,
+            SchedulingClass::Queued,
+            SchedulingClass::Immediate,
+            SchedulingClass::OutOfBand,
+        ] {
+            assert_eq!(class.to_string().parse::<SchedulingClass>().unwrap(), class);
+        }
+        assert_eq!(
+            "rt".parse::<SchedulingClass>().unwrap(),
+            SchedulingClass::RealtimeMotion
+        );
+        assert_eq!(
+            "be".parse::<SchedulingClass>().unwrap(),
+            SchedulingClass::Queued
+        );
+        assert!("urgent".parse::<SchedulingClass>().is_err());
+    }
+
     #[test]
     fn test_registry_config_schema() {
         let registry = PluginRegistry::new();
@@ -340,6 +764,39 @@ mod tests {
         assert!(schemas.contains_key("test"));
     }
 
+    #[test]
+    fn test_schema_validation() {
+        let schema = Schema {
+            json_schema: r#"{
+                "type": "object",
+                "required": ["pin"],
+                "additionalProperties": false,
+                "properties": {
+                    "pin": {"type": "string", "minLength": 1},
+                    "speed": {"type": "number", "minimum": 0, "maximum": 1},
+                    "modes": {"type": "array", "items": {"enum": ["auto", "manual"]}}
+                }
+            }"#
+            .to_string(),
+            description: None,
+        };
+
+        let ok = serde_json::json!({"pin": "PA1", "speed": 0.5, "modes": ["auto"]});
+        assert!(schema.validate(&ok).unwrap().is_empty());
+
+        let bad = serde_json::json!({"speed": 2, "modes": ["off"], "extra": true});
+        let errors = schema.validate(&bad).unwrap();
+        assert_eq!(errors.len(), 4, "{errors:?}");
+        assert!(
+            errors
+                .iter()
+                .any(|e| e.contains("missing required property `pin`"))
+        );
+        assert!(errors.iter().any(|e| e.starts_with("/speed:")));
+        assert!(errors.iter().any(|e| e.starts_with("/modes/0:")));
+        assert!(errors.iter().any(|e| e.starts_with("/extra:")));
+    }
+
     #[test]
     fn test_registry_command_handler() {
         let registry = PluginRegistry::new();
@@ -352,9 +809,11 @@ mod tests {
                 required: false,
                 description: Some("X coordinate".to_string()),
                 default_value: None,
+                minimum: None,
+                maximum: None,
             }],
             description: Some("Linear move".to_string()),
-            scheduling_class: "rt".to_string(),
+            scheduling_class: SchedulingClass::RealtimeMotion,
         };
 
         let id = registry.register_command_handler(handler).unwrap();
@@ -368,6 +827,27 @@ mod tests {
         assert!(registry.unregister_command_handler(id).is_err());
     }
 
+    #[test]
+    fn test_registry_macro() {
+        let registry = PluginRegistry::new();
+        let prime = MacroConfig {
+            description: Some("Prime the nozzle".to_string()),
+            gcode: "G1 E{params.LENGTH|default(5)}".to_string(),
+        };
+
+        registry.register_macro("prime", prime.clone()).unwrap();
+        assert!(registry.register_macro("PRIME", prime.clone()).is_err());
+        assert_eq!(registry.get_macro("Prime"), Some(prime.clone()));
+        assert_eq!(registry.get_macros().len(), 1);
+
+        let broken = MacroConfig {
+            description: None,
+            gcode: "{% if %}".to_string(),
+        };
+        assert!(registry.register_macro("BROKEN", broken).is_err());
+        assert!(registry.register_macro("G1", prime).is_err());
+    }
+
     #[test]
     fn test_registry_plugin_info() {
         let registry = PluginRegistry::new();
@@ -0,0 +1,184 @@
+//! Print statistics
+//!
+//! Tracks the job being printed: how long it has run, how much filament it
+//! has used, the layer it is on, and how far through the program it is. A
+//! job reports progress by counting its `submit` calls, which the compiler's
+//! source map turns into a line, layer, and filament total.
+
+use scherzo_compile::source_map::SourceMap;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+/// What the printer is doing with a job
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PrintState {
+    #[default]
+    Standby,
+    Printing,
+    Paused,
+    Complete,
+    Cancelled,
+    Error,
+}
+
+/// Statistics for the current or last print
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PrintStats {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<Uuid>,
+    pub state: PrintState,
+    /// Seconds since the print started, including pauses
+    pub total_duration: f64,
+    /// Seconds spent printing, excluding pauses
+    pub print_duration: f64,
+    /// Net filament (mm) extruded
+    pub filament_used: f64,
+    /// Layer being printed (1-based), once the first layer has started
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_layer: Option<usize>,
+    pub total_layers: usize,
+    /// Source line of the last statement run, if the job is G-code
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_line: Option<usize>,
+    /// Fraction (0 to 1) of the job's statements that have run
+    pub progress: f64,
+}
+
+/// Keeps the statistics of the print in progress up to date
+#[derive(Debug, Default)]
+pub struct PrintStatsTracker {
+    stats: PrintStats,
+    source_map: Option<Arc<SourceMap>>,
+    started: Option<Instant>,
+    paused_since: Option<Instant>,
+    paused: Duration,
+    finished: Option<Instant>,
+}
+
+impl PrintStatsTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start tracking `job_id`, using its source map if it was compiled from
+    /// G-code
+    pub fn start(&mut self, job_id: Uuid, source_map: Option<Arc<SourceMap>>) {
+        *self = Self {
+            stats: PrintStats {
+                job_id: Some(job_id),
+                state: PrintState::Printing,
+                total_layers: source_map.as_ref().map_or(0, |map| map.layers),
+                ..PrintStats::default()
+            },
+            source_map,
+            started: Some(Instant::now()),
+            ..Self::default()
+        };
+    }
+
+    /// Record that the job has submitted `submitted` statements
+    pub fn statements_submitted(&mut self, submitted: usize) {
+        let Some(map) = &self.source_map else {
+            return;
+        };
+        self.stats.progress = map.progress(submitted);
+        let Some(entry) = submitted.checked_sub(1).and_then(|i| map.entries.get(i)) else {
+            return;
+        };
+        self.stats.current_line = Some(entry.line);
+        self.stats.current_layer = (entry.layer > 0).then_some(entry.layer);
+        self.stats.filament_used = entry.filament_mm;
+    }
+
+    /// Whether `job_id` is the job being printed
+    pub fn is_active(&self, job_id: &Uuid) -> bool {
+        self.stats.job_id.as_ref() == Some(job_id)
+            && matches!(self.stats.state, PrintState::Printing | PrintState::Paused)
+    }
+
+    pub fn pause(&mut self) {
+        if self.stats.state == PrintState::Printing {
+            self.stats.state = PrintState::Paused;
+            self.paused_since = Some(Instant::now());
+        }
+    }
+
+    pub fn resume(&mut self) {
+        if self.stats.state == PrintState::Paused {
+            self.stats.state = PrintState::Printing;
+            if let Some(since) = self.paused_since.take() {
+                self.paused += since.elapsed();
+            }
+        }
+    }
+
+    /// Stop tracking with a final `state`, returning the final statistics
+    pub fn finish(&mut self, state: PrintState) -> PrintStats {
+        self.resume();
+        self.finished = Some(Instant::now());
+        self.stats.state = state;
+        if state == PrintState::Complete {
+            self.stats.progress = 1.0;
+        }
+        self.stats()
+    }
+
+    /// Statistics as of now
+    pub fn stats(&self) -> PrintStats {
+        let mut stats = self.stats.clone();
+        if let Some(started) = self.started {
+            let now = self.finished.unwrap_or_else(Instant::now);
+            let total = now.duration_since(started);
+            let paused = self.paused
+                + self
+                    .paused_since
+                    .map_or(Duration::ZERO, |since| now - since);
+            stats.total_duration = total.as_secs_f64();
+            stats.print_duration = total.saturating_sub(paused).as_secs_f64();
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tracks_progress_through_source_map() {
+        let statements =
+            scherzo_gcode::parse("G1 Z0.2 F600\nG1 X10 E1\nG1 Z0.4\nG1 X0 E2\n").unwrap();
+        let map = Arc::new(SourceMap::build(&statements));
+        let job = Uuid::new_v4();
+
+        let mut tracker = PrintStatsTracker::new();
+        assert_eq!(tracker.stats().state, PrintState::Standby);
+        tracker.start(job, Some(map));
+        assert!(tracker.is_active(&job));
+
+        tracker.statements_submitted(2);
+        let stats = tracker.stats();
+        assert_eq!(stats.state, PrintState::Printing);
+        assert_eq!(stats.current_line, Some(2));
+        assert_eq!(stats.current_layer, Some(1));
+        assert_eq!(stats.total_layers, 2);
+        assert_eq!(stats.filament_used, 1.0);
+        assert_eq!(stats.progress, 0.5);
+
+        tracker.pause();
+        assert_eq!(tracker.stats().state, PrintState::Paused);
+        tracker.resume();
+        tracker.statements_submitted(4);
+        let stats = tracker.finish(PrintState::Complete);
+        assert!(!tracker.is_active(&job));
+        assert_eq!(stats.current_layer, Some(2));
+        assert_eq!(stats.progress, 1.0);
+        assert!(stats.print_duration <= stats.total_duration);
+        assert_eq!(tracker.stats().total_duration, stats.total_duration);
+    }
+}
@@ -1,35 +1,99 @@
-use crate::config::{Config, verify_password};
+use crate::{
+    archive::{ArchiveFormat, Asset, Bundle},
+    bed_mesh::BedMeshController,
+    checkpoint::{Checkpoint, CheckpointWriter},
+    compile_cache::{self, CacheKey, CompileCache},
+    config::{AuthConfig, Config, verify_password},
+    console::Console,
+    crash::CrashReporter,
+    dispatch::CommandRegistry,
+    input_shaper::{AccelSample, Axis, InputShaperController},
+    job_queue::JobQueue,
+    machine::{Machine, RailInfo},
+    metrics,
+    overrides::JobOverrides,
+    pause::{PauseController, PauseReason},
+    print_stats::{PrintState, PrintStats, PrintStatsTracker},
+    telemetry,
+};
 use anyhow::{Context, Result};
 use axum::{
     Router,
     body::Body,
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::{Request, StatusCode},
     middleware::{self, Next},
     response::{IntoResponse, Response},
     routing::{delete, get, post, put},
 };
+use scherzo_compile::{
+    diff::{CommandChange, diff_programs},
+    estimate::{MotionInterpreter, estimate_statements},
+    job_template::{JobTemplate, TemplateVariable},
+    objects::ObjectDefinition,
+    render::Toolpath,
+    resume::resume_program,
+    source_map::SourceMap,
+};
+use scherzo_core::{MotionError, exclude_object::ExcludeObject, planner::MachineLimits};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap, HashSet},
     fs,
     path::PathBuf,
-    sync::{Arc, RwLock},
+    sync::{Arc, Condvar, Mutex, RwLock},
+    thread::{self, JoinHandle},
+    time::Duration,
 };
 use tower_http::trace::TraceLayer;
+use tracing::{Span, field};
 use uuid::Uuid;
 
 /// Shared application state
 #[derive(Clone)]
 pub struct AppState {
     config: Arc<Config>,
-    jobs: Arc<RwLock<JobStore>>,
+    jobs: JobStore,
+    machine: Option<Arc<Mutex<Machine>>>,
+    pause: PauseController,
+    bed_mesh: BedMeshController,
+    input_shaper: InputShaperController,
+    console: Console,
+    crash: CrashReporter,
+    print_stats: Arc<Mutex<PrintStatsTracker>>,
+    job_queue: Arc<Mutex<JobQueue>>,
+    /// Signalled, with `job_queue` locked, when the job runner may have
+    /// work: a job was enqueued or resumed, or the queue released
+    queue_changed: Arc<Condvar>,
+    compile_cache: Arc<CompileCache>,
 }
 
-/// In-memory job store with metadata
+/// Job store, keeping each job's metadata next to its component so jobs
+/// survive a restart
+///
+/// Metadata is read and changed in memory behind a lock that is never held
+/// while touching the disk: a job's component is written before the job is
+/// added, and its metadata is saved once the lock is released. Each change
+/// bumps the job's revision, so a save that lost the race to a newer change
+/// is skipped instead of overwriting it.
+#[derive(Clone)]
 pub struct JobStore {
+    index: Arc<RwLock<JobIndex>>,
+    storage_dir: Arc<PathBuf>,
+    /// Held while writing or deleting a job's files
+    disk: Arc<Mutex<()>>,
+}
+
+/// The jobs as the server currently sees them
+#[derive(Default)]
+struct JobIndex {
     jobs: HashMap<Uuid, JobMetadata>,
-    storage_dir: PathBuf,
+    /// Bumped by each change to a job's metadata
+    revisions: HashMap<Uuid, u64>,
+    /// Source maps of the jobs compiled from G-code
+    source_maps: HashMap<Uuid, Arc<SourceMap>>,
+    /// Root spans of the jobs that have not finished
+    spans: HashMap<Uuid, Span>,
 }
 
 /// Metadata for a stored job
@@ -44,6 +108,129 @@ pub struct JobMetadata {
     /// The original format uploaded (e.g., "gcode" or "wasm")
     #[serde(skip_serializing_if = "Option::is_none")]
     pub original_format: Option<String>,
+    /// Whether `G10`/`G11` use the printer's firmware retraction
+    #[serde(default = "crate::config::default_true")]
+    pub firmware_retraction: bool,
+    /// Objects labelled by the slicer
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub objects: Vec<JobObject>,
+    /// Names of the objects excluded from the print
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub excluded_objects: Vec<String>,
+    /// Final statistics, once the job has printed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub print_stats: Option<PrintStats>,
+    /// Job this one continues from a power loss recovery checkpoint
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resumed_from: Option<Uuid>,
+    /// Variables declared by a G-code template, resolved at enqueue
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub template_variables: Vec<JobTemplateVariable>,
+    /// Values the template was last resolved with; defaults fill the rest
+    #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+    pub template_values: serde_json::Map<String, serde_json::Value>,
+    /// Speed, extrusion, and temperature overrides, applied live while the
+    /// job prints
+    #[serde(default, skip_serializing_if = "JobOverrides::is_default")]
+    pub overrides: JobOverrides,
+    /// Problems found when the job was compiled that do not stop it printing
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub diagnostics: Vec<JobDiagnostic>,
+    /// Files uploaded in the job's archive besides its G-code, like
+    /// thumbnails and slicer settings
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub assets: Vec<JobAsset>,
+    /// How the component was compiled, if the server compiled it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build: Option<JobBuild>,
+}
+
+/// What a job's component was compiled from and with, to tell whether
+/// compiling it again would give the same component
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobBuild {
+    /// Version of the compiler
+    pub compiler_version: String,
+    /// SHA-256 of the compile options and the commands jobs could import
+    pub options_hash: String,
+    /// SHA-256 of the G-code compiled
+    pub input_hash: String,
+}
+
+impl JobBuild {
+    fn new(
+        gcode: &str,
+        options: &scherzo_compile::CompileOptions,
+        commands: &BTreeSet<String>,
+    ) -> Self {
+        Self {
+            compiler_version: scherzo_compile::VERSION.to_string(),
+            options_hash: compile_cache::options_hash(options, commands),
+            input_hash: compile_cache::input_hash(gcode),
+        }
+    }
+}
+
+/// A file kept with a job, served from `/jobs/{id}/assets/{path}`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobAsset {
+    /// Path in the uploaded archive
+    pub path: String,
+    pub size_bytes: u64,
+}
+
+/// A warning about a job, e.g. a parameter past the range its plugin
+/// declares
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobDiagnostic {
+    /// Stable identifier of the kind of warning, e.g. `param_out_of_range`
+    #[serde(default = "JobDiagnostic::out_of_range")]
+    pub code: String,
+    /// Source line of the statement it concerns
+    pub line: usize,
+    pub message: String,
+}
+
+impl JobDiagnostic {
+    fn out_of_range() -> String {
+        "param_out_of_range".to_string()
+    }
+}
+
+/// A variable of a job template, with the value used if none is given
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobTemplateVariable {
+    pub name: String,
+    pub default: serde_json::Value,
+}
+
+impl From<&TemplateVariable> for JobTemplateVariable {
+    fn from(variable: &TemplateVariable) -> Self {
+        Self {
+            name: variable.name.clone(),
+            default: variable.default.clone(),
+        }
+    }
+}
+
+/// An object a job prints, which can be excluded mid-print
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobObject {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub center: Option<[f64; 2]>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub polygon: Vec<[f64; 2]>,
+}
+
+impl From<ObjectDefinition> for JobObject {
+    fn from(object: ObjectDefinition) -> Self {
+        Self {
+            name: object.name,
+            center: object.center,
+            polygon: object.polygon,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -52,18 +239,33 @@ pub enum JobStatus {
     Uploaded,
     Enqueued,
     Running,
+    Paused,
     Completed,
     Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    /// Whether the job has stopped for good and can no longer change state
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            JobStatus::Completed | JobStatus::Failed | JobStatus::Cancelled
+        )
+    }
 }
 
 /// Response when a job is successfully uploaded
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct UploadResponse {
     pub job_id: Uuid,
     pub url: String,
     /// If the job was compiled from a different format (e.g., "gcode")
     #[serde(skip_serializing_if = "Option::is_none")]
     pub compiled_from: Option<String>,
+    /// Warnings found while compiling, also kept in the job's metadata
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub diagnostics: Vec<JobDiagnostic>,
 }
 
 /// Request to rename a job
@@ -72,6 +274,181 @@ pub struct RenameRequest {
     pub name: String,
 }
 
+/// Request to enqueue a job, resolving its template first
+#[derive(Deserialize)]
+pub struct EnqueueRequest {
+    /// Values for the template's variables; others take their defaults
+    #[serde(default)]
+    pub variables: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Request to dry run a job
+#[derive(Deserialize)]
+pub struct DryRunRequest {
+    /// Seconds between samples (default 0.1)
+    #[serde(default = "default_dry_run_interval")]
+    pub interval: f64,
+}
+
+fn default_dry_run_interval() -> f64 {
+    0.1
+}
+
+/// A job's planned motion, sampled over time
+#[derive(Serialize)]
+pub struct DryRunResponse {
+    /// Seconds the job's motion takes
+    pub duration: f64,
+    pub estimated_duration: String,
+    /// Seconds between samples, wider than requested for long jobs
+    pub interval: f64,
+    pub samples: Vec<DryRunSample>,
+}
+
+/// Where the toolhead is at a point of a dry run
+#[derive(Serialize)]
+pub struct DryRunSample {
+    pub time: f64,
+    /// X/Y/Z/E position (mm)
+    pub position: [f64; 4],
+    /// Speed (mm/s) along the current move
+    pub velocity: f64,
+}
+
+/// How two jobs differ, `a` being the one compared against
+#[derive(Serialize)]
+pub struct JobDiffResponse {
+    /// Whether both run the same commands, ignoring comments and formatting
+    pub identical: bool,
+    /// Commands only one job has, in order, or none if they differ by too
+    /// many to list
+    pub changes: Option<Vec<JobCommandChange>>,
+    pub commands: Compared<usize>,
+    /// First targets (°C) of the heaters whose targets differ
+    pub temperatures: Vec<JobTemperatureChange>,
+    pub size_bytes: Compared<u64>,
+    pub estimated_seconds: Compared<f64>,
+    pub filament_mm: Compared<f64>,
+    pub layers: Compared<usize>,
+    /// Labelled objects only `b` prints
+    pub objects_added: Vec<String>,
+    /// Labelled objects only `a` prints
+    pub objects_removed: Vec<String>,
+}
+
+/// A value of the two jobs of a diff
+#[derive(Serialize)]
+pub struct Compared<T> {
+    pub a: T,
+    pub b: T,
+}
+
+/// A command only one job of a diff has
+#[derive(Serialize)]
+pub struct JobCommandChange {
+    /// `removed` if only `a` has it, `added` if only `b` does
+    pub change: &'static str,
+    /// Line of the command in the job that has it
+    pub line: usize,
+    pub command: String,
+}
+
+/// A heater the jobs of a diff first heat to different targets
+#[derive(Serialize)]
+pub struct JobTemperatureChange {
+    pub heater: &'static str,
+    pub a: Option<f64>,
+    pub b: Option<f64>,
+}
+
+/// Request to toggle firmware retraction for a job
+#[derive(Deserialize)]
+pub struct FirmwareRetractionRequest {
+    pub enabled: bool,
+}
+
+/// Request to change a job's overrides; fields left out keep their values
+#[derive(Deserialize)]
+pub struct JobOverridesRequest {
+    pub speed_factor: Option<f64>,
+    pub extrude_factor: Option<f64>,
+    /// Offsets by heater, replacing those of the heaters named
+    #[serde(default)]
+    pub temperature_offsets: HashMap<String, f64>,
+}
+
+impl JobOverridesRequest {
+    fn apply(&self, overrides: &mut JobOverrides) {
+        if let Some(factor) = self.speed_factor {
+            overrides.speed_factor = factor;
+        }
+        if let Some(factor) = self.extrude_factor {
+            overrides.extrude_factor = factor;
+        }
+        for (heater, &offset) in &self.temperature_offsets {
+            if offset == 0.0 {
+                overrides.temperature_offsets.remove(heater);
+            } else {
+                overrides.temperature_offsets.insert(heater.clone(), offset);
+            }
+        }
+    }
+}
+
+/// Request to exclude an object from a job
+#[derive(Deserialize)]
+pub struct ExcludeObjectRequest {
+    pub name: String,
+}
+
+/// Reading reported by a filament sensor
+#[derive(Deserialize)]
+pub struct FilamentSensorRequest {
+    pub filament_present: bool,
+}
+
+/// Bed height measured at the current calibration point
+#[derive(Deserialize)]
+pub struct ProbeHeightRequest {
+    pub z: f64,
+}
+
+/// Axis to run input shaper calibration on
+#[derive(Deserialize)]
+pub struct InputShaperCalibrateRequest {
+    pub axis: Axis,
+}
+
+/// Samples reported by an accelerometer
+#[derive(Deserialize)]
+pub struct AccelerometerSamplesRequest {
+    pub samples: Vec<AccelSample>,
+}
+
+/// Number of accelerometer samples kept for a running resonance test
+#[derive(Serialize)]
+pub struct AccelerometerSamplesResponse {
+    pub recorded: usize,
+}
+
+/// Queued jobs in the order to print them
+#[derive(Deserialize)]
+pub struct QueueOrderRequest {
+    pub jobs: Vec<Uuid>,
+}
+
+/// G-code to run through the console
+#[derive(Deserialize)]
+pub struct GCodeScriptRequest {
+    pub script: String,
+}
+
+/// Lines the console responded with
+#[derive(Serialize)]
+pub struct GCodeScriptResponse {
+    pub output: Vec<String>,
+}
+
 /// Response with job time estimate
 #[derive(Serialize)]
 pub struct EstimateResponse {
@@ -86,56 +463,704 @@ pub struct PreviewResponse {
     pub summary: String,
 }
 
+/// A layer of a job's toolpath
+#[derive(Serialize)]
+pub struct LayerSummary {
+    /// 1-based layer number
+    pub number: usize,
+    pub z: f64,
+    /// Number of extruding moves in the layer
+    pub segments: usize,
+}
+
+/// Query for a rasterized layer
+#[derive(Deserialize)]
+struct LayerImageQuery {
+    /// Length (pixels) of the image's longer side
+    size: Option<u32>,
+}
+
+/// Default length (pixels) of a layer PNG's longer side
+const LAYER_PNG_SIZE: u32 = 512;
+/// Largest layer PNG served, in pixels along the longer side
+const LAYER_PNG_MAX_SIZE: u32 = 4096;
+
+/// Longest the job runner sleeps before looking at a paused job or the
+/// queue again, in case it missed a signal
+const RUNNER_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
 impl AppState {
-    pub fn new(config: Config) -> Result<Self> {
+    pub fn new(
+        config: Config,
+        machine: Option<Arc<Mutex<Machine>>>,
+        pause: PauseController,
+        bed_mesh: BedMeshController,
+        input_shaper: InputShaperController,
+        console: Console,
+        crash: CrashReporter,
+    ) -> Result<Self> {
         let storage_dir = PathBuf::from(&config.jobs.storage_dir);
         fs::create_dir_all(&storage_dir).context("failed to create jobs storage directory")?;
 
-        let jobs = JobStore {
-            jobs: HashMap::new(),
-            storage_dir,
-        };
+        let compile_cache = CompileCache::open(
+            storage_dir.join("compile_cache"),
+            config.jobs.compile_cache_size_bytes,
+        )?;
+        let jobs = JobStore::load(storage_dir)?;
+        let print_stats = Arc::new(Mutex::new(PrintStatsTracker::new()));
+
+        // Jobs left enqueued at the last shutdown print in upload order
+        let mut job_queue = JobQueue::new(&config.job_queue);
+        for metadata in jobs.list_jobs() {
+            if metadata.status == JobStatus::Enqueued {
+                job_queue.push(metadata.id);
+            }
+        }
+        let job_queue = Arc::new(Mutex::new(job_queue));
+
+        // Filament runout pauses whichever job is running
+        pause.set_job_hook({
+            let jobs = jobs.clone();
+            let print_stats = print_stats.clone();
+            move |reason| {
+                print_stats.lock().unwrap().pause();
+                let mut paused = false;
+                for metadata in jobs.list_jobs() {
+                    let pause = |metadata: &mut JobMetadata| {
+                        if metadata.status != JobStatus::Running {
+                            return Err(AppError::InvalidJobStatus {
+                                message: "job is not running".to_string(),
+                                status: metadata.status.clone(),
+                            });
+                        }
+                        tracing::warn!("Pausing job {}: {reason:?}", metadata.name);
+                        metadata.status = JobStatus::Paused;
+                        Ok(())
+                    };
+                    if metadata.status == JobStatus::Running {
+                        paused |= jobs.update_job(&metadata.id, pause).is_ok();
+                    }
+                }
+                paused
+            }
+        });
+
+        // Macros see the printer much as Klipper's `printer` object
+        console.set_status_hook({
+            let machine = machine.clone();
+            let pause = pause.clone();
+            let print_stats = print_stats.clone();
+            move || {
+                let mut printer = serde_json::Map::new();
+                if let Some(machine) = &machine {
+                    let info = machine.lock().unwrap().info();
+                    let axis_limits = |limit: fn(&RailInfo) -> f64| {
+                        let limits = info.rails.iter().map(|rail| (rail.axis, limit(rail)));
+                        serde_json::Map::from_iter(limits.map(|(axis, v)| (axis.into(), v.into())))
+                    };
+                    let [x, y, z, e] = info.position;
+                    printer.insert(
+                        "toolhead".into(),
+                        serde_json::json!({
+                            "position": { "x": x, "y": y, "z": z, "e": e },
+                            "axis_minimum": axis_limits(|rail| rail.position_min),
+                            "axis_maximum": axis_limits(|rail| rail.position_max),
+                            "max_velocity": info.max_velocity,
+                            "max_accel": info.max_accel,
+                            "square_corner_velocity": info.square_corner_velocity,
+                        }),
+                    );
+                }
+                let paused = pause.status().paused;
+                printer.insert(
+                    "pause_resume".into(),
+                    serde_json::json!({ "is_paused": paused }),
+                );
+                let stats = print_stats.lock().unwrap().stats();
+                if let Ok(stats) = serde_json::to_value(stats) {
+                    printer.insert("print_stats".into(), stats);
+                }
+                printer
+            }
+        });
 
         Ok(Self {
             config: Arc::new(config),
-            jobs: Arc::new(RwLock::new(jobs)),
+            jobs,
+            machine,
+            pause,
+            bed_mesh,
+            input_shaper,
+            console,
+            crash,
+            print_stats,
+            job_queue,
+            queue_changed: Arc::new(Condvar::new()),
+            compile_cache: Arc::new(compile_cache),
+        })
+    }
+
+    /// Print queued jobs on a background thread for as long as the server
+    /// runs
+    pub fn spawn_job_runner(&self) -> Result<JoinHandle<()>> {
+        let state = self.clone();
+        thread::Builder::new()
+            .name("scherzo-jobs".to_string())
+            .spawn(move || {
+                loop {
+                    state.run_queued_jobs();
+                    state.wait_for_queue();
+                }
+            })
+            .context("failed to start the job runner")
+    }
+
+    /// Print the queued jobs one after another until the queue is empty or
+    /// held
+    pub fn run_queued_jobs(&self) {
+        while let Some((metadata, span)) = self.start_next_job() {
+            let completed = span.in_scope(|| {
+                self.run_job(&metadata.id).unwrap_or_else(|e| {
+                    tracing::error!("Job {} failed: {e:#}", metadata.name);
+                    false
+                })
+            });
+            self.finish_job(&metadata.id, completed);
+        }
+    }
+
+    /// Run the statements of the running job `id` through the console,
+    /// returning false if it was cancelled or deleted before the end. The
+    /// statements are recovered from its component, so any job the compiler
+    /// produced runs. Checkpoints are saved as it goes.
+    fn run_job(&self, id: &Uuid) -> Result<bool> {
+        let component = fs::read(self.jobs.job_path(id)).context("failed to read job file")?;
+        let program = scherzo_compile::decompile::decompile(&component)?;
+        // Source maps are lost on a restart, when the recovered program is
+        // mapped instead, numbering its own lines
+        let source_map = match self.jobs.source_map(id) {
+            Some(source_map) => source_map,
+            None => Arc::new(SourceMap::build(&scherzo_gcode::parse(&program)?)),
+        };
+        let mut checkpoints = CheckpointWriter::new(
+            self.jobs.checkpoint_path(id),
+            self.config.jobs.checkpoint_interval,
+        );
+        for (index, statement) in program.lines().enumerate() {
+            if !self.wait_while_paused(id) {
+                return Ok(false);
+            }
+            let output = self
+                .console
+                .run_script(statement)
+                .with_context(|| format!("statement {}: {statement}", index + 1))?;
+            for line in output {
+                tracing::info!("{line}");
+            }
+            self.print_stats
+                .lock()
+                .unwrap()
+                .statements_submitted(index + 1);
+            let saved = checkpoints.save_if_due(|| {
+                Checkpoint::capture(index + 1, &source_map, self.toolhead_position())
+            });
+            if let Err(e) = saved {
+                tracing::warn!("Failed to save checkpoint: {e:#}");
+            }
+        }
+        self.console.wait_moves()?;
+        Ok(true)
+    }
+
+    /// Where the moves queued so far leave the toolhead
+    fn toolhead_position(&self) -> [f64; 4] {
+        self.machine.as_ref().map_or([0.0; 4], |machine| {
+            machine.lock().unwrap().commanded_position()
         })
     }
+
+    /// Block while job `id` is paused, returning whether it is running
+    fn wait_while_paused(&self, id: &Uuid) -> bool {
+        loop {
+            match self.jobs.get_job(id).map(|metadata| metadata.status) {
+                Some(JobStatus::Running) => return true,
+                Some(JobStatus::Paused) => {
+                    let job_queue = self.job_queue.lock().unwrap();
+                    let _ = self
+                        .queue_changed
+                        .wait_timeout(job_queue, RUNNER_POLL_INTERVAL);
+                }
+                _ => return false,
+            }
+        }
+    }
+
+    /// Block until the queue may have a job to start
+    fn wait_for_queue(&self) {
+        let job_queue = self.job_queue.lock().unwrap();
+        if !job_queue.has_next() {
+            let _ = self
+                .queue_changed
+                .wait_timeout(job_queue, RUNNER_POLL_INTERVAL);
+        }
+    }
+
+    /// Tell the job runner a job may start or continue
+    fn wake_job_runner(&self) {
+        self.queue_changed.notify_all();
+    }
+
+    /// Mark the next queued job as running and start tracking it, unless a
+    /// job is running or the queue is held. `G10`/`G11` retract as the job
+    /// asks, the machine skips the objects it excludes, and its motion is
+    /// traced under the returned span.
+    fn start_next_job(&self) -> Option<(JobMetadata, Span)> {
+        let id = self.job_queue.lock().unwrap().next()?;
+        let source_map = self.jobs.source_map(&id);
+        let metadata = self
+            .jobs
+            .update_job(&id, |metadata| {
+                metadata.status = JobStatus::Running;
+                self.print_stats.lock().unwrap().start(id, source_map);
+                // Under the job's lock, so exclusions made as it starts apply
+                if let Some(machine) = &self.machine {
+                    let mut exclude_object = ExcludeObject::new();
+                    for name in &metadata.excluded_objects {
+                        exclude_object.exclude(name);
+                    }
+                    machine.lock().unwrap().exclude_object = exclude_object;
+                }
+                Ok(())
+            })
+            .ok()?;
+        self.console.overrides().set(metadata.overrides.clone());
+        self.console
+            .set_firmware_retraction(metadata.firmware_retraction);
+        let span = tracing::info_span!(parent: &self.jobs.span(&id), "print");
+        span.in_scope(|| tracing::info!("Starting job {}", metadata.name));
+        Some((metadata, span))
+    }
+
+    /// Record that the running job `id` completed or failed, then run the
+    /// between-jobs script if it completed. Blocks until the script's moves
+    /// are queued. A job cancelled while it ran stays cancelled. Its
+    /// exclusions no longer apply to the script.
+    fn finish_job(&self, id: &Uuid, completed: bool) {
+        let span = tracing::info_span!(parent: &self.jobs.span(id), "between_jobs");
+        let (status, state) = if completed {
+            (JobStatus::Completed, PrintState::Complete)
+        } else {
+            (JobStatus::Failed, PrintState::Error)
+        };
+        // The job may have been deleted while it ran
+        let _ = self.jobs.update_job(id, |metadata| {
+            if metadata.status.is_finished() {
+                return Ok(());
+            }
+            metadata.status = status;
+            let mut print_stats = self.print_stats.lock().unwrap();
+            if print_stats.is_active(id) {
+                metadata.print_stats = Some(print_stats.finish(state));
+            }
+            Ok(())
+        });
+
+        self.console.overrides().clear();
+        self.console.set_firmware_retraction(true);
+        if let Some(machine) = &self.machine {
+            machine.lock().unwrap().exclude_object = ExcludeObject::new();
+        }
+
+        let script = self.job_queue.lock().unwrap().finish(id, completed);
+        if let Some(script) = script
+            && let Err(e) = span.in_scope(|| self.console.run_script(&script))
+        {
+            tracing::error!("Between-jobs script failed; holding the queue: {e:#}");
+            let error = format!("{e:#}");
+            self.job_queue.lock().unwrap().script_failed(id, error);
+        }
+    }
 }
 
 impl JobStore {
-    fn add_job(&mut self, id: Uuid, metadata: JobMetadata) {
-        self.jobs.insert(id, metadata);
+    /// Load the jobs saved in `storage_dir`. Jobs that were running or paused
+    /// were interrupted, so they are marked failed.
+    fn load(storage_dir: PathBuf) -> Result<Self> {
+        let store = Self {
+            index: Arc::default(),
+            storage_dir: Arc::new(storage_dir),
+            disk: Arc::default(),
+        };
+        let entries =
+            fs::read_dir(&*store.storage_dir).context("failed to read jobs storage directory")?;
+        let _disk = store.disk.lock().unwrap();
+        let mut index = store.index.write().unwrap();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            // Checkpoints are `<id>.checkpoint.json`, so their stems are not IDs
+            let Some(id) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| Uuid::parse_str(stem).ok())
+            else {
+                continue;
+            };
+            if !store.job_path(&id).exists() {
+                continue;
+            }
+            let loaded = fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|json| Ok(serde_json::from_slice::<JobMetadata>(&json)?));
+            let mut metadata = match loaded {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    tracing::warn!("Skipping job metadata {}: {e:#}", path.display());
+                    continue;
+                }
+            };
+            if matches!(metadata.status, JobStatus::Running | JobStatus::Paused) {
+                tracing::warn!("Job {} was interrupted", metadata.name);
+                metadata.status = JobStatus::Failed;
+                store.write_metadata(&metadata);
+            }
+            index.revisions.insert(id, 0);
+            index.jobs.insert(id, metadata);
+        }
+        drop((index, _disk));
+        Ok(store)
+    }
+
+    /// Store a new job's component and metadata, then add it
+    fn create_job(
+        &self,
+        component: &[u8],
+        original_format: &str,
+        objects: Vec<JobObject>,
+        source_map: Option<Arc<SourceMap>>,
+        build: Option<JobBuild>,
+    ) -> Result<JobMetadata> {
+        let id = Uuid::new_v4();
+        let metadata = JobMetadata {
+            id,
+            name: format!("job-{}", id),
+            original_filename: None,
+            size_bytes: component.len() as u64,
+            created_at: chrono::Utc::now().to_rfc3339(),
+            status: JobStatus::Uploaded,
+            original_format: Some(original_format.to_string()),
+            firmware_retraction: true,
+            objects,
+            excluded_objects: Vec::new(),
+            print_stats: None,
+            resumed_from: None,
+            template_variables: Vec::new(),
+            template_values: serde_json::Map::new(),
+            overrides: JobOverrides::default(),
+            diagnostics: Vec::new(),
+            assets: Vec::new(),
+            build,
+        };
+        {
+            // Nothing else knows the job yet, so only the disk is locked
+            let _disk = self.disk.lock().unwrap();
+            fs::write(self.job_path(&id), component).context("failed to write job file")?;
+            self.write_metadata(&metadata);
+        }
+
+        let mut index = self.index.write().unwrap();
+        index.revisions.insert(id, 0);
+        index.jobs.insert(id, metadata.clone());
+        if let Some(source_map) = source_map {
+            index.source_maps.insert(id, source_map);
+        }
+        Ok(metadata)
     }
 
     fn get_job(&self, id: &Uuid) -> Option<JobMetadata> {
-        self.jobs.get(id).cloned()
+        self.index.read().unwrap().jobs.get(id).cloned()
+    }
+
+    fn list_jobs(&self) -> Vec<JobMetadata> {
+        let mut jobs: Vec<_> = self.index.read().unwrap().jobs.values().cloned().collect();
+        jobs.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+        jobs
+    }
+
+    /// Whether any job is running
+    fn is_running(&self) -> bool {
+        let index = self.index.read().unwrap();
+        index
+            .jobs
+            .values()
+            .any(|job| job.status == JobStatus::Running)
+    }
+
+    /// Change job `id`'s metadata with `change`, then save it. If `change`
+    /// fails the job is left as it was. Nothing else can see or change the
+    /// job while `change` runs, so state kept alongside it, like the queue,
+    /// can be updated there.
+    fn update_job(
+        &self,
+        id: &Uuid,
+        change: impl FnOnce(&mut JobMetadata) -> Result<(), AppError>,
+    ) -> Result<JobMetadata, AppError> {
+        let (metadata, revision) = {
+            let mut index = self.index.write().unwrap();
+            let mut metadata = index.jobs.get(id).cloned().ok_or(AppError::NotFound)?;
+            change(&mut metadata)?;
+            // Closing the span ends the job's trace
+            if metadata.status.is_finished() {
+                index.spans.remove(id);
+            }
+            index.jobs.insert(*id, metadata.clone());
+            let revision = index.revisions.entry(*id).or_default();
+            *revision += 1;
+            (metadata, *revision)
+        };
+        self.save_metadata(&metadata, revision);
+        Ok(metadata)
+    }
+
+    /// Remove job `id` and delete its files. `forget` runs as the job is
+    /// removed, so nothing can be done to it in between.
+    fn remove_job(
+        &self,
+        id: &Uuid,
+        forget: impl FnOnce(&JobMetadata),
+    ) -> Result<Option<JobMetadata>> {
+        let metadata = {
+            let mut index = self.index.write().unwrap();
+            index.source_maps.remove(id);
+            index.spans.remove(id);
+            index.revisions.remove(id);
+            let Some(metadata) = index.jobs.remove(id) else {
+                return Ok(None);
+            };
+            forget(&metadata);
+            metadata
+        };
+
+        let _disk = self.disk.lock().unwrap();
+        for path in [
+            self.metadata_path(id),
+            self.checkpoint_path(id),
+            self.template_path(id),
+        ] {
+            if let Err(e) = fs::remove_file(&path)
+                && e.kind() != std::io::ErrorKind::NotFound
+            {
+                tracing::warn!("Failed to delete {}: {e}", path.display());
+            }
+        }
+        let assets_dir = self.assets_dir(id);
+        if let Err(e) = fs::remove_dir_all(&assets_dir)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            tracing::warn!("Failed to delete {}: {e}", assets_dir.display());
+        }
+        let job_path = self.job_path(id);
+        if job_path.exists() {
+            fs::remove_file(&job_path).context("failed to delete job file")?;
+        }
+        Ok(Some(metadata))
+    }
+
+    /// Keep the files uploaded with job `id` in its archive
+    fn write_assets(&self, id: &Uuid, assets: &[Asset]) -> Result<Vec<JobAsset>> {
+        let _disk = self.disk.lock().unwrap();
+        let dir = self.assets_dir(id);
+        assets
+            .iter()
+            .map(|asset| {
+                let path = dir.join(&asset.path);
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(&path, &asset.data)
+                    .with_context(|| format!("failed to write job asset {}", asset.path))?;
+                Ok(JobAsset {
+                    path: asset.path.clone(),
+                    size_bytes: asset.data.len() as u64,
+                })
+            })
+            .collect()
+    }
+
+    /// Keep the G-code template job `id` was compiled from
+    fn write_template(&self, id: &Uuid, source: &str) -> Result<()> {
+        let _disk = self.disk.lock().unwrap();
+        fs::write(self.template_path(id), source).context("failed to write job template")
+    }
+
+    /// Replace the component of job `id`, e.g. with its template resolved
+    /// for new values
+    fn replace_component(
+        &self,
+        id: &Uuid,
+        component: &[u8],
+        source_map: Arc<SourceMap>,
+    ) -> Result<()> {
+        {
+            let _disk = self.disk.lock().unwrap();
+            let path = self.job_path(id);
+            let tmp = path.with_extension("wasm.tmp");
+            fs::write(&tmp, component)
+                .and_then(|()| fs::rename(&tmp, &path))
+                .context("failed to write job file")?;
+        }
+        self.index
+            .write()
+            .unwrap()
+            .source_maps
+            .insert(*id, source_map);
+        Ok(())
+    }
+
+    /// Source map for tracking the progress of a G-code job
+    fn source_map(&self, id: &Uuid) -> Option<Arc<SourceMap>> {
+        self.index.read().unwrap().source_maps.get(id).cloned()
     }
 
-    fn remove_job(&mut self, id: &Uuid) -> Option<JobMetadata> {
-        self.jobs.remove(id)
+    /// Root span the job's phases are traced under, until it finishes
+    fn span(&self, id: &Uuid) -> Span {
+        self.index
+            .write()
+            .unwrap()
+            .spans
+            .entry(*id)
+            .or_insert_with(|| {
+                let span = telemetry::job_span();
+                span.record("job_id", field::display(id));
+                span
+            })
+            .clone()
     }
 
-    fn update_job(&mut self, id: &Uuid, metadata: JobMetadata) {
-        self.jobs.insert(*id, metadata);
+    /// Trace job `id` under `span`, e.g. one that covered its upload
+    fn set_span(&self, id: &Uuid, span: Span) {
+        self.index.write().unwrap().spans.insert(*id, span);
+    }
+
+    /// Save a job's metadata as of `revision`, unless a newer change or the
+    /// job's removal got there first
+    fn save_metadata(&self, metadata: &JobMetadata, revision: u64) {
+        let _disk = self.disk.lock().unwrap();
+        let index = self.index.read().unwrap();
+        if index.revisions.get(&metadata.id) != Some(&revision) {
+            return;
+        }
+        drop(index);
+        self.write_metadata(metadata);
+    }
+
+    /// Replace a job's metadata file, keeping the in-memory copy if that
+    /// fails. The disk must be locked.
+    fn write_metadata(&self, metadata: &JobMetadata) {
+        let path = self.metadata_path(&metadata.id);
+        let tmp = path.with_extension("json.tmp");
+        let saved = serde_json::to_vec_pretty(metadata)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| Ok(fs::write(&tmp, json)?))
+            .and_then(|()| Ok(fs::rename(&tmp, &path)?));
+        if let Err(e) = saved {
+            tracing::warn!("Failed to save {}: {e:#}", path.display());
+        }
     }
 
     fn job_path(&self, id: &Uuid) -> PathBuf {
         self.storage_dir.join(format!("{}.wasm", id))
     }
+
+    fn metadata_path(&self, id: &Uuid) -> PathBuf {
+        self.storage_dir.join(format!("{}.json", id))
+    }
+
+    /// Where a running job's power loss recovery checkpoints are saved
+    fn checkpoint_path(&self, id: &Uuid) -> PathBuf {
+        self.storage_dir.join(format!("{}.checkpoint.json", id))
+    }
+
+    /// Where the G-code template of a parameterized job is kept
+    fn template_path(&self, id: &Uuid) -> PathBuf {
+        self.storage_dir.join(format!("{}.template.gcode", id))
+    }
+
+    /// Where the assets uploaded with a job are kept
+    fn assets_dir(&self, id: &Uuid) -> PathBuf {
+        self.storage_dir.join(format!("{}.assets", id))
+    }
 }
 
 /// Create the main application router
 pub fn create_router(state: AppState) -> Router {
     Router::new()
         .route("/health", get(health_check))
+        .route("/printer", get(get_printer))
+        .route("/metrics", get(get_metrics))
+        .route("/printer/pause", get(get_pause_status))
+        .route("/printer/print_stats", get(get_print_stats))
+        .route("/printer/bed_mesh", get(get_bed_mesh))
+        .route("/printer/bed_mesh", delete(clear_bed_mesh))
+        .route("/printer/bed_mesh/calibrate", post(calibrate_bed_mesh))
+        .route(
+            "/printer/bed_mesh/calibrate",
+            delete(cancel_bed_mesh_calibration),
+        )
+        .route(
+            "/printer/bed_mesh/calibrate/height",
+            post(report_probe_height),
+        )
+        .route("/printer/input_shaper", get(get_input_shaper))
+        .route(
+            "/printer/input_shaper/calibrate",
+            post(calibrate_input_shaper),
+        )
+        .route(
+            "/printer/accelerometers/{name}/samples",
+            post(report_accelerometer_samples),
+        )
+        .route(
+            "/printer/filament_sensors/{name}",
+            post(report_filament_sensor),
+        )
+        .route("/printer/gcode/script", post(run_gcode_script))
+        .route("/printer/gcode/macros", get(list_macros))
+        .route("/debug/crash_report", get(get_crash_report))
+        .route("/debug/compile_cache", get(get_compile_cache_stats))
+        .route("/queue", get(get_queue))
+        .route("/queue", put(reorder_queue))
+        .route("/queue/confirm", post(confirm_queue))
+        .route("/jobs", get(list_jobs))
         .route("/jobs", post(upload_job))
+        .route("/jobs/outdated", get(list_outdated_jobs))
         .route("/jobs/{id}", get(get_job))
         .route("/jobs/{id}", delete(delete_job))
         .route("/jobs/{id}/rename", put(rename_job))
+        .route(
+            "/jobs/{id}/firmware_retraction",
+            put(set_firmware_retraction),
+        )
+        .route("/jobs/{id}/exclude_object", post(exclude_object))
+        .route("/jobs/{id}/overrides", post(set_job_overrides))
         .route("/jobs/{id}/estimate", get(estimate_job))
         .route("/jobs/{id}/preview", get(preview_job))
+        .route("/jobs/{id}/layers", get(list_job_layers))
+        .route("/jobs/{id}/layers/{file}", get(get_job_layer))
+        .route("/jobs/{id}/toolpath.glb", get(get_job_toolpath_glb))
+        .route("/jobs/{id}/assets/{*path}", get(get_job_asset))
+        .route("/jobs/{id}/dry_run", post(dry_run_job))
+        .route("/jobs/{a}/diff/{b}", get(diff_jobs))
         .route("/jobs/{id}/enqueue", post(enqueue_job))
+        .route("/jobs/{id}/pause", post(pause_job))
+        .route("/jobs/{id}/resume", post(resume_job))
+        .route("/jobs/{id}/cancel", post(cancel_job))
+        .route(
+            "/jobs/{id}/resume_from_checkpoint",
+            post(resume_from_checkpoint),
+        )
         .layer(middleware::from_fn_with_state(
             state.clone(),
             auth_middleware,
@@ -149,12 +1174,12 @@ async fn health_check() -> impl IntoResponse {
     (StatusCode::OK, "OK")
 }
 
-/// Basic auth middleware
+/// Basic auth and API key middleware
 async fn auth_middleware(
     State(state): State<AppState>,
     request: Request<Body>,
     next: Next,
-) -> Result<Response, StatusCode> {
+) -> Result<Response, AppError> {
     // Skip auth for health check
     if request.uri().path() == "/health" {
         return Ok(next.run(request).await);
@@ -171,18 +1196,198 @@ async fn auth_middleware(
         .get("Authorization")
         .and_then(|v| v.to_str().ok());
 
-    if let Some(auth) = auth_header
-        && let Some(credentials) = auth.strip_prefix("Basic ")
+    if is_authorized(auth_config, auth_header) {
+        return Ok(next.run(request).await);
+    }
+
+    Err(AppError::Unauthorized)
+}
+
+/// Check an `Authorization` header against basic auth credentials or API keys
+fn is_authorized(auth_config: &AuthConfig, auth_header: Option<&str>) -> bool {
+    let Some(auth) = auth_header else {
+        return false;
+    };
+
+    if let Some(key) = auth.strip_prefix("Bearer ") {
+        return auth_config.api_keys.iter().any(|k| k == key);
+    }
+
+    if let Some(credentials) = auth.strip_prefix("Basic ")
         && let Ok(decoded) = decode_base64(credentials)
         && let Ok(creds_str) = String::from_utf8(decoded)
         && let Some((username, password)) = creds_str.split_once(':')
-        && username == auth_config.username
-        && verify_password(password, &auth_config.password_hash)
     {
-        return Ok(next.run(request).await);
+        return username == auth_config.username
+            && verify_password(password, &auth_config.password_hash);
+    }
+
+    false
+}
+
+/// Describe the configured motion system
+async fn get_printer(State(state): State<AppState>) -> Result<impl IntoResponse, AppError> {
+    let machine = state.machine.as_ref().ok_or(AppError::NoPrinter)?;
+    let info = machine.lock().unwrap().info();
+    Ok(axum::Json(info))
+}
+
+/// Step pipeline metrics for Prometheus; empty without a printer
+async fn get_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let body = state
+        .machine
+        .as_ref()
+        .map(|machine| metrics::render(&machine.lock().unwrap().motion_info()))
+        .unwrap_or_default();
+    (
+        [(axum::http::header::CONTENT_TYPE, metrics::CONTENT_TYPE)],
+        body,
+    )
+}
+
+/// Whether the print is paused, and the filament sensor readings
+async fn get_pause_status(State(state): State<AppState>) -> impl IntoResponse {
+    axum::Json(state.pause.status())
+}
+
+/// Statistics for the current or last print
+async fn get_print_stats(State(state): State<AppState>) -> impl IntoResponse {
+    axum::Json(state.print_stats.lock().unwrap().stats())
+}
+
+/// Active bed mesh and calibration progress
+async fn get_bed_mesh(State(state): State<AppState>) -> impl IntoResponse {
+    axum::Json(state.bed_mesh.status())
+}
+
+/// Stop following the bed mesh until the next calibration or restart
+async fn clear_bed_mesh(State(state): State<AppState>) -> impl IntoResponse {
+    state.bed_mesh.clear();
+    axum::Json(state.bed_mesh.status())
+}
+
+/// Start bed mesh calibration, moving to the first point to probe
+async fn calibrate_bed_mesh(State(state): State<AppState>) -> Result<impl IntoResponse, AppError> {
+    if state.jobs.is_running() {
+        return Err(AppError::JobRunning(
+            "cannot calibrate the bed mesh while a job is running".to_string(),
+        ));
+    }
+    let status = state
+        .bed_mesh
+        .start_calibration()
+        .map_err(|e| AppError::Conflict(format!("{e:#}")))?;
+    Ok(axum::Json(status))
+}
+
+/// Record the bed height at the current calibration point, finishing the
+/// calibration after the last one
+async fn report_probe_height(
+    State(state): State<AppState>,
+    axum::Json(request): axum::Json<ProbeHeightRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let status = state
+        .bed_mesh
+        .report_height(request.z)
+        .map_err(|e| AppError::Conflict(format!("{e:#}")))?;
+    Ok(axum::Json(status))
+}
+
+/// Cancel bed mesh calibration, restoring the mesh active before
+async fn cancel_bed_mesh_calibration(
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    if !state.bed_mesh.cancel_calibration() {
+        return Err(AppError::Conflict(
+            "bed mesh calibration is not running".to_string(),
+        ));
+    }
+    Ok(axum::Json(state.bed_mesh.status()))
+}
+
+/// Input shaper settings and the progress of the last calibration
+async fn get_input_shaper(State(state): State<AppState>) -> impl IntoResponse {
+    axum::Json(state.input_shaper.status())
+}
+
+/// Start calibrating the input shaper of an axis in the background
+async fn calibrate_input_shaper(
+    State(state): State<AppState>,
+    axum::Json(request): axum::Json<InputShaperCalibrateRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    if state.jobs.is_running() {
+        return Err(AppError::JobRunning(
+            "cannot calibrate the input shaper while a job is running".to_string(),
+        ));
+    }
+    let calibration = state
+        .input_shaper
+        .start_calibration(request.axis)
+        .map_err(|e| AppError::Conflict(format!("{e:#}")))?;
+    Ok((StatusCode::ACCEPTED, axum::Json(calibration)))
+}
+
+/// Record accelerometer samples for the running resonance test
+async fn report_accelerometer_samples(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    axum::Json(request): axum::Json<AccelerometerSamplesRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let recorded = state
+        .input_shaper
+        .report_samples(&name, &request.samples)
+        .map_err(|e| AppError::BadRequest(format!("{e:#}")))?;
+    Ok(axum::Json(AccelerometerSamplesResponse { recorded }))
+}
+
+/// Run G-code through the console, returning once its moves are queued
+async fn run_gcode_script(
+    State(state): State<AppState>,
+    axum::Json(request): axum::Json<GCodeScriptRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let console = state.console.clone();
+    let output = tokio::task::spawn_blocking(move || console.run_script(&request.script))
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?
+        .map_err(command_error)?;
+    Ok(axum::Json(GCodeScriptResponse { output }))
+}
+
+/// Blame a failed command on the request unless the motion system failed
+fn command_error(err: anyhow::Error) -> AppError {
+    let motion = err.chain().find_map(|e| e.downcast_ref::<MotionError>());
+    match motion {
+        Some(motion) if !motion.is_invalid_request() => AppError::Internal(format!("{err:#}")),
+        _ => AppError::BadRequest(format!("{err:#}")),
+    }
+}
+
+/// Macros the console can run
+async fn list_macros(State(state): State<AppState>) -> impl IntoResponse {
+    axum::Json(state.console.macros())
+}
+
+/// Record a filament sensor reading, pausing the running job on runout
+async fn report_filament_sensor(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    axum::Json(request): axum::Json<FilamentSensorRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    if !state.config.filament_sensor.contains_key(&name) {
+        return Err(AppError::BadRequest(format!(
+            "no [filament_sensor.{name}] is configured"
+        )));
     }
+    state
+        .pause
+        .report_sensor(&name, request.filament_present)
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    Ok(axum::Json(state.pause.status()))
+}
 
-    Err(StatusCode::UNAUTHORIZED)
+/// List all jobs, oldest first
+async fn list_jobs(State(state): State<AppState>) -> impl IntoResponse {
+    axum::Json(state.jobs.list_jobs())
 }
 
 /// Upload a new job
@@ -203,76 +1408,287 @@ async fn upload_job(
         .unwrap_or("application/wasm");
 
     // Convert to WebAssembly component based on content type
-    let (wasm_bytes, original_format) = if content_type.contains("gcode")
+    let job_span = telemetry::job_span();
+    let compile_span = tracing::info_span!(parent: &job_span, "compile").entered();
+    let mut template = None;
+    let mut diagnostics = Vec::new();
+    let mut original_filename = None;
+    let mut assets = Vec::new();
+    let mut build = None;
+    let gcode_source = if let Some(format) = ArchiveFormat::from_content_type(content_type) {
+        // A slicer's bundle: compile its G-code and keep the rest
+        let bundle = Bundle::extract(format, &body, state.config.jobs.max_size_bytes)
+            .map_err(|e| AppError::BadRequest(format!("Invalid job archive: {e:#}")))?;
+        original_filename = bundle.gcode_path.rsplit('/').next().map(str::to_string);
+        assets = bundle.assets;
+        Some((bundle.gcode, format.name()))
+    } else if content_type.contains("gcode")
         || content_type.contains("text/plain")
         || content_type.contains("text/x-gcode")
     {
-        // It's G-code, compile it
-        tracing::info!("Compiling G-code to WebAssembly component");
         let gcode_source =
             String::from_utf8(body.to_vec()).map_err(|_| AppError::InvalidGCode {
                 message: "G-code file must be valid UTF-8".to_string(),
+                location: None,
             })?;
-
-        let compilation =
-            scherzo_compile::compile_gcode(&gcode_source).map_err(|e| AppError::InvalidGCode {
-                message: format!("Failed to compile G-code: {}", e),
-            })?;
-
-        (compilation.component, "gcode")
+        Some((gcode_source, "gcode"))
     } else {
-        // Assume it's already a WebAssembly component
-        (body.to_vec(), "wasm")
+        None
     };
+    let (wasm_bytes, original_format, objects, source_map) =
+        if let Some((gcode_source, format)) = gcode_source {
+            // It's G-code, compile it
+            tracing::info!("Compiling G-code to WebAssembly component");
 
-    // Validate it's a valid WebAssembly component
-    // TODO: Validate that all of the requested interfaces are present
-    validate_wasm_component(&wasm_bytes)?;
-
-    // Generate job ID
-    let job_id = Uuid::new_v4();
-
-    // Store the job file
-    let mut jobs = state.jobs.write().unwrap();
-    let job_path = jobs.job_path(&job_id);
+            // Templates compile with their defaults until enqueued with values
+            template = JobTemplate::parse(&gcode_source)
+                .map_err(|e| AppError::InvalidGCode {
+                    message: format!("{e:#}"),
+                    location: None,
+                })?
+                .map(|parsed| (gcode_source.clone(), parsed));
+            let gcode =
+                match &template {
+                    Some((_, parsed)) => parsed.resolve(&serde_json::Map::new()).map_err(|e| {
+                        AppError::InvalidGCode {
+                            message: format!("Failed to resolve job template: {e:#}"),
+                            location: None,
+                        }
+                    })?,
+                    None => gcode_source,
+                };
+            let compiled = compile_job_gcode(&state, &gcode)?;
+            diagnostics = compiled.diagnostics;
+            build = Some(compiled.build);
+            (
+                compiled.component,
+                format,
+                compiled.objects,
+                Some(Arc::new(compiled.source_map)),
+            )
+        } else {
+            // Assume it's already a WebAssembly component
+            if let Some(max) = state.config.jobs.max_component_size
+                && body.len() > max
+            {
+                return Err(AppError::CompiledTooLarge {
+                    message: format!(
+                        "Component takes {} bytes, over the limit of {max}",
+                        body.len()
+                    ),
+                    size: body.len(),
+                    max,
+                });
+            }
+            (body.to_vec(), "wasm", Vec::new(), None)
+        };
 
-    fs::write(&job_path, &wasm_bytes)
-        .context("failed to write job file")
-        .map_err(|e| AppError::Internal(e.to_string()))?;
+    // Validate it's a job component the host and plugins can run
+    validate_wasm_component(&wasm_bytes, state.console.commands())?;
+    compile_span.exit();
 
-    // Create metadata
-    let metadata = JobMetadata {
-        id: job_id,
-        name: format!("job-{}", job_id),
-        original_filename: None,
-        size_bytes: wasm_bytes.len() as u64,
-        created_at: chrono::Utc::now().to_rfc3339(),
-        status: JobStatus::Uploaded,
-        original_format: Some(original_format.to_string()),
-    };
-
-    jobs.add_job(job_id, metadata.clone());
+    // Store the job file and its metadata
+    let metadata = state
+        .jobs
+        .create_job(&wasm_bytes, original_format, objects, source_map, build)
+        .map_err(|e| AppError::Internal(format!("{e:#}")))?;
+    let job_id = metadata.id;
+    if !assets.is_empty() || original_filename.is_some() {
+        let assets = state
+            .jobs
+            .write_assets(&job_id, &assets)
+            .map_err(|e| AppError::Internal(format!("{e:#}")))?;
+        state.jobs.update_job(&job_id, |metadata| {
+            metadata.original_filename = original_filename;
+            metadata.assets = assets;
+            Ok(())
+        })?;
+    }
+    if let Some((source, parsed)) = template {
+        state
+            .jobs
+            .write_template(&job_id, &source)
+            .map_err(|e| AppError::Internal(format!("{e:#}")))?;
+        state.jobs.update_job(&job_id, |metadata| {
+            metadata.template_variables = parsed.variables().iter().map(Into::into).collect();
+            Ok(())
+        })?;
+    }
+    if !diagnostics.is_empty() {
+        state.jobs.update_job(&job_id, |metadata| {
+            metadata.diagnostics = diagnostics.clone();
+            Ok(())
+        })?;
+    }
+    job_span.record("job_id", field::display(job_id));
+    state.jobs.set_span(&job_id, job_span);
 
     let response = UploadResponse {
         job_id,
         url: format!("/jobs/{}", job_id),
-        compiled_from: if original_format == "gcode" {
-            Some("gcode".to_string())
-        } else {
+        compiled_from: if original_format == "wasm" {
             None
+        } else {
+            Some("gcode".to_string())
         },
+        diagnostics,
     };
 
     Ok((StatusCode::CREATED, axum::Json(response)))
 }
 
+/// Warnings about `gcode`, which compiled, for parameters outside the
+/// ranges plugins declare
+fn job_diagnostics(state: &AppState, gcode: &str) -> Vec<JobDiagnostic> {
+    let Ok(statements) = scherzo_gcode::parse(gcode) else {
+        return Vec::new();
+    };
+    let warnings = state.console.commands().range_warnings(&statements);
+    warnings
+        .into_iter()
+        .map(|(line, message)| {
+            tracing::warn!(line, "{message}");
+            JobDiagnostic {
+                code: JobDiagnostic::out_of_range(),
+                line,
+                message,
+            }
+        })
+        .collect()
+}
+
+/// A G-code job compiled to a component
+struct CompiledJob {
+    component: Vec<u8>,
+    objects: Vec<JobObject>,
+    source_map: SourceMap,
+    diagnostics: Vec<JobDiagnostic>,
+    build: JobBuild,
+}
+
+/// Options the server compiles G-code jobs with
+fn job_compile_options(state: &AppState) -> scherzo_compile::CompileOptions {
+    scherzo_compile::CompileOptions {
+        modal_motion: state.config.jobs.modal_motion,
+        max_component_size: state.config.jobs.max_component_size,
+        max_function_size: state.config.jobs.max_function_size,
+        ..Default::default()
+    }
+}
+
+/// Compile a G-code job, reusing the component of an identical earlier
+/// compile if the cache has it
+fn compile_job_gcode(state: &AppState, gcode: &str) -> Result<CompiledJob, AppError> {
+    let options = job_compile_options(state);
+    let commands = state.console.commands().commands();
+    let key = CacheKey::new(gcode, &options, &commands);
+    let (component, objects, source_map) = match state.compile_cache.get(&key) {
+        Some(component) => {
+            // Only the component is cached; the rest is cheap to rebuild
+            tracing::info!("Reusing cached component");
+            let statements = scherzo_gcode::parse(gcode).map_err(|e| AppError::InvalidGCode {
+                message: format!("Failed to compile G-code: {}", e),
+                location: Some(SourceLocation::of(&e)),
+            })?;
+            let objects = scherzo_compile::objects::collect_objects(&statements);
+            (component, objects, SourceMap::build(&statements))
+        }
+        None => {
+            let compilation =
+                scherzo_compile::compile_gcode_with(gcode, &options).map_err(|e| {
+                    match e.downcast_ref::<scherzo_compile::SizeLimitExceeded>() {
+                        Some(exceeded) => AppError::CompiledTooLarge {
+                            message: format!("Failed to compile G-code: {exceeded}"),
+                            size: exceeded.size,
+                            max: exceeded.max,
+                        },
+                        None => AppError::InvalidGCode {
+                            message: format!("Failed to compile G-code: {}", e),
+                            location: e
+                                .downcast_ref::<scherzo_gcode::ParseError>()
+                                .map(SourceLocation::of),
+                        },
+                    }
+                })?;
+            let sizes = compilation.sizes;
+            tracing::info!(
+                component = sizes.component,
+                code = sizes.code,
+                data = sizes.data,
+                types = sizes.types,
+                "Compiled job"
+            );
+            state.compile_cache.insert(&key, &compilation.component);
+            let metadata = compilation.metadata;
+            (compilation.component, metadata.objects, metadata.source_map)
+        }
+    };
+    Ok(CompiledJob {
+        component,
+        objects: objects.into_iter().map(Into::into).collect(),
+        source_map,
+        diagnostics: job_diagnostics(state, gcode),
+        build: JobBuild::new(gcode, &options, &commands),
+    })
+}
+
+/// Jobs compiled by a different compiler version, or with different options
+/// or plugin commands, than the server would compile them with now
+#[derive(Debug, Serialize)]
+struct OutdatedJobs {
+    /// Version of the server's compiler
+    compiler_version: &'static str,
+    jobs: Vec<OutdatedJob>,
+}
+
+/// A job that may compile differently now
+#[derive(Debug, Serialize)]
+struct OutdatedJob {
+    id: Uuid,
+    name: String,
+    /// Compiler version it was compiled with, if recorded
+    compiler_version: Option<String>,
+    /// Whether the compile options or commands have changed since
+    options_changed: bool,
+}
+
+/// G-code jobs to compile again for the current compiler and options.
+/// Uploaded components are left out, having no G-code to compile.
+async fn list_outdated_jobs(State(state): State<AppState>) -> impl IntoResponse {
+    let options_hash = compile_cache::options_hash(
+        &job_compile_options(&state),
+        &state.console.commands().commands(),
+    );
+    let jobs = state
+        .jobs
+        .list_jobs()
+        .into_iter()
+        .filter(|job| job.original_format.as_deref() != Some("wasm"))
+        .filter_map(|job| {
+            let build = job.build.as_ref();
+            let compiler_version = build.map(|build| build.compiler_version.clone());
+            let options_changed = build.is_none_or(|build| build.options_hash != options_hash);
+            (compiler_version.as_deref() != Some(scherzo_compile::VERSION) || options_changed)
+                .then_some(OutdatedJob {
+                    id: job.id,
+                    name: job.name,
+                    compiler_version,
+                    options_changed,
+                })
+        })
+        .collect();
+    axum::Json(OutdatedJobs {
+        compiler_version: scherzo_compile::VERSION,
+        jobs,
+    })
+}
+
 /// Get job metadata
 async fn get_job(
     State(state): State<AppState>,
     Path(id): Path<Uuid>,
 ) -> Result<impl IntoResponse, AppError> {
-    let jobs = state.jobs.read().unwrap();
-    let metadata = jobs.get_job(&id).ok_or(AppError::NotFound)?;
+    let metadata = state.jobs.get_job(&id).ok_or(AppError::NotFound)?;
     Ok(axum::Json(metadata))
 }
 
@@ -281,16 +1697,13 @@ async fn delete_job(
     State(state): State<AppState>,
     Path(id): Path<Uuid>,
 ) -> Result<impl IntoResponse, AppError> {
-    let mut jobs = state.jobs.write().unwrap();
-    let metadata = jobs.remove_job(&id).ok_or(AppError::NotFound)?;
-
-    // Delete the file
-    let job_path = jobs.job_path(&id);
-    if job_path.exists() {
-        fs::remove_file(&job_path)
-            .context("failed to delete job file")
-            .map_err(|e| AppError::Internal(e.to_string()))?;
-    }
+    let metadata = state
+        .jobs
+        .remove_job(&id, |_| {
+            state.job_queue.lock().unwrap().remove(&id);
+        })
+        .map_err(|e| AppError::Internal(e.to_string()))?
+        .ok_or(AppError::NotFound)?;
 
     Ok((StatusCode::OK, axum::Json(metadata)))
 }
@@ -301,11 +1714,113 @@ async fn rename_job(
     Path(id): Path<Uuid>,
     axum::Json(request): axum::Json<RenameRequest>,
 ) -> Result<impl IntoResponse, AppError> {
-    let mut jobs = state.jobs.write().unwrap();
-    let mut metadata = jobs.get_job(&id).ok_or(AppError::NotFound)?;
+    let metadata = state.jobs.update_job(&id, |metadata| {
+        metadata.name = request.name;
+        Ok(())
+    })?;
 
-    metadata.name = request.name;
-    jobs.update_job(&id, metadata.clone());
+    Ok(axum::Json(metadata))
+}
+
+/// Enable or disable firmware retraction for a job that has not started
+async fn set_firmware_retraction(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    axum::Json(request): axum::Json<FirmwareRetractionRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let metadata = state.jobs.update_job(&id, |metadata| {
+        if metadata.status == JobStatus::Running || metadata.status.is_finished() {
+            return Err(AppError::InvalidJobStatus {
+                message: format!(
+                    "job is {:?} and its retraction can no longer change",
+                    metadata.status
+                ),
+                status: metadata.status.clone(),
+            });
+        }
+        metadata.firmware_retraction = request.enabled;
+        Ok(())
+    })?;
+
+    Ok(axum::Json(metadata))
+}
+
+/// Exclude one of a job's objects, skipping its remaining moves if the job is
+/// already printing
+async fn exclude_object(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    axum::Json(request): axum::Json<ExcludeObjectRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let metadata = state.jobs.update_job(&id, |metadata| {
+        if metadata.status.is_finished() {
+            return Err(AppError::InvalidJobStatus {
+                message: format!(
+                    "job is {:?} and its objects can no longer be excluded",
+                    metadata.status
+                ),
+                status: metadata.status.clone(),
+            });
+        }
+
+        let name = request.name.trim().to_ascii_uppercase();
+        if !metadata.objects.iter().any(|object| object.name == name) {
+            return Err(AppError::UnknownObject {
+                name: request.name.clone(),
+            });
+        }
+        if metadata.excluded_objects.contains(&name) {
+            return Ok(());
+        }
+
+        if metadata.status == JobStatus::Running
+            && let Some(machine) = &state.machine
+        {
+            machine.lock().unwrap().exclude_object.exclude(&name);
+        }
+
+        metadata.excluded_objects.push(name);
+        Ok(())
+    })?;
+
+    Ok(axum::Json(metadata))
+}
+
+/// Change a job's speed and extrusion factors or heater temperature offsets.
+/// Those of the running job take effect with its next command.
+async fn set_job_overrides(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    axum::Json(request): axum::Json<JobOverridesRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let metadata = state.jobs.update_job(&id, |metadata| {
+        if metadata.status.is_finished() {
+            return Err(AppError::InvalidJobStatus {
+                message: format!(
+                    "job is {:?} and its overrides can no longer change",
+                    metadata.status
+                ),
+                status: metadata.status.clone(),
+            });
+        }
+
+        let mut overrides = metadata.overrides.clone();
+        request.apply(&mut overrides);
+        overrides
+            .validate()
+            .map_err(|e| AppError::BadRequest(format!("{e:#}")))?;
+
+        // Only the fields given replace what `M220`/`M221` set while printing
+        if state.print_stats.lock().unwrap().is_active(&id) {
+            state
+                .console
+                .overrides()
+                .update(|live| request.apply(live))
+                .map_err(|e| AppError::BadRequest(format!("{e:#}")))?;
+        }
+        metadata.overrides = overrides;
+        Ok(())
+    })?;
 
     Ok(axum::Json(metadata))
 }
@@ -315,8 +1830,7 @@ async fn estimate_job(
     State(state): State<AppState>,
     Path(id): Path<Uuid>,
 ) -> Result<impl IntoResponse, AppError> {
-    let jobs = state.jobs.read().unwrap();
-    let _metadata = jobs.get_job(&id).ok_or(AppError::NotFound)?;
+    let _metadata = state.jobs.get_job(&id).ok_or(AppError::NotFound)?;
 
     // TODO: Actually analyze the job and compute real estimates
     // For now, return a placeholder
@@ -335,8 +1849,7 @@ async fn preview_job(
     State(state): State<AppState>,
     Path(id): Path<Uuid>,
 ) -> Result<impl IntoResponse, AppError> {
-    let jobs = state.jobs.read().unwrap();
-    let _metadata = jobs.get_job(&id).ok_or(AppError::NotFound)?;
+    let _metadata = state.jobs.get_job(&id).ok_or(AppError::NotFound)?;
 
     // TODO: Actually analyze the job component and extract command info
     // For now, return placeholder data
@@ -348,25 +1861,573 @@ async fn preview_job(
     Ok(axum::Json(response))
 }
 
+/// Layers of a job's toolpath, in print order
+async fn list_job_layers(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    let toolpath = job_toolpath(&state.jobs, &id)?;
+    let layers: Vec<_> = toolpath
+        .layers
+        .iter()
+        .enumerate()
+        .map(|(index, layer)| LayerSummary {
+            number: index + 1,
+            z: layer.z,
+            segments: layer.segments.len(),
+        })
+        .collect();
+    Ok(axum::Json(layers))
+}
+
+/// A layer of a job's toolpath, drawn as `{n}.svg` or `{n}.png`
+async fn get_job_layer(
+    State(state): State<AppState>,
+    Path((id, file)): Path<(Uuid, String)>,
+    Query(query): Query<LayerImageQuery>,
+) -> Result<Response, AppError> {
+    let (number, extension) = file
+        .rsplit_once('.')
+        .and_then(|(number, extension)| Some((number.parse::<usize>().ok()?, extension)))
+        .ok_or(AppError::NoLayer)?;
+    if !matches!(extension, "svg" | "png") {
+        return Err(AppError::BadRequest(format!(
+            "unsupported layer image format `{extension}`; use svg or png"
+        )));
+    }
+
+    let toolpath = job_toolpath(&state.jobs, &id)?;
+    let (content_type, body) = if extension == "svg" {
+        let svg = toolpath.layer_svg(number).ok_or(AppError::NoLayer)?;
+        ("image/svg+xml", svg.into_bytes())
+    } else {
+        let size = query.size.unwrap_or(LAYER_PNG_SIZE);
+        if !(1..=LAYER_PNG_MAX_SIZE).contains(&size) {
+            return Err(AppError::BadRequest(format!(
+                "size must be between 1 and {LAYER_PNG_MAX_SIZE} pixels"
+            )));
+        }
+        let png = toolpath.layer_png(number, size).ok_or(AppError::NoLayer)?;
+        ("image/png", png)
+    };
+    Ok(([(axum::http::header::CONTENT_TYPE, content_type)], body).into_response())
+}
+
+/// A file uploaded in a job's archive
+async fn get_job_asset(
+    State(state): State<AppState>,
+    Path((id, path)): Path<(Uuid, String)>,
+) -> Result<Response, AppError> {
+    let job = state.jobs.get_job(&id).ok_or(AppError::NotFound)?;
+    // Only serve paths the job was stored with, which never leave its assets
+    if !job.assets.iter().any(|asset| asset.path == path) {
+        return Err(AppError::AssetNotFound { path });
+    }
+    let body = fs::read(state.jobs.assets_dir(&id).join(&path))
+        .context("failed to read job asset")
+        .map_err(|e| AppError::Internal(format!("{e:#}")))?;
+    let extension = path
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase());
+    let content_type = match extension.as_deref() {
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("svg") => "image/svg+xml",
+        Some("json") => "application/json",
+        Some("ini" | "cfg" | "conf" | "txt") => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    };
+    Ok(([(axum::http::header::CONTENT_TYPE, content_type)], body).into_response())
+}
+
+/// A job's planned moves as binary glTF, for 3D viewers
+async fn get_job_toolpath_glb(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<Response, AppError> {
+    let statements = job_statements(&state.jobs, &id)?;
+    let glb = scherzo_compile::gltf::toolpath_glb(&statements, &planning_limits(&state));
+    Ok((
+        [(axum::http::header::CONTENT_TYPE, "model/gltf-binary")],
+        glb,
+    )
+        .into_response())
+}
+
+/// Plan a job's motion, with its overrides, and sample where the toolhead
+/// would be over time, without moving the printer
+async fn dry_run_job(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    request: Option<axum::Json<DryRunRequest>>,
+) -> Result<impl IntoResponse, AppError> {
+    let interval = request.map_or_else(default_dry_run_interval, |axum::Json(request)| {
+        request.interval
+    });
+    if !(interval.is_finite() && interval > 0.0) {
+        return Err(AppError::BadRequest(format!(
+            "interval must be positive, got {interval}"
+        )));
+    }
+    let metadata = state.jobs.get_job(&id).ok_or(AppError::NotFound)?;
+    let statements = job_statements(&state.jobs, &id)?;
+    let mut interp = MotionInterpreter::new();
+    metadata.overrides.apply(&mut interp);
+    let limits = planning_limits(&state);
+    let run = tokio::task::spawn_blocking(move || {
+        scherzo_compile::dry_run::dry_run(&statements, &limits, interp, interval)
+    })
+    .await
+    .map_err(|e| AppError::Internal(format!("dry run panicked: {e}")))?;
+
+    Ok(axum::Json(DryRunResponse {
+        duration: run.duration,
+        estimated_duration: format_duration(run.duration),
+        interval: run.interval,
+        samples: run
+            .samples
+            .into_iter()
+            .map(|sample| DryRunSample {
+                time: sample.time,
+                position: sample.position,
+                velocity: sample.velocity,
+            })
+            .collect(),
+    }))
+}
+
+/// Compare two jobs command by command, and by what they set heaters to,
+/// take, and print, e.g. to check what a re-slice changed
+async fn diff_jobs(
+    State(state): State<AppState>,
+    Path((a, b)): Path<(Uuid, Uuid)>,
+) -> Result<impl IntoResponse, AppError> {
+    let metadata_a = state.jobs.get_job(&a).ok_or(AppError::NotFound)?;
+    let metadata_b = state.jobs.get_job(&b).ok_or(AppError::NotFound)?;
+    let statements = (
+        job_statements(&state.jobs, &a)?,
+        job_statements(&state.jobs, &b)?,
+    );
+    let limits = planning_limits(&state);
+    let (diff, estimates) = tokio::task::spawn_blocking(move || {
+        let (a, b) = &statements;
+        let estimates = (
+            estimate_statements(a, &limits),
+            estimate_statements(b, &limits),
+        );
+        (diff_programs(a, b), estimates)
+    })
+    .await
+    .map_err(|e| AppError::Internal(format!("diff panicked: {e}")))?;
+
+    let objects = |metadata: &JobMetadata| -> BTreeSet<String> {
+        metadata.objects.iter().map(|o| o.name.clone()).collect()
+    };
+    let (objects_a, objects_b) = (objects(&metadata_a), objects(&metadata_b));
+    let (estimate_a, estimate_b) = estimates;
+    Ok(axum::Json(JobDiffResponse {
+        identical: diff.is_identical(),
+        changes: diff.changes.map(|changes| {
+            changes
+                .into_iter()
+                .map(|change| match change {
+                    CommandChange::Removed { line, command } => JobCommandChange {
+                        change: "removed",
+                        line,
+                        command,
+                    },
+                    CommandChange::Added { line, command } => JobCommandChange {
+                        change: "added",
+                        line,
+                        command,
+                    },
+                })
+                .collect()
+        }),
+        commands: Compared {
+            a: diff.commands[0],
+            b: diff.commands[1],
+        },
+        temperatures: diff
+            .temperatures
+            .into_iter()
+            .map(|change| JobTemperatureChange {
+                heater: change.heater,
+                a: change.a,
+                b: change.b,
+            })
+            .collect(),
+        size_bytes: Compared {
+            a: metadata_a.size_bytes,
+            b: metadata_b.size_bytes,
+        },
+        estimated_seconds: Compared {
+            a: estimate_a.total_seconds,
+            b: estimate_b.total_seconds,
+        },
+        filament_mm: Compared {
+            a: estimate_a.filament_mm,
+            b: estimate_b.filament_mm,
+        },
+        layers: Compared {
+            a: estimate_a.layers.len(),
+            b: estimate_b.layers.len(),
+        },
+        objects_added: objects_b.difference(&objects_a).cloned().collect(),
+        objects_removed: objects_a.difference(&objects_b).cloned().collect(),
+    }))
+}
+
+/// Limits to plan jobs with, the printer's so speeds match what it would
+/// print
+fn planning_limits(state: &AppState) -> MachineLimits {
+    match &state.machine {
+        Some(machine) => machine.lock().unwrap().limits,
+        None => MachineLimits::default(),
+    }
+}
+
+/// The extrusions of a job, recovered from its component
+fn job_toolpath(jobs: &JobStore, id: &Uuid) -> Result<Toolpath, AppError> {
+    Ok(Toolpath::build(&job_statements(jobs, id)?))
+}
+
+/// The statements of a job, recovered from its component
+fn job_statements(jobs: &JobStore, id: &Uuid) -> Result<Vec<scherzo_gcode::Statement>, AppError> {
+    jobs.get_job(id).ok_or(AppError::NotFound)?;
+    let component = fs::read(jobs.job_path(id))
+        .context("failed to read job file")
+        .map_err(|e| AppError::Internal(format!("{e:#}")))?;
+    scherzo_compile::decompile::decompile(&component)
+        .and_then(|source| Ok(scherzo_gcode::parse(&source)?))
+        .map_err(|e| AppError::Conflict(format!("job has no toolpath to draw: {e:#}")))
+}
+
+/// The latest crash report, or no content if none was written
+async fn get_crash_report(State(state): State<AppState>) -> Result<Response, AppError> {
+    let report = state
+        .crash
+        .latest()
+        .map_err(|e| AppError::Internal(format!("{e:#}")))?;
+    Ok(match report {
+        Some(report) => axum::Json(report).into_response(),
+        None => StatusCode::NO_CONTENT.into_response(),
+    })
+}
+
+/// Hits, misses and size of the compile cache
+async fn get_compile_cache_stats(State(state): State<AppState>) -> impl IntoResponse {
+    axum::Json(state.compile_cache.stats())
+}
+
+/// Queued jobs and whether the queue is held
+async fn get_queue(State(state): State<AppState>) -> impl IntoResponse {
+    axum::Json(state.job_queue.lock().unwrap().status())
+}
+
+/// Change the order queued jobs print in
+async fn reorder_queue(
+    State(state): State<AppState>,
+    axum::Json(request): axum::Json<QueueOrderRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut job_queue = state.job_queue.lock().unwrap();
+    job_queue
+        .reorder(&request.jobs)
+        .map_err(|e| AppError::BadRequest(format!("{e:#}")))?;
+    Ok(axum::Json(job_queue.status()))
+}
+
+/// Confirm the printer is ready for the next job, releasing a held queue
+async fn confirm_queue(State(state): State<AppState>) -> Result<impl IntoResponse, AppError> {
+    let mut job_queue = state.job_queue.lock().unwrap();
+    if !job_queue.confirm() {
+        return Err(AppError::QueueNotHeld);
+    }
+    state.wake_job_runner();
+    Ok(axum::Json(job_queue.status()))
+}
+
 /// Enqueue a job for execution
 async fn enqueue_job(
     State(state): State<AppState>,
     Path(id): Path<Uuid>,
+    request: Option<axum::Json<EnqueueRequest>>,
 ) -> Result<impl IntoResponse, AppError> {
-    let mut jobs = state.jobs.write().unwrap();
-    let mut metadata = jobs.get_job(&id).ok_or(AppError::NotFound)?;
+    let variables = request
+        .map(|axum::Json(request)| request.variables)
+        .unwrap_or_default();
+    // Without values, a template job keeps the component it was last
+    // resolved to
+    let resolved = if variables.is_empty() {
+        None
+    } else {
+        let compiled = resolve_job_template(&state, &id, &variables)?;
+        state
+            .jobs
+            .replace_component(&id, &compiled.component, Arc::new(compiled.source_map))
+            .map_err(|e| AppError::Internal(format!("{e:#}")))?;
+        let size_bytes = compiled.component.len() as u64;
+        Some((
+            size_bytes,
+            compiled.objects,
+            compiled.diagnostics,
+            compiled.build,
+        ))
+    };
 
-    // Update status to enqueued
-    metadata.status = JobStatus::Enqueued;
-    jobs.update_job(&id, metadata.clone());
+    let metadata = state.jobs.update_job(&id, |metadata| {
+        // Paused jobs go back in the queue when resumed
+        if !matches!(metadata.status, JobStatus::Uploaded | JobStatus::Enqueued) {
+            return Err(AppError::InvalidJobStatus {
+                message: format!("job is {:?} and cannot be enqueued", metadata.status),
+                status: metadata.status.clone(),
+            });
+        }
+        if let Some((size_bytes, objects, diagnostics, build)) = resolved {
+            metadata.size_bytes = size_bytes;
+            metadata.objects = objects;
+            metadata.diagnostics = diagnostics;
+            metadata.build = Some(build);
+            metadata.template_values = variables;
+        }
 
-    // TODO: Actually enqueue the job in a job queue
+        // Update status to enqueued
+        metadata.status = JobStatus::Enqueued;
+        state.job_queue.lock().unwrap().push(id);
+        Ok(())
+    })?;
+    state.wake_job_runner();
+    let _span = tracing::info_span!(parent: &state.jobs.span(&id), "queue").entered();
+    tracing::info!("Enqueued job {}", metadata.name);
 
     Ok(axum::Json(metadata))
 }
 
-/// Validate that the bytes represent a valid WebAssembly component
-fn validate_wasm_component(bytes: &[u8]) -> Result<(), AppError> {
+/// Compile the template of job `id` with `values` for its variables
+fn resolve_job_template(
+    state: &AppState,
+    id: &Uuid,
+    values: &serde_json::Map<String, serde_json::Value>,
+) -> Result<CompiledJob, AppError> {
+    let metadata = state.jobs.get_job(id).ok_or(AppError::NotFound)?;
+    if metadata.template_variables.is_empty() {
+        return Err(AppError::NotATemplate);
+    }
+    // A queued job may start any moment, so its component stays as it is
+    if metadata.status != JobStatus::Uploaded {
+        return Err(AppError::InvalidJobStatus {
+            message: format!(
+                "job is {:?} and its template cannot be resolved",
+                metadata.status
+            ),
+            status: metadata.status.clone(),
+        });
+    }
+    if let Some(name) = values.keys().find(|name| {
+        !metadata
+            .template_variables
+            .iter()
+            .any(|variable| &variable.name == *name)
+    }) {
+        return Err(AppError::UnknownTemplateVariable { name: name.clone() });
+    }
+
+    let source = fs::read_to_string(state.jobs.template_path(id))
+        .context("failed to read job template")
+        .map_err(|e| AppError::Internal(format!("{e:#}")))?;
+    let template = JobTemplate::parse(&source)
+        .ok()
+        .flatten()
+        .ok_or_else(|| AppError::Internal("stored job template is invalid".to_string()))?;
+    let gcode = template
+        .resolve(values)
+        .map_err(|e| AppError::BadRequest(format!("{e:#}")))?;
+    let compiled = compile_job_gcode(state, &gcode)?;
+    validate_wasm_component(&compiled.component, state.console.commands())?;
+    Ok(compiled)
+}
+
+/// Pause an enqueued or running job
+async fn pause_job(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut running = false;
+    let metadata = state.jobs.update_job(&id, |metadata| {
+        if !matches!(metadata.status, JobStatus::Enqueued | JobStatus::Running) {
+            return Err(AppError::InvalidJobStatus {
+                message: format!("job is {:?} and cannot be paused", metadata.status),
+                status: metadata.status.clone(),
+            });
+        }
+
+        running = metadata.status == JobStatus::Running;
+        metadata.status = JobStatus::Paused;
+        // A paused job that never started leaves the queue until resumed
+        if !running {
+            state.job_queue.lock().unwrap().remove(&id);
+        }
+        Ok(())
+    })?;
+
+    // Park a running job out of the way
+    if running {
+        state.print_stats.lock().unwrap().pause();
+        state
+            .pause
+            .pause(PauseReason::Requested)
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+    }
+
+    Ok(axum::Json(metadata))
+}
+
+/// Resume a paused job, re-priming and returning to where it stopped if it
+/// was running
+async fn resume_job(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    let metadata = state.jobs.update_job(&id, |metadata| {
+        if metadata.status != JobStatus::Paused {
+            return Err(AppError::InvalidJobStatus {
+                message: format!("job is {:?} and cannot be resumed", metadata.status),
+                status: metadata.status.clone(),
+            });
+        }
+
+        let was_running = state
+            .pause
+            .resume()
+            .map_err(|e| AppError::Conflict(format!("cannot resume: {e:#}")))?;
+        metadata.status = if was_running {
+            JobStatus::Running
+        } else {
+            JobStatus::Enqueued
+        };
+        if !was_running {
+            state.job_queue.lock().unwrap().push(id);
+        }
+        let mut print_stats = state.print_stats.lock().unwrap();
+        if print_stats.is_active(&id) {
+            print_stats.resume();
+        }
+        Ok(())
+    })?;
+    state.wake_job_runner();
+
+    Ok(axum::Json(metadata))
+}
+
+/// Cancel a job that has not finished yet
+async fn cancel_job(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    let metadata = state.jobs.update_job(&id, |metadata| {
+        if metadata.status.is_finished() {
+            return Err(AppError::InvalidJobStatus {
+                message: format!("job is {:?} and cannot be cancelled", metadata.status),
+                status: metadata.status.clone(),
+            });
+        }
+
+        metadata.status = JobStatus::Cancelled;
+        let mut print_stats = state.print_stats.lock().unwrap();
+        if print_stats.is_active(&id) {
+            metadata.print_stats = Some(print_stats.finish(PrintState::Cancelled));
+            state.console.overrides().clear();
+        }
+        let mut job_queue = state.job_queue.lock().unwrap();
+        job_queue.remove(&id);
+        job_queue.finish(&id, false);
+        Ok(())
+    })?;
+
+    // The runner stops the job before its next statement
+    state.wake_job_runner();
+
+    Ok(axum::Json(metadata))
+}
+
+/// Continue an interrupted job from its last power loss recovery checkpoint.
+/// The rest of its program, after a prologue that reheats and returns to
+/// where it stopped, is enqueued as a new job.
+async fn resume_from_checkpoint(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    let jobs = &state.jobs;
+    let metadata = jobs.get_job(&id).ok_or(AppError::NotFound)?;
+
+    if !matches!(metadata.status, JobStatus::Failed | JobStatus::Cancelled) {
+        return Err(AppError::InvalidJobStatus {
+            message: format!(
+                "job is {:?}; only failed or cancelled jobs resume from a checkpoint",
+                metadata.status
+            ),
+            status: metadata.status.clone(),
+        });
+    }
+
+    let checkpoint = Checkpoint::load(&jobs.checkpoint_path(&id))
+        .map_err(|e| AppError::Internal(format!("{e:#}")))?
+        .ok_or(AppError::NoCheckpoint)?;
+
+    // The job's statements are recovered from its component, so this works
+    // for any job the compiler produced
+    let component = fs::read(jobs.job_path(&id))
+        .context("failed to read job file")
+        .map_err(|e| AppError::Internal(format!("{e:#}")))?;
+    let program = scherzo_compile::decompile::decompile(&component)
+        .and_then(|source| Ok(scherzo_gcode::parse(&source)?))
+        .and_then(|statements| resume_program(&statements, &checkpoint.resume_point()))
+        .map_err(|e| AppError::Conflict(format!("cannot resume job: {e:#}")))?;
+    let compilation = scherzo_compile::compile_gcode(&program)
+        .map_err(|e| AppError::Internal(format!("failed to compile resume program: {e:#}")))?;
+
+    let source_map = Some(Arc::new(compilation.metadata.source_map));
+    let build = JobBuild::new(
+        &program,
+        &scherzo_compile::CompileOptions::default(),
+        &state.console.commands().commands(),
+    );
+    let created = jobs
+        .create_job(
+            &compilation.component,
+            "gcode",
+            Vec::new(),
+            source_map,
+            Some(build),
+        )
+        .map_err(|e| AppError::Internal(format!("{e:#}")))?;
+    let resumed = jobs.update_job(&created.id, |resumed| {
+        resumed.name = format!("{} (resumed)", metadata.name);
+        resumed.original_filename = metadata.original_filename;
+        resumed.firmware_retraction = metadata.firmware_retraction;
+        resumed.objects = metadata.objects;
+        resumed.excluded_objects = metadata.excluded_objects;
+        resumed.overrides = metadata.overrides;
+        resumed.resumed_from = Some(id);
+        resumed.status = JobStatus::Enqueued;
+        state.job_queue.lock().unwrap().push(resumed.id);
+        Ok(())
+    })?;
+    state.wake_job_runner();
+
+    tracing::info!(
+        "Resuming job {} after statement {} as {}",
+        resumed.name,
+        checkpoint.statement,
+        resumed.id
+    );
+
+    Ok((StatusCode::CREATED, axum::Json(resumed)))
+}
+
+/// Validate that the bytes are a WebAssembly component of the job world, and
+/// that each command it imports is handled by the host or a plugin
+fn validate_wasm_component(bytes: &[u8], commands: &CommandRegistry) -> Result<(), AppError> {
     // Use wasmparser to validate the component
     // wasmparser automatically detects and validates components
     let mut validator = wasmparser::Validator::new();
@@ -376,11 +2437,53 @@ fn validate_wasm_component(bytes: &[u8]) -> Result<(), AppError> {
         .context("invalid WebAssembly component")
         .map_err(|e| AppError::InvalidComponent(e.to_string()))?;
 
+    let report = scherzo_compile::wasm_util::inspect(bytes)
+        .map_err(|e| AppError::InvalidComponent(format!("{e:#}")))?;
+    if !report.component {
+        return Err(AppError::InvalidComponent(
+            "core wasm module; jobs must be encoded as components".to_string(),
+        ));
+    }
+    if !report.exports.iter().any(|item| item.name == "run") {
+        return Err(AppError::InvalidComponent(
+            "component does not export `run`".to_string(),
+        ));
+    }
+    if let Some(item) = report
+        .imports
+        .iter()
+        .find(|item| !item.name.starts_with("job:print/"))
+    {
+        return Err(AppError::InvalidComponent(format!(
+            "import `{}` is not provided to jobs",
+            item.name
+        )));
+    }
+
+    let provided: HashSet<_> = commands
+        .commands()
+        .iter()
+        .map(|command| scherzo_compile::import_module_name(command))
+        .collect();
+    let mut missing: Vec<_> = report
+        .imports
+        .iter()
+        .filter(|item| !provided.contains(&item.name))
+        .map(|item| item.name["job:print/".len()..].to_uppercase())
+        .collect();
+    if !missing.is_empty() {
+        missing.sort();
+        return Err(AppError::InvalidComponent(format!(
+            "no handler for commands: {}",
+            missing.join(", ")
+        )));
+    }
+
     Ok(())
 }
 
 /// Format seconds into a human-readable duration
-fn format_duration(seconds: f64) -> String {
+pub(crate) fn format_duration(seconds: f64) -> String {
     let hours = (seconds / 3600.0).floor();
     let minutes = ((seconds % 3600.0) / 60.0).floor();
     let secs = (seconds % 60.0).floor();
@@ -394,32 +2497,189 @@ fn format_duration(seconds: f64) -> String {
     }
 }
 
+/// Where in a job's G-code an error is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceLocation {
+    pub line: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub column: Option<usize>,
+}
+
+impl SourceLocation {
+    fn of(err: &scherzo_gcode::ParseError) -> Self {
+        let (line, column) = err.position();
+        Self { line, column }
+    }
+}
+
+/// Body of every error response. Clients match on `code`, which is stable,
+/// to translate or handle an error rather than parsing `message`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorBody {
+    /// Identifier of the kind of error, e.g. `job_not_found`
+    pub code: String,
+    /// Description of the error in English
+    pub message: String,
+    /// Values for the message, keyed by name, for the errors that have any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<SourceLocation>,
+}
+
 /// Application error types
 #[derive(Debug)]
 pub enum AppError {
     NotFound,
+    NoPrinter,
+    NoLayer,
+    Unauthorized,
     PayloadTooLarge,
+    /// The job compiled to more than the configured limits
+    CompiledTooLarge {
+        message: String,
+        /// Bytes the job took
+        size: usize,
+        /// Most bytes allowed
+        max: usize,
+    },
     InvalidComponent(String),
-    InvalidGCode { message: String },
+    /// The job has no asset at `path`
+    AssetNotFound {
+        path: String,
+    },
+    /// The job's status does not allow the request
+    InvalidJobStatus {
+        message: String,
+        status: JobStatus,
+    },
+    /// Calibrating while a job runs
+    JobRunning(String),
+    QueueNotHeld,
+    /// The job has no object named `name`
+    UnknownObject {
+        name: String,
+    },
+    /// Variables were given for a job that is not a template
+    NotATemplate,
+    /// A value was given for a variable the job template does not declare
+    UnknownTemplateVariable {
+        name: String,
+    },
+    NoCheckpoint,
+    InvalidGCode {
+        message: String,
+        location: Option<SourceLocation>,
+    },
+    BadRequest(String),
+    Conflict(String),
     Internal(String),
 }
 
-impl IntoResponse for AppError {
-    fn into_response(self) -> Response {
-        let (status, message) = match self {
-            AppError::NotFound => (StatusCode::NOT_FOUND, "Job not found"),
-            AppError::PayloadTooLarge => (StatusCode::PAYLOAD_TOO_LARGE, "Job file too large"),
-            AppError::InvalidComponent(ref msg) => {
-                return (StatusCode::BAD_REQUEST, msg.clone()).into_response();
-            }
-            AppError::InvalidGCode { ref message } => {
-                return (StatusCode::BAD_REQUEST, message.clone()).into_response();
+impl AppError {
+    fn status(&self) -> StatusCode {
+        match self {
+            AppError::NotFound
+            | AppError::NoPrinter
+            | AppError::NoLayer
+            | AppError::AssetNotFound { .. } => StatusCode::NOT_FOUND,
+            AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AppError::PayloadTooLarge | AppError::CompiledTooLarge { .. } => {
+                StatusCode::PAYLOAD_TOO_LARGE
             }
-            AppError::Internal(ref msg) => {
-                return (StatusCode::INTERNAL_SERVER_ERROR, msg.clone()).into_response();
+            AppError::InvalidComponent(_)
+            | AppError::InvalidGCode { .. }
+            | AppError::UnknownObject { .. }
+            | AppError::NotATemplate
+            | AppError::UnknownTemplateVariable { .. }
+            | AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::InvalidJobStatus { .. }
+            | AppError::JobRunning(_)
+            | AppError::QueueNotHeld
+            | AppError::NoCheckpoint
+            | AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Stable identifier of the error for [`ErrorBody::code`]
+    fn code(&self) -> &'static str {
+        match self {
+            AppError::NotFound => "job_not_found",
+            AppError::NoPrinter => "no_printer",
+            AppError::NoLayer => "layer_not_found",
+            AppError::Unauthorized => "unauthorized",
+            AppError::PayloadTooLarge => "payload_too_large",
+            AppError::CompiledTooLarge { .. } => "compiled_too_large",
+            AppError::InvalidComponent(_) => "invalid_component",
+            AppError::AssetNotFound { .. } => "asset_not_found",
+            AppError::InvalidJobStatus { .. } => "invalid_job_status",
+            AppError::JobRunning(_) => "job_running",
+            AppError::QueueNotHeld => "queue_not_held",
+            AppError::UnknownObject { .. } => "object_not_found",
+            AppError::NotATemplate => "not_a_template",
+            AppError::UnknownTemplateVariable { .. } => "template_variable_not_found",
+            AppError::NoCheckpoint => "checkpoint_not_found",
+            AppError::InvalidGCode { .. } => "invalid_gcode",
+            AppError::BadRequest(_) => "bad_request",
+            AppError::Conflict(_) => "conflict",
+            AppError::Internal(_) => "internal",
+        }
+    }
+
+    fn body(self) -> ErrorBody {
+        let code = self.code().to_string();
+        let (message, details, location) = match self {
+            AppError::NotFound => ("Job not found".to_string(), None, None),
+            AppError::NoPrinter => ("No [printer] is configured".to_string(), None, None),
+            AppError::NoLayer => ("Layer not found".to_string(), None, None),
+            AppError::Unauthorized => ("Authentication required".to_string(), None, None),
+            AppError::PayloadTooLarge => ("Job file too large".to_string(), None, None),
+            AppError::CompiledTooLarge { message, size, max } => (
+                message,
+                Some(serde_json::json!({ "size": size, "max": max })),
+                None,
+            ),
+            AppError::AssetNotFound { path } => (
+                "Asset not found".to_string(),
+                Some(serde_json::json!({ "path": path })),
+                None,
+            ),
+            AppError::InvalidJobStatus { message, status } => {
+                (message, Some(serde_json::json!({ "status": status })), None)
             }
+            AppError::QueueNotHeld => ("the queue is not held".to_string(), None, None),
+            AppError::UnknownObject { name } => (
+                format!("job has no object named `{name}`"),
+                Some(serde_json::json!({ "name": name })),
+                None,
+            ),
+            AppError::NotATemplate => ("job has no template variables".to_string(), None, None),
+            AppError::UnknownTemplateVariable { name } => (
+                format!("job has no template variable `{name}`"),
+                Some(serde_json::json!({ "name": name })),
+                None,
+            ),
+            AppError::NoCheckpoint => ("job has no checkpoint".to_string(), None, None),
+            AppError::InvalidGCode { message, location } => (message, None, location),
+            AppError::InvalidComponent(message)
+            | AppError::JobRunning(message)
+            | AppError::BadRequest(message)
+            | AppError::Conflict(message)
+            | AppError::Internal(message) => (message, None, None),
         };
-        (status, message).into_response()
+        ErrorBody {
+            code,
+            message,
+            details,
+            location,
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        (self.status(), axum::Json(self.body())).into_response()
     }
 }
 
@@ -429,3 +2689,1217 @@ use base64::prelude::*;
 fn decode_base64(input: &str) -> Result<Vec<u8>, base64::DecodeError> {
     BASE64_STANDARD.decode(input)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::hash_password,
+        executor::{Executor, HOST_CLOCK_FREQ, HostClock},
+        job_queue::QueueHold,
+        plugin::{CommandHandler, FieldDef, FieldType, PluginRegistry, SchedulingClass},
+    };
+
+    #[test]
+    fn test_authorization_header() {
+        let auth = AuthConfig {
+            username: "admin".to_string(),
+            password_hash: hash_password("secret").unwrap(),
+            api_keys: vec!["key-1".to_string()],
+        };
+        let basic = |creds: &str| format!("Basic {}", BASE64_STANDARD.encode(creds));
+
+        assert!(is_authorized(&auth, Some("Bearer key-1")));
+        assert!(!is_authorized(&auth, Some("Bearer key-2")));
+        assert!(is_authorized(&auth, Some(&basic("admin:secret"))));
+        assert!(!is_authorized(&auth, Some(&basic("admin:wrong"))));
+        assert!(!is_authorized(&auth, Some(&basic("root:secret"))));
+        assert!(!is_authorized(&auth, None));
+    }
+
+    #[test]
+    fn test_command_error_blames_motion_failures() {
+        let invalid = anyhow::Error::from(MotionError::HomingUnsupported).context("G28");
+        assert!(
+            matches!(command_error(invalid), AppError::BadRequest(msg) if msg == "G28: homing is not supported yet")
+        );
+        let overlap = MotionError::MoveOverlap {
+            print_time: 1.0,
+            queue_end: 2.0,
+        };
+        assert!(matches!(
+            command_error(overlap.into()),
+            AppError::Internal(_)
+        ));
+        assert!(matches!(
+            command_error(anyhow::anyhow!("unknown command `M999`")),
+            AppError::BadRequest(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_resume_interrupted_job_from_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = format!("[jobs]\nstorage_dir = {:?}\n", dir.path());
+        let config = Config::from_toml(&config).unwrap();
+
+        let store = JobStore::load(dir.path().to_path_buf()).unwrap();
+        let compilation =
+            scherzo_compile::compile_gcode("M104 S200\nG28\nG1 Z0.2 F600\nG1 X10 E1\nG1 X20 E2\n")
+                .unwrap();
+        let metadata = store
+            .create_job(&compilation.component, "gcode", Vec::new(), None, None)
+            .unwrap();
+        let id = metadata.id;
+        store
+            .update_job(&id, |metadata| {
+                metadata.status = JobStatus::Running;
+                Ok(())
+            })
+            .unwrap();
+        let source_map = &compilation.metadata.source_map;
+        Checkpoint::capture(4, source_map, [10.0, 0.0, 0.2, 1.0])
+            .save(&store.checkpoint_path(&id))
+            .unwrap();
+
+        // Restarting finds the job was interrupted
+        let pause = PauseController::new(&config, None);
+        let bed_mesh = BedMeshController::new(&config, None).unwrap();
+        let input_shaper = InputShaperController::new(&config, None);
+        let console = Console::new(&config, None, PluginRegistry::new());
+        let crash = CrashReporter::new(&config, PluginRegistry::new());
+        let state =
+            AppState::new(config, None, pause, bed_mesh, input_shaper, console, crash).unwrap();
+        let status = state.jobs.get_job(&id).unwrap().status;
+        assert_eq!(status, JobStatus::Failed);
+
+        let response = resume_from_checkpoint(State(state.clone()), Path(id))
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let jobs = &state.jobs;
+        let resumed = jobs
+            .list_jobs()
+            .into_iter()
+            .find(|job| job.resumed_from == Some(id))
+            .unwrap();
+        assert_eq!(resumed.status, JobStatus::Enqueued);
+        let component = fs::read(jobs.job_path(&resumed.id)).unwrap();
+        let source = scherzo_compile::decompile::decompile(&component).unwrap();
+        assert!(source.contains("M109 S200\n"), "{source}");
+        assert!(source.ends_with("G1 F600\nG1 X20 E2\n"), "{source}");
+    }
+
+    #[tokio::test]
+    async fn test_job_toolpath_is_drawn() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = format!("[jobs]\nstorage_dir = {:?}\n", dir.path());
+        let config = Config::from_toml(&config).unwrap();
+        let pause = PauseController::new(&config, None);
+        let bed_mesh = BedMeshController::new(&config, None).unwrap();
+        let input_shaper = InputShaperController::new(&config, None);
+        let console = Console::new(&config, None, PluginRegistry::new());
+        let crash = CrashReporter::new(&config, PluginRegistry::new());
+        let state =
+            AppState::new(config, None, pause, bed_mesh, input_shaper, console, crash).unwrap();
+
+        let compilation = scherzo_compile::compile_gcode(
+            "G28\nG1 Z0.2 F600\nG1 X10 E1\nG1 Y10 E2\nG1 Z0.4\nG1 X0 E3\n",
+        )
+        .unwrap();
+        let id = state
+            .jobs
+            .create_job(&compilation.component, "gcode", Vec::new(), None, None)
+            .unwrap()
+            .id;
+        let layer = |file: &str, size: Option<u32>| {
+            get_job_layer(
+                State(state.clone()),
+                Path((id, file.to_string())),
+                Query(LayerImageQuery { size }),
+            )
+        };
+
+        let layers = list_job_layers(State(state.clone()), Path(id))
+            .await
+            .unwrap()
+            .into_response();
+        let body = axum::body::to_bytes(layers.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let layers: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            layers,
+            serde_json::json!([
+                { "number": 1, "z": 0.2, "segments": 2 },
+                { "number": 2, "z": 0.4, "segments": 1 },
+            ])
+        );
+
+        let svg = layer("2.svg", None).await.unwrap();
+        assert_eq!(svg.headers()["content-type"], "image/svg+xml");
+        let png = layer("1.png", Some(64)).await.unwrap();
+        assert_eq!(png.headers()["content-type"], "image/png");
+        let body = axum::body::to_bytes(png.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(body.starts_with(b"\x89PNG"));
+
+        assert!(matches!(layer("3.svg", None).await, Err(AppError::NoLayer)));
+        assert!(matches!(
+            layer("first.svg", None).await,
+            Err(AppError::NoLayer)
+        ));
+        assert!(matches!(
+            layer("1.gif", None).await,
+            Err(AppError::BadRequest(_))
+        ));
+        assert!(matches!(
+            layer("1.png", Some(1 << 20)).await,
+            Err(AppError::BadRequest(_))
+        ));
+
+        let glb = get_job_toolpath_glb(State(state.clone()), Path(id))
+            .await
+            .unwrap();
+        assert_eq!(glb.headers()["content-type"], "model/gltf-binary");
+        let body = axum::body::to_bytes(glb.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(body.starts_with(b"glTF"));
+
+        let dry_run = |interval| {
+            let request = DryRunRequest { interval };
+            dry_run_job(State(state.clone()), Path(id), Some(axum::Json(request)))
+        };
+        let response = dry_run(0.05).await.unwrap().into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let run: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let duration = run["duration"].as_f64().unwrap();
+        let samples = run["samples"].as_array().unwrap();
+        assert!(duration > 0.0);
+        assert_eq!(samples.last().unwrap()["time"].as_f64(), Some(duration));
+        assert_eq!(
+            samples.last().unwrap()["position"],
+            serde_json::json!([0.0, 10.0, 0.4, 3.0])
+        );
+        assert!(matches!(dry_run(0.0).await, Err(AppError::BadRequest(_))));
+    }
+
+    #[tokio::test]
+    async fn test_jobs_are_diffed() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = format!("[jobs]\nstorage_dir = {:?}\n", dir.path());
+        let config = Config::from_toml(&config).unwrap();
+        let pause = PauseController::new(&config, None);
+        let bed_mesh = BedMeshController::new(&config, None).unwrap();
+        let input_shaper = InputShaperController::new(&config, None);
+        let console = Console::new(&config, None, PluginRegistry::new());
+        let crash = CrashReporter::new(&config, PluginRegistry::new());
+        let state =
+            AppState::new(config, None, pause, bed_mesh, input_shaper, console, crash).unwrap();
+
+        let object = |name: &str| JobObject {
+            name: name.to_string(),
+            center: None,
+            polygon: Vec::new(),
+        };
+        let create = |gcode: &str, objects: Vec<JobObject>| {
+            let compilation = scherzo_compile::compile_gcode(gcode).unwrap();
+            state
+                .jobs
+                .create_job(&compilation.component, "gcode", objects, None, None)
+                .unwrap()
+                .id
+        };
+        let a = create(
+            "M104 S210\nG28\nG1 Z0.2 F600\nG1 X10 E1\nG1 Y10 E2\n",
+            vec![object("cube"), object("cone")],
+        );
+        let b = create(
+            "M104 S215\nG28\nG1 Z0.2 F600\nG1 X20 E2\nG1 Y10 E3\n",
+            vec![object("cube"), object("cylinder")],
+        );
+
+        let diff = |a, b| diff_jobs(State(state.clone()), Path((a, b)));
+        let response = diff(a, b).await.unwrap().into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let diff_ab: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(diff_ab["identical"], false);
+        let changes: Vec<_> = diff_ab["changes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|change| {
+                format!(
+                    "{} {}",
+                    change["change"].as_str().unwrap(),
+                    change["command"].as_str().unwrap()
+                )
+            })
+            .collect();
+        assert!(
+            changes.contains(&"removed M104 S210".to_string()),
+            "{changes:?}"
+        );
+        assert!(
+            changes.contains(&"added M104 S215".to_string()),
+            "{changes:?}"
+        );
+        assert_eq!(
+            diff_ab["temperatures"],
+            serde_json::json!([{ "heater": "extruder", "a": 210.0, "b": 215.0 }])
+        );
+        assert_eq!(
+            diff_ab["filament_mm"],
+            serde_json::json!({ "a": 2.0, "b": 3.0 })
+        );
+        assert!(
+            diff_ab["estimated_seconds"]["b"].as_f64() > diff_ab["estimated_seconds"]["a"].as_f64()
+        );
+        assert_eq!(diff_ab["objects_added"], serde_json::json!(["cylinder"]));
+        assert_eq!(diff_ab["objects_removed"], serde_json::json!(["cone"]));
+
+        let response = diff(a, a).await.unwrap().into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let same: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(same["identical"], true);
+        assert_eq!(same["changes"], serde_json::json!([]));
+        assert!(matches!(
+            diff(a, Uuid::new_v4()).await,
+            Err(AppError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_identical_uploads_reuse_the_compiled_component() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = format!("[jobs]\nstorage_dir = {:?}\n", dir.path());
+        let config = Config::from_toml(&config).unwrap();
+        let pause = PauseController::new(&config, None);
+        let bed_mesh = BedMeshController::new(&config, None).unwrap();
+        let input_shaper = InputShaperController::new(&config, None);
+        let console = Console::new(&config, None, PluginRegistry::new());
+        let crash = CrashReporter::new(&config, PluginRegistry::new());
+        let state =
+            AppState::new(config, None, pause, bed_mesh, input_shaper, console, crash).unwrap();
+
+        let mut headers = axum::http::HeaderMap::new();
+        headers.insert(
+            axum::http::header::CONTENT_TYPE,
+            "text/x-gcode".parse().unwrap(),
+        );
+        let gcode = axum::body::Bytes::from_static(b"G28\nG1 X10 F600\nG1 Y10\n");
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let response = upload_job(State(state.clone()), headers.clone(), gcode.clone())
+                .await
+                .unwrap()
+                .into_response();
+            assert_eq!(response.status(), StatusCode::CREATED);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let upload: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            ids.push(upload["job_id"].as_str().unwrap().parse::<Uuid>().unwrap());
+        }
+
+        let stats = state.compile_cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+        let components: Vec<_> = ids
+            .iter()
+            .map(|id| fs::read(state.jobs.job_path(id)).unwrap())
+            .collect();
+        assert_eq!(components[0], components[1]);
+        let source_maps: Vec<_> = ids
+            .iter()
+            .map(|id| state.jobs.source_map(id).unwrap())
+            .collect();
+        assert_eq!(source_maps[0], source_maps[1]);
+        assert!(!source_maps[0].entries.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_errors_are_structured() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = format!("[jobs]\nstorage_dir = {:?}\n", dir.path());
+        let config = Config::from_toml(&config).unwrap();
+        let pause = PauseController::new(&config, None);
+        let bed_mesh = BedMeshController::new(&config, None).unwrap();
+        let input_shaper = InputShaperController::new(&config, None);
+        let console = Console::new(&config, None, PluginRegistry::new());
+        let crash = CrashReporter::new(&config, PluginRegistry::new());
+        let state =
+            AppState::new(config, None, pause, bed_mesh, input_shaper, console, crash).unwrap();
+
+        let body = |response: Response| async move {
+            let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<ErrorBody>(&bytes).unwrap()
+        };
+
+        let Err(err) = get_job(State(state.clone()), Path(Uuid::new_v4())).await else {
+            panic!("found a job that does not exist");
+        };
+        let response = err.into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let not_found = body(response).await;
+        assert_eq!(not_found.code, "job_not_found");
+        assert_eq!(not_found.message, "Job not found");
+        assert_eq!((not_found.details, not_found.location), (None, None));
+
+        let mut headers = axum::http::HeaderMap::new();
+        headers.insert(
+            axum::http::header::CONTENT_TYPE,
+            "text/x-gcode".parse().unwrap(),
+        );
+        let Err(err) = upload_job(State(state.clone()), headers, "G28\nM117 \"abc\n".into()).await
+        else {
+            panic!("invalid G-code was accepted");
+        };
+        let response = err.into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let invalid = body(response).await;
+        assert_eq!(invalid.code, "invalid_gcode");
+        assert_eq!(
+            invalid.location,
+            Some(SourceLocation {
+                line: 2,
+                column: Some(7)
+            })
+        );
+
+        let too_large = AppError::CompiledTooLarge {
+            message: "too large".to_string(),
+            size: 10,
+            max: 4,
+        };
+        let too_large = body(too_large.into_response()).await;
+        assert_eq!(too_large.code, "compiled_too_large");
+        assert_eq!(
+            too_large.details,
+            Some(serde_json::json!({ "size": 10, "max": 4 }))
+        );
+
+        let Err(err) = confirm_queue(State(state.clone())).await else {
+            panic!("confirmed a queue that is not held");
+        };
+        let response = err.into_response();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(body(response).await.code, "queue_not_held");
+
+        let stopped = AppError::InvalidJobStatus {
+            message: "job is Cancelled and cannot be paused".to_string(),
+            status: JobStatus::Cancelled,
+        };
+        let response = stopped.into_response();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let stopped = body(response).await;
+        assert_eq!(stopped.code, "invalid_job_status");
+        assert_eq!(
+            stopped.details,
+            Some(serde_json::json!({ "status": "cancelled" }))
+        );
+
+        let missing = AppError::AssetNotFound {
+            path: "thumbnail.png".to_string(),
+        };
+        let response = missing.into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let missing = body(response).await;
+        assert_eq!(missing.code, "asset_not_found");
+        assert_eq!(
+            missing.details,
+            Some(serde_json::json!({ "path": "thumbnail.png" }))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_oversized_jobs_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = format!(
+            "[jobs]\nstorage_dir = {:?}\nmax_function_size = 100\nmax_component_size = 4096\n",
+            dir.path()
+        );
+        let config = Config::from_toml(&config).unwrap();
+        let pause = PauseController::new(&config, None);
+        let bed_mesh = BedMeshController::new(&config, None).unwrap();
+        let input_shaper = InputShaperController::new(&config, None);
+        let console = Console::new(&config, None, PluginRegistry::new());
+        let crash = CrashReporter::new(&config, PluginRegistry::new());
+        let state =
+            AppState::new(config, None, pause, bed_mesh, input_shaper, console, crash).unwrap();
+
+        let upload = |content_type: &'static str, body: Vec<u8>| {
+            let mut headers = axum::http::HeaderMap::new();
+            headers.insert(
+                axum::http::header::CONTENT_TYPE,
+                content_type.parse().unwrap(),
+            );
+            upload_job(State(state.clone()), headers, body.into())
+        };
+        let small = upload("text/x-gcode", b"G28\nG1 X10 F600\n".to_vec()).await;
+        assert_eq!(small.unwrap().into_response().status(), StatusCode::CREATED);
+
+        let large = "G1 X10 F600\nG1 Y10\n".repeat(50);
+        let Err(AppError::CompiledTooLarge { message, .. }) =
+            upload("text/x-gcode", large.into_bytes()).await
+        else {
+            panic!("oversized job was accepted");
+        };
+        assert!(message.contains("run function"), "{message}");
+        let Err(AppError::CompiledTooLarge { size: 5000, .. }) =
+            upload("application/wasm", vec![0; 5000]).await
+        else {
+            panic!("oversized component was accepted");
+        };
+        assert_eq!(state.jobs.list_jobs().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_template_job_is_resolved_at_enqueue() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = format!("[jobs]\nstorage_dir = {:?}\n", dir.path());
+        let config = Config::from_toml(&config).unwrap();
+        let pause = PauseController::new(&config, None);
+        let bed_mesh = BedMeshController::new(&config, None).unwrap();
+        let input_shaper = InputShaperController::new(&config, None);
+        let console = Console::new(&config, None, PluginRegistry::new());
+        let crash = CrashReporter::new(&config, PluginRegistry::new());
+        let state =
+            AppState::new(config, None, pause, bed_mesh, input_shaper, console, crash).unwrap();
+
+        let mut headers = axum::http::HeaderMap::new();
+        headers.insert(
+            axum::http::header::CONTENT_TYPE,
+            "text/x-gcode".parse().unwrap(),
+        );
+        let gcode = "; TEMPLATE_VARIABLE NAME=distance DEFAULT=10\nG28\nG1 X{ distance } F600\n";
+        upload_job(State(state.clone()), headers, gcode.into())
+            .await
+            .unwrap();
+        let job = state.jobs.list_jobs().pop().unwrap();
+        assert_eq!(job.template_variables.len(), 1);
+        assert_eq!(job.template_variables[0].name, "distance");
+        let source = || {
+            let component = fs::read(state.jobs.job_path(&job.id)).unwrap();
+            scherzo_compile::decompile::decompile(&component).unwrap()
+        };
+        assert!(source().contains("G1 X10 F600"), "{}", source());
+
+        let enqueue = |variables: serde_json::Value| {
+            let variables = variables.as_object().unwrap().clone();
+            enqueue_job(
+                State(state.clone()),
+                Path(job.id),
+                Some(axum::Json(EnqueueRequest { variables })),
+            )
+        };
+        assert!(matches!(
+            enqueue(serde_json::json!({ "speed": 1 })).await,
+            Err(AppError::UnknownTemplateVariable { name }) if name == "speed"
+        ));
+        enqueue(serde_json::json!({ "distance": 25 }))
+            .await
+            .unwrap();
+        let enqueued = state.jobs.get_job(&job.id).unwrap();
+        assert_eq!(enqueued.status, JobStatus::Enqueued);
+        assert_eq!(enqueued.template_values["distance"], 25);
+        assert!(source().contains("G1 X25 F600"), "{}", source());
+        // Once queued, the component stays as it was resolved
+        assert!(matches!(
+            enqueue(serde_json::json!({ "distance": 30 })).await,
+            Err(AppError::InvalidJobStatus {
+                status: JobStatus::Enqueued,
+                ..
+            })
+        ));
+
+        delete_job(State(state.clone()), Path(job.id))
+            .await
+            .unwrap();
+        assert!(!state.jobs.template_path(&job.id).exists());
+    }
+
+    #[tokio::test]
+    async fn test_queue_runs_script_and_waits_for_confirmation() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = format!(
+            "[jobs]\nstorage_dir = {:?}\n\n[job_queue]\nbetween_jobs_gcode = \"EJECT\"\nconfirm_between_jobs = true\n\n[macros.EJECT]\ngcode = \"M118 ejected\"\n",
+            dir.path()
+        );
+        let config = Config::from_toml(&config).unwrap();
+        config.validate().unwrap();
+        let pause = PauseController::new(&config, None);
+        let bed_mesh = BedMeshController::new(&config, None).unwrap();
+        let input_shaper = InputShaperController::new(&config, None);
+        let console = Console::new(&config, None, PluginRegistry::new());
+        let crash = CrashReporter::new(&config, PluginRegistry::new());
+        let state =
+            AppState::new(config, None, pause, bed_mesh, input_shaper, console, crash).unwrap();
+
+        let compilation = scherzo_compile::compile_gcode("G1 X10 F600\n").unwrap();
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let metadata = state
+                .jobs
+                .create_job(&compilation.component, "gcode", Vec::new(), None, None)
+                .unwrap();
+            ids.push(metadata.id);
+        }
+        for &id in ids.iter().rev() {
+            enqueue_job(State(state.clone()), Path(id), None)
+                .await
+                .unwrap();
+        }
+        let order = QueueOrderRequest { jobs: ids.clone() };
+        reorder_queue(State(state.clone()), axum::Json(order))
+            .await
+            .unwrap();
+
+        let (first, _span) = state.start_next_job().unwrap();
+        assert_eq!(first.id, ids[0]);
+        assert!(state.start_next_job().is_none());
+        state.finish_job(&ids[0], true);
+        let status = state.jobs.get_job(&ids[0]).unwrap().status;
+        assert_eq!(status, JobStatus::Completed);
+
+        // The queue holds until the plate is confirmed clear
+        assert!(state.start_next_job().is_none());
+        confirm_queue(State(state.clone())).await.unwrap();
+        assert!(confirm_queue(State(state.clone())).await.is_err());
+        assert_eq!(state.start_next_job().unwrap().0.id, ids[1]);
+
+        // Cancelling the running job holds the queue too
+        cancel_job(State(state.clone()), Path(ids[1]))
+            .await
+            .unwrap();
+        let hold = state.job_queue.lock().unwrap().status().hold;
+        assert_eq!(hold, Some(QueueHold::JobStopped { job_id: ids[1] }));
+        // The runner finishing the job it was running leaves it cancelled
+        state.finish_job(&ids[1], false);
+        let status = state.jobs.get_job(&ids[1]).unwrap().status;
+        assert_eq!(status, JobStatus::Cancelled);
+    }
+
+    #[tokio::test]
+    async fn test_runner_saves_checkpoints() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = format!(
+            "[jobs]\nstorage_dir = {:?}\ncheckpoint_interval = 1e-9\n",
+            dir.path()
+        );
+        let config = Config::from_toml(&config).unwrap();
+        let pause = PauseController::new(&config, None);
+        let bed_mesh = BedMeshController::new(&config, None).unwrap();
+        let input_shaper = InputShaperController::new(&config, None);
+        let console = Console::new(&config, None, PluginRegistry::new());
+        let crash = CrashReporter::new(&config, PluginRegistry::new());
+        let state =
+            AppState::new(config, None, pause, bed_mesh, input_shaper, console, crash).unwrap();
+
+        // The move fails without a printer, as a power loss would stop it
+        let compilation = scherzo_compile::compile_gcode("M83\nG90\nG1 X10 F600\nM82\n").unwrap();
+        let metadata = state
+            .jobs
+            .create_job(&compilation.component, "gcode", Vec::new(), None, None)
+            .unwrap();
+        let id = metadata.id;
+        assert!(!state.jobs.checkpoint_path(&id).exists());
+        enqueue_job(State(state.clone()), Path(id), None)
+            .await
+            .unwrap();
+        state.run_queued_jobs();
+        assert_eq!(state.jobs.get_job(&id).unwrap().status, JobStatus::Failed);
+
+        let checkpoint = Checkpoint::load(&state.jobs.checkpoint_path(&id))
+            .unwrap()
+            .unwrap();
+        assert_eq!(checkpoint.statement, 2);
+        assert_eq!(checkpoint.line, Some(2));
+        let response = resume_from_checkpoint(State(state.clone()), Path(id))
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_runner_tracks_print_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = format!("[jobs]\nstorage_dir = {:?}\n", dir.path());
+        let config = Config::from_toml(&config).unwrap();
+        let pause = PauseController::new(&config, None);
+        let bed_mesh = BedMeshController::new(&config, None).unwrap();
+        let input_shaper = InputShaperController::new(&config, None);
+        let console = Console::new(&config, None, PluginRegistry::new());
+        let crash = CrashReporter::new(&config, PluginRegistry::new());
+        let state =
+            AppState::new(config, None, pause, bed_mesh, input_shaper, console, crash).unwrap();
+
+        // Without a printer the move stops the job part way through
+        let gcode = "G91\n\nM83\nG1 X10\nM82\n";
+        let statements = scherzo_gcode::parse(gcode).unwrap();
+        let compilation = scherzo_compile::compile_gcode(gcode).unwrap();
+        let source_map = Some(Arc::new(SourceMap::build(&statements)));
+        let metadata = state
+            .jobs
+            .create_job(
+                &compilation.component,
+                "gcode",
+                Vec::new(),
+                source_map,
+                None,
+            )
+            .unwrap();
+        enqueue_job(State(state.clone()), Path(metadata.id), None)
+            .await
+            .unwrap();
+        state.run_queued_jobs();
+
+        let stats = state
+            .jobs
+            .get_job(&metadata.id)
+            .unwrap()
+            .print_stats
+            .unwrap();
+        assert_eq!(stats.state, PrintState::Error);
+        assert_eq!(stats.current_line, Some(3));
+        assert_eq!(stats.progress, 0.5);
+        assert_eq!(state.print_stats.lock().unwrap().stats(), stats);
+    }
+
+    #[tokio::test]
+    async fn test_runner_prints_queued_jobs_in_turn() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = format!(
+            "[jobs]\nstorage_dir = {:?}\n\n[job_queue]\nbetween_jobs_gcode = \"EJECT\"\n\n[macros.EJECT]\ngcode = \"M118 ejected\"\n",
+            dir.path()
+        );
+        let config = Config::from_toml(&config).unwrap();
+        let pause = PauseController::new(&config, None);
+        let bed_mesh = BedMeshController::new(&config, None).unwrap();
+        let input_shaper = InputShaperController::new(&config, None);
+        let console = Console::new(&config, None, PluginRegistry::new());
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        console.set_command_hook({
+            let lines = lines.clone();
+            move |line| lines.lock().unwrap().push(line.to_string())
+        });
+        let crash = CrashReporter::new(&config, PluginRegistry::new());
+        let state =
+            AppState::new(config, None, pause, bed_mesh, input_shaper, console, crash).unwrap();
+
+        // Without a printer the move fails its job
+        let mut ids = Vec::new();
+        for gcode in ["G90\nM83\n", "G91\nG1 X10\nM82\n", "M82\n"] {
+            let compilation = scherzo_compile::compile_gcode(gcode).unwrap();
+            let metadata = state
+                .jobs
+                .create_job(&compilation.component, "gcode", Vec::new(), None, None)
+                .unwrap();
+            enqueue_job(State(state.clone()), Path(metadata.id), None)
+                .await
+                .unwrap();
+            ids.push(metadata.id);
+        }
+
+        state.run_queued_jobs();
+        let status = |id| state.jobs.get_job(id).unwrap().status;
+        assert_eq!(status(&ids[0]), JobStatus::Completed);
+        assert_eq!(status(&ids[1]), JobStatus::Failed);
+        assert_eq!(status(&ids[2]), JobStatus::Enqueued);
+        assert_eq!(
+            *lines.lock().unwrap(),
+            ["G90", "M83", "EJECT", "M118 ejected", "G91", "G1 X10"]
+        );
+        let hold = state.job_queue.lock().unwrap().status().hold;
+        assert_eq!(hold, Some(QueueHold::JobStopped { job_id: ids[1] }));
+
+        confirm_queue(State(state.clone())).await.unwrap();
+        state.run_queued_jobs();
+        assert_eq!(status(&ids[2]), JobStatus::Completed);
+        assert_eq!(state.job_queue.lock().unwrap().status().printing, None);
+    }
+
+    const PRINTER: &str = r#"
+[printer]
+kinematics = "cartesian"
+max_velocity = 300
+max_accel = 3000
+buffer_time_start = 0.05
+
+[stepper_x]
+rotation_distance = 40
+microsteps = 16
+position_max = 100
+
+[stepper_y]
+rotation_distance = 40
+microsteps = 16
+position_max = 100
+
+[stepper_z]
+rotation_distance = 8
+microsteps = 16
+position_max = 50
+
+[extruder]
+rotation_distance = 22.6789511
+microsteps = 16
+
+[firmware_retraction]
+retract_length = 0.8
+retract_speed = 35
+"#;
+
+    /// State of a server driving a printer, with the machine it moves
+    fn printer_state(dir: &std::path::Path) -> (AppState, Arc<Mutex<Machine>>) {
+        let config = format!("{PRINTER}\n[jobs]\nstorage_dir = {dir:?}\n");
+        let config = Config::from_toml(&config).unwrap();
+        let machine = Machine::from_config(&config).unwrap().unwrap();
+        let machine = Arc::new(Mutex::new(machine));
+        let clock = HostClock::new(HOST_CLOCK_FREQ);
+        let executor = Arc::new(Executor::spawn(machine.clone(), clock));
+        let pause = PauseController::new(&config, Some(executor.clone()));
+        let bed_mesh = BedMeshController::new(&config, Some(executor.clone())).unwrap();
+        let input_shaper = InputShaperController::new(&config, Some(executor.clone()));
+        let console = Console::new(&config, Some(executor), PluginRegistry::new());
+        let crash = CrashReporter::new(&config, PluginRegistry::new());
+        let state = AppState::new(
+            config,
+            Some(machine.clone()),
+            pause,
+            bed_mesh,
+            input_shaper,
+            console,
+            crash,
+        )
+        .unwrap();
+        (state, machine)
+    }
+
+    #[tokio::test]
+    async fn test_runner_skips_excluded_objects() {
+        let dir = tempfile::tempdir().unwrap();
+        let (state, machine) = printer_state(dir.path());
+
+        let gcode = "G1 X10 E1 F6000\n\
+                     EXCLUDE_OBJECT_START NAME=part_2\nG1 X20 E2\nEXCLUDE_OBJECT_END\n\
+                     EXCLUDE_OBJECT_START NAME=part_1\nG1 X20 Y10 E3\nEXCLUDE_OBJECT_END\n";
+        let compilation = scherzo_compile::compile_gcode(gcode).unwrap();
+        let objects = ["PART_2", "PART_1"].map(|name| JobObject {
+            name: name.to_string(),
+            center: None,
+            polygon: Vec::new(),
+        });
+        let id = state
+            .jobs
+            .create_job(
+                &compilation.component,
+                "gcode",
+                objects.to_vec(),
+                None,
+                None,
+            )
+            .unwrap()
+            .id;
+        let request = ExcludeObjectRequest {
+            name: "part_2".to_string(),
+        };
+        exclude_object(State(state.clone()), Path(id), axum::Json(request))
+            .await
+            .unwrap();
+        enqueue_job(State(state.clone()), Path(id), None)
+            .await
+            .unwrap();
+
+        state.run_queued_jobs();
+        assert_eq!(
+            state.jobs.get_job(&id).unwrap().status,
+            JobStatus::Completed
+        );
+        let machine = machine.lock().unwrap();
+        // The move to X20 was skipped, and its extrusion with it
+        assert_eq!(machine.toolhead.position(), [20.0, 10.0, 0.0, 2.0]);
+        // The next job starts with nothing excluded
+        assert_eq!(machine.exclude_object.excluded().count(), 0);
+        assert_eq!(machine.exclude_object.current(), None);
+    }
+
+    #[tokio::test]
+    async fn test_runner_retracts_only_if_the_job_asks() {
+        let dir = tempfile::tempdir().unwrap();
+        let (state, machine) = printer_state(dir.path());
+
+        let compilation = scherzo_compile::compile_gcode("G10\n").unwrap();
+        let mut ids = Vec::new();
+        for enabled in [false, true] {
+            let id = state
+                .jobs
+                .create_job(&compilation.component, "gcode", Vec::new(), None, None)
+                .unwrap()
+                .id;
+            let request = FirmwareRetractionRequest { enabled };
+            set_firmware_retraction(State(state.clone()), Path(id), axum::Json(request))
+                .await
+                .unwrap();
+            ids.push(id);
+        }
+        let position = || machine.lock().unwrap().commanded_position();
+
+        enqueue_job(State(state.clone()), Path(ids[0]), None)
+            .await
+            .unwrap();
+        state.run_queued_jobs();
+        assert_eq!(
+            state.jobs.get_job(&ids[0]).unwrap().status,
+            JobStatus::Completed
+        );
+        assert_eq!(position(), [0.0; 4]);
+
+        enqueue_job(State(state.clone()), Path(ids[1]), None)
+            .await
+            .unwrap();
+        state.run_queued_jobs();
+        assert_eq!(position(), [0.0, 0.0, 0.0, -0.8]);
+    }
+
+    #[tokio::test]
+    async fn test_overrides_apply_live_to_the_running_job() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = format!("[jobs]\nstorage_dir = {:?}\n", dir.path());
+        let config = Config::from_toml(&config).unwrap();
+        let pause = PauseController::new(&config, None);
+        let bed_mesh = BedMeshController::new(&config, None).unwrap();
+        let input_shaper = InputShaperController::new(&config, None);
+        let console = Console::new(&config, None, PluginRegistry::new());
+        let crash = CrashReporter::new(&config, PluginRegistry::new());
+        let state =
+            AppState::new(config, None, pause, bed_mesh, input_shaper, console, crash).unwrap();
+
+        let compilation = scherzo_compile::compile_gcode("G1 X10 F600\n").unwrap();
+        let id = state
+            .jobs
+            .create_job(&compilation.component, "gcode", Vec::new(), None, None)
+            .unwrap()
+            .id;
+        let set_overrides = |request: serde_json::Value| {
+            let request = serde_json::from_value(request).unwrap();
+            set_job_overrides(State(state.clone()), Path(id), axum::Json(request))
+        };
+
+        set_overrides(serde_json::json!({ "speed_factor": 1.5 }))
+            .await
+            .unwrap();
+        assert!(matches!(
+            set_overrides(serde_json::json!({ "extrude_factor": 0 })).await,
+            Err(AppError::BadRequest(_))
+        ));
+        // Nothing is live until the job starts
+        assert!(state.console.overrides().get().is_default());
+
+        enqueue_job(State(state.clone()), Path(id), None)
+            .await
+            .unwrap();
+        state.start_next_job().unwrap();
+        assert_eq!(state.console.overrides().get().speed_factor, 1.5);
+
+        let request = serde_json::json!({
+            "extrude_factor": 0.9,
+            "temperature_offsets": { "extruder": 5 },
+        });
+        set_overrides(request).await.unwrap();
+        let live = state.console.overrides().get();
+        assert_eq!(live.speed_factor, 1.5);
+        assert_eq!(live.extrude_factor, 0.9);
+        assert_eq!(live.temperature("extruder", 200.0), 205.0);
+
+        // Saved with the job
+        let reloaded = JobStore::load(dir.path().to_path_buf()).unwrap();
+        assert_eq!(reloaded.get_job(&id).unwrap().overrides, live);
+
+        state.finish_job(&id, true);
+        assert!(state.console.overrides().get().is_default());
+        assert!(matches!(
+            set_overrides(serde_json::json!({ "speed_factor": 1 })).await,
+            Err(AppError::InvalidJobStatus { .. })
+        ));
+    }
+
+    #[test]
+    fn test_concurrent_updates_save_the_latest_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = JobStore::load(dir.path().to_path_buf()).unwrap();
+        let id = store
+            .create_job(b"component", "wasm", Vec::new(), None, None)
+            .unwrap()
+            .id;
+
+        std::thread::scope(|scope| {
+            for thread in 0..8 {
+                let store = store.clone();
+                scope.spawn(move || {
+                    for i in 0..20 {
+                        let _ = store.update_job(&id, |metadata| {
+                            metadata.name = format!("job-{thread}-{i}");
+                            Ok(())
+                        });
+                        assert!(store.get_job(&id).is_some());
+                    }
+                });
+            }
+        });
+        let latest = store.get_job(&id).unwrap();
+        assert_ne!(latest.name, format!("job-{id}"));
+
+        // The disk holds the last change, not whichever save finished last
+        let reloaded = JobStore::load(dir.path().to_path_buf()).unwrap();
+        assert_eq!(reloaded.get_job(&id).unwrap().name, latest.name);
+
+        // A failed change leaves the job as it was
+        let err = store.update_job(&id, |metadata| {
+            metadata.name = "discarded".to_string();
+            Err(AppError::Conflict("no".to_string()))
+        });
+        assert!(matches!(err, Err(AppError::Conflict(_))));
+        assert_eq!(store.get_job(&id).unwrap().name, latest.name);
+
+        store.remove_job(&id, |_| {}).unwrap().unwrap();
+        assert!(store.remove_job(&id, |_| {}).unwrap().is_none());
+        assert!(
+            JobStore::load(dir.path().to_path_buf())
+                .unwrap()
+                .list_jobs()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_upload_requires_handler_for_each_command() {
+        let compilation =
+            scherzo_compile::compile_gcode("G28\nG1 X10 F600\nM104 S200\nM106 S255\n").unwrap();
+        let plugins = PluginRegistry::new();
+        let commands = CommandRegistry::new(plugins.clone());
+        let err = validate_wasm_component(&compilation.component, &commands).unwrap_err();
+        let AppError::InvalidComponent(message) = err else {
+            panic!("unexpected error");
+        };
+        assert_eq!(message, "no handler for commands: M104, M106");
+
+        for command in ["M104", "M106"] {
+            plugins
+                .register_command_handler(CommandHandler {
+                    command: command.to_string(),
+                    params: Vec::new(),
+                    description: None,
+                    scheduling_class: SchedulingClass::Queued,
+                })
+                .unwrap();
+        }
+        validate_wasm_component(&compilation.component, &commands).unwrap();
+        assert!(validate_wasm_component(&compilation.wasm, &commands).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_upload_warns_of_params_out_of_plugin_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = format!("[jobs]\nstorage_dir = {:?}\n", dir.path());
+        let config = Config::from_toml(&config).unwrap();
+        let plugins = PluginRegistry::new();
+        plugins
+            .register_command_handler(CommandHandler {
+                command: "M104".to_string(),
+                params: vec![FieldDef {
+                    name: "s".to_string(),
+                    field_type: FieldType::Float,
+                    required: true,
+                    description: None,
+                    default_value: None,
+                    minimum: Some(0.0),
+                    maximum: Some(260.0),
+                }],
+                description: None,
+                scheduling_class: SchedulingClass::Queued,
+            })
+            .unwrap();
+        let pause = PauseController::new(&config, None);
+        let bed_mesh = BedMeshController::new(&config, None).unwrap();
+        let input_shaper = InputShaperController::new(&config, None);
+        let console = Console::new(&config, None, plugins.clone());
+        let crash = CrashReporter::new(&config, plugins);
+        let state =
+            AppState::new(config, None, pause, bed_mesh, input_shaper, console, crash).unwrap();
+
+        let mut headers = axum::http::HeaderMap::new();
+        headers.insert(
+            axum::http::header::CONTENT_TYPE,
+            "text/x-gcode".parse().unwrap(),
+        );
+        let gcode = "M104 S200\nM104 S280\nG28\nM104 S-5\n";
+        let response = upload_job(State(state.clone()), headers, gcode.into())
+            .await
+            .unwrap()
+            .into_response();
+        // Warnings do not stop the job being stored
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let job = state.jobs.list_jobs().pop().unwrap();
+        let expected = [
+            JobDiagnostic {
+                code: "param_out_of_range".to_string(),
+                line: 2,
+                message: "S280 exceeds configured max 260".to_string(),
+            },
+            JobDiagnostic {
+                code: "param_out_of_range".to_string(),
+                line: 4,
+                message: "S-5 is below configured min 0".to_string(),
+            },
+        ];
+        assert_eq!(job.diagnostics, expected);
+    }
+
+    #[tokio::test]
+    async fn test_upload_archive_keeps_assets() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = format!("[jobs]\nstorage_dir = {:?}\n", dir.path());
+        let config = Config::from_toml(&config).unwrap();
+        let plugins = PluginRegistry::new();
+        let pause = PauseController::new(&config, None);
+        let bed_mesh = BedMeshController::new(&config, None).unwrap();
+        let input_shaper = InputShaperController::new(&config, None);
+        let console = Console::new(&config, None, plugins.clone());
+        let crash = CrashReporter::new(&config, plugins);
+        let state =
+            AppState::new(config, None, pause, bed_mesh, input_shaper, console, crash).unwrap();
+
+        let archive = crate::archive::zip(&[
+            ("Metadata/plate_1.gcode", b"G28\nG1 X10 Y10\n"),
+            ("Metadata/plate_1.png", b"\x89PNG"),
+        ]);
+        let mut headers = axum::http::HeaderMap::new();
+        headers.insert(
+            axum::http::header::CONTENT_TYPE,
+            "application/zip".parse().unwrap(),
+        );
+        let response = upload_job(State(state.clone()), headers, archive.into())
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let job = state.jobs.list_jobs().pop().unwrap();
+        assert_eq!(job.original_format.as_deref(), Some("zip"));
+        assert_eq!(job.original_filename.as_deref(), Some("plate_1.gcode"));
+        assert_eq!(
+            job.assets,
+            [JobAsset {
+                path: "Metadata/plate_1.png".to_string(),
+                size_bytes: 4,
+            }]
+        );
+        let source =
+            scherzo_compile::decompile::decompile(&fs::read(state.jobs.job_path(&job.id)).unwrap())
+                .unwrap();
+        assert_eq!(source, "G28\nG1 X10 Y10\n");
+
+        let asset =
+            |path: &str| get_job_asset(State(state.clone()), Path((job.id, path.to_string())));
+        let response = asset("Metadata/plate_1.png").await.unwrap();
+        assert_eq!(response.headers()["content-type"], "image/png");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"\x89PNG");
+        assert!(matches!(
+            asset("../../etc/passwd").await,
+            Err(AppError::AssetNotFound { .. })
+        ));
+
+        state.jobs.remove_job(&job.id, |_| {}).unwrap();
+        assert!(!state.jobs.assets_dir(&job.id).exists());
+    }
+
+    #[tokio::test]
+    async fn test_outdated_jobs_by_compiler_build() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = format!("[jobs]\nstorage_dir = {:?}\n", dir.path());
+        let config = Config::from_toml(&config).unwrap();
+        let plugins = PluginRegistry::new();
+        let pause = PauseController::new(&config, None);
+        let bed_mesh = BedMeshController::new(&config, None).unwrap();
+        let input_shaper = InputShaperController::new(&config, None);
+        let console = Console::new(&config, None, plugins.clone());
+        let crash = CrashReporter::new(&config, plugins);
+        let state =
+            AppState::new(config, None, pause, bed_mesh, input_shaper, console, crash).unwrap();
+
+        let upload = |content_type: &'static str, body: Vec<u8>| {
+            let mut headers = axum::http::HeaderMap::new();
+            headers.insert(
+                axum::http::header::CONTENT_TYPE,
+                content_type.parse().unwrap(),
+            );
+            upload_job(State(state.clone()), headers, body.into())
+        };
+        let gcode = "G28\nG1 X10 F600\n";
+        upload("text/x-gcode", gcode.into()).await.unwrap();
+        let component = scherzo_compile::compile_gcode(gcode).unwrap().component;
+        upload("application/wasm", component).await.unwrap();
+
+        let gcode_job = state
+            .jobs
+            .list_jobs()
+            .into_iter()
+            .find(|job| job.original_format.as_deref() == Some("gcode"))
+            .unwrap();
+        let build = gcode_job.build.clone().unwrap();
+        assert_eq!(build.compiler_version, scherzo_compile::VERSION);
+        assert_eq!(build.input_hash, compile_cache::input_hash(gcode));
+
+        let outdated = || async {
+            let response = list_outdated_jobs(State(state.clone()))
+                .await
+                .into_response();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+        let listed = outdated().await;
+        assert_eq!(listed["compiler_version"], scherzo_compile::VERSION);
+        assert_eq!(listed["jobs"], serde_json::json!([]));
+
+        // An older compiler's job is flagged; the uploaded component never is
+        state
+            .jobs
+            .update_job(&gcode_job.id, |metadata| {
+                metadata.build = Some(JobBuild {
+                    compiler_version: "0.0.1".to_string(),
+                    ..build
+                });
+                Ok(())
+            })
+            .unwrap();
+        let listed = outdated().await;
+        assert_eq!(
+            listed["jobs"],
+            serde_json::json!([{
+                "id": gcode_job.id,
+                "name": gcode_job.name,
+                "compiler_version": "0.0.1",
+                "options_changed": false,
+            }])
+        );
+    }
+}
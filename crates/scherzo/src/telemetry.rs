@@ -0,0 +1,85 @@
+//! Tracing setup
+//!
+//! Logs are written to stderr, filtered by `RUST_LOG`. With
+//! `[telemetry] otlp_endpoint` set, spans are also exported over OTLP/HTTP.
+//! Each job is traced under one `job` span, with its `compile`, `queue`,
+//! `print`, and `between_jobs` phases as children, so a job can be followed
+//! from upload to the end of its motion. Plugins load under a `plugin` span
+//! naming the plugin.
+
+use crate::config::TelemetryConfig;
+use anyhow::{Context, Result};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{Resource, trace::SdkTracerProvider};
+use tracing::Span;
+use tracing_subscriber::{EnvFilter, Layer, layer::SubscriberExt, util::SubscriberInitExt};
+
+/// Keeps spans exporting until dropped, then flushes the last of them
+pub struct Telemetry {
+    provider: Option<SdkTracerProvider>,
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take()
+            && let Err(e) = provider.shutdown()
+        {
+            eprintln!("Failed to flush spans: {e}");
+        }
+    }
+}
+
+/// Install the global subscriber. Must be called outside any Tokio runtime,
+/// as the exporter uses a blocking HTTP client.
+pub fn init(config: &TelemetryConfig) -> Result<Telemetry> {
+    let provider = config
+        .otlp_endpoint
+        .as_ref()
+        .map(|endpoint| {
+            let exporter = SpanExporter::builder()
+                .with_http()
+                .with_endpoint(endpoint)
+                .build()
+                .context("failed to create OTLP exporter")?;
+            let resource = Resource::builder()
+                .with_service_name(config.service_name.clone())
+                .build();
+            anyhow::Ok(
+                SdkTracerProvider::builder()
+                    .with_batch_exporter(exporter)
+                    .with_resource(resource)
+                    .build(),
+            )
+        })
+        .transpose()?;
+
+    let otlp = match &provider {
+        Some(provider) => {
+            let filter = EnvFilter::try_new(&config.filter).context("invalid telemetry.filter")?;
+            let tracer = provider.tracer("scherzo");
+            Some(
+                tracing_opentelemetry::layer()
+                    .with_tracer(tracer)
+                    .with_filter(filter),
+            )
+        }
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(EnvFilter::from_default_env()))
+        .with(otlp)
+        .try_init()
+        .context("failed to install tracing subscriber")?;
+
+    if let Some(endpoint) = &config.otlp_endpoint {
+        tracing::info!("Exporting spans to {endpoint}");
+    }
+    Ok(Telemetry { provider })
+}
+
+/// Root span of a job, which its phases are traced under. `job_id` is
+/// recorded once the job has one.
+pub fn job_span() -> Span {
+    tracing::info_span!(parent: None, "job", job_id = tracing::field::Empty)
+}
@@ -2,16 +2,21 @@ use anyhow::Result;
 use clap::Subcommand;
 use xshell::Shell;
 
+pub mod bench;
 pub mod build;
 pub mod ci;
 pub mod common;
 pub mod fmt;
 pub mod hooks;
+pub mod plugin;
 pub mod precommit;
+pub mod release;
 pub mod test;
 
 #[derive(Subcommand)]
 pub enum Command {
+    /// Run the criterion benchmarks, record them, and flag regressions against the previous run
+    Bench(bench::Bench),
     /// Build all components
     Build(build::Build),
     /// Run CI checks (fmt, clippy, udeps, test). Runs all if no subcommand specified.
@@ -20,8 +25,12 @@ pub enum Command {
     Fmt(fmt::Fmt),
     /// Manage git hooks
     Hooks(hooks::Hooks),
+    /// Build plugin crates into components
+    Plugin(plugin::Plugin),
     /// Run precommit checks (checks rustfmt and runs clippy)
     Precommit(precommit::Precommit),
+    /// Cross-compile a stripped release and package it with a systemd unit and default config
+    Release(release::Release),
     /// Run tests
     Test(test::Test),
 }
@@ -29,11 +38,14 @@ pub enum Command {
 impl Command {
     pub fn run(self, sh: &Shell) -> Result<()> {
         match self {
+            Command::Bench(cmd) => cmd.run(sh),
             Command::Build(cmd) => cmd.run(sh),
             Command::Ci(cmd) => cmd.run(sh),
             Command::Fmt(cmd) => cmd.run(sh),
             Command::Hooks(cmd) => cmd.run(sh),
+            Command::Plugin(cmd) => cmd.run(sh),
             Command::Precommit(cmd) => cmd.run(sh),
+            Command::Release(cmd) => cmd.run(sh),
             Command::Test(cmd) => cmd.run(sh),
         }
     }
@@ -0,0 +1,369 @@
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use xshell::{Shell, cmd};
+
+#[derive(Args)]
+pub struct Bench {
+    /// Only run the benchmarks of this package
+    #[arg(short, long)]
+    package: Option<String>,
+
+    /// JSON file the results of every run are appended to
+    #[arg(long, default_value = "target/bench-history.json")]
+    history: PathBuf,
+
+    /// Percent slower (or larger peak memory) than the previous run that
+    /// counts as a regression
+    #[arg(long, default_value_t = 10.0)]
+    threshold: f64,
+
+    /// Only run benchmarks whose name matches this filter
+    filter: Option<String>,
+}
+
+/// Every recorded run, oldest first
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct History {
+    runs: Vec<Run>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Run {
+    /// Seconds since the Unix epoch
+    timestamp: u64,
+    commit: Option<String>,
+    /// Results by `<bench target>/<benchmark id>`
+    benches: BTreeMap<String, BenchResult>,
+    /// Peak resident memory (KiB) of each bench target
+    peak_rss_kib: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct BenchResult {
+    mean_ns: f64,
+    std_dev_ns: f64,
+}
+
+/// A result that got worse by more than the threshold
+#[derive(Debug, PartialEq)]
+struct Regression {
+    name: String,
+    previous: f64,
+    current: f64,
+}
+
+impl Bench {
+    pub fn run(&self, sh: &Shell) -> Result<()> {
+        let criterion_home = sh.current_dir().join("target/criterion");
+        let mut run = Run {
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            commit: cmd!(sh, "git rev-parse --short HEAD").quiet().read().ok(),
+            ..Run::default()
+        };
+
+        for (name, executable) in self.build(sh)? {
+            eprintln!("Running {name}...");
+            let started = SystemTime::now();
+            let mut bench = Command::new(&executable);
+            bench.arg("--bench").env("CRITERION_HOME", &criterion_home);
+            if let Some(filter) = &self.filter {
+                bench.arg(filter);
+            }
+            if let Some(peak_rss) = run_measured(bench)
+                .with_context(|| format!("failed to run {}", executable.display()))?
+            {
+                run.peak_rss_kib.insert(name.clone(), peak_rss);
+            }
+            for (id, result) in collect_results(&criterion_home, started)? {
+                run.benches.insert(format!("{name}/{id}"), result);
+            }
+        }
+
+        let mut history = if self.history.exists() {
+            let text = fs::read_to_string(&self.history)
+                .with_context(|| format!("failed to read {}", self.history.display()))?;
+            serde_json::from_str(&text)
+                .with_context(|| format!("failed to parse {}", self.history.display()))?
+        } else {
+            History::default()
+        };
+
+        let regressions = match history.runs.last() {
+            Some(previous) => {
+                report(previous, &run);
+                compare(previous, &run, self.threshold)
+            }
+            None => {
+                eprintln!("No previous run in {}", self.history.display());
+                Vec::new()
+            }
+        };
+
+        history.runs.push(run);
+        if let Some(parent) = self.history.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.history, serde_json::to_string_pretty(&history)?)
+            .with_context(|| format!("failed to write {}", self.history.display()))?;
+        eprintln!("Recorded run in {}", self.history.display());
+
+        if !regressions.is_empty() {
+            for regression in &regressions {
+                eprintln!(
+                    "REGRESSION {}: {:.1} -> {:.1} ({:+.1}%)",
+                    regression.name,
+                    regression.previous,
+                    regression.current,
+                    change(regression.previous, regression.current)
+                );
+            }
+            bail!(
+                "{} result(s) regressed by more than {}%",
+                regressions.len(),
+                self.threshold
+            );
+        }
+        Ok(())
+    }
+
+    /// Build the bench targets, returning each one's name and executable
+    fn build(&self, sh: &Shell) -> Result<Vec<(String, PathBuf)>> {
+        let package = match &self.package {
+            Some(package) => vec!["--package".to_string(), package.clone()],
+            None => vec!["--workspace".to_string()],
+        };
+        eprintln!("Building benchmarks...");
+        let output = cmd!(
+            sh,
+            "cargo bench {package...} --benches --no-run --message-format=json"
+        )
+        .read()?;
+
+        let mut benches = Vec::new();
+        for line in output.lines() {
+            let Ok(message) = serde_json::from_str::<Value>(line) else {
+                continue;
+            };
+            let target = &message["target"];
+            let is_bench = target["kind"]
+                .as_array()
+                .is_some_and(|kinds| kinds.iter().any(|kind| kind == "bench"));
+            if let (true, Some(name), Some(executable)) = (
+                is_bench,
+                target["name"].as_str(),
+                message["executable"].as_str(),
+            ) {
+                benches.push((name.to_string(), PathBuf::from(executable)));
+            }
+        }
+        if benches.is_empty() {
+            bail!("no benchmarks found");
+        }
+        Ok(benches)
+    }
+}
+
+/// Run `command` to completion, returning its peak resident memory (KiB)
+/// where the platform reports it
+#[cfg(unix)]
+fn run_measured(mut command: Command) -> Result<Option<u64>> {
+    let child = command.spawn()?;
+    let mut status = 0;
+    // SAFETY: `rusage` is plain data, and the child is reaped here rather
+    // than through `Child::wait`.
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    let pid = unsafe { libc::wait4(child.id() as libc::pid_t, &mut status, 0, &mut usage) };
+    if pid < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    if !libc::WIFEXITED(status) || libc::WEXITSTATUS(status) != 0 {
+        bail!("benchmark failed with status {status}");
+    }
+    // Linux reports KiB, macOS bytes
+    let max_rss = usage.ru_maxrss as u64;
+    Ok(Some(if cfg!(target_os = "macos") {
+        max_rss / 1024
+    } else {
+        max_rss
+    }))
+}
+
+#[cfg(not(unix))]
+fn run_measured(mut command: Command) -> Result<Option<u64>> {
+    let status = command.status()?;
+    if !status.success() {
+        bail!("benchmark failed with {status}");
+    }
+    Ok(None)
+}
+
+/// Criterion results written under `criterion_home` since `since`, by
+/// benchmark id
+fn collect_results(criterion_home: &Path, since: SystemTime) -> Result<Vec<(String, BenchResult)>> {
+    let mut results = Vec::new();
+    let mut dirs = vec![criterion_home.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if !path.is_dir() {
+                continue;
+            }
+            if path.file_name().is_some_and(|name| name == "new") {
+                if let Some(result) = read_result(&path, since)? {
+                    results.push(result);
+                }
+            } else {
+                dirs.push(path);
+            }
+        }
+    }
+    results.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(results)
+}
+
+/// Result in a criterion `new` directory, if it was written since `since`
+fn read_result(dir: &Path, since: SystemTime) -> Result<Option<(String, BenchResult)>> {
+    let estimates_path = dir.join("estimates.json");
+    let Ok(modified) = fs::metadata(&estimates_path).and_then(|meta| meta.modified()) else {
+        return Ok(None);
+    };
+    if modified < since {
+        return Ok(None);
+    }
+    let read = |path: PathBuf| -> Result<Value> {
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        Ok(serde_json::from_str(&text)?)
+    };
+    let benchmark = read(dir.join("benchmark.json"))?;
+    let estimates = read(estimates_path)?;
+    let (Some(id), Some(mean_ns), Some(std_dev_ns)) = (
+        benchmark["full_id"].as_str(),
+        estimates["mean"]["point_estimate"].as_f64(),
+        estimates["std_dev"]["point_estimate"].as_f64(),
+    ) else {
+        bail!("unexpected criterion output in {}", dir.display());
+    };
+    Ok(Some((
+        id.to_string(),
+        BenchResult {
+            mean_ns,
+            std_dev_ns,
+        },
+    )))
+}
+
+/// Percent change from `previous` to `current`
+fn change(previous: f64, current: f64) -> f64 {
+    (current - previous) / previous * 100.0
+}
+
+/// Print each result next to the previous run's
+fn report(previous: &Run, current: &Run) {
+    for (name, result) in &current.benches {
+        match previous.benches.get(name) {
+            Some(old) => eprintln!(
+                "{name}: {:.1} ns ({:+.1}%)",
+                result.mean_ns,
+                change(old.mean_ns, result.mean_ns)
+            ),
+            None => eprintln!("{name}: {:.1} ns (new)", result.mean_ns),
+        }
+    }
+    for (name, rss) in &current.peak_rss_kib {
+        match previous.peak_rss_kib.get(name) {
+            Some(&old) => eprintln!(
+                "{name}: peak RSS {rss} KiB ({:+.1}%)",
+                change(old as f64, *rss as f64)
+            ),
+            None => eprintln!("{name}: peak RSS {rss} KiB (new)"),
+        }
+    }
+}
+
+/// Results of `current` more than `threshold` percent worse than in
+/// `previous`. Results missing from either run are skipped.
+fn compare(previous: &Run, current: &Run, threshold: f64) -> Vec<Regression> {
+    let times = current.benches.iter().filter_map(|(name, result)| {
+        let old = previous.benches.get(name)?;
+        Some((name.clone(), old.mean_ns, result.mean_ns))
+    });
+    let memory = current.peak_rss_kib.iter().filter_map(|(name, &rss)| {
+        let old = *previous.peak_rss_kib.get(name)?;
+        Some((format!("{name} peak RSS (KiB)"), old as f64, rss as f64))
+    });
+    times
+        .chain(memory)
+        .filter(|&(_, previous, current)| change(previous, current) > threshold)
+        .map(|(name, previous, current)| Regression {
+            name,
+            previous,
+            current,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(find_move: f64, rss: u64) -> Run {
+        Run {
+            benches: [(
+                "trap_queue/find_move".to_string(),
+                BenchResult {
+                    mean_ns: find_move,
+                    std_dev_ns: 1.0,
+                },
+            )]
+            .into(),
+            peak_rss_kib: [("trap_queue".to_string(), rss)].into(),
+            ..Run::default()
+        }
+    }
+
+    #[test]
+    fn flags_results_beyond_the_threshold() {
+        let previous = run(100.0, 1000);
+        assert!(compare(&previous, &run(109.0, 1050), 10.0).is_empty());
+        assert!(compare(&previous, &run(50.0, 500), 10.0).is_empty());
+        assert_eq!(
+            compare(&previous, &run(120.0, 1200), 10.0),
+            vec![
+                Regression {
+                    name: "trap_queue/find_move".into(),
+                    previous: 100.0,
+                    current: 120.0,
+                },
+                Regression {
+                    name: "trap_queue peak RSS (KiB)".into(),
+                    previous: 1000.0,
+                    current: 1200.0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn history_round_trips() {
+        let history = History {
+            runs: vec![run(100.0, 1000)],
+        };
+        let json = serde_json::to_string(&history).unwrap();
+        let parsed: History = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.runs[0].peak_rss_kib["trap_queue"], 1000);
+        assert!(compare(&history.runs[0], &parsed.runs[0], 0.0).is_empty());
+    }
+}
@@ -0,0 +1,163 @@
+use anyhow::{Context, Result, bail};
+use clap::{Args, Subcommand};
+use serde_json::Value;
+use std::{fs, path::PathBuf};
+use xshell::{Shell, cmd};
+
+#[derive(Args)]
+pub struct Plugin {
+    #[command(subcommand)]
+    command: PluginCommand,
+}
+
+#[derive(Subcommand)]
+pub enum PluginCommand {
+    /// Build the plugin crates and copy their components into target/plugins
+    Build(BuildArgs),
+}
+
+#[derive(Args)]
+pub struct BuildArgs {
+    /// Target to compile the plugins for
+    #[arg(long, default_value = "wasm32-wasip2")]
+    target: String,
+
+    /// Build with the release profile
+    #[arg(long)]
+    release: bool,
+
+    /// Only build this plugin crate
+    #[arg(short, long)]
+    package: Option<String>,
+
+    /// Directory the components are copied into
+    #[arg(long, default_value = "target/plugins")]
+    out_dir: PathBuf,
+}
+
+/// A workspace crate that sets `package.metadata.scherzo.plugin = true`
+struct PluginCrate {
+    name: String,
+    /// Name of its `cdylib` target, which names the built `.wasm`
+    lib: String,
+}
+
+impl Plugin {
+    pub fn run(&self, sh: &Shell) -> Result<()> {
+        match &self.command {
+            PluginCommand::Build(args) => args.run(sh),
+        }
+    }
+}
+
+impl BuildArgs {
+    fn run(&self, sh: &Shell) -> Result<()> {
+        let metadata = cmd!(sh, "cargo metadata --format-version 1 --no-deps")
+            .quiet()
+            .read()?;
+        let metadata: Value = serde_json::from_str(&metadata)?;
+        let mut plugins = find_plugins(&metadata);
+        if let Some(package) = &self.package {
+            plugins.retain(|plugin| &plugin.name == package);
+            if plugins.is_empty() {
+                bail!("`{package}` is not a plugin crate");
+            }
+        }
+        if plugins.is_empty() {
+            eprintln!("No plugin crates found (set package.metadata.scherzo.plugin = true)");
+            return Ok(());
+        }
+
+        let target_dir = metadata["target_directory"]
+            .as_str()
+            .map(PathBuf::from)
+            .context("cargo metadata did not report a target directory")?;
+        let profile = if self.release { "release" } else { "debug" };
+        let release = self.release.then_some("--release");
+        let target = &self.target;
+        fs::create_dir_all(&self.out_dir)
+            .with_context(|| format!("failed to create {}", self.out_dir.display()))?;
+
+        for plugin in &plugins {
+            let name = &plugin.name;
+            eprintln!("Building plugin {name}...");
+            cmd!(
+                sh,
+                "cargo build --package {name} --lib --target {target} {release...}"
+            )
+            .run()?;
+
+            let built = target_dir
+                .join(target)
+                .join(profile)
+                .join(format!("{}.wasm", plugin.lib));
+            let bytes =
+                fs::read(&built).with_context(|| format!("failed to read {}", built.display()))?;
+            if !wasmparser::Parser::is_component(&bytes) {
+                bail!(
+                    "{} is a core module, not a component; build for a component target such as wasm32-wasip2",
+                    built.display()
+                );
+            }
+            let installed = self.out_dir.join(format!("{}.wasm", plugin.lib));
+            fs::write(&installed, &bytes)
+                .with_context(|| format!("failed to write {}", installed.display()))?;
+            eprintln!("Installed {}", installed.display());
+        }
+        Ok(())
+    }
+}
+
+/// Plugin crates listed in `cargo metadata` output
+fn find_plugins(metadata: &Value) -> Vec<PluginCrate> {
+    let Some(packages) = metadata["packages"].as_array() else {
+        return Vec::new();
+    };
+    packages
+        .iter()
+        .filter(|package| package["metadata"]["scherzo"]["plugin"] == true)
+        .filter_map(|package| {
+            let lib = package["targets"].as_array()?.iter().find(|target| {
+                target["crate_types"]
+                    .as_array()
+                    .is_some_and(|types| types.iter().any(|t| t == "cdylib"))
+            })?;
+            Some(PluginCrate {
+                name: package["name"].as_str()?.to_string(),
+                lib: lib["name"].as_str()?.replace('-', "_"),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_plugin_crates_with_a_cdylib() {
+        let metadata = serde_json::json!({
+            "packages": [
+                {
+                    "name": "fan-control",
+                    "metadata": {"scherzo": {"plugin": true}},
+                    "targets": [{"name": "fan-control", "crate_types": ["cdylib"]}],
+                },
+                {
+                    "name": "no-lib",
+                    "metadata": {"scherzo": {"plugin": true}},
+                    "targets": [{"name": "no-lib", "crate_types": ["bin"]}],
+                },
+                {
+                    "name": "scherzo-core",
+                    "metadata": null,
+                    "targets": [{"name": "scherzo_core", "crate_types": ["cdylib"]}],
+                },
+            ],
+        });
+        let plugins = find_plugins(&metadata);
+        assert_eq!(plugins.len(), 1);
+        assert_eq!(plugins[0].name, "fan-control");
+        assert_eq!(plugins[0].lib, "fan_control");
+    }
+}
@@ -0,0 +1,130 @@
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use serde_json::Value;
+use std::{fs, path::PathBuf};
+use xshell::{Shell, cmd};
+
+/// Where the packaged unit expects the binary and its config
+const INSTALL_BIN: &str = "/usr/local/bin/scherzo";
+const INSTALL_CONFIG: &str = "/etc/scherzo/scherzo.toml";
+
+#[derive(Args)]
+pub struct Release {
+    /// Target to cross-compile for
+    #[arg(long, default_value = "aarch64-unknown-linux-gnu")]
+    target: String,
+
+    /// Directory the tarball is written to
+    #[arg(long, default_value = "target/dist")]
+    out_dir: PathBuf,
+}
+
+impl Release {
+    pub fn run(&self, sh: &Shell) -> Result<()> {
+        let target = &self.target;
+        let version = package_version(sh, "scherzo")?;
+
+        eprintln!("Building scherzo {version} for {target}...");
+        cmd!(
+            sh,
+            "cargo build --release --locked --package scherzo --bin scherzo --target {target}"
+        )
+        .env("CARGO_PROFILE_RELEASE_STRIP", "symbols")
+        .run()?;
+
+        let executable = if target.contains("windows") {
+            "scherzo.exe"
+        } else {
+            "scherzo"
+        };
+        let binary = sh
+            .current_dir()
+            .join("target")
+            .join(target)
+            .join("release")
+            .join(executable);
+        if !binary.exists() {
+            bail!("build did not produce {}", binary.display());
+        }
+
+        let name = archive_name(&version, target);
+        let staging = self.out_dir.join(&name);
+        if staging.exists() {
+            fs::remove_dir_all(&staging)
+                .with_context(|| format!("failed to clear {}", staging.display()))?;
+        }
+        fs::create_dir_all(&staging)
+            .with_context(|| format!("failed to create {}", staging.display()))?;
+        fs::copy(&binary, staging.join(executable))?;
+        fs::copy("example.toml", staging.join("scherzo.toml"))?;
+        fs::copy("README.md", staging.join("README.md"))?;
+        fs::write(staging.join("scherzo.service"), systemd_unit())?;
+
+        let tarball = format!("{name}.tar.gz");
+        let out_dir = &self.out_dir;
+        cmd!(sh, "tar -czf {out_dir}/{tarball} -C {out_dir} {name}").run()?;
+        eprintln!("Packaged {}", out_dir.join(&tarball).display());
+        Ok(())
+    }
+}
+
+/// Version of a workspace package, from `cargo metadata`
+fn package_version(sh: &Shell, package: &str) -> Result<String> {
+    let metadata = cmd!(sh, "cargo metadata --format-version 1 --no-deps")
+        .quiet()
+        .read()?;
+    let metadata: Value = serde_json::from_str(&metadata)?;
+    metadata["packages"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|p| p["name"] == package)
+        .and_then(|p| p["version"].as_str())
+        .map(str::to_string)
+        .with_context(|| format!("package {package} not found in the workspace"))
+}
+
+fn archive_name(version: &str, target: &str) -> String {
+    format!("scherzo-{version}-{target}")
+}
+
+/// Unit running `scherzo start` on the installed config
+fn systemd_unit() -> String {
+    format!(
+        "\
+[Unit]
+Description=Scherzo 3D printer host
+After=network-online.target
+Wants=network-online.target
+
+[Service]
+Type=simple
+User=scherzo
+Group=scherzo
+SupplementaryGroups=dialout
+ExecStart={INSTALL_BIN} start {INSTALL_CONFIG}
+Restart=on-failure
+RestartSec=5
+
+[Install]
+WantedBy=multi-user.target
+"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unit_starts_the_installed_config() {
+        let unit = systemd_unit();
+        assert!(
+            unit.contains("ExecStart=/usr/local/bin/scherzo start /etc/scherzo/scherzo.toml\n")
+        );
+        assert_eq!(
+            archive_name("0.1.0", "aarch64-unknown-linux-gnu"),
+            "scherzo-0.1.0-aarch64-unknown-linux-gnu"
+        );
+    }
+}